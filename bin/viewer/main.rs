@@ -3,7 +3,8 @@ use bevy::{
     prelude::*,
     render::{settings::WgpuSettings, settings::WgpuFeatures},
 };
-use feldspar_map::MapPlugin;
+use feldspar_map::{MapPlugin, Witness};
+use feldspar_renderer::RenderPlugin;
 use smooth_bevy_cameras::{
     controllers::fps::{FpsCameraBundle, FpsCameraController, FpsCameraPlugin},
     LookTransformPlugin,
@@ -28,7 +29,7 @@ fn main() {
         .add_plugin(WireframePlugin)
         // Feldspar
         .add_plugin(MapPlugin::default())
-        // .add_plugin(RenderPlugin)
+        .add_plugin(RenderPlugin)
         // Viewer
         .add_plugin(LookTransformPlugin)
         .add_plugin(FpsCameraPlugin::default())
@@ -50,9 +51,11 @@ fn setup(mut commands: Commands, mut wireframe_config: ResMut<WireframeConfig>)
     });
     let eye = Vec3::new(50.0, 15.0, 50.0);
     let target = Vec3::new(0.0, 0.0, 0.0);
-    commands.spawn_bundle(FpsCameraBundle::new(
-        FpsCameraController::default(),
-        eye,
-        target,
-    ));
+    commands
+        .spawn_bundle(FpsCameraBundle::new(
+            FpsCameraController::default(),
+            eye,
+            target,
+        ))
+        .insert(Witness::default());
 }
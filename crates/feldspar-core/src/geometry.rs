@@ -1,4 +1,4 @@
-use crate::glam::Vec3A;
+use crate::glam::{Mat4, Vec3A, Vec4};
 use crate::ilattice::prelude::Extent;
 
 #[derive(Clone, Copy)]
@@ -74,6 +74,132 @@ impl Sphere {
     }
 }
 
+/// All points within `radius` of the line segment from `a` to `b`.
+#[derive(Clone, Copy, Debug)]
+pub struct Capsule {
+    pub a: Vec3A,
+    pub b: Vec3A,
+    pub radius: f32,
+}
+
+impl Capsule {
+    pub fn new(a: Vec3A, b: Vec3A, radius: f32) -> Self {
+        Self { a, b, radius }
+    }
+
+    /// The point on the segment from `a` to `b` that is closest to `p`.
+    pub fn closest_point_on_segment(&self, p: Vec3A) -> Vec3A {
+        let ab = self.b - self.a;
+        let length_squared = ab.length_squared();
+        if length_squared == 0.0 {
+            return self.a;
+        }
+        let s = ((p - self.a).dot(ab) / length_squared).clamp(0.0, 1.0);
+        self.a + s * ab
+    }
+
+    /// Signed distance from `p` to the surface of the capsule, negative inside.
+    pub fn signed_distance(&self, p: Vec3A) -> f32 {
+        p.distance(self.closest_point_on_segment(p)) - self.radius
+    }
+
+    pub fn aabb(&self) -> Extent<Vec3A> {
+        Extent::from_min_and_lub(
+            self.a.min(self.b) - self.radius,
+            self.a.max(self.b) + self.radius,
+        )
+    }
+}
+
+/// A convex volume bounded by 6 planes, usually the view volume of a camera.
+#[derive(Clone, Copy, Debug)]
+pub struct Frustum {
+    /// Each plane is `(normal, d)`, and the inside is where `normal.dot(p) + d >= 0`. Normals are not necessarily normalized.
+    pub planes: [Vec4; 6],
+}
+
+impl Frustum {
+    /// Extracts the planes from a view-projection matrix that maps the view volume into clip space with depth in `[0, 1]`, like
+    /// the perspective and orthographic projections in `glam`.
+    ///
+    /// Refer to [Gribb and Hartmann](https://www.gamedevs.org/uploads/fast-extraction-viewing-frustum-planes-from-world-view-projection-matrix.pdf).
+    pub fn from_view_projection(view_projection: Mat4) -> Self {
+        let [x, y, z, w] = [0, 1, 2, 3].map(|i| view_projection.row(i));
+        Self {
+            planes: [w + x, w - x, w + y, w - y, z, w - z],
+        }
+    }
+
+    /// Conservative test for intersection with `aabb`. It never misses an intersection, but a box just outside of a corner may
+    /// be reported as intersecting.
+    pub fn intersects_extent(&self, aabb: Extent<Vec3A>) -> bool {
+        let min = aabb.minimum;
+        let max = aabb.least_upper_bound();
+        self.planes.iter().all(|plane| {
+            let normal = Vec3A::new(plane.x, plane.y, plane.z);
+            // The corner of the box that is farthest along the normal.
+            let corner = Vec3A::select(normal.cmpge(Vec3A::ZERO), max, min);
+            normal.dot(corner) + plane.w >= 0.0
+        })
+    }
+
+    pub fn contains_point(&self, p: Vec3A) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| Vec3A::new(plane.x, plane.y, plane.z).dot(p) + plane.w >= 0.0)
+    }
+}
+
+/// A cone truncated by a plane perpendicular to its axis.
+#[derive(Clone, Copy, Debug)]
+pub struct Cone {
+    pub apex: Vec3A,
+    /// Must be normalized.
+    pub axis: Vec3A,
+    /// The distance from the apex to the base, along the axis.
+    pub length: f32,
+    /// The angle (in radians) between the axis and the lateral surface. Must be less than `PI / 2`.
+    pub half_angle: f32,
+}
+
+impl Cone {
+    pub fn new(apex: Vec3A, axis: Vec3A, length: f32, half_angle: f32) -> Self {
+        Self {
+            apex,
+            axis,
+            length,
+            half_angle,
+        }
+    }
+
+    pub fn base_radius(&self) -> f32 {
+        self.length * self.half_angle.tan()
+    }
+
+    /// A sphere centered halfway along the axis that contains the entire cone.
+    pub fn bounding_sphere(&self) -> Sphere {
+        let half_length = 0.5 * self.length;
+        Sphere::new(
+            self.apex + half_length * self.axis,
+            half_length.hypot(self.base_radius()),
+        )
+    }
+
+    /// Conservative test for intersection with `sphere`. It never misses an intersection, but a sphere just outside of the
+    /// base or apex may be reported as intersecting.
+    pub fn intersects_sphere(&self, sphere: &Sphere) -> bool {
+        let v = sphere.center - self.apex;
+        let along = v.dot(self.axis);
+        if along < -sphere.radius || along > self.length + sphere.radius {
+            return false;
+        }
+        // Distance from the sphere center to the lateral surface, measured in the plane containing the axis.
+        let perp = (v - along * self.axis).length();
+        let (sin, cos) = self.half_angle.sin_cos();
+        perp * cos - along * sin < sphere.radius
+    }
+}
+
 // ████████╗███████╗███████╗████████╗
 // ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
 //    ██║   █████╗  ███████╗   ██║
@@ -106,4 +232,59 @@ mod test {
         assert_relative_eq!(tmin, 0.1);
         assert_relative_eq!(tmax, 1.0);
     }
+
+    #[test]
+    fn cone_intersects_spheres_inside_and_near_surface() {
+        let cone = Cone::new(Vec3A::ZERO, Vec3A::X, 10.0, std::f32::consts::FRAC_PI_4);
+
+        assert!(cone.intersects_sphere(&Sphere::new(Vec3A::new(5.0, 0.0, 0.0), 0.1)));
+        assert!(cone.intersects_sphere(&Sphere::new(Vec3A::new(5.0, 5.5, 0.0), 1.0)));
+        assert!(cone.intersects_sphere(&Sphere::new(Vec3A::new(-0.5, 0.0, 0.0), 1.0)));
+    }
+
+    #[test]
+    fn cone_misses_spheres_outside() {
+        let cone = Cone::new(Vec3A::ZERO, Vec3A::X, 10.0, std::f32::consts::FRAC_PI_4);
+
+        assert!(!cone.intersects_sphere(&Sphere::new(Vec3A::new(5.0, 8.0, 0.0), 1.0)));
+        assert!(!cone.intersects_sphere(&Sphere::new(Vec3A::new(-5.0, 0.0, 0.0), 1.0)));
+        assert!(!cone.intersects_sphere(&Sphere::new(Vec3A::new(15.0, 0.0, 0.0), 1.0)));
+    }
+
+    #[test]
+    fn cone_bounding_sphere_contains_base() {
+        let cone = Cone::new(Vec3A::ZERO, Vec3A::X, 10.0, std::f32::consts::FRAC_PI_4);
+        let bounds = cone.bounding_sphere();
+
+        assert_eq!(bounds.center, Vec3A::new(5.0, 0.0, 0.0));
+        assert!(bounds.radius >= Vec3A::new(10.0, 10.0, 0.0).distance(bounds.center) - 1e-4);
+    }
+
+    #[test]
+    fn capsule_signed_distance() {
+        let capsule = Capsule::new(Vec3A::ZERO, Vec3A::new(0.0, 2.0, 0.0), 0.5);
+
+        assert_relative_eq!(capsule.signed_distance(Vec3A::new(1.0, 1.0, 0.0)), 0.5);
+        assert_relative_eq!(capsule.signed_distance(Vec3A::new(0.0, 3.0, 0.0)), 0.5);
+        assert_relative_eq!(capsule.signed_distance(Vec3A::new(0.0, -0.25, 0.0)), -0.25);
+        assert_relative_eq!(capsule.signed_distance(Vec3A::new(0.25, 1.0, 0.0)), -0.25);
+    }
+
+    #[test]
+    fn frustum_culls_boxes_outside_view() {
+        let projection = Mat4::perspective_rh(std::f32::consts::FRAC_PI_2, 1.0, 0.1, 100.0);
+        // Looking down -Z from the origin.
+        let frustum = Frustum::from_view_projection(projection);
+
+        assert!(frustum.contains_point(Vec3A::new(0.0, 0.0, -10.0)));
+        assert!(!frustum.contains_point(Vec3A::new(0.0, 0.0, 10.0)));
+        assert!(!frustum.contains_point(Vec3A::new(0.0, 0.0, -200.0)));
+
+        let unit_box = |min: Vec3A| Extent::from_min_and_shape(min, Vec3A::ONE);
+        assert!(frustum.intersects_extent(unit_box(Vec3A::new(-0.5, -0.5, -10.0))));
+        // Straddles the left plane.
+        assert!(frustum.intersects_extent(unit_box(Vec3A::new(-10.5, 0.0, -10.0))));
+        assert!(!frustum.intersects_extent(unit_box(Vec3A::new(-20.0, 0.0, -10.0))));
+        assert!(!frustum.intersects_extent(unit_box(Vec3A::new(0.0, 0.0, 5.0))));
+    }
 }
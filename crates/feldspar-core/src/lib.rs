@@ -3,6 +3,7 @@ pub mod archived_buf;
 pub mod bitset;
 pub mod frame_budget;
 pub mod geometry;
+pub mod noise;
 pub mod work_timer;
 
 use ahash::{AHashMap, AHashSet};
@@ -0,0 +1,201 @@
+//! A 2D map of biomes that world generation and rendering can both sample.
+//!
+//! The map is a lattice of square cells over the XZ plane, where every cell is assigned a [`BiomeId`] by a [`BiomeLattice`].
+//! Each biome has [`BiomeParams`] that [`GenStage`](crate::generator::GenStage)s can use to vary the terrain, and the
+//! renderer writes the biome of every vertex into a mesh attribute for shading.
+//!
+//! With the Bevy plugin, insert the [`BiomeMap`] as a resource so the mesher can find it, and give the same map to
+//! [`WorldGenPipeline::with_biomes`](crate::generator::WorldGenPipeline::with_biomes).
+
+use crate::core::glam::{IVec2, IVec3};
+use crate::core::noise::Fbm;
+use crate::palette::PaletteId8;
+use crate::units::VoxelUnits;
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+
+pub type BiomeId = u8;
+
+/// Assigns a biome to every cell of a [`BiomeMap`]. Like world generation, this must be a pure function of the cell.
+pub trait BiomeLattice: Send + Sync {
+    fn biome(&self, cell: IVec2) -> BiomeId;
+}
+
+impl<F> BiomeLattice for F
+where
+    F: Fn(IVec2) -> BiomeId + Send + Sync,
+{
+    fn biome(&self, cell: IVec2) -> BiomeId {
+        self(cell)
+    }
+}
+
+/// Picks biomes by thresholding 2D [`Fbm`] noise. Cells with noise below `thresholds[i].0` get `thresholds[i].1`, where the
+/// thresholds are sorted in increasing order, and cells above every threshold get `default_biome`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NoiseBiomes {
+    pub seed: u32,
+    /// The noise is sampled at cell coordinates, so the frequency is in cycles per cell.
+    pub noise: Fbm,
+    pub thresholds: Vec<(f32, BiomeId)>,
+    pub default_biome: BiomeId,
+}
+
+impl BiomeLattice for NoiseBiomes {
+    fn biome(&self, cell: IVec2) -> BiomeId {
+        let value = self.noise.sample_grid2(self.seed, cell);
+        self.thresholds
+            .iter()
+            .find(|(threshold, _)| value < *threshold)
+            .map_or(self.default_biome, |(_, biome)| *biome)
+    }
+}
+
+/// The generation parameters of one biome.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct BiomeParams {
+    pub name: String,
+    /// The material of the top layer of solid voxels.
+    pub surface_palette_id: PaletteId8,
+    /// The material below the surface layer.
+    pub subsurface_palette_id: PaletteId8,
+    /// Added to the base terrain height, in LOD0 voxels.
+    pub height_offset: f32,
+    /// Scales the amplitude of the height noise.
+    pub height_scale: f32,
+}
+
+impl Default for BiomeParams {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            surface_palette_id: 1,
+            subsurface_palette_id: 1,
+            height_offset: 0.0,
+            height_scale: 1.0,
+        }
+    }
+}
+
+/// The biome of every column of the map, and the parameters of each biome.
+///
+/// Cloning is cheap, since the lattice is shared.
+#[derive(Clone)]
+pub struct BiomeMap {
+    cell_size_log2: u8,
+    lattice: Arc<dyn BiomeLattice>,
+    params: Vec<BiomeParams>,
+}
+
+impl fmt::Debug for BiomeMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BiomeMap")
+            .field("cell_size_log2", &self.cell_size_log2)
+            .field("params", &self.params)
+            .finish_non_exhaustive()
+    }
+}
+
+impl BiomeMap {
+    /// A map with cells of `2^cell_size_log2` LOD0 voxels along X and Z.
+    pub fn new(cell_size_log2: u8, lattice: impl BiomeLattice + 'static) -> Self {
+        Self {
+            cell_size_log2,
+            lattice: Arc::new(lattice),
+            params: Vec::new(),
+        }
+    }
+
+    /// Sets the parameters of `biome`. Biomes without parameters use the default [`BiomeParams`].
+    pub fn with_params(mut self, biome: BiomeId, params: BiomeParams) -> Self {
+        let i = biome as usize;
+        if self.params.len() <= i {
+            self.params.resize_with(i + 1, Default::default);
+        }
+        self.params[i] = params;
+        self
+    }
+
+    pub fn cell_size_log2(&self) -> u8 {
+        self.cell_size_log2
+    }
+
+    /// The cell that contains the column at `(x, z)`.
+    pub fn cell(&self, x: i32, z: i32) -> IVec2 {
+        IVec2::new(x, z) >> self.cell_size_log2 as i32
+    }
+
+    /// The biome of the column that contains the LOD0 voxel `p`.
+    pub fn biome_at(&self, p: VoxelUnits<IVec3>) -> BiomeId {
+        let VoxelUnits(p) = p;
+        self.biome_at_column(p.x, p.z)
+    }
+
+    pub fn biome_at_column(&self, x: i32, z: i32) -> BiomeId {
+        self.lattice.biome(self.cell(x, z))
+    }
+
+    pub fn params(&self, biome: BiomeId) -> Option<&BiomeParams> {
+        self.params.get(biome as usize)
+    }
+
+    /// The parameters of the biome at `p`, if any were set.
+    pub fn params_at(&self, p: VoxelUnits<IVec3>) -> Option<&BiomeParams> {
+        self.params(self.biome_at(p))
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn biomes_are_constant_within_cells() {
+        // Biome 1 for cells with positive X, otherwise biome 0.
+        let map = BiomeMap::new(3, |cell: IVec2| (cell.x > 0) as BiomeId).with_params(
+            1,
+            BiomeParams {
+                name: "desert".into(),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(map.biome_at(VoxelUnits(IVec3::new(7, 100, -50))), 0);
+        assert_eq!(map.biome_at(VoxelUnits(IVec3::new(8, 0, 3))), 1);
+        assert_eq!(map.biome_at_column(-1, 0), 0);
+        assert_eq!(map.params(1).unwrap().name, "desert");
+        assert_eq!(map.params(0), Some(&BiomeParams::default()));
+        assert_eq!(map.params(2), None);
+    }
+
+    #[test]
+    fn noise_biomes_follow_thresholds() {
+        let biomes = NoiseBiomes {
+            seed: 3,
+            noise: Fbm {
+                frequency: 0.1,
+                ..Default::default()
+            },
+            thresholds: vec![(-0.2, 1), (0.2, 2)],
+            default_biome: 3,
+        };
+        let mut seen = [false; 4];
+        for z in 0..32 {
+            for x in 0..32 {
+                seen[biomes.biome(IVec2::new(x, z)) as usize] = true;
+            }
+        }
+        assert!(!seen[0]);
+        assert!(seen[2]);
+    }
+}
@@ -0,0 +1,397 @@
+//! Voxel editing operations that can be applied with [`MapEdits::apply_brush`](crate::MapEdits::apply_brush).
+//!
+//! The built-in brushes are defined by signed distance functions in LOD0 voxel units. Adding or subtracting a shape takes the
+//! (optionally smoothed) union or difference of the shape with the existing terrain, so brush strokes blend into the terrain
+//! instead of leaving hard seams.
+
+use crate::chunk::AMBIENT_SD8;
+use crate::core::geometry::Sphere;
+use crate::core::glam::{IVec3, Vec3A};
+use crate::core::ilattice::prelude::Extent;
+use crate::core::noise::Fbm;
+use crate::palette::PaletteId8;
+use crate::sdf::Sd8;
+use crate::units::VoxelUnits;
+
+/// Edits all LOD0 voxels in a bounded region.
+///
+/// Brushes are applied to each chunk they overlap separately, and possibly on a later frame if the chunk is still loading, so
+/// the result for each voxel should only depend on the voxel's position and old value.
+pub trait Brush: Send + Sync + 'static {
+    /// The LOD0 voxels that might be edited by this brush.
+    fn extent(&self) -> VoxelUnits<Extent<IVec3>>;
+
+    /// Edits the voxel at `p`, which is guaranteed to be in the [`Brush::extent`].
+    fn paint(&self, p: VoxelUnits<IVec3>, sdf: &mut Sd8, palette_id: &mut PaletteId8);
+}
+
+/// How a brush shape is combined with the existing terrain.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BlendMode {
+    /// Fill the shape with solid voxels.
+    Add,
+    /// Carve the shape out of the terrain.
+    Subtract,
+}
+
+/// Adds or subtracts a sphere.
+#[derive(Clone, Copy, Debug)]
+pub struct SphereBrush {
+    pub sphere: VoxelUnits<Sphere>,
+    pub mode: BlendMode,
+    /// The radius of the smooth blend with the existing terrain. Zero gives a sharp crease.
+    pub smoothness: VoxelUnits<f32>,
+    /// The material of added voxels.
+    pub palette_id: PaletteId8,
+}
+
+impl Brush for SphereBrush {
+    fn extent(&self) -> VoxelUnits<Extent<IVec3>> {
+        let VoxelUnits(sphere) = self.sphere;
+        VoxelUnits(sdf_extent(sphere.aabb(), self.smoothness))
+    }
+
+    fn paint(&self, p: VoxelUnits<IVec3>, sdf: &mut Sd8, palette_id: &mut PaletteId8) {
+        let VoxelUnits(sphere) = self.sphere;
+        let shape_dist = voxel_position(p).distance(sphere.center) - sphere.radius;
+        blend(
+            self.mode,
+            self.smoothness,
+            self.palette_id,
+            shape_dist,
+            sdf,
+            palette_id,
+        );
+    }
+}
+
+/// Adds or subtracts an axis-aligned box.
+#[derive(Clone, Copy, Debug)]
+pub struct BoxBrush {
+    pub aabb: VoxelUnits<Extent<Vec3A>>,
+    pub mode: BlendMode,
+    /// The radius of the smooth blend with the existing terrain. Zero gives a sharp crease.
+    pub smoothness: VoxelUnits<f32>,
+    /// The material of added voxels.
+    pub palette_id: PaletteId8,
+}
+
+impl Brush for BoxBrush {
+    fn extent(&self) -> VoxelUnits<Extent<IVec3>> {
+        let VoxelUnits(aabb) = self.aabb;
+        VoxelUnits(sdf_extent(aabb, self.smoothness))
+    }
+
+    fn paint(&self, p: VoxelUnits<IVec3>, sdf: &mut Sd8, palette_id: &mut PaletteId8) {
+        let VoxelUnits(aabb) = self.aabb;
+        let half_shape = 0.5 * aabb.shape;
+        let center = aabb.minimum + half_shape;
+        let q = (voxel_position(p) - center).abs() - half_shape;
+        let shape_dist = q.max(Vec3A::ZERO).length() + q.max_element().min(0.0);
+        blend(
+            self.mode,
+            self.smoothness,
+            self.palette_id,
+            shape_dist,
+            sdf,
+            palette_id,
+        );
+    }
+}
+
+/// Changes the material of solid voxels in a sphere without changing the geometry.
+#[derive(Clone, Copy, Debug)]
+pub struct PaintBrush {
+    pub sphere: VoxelUnits<Sphere>,
+    pub palette_id: PaletteId8,
+}
+
+impl Brush for PaintBrush {
+    fn extent(&self) -> VoxelUnits<Extent<IVec3>> {
+        let VoxelUnits(sphere) = self.sphere;
+        VoxelUnits(sdf_extent(sphere.aabb(), VoxelUnits(0.0)))
+    }
+
+    fn paint(&self, p: VoxelUnits<IVec3>, sdf: &mut Sd8, palette_id: &mut PaletteId8) {
+        let VoxelUnits(sphere) = self.sphere;
+        if sdf.0 < 0 && voxel_position(p).distance(sphere.center) <= sphere.radius {
+            *palette_id = self.palette_id;
+        }
+    }
+}
+
+/// Resets every voxel in an extent to air, like a fresh [`Chunk::default`](crate::chunk::Chunk::default). Unlike a
+/// subtracted [`BoxBrush`], the terrain isn't blended, so the surface of the cut is blocky.
+#[derive(Clone, Copy, Debug)]
+pub struct ClearBrush {
+    pub extent: VoxelUnits<Extent<IVec3>>,
+}
+
+impl Brush for ClearBrush {
+    fn extent(&self) -> VoxelUnits<Extent<IVec3>> {
+        self.extent
+    }
+
+    fn paint(&self, _p: VoxelUnits<IVec3>, sdf: &mut Sd8, palette_id: &mut PaletteId8) {
+        *sdf = AMBIENT_SD8;
+        *palette_id = 0;
+    }
+}
+
+/// The fraction of a [`CraterBrush`]'s radius over which its rim is blended into the terrain.
+pub const CRATER_FALLOFF: f32 = 0.25;
+
+/// Moves the rim of a [`CraterBrush`] in and out, so craters don't look like perfect spheres.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CraterNoise {
+    /// Sampled at LOD0 voxel coordinates.
+    pub fbm: Fbm,
+    pub seed: u32,
+    /// The farthest that the rim moves from the sphere.
+    pub amplitude: VoxelUnits<f32>,
+}
+
+impl Default for CraterNoise {
+    fn default() -> Self {
+        Self {
+            fbm: Fbm {
+                frequency: 1.0 / 8.0,
+                ..Default::default()
+            },
+            seed: 0,
+            amplitude: VoxelUnits(1.0),
+        }
+    }
+}
+
+/// Carves a sphere with a noisy rim out of the terrain, like an explosion. The rim is blended into the terrain over the outer
+/// [`CRATER_FALLOFF`] of the radius.
+///
+/// [`ChunkClipMap::carve_sphere_with_falloff`](crate::clipmap::ChunkClipMap::carve_sphere_with_falloff) applies it right
+/// away and also returns the [`Debris`](crate::clipmap::Debris).
+#[derive(Clone, Copy, Debug)]
+pub struct CraterBrush {
+    pub sphere: VoxelUnits<Sphere>,
+    pub noise: CraterNoise,
+}
+
+impl CraterBrush {
+    fn falloff(&self) -> VoxelUnits<f32> {
+        VoxelUnits(CRATER_FALLOFF * self.sphere.0.radius.max(0.0))
+    }
+}
+
+impl Brush for CraterBrush {
+    fn extent(&self) -> VoxelUnits<Extent<IVec3>> {
+        let VoxelUnits(sphere) = self.sphere;
+        let VoxelUnits(amplitude) = self.noise.amplitude;
+        let grown = Sphere::new(sphere.center, sphere.radius + amplitude.abs());
+        VoxelUnits(sdf_extent(grown.aabb(), self.falloff()))
+    }
+
+    fn paint(&self, p: VoxelUnits<IVec3>, sdf: &mut Sd8, palette_id: &mut PaletteId8) {
+        let VoxelUnits(sphere) = self.sphere;
+        let VoxelUnits(amplitude) = self.noise.amplitude;
+        let rim = sphere.radius + amplitude * self.noise.fbm.sample_grid3(self.noise.seed, p.0);
+        let shape_dist = voxel_position(p).distance(sphere.center) - rim;
+        blend(
+            BlendMode::Subtract,
+            self.falloff(),
+            *palette_id,
+            shape_dist,
+            sdf,
+            palette_id,
+        );
+    }
+}
+
+fn voxel_position(p: VoxelUnits<IVec3>) -> Vec3A {
+    p.0.as_vec3a()
+}
+
+/// All voxels whose SDF could be changed by a shape with bounding box `aabb`.
+///
+/// [`Sd8`] saturates one voxel away from the surface, so we only need to look one voxel past the blend region.
+fn sdf_extent(aabb: Extent<Vec3A>, smoothness: VoxelUnits<f32>) -> Extent<IVec3> {
+    let VoxelUnits(smoothness) = smoothness;
+    let margin = Vec3A::splat(1.0 + smoothness.max(0.0));
+    Extent::from_min_and_max(
+        (aabb.minimum - margin).floor().as_ivec3(),
+        (aabb.least_upper_bound() + margin).ceil().as_ivec3(),
+    )
+}
+
+fn blend(
+    mode: BlendMode,
+    smoothness: VoxelUnits<f32>,
+    brush_palette_id: PaletteId8,
+    shape_dist: f32,
+    sdf: &mut Sd8,
+    palette_id: &mut PaletteId8,
+) {
+    let VoxelUnits(k) = smoothness;
+    let old_dist = f32::from(*sdf);
+    let new_dist = match mode {
+        BlendMode::Add => {
+            let new_dist = smooth_min(old_dist, shape_dist, k);
+            if new_dist < 0.0 && shape_dist < old_dist {
+                *palette_id = brush_palette_id;
+            }
+            new_dist
+        }
+        BlendMode::Subtract => -smooth_min(-old_dist, shape_dist, k),
+    };
+    // Don't requantize voxels that the shape didn't affect.
+    if new_dist != old_dist {
+        *sdf = Sd8::from(new_dist);
+    }
+}
+
+/// Polynomial smooth minimum, as described by [Inigo Quilez](https://iquilezles.org/articles/smin/).
+fn smooth_min(a: f32, b: f32, k: f32) -> f32 {
+    if k <= 0.0 {
+        return a.min(b);
+    }
+    let h = (0.5 + 0.5 * (b - a) / k).clamp(0.0, 1.0);
+    b + h * (a - b) - k * h * (1.0 - h)
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sphere_brush(mode: BlendMode, smoothness: f32) -> SphereBrush {
+        SphereBrush {
+            sphere: VoxelUnits(Sphere::new(Vec3A::ZERO, 4.0)),
+            mode,
+            smoothness: VoxelUnits(smoothness),
+            palette_id: 3,
+        }
+    }
+
+    fn paint_at(
+        brush: &impl Brush,
+        p: IVec3,
+        mut sdf: Sd8,
+        mut palette_id: PaletteId8,
+    ) -> (Sd8, PaletteId8) {
+        brush.paint(VoxelUnits(p), &mut sdf, &mut palette_id);
+        (sdf, palette_id)
+    }
+
+    #[test]
+    fn sphere_add_fills_inside() {
+        let brush = sphere_brush(BlendMode::Add, 0.0);
+
+        assert_eq!(paint_at(&brush, IVec3::ZERO, AMBIENT_SD8, 0), (Sd8::MIN, 3));
+        // Outside of the sphere, ambient voxels are unchanged.
+        assert_eq!(
+            paint_at(&brush, IVec3::new(6, 0, 0), AMBIENT_SD8, 0),
+            (AMBIENT_SD8, 0)
+        );
+        // And so is the existing terrain.
+        assert_eq!(
+            paint_at(&brush, IVec3::new(6, 0, 0), Sd8::MIN, 1),
+            (Sd8::MIN, 1)
+        );
+    }
+
+    #[test]
+    fn sphere_subtract_carves_inside() {
+        let brush = sphere_brush(BlendMode::Subtract, 0.0);
+
+        let (sdf, _) = paint_at(&brush, IVec3::ZERO, Sd8::MIN, 1);
+        assert_eq!(sdf, Sd8::MAX);
+        assert_eq!(
+            paint_at(&brush, IVec3::new(6, 0, 0), Sd8::MIN, 1),
+            (Sd8::MIN, 1)
+        );
+    }
+
+    #[test]
+    fn smooth_blend_extends_past_shape() {
+        // Just outside of the sphere, a sharp union doesn't change the terrain, but a smooth union pulls the surface outward.
+        let p = IVec3::new(0, 0, 5);
+        let old = Sd8::ZERO;
+        let (sharp, _) = paint_at(&sphere_brush(BlendMode::Add, 0.0), p, old, 0);
+        let (smooth, _) = paint_at(&sphere_brush(BlendMode::Add, 2.0), p, old, 0);
+        assert_eq!(sharp, old);
+        assert!(smooth.0 < old.0);
+
+        // The extent covers the blend region.
+        let VoxelUnits(extent) = sphere_brush(BlendMode::Add, 2.0).extent();
+        assert!(extent.contains(IVec3::new(0, 0, 6)));
+    }
+
+    #[test]
+    fn box_add_fills_inside() {
+        let brush = BoxBrush {
+            aabb: VoxelUnits(Extent::from_min_and_shape(
+                Vec3A::ZERO,
+                Vec3A::new(4.0, 2.0, 2.0),
+            )),
+            mode: BlendMode::Add,
+            smoothness: VoxelUnits(0.0),
+            palette_id: 2,
+        };
+
+        assert_eq!(
+            paint_at(&brush, IVec3::new(2, 1, 1), AMBIENT_SD8, 0),
+            (Sd8::MIN, 2)
+        );
+        assert_eq!(
+            paint_at(&brush, IVec3::new(2, 4, 1), AMBIENT_SD8, 0),
+            (AMBIENT_SD8, 0)
+        );
+    }
+
+    #[test]
+    fn paint_only_changes_solid_voxels() {
+        let brush = PaintBrush {
+            sphere: VoxelUnits(Sphere::new(Vec3A::ZERO, 4.0)),
+            palette_id: 9,
+        };
+
+        assert_eq!(paint_at(&brush, IVec3::ZERO, Sd8::MIN, 1), (Sd8::MIN, 9));
+        assert_eq!(
+            paint_at(&brush, IVec3::ZERO, AMBIENT_SD8, 1),
+            (AMBIENT_SD8, 1)
+        );
+    }
+
+    #[test]
+    fn crater_noise_moves_the_rim() {
+        let smooth = CraterBrush {
+            sphere: VoxelUnits(Sphere::new(Vec3A::ZERO, 8.0)),
+            noise: CraterNoise {
+                amplitude: VoxelUnits(0.0),
+                ..Default::default()
+            },
+        };
+        let noisy = CraterBrush {
+            noise: CraterNoise {
+                amplitude: VoxelUnits(3.0),
+                ..Default::default()
+            },
+            ..smooth
+        };
+
+        // The middle is carved either way, and materials are kept.
+        assert_eq!(paint_at(&noisy, IVec3::ZERO, Sd8::MIN, 2), (Sd8::MAX, 2));
+        // Somewhere near the rim, the noise changes the result.
+        let differs = (5..12).any(|x| {
+            let p = IVec3::new(x, 0, 0);
+            paint_at(&smooth, p, Sd8::MIN, 2) != paint_at(&noisy, p, Sd8::MIN, 2)
+        });
+        assert!(differs);
+        let VoxelUnits(extent) = noisy.extent();
+        assert!(extent.contains(IVec3::new(11, 0, 0)));
+    }
+}
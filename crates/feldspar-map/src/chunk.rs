@@ -5,28 +5,111 @@ use crate::core::static_assertions::const_assert_eq;
 use crate::sampling::OctantKernel;
 use crate::{coordinates::*, ndview::NdView, palette::PaletteId8, sdf::Sd8, units::*};
 
-use bytemuck::{bytes_of, bytes_of_mut, Pod, Zeroable};
+use bytemuck::{pod_read_unaligned, Pod, Zeroable};
 use grid_ray::GridRayIter3;
-use lz4_flex::frame::{FrameDecoder, FrameEncoder};
 use ndshape::{ConstPow2Shape3i32, ConstShape, ConstShape3i32};
-use std::io;
 use std::mem;
 
+mod compression;
+mod delta;
+mod fluid;
+mod layers;
+mod light;
+mod material_weights;
+mod occupancy;
+mod paletted;
+
+pub use compression::{ChunkEncoding, CompressionCodec};
+pub use delta::ChunkDelta;
+pub use fluid::*;
+pub use layers::*;
+pub use light::*;
+pub use material_weights::*;
+pub use occupancy::*;
+pub use paletted::*;
+
+use compression::{
+    decode_channel, decode_stream, decode_uniform, encode_channels, encode_stream, encode_uniform,
+    split_channels, CodecKind,
+};
+
+#[cfg(all(feature = "chunk_edge_32", feature = "chunk_edge_64"))]
+compile_error!("Only one of the chunk_edge_32 and chunk_edge_64 features can be enabled");
+
+/// The log2 of [`CHUNK_EDGE`].
+#[cfg(not(any(feature = "chunk_edge_32", feature = "chunk_edge_64")))]
+pub const CHUNK_EDGE_LOG2: i32 = 4;
+/// The log2 of [`CHUNK_EDGE`].
+#[cfg(feature = "chunk_edge_32")]
+pub const CHUNK_EDGE_LOG2: i32 = 5;
+/// The log2 of [`CHUNK_EDGE`].
+#[cfg(feature = "chunk_edge_64")]
+pub const CHUNK_EDGE_LOG2: i32 = 6;
+/// The number of voxels along each edge of a chunk. 16 unless the crate is built with the `chunk_edge_32` or `chunk_edge_64`
+/// feature; see [`ChunkEdge`].
+pub const CHUNK_EDGE: i32 = 1 << CHUNK_EDGE_LOG2;
+
 /// The standard 3D array shape for chunks.
-pub type ChunkShape = ConstPow2Shape3i32<4, 4, 4>;
-const_assert_eq!(ChunkShape::SIZE, 16 * 16 * 16);
+pub type ChunkShape =
+    ConstPow2Shape3i32<{ CHUNK_EDGE_LOG2 }, { CHUNK_EDGE_LOG2 }, { CHUNK_EDGE_LOG2 }>;
+const_assert_eq!(ChunkShape::SIZE, CHUNK_EDGE * CHUNK_EDGE * CHUNK_EDGE);
 pub const CHUNK_SIZE: usize = ChunkShape::SIZE as usize;
+/// An upper bound on the number of bytes of any [`CompressedChunk`], with room to spare, since no codec grows incompressible
+/// data by more than a few percent. Lengths read from files are checked against it before anything is allocated.
+pub const MAX_COMPRESSED_CHUNK_BYTES: usize = 2 * mem::size_of::<Chunk>();
 pub const CHUNK_SHAPE_IVEC3: IVec3 = const_ivec3!(ChunkShape::ARRAY);
-pub const CHUNK_SHAPE_VEC3A: Vec3A = const_vec3a!([16.0; 3]);
-pub const CHUNK_SHAPE_LOG2_IVEC3: IVec3 = const_ivec3!([4; 3]);
-pub const HALF_CHUNK_SHAPE_LOG2_IVEC3: IVec3 = const_ivec3!([3; 3]);
-pub const HALF_CHUNK_EDGE_LENGTH: i32 = 8;
+pub const CHUNK_SHAPE_VEC3A: Vec3A = const_vec3a!([CHUNK_EDGE as f32; 3]);
+pub const CHUNK_SHAPE_LOG2_IVEC3: IVec3 = const_ivec3!([CHUNK_EDGE_LOG2; 3]);
+pub const HALF_CHUNK_SHAPE_LOG2_IVEC3: IVec3 = const_ivec3!([CHUNK_EDGE_LOG2 - 1; 3]);
+pub const HALF_CHUNK_EDGE_LENGTH: i32 = CHUNK_EDGE / 2;
+
+/// The edge lengths (in voxels) that a map's chunks can be created with, recorded in the database header by
+/// [`MapDb::create`](crate::database::MapDb::create).
+///
+/// Small chunks suit highly dynamic worlds, since every edit rewrites, recompresses, and remeshes less, and big chunks suit
+/// static terrain, since there are fewer nodes and database records. The clipmap math, meshing, and compression are all
+/// built around the constant [`ChunkShape`], so the edge is chosen when the crate is built, with the `chunk_edge_32` and
+/// `chunk_edge_64` features, and a map can only be created or opened with [`ChunkEdge::COMPILED`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChunkEdge {
+    Edge16,
+    Edge32,
+    Edge64,
+}
+
+impl ChunkEdge {
+    /// The edge length of [`ChunkShape`].
+    pub const COMPILED: Self = match Self::from_length(CHUNK_EDGE as u32) {
+        Some(edge) => edge,
+        None => panic!("ChunkShape has an unsupported edge length"),
+    };
+
+    pub const fn length(&self) -> u32 {
+        match self {
+            Self::Edge16 => 16,
+            Self::Edge32 => 32,
+            Self::Edge64 => 64,
+        }
+    }
 
+    pub const fn from_length(length: u32) -> Option<Self> {
+        match length {
+            16 => Some(Self::Edge16),
+            32 => Some(Self::Edge32),
+            64 => Some(Self::Edge64),
+            _ => None,
+        }
+    }
+}
+
+/// The number of voxels along each edge of a padded chunk, with one voxel of padding on each side.
+pub const PADDED_CHUNK_EDGE: i32 = CHUNK_EDGE + 2;
 /// The shape (in voxels) of a padded chunk, i.e. the full set of voxels necessary to produce a chunk mesh.
-pub type PaddedChunkShape = ConstShape3i32<18, 18, 18>;
+pub type PaddedChunkShape =
+    ConstShape3i32<{ PADDED_CHUNK_EDGE }, { PADDED_CHUNK_EDGE }, { PADDED_CHUNK_EDGE }>;
 /// [`IVec3`] version of [`PaddedChunkShape`].
 pub const PADDED_CHUNK_SHAPE_IVEC3: IVec3 = const_ivec3!(PaddedChunkShape::ARRAY);
-pub const PADDED_CHUNK_SHAPE_VEC3A: Vec3A = const_vec3a!([18.0; 3]);
+pub const PADDED_CHUNK_SHAPE_VEC3A: Vec3A = const_vec3a!([PADDED_CHUNK_EDGE as f32; 3]);
 pub const PADDED_CHUNK_SIZE: usize = PaddedChunkShape::SIZE as usize;
 
 /// "As far *outside* of the terrain surface as possible."
@@ -53,13 +136,42 @@ impl Default for Chunk {
     }
 }
 
-const_assert_eq!(mem::size_of::<Chunk>(), 8192);
+const_assert_eq!(mem::size_of::<Chunk>(), 2 * CHUNK_SIZE);
 
 pub type SdfChunk = [Sd8; CHUNK_SIZE];
 pub type PaletteIdChunk = [PaletteId8; CHUNK_SIZE];
 
-const_assert_eq!(mem::size_of::<SdfChunk>(), 4096);
-const_assert_eq!(mem::size_of::<PaletteIdChunk>(), 4096);
+const_assert_eq!(mem::size_of::<SdfChunk>(), CHUNK_SIZE);
+const_assert_eq!(mem::size_of::<PaletteIdChunk>(), CHUNK_SIZE);
+
+/// A chunk where every voxel is the same, either entirely outside or entirely inside of the terrain. These are very common far
+/// from the surface, so they are stored as tiny sentinels instead of full chunks: they skip compression, and they have no
+/// meshes or colliders unless a neighbor has a surface.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UniformChunk {
+    /// Same as [`Chunk::default`].
+    Air,
+    /// Every voxel has [`Sd8::MIN`] and the same palette ID.
+    Solid { palette_id: PaletteId8 },
+}
+
+impl UniformChunk {
+    pub fn to_chunk(self) -> Chunk {
+        match self {
+            Self::Air => Chunk::default(),
+            Self::Solid { palette_id } => Chunk {
+                sdf: [Sd8::MIN; CHUNK_SIZE],
+                palette_ids: [palette_id; CHUNK_SIZE],
+            },
+        }
+    }
+
+    pub fn compress(self) -> CompressedChunk {
+        CompressedChunk {
+            bytes: encode_uniform(self).into_boxed_slice(),
+        }
+    }
+}
 
 impl Chunk {
     pub fn sdf_view(&self) -> NdView<Sd8, &SdfChunk, ChunkShape> {
@@ -84,24 +196,114 @@ impl Chunk {
         self.palette_ids[index] = palette_id;
     }
 
+    /// Compresses with the default [`CompressionCodec`].
     pub fn compress(&self) -> CompressedChunk {
-        let mut encoder = FrameEncoder::new(Vec::new());
-        let mut reader = bytes_of(self);
-        io::copy(&mut reader, &mut encoder).unwrap();
+        self.compress_with(CompressionCodec::default())
+    }
+
+    pub fn compress_with(&self, codec: CompressionCodec) -> CompressedChunk {
+        self.compress_as(ChunkEncoding::Dense, codec)
+    }
+
+    /// Returns the [`UniformChunk`] equal to `self`, if there is one.
+    pub fn uniform(&self) -> Option<UniformChunk> {
+        let palette_id = self.palette_ids[0];
+        if self.palette_ids.iter().any(|&id| id != palette_id) {
+            return None;
+        }
+        let uniform = if self.sdf.iter().all(|&d| d == AMBIENT_SD8) && palette_id == 0 {
+            UniformChunk::Air
+        } else if self.sdf.iter().all(|&d| d == Sd8::MIN) {
+            UniformChunk::Solid { palette_id }
+        } else {
+            return None;
+        };
+        Some(uniform)
+    }
+
+    /// Compresses the chunk after converting it to `encoding`. [`ChunkEncoding::Paletted`] falls back to
+    /// [`ChunkEncoding::Dense`] when the palette would be larger than the dense chunk.
+    ///
+    /// [`UniformChunk`]s are always stored as sentinels, regardless of `encoding` and `codec`.
+    pub fn compress_as(&self, encoding: ChunkEncoding, codec: CompressionCodec) -> CompressedChunk {
+        if let Some(uniform) = self.uniform() {
+            return uniform.compress();
+        }
+
+        if encoding == ChunkEncoding::Paletted {
+            let paletted_bytes = PalettedChunk::from_chunk(self).to_bytes();
+            if paletted_bytes.len() < mem::size_of::<Chunk>() {
+                return CompressedChunk {
+                    bytes: encode_stream(&paletted_bytes, codec).into_boxed_slice(),
+                };
+            }
+        }
+
+        let channels = [bytemuck::cast_slice(&self.sdf[..]), &self.palette_ids[..]];
         CompressedChunk {
-            bytes: encoder.finish().unwrap().into_boxed_slice(),
+            bytes: encode_channels(channels, codec).into_boxed_slice(),
         }
     }
 
+    /// Decompresses `bytes` written with any [`ChunkEncoding`] and [`CompressionCodec`], which are detected from the bytes.
+    ///
+    /// # Panics
+    ///
+    /// If `bytes` are corrupt. Use [`Self::try_from_compressed_bytes`] for bytes that weren't verified, e.g. from storage.
     pub fn from_compressed_bytes(bytes: &[u8]) -> Chunk {
-        let mut chunk = Chunk {
-            sdf: [Sd8(0); CHUNK_SIZE],
-            palette_ids: [0; CHUNK_SIZE],
-        };
-        let mut decoder = FrameDecoder::new(bytes);
-        let mut writer = bytes_of_mut(&mut chunk);
-        io::copy(&mut decoder, &mut writer).unwrap();
-        chunk
+        Self::try_from_compressed_bytes(bytes).expect("Corrupt compressed chunk")
+    }
+
+    /// Same as [`Self::from_compressed_bytes`], but returns `None` if `bytes` are corrupt.
+    pub fn try_from_compressed_bytes(bytes: &[u8]) -> Option<Chunk> {
+        if let Some(uniform) = decode_uniform(bytes) {
+            return Some(uniform.to_chunk());
+        }
+
+        let mut chunk = Chunk::default();
+        if let Some([sdf, palette_ids]) = split_channels(bytes) {
+            decode_channel(sdf, bytemuck::cast_slice_mut(&mut chunk.sdf[..]))?;
+            decode_channel(palette_ids, &mut chunk.palette_ids)?;
+            return Some(chunk);
+        }
+
+        // Dense chunks used to be compressed as a single stream, and they're always larger than paletted chunks.
+        let payload = decode_stream(bytes, mem::size_of::<Chunk>())?;
+        if payload.len() == mem::size_of::<Chunk>() {
+            Some(pod_read_unaligned(&payload))
+        } else {
+            PalettedChunk::from_bytes(&payload).map(|paletted| paletted.to_chunk())
+        }
+    }
+
+    /// Same as [`Self::try_from_compressed_bytes`], but only the SDF channel is decompressed when it's stored separately.
+    pub fn try_sdf_from_compressed_bytes(bytes: &[u8]) -> Option<SdfChunk> {
+        if let Some(uniform) = decode_uniform(bytes) {
+            Some(match uniform {
+                UniformChunk::Air => [AMBIENT_SD8; CHUNK_SIZE],
+                UniformChunk::Solid { .. } => [Sd8::MIN; CHUNK_SIZE],
+            })
+        } else if let Some([sdf_bytes, _]) = split_channels(bytes) {
+            let mut sdf = [AMBIENT_SD8; CHUNK_SIZE];
+            decode_channel(sdf_bytes, bytemuck::cast_slice_mut(&mut sdf[..]))?;
+            Some(sdf)
+        } else {
+            Self::try_from_compressed_bytes(bytes).map(|chunk| chunk.sdf)
+        }
+    }
+
+    /// Same as [`Self::try_from_compressed_bytes`], but only the palette ID channel is decompressed when it's stored
+    /// separately.
+    pub fn try_palette_ids_from_compressed_bytes(bytes: &[u8]) -> Option<PaletteIdChunk> {
+        if let Some(uniform) = decode_uniform(bytes) {
+            Some(uniform.to_chunk().palette_ids)
+        } else if let Some([_, palette_id_bytes]) = split_channels(bytes) {
+            let mut palette_ids = [0; CHUNK_SIZE];
+            decode_channel(palette_id_bytes, &mut palette_ids)?;
+            Some(palette_ids)
+        } else {
+            Self::try_from_compressed_bytes(bytes).map(|chunk| chunk.palette_ids)
+        }
     }
 
     /// Downsamples the SDF and palette IDs from `self` at half resolution into one octant of a parent chunk.
@@ -183,6 +385,134 @@ impl CompressedChunk {
     pub fn decompress(&self) -> Chunk {
         Chunk::from_compressed_bytes(&self.bytes)
     }
+
+    /// Returns `None` instead of panicking if the bytes are corrupt.
+    pub fn try_decompress(&self) -> Option<Chunk> {
+        Chunk::try_from_compressed_bytes(&self.bytes)
+    }
+
+    /// Checks for a [`UniformChunk`] sentinel without decompressing.
+    pub fn uniform(&self) -> Option<UniformChunk> {
+        decode_uniform(&self.bytes)
+    }
+
+    /// Re-encodes the chunk with `encoding` and `codec`, unless it's already compressed with the same kind of codec. The
+    /// encoding isn't checked, since that would require decompressing.
+    pub fn recompress(self, encoding: ChunkEncoding, codec: CompressionCodec) -> Self {
+        let kind = CodecKind::detect(&self.bytes);
+        if kind.map_or(true, |k| k == CodecKind::from(codec)) {
+            self
+        } else {
+            self.decompress().compress_as(encoding, codec)
+        }
+    }
+
+    /// Decompresses straight from the archived bytes, e.g. from a database read, without first deserializing into an owned
+    /// [`CompressedChunk`].
+    pub fn decompress_from_archived(archived: &ArchivedCompressedChunk) -> Chunk {
+        Chunk::from_compressed_bytes(&archived.bytes)
+    }
+
+    /// Decompresses only the SDF, e.g. for physics, which doesn't need materials. Returns `None` if the bytes are corrupt.
+    pub fn try_decompress_sdf(&self) -> Option<SdfChunk> {
+        Chunk::try_sdf_from_compressed_bytes(&self.bytes)
+    }
+
+    pub fn try_decompress_palette_ids(&self) -> Option<PaletteIdChunk> {
+        Chunk::try_palette_ids_from_compressed_bytes(&self.bytes)
+    }
+}
+
+impl ArchivedCompressedChunk {
+    pub fn decompress(&self) -> Chunk {
+        CompressedChunk::decompress_from_archived(self)
+    }
+
+    pub fn try_decompress(&self) -> Option<Chunk> {
+        Chunk::try_from_compressed_bytes(&self.bytes)
+    }
+
+    pub fn uniform(&self) -> Option<UniformChunk> {
+        decode_uniform(&self.bytes)
+    }
+
+    pub fn try_decompress_sdf(&self) -> Option<SdfChunk> {
+        Chunk::try_sdf_from_compressed_bytes(&self.bytes)
+    }
+
+    pub fn try_decompress_palette_ids(&self) -> Option<PaletteIdChunk> {
+        Chunk::try_palette_ids_from_compressed_bytes(&self.bytes)
+    }
+}
+
+/// A buffer that holds the bytes of a [`CompressedChunk`], e.g. the database record that the chunk was read from.
+pub trait CompressedBytes: Send + Sync {
+    fn compressed_bytes(&self) -> &[u8];
+}
+
+impl CompressedBytes for CompressedChunk {
+    fn compressed_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// A compressed chunk whose bytes are still in the buffer they were read from, so loading it doesn't copy them.
+///
+/// The [`ChunkClipMap`](crate::clipmap::ChunkClipMap) keeps a mapped chunk until it's decompressed for a read or taken out
+/// of its node, e.g. to be replaced by a write, at which point it's copied into an owned [`CompressedChunk`].
+pub struct MappedChunk {
+    buffer: Box<dyn CompressedBytes>,
+}
+
+const_assert_eq!(mem::size_of::<MappedChunk>(), 2 * mem::size_of::<usize>());
+
+impl MappedChunk {
+    pub fn new(buffer: impl CompressedBytes + 'static) -> Self {
+        Self {
+            buffer: Box::new(buffer),
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        self.buffer.compressed_bytes()
+    }
+
+    pub fn decompress(&self) -> Chunk {
+        Chunk::from_compressed_bytes(self.bytes())
+    }
+
+    pub fn try_decompress(&self) -> Option<Chunk> {
+        Chunk::try_from_compressed_bytes(self.bytes())
+    }
+
+    pub fn uniform(&self) -> Option<UniformChunk> {
+        decode_uniform(self.bytes())
+    }
+
+    pub fn try_decompress_sdf(&self) -> Option<SdfChunk> {
+        Chunk::try_sdf_from_compressed_bytes(self.bytes())
+    }
+
+    /// Copies the bytes out of the buffer.
+    pub fn to_compressed(&self) -> CompressedChunk {
+        CompressedChunk {
+            bytes: self.bytes().into(),
+        }
+    }
+}
+
+impl From<CompressedChunk> for MappedChunk {
+    fn from(compressed: CompressedChunk) -> Self {
+        Self::new(compressed)
+    }
+}
+
+impl std::fmt::Debug for MappedChunk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MappedChunk")
+            .field("len", &self.bytes().len())
+            .finish()
+    }
 }
 
 // ████████╗███████╗███████╗████████╗
@@ -195,8 +525,21 @@ impl CompressedChunk {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::core::ilattice::prelude::Extent;
     use crate::{chunk::AMBIENT_SD8, coordinates::chunk_extent_from_min_ivec3, units::VoxelUnits};
 
+    #[test]
+    fn mapped_chunk_reads_the_same_bytes() {
+        let mut chunk = Chunk::default();
+        chunk.set_voxel(IVec3::new(1, 2, 3), 4, Sd8::MIN);
+        let compressed = chunk.compress();
+        let mapped = MappedChunk::from(compressed.clone());
+        assert_eq!(mapped.bytes(), &*compressed.bytes);
+        assert_eq!(mapped.decompress(), chunk);
+        assert_eq!(mapped.uniform(), None);
+        assert_eq!(mapped.to_compressed(), compressed);
+    }
+
     #[test]
     fn compress_default_chunk() {
         let chunk = Chunk::default();
@@ -226,6 +569,146 @@ mod test {
         assert_eq!(compressed.decompress(), chunk);
     }
 
+    #[test]
+    fn decompress_any_codec() {
+        let mut chunk = Chunk::default();
+        chunk.set_voxel(IVec3::new(4, 5, 6), 3, Sd8::MIN);
+
+        let codecs = [
+            CompressionCodec::Lz4,
+            CompressionCodec::Zstd { level: 3 },
+            CompressionCodec::None,
+        ];
+        for codec in codecs {
+            let compressed = chunk.compress_with(codec);
+            assert_eq!(compressed.decompress(), chunk, "{:?}", codec);
+            for other_codec in codecs {
+                assert_eq!(
+                    compressed
+                        .clone()
+                        .recompress(ChunkEncoding::Dense, other_codec)
+                        .decompress(),
+                    chunk
+                );
+            }
+        }
+
+        let zstd = chunk.compress_with(CompressionCodec::Zstd { level: 19 });
+        assert!(zstd.bytes.len() < chunk.compress().bytes.len());
+    }
+
+    #[test]
+    fn compress_paletted_blocky_chunk() {
+        let mut chunk = Chunk::default();
+        for p in Extent::from_min_and_shape(IVec3::ZERO, IVec3::new(16, 5, 16)).iter3() {
+            chunk.set_voxel(p, 1 + (p.y % 2) as PaletteId8, Sd8::MIN);
+        }
+
+        let paletted = chunk.compress_as(ChunkEncoding::Paletted, CompressionCodec::None);
+        assert!(paletted.bytes.len() < mem::size_of::<Chunk>() / 7);
+        assert_eq!(paletted.decompress(), chunk);
+        let paletted = chunk.compress_as(ChunkEncoding::Paletted, CompressionCodec::default());
+        assert_eq!(paletted.decompress(), chunk);
+
+        // Every voxel is distinct, so this falls back to the dense encoding.
+        let mut distinct = Chunk::default();
+        for i in 0..CHUNK_SIZE {
+            distinct.sdf[i] = Sd8((i % 256) as u8 as i8);
+            distinct.palette_ids[i] = (i / 256) as PaletteId8;
+        }
+        let compressed = distinct.compress_as(ChunkEncoding::Paletted, CompressionCodec::None);
+        assert_eq!(compressed.bytes.len(), 8 + 2 * (4 + CHUNK_SIZE));
+        assert_eq!(compressed.decompress(), distinct);
+    }
+
+    #[test]
+    fn decompress_channels_independently() {
+        let mut chunk = Chunk::default();
+        for (i, sdf) in chunk.sdf.iter_mut().enumerate() {
+            *sdf = Sd8((i % 64) as i8 - 32);
+        }
+
+        let compressed = chunk.compress();
+        assert_eq!(compressed.try_decompress_sdf(), Some(chunk.sdf));
+        assert_eq!(
+            compressed.try_decompress_palette_ids(),
+            Some(chunk.palette_ids)
+        );
+        assert_eq!(compressed.decompress(), chunk);
+
+        // The palette IDs are homogeneous, so they take a few bytes.
+        let sdf_only = chunk.compress_with(CompressionCodec::None);
+        assert_eq!(sdf_only.bytes.len(), 8 + (4 + CHUNK_SIZE) + 5);
+
+        // Chunks compressed as a single LZ4 stream can still be read.
+        let mut encoder = lz4_flex::frame::FrameEncoder::new(Vec::new());
+        std::io::copy(&mut bytemuck::bytes_of(&chunk), &mut encoder).unwrap();
+        let legacy_bytes = encoder.finish().unwrap();
+        assert_eq!(Chunk::from_compressed_bytes(&legacy_bytes), chunk);
+        assert_eq!(
+            Chunk::try_sdf_from_compressed_bytes(&legacy_bytes),
+            Some(chunk.sdf)
+        );
+    }
+
+    #[test]
+    fn try_decompress_corrupt_bytes() {
+        let mut chunk = Chunk::default();
+        for (i, sdf) in chunk.sdf.iter_mut().enumerate() {
+            *sdf = Sd8((i % 64) as i8 - 32);
+        }
+
+        for codec in [
+            CompressionCodec::default(),
+            CompressionCodec::Zstd { level: 3 },
+            CompressionCodec::None,
+        ] {
+            let compressed = chunk.compress_with(codec);
+            assert_eq!(compressed.try_decompress(), Some(chunk));
+
+            let truncated = CompressedChunk {
+                bytes: compressed.bytes[..compressed.bytes.len() / 2].into(),
+            };
+            assert_eq!(truncated.try_decompress(), None, "{:?}", codec);
+            assert_eq!(truncated.try_decompress_sdf(), None, "{:?}", codec);
+        }
+        let garbage = CompressedChunk {
+            bytes: vec![0xAB; 64].into(),
+        };
+        assert_eq!(garbage.try_decompress(), None);
+    }
+
+    #[test]
+    fn uniform_chunks_compress_to_sentinels() {
+        let solid = UniformChunk::Solid { palette_id: 4 };
+        for uniform in [UniformChunk::Air, solid] {
+            let chunk = uniform.to_chunk();
+            assert_eq!(chunk.uniform(), Some(uniform));
+
+            let compressed = chunk.compress_with(CompressionCodec::Zstd { level: 3 });
+            assert!(compressed.bytes.len() <= 6);
+            assert_eq!(compressed.uniform(), Some(uniform));
+            assert_eq!(compressed.decompress(), chunk);
+            assert_eq!(compressed.try_decompress_sdf(), Some(chunk.sdf));
+        }
+
+        let mut chunk = solid.to_chunk();
+        chunk.set_voxel(IVec3::new(1, 2, 3), 5, Sd8::MIN);
+        assert_eq!(chunk.uniform(), None);
+        assert_eq!(chunk.compress().uniform(), None);
+    }
+
+    #[test]
+    fn decompress_from_archived_bytes() {
+        let mut chunk = Chunk::default();
+        chunk.set_voxel(IVec3::new(1, 2, 3), 7, Sd8::MIN);
+        let compressed = chunk.compress();
+
+        let bytes = crate::core::rkyv::to_bytes::<_, 256>(&compressed).unwrap();
+        let archived = unsafe { crate::core::rkyv::archived_root::<CompressedChunk>(&bytes) };
+        assert_eq!(CompressedChunk::decompress_from_archived(archived), chunk);
+    }
+
     #[test]
     fn ray_intersections_pass_through() {
         let ray = Ray::new(Vec3A::new(-0.5, 0.5, 0.5), Vec3A::new(1.0, 0.0, 0.0));
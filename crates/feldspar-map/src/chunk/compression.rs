@@ -0,0 +1,219 @@
+use super::UniformChunk;
+use crate::core::rkyv::{Archive, Deserialize, Serialize};
+
+use lz4_flex::frame::{FrameDecoder, FrameEncoder};
+use std::borrow::Cow;
+use std::io;
+
+/// The algorithm used to compress chunks. Each [`MapDb`](crate::database::MapDb) stores the codec for new writes in its
+/// header, but chunks written with any codec can always be read back, so the codec can be changed at any time without
+/// rewriting the database.
+#[derive(
+    Archive,
+    Clone,
+    Copy,
+    Debug,
+    Deserialize,
+    Eq,
+    PartialEq,
+    Serialize,
+    serde::Deserialize,
+    serde::Serialize,
+)]
+#[archive(crate = "crate::core::rkyv")]
+pub enum CompressionCodec {
+    /// Fast compression and decompression with a decent ratio. `lz4_flex` only implements the default level, so there's
+    /// nothing to configure.
+    Lz4,
+    /// Slower, but smaller than LZ4, especially at high levels (1 to 22). Good for databases that are shipped over a network.
+    ///
+    /// Not available on `wasm32`, where chunks are compressed with LZ4 instead, and chunks compressed with zstd can't be
+    /// decompressed.
+    Zstd { level: i32 },
+    /// Stores the raw bytes. Only useful when the storage layer compresses on its own.
+    None,
+}
+
+impl Default for CompressionCodec {
+    fn default() -> Self {
+        Self::Lz4
+    }
+}
+
+/// The layout of chunk data before it's compressed with a [`CompressionCodec`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum ChunkEncoding {
+    /// The SDF and palette ID channels of the [`Chunk`](super::Chunk), compressed independently so either one can be
+    /// decompressed on its own.
+    Dense,
+    /// A [`PalettedChunk`](super::PalettedChunk). Much smaller for blocky worlds with only a few distinct voxels per chunk.
+    /// Both channels are needed to decode the palette.
+    Paletted,
+}
+
+impl Default for ChunkEncoding {
+    fn default() -> Self {
+        Self::Dense
+    }
+}
+
+/// Prefix of streams stored with [`CompressionCodec::None`]. LZ4 and Zstd frames start with their own magic numbers.
+const UNCOMPRESSED_MAGIC: [u8; 4] = *b"FSRC";
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+/// Prefix of chunks with separately compressed channels, followed by the length of the SDF channel.
+const CHANNELS_MAGIC: [u8; 4] = *b"FSCH";
+/// Prefix of a channel where every byte is the same, followed by that byte.
+const UNIFORM_MAGIC: [u8; 4] = *b"FSUN";
+/// Prefix of a [`UniformChunk`] sentinel, followed by `0` for air, or `1` and the palette ID for solids.
+const UNIFORM_CHUNK_MAGIC: [u8; 4] = *b"FSUC";
+
+/// A [`CompressionCodec`] without its parameters, which aren't recoverable from the compressed bytes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(super) enum CodecKind {
+    Lz4,
+    Zstd,
+    None,
+}
+
+impl CodecKind {
+    fn detect_stream(bytes: &[u8]) -> Self {
+        if bytes.starts_with(&UNCOMPRESSED_MAGIC) {
+            Self::None
+        } else if bytes.starts_with(&ZSTD_MAGIC) {
+            Self::Zstd
+        } else {
+            // Databases written before codecs were configurable only have untagged LZ4 frames.
+            Self::Lz4
+        }
+    }
+
+    /// Returns `None` if there is nothing to decompress, i.e. the chunk or all of its channels are uniform.
+    pub fn detect(bytes: &[u8]) -> Option<Self> {
+        if decode_uniform(bytes).is_some() {
+            None
+        } else if let Some(channels) = split_channels(bytes) {
+            channels
+                .into_iter()
+                .find(|c| !c.starts_with(&UNIFORM_MAGIC))
+                .map(Self::detect_stream)
+        } else {
+            Some(Self::detect_stream(bytes))
+        }
+    }
+}
+
+impl From<CompressionCodec> for CodecKind {
+    fn from(codec: CompressionCodec) -> Self {
+        match codec {
+            CompressionCodec::Lz4 => Self::Lz4,
+            CompressionCodec::Zstd { .. } => Self::Zstd,
+            CompressionCodec::None => Self::None,
+        }
+    }
+}
+
+pub(super) fn encode_stream(mut payload: &[u8], codec: CompressionCodec) -> Vec<u8> {
+    match codec {
+        CompressionCodec::Lz4 => {
+            let mut encoder = FrameEncoder::new(Vec::new());
+            io::copy(&mut payload, &mut encoder).unwrap();
+            encoder.finish().unwrap()
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        CompressionCodec::Zstd { level } => zstd::stream::encode_all(payload, level).unwrap(),
+        #[cfg(target_arch = "wasm32")]
+        CompressionCodec::Zstd { .. } => encode_stream(payload, CompressionCodec::default()),
+        CompressionCodec::None => {
+            let mut bytes = Vec::with_capacity(UNCOMPRESSED_MAGIC.len() + payload.len());
+            bytes.extend_from_slice(&UNCOMPRESSED_MAGIC);
+            bytes.extend_from_slice(payload);
+            bytes
+        }
+    }
+}
+
+/// Returns `None` if `bytes` isn't a valid stream.
+pub(super) fn decode_stream(bytes: &[u8], size_hint: usize) -> Option<Cow<'_, [u8]>> {
+    match CodecKind::detect_stream(bytes) {
+        CodecKind::Lz4 => {
+            let mut payload = Vec::with_capacity(size_hint);
+            let mut decoder = FrameDecoder::new(bytes);
+            io::copy(&mut decoder, &mut payload).ok()?;
+            Some(Cow::Owned(payload))
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        CodecKind::Zstd => zstd::stream::decode_all(bytes).ok().map(Cow::Owned),
+        #[cfg(target_arch = "wasm32")]
+        CodecKind::Zstd => None,
+        CodecKind::None => Some(Cow::Borrowed(&bytes[UNCOMPRESSED_MAGIC.len()..])),
+    }
+}
+
+/// Compresses each of `channels` independently and concatenates them. Uniform channels only take a few bytes.
+pub(super) fn encode_channels(channels: [&[u8]; 2], codec: CompressionCodec) -> Vec<u8> {
+    let [sdf, palette_ids] = channels.map(|channel| encode_channel(channel, codec));
+
+    let mut bytes = Vec::with_capacity(CHANNELS_MAGIC.len() + 4 + sdf.len() + palette_ids.len());
+    bytes.extend_from_slice(&CHANNELS_MAGIC);
+    bytes.extend_from_slice(&(sdf.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&sdf);
+    bytes.extend_from_slice(&palette_ids);
+    bytes
+}
+
+/// Compresses one channel, which can be read back with [`decode_channel`]. Uniform channels only take a few bytes.
+pub(super) fn encode_channel(channel: &[u8], codec: CompressionCodec) -> Vec<u8> {
+    if channel.iter().all(|&b| b == channel[0]) {
+        let mut bytes = UNIFORM_MAGIC.to_vec();
+        bytes.push(channel[0]);
+        bytes
+    } else {
+        encode_stream(channel, codec)
+    }
+}
+
+/// Returns the compressed SDF and palette ID channels, or `None` if the chunk wasn't compressed with [`encode_channels`].
+pub(super) fn split_channels(bytes: &[u8]) -> Option<[&[u8]; 2]> {
+    let header_len = CHANNELS_MAGIC.len() + 4;
+    if !bytes.starts_with(&CHANNELS_MAGIC) || bytes.len() < header_len {
+        return None;
+    }
+    let sdf_len = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+    let channels = &bytes[header_len..];
+    if channels.len() < sdf_len {
+        return None;
+    }
+    let (sdf, palette_ids) = channels.split_at(sdf_len);
+    Some([sdf, palette_ids])
+}
+
+/// Decompresses one channel from [`split_channels`] or [`encode_channel`] into `out`. Returns `None` if the channel is corrupt.
+pub(super) fn decode_channel(bytes: &[u8], out: &mut [u8]) -> Option<()> {
+    if let Some(rest) = bytes.strip_prefix(&UNIFORM_MAGIC) {
+        out.fill(*rest.first()?);
+    } else {
+        let payload = decode_stream(bytes, out.len())?;
+        if payload.len() != out.len() {
+            return None;
+        }
+        out.copy_from_slice(&payload);
+    }
+    Some(())
+}
+
+pub(super) fn encode_uniform(uniform: UniformChunk) -> Vec<u8> {
+    let mut bytes = UNIFORM_CHUNK_MAGIC.to_vec();
+    match uniform {
+        UniformChunk::Air => bytes.push(0),
+        UniformChunk::Solid { palette_id } => bytes.extend_from_slice(&[1, palette_id]),
+    }
+    bytes
+}
+
+pub(super) fn decode_uniform(bytes: &[u8]) -> Option<UniformChunk> {
+    match bytes.strip_prefix(&UNIFORM_CHUNK_MAGIC)? {
+        [0] => Some(UniformChunk::Air),
+        &[1, palette_id] => Some(UniformChunk::Solid { palette_id }),
+        _ => None,
+    }
+}
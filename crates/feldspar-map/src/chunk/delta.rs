@@ -0,0 +1,350 @@
+use super::{Chunk, ChunkShape, PalettedVoxel, CHUNK_SIZE};
+use crate::coordinates::chunk_min;
+use crate::core::glam::IVec3;
+use crate::core::ilattice::prelude::Extent;
+use crate::sdf::Sd8;
+use crate::units::{ChunkUnits, VoxelUnits};
+
+use ndshape::ConstShape;
+
+/// The voxels that changed between two versions of a [`Chunk`], with their values before and after the change.
+///
+/// Changed voxels are stored as runs of consecutive linear indices, so a small edit costs a few bytes per voxel instead of a
+/// whole chunk. Keeping the old values means a delta can be inverted, e.g. to roll back an edit.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ChunkDelta {
+    /// Sorted and disjoint, with at least one full voxel between consecutive runs.
+    runs: Vec<DeltaRun>,
+    /// One value per changed voxel, in the order of the runs.
+    before: Vec<PalettedVoxel>,
+    after: Vec<PalettedVoxel>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct DeltaRun {
+    start: RunIndex,
+    len: RunIndex,
+}
+
+/// Enough for the linear indices, run lengths, and number of runs of chunks of up to 32³.
+#[cfg(not(feature = "chunk_edge_64"))]
+type RunIndex = u16;
+/// Enough for the linear indices, run lengths, and number of runs of 64³ chunks.
+#[cfg(feature = "chunk_edge_64")]
+type RunIndex = u32;
+const RUN_INDEX_BYTES: usize = std::mem::size_of::<RunIndex>();
+
+impl ChunkDelta {
+    /// The voxels that differ between `old` and `new`.
+    pub fn between(old: &Chunk, new: &Chunk) -> Self {
+        Self::from_voxels((0..CHUNK_SIZE).map(|i| (i, voxel_at(old, i), voxel_at(new, i))))
+    }
+
+    /// Builds a delta from `(index, before, after)` sorted by index. Voxels that didn't change are skipped.
+    fn from_voxels(voxels: impl Iterator<Item = (usize, PalettedVoxel, PalettedVoxel)>) -> Self {
+        let mut delta = Self::default();
+        for (i, before, after) in voxels {
+            if before == after {
+                continue;
+            }
+            match delta.runs.last_mut() {
+                Some(run) if (run.start + run.len) as usize == i => run.len += 1,
+                _ => delta.runs.push(DeltaRun {
+                    start: i as RunIndex,
+                    len: 1,
+                }),
+            }
+            delta.before.push(before);
+            delta.after.push(after);
+        }
+        delta
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.runs.is_empty()
+    }
+
+    /// The number of changed voxels.
+    pub fn num_voxels(&self) -> usize {
+        self.after.len()
+    }
+
+    /// `(index, before, after)` for every changed voxel, by linear index in [`ChunkShape`].
+    fn voxels(&self) -> impl Iterator<Item = (usize, PalettedVoxel, PalettedVoxel)> + '_ {
+        self.runs
+            .iter()
+            .flat_map(|run| run.start as usize..run.start as usize + run.len as usize)
+            .zip(self.before.iter().zip(self.after.iter()))
+            .map(|(i, (&before, &after))| (i, before, after))
+    }
+
+    /// Writes the new value of every changed voxel into `chunk`.
+    pub fn apply(&self, chunk: &mut Chunk) {
+        for (i, _, after) in self.voxels() {
+            chunk.sdf[i] = after.sdf;
+            chunk.palette_ids[i] = after.palette_id;
+        }
+    }
+
+    /// The delta that undoes this one.
+    pub fn invert(&self) -> Self {
+        Self {
+            runs: self.runs.clone(),
+            before: self.after.clone(),
+            after: self.before.clone(),
+        }
+    }
+
+    /// The delta with the combined effect of applying `self`, then `next`. Voxels that end up back at their old values are
+    /// dropped.
+    pub fn then(&self, next: &Self) -> Self {
+        let mut first = self.voxels().peekable();
+        let mut second = next.voxels().peekable();
+        let merged = std::iter::from_fn(|| match (first.peek(), second.peek()) {
+            (Some(&(i, before, _)), Some(&(j, _, after))) if i == j => {
+                first.next();
+                second.next();
+                Some((i, before, after))
+            }
+            (Some(&(i, ..)), Some(&(j, ..))) if j < i => second.next(),
+            (Some(_), _) => first.next(),
+            (None, _) => second.next(),
+        });
+        Self::from_voxels(merged)
+    }
+
+    /// Replaces the old values of the voxels changed by both deltas with their new values in `base`, as if `base` had been
+    /// applied before `self`. Returns the rest of `base`.
+    ///
+    /// Voxels that `base` changes to their new values in `self` are dropped from `self`.
+    pub fn rebase(&mut self, base: &Self) -> Self {
+        let mut rest = Vec::new();
+        let mut base_voxels = base.voxels().peekable();
+        let mut rebased = Vec::with_capacity(self.num_voxels());
+        for (i, mut before, after) in self.voxels() {
+            while let Some(&(j, ..)) = base_voxels.peek() {
+                if j > i {
+                    break;
+                }
+                let (j, base_before, base_after) = base_voxels.next().unwrap();
+                if j == i {
+                    before = base_after;
+                } else {
+                    rest.push((j, base_before, base_after));
+                }
+            }
+            rebased.push((i, before, after));
+        }
+        rest.extend(base_voxels);
+        *self = Self::from_voxels(rebased.into_iter());
+        Self::from_voxels(rest.into_iter())
+    }
+
+    /// The bounding box of the changed voxels of the chunk at `coords`. Empty if nothing changed.
+    pub fn extent(&self, coords: ChunkUnits<IVec3>) -> VoxelUnits<Extent<IVec3>> {
+        let VoxelUnits(min) = chunk_min(coords);
+        if self.is_empty() {
+            return VoxelUnits(Extent::from_min_and_shape(min, IVec3::ZERO));
+        }
+        let mut lo = IVec3::splat(i32::MAX);
+        let mut hi = IVec3::splat(i32::MIN);
+        for (i, ..) in self.voxels() {
+            let p = IVec3::from(ChunkShape::delinearize(i as i32));
+            lo = lo.min(p);
+            hi = hi.max(p);
+        }
+        VoxelUnits(Extent::from_min_and_max(min + lo, min + hi))
+    }
+
+    /// The length of [`Self::to_bytes`].
+    pub fn num_bytes(&self) -> usize {
+        RUN_INDEX_BYTES * (1 + 2 * self.runs.len()) + 4 * self.num_voxels()
+    }
+
+    /// All integers are little-endian. Indices are `u32` instead of `u16` when the crate is built for 64³ chunks.
+    ///
+    /// ```text
+    /// num_runs: u16
+    /// runs:     [(start: u16, len: u16)]
+    /// values:   [(before_sdf: i8, before_palette_id: u8, after_sdf: i8, after_palette_id: u8)]
+    /// ```
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.num_bytes());
+        bytes.extend_from_slice(&(self.runs.len() as RunIndex).to_le_bytes());
+        for run in self.runs.iter() {
+            bytes.extend_from_slice(&run.start.to_le_bytes());
+            bytes.extend_from_slice(&run.len.to_le_bytes());
+        }
+        for (before, after) in self.before.iter().zip(self.after.iter()) {
+            bytes.extend_from_slice(&[
+                before.sdf.0 as u8,
+                before.palette_id,
+                after.sdf.0 as u8,
+                after.palette_id,
+            ]);
+        }
+        bytes
+    }
+
+    /// Returns `None` if `bytes` weren't written by [`Self::to_bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let read_index = |b: &[u8]| RunIndex::from_le_bytes(b.try_into().unwrap());
+        let num_runs = read_index(bytes.get(..RUN_INDEX_BYTES)?) as usize;
+        let runs_end = RUN_INDEX_BYTES * (1 + 2 * num_runs);
+        let runs: Vec<_> = bytes
+            .get(RUN_INDEX_BYTES..runs_end)?
+            .chunks_exact(2 * RUN_INDEX_BYTES)
+            .map(|run| DeltaRun {
+                start: read_index(&run[..RUN_INDEX_BYTES]),
+                len: read_index(&run[RUN_INDEX_BYTES..]),
+            })
+            .collect();
+
+        // Runs must be in bounds, nonempty, sorted, and separated, so every delta has exactly one encoding.
+        let mut end = None;
+        for run in runs.iter() {
+            let run_end = run.start as usize + run.len as usize;
+            if run.len == 0
+                || run_end > CHUNK_SIZE
+                || end.map_or(false, |end| run.start as usize <= end)
+            {
+                return None;
+            }
+            end = Some(run_end);
+        }
+        let num_voxels: usize = runs.iter().map(|run| run.len as usize).sum();
+        if bytes.len() != runs_end + 4 * num_voxels {
+            return None;
+        }
+
+        let voxel = |sdf: u8, palette_id| PalettedVoxel {
+            sdf: Sd8(sdf as i8),
+            palette_id,
+        };
+        let values = bytes[runs_end..].chunks_exact(4);
+        let before = values.clone().map(|v| voxel(v[0], v[1])).collect();
+        let after = values.map(|v| voxel(v[2], v[3])).collect();
+        Some(Self {
+            runs,
+            before,
+            after,
+        })
+    }
+}
+
+fn voxel_at(chunk: &Chunk, i: usize) -> PalettedVoxel {
+    PalettedVoxel {
+        sdf: chunk.sdf[i],
+        palette_id: chunk.palette_ids[i],
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn edited_chunk(base: &Chunk, voxels: &[(IVec3, u8)]) -> Chunk {
+        let mut chunk = *base;
+        for &(p, palette_id) in voxels.iter() {
+            chunk.set_voxel(p, palette_id, Sd8::MIN);
+        }
+        chunk
+    }
+
+    #[test]
+    fn apply_and_invert() {
+        let old = edited_chunk(&Chunk::default(), &[(IVec3::new(3, 3, 3), 1)]);
+        let new = edited_chunk(
+            &old,
+            &[
+                (IVec3::new(1, 0, 0), 2),
+                (IVec3::new(2, 0, 0), 2),
+                (IVec3::new(3, 0, 0), 2),
+                (IVec3::new(5, 9, 15), 3),
+            ],
+        );
+        let delta = ChunkDelta::between(&old, &new);
+        assert_eq!(delta.num_voxels(), 4);
+        assert_eq!(delta.runs.len(), 2);
+
+        let mut chunk = old;
+        delta.apply(&mut chunk);
+        assert_eq!(chunk, new);
+        delta.invert().apply(&mut chunk);
+        assert_eq!(chunk, old);
+
+        assert!(ChunkDelta::between(&old, &old).is_empty());
+        assert_eq!(
+            delta.extent(ChunkUnits(IVec3::new(1, 0, -1))),
+            VoxelUnits(Extent::from_min_and_max(
+                IVec3::new(17, 0, -16),
+                IVec3::new(21, 9, -1)
+            ))
+        );
+    }
+
+    #[test]
+    fn compose_deltas() {
+        let a = Chunk::default();
+        let b = edited_chunk(&a, &[(IVec3::new(1, 0, 0), 1), (IVec3::new(4, 4, 4), 1)]);
+        let c = edited_chunk(&b, &[(IVec3::new(2, 0, 0), 2)]);
+        let mut c = c;
+        // Puts one voxel back the way it was.
+        c.set_voxel(IVec3::new(4, 4, 4), 0, a.sdf[0]);
+
+        let ab = ChunkDelta::between(&a, &b);
+        let bc = ChunkDelta::between(&b, &c);
+        let ac = ab.then(&bc);
+        assert_eq!(ac, ChunkDelta::between(&a, &c));
+        assert_eq!(ac.num_voxels(), 2);
+        assert!(ab.then(&ab.invert()).is_empty());
+    }
+
+    #[test]
+    fn rebase_onto_other_changes() {
+        let a = Chunk::default();
+        // A prediction, made on top of `a`.
+        let predicted = edited_chunk(&a, &[(IVec3::new(1, 0, 0), 1), (IVec3::new(2, 0, 0), 1)]);
+        // Someone else's change, which happened first.
+        let b = edited_chunk(&a, &[(IVec3::new(2, 0, 0), 2), (IVec3::new(9, 9, 9), 2)]);
+
+        let mut prediction = ChunkDelta::between(&a, &predicted);
+        let rest = prediction.rebase(&ChunkDelta::between(&a, &b));
+        assert_eq!(rest.num_voxels(), 1);
+
+        let mut chunk = predicted;
+        rest.apply(&mut chunk);
+        assert_eq!(
+            chunk,
+            edited_chunk(&b, &[(IVec3::new(1, 0, 0), 1), (IVec3::new(2, 0, 0), 1)])
+        );
+        prediction.invert().apply(&mut chunk);
+        assert_eq!(chunk, b);
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let old = Chunk::default();
+        let new = edited_chunk(
+            &old,
+            &[(IVec3::new(0, 0, 0), 7), (IVec3::new(15, 15, 15), 8)],
+        );
+        let delta = ChunkDelta::between(&old, &new);
+        let bytes = delta.to_bytes();
+        assert_eq!(bytes.len(), delta.num_bytes());
+        assert_eq!(ChunkDelta::from_bytes(&bytes), Some(delta));
+
+        assert_eq!(ChunkDelta::from_bytes(&bytes[..bytes.len() - 1]), None);
+        // Overlapping runs.
+        let mut bad = vec![2, 0, 0, 0, 2, 0, 1, 0, 1, 0];
+        bad.extend_from_slice(&[0; 12]);
+        assert_eq!(ChunkDelta::from_bytes(&bad), None);
+    }
+}
@@ -0,0 +1,93 @@
+use super::{ChunkLayers, ChunkShape, LayerId, VoxelLayerSchema, CHUNK_SIZE};
+use crate::core::glam::IVec3;
+
+use ndshape::ConstShape;
+
+/// The most fluid that one voxel can hold.
+pub const MAX_FLUID: u8 = 15;
+
+/// The fluid level of each voxel in a chunk, from 0 (dry) to [`MAX_FLUID`].
+pub type FluidChunk = [u8; CHUNK_SIZE];
+
+/// The voxel layer that stores fluid levels in a [`VoxelLayerSchema`], so fluid is saved with the other [`ChunkLayers`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FluidLayer {
+    pub level: LayerId<u8>,
+}
+
+impl FluidLayer {
+    pub const NAME: &'static str = "fluid";
+    pub const BITS: u8 = 4;
+
+    pub fn register(schema: &mut VoxelLayerSchema) -> Self {
+        Self {
+            level: schema.register(Self::NAME, Self::BITS),
+        }
+    }
+
+    /// Returns `None` if the fluid layer wasn't registered in `schema`.
+    pub fn find(schema: &VoxelLayerSchema) -> Option<Self> {
+        Some(Self {
+            level: schema.layer(Self::NAME)?,
+        })
+    }
+
+    pub fn read(&self, layers: &ChunkLayers) -> Box<FluidChunk> {
+        let mut fluid = Box::new([0; CHUNK_SIZE]);
+        for (i, level) in fluid.iter_mut().enumerate() {
+            let p = IVec3::from(ChunkShape::delinearize(i as i32));
+            *level = layers.get(self.level, p).min(MAX_FLUID);
+        }
+        fluid
+    }
+
+    pub fn write(&self, fluid: &FluidChunk, layers: &mut ChunkLayers) {
+        for (i, &level) in fluid.iter().enumerate() {
+            let p = IVec3::from(ChunkShape::delinearize(i as i32));
+            layers.set(self.level, p, level.min(MAX_FLUID));
+        }
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chunk::LightLayer;
+
+    #[test]
+    fn fluid_round_trips_next_to_other_layers() {
+        let mut schema = VoxelLayerSchema::default();
+        let light_layer = LightLayer::register(&mut schema);
+        let fluid_layer = FluidLayer::register(&mut schema);
+        assert_eq!(FluidLayer::find(&schema), Some(fluid_layer));
+
+        let mut layers = ChunkLayers::new(&schema);
+        layers.fill(light_layer.sunlight, 9);
+        let mut fluid = Box::new([0; CHUNK_SIZE]);
+        fluid[12] = MAX_FLUID;
+        fluid[300] = 4;
+        fluid_layer.write(&fluid, &mut layers);
+        assert_eq!(fluid_layer.read(&layers), fluid);
+        assert_eq!(layers.get(light_layer.sunlight, IVec3::ZERO), 9);
+    }
+
+    #[test]
+    fn levels_above_max_fluid_are_clamped_when_read() {
+        // A schema can register the layer with more bits than fluid needs.
+        let mut schema = VoxelLayerSchema::default();
+        let level: LayerId<u8> = schema.register(FluidLayer::NAME, 8);
+        let fluid_layer = FluidLayer::find(&schema).unwrap();
+
+        let mut layers = ChunkLayers::new(&schema);
+        layers.set(level, IVec3::new(1, 2, 3), 200);
+        let fluid = fluid_layer.read(&layers);
+        assert_eq!(fluid[ChunkShape::linearize([1, 2, 3]) as usize], MAX_FLUID);
+    }
+}
@@ -0,0 +1,392 @@
+use super::compression::{decode_channel, encode_channel};
+use super::paletted::{num_words, read_index, write_index};
+use super::{ChunkShape, CompressionCodec, CHUNK_SHAPE_IVEC3, CHUNK_SIZE};
+use crate::core::glam::IVec3;
+use crate::core::rkyv::{Archive, Deserialize, Serialize};
+
+use ndshape::ConstShape;
+use std::marker::PhantomData;
+
+/// The extra per-voxel attribute layers of a map, e.g. temperature, light, or ownership, which are stored in
+/// [`ChunkLayers`] alongside the SDF and palette IDs of each [`Chunk`](super::Chunk).
+///
+/// Layers are registered once when the map is created. Layers can be appended to the schema of an existing map later, but not
+/// removed or changed, since that would reinterpret stored bits.
+#[derive(Archive, Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[archive(crate = "crate::core::rkyv")]
+pub struct VoxelLayerSchema {
+    layers: Vec<VoxelLayerDesc>,
+}
+
+/// The name and bit width of one layer in a [`VoxelLayerSchema`].
+#[derive(Archive, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[archive(crate = "crate::core::rkyv")]
+pub struct VoxelLayerDesc {
+    pub name: String,
+    /// From 1 to 16. Values never straddle the `u64` words they're packed into, so widths that don't divide 64 waste a few
+    /// bits per word.
+    pub bits: u8,
+    /// Signed layers store two's complement values.
+    pub signed: bool,
+}
+
+/// A typed handle to a layer in a [`VoxelLayerSchema`], used to read and write [`ChunkLayers`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct LayerId<T> {
+    index: usize,
+    marker: PhantomData<T>,
+}
+
+impl<T> LayerId<T> {
+    fn new(index: usize) -> Self {
+        Self {
+            index,
+            marker: PhantomData,
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+/// A type that can be stored in a voxel layer, using only the lowest `width` bits of a `u16`.
+pub trait LayerValue: Copy {
+    const MAX_BITS: u8;
+    const SIGNED: bool;
+
+    fn from_bits(bits: u16, width: u8) -> Self;
+
+    /// Values that don't fit in `width` bits are truncated.
+    fn into_bits(self, width: u8) -> u16;
+}
+
+fn low_bits_mask(width: u8) -> u16 {
+    ((1u32 << width) - 1) as u16
+}
+
+/// Sign extends from the highest of the `width` bits.
+fn sign_extend(bits: u16, width: u8) -> i16 {
+    let unused = 16 - u32::from(width);
+    (bits << unused) as i16 >> unused
+}
+
+impl LayerValue for bool {
+    const MAX_BITS: u8 = 1;
+    const SIGNED: bool = false;
+
+    fn from_bits(bits: u16, _width: u8) -> Self {
+        bits != 0
+    }
+
+    fn into_bits(self, _width: u8) -> u16 {
+        u16::from(self)
+    }
+}
+
+impl LayerValue for u8 {
+    const MAX_BITS: u8 = 8;
+    const SIGNED: bool = false;
+
+    fn from_bits(bits: u16, _width: u8) -> Self {
+        bits as u8
+    }
+
+    fn into_bits(self, width: u8) -> u16 {
+        u16::from(self) & low_bits_mask(width)
+    }
+}
+
+impl LayerValue for u16 {
+    const MAX_BITS: u8 = 16;
+    const SIGNED: bool = false;
+
+    fn from_bits(bits: u16, _width: u8) -> Self {
+        bits
+    }
+
+    fn into_bits(self, width: u8) -> u16 {
+        self & low_bits_mask(width)
+    }
+}
+
+impl LayerValue for i8 {
+    const MAX_BITS: u8 = 8;
+    const SIGNED: bool = true;
+
+    fn from_bits(bits: u16, width: u8) -> Self {
+        sign_extend(bits, width) as i8
+    }
+
+    fn into_bits(self, width: u8) -> u16 {
+        self as u16 & low_bits_mask(width)
+    }
+}
+
+impl LayerValue for i16 {
+    const MAX_BITS: u8 = 16;
+    const SIGNED: bool = true;
+
+    fn from_bits(bits: u16, width: u8) -> Self {
+        sign_extend(bits, width)
+    }
+
+    fn into_bits(self, width: u8) -> u16 {
+        self as u16 & low_bits_mask(width)
+    }
+}
+
+impl VoxelLayerSchema {
+    /// Adds a layer of `T` values with `bits` per voxel.
+    ///
+    /// # Panics
+    ///
+    /// If `bits` is 0 or more than `T` can hold, or if a layer with the same `name` is already registered.
+    pub fn register<T: LayerValue>(&mut self, name: impl Into<String>, bits: u8) -> LayerId<T> {
+        let name = name.into();
+        assert!(
+            (1..=T::MAX_BITS).contains(&bits),
+            "Layer {} can't store {} bits per voxel",
+            name,
+            bits
+        );
+        assert!(
+            self.layer_index(&name).is_none(),
+            "Layer {} is already registered",
+            name
+        );
+        self.layers.push(VoxelLayerDesc {
+            name,
+            bits,
+            signed: T::SIGNED,
+        });
+        LayerId::new(self.layers.len() - 1)
+    }
+
+    /// Finds the registered layer called `name`, if it can be read as `T`.
+    pub fn layer<T: LayerValue>(&self, name: &str) -> Option<LayerId<T>> {
+        let index = self.layer_index(name)?;
+        let desc = &self.layers[index];
+        (desc.signed == T::SIGNED && desc.bits <= T::MAX_BITS).then(|| LayerId::new(index))
+    }
+
+    pub fn layers(&self) -> &[VoxelLayerDesc] {
+        &self.layers
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Returns `true` if every layer of `self` is in `other`, in the same order and with the same widths, so data written with
+    /// `self` can be read with `other`.
+    pub fn is_prefix_of(&self, other: &Self) -> bool {
+        other.layers.starts_with(&self.layers)
+    }
+
+    fn layer_index(&self, name: &str) -> Option<usize> {
+        self.layers.iter().position(|desc| desc.name == name)
+    }
+}
+
+/// The voxels of every layer in a [`VoxelLayerSchema`] for one chunk, bit-packed like a
+/// [`PalettedChunk`](super::PalettedChunk).
+///
+/// New layers are filled with zeros. Layers aren't downsampled, so they're usually only written at LOD0.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChunkLayers {
+    layers: Vec<LayerData>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct LayerData {
+    bits: u8,
+    words: Vec<u64>,
+}
+
+impl LayerData {
+    fn zeroed(bits: u8) -> Self {
+        Self {
+            bits,
+            words: vec![0; num_words(u32::from(bits))],
+        }
+    }
+}
+
+/// Prefix of [`CompressedLayers`], followed by the number of layers and then the length and compressed bytes of each layer.
+const LAYERS_MAGIC: [u8; 4] = *b"FSLY";
+
+impl ChunkLayers {
+    pub fn new(schema: &VoxelLayerSchema) -> Self {
+        Self {
+            layers: schema
+                .layers
+                .iter()
+                .map(|desc| LayerData::zeroed(desc.bits))
+                .collect(),
+        }
+    }
+
+    /// # Panics
+    ///
+    /// If `layer` is from a different schema than `self`, or `offset` is outside of the chunk.
+    pub fn get<T: LayerValue>(&self, layer: LayerId<T>, offset: IVec3) -> T {
+        let data = &self.layers[layer.index];
+        let raw = read_index(&data.words, u32::from(data.bits), linearize(offset));
+        T::from_bits(raw, data.bits)
+    }
+
+    /// # Panics
+    ///
+    /// If `layer` is from a different schema than `self`, or `offset` is outside of the chunk.
+    pub fn set<T: LayerValue>(&mut self, layer: LayerId<T>, offset: IVec3, value: T) {
+        let data = &mut self.layers[layer.index];
+        let raw = value.into_bits(data.bits);
+        write_index(
+            &mut data.words,
+            u32::from(data.bits),
+            linearize(offset),
+            raw,
+        );
+    }
+
+    /// Sets every voxel of `layer` to `value`.
+    pub fn fill<T: LayerValue>(&mut self, layer: LayerId<T>, value: T) {
+        let data = &mut self.layers[layer.index];
+        let raw = value.into_bits(data.bits);
+        for i in 0..CHUNK_SIZE {
+            write_index(&mut data.words, u32::from(data.bits), i, raw);
+        }
+    }
+
+    /// Compresses each layer independently, so layers that are all zeros only take a few bytes.
+    pub fn compress(&self, codec: CompressionCodec) -> CompressedLayers {
+        let mut bytes = LAYERS_MAGIC.to_vec();
+        bytes.push(self.layers.len() as u8);
+        for layer in self.layers.iter() {
+            let raw: Vec<u8> = layer.words.iter().flat_map(|w| w.to_le_bytes()).collect();
+            let channel = encode_channel(&raw, codec);
+            bytes.extend_from_slice(&(channel.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&channel);
+        }
+        CompressedLayers {
+            bytes: bytes.into_boxed_slice(),
+        }
+    }
+}
+
+fn linearize(offset: IVec3) -> usize {
+    assert!(
+        offset.cmpge(IVec3::ZERO).all() && offset.cmplt(CHUNK_SHAPE_IVEC3).all(),
+        "{} is outside of the chunk",
+        offset
+    );
+    ChunkShape::linearize(offset.to_array()) as usize
+}
+
+/// [`ChunkLayers`] compressed with any [`CompressionCodec`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CompressedLayers {
+    pub bytes: Box<[u8]>,
+}
+
+impl CompressedLayers {
+    /// Decompresses the layers that were written with `schema` or any prefix of it. Layers that were appended to the schema
+    /// since are filled with zeros.
+    ///
+    /// Returns `None` if the bytes are corrupt or were written with more layers than `schema` has.
+    pub fn decompress(&self, schema: &VoxelLayerSchema) -> Option<ChunkLayers> {
+        let rest = self.bytes.strip_prefix(&LAYERS_MAGIC)?;
+        let (&num_layers, mut rest) = rest.split_first()?;
+        if usize::from(num_layers) > schema.layers.len() {
+            return None;
+        }
+
+        let mut layers = ChunkLayers::new(schema);
+        for layer in layers.layers.iter_mut().take(num_layers.into()) {
+            if rest.len() < 4 {
+                return None;
+            }
+            let (len, tail) = rest.split_at(4);
+            let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+            if tail.len() < len {
+                return None;
+            }
+            let (channel, tail) = tail.split_at(len);
+            let mut raw = vec![0; 8 * layer.words.len()];
+            decode_channel(channel, &mut raw)?;
+            for (word, word_bytes) in layer.words.iter_mut().zip(raw.chunks_exact(8)) {
+                *word = u64::from_le_bytes(word_bytes.try_into().unwrap());
+            }
+            rest = tail;
+        }
+        Some(layers)
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn typed_layers_round_trip() {
+        let mut schema = VoxelLayerSchema::default();
+        let wet: LayerId<bool> = schema.register("wet", 1);
+        let light: LayerId<u8> = schema.register("light", 4);
+        let temperature: LayerId<i16> = schema.register("temperature", 12);
+        assert_eq!(schema.layer::<u8>("light"), Some(light));
+        // Signed layers can't be read as unsigned, or with a narrower type.
+        assert_eq!(schema.layer::<u16>("temperature"), None);
+        assert_eq!(schema.layer::<i8>("temperature"), None);
+
+        let mut layers = ChunkLayers::new(&schema);
+        let p = IVec3::new(3, 9, 15);
+        assert_eq!(layers.get(temperature, p), 0);
+        layers.set(wet, p, true);
+        layers.set(light, p, 0x1F);
+        layers.set(temperature, p, -300);
+        layers.set(temperature, IVec3::ZERO, 2047);
+        assert!(layers.get(wet, p));
+        assert!(!layers.get(wet, IVec3::ZERO));
+        // Truncated to 4 bits.
+        assert_eq!(layers.get(light, p), 0xF);
+        assert_eq!(layers.get(temperature, p), -300);
+        assert_eq!(layers.get(temperature, IVec3::ZERO), 2047);
+
+        for codec in [CompressionCodec::default(), CompressionCodec::None] {
+            let compressed = layers.compress(codec);
+            assert_eq!(compressed.decompress(&schema), Some(layers.clone()));
+        }
+    }
+
+    #[test]
+    fn decompress_with_appended_layers() {
+        let mut schema = VoxelLayerSchema::default();
+        let owner: LayerId<u16> = schema.register("owner", 16);
+        let mut layers = ChunkLayers::new(&schema);
+        layers.fill(owner, 7);
+        let compressed = layers.compress(CompressionCodec::default());
+        // The owner layer is uniform.
+        assert!(compressed.bytes.len() < 20);
+
+        let old_schema = schema.clone();
+        let heat: LayerId<i8> = schema.register("heat", 8);
+        assert!(old_schema.is_prefix_of(&schema));
+        assert!(!schema.is_prefix_of(&old_schema));
+
+        let layers = compressed.decompress(&schema).unwrap();
+        assert_eq!(layers.get(owner, IVec3::splat(5)), 7);
+        assert_eq!(layers.get(heat, IVec3::splat(5)), 0);
+
+        // Too many layers for the old schema.
+        let compressed = layers.compress(CompressionCodec::default());
+        assert_eq!(compressed.decompress(&old_schema), None);
+    }
+}
@@ -0,0 +1,78 @@
+use super::{ChunkLayers, ChunkShape, LayerId, VoxelLayerSchema, CHUNK_SIZE};
+use crate::core::glam::IVec3;
+
+use ndshape::ConstShape;
+
+/// The brightest light level. Sunlight enters the top of the map at this level and travels straight down without dimming, and
+/// every other step through an empty voxel dims it by one.
+pub const MAX_LIGHT: u8 = 15;
+
+/// The light level of each voxel in a chunk, from 0 to [`MAX_LIGHT`].
+pub type LightChunk = [u8; CHUNK_SIZE];
+
+/// The voxel layer that stores light levels in a [`VoxelLayerSchema`], so they're saved with the other [`ChunkLayers`] instead
+/// of being propagated again when the chunk loads.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LightLayer {
+    pub sunlight: LayerId<u8>,
+}
+
+impl LightLayer {
+    pub const NAME: &'static str = "sunlight";
+    pub const BITS: u8 = 4;
+
+    pub fn register(schema: &mut VoxelLayerSchema) -> Self {
+        Self {
+            sunlight: schema.register(Self::NAME, Self::BITS),
+        }
+    }
+
+    /// Returns `None` if the light layer wasn't registered in `schema`.
+    pub fn find(schema: &VoxelLayerSchema) -> Option<Self> {
+        Some(Self {
+            sunlight: schema.layer(Self::NAME)?,
+        })
+    }
+
+    pub fn read(&self, layers: &ChunkLayers) -> Box<LightChunk> {
+        let mut light = Box::new([0; CHUNK_SIZE]);
+        for (i, level) in light.iter_mut().enumerate() {
+            let p = IVec3::from(ChunkShape::delinearize(i as i32));
+            *level = layers.get(self.sunlight, p);
+        }
+        light
+    }
+
+    pub fn write(&self, light: &LightChunk, layers: &mut ChunkLayers) {
+        for (i, &level) in light.iter().enumerate() {
+            let p = IVec3::from(ChunkShape::delinearize(i as i32));
+            layers.set(self.sunlight, p, level.min(MAX_LIGHT));
+        }
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn light_round_trips_through_layer() {
+        let mut schema = VoxelLayerSchema::default();
+        let light_layer = LightLayer::register(&mut schema);
+        assert_eq!(LightLayer::find(&schema), Some(light_layer));
+
+        let mut light = Box::new([MAX_LIGHT; CHUNK_SIZE]);
+        light[7] = 3;
+        light[4000] = 0;
+        let mut layers = ChunkLayers::new(&schema);
+        light_layer.write(&light, &mut layers);
+        assert_eq!(light_layer.read(&layers), light);
+    }
+}
@@ -0,0 +1,131 @@
+use super::{ChunkLayers, ChunkShape, LayerId, VoxelLayerSchema, CHUNK_SIZE};
+use crate::core::glam::IVec3;
+
+use ndshape::ConstShape;
+
+/// How much each of up to 4 terrain materials contributes to a voxel, out of 255. Shaders can blend textures by these weights,
+/// e.g. between grass, dirt, and rock on a smooth slope, instead of switching abruptly at palette ID boundaries.
+///
+/// Which material each weight refers to is up to the game's shader. The default is entirely the first material.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct MaterialWeights(pub [u8; 4]);
+
+impl Default for MaterialWeights {
+    fn default() -> Self {
+        Self([255, 0, 0, 0])
+    }
+}
+
+pub type MaterialWeightsChunk = [MaterialWeights; CHUNK_SIZE];
+
+impl MaterialWeights {
+    /// Scales `weights` so they sum to 255, up to rounding. If every weight is zero or negative, the default is returned.
+    pub fn normalized(weights: [f32; 4]) -> Self {
+        let weights = weights.map(|w| w.max(0.0));
+        let sum: f32 = weights.iter().sum();
+        if sum <= 0.0 {
+            return Self::default();
+        }
+        Self(weights.map(|w| (255.0 * w / sum).round() as u8))
+    }
+
+    /// The normalized mean of `samples`, e.g. the voxels around a mesh vertex.
+    pub fn mean(samples: impl IntoIterator<Item = Self>) -> Self {
+        let mut sum = [0.0; 4];
+        for Self(weights) in samples {
+            for (s, w) in sum.iter_mut().zip(weights) {
+                *s += f32::from(w);
+            }
+        }
+        Self::normalized(sum)
+    }
+}
+
+/// The pair of voxel layers that store [`MaterialWeights`] in a [`VoxelLayerSchema`], so they're saved with the other
+/// [`ChunkLayers`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MaterialWeightLayers {
+    /// The first and second weights, in the low and high byte.
+    pub weights_01: LayerId<u16>,
+    pub weights_23: LayerId<u16>,
+}
+
+impl MaterialWeightLayers {
+    pub const NAMES: [&'static str; 2] = ["material_weights_01", "material_weights_23"];
+
+    pub fn register(schema: &mut VoxelLayerSchema) -> Self {
+        let [name_01, name_23] = Self::NAMES;
+        Self {
+            weights_01: schema.register(name_01, 16),
+            weights_23: schema.register(name_23, 16),
+        }
+    }
+
+    /// Returns `None` if the weight layers weren't registered in `schema`.
+    pub fn find(schema: &VoxelLayerSchema) -> Option<Self> {
+        let [name_01, name_23] = Self::NAMES;
+        Some(Self {
+            weights_01: schema.layer(name_01)?,
+            weights_23: schema.layer(name_23)?,
+        })
+    }
+
+    pub fn read(&self, layers: &ChunkLayers) -> Box<MaterialWeightsChunk> {
+        let mut weights = Box::new([MaterialWeights::default(); CHUNK_SIZE]);
+        for (i, w) in weights.iter_mut().enumerate() {
+            let p = IVec3::from(ChunkShape::delinearize(i as i32));
+            let [w0, w1] = layers.get(self.weights_01, p).to_le_bytes();
+            let [w2, w3] = layers.get(self.weights_23, p).to_le_bytes();
+            *w = MaterialWeights([w0, w1, w2, w3]);
+        }
+        weights
+    }
+
+    pub fn write(&self, weights: &MaterialWeightsChunk, layers: &mut ChunkLayers) {
+        for (i, MaterialWeights([w0, w1, w2, w3])) in weights.iter().enumerate() {
+            let p = IVec3::from(ChunkShape::delinearize(i as i32));
+            layers.set(self.weights_01, p, u16::from_le_bytes([*w0, *w1]));
+            layers.set(self.weights_23, p, u16::from_le_bytes([*w2, *w3]));
+        }
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mean_weights_are_normalized() {
+        let grass = MaterialWeights([255, 0, 0, 0]);
+        let rock = MaterialWeights([0, 0, 255, 0]);
+        assert_eq!(
+            MaterialWeights::mean([grass, grass, rock, rock]),
+            MaterialWeights([128, 0, 128, 0])
+        );
+        assert_eq!(MaterialWeights::mean([]), MaterialWeights::default());
+        assert_eq!(
+            MaterialWeights::normalized([1.0, 1.0, 1.0, 2.0]),
+            MaterialWeights([51, 51, 51, 102])
+        );
+    }
+
+    #[test]
+    fn weights_round_trip_through_layers() {
+        let mut schema = VoxelLayerSchema::default();
+        let weight_layers = MaterialWeightLayers::register(&mut schema);
+        assert_eq!(MaterialWeightLayers::find(&schema), Some(weight_layers));
+
+        let mut weights = Box::new([MaterialWeights::default(); CHUNK_SIZE]);
+        weights[100] = MaterialWeights([10, 20, 30, 195]);
+        let mut layers = ChunkLayers::new(&schema);
+        weight_layers.write(&weights, &mut layers);
+        assert_eq!(weight_layers.read(&layers), weights);
+    }
+}
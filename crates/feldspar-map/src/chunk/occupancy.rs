@@ -0,0 +1,181 @@
+use super::{Chunk, ChunkShape, SdfChunk, UniformChunk, CHUNK_EDGE, CHUNK_SIZE};
+use crate::core::glam::IVec3;
+
+use ndshape::ConstShape;
+
+/// One bit for each voxel of a vertical column of a chunk.
+#[cfg(not(any(feature = "chunk_edge_32", feature = "chunk_edge_64")))]
+pub type OccupancyColumn = u16;
+/// One bit for each voxel of a vertical column of a chunk.
+#[cfg(feature = "chunk_edge_32")]
+pub type OccupancyColumn = u32;
+/// One bit for each voxel of a vertical column of a chunk.
+#[cfg(feature = "chunk_edge_64")]
+pub type OccupancyColumn = u64;
+
+/// The number of vertical columns in a chunk.
+pub(crate) const CHUNK_COLUMNS: usize = (CHUNK_EDGE * CHUNK_EDGE) as usize;
+/// The number of voxels along each edge of a brick. Every chunk has 4x4x4 bricks.
+const BRICK_EDGE: i32 = CHUNK_EDGE / 4;
+const BRICK_COLUMN_MASK: OccupancyColumn = (1 << BRICK_EDGE) - 1;
+
+/// A bitset of the solid voxels in a chunk, so queries can skip chunks, columns, and bricks of empty space without reading the
+/// SDF.
+///
+/// Each vertical column of [`CHUNK_EDGE`] voxels is an [`OccupancyColumn`] where bit `y` is set if the voxel is solid. The
+/// column masks are summarized by a single `u64` with one bit for each of the 4x4x4 bricks of the chunk.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ChunkOccupancy {
+    /// Indexed by `x + CHUNK_EDGE * z`.
+    columns: [OccupancyColumn; CHUNK_COLUMNS],
+    /// Bit `x + 4 * y + 16 * z` is set if any voxel in the brick with coordinates `[x, y, z]` is solid.
+    bricks: u64,
+}
+
+impl Default for ChunkOccupancy {
+    fn default() -> Self {
+        Self::EMPTY
+    }
+}
+
+impl ChunkOccupancy {
+    pub const EMPTY: Self = Self {
+        columns: [0; CHUNK_COLUMNS],
+        bricks: 0,
+    };
+    pub const FULL: Self = Self {
+        columns: [OccupancyColumn::MAX; CHUNK_COLUMNS],
+        bricks: u64::MAX,
+    };
+
+    pub fn from_sdf(sdf: &SdfChunk) -> Self {
+        let mut occupancy = Self::EMPTY;
+        for (i, d) in sdf.iter().enumerate() {
+            if d.0 < 0 {
+                let [x, y, z] = ChunkShape::delinearize(i as i32);
+                occupancy.columns[column_index(x, z)] |= 1 << y;
+            }
+        }
+        occupancy.bricks = brick_summary(&occupancy.columns);
+        occupancy
+    }
+
+    pub fn from_uniform(uniform: UniformChunk) -> Self {
+        match uniform {
+            UniformChunk::Air => Self::EMPTY,
+            UniformChunk::Solid { .. } => Self::FULL,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bricks == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.columns.iter().all(|&c| c == OccupancyColumn::MAX)
+    }
+
+    /// The number of solid voxels.
+    pub fn count(&self) -> usize {
+        self.columns.iter().map(|c| c.count_ones() as usize).sum()
+    }
+
+    /// `offset` is relative to the chunk's minimum, and must be inside of the chunk.
+    pub fn is_solid(&self, offset: IVec3) -> bool {
+        debug_assert!(ChunkShape::linearize(offset.to_array()) < CHUNK_SIZE as i32);
+        (self.column(offset.x, offset.z) >> offset.y) & 1 == 1
+    }
+
+    /// The solid voxels of the column at `(x, z)`, where bit `y` is set if voxel `[x, y, z]` is solid.
+    pub fn column(&self, x: i32, z: i32) -> OccupancyColumn {
+        self.columns[column_index(x, z)]
+    }
+
+    /// The `y` of the highest solid voxel in the column at `(x, z)`, if any.
+    pub fn column_top(&self, x: i32, z: i32) -> Option<i32> {
+        let column = self.column(x, z);
+        (column != 0).then(|| CHUNK_EDGE - 1 - column.leading_zeros() as i32)
+    }
+
+    /// Returns `true` if every voxel of the column at `(x, z)` above `y` is empty.
+    pub fn column_is_empty_above(&self, x: i32, z: i32, y: i32) -> bool {
+        y >= CHUNK_EDGE - 1 || self.column(x, z) >> (y + 1).max(0) == 0
+    }
+
+    /// The summary of the 4x4x4 bricks, where bit `x + 4 * y + 16 * z` is set if brick `[x, y, z]` has any solid voxels.
+    pub fn bricks(&self) -> u64 {
+        self.bricks
+    }
+
+    pub fn brick_is_empty(&self, brick: IVec3) -> bool {
+        (self.bricks >> (brick.x + 4 * brick.y + 16 * brick.z)) & 1 == 0
+    }
+}
+
+impl Chunk {
+    /// Scans the SDF for solid voxels. The [`ChunkClipMap`](crate::clipmap::ChunkClipMap) keeps the occupancy of its chunks up
+    /// to date, so prefer [`ChunkClipMap::occupancy`](crate::clipmap::ChunkClipMap::occupancy) for chunks in the map.
+    pub fn occupancy(&self) -> ChunkOccupancy {
+        ChunkOccupancy::from_sdf(&self.sdf)
+    }
+}
+
+fn column_index(x: i32, z: i32) -> usize {
+    (x + CHUNK_EDGE * z) as usize
+}
+
+fn brick_summary(columns: &[OccupancyColumn; CHUNK_COLUMNS]) -> u64 {
+    let mut bricks = 0;
+    for z in 0..CHUNK_EDGE {
+        for x in 0..CHUNK_EDGE {
+            let column = columns[column_index(x, z)];
+            for brick_y in 0..4 {
+                if (column >> (BRICK_EDGE * brick_y)) & BRICK_COLUMN_MASK != 0 {
+                    bricks |= 1 << (x / BRICK_EDGE + 4 * brick_y + 16 * (z / BRICK_EDGE));
+                }
+            }
+        }
+    }
+    bricks
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sdf::Sd8;
+
+    #[test]
+    fn occupancy_tracks_columns_and_bricks() {
+        let mut chunk = Chunk::default();
+        assert!(chunk.occupancy().is_empty());
+
+        chunk.set_voxel(IVec3::new(5, 2, 9), 1, Sd8::MIN);
+        chunk.set_voxel(IVec3::new(5, 13, 9), 1, Sd8::MIN);
+        let occupancy = chunk.occupancy();
+        assert!(!occupancy.is_empty());
+        assert_eq!(occupancy.count(), 2);
+        assert!(occupancy.is_solid(IVec3::new(5, 13, 9)));
+        assert!(!occupancy.is_solid(IVec3::new(5, 12, 9)));
+        assert_eq!(occupancy.column_top(5, 9), Some(13));
+        assert_eq!(occupancy.column_top(4, 9), None);
+        assert!(!occupancy.column_is_empty_above(5, 9, 12));
+        assert!(occupancy.column_is_empty_above(5, 9, 13));
+        assert!(!occupancy.brick_is_empty(IVec3::new(1, 0, 2)));
+        assert!(!occupancy.brick_is_empty(IVec3::new(1, 3, 2)));
+        assert_eq!(occupancy.bricks().count_ones(), 2);
+
+        let solid = UniformChunk::Solid { palette_id: 1 };
+        assert_eq!(
+            solid.to_chunk().occupancy(),
+            ChunkOccupancy::from_uniform(solid)
+        );
+        assert!(ChunkOccupancy::FULL.is_full());
+    }
+}
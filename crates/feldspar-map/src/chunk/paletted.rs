@@ -0,0 +1,308 @@
+use super::{Chunk, CHUNK_SIZE};
+use crate::core::SmallKeyHashMap;
+use crate::{palette::PaletteId8, sdf::Sd8};
+
+/// One distinct voxel value in a [`PalettedChunk`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PalettedVoxel {
+    pub sdf: Sd8,
+    pub palette_id: PaletteId8,
+}
+
+impl PalettedVoxel {
+    fn key(self) -> u16 {
+        u16::from_le_bytes([self.sdf.0 as u8, self.palette_id])
+    }
+}
+
+/// A [`Chunk`] stored as a per-chunk palette of distinct voxels and a bit-packed array of palette indices, Minecraft-style.
+///
+/// Blocky worlds usually only have a couple of distinct voxels per chunk, e.g. `(Sd8::MAX, 0)` for air and `(Sd8::MIN, id)` for
+/// each kind of block, so every voxel takes a few bits instead of 2 bytes. Smooth SDFs have many distinct values and don't
+/// benefit nearly as much. Conversion to and from [`Chunk`] is lossless.
+///
+/// Indices never straddle the `u64` words they're packed into, so each word holds `64 / bits_per_index` indices.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PalettedChunk {
+    palette: Vec<PalettedVoxel>,
+    bits_per_index: u32,
+    words: Vec<u64>,
+}
+
+/// Prefix of a serialized [`PalettedChunk`].
+const PALETTED_MAGIC: [u8; 4] = *b"FSPL";
+const HEADER_BYTES: usize = PALETTED_MAGIC.len() + 2 + 1;
+
+impl PalettedChunk {
+    /// A chunk filled with `voxel`.
+    pub fn filled(voxel: PalettedVoxel) -> Self {
+        Self {
+            palette: vec![voxel],
+            bits_per_index: 0,
+            words: Vec::new(),
+        }
+    }
+
+    pub fn from_chunk(chunk: &Chunk) -> Self {
+        let mut palette = Vec::new();
+        let mut palette_indices = SmallKeyHashMap::default();
+        let mut indices = vec![0u16; CHUNK_SIZE];
+        for (i, (&sdf, &palette_id)) in chunk.sdf.iter().zip(chunk.palette_ids.iter()).enumerate() {
+            let voxel = PalettedVoxel { sdf, palette_id };
+            indices[i] = *palette_indices.entry(voxel.key()).or_insert_with(|| {
+                palette.push(voxel);
+                (palette.len() - 1) as u16
+            });
+        }
+
+        let bits_per_index = bits_for_palette_len(palette.len());
+        let mut words = vec![0; num_words(bits_per_index)];
+        for (i, &index) in indices.iter().enumerate() {
+            write_index(&mut words, bits_per_index, i, index);
+        }
+        Self {
+            palette,
+            bits_per_index,
+            words,
+        }
+    }
+
+    pub fn to_chunk(&self) -> Chunk {
+        let mut chunk = Chunk::default();
+        for i in 0..CHUNK_SIZE {
+            let voxel = self.get(i);
+            chunk.sdf[i] = voxel.sdf;
+            chunk.palette_ids[i] = voxel.palette_id;
+        }
+        chunk
+    }
+
+    pub fn palette(&self) -> &[PalettedVoxel] {
+        &self.palette
+    }
+
+    pub fn bits_per_index(&self) -> u32 {
+        self.bits_per_index
+    }
+
+    /// The voxel at linear `index` in [`ChunkShape`](super::ChunkShape).
+    pub fn get(&self, index: usize) -> PalettedVoxel {
+        self.palette[read_index(&self.words, self.bits_per_index, index) as usize]
+    }
+
+    /// Sets the voxel at linear `index` in [`ChunkShape`](super::ChunkShape). If `voxel` isn't in the palette yet, it gets added,
+    /// and the indices are repacked when the palette outgrows them.
+    ///
+    /// Entries that are no longer used are kept in the palette until the chunk is rebuilt with [`Self::from_chunk`].
+    pub fn set(&mut self, index: usize, voxel: PalettedVoxel) {
+        let palette_index = if let Some(i) = self.palette.iter().position(|&v| v == voxel) {
+            i
+        } else {
+            self.palette.push(voxel);
+            let bits_per_index = bits_for_palette_len(self.palette.len());
+            if bits_per_index != self.bits_per_index {
+                self.repack(bits_per_index);
+            }
+            self.palette.len() - 1
+        };
+        write_index(
+            &mut self.words,
+            self.bits_per_index,
+            index,
+            palette_index as u16,
+        );
+    }
+
+    fn repack(&mut self, bits_per_index: u32) {
+        let mut words = vec![0; num_words(bits_per_index)];
+        for i in 0..CHUNK_SIZE {
+            let index = read_index(&self.words, self.bits_per_index, i);
+            write_index(&mut words, bits_per_index, i, index);
+        }
+        self.words = words;
+        self.bits_per_index = bits_per_index;
+    }
+
+    /// The length of [`Self::to_bytes`].
+    pub fn num_bytes(&self) -> usize {
+        HEADER_BYTES + 2 * self.palette.len() + 8 * self.words.len()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.num_bytes());
+        bytes.extend_from_slice(&PALETTED_MAGIC);
+        bytes.extend_from_slice(&(self.palette.len() as u16).to_le_bytes());
+        bytes.push(self.bits_per_index as u8);
+        for voxel in self.palette.iter() {
+            bytes.extend_from_slice(&voxel.key().to_le_bytes());
+        }
+        for word in self.words.iter() {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Returns `None` if `bytes` weren't written by [`Self::to_bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_BYTES || !bytes.starts_with(&PALETTED_MAGIC) {
+            return None;
+        }
+        // Only a 64³ chunk can have all 65536 distinct voxels, and then the length wraps to zero.
+        let palette_len = match u16::from_le_bytes([bytes[4], bytes[5]]) {
+            0 if CHUNK_SIZE > usize::from(u16::MAX) => 1 << 16,
+            len => usize::from(len),
+        };
+        let bits_per_index = bytes[6] as u32;
+        if palette_len == 0 || bits_per_index != bits_for_palette_len(palette_len) {
+            return None;
+        }
+        let words_start = HEADER_BYTES + 2 * palette_len;
+        if bytes.len() != words_start + 8 * num_words(bits_per_index) {
+            return None;
+        }
+
+        let palette = bytes[HEADER_BYTES..words_start]
+            .chunks_exact(2)
+            .map(|b| PalettedVoxel {
+                sdf: Sd8(b[0] as i8),
+                palette_id: b[1],
+            })
+            .collect();
+        let words: Vec<u64> = bytes[words_start..]
+            .chunks_exact(8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        // Unless the palette length is a power of 2, the indices have room for entries past the end of the palette.
+        if (0..CHUNK_SIZE)
+            .any(|i| usize::from(read_index(&words, bits_per_index, i)) >= palette_len)
+        {
+            return None;
+        }
+        Some(Self {
+            palette,
+            bits_per_index,
+            words,
+        })
+    }
+}
+
+impl From<&Chunk> for PalettedChunk {
+    fn from(chunk: &Chunk) -> Self {
+        Self::from_chunk(chunk)
+    }
+}
+
+impl From<&PalettedChunk> for Chunk {
+    fn from(chunk: &PalettedChunk) -> Self {
+        chunk.to_chunk()
+    }
+}
+
+fn bits_for_palette_len(len: usize) -> u32 {
+    usize::BITS - (len.max(1) - 1).leading_zeros()
+}
+
+pub(super) fn num_words(bits_per_index: u32) -> usize {
+    if bits_per_index == 0 {
+        0
+    } else {
+        CHUNK_SIZE.div_ceil((64 / bits_per_index) as usize)
+    }
+}
+
+pub(super) fn read_index(words: &[u64], bits_per_index: u32, i: usize) -> u16 {
+    if bits_per_index == 0 {
+        return 0;
+    }
+    let per_word = (64 / bits_per_index) as usize;
+    let shift = (i % per_word) as u32 * bits_per_index;
+    ((words[i / per_word] >> shift) & ((1 << bits_per_index) - 1)) as u16
+}
+
+pub(super) fn write_index(words: &mut [u64], bits_per_index: u32, i: usize, index: u16) {
+    if bits_per_index == 0 {
+        return;
+    }
+    let per_word = (64 / bits_per_index) as usize;
+    let shift = (i % per_word) as u32 * bits_per_index;
+    let mask = ((1 << bits_per_index) - 1) << shift;
+    let word = &mut words[i / per_word];
+    *word = (*word & !mask) | ((index as u64) << shift);
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chunk::ChunkShape;
+    use crate::core::glam::IVec3;
+
+    use ndshape::ConstShape;
+
+    #[test]
+    fn blocky_chunk_round_trip() {
+        let mut chunk = Chunk::default();
+        for z in 0..16 {
+            for x in 0..16 {
+                chunk.set_voxel(IVec3::new(x, 0, z), 1, Sd8::MIN);
+                chunk.set_voxel(IVec3::new(x, 1, z), 2, Sd8::MIN);
+            }
+        }
+        chunk.set_voxel(IVec3::new(3, 2, 4), 3, Sd8::MIN);
+
+        let paletted = PalettedChunk::from_chunk(&chunk);
+        assert_eq!(paletted.palette().len(), 4);
+        assert_eq!(paletted.bits_per_index(), 2);
+        assert!(paletted.num_bytes() < 1100, "{}", paletted.num_bytes());
+        assert_eq!(paletted.to_chunk(), chunk);
+
+        let bytes = paletted.to_bytes();
+        assert_eq!(bytes.len(), paletted.num_bytes());
+        assert_eq!(PalettedChunk::from_bytes(&bytes), Some(paletted));
+    }
+
+    #[test]
+    fn set_grows_palette() {
+        let air = PalettedVoxel {
+            sdf: Sd8::MAX,
+            palette_id: 0,
+        };
+        let mut paletted = PalettedChunk::filled(air);
+        assert_eq!(paletted.num_bytes(), HEADER_BYTES + 2);
+
+        let mut chunk = Chunk::default();
+        for id in 1..=20 {
+            let p = IVec3::new(id as i32 % 16, id as i32 / 16, 5);
+            let voxel = PalettedVoxel {
+                sdf: Sd8::MIN,
+                palette_id: id,
+            };
+            paletted.set(ChunkShape::linearize(p.to_array()) as usize, voxel);
+            chunk.set_voxel(p, id, Sd8::MIN);
+        }
+        assert_eq!(paletted.bits_per_index(), 5);
+        assert_eq!(paletted.to_chunk(), chunk);
+    }
+
+    #[test]
+    fn from_bytes_rejects_indices_past_the_palette() {
+        let mut chunk = Chunk::default();
+        chunk.set_voxel(IVec3::new(1, 2, 3), 1, Sd8::MIN);
+        chunk.set_voxel(IVec3::new(4, 5, 6), 2, Sd8::MIN);
+        let paletted = PalettedChunk::from_chunk(&chunk);
+        assert_eq!(paletted.palette().len(), 3);
+        assert_eq!(paletted.bits_per_index(), 2);
+
+        // Point the last voxel at the unused fourth entry.
+        let mut bytes = paletted.to_bytes();
+        let last = bytes.len() - 1;
+        bytes[last] |= 0b11 << 6;
+        assert_eq!(PalettedChunk::from_bytes(&bytes), None);
+    }
+}
@@ -1,24 +1,65 @@
+mod border;
+mod cache;
+mod connectivity;
+mod crater;
+mod editing;
+mod introspection;
+mod light;
+mod lod_boundary;
+mod material_weights;
+mod navmesh;
+mod neighborhood;
 mod neighborhood_subdiv;
 mod node;
+mod occupancy;
+mod pathfinding;
 mod raycast;
+mod region;
+mod sdf_sampler;
+mod stamp;
 mod streaming;
+mod surface_height;
+mod sweep;
+mod visibility;
 
-use crate::chunk::CompressedChunk;
+use crate::chunk::{
+    Chunk, ChunkOccupancy, CompressedChunk, LightChunk, MappedChunk, MaterialWeightsChunk,
+};
 use crate::coordinates::{
     ancestor_extent, child_index, chunk_bounding_sphere, chunk_extent_at_level_ivec3,
-    descendant_extent, in_chunk_extent, sphere_intersecting_ancestor_chunk_extent,
+    chunk_lod0_extent, descendant_extent, in_chunk_extent,
+    sphere_intersecting_ancestor_chunk_extent,
 };
 use crate::core::geometry::Sphere;
 use crate::core::glam::IVec3;
 use crate::core::ilattice::prelude::Extent;
+use crate::core::SmallKeyHashMap;
 use crate::units::{ChunkUnits, VoxelUnits};
 
+pub use border::*;
+pub use cache::*;
+pub use connectivity::*;
+pub use crater::*;
+pub use editing::*;
+pub use introspection::*;
 pub use grid_tree::{
     BranchShape, ChildIndex, Level, NodeKey, NodePtr, OctreeShapeI32, VisitCommand, EMPTY_ALLOC_PTR,
 };
+pub use lod_boundary::*;
+pub use navmesh::*;
+pub use neighborhood::*;
 pub use node::*;
+pub use pathfinding::*;
+pub use raycast::*;
+pub use region::*;
+pub use sdf_sampler::SdfSampler;
 pub use streaming::*;
+pub use sweep::*;
+pub use visibility::*;
 
+use either::Either;
+use parking_lot::Mutex;
+use occupancy::{either_occupancy, mapped_occupancy};
 use grid_tree::OctreeI32;
 use smallvec::SmallVec;
 
@@ -53,6 +94,27 @@ impl NodeLocation {
 pub struct ChunkClipMap {
     pub octree: OctreeI32<ChunkNode>,
     pub stream_config: StreamingConfig,
+    /// If set, the load searches never create nodes that are entirely outside of this extent (at LOD0), even if they're in
+    /// the clip region of an observer.
+    pub world_bounds: Option<VoxelUnits<Extent<IVec3>>>,
+    /// Keeps a [`ChunkBorder`] for every chunk that is written through the clipmap, so the render search doesn't wait for the
+    /// positive neighbors of a chunk to load before meshing it. Must be set before any chunks are written.
+    pub share_borders: bool,
+    /// Only chunks with blended materials have weights.
+    material_weights: SmallKeyHashMap<NodeKey<IVec3>, Box<MaterialWeightsChunk>>,
+    /// Light levels of the LOD0 chunks that have been lit, keyed by chunk coordinates.
+    light: SmallKeyHashMap<IVec3, Box<LightChunk>>,
+    /// The occupancy of every occupied node that was written through the clipmap.
+    occupancy: SmallKeyHashMap<NodeKey<IVec3>, Box<ChunkOccupancy>>,
+    /// The borders of the chunks that were written while [`Self::share_borders`] was set.
+    borders: SmallKeyHashMap<NodeKey<IVec3>, Box<ChunkBorder>>,
+    /// The [`Self::edit_generation`] of every node that was marked as needing a mesh. Locked by the marking methods, which
+    /// only borrow the clipmap.
+    edit_generations: Mutex<SmallKeyHashMap<NodeKey<IVec3>, u64>>,
+    /// The face connectivity of every occupied node that was written through the clipmap, kept alongside its occupancy.
+    connectivity: SmallKeyHashMap<NodeKey<IVec3>, FaceConnectivity>,
+    /// Claims of the vacant loads that link to a shared ancestor.
+    ancestor_load_claims: AncestorLoadClaims,
 }
 
 impl ChunkClipMap {
@@ -60,6 +122,15 @@ impl ChunkClipMap {
         Self {
             octree: OctreeI32::new(height),
             stream_config,
+            world_bounds: None,
+            share_borders: false,
+            material_weights: SmallKeyHashMap::default(),
+            light: SmallKeyHashMap::default(),
+            occupancy: SmallKeyHashMap::default(),
+            borders: SmallKeyHashMap::default(),
+            edit_generations: Mutex::default(),
+            connectivity: SmallKeyHashMap::default(),
+            ancestor_load_claims: AncestorLoadClaims::default(),
         }
     }
 
@@ -179,7 +250,9 @@ impl ChunkClipMap {
     /// it will check if the load is still pending; if not, the loaded data gets ignored and dropped.
     ///
     /// Similarly, if the `nearest_ancestor` is empty, the load is canceled.
-    pub fn complete_pending_load(&mut self, load: PendingLoad) {
+    ///
+    /// Returns `true` if the loaded chunk was inserted, or `false` if the load was canceled.
+    pub fn complete_pending_load(&mut self, load: PendingLoad) -> bool {
         // We need to ensure a couple things:
         // 1. If we insert a `None`, then we need to check if we're the last sibling node finished loading and maybe collapse
         //    into the parent node if all children are empty. This continues recursively to the root, but we will leave empty
@@ -194,9 +267,12 @@ impl ChunkClipMap {
             loaded_key,
             link_ptr,
             chunk,
+            mapped,
+            occupancy,
         } = load;
 
         let mut do_collapse = false;
+        let mut loaded_occupancy = None;
         match link_ptr {
             LinkPointer::OverwriteNode { child, parent } => {
                 // The node existed when it started loading. It's guaranteed to exist when the load completes because other
@@ -209,19 +285,47 @@ impl ChunkClipMap {
                 let was_loading = node.state_mut().fetch_and_clear_loading();
                 if !was_loading {
                     // This means there was an intervening edit. Cancel the load.
-                    return;
+                    return false;
                 }
 
-                if let Some(chunk) = chunk {
-                    node.put_compressed(chunk);
+                let loaded_occupied = chunk.is_some() || mapped.is_some();
+                loaded_occupancy = Some(if let Some(mapped) = &mapped {
+                    Some(occupancy.unwrap_or_else(|| mapped_occupancy(mapped)))
                 } else {
-                    node.take_chunk();
+                    chunk.as_ref().map(|chunk| {
+                        occupancy.unwrap_or_else(|| {
+                            either_occupancy(
+                                chunk.as_ref().map_left(|decompressed| &**decompressed),
+                            )
+                        })
+                    })
+                });
+                match (mapped, chunk) {
+                    (Some(mapped), _) => {
+                        node.put_mapped(mapped);
+                    }
+                    (None, Some(Either::Left(decompressed))) => {
+                        node.put_decompressed(decompressed);
+                    }
+                    (None, Some(Either::Right(compressed))) => {
+                        node.put_compressed(compressed);
+                    }
+                    (None, None) => {
+                        node.take_chunk();
+                    }
                 }
 
                 // If this is the last load of this subtree, then clear the descendant is loading bit on the parent.
-                if node.state().descendant_is_loading.none() {
-                    if let Some(parent_ptr) = parent {
-                        let parent_node = self.octree.get_value_mut(parent_ptr).unwrap();
+                let subtree_loaded = node.state().descendant_is_loading.none();
+                if let Some(parent_ptr) = parent {
+                    let parent_node = self.octree.get_value_mut(parent_ptr).unwrap();
+
+                    // A parent without any data of its own can be generated from its children instead.
+                    if loaded_occupied && parent_node.state().slot_state() == SlotState::Empty {
+                        parent_node.state().set_needs_downsample();
+                    }
+
+                    if subtree_loaded {
                         let descendant_index = child_index(loaded_key.coordinates);
                         parent_node
                             .state_mut()
@@ -236,15 +340,20 @@ impl ChunkClipMap {
             LinkPointer::LinkToNearestAncestor(nearest_ancestor_ptr) => {
                 let ancestor_node =
                     if let Some(ancestor_node) = self.octree.get_value(nearest_ancestor_ptr) {
-                        if ancestor_node.state().fetch_and_clear_load_pending() {
+                        // Siblings of this node can still be loading, so the ancestor only stops being pending once the
+                        // last of them is done.
+                        if self
+                            .ancestor_load_claims
+                            .release(nearest_ancestor_ptr, Some(ancestor_node.state()))
+                        {
                             ancestor_node
                         } else {
                             // Cancel load.
-                            return;
+                            return false;
                         }
                     } else {
                         // Cancel load.
-                        return;
+                        return false;
                     };
 
                 // We need to link a new node to the ancestor.
@@ -270,17 +379,73 @@ impl ChunkClipMap {
             }
         }
 
+        if let Some(occupancy) = loaded_occupancy {
+            self.set_occupancy(loaded_key, occupancy);
+            if self.share_borders {
+                // The negative neighbors might already be rendering with an ambient border.
+                self.update_borders(loaded_key);
+                self.mark_needs_mesh(loaded_key);
+            }
+        }
+
         if do_collapse {
             // PERF: most expensive path. We need to start from the root for collapsing an arbitrary number of levels.
             self.try_collapse_key(loaded_key);
         }
+
+        true
+    }
+}
+
+impl ChunkClipMap {
+    /// Releases the claim that `load` has on the tree without writing any chunk data, so that a future search can start the
+    /// load again.
+    pub fn cancel_pending_load(&mut self, load: PendingLoad) {
+        self.release_load_claim(&load.link_ptr);
+    }
+
+    /// Releases the claim that a load with `link_ptr` has on the tree. A vacant load only releases its own claim on the
+    /// nearest ancestor, which stays pending until its siblings are done.
+    pub(crate) fn release_load_claim(&self, link_ptr: &LinkPointer) {
+        match *link_ptr {
+            LinkPointer::OverwriteNode { child, .. } => {
+                if let Some(node) = self.octree.get_value(child) {
+                    node.state().clear_load_pending();
+                }
+            }
+            LinkPointer::LinkToNearestAncestor(ancestor) => {
+                let ancestor_node = self.octree.get_value(ancestor);
+                self.ancestor_load_claims
+                    .release(ancestor, ancestor_node.map(ChunkNode::state));
+            }
+        }
     }
 }
 
 pub struct PendingLoad {
     pub loaded_key: NodeKey<IVec3>,
     pub link_ptr: LinkPointer,
-    pub chunk: Option<CompressedChunk>,
+    /// The loaded chunk may be fulfilled in either representation. `None` means the chunk is empty.
+    pub chunk: Option<Either<Box<Chunk>, CompressedChunk>>,
+    /// A chunk that's still in the buffer it was read into. If this is set, it's loaded instead of `chunk`.
+    pub mapped: Option<MappedChunk>,
+    /// The [`ChunkOccupancy`] of `chunk`, if it was measured by [`PendingLoad::measure_occupancy`]. Otherwise it's measured
+    /// when the load is completed.
+    pub occupancy: Option<ChunkOccupancy>,
+}
+
+impl PendingLoad {
+    /// Measures the occupancy of `chunk` ahead of time, e.g. on a load task, so that completing the load doesn't have to scan
+    /// the chunk.
+    pub fn measure_occupancy(&mut self) {
+        self.occupancy = if let Some(mapped) = &self.mapped {
+            Some(mapped_occupancy(mapped))
+        } else {
+            self.chunk.as_ref().map(|chunk| {
+                either_occupancy(chunk.as_ref().map_left(|decompressed| &**decompressed))
+            })
+        };
+    }
 }
 
 pub enum LinkPointer {
@@ -291,6 +456,18 @@ pub enum LinkPointer {
     },
 }
 
+/// Returns `true` if any part of the node at `(level, coordinates)` is within the `bounds`, or if there are no bounds.
+pub(crate) fn node_is_in_bounds(
+    bounds: Option<VoxelUnits<Extent<IVec3>>>,
+    level: Level,
+    coordinates: ChunkUnits<IVec3>,
+) -> bool {
+    bounds.map_or(true, |VoxelUnits(bounds)| {
+        let VoxelUnits(node_extent) = chunk_lod0_extent(level, coordinates);
+        !node_extent.intersection(&bounds).is_empty()
+    })
+}
+
 // ████████╗███████╗███████╗████████╗
 // ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
 //    ██║   █████╗  ███████╗   ██║
@@ -302,11 +479,15 @@ pub enum LinkPointer {
 mod test {
     use super::node::NodeState;
     use super::*;
-    use crate::core::{geometry::Ray, glam::Vec3A};
+    use crate::core::{
+        geometry::{Capsule, Frustum, Ray},
+        glam::{Mat4, Vec3, Vec3A},
+    };
     use crate::{
-        chunk::Chunk,
+        chunk::{Chunk, ChunkEncoding},
         coordinates::{chunk_extent_from_min_ivec3, in_chunk_extent},
         ndview::NdView,
+        sdf::Sd8,
     };
 
     use ndshape::RuntimeShape;
@@ -370,4 +551,222 @@ mod test {
         assert_eq!(tmin, 1.0);
         assert_eq!(tmax, 17.0);
     }
+
+    /// Inserts LOD0 chunk (1, 0, 0) with a solid floor below y = 4, so the surface is at y = 3.5.
+    fn insert_floor_chunk(tree: &mut ChunkClipMap) {
+        let mut chunk = Chunk::default();
+        for p in Extent::from_min_and_shape(IVec3::ZERO, IVec3::splat(16)).iter3() {
+            let palette_id = if p.y < 4 { 7 } else { 0 };
+            chunk.set_voxel(p, palette_id, Sd8::from(p.y as f32 - 3.5));
+        }
+        let write_key = NodeKey::new(0, IVec3::new(1, 0, 0));
+        tree.octree
+            .fill_path_to_node_from_root(write_key, |node_key, entry| {
+                entry.or_insert_with(|| {
+                    if node_key.level == 0 {
+                        ChunkNode::new_decompressed(Box::new(chunk), NodeState::new_zeroed())
+                    } else {
+                        ChunkNode::new_empty(NodeState::new_zeroed())
+                    }
+                });
+                VisitCommand::Continue
+            });
+    }
+
+    #[test]
+    fn cast_ray_hits_solid_voxels() {
+        let mut tree = ChunkClipMap::new(3, StreamingConfig::default());
+        insert_floor_chunk(&mut tree);
+
+        let origin = VoxelUnits(Vec3A::new(20.5, 10.0, 8.5));
+        let hit = tree
+            .cast_ray(origin, Vec3A::new(0.0, -1.0, 0.0), 100.0)
+            .unwrap();
+        assert!((hit.t - 6.5).abs() < 0.1, "{hit:?}");
+        assert!(hit.normal.abs_diff_eq(Vec3A::Y, 1e-3), "{hit:?}");
+        assert_eq!(hit.palette_id, 7);
+        assert_eq!(hit.voxel.0.y, 3);
+
+        // Too short to reach the floor.
+        assert!(tree.cast_ray(origin, -Vec3A::Y, 5.0).is_none());
+        // Pointing away from the floor.
+        assert!(tree.cast_ray(origin, Vec3A::Y, 100.0).is_none());
+    }
+
+    #[test]
+    fn cast_sphere_touches_floor() {
+        let mut tree = ChunkClipMap::new(3, StreamingConfig::default());
+        insert_floor_chunk(&mut tree);
+
+        let sphere = VoxelUnits(Sphere::new(Vec3A::new(20.0, 10.0, 8.0), 1.5));
+        let hit = tree.cast_sphere(sphere, -Vec3A::Y, 100.0).unwrap();
+        assert!((hit.toi - 5.0).abs() < 0.1, "{hit:?}");
+        assert!(hit.normal.abs_diff_eq(Vec3A::Y, 1e-3), "{hit:?}");
+        assert!(
+            hit.contact.0.abs_diff_eq(Vec3A::new(20.0, 3.5, 8.0), 0.1),
+            "{hit:?}"
+        );
+
+        assert!(tree.cast_sphere(sphere, -Vec3A::Y, 4.0).is_none());
+        assert!(tree.cast_sphere(sphere, Vec3A::X, 100.0).is_none());
+    }
+
+    #[test]
+    fn cast_capsule_touches_floor() {
+        let mut tree = ChunkClipMap::new(3, StreamingConfig::default());
+        insert_floor_chunk(&mut tree);
+
+        let capsule = Capsule::new(
+            Vec3A::new(18.0, 10.0, 8.0),
+            Vec3A::new(22.0, 10.0, 8.0),
+            1.0,
+        );
+        let hit = tree
+            .cast_capsule(VoxelUnits(capsule), -Vec3A::Y, 100.0)
+            .unwrap();
+        assert!((hit.toi - 5.5).abs() < 0.1, "{hit:?}");
+        assert!(hit.normal.abs_diff_eq(Vec3A::Y, 1e-3), "{hit:?}");
+
+        // Already overlapping the floor.
+        let buried = Capsule::new(Vec3A::new(18.0, 4.0, 8.0), Vec3A::new(22.0, 4.0, 8.0), 1.0);
+        let hit = tree
+            .cast_capsule(VoxelUnits(buried), Vec3A::Y, 100.0)
+            .unwrap();
+        assert_eq!(hit.toi, 0.0);
+    }
+
+    #[test]
+    fn iter_visible_culls_and_selects_lod() {
+        let mut tree = ChunkClipMap::new(3, StreamingConfig::default());
+        for coords in [IVec3::ZERO, IVec3::new(3, 0, 0)] {
+            tree.octree
+                .fill_path_to_node_from_root(NodeKey::new(0, coords), |_node_key, entry| {
+                    entry.or_insert_with(|| ChunkNode::new_empty(NodeState::new_zeroed()));
+                    VisitCommand::Continue
+                });
+        }
+
+        // Looking down -Z at the voxels in 1 <= x, y <= 15.
+        let view = Mat4::look_at_rh(Vec3::new(0.0, 0.0, 100.0), Vec3::ZERO, Vec3::Y);
+        let projection = Mat4::orthographic_rh(1.0, 15.0, 1.0, 15.0, 0.0, 200.0);
+        let frustum = Frustum::from_view_projection(projection * view);
+
+        let visible: Vec<_> = tree
+            .iter_visible(&frustum, |_| false)
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(visible, vec![NodeKey::new(0, IVec3::ZERO)]);
+
+        let visible: Vec<_> = tree
+            .iter_visible(&frustum, |key| key.level >= 1)
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(visible, vec![NodeKey::new(1, IVec3::ZERO)]);
+    }
+
+    #[test]
+    fn compress_least_recently_touched_chunks() {
+        let mut tree = ChunkClipMap::new(3, StreamingConfig::default());
+        let keys = [0, 1, 2].map(|x| NodeKey::new(0, IVec3::new(x, 0, 0)));
+        for key in keys {
+            tree.octree
+                .fill_path_to_node_from_root(key, |node_key, entry| {
+                    entry.or_insert_with(|| {
+                        if node_key.level == 0 {
+                            ChunkNode::new_decompressed(
+                                Box::new(Chunk::default()),
+                                NodeState::new_zeroed(),
+                            )
+                        } else {
+                            ChunkNode::new_empty(NodeState::new_zeroed())
+                        }
+                    });
+                    VisitCommand::Continue
+                });
+        }
+        let slot_state = |tree: &ChunkClipMap, key| {
+            let ptr = tree.octree.find_node(key).unwrap();
+            tree.octree.get_value(ptr).unwrap().state().slot_state()
+        };
+
+        // Everything fits.
+        let sweep =
+            tree.compress_cold_chunks(1, 3 * DECOMPRESSED_CHUNK_BYTES, ChunkEncoding::Dense, &[]);
+        assert_eq!(
+            sweep,
+            CacheSweep {
+                resident_bytes: 3 * DECOMPRESSED_CHUNK_BYTES,
+                num_compressed: 0
+            }
+        );
+
+        // Touch all but the first chunk on a later frame.
+        for key in &keys[1..] {
+            let ptr = tree.octree.find_node(*key).unwrap();
+            tree.octree.get_value(ptr).unwrap().get_decompressed();
+        }
+        let sweep =
+            tree.compress_cold_chunks(2, 2 * DECOMPRESSED_CHUNK_BYTES, ChunkEncoding::Dense, &[]);
+        assert_eq!(sweep.num_compressed, 1);
+        assert_eq!(slot_state(&tree, keys[0]), SlotState::Compressed);
+        assert_eq!(slot_state(&tree, keys[1]), SlotState::Decompressed);
+        assert_eq!(slot_state(&tree, keys[2]), SlotState::Decompressed);
+
+        // The retained chunk outlasts an equally cold one.
+        let retained = ClipRegion::new(&[VoxelUnits(Vec3A::new(40.0, 8.0, 8.0))], VoxelUnits(1.0));
+        let sweep = tree.compress_cold_chunks(
+            3,
+            DECOMPRESSED_CHUNK_BYTES,
+            ChunkEncoding::Dense,
+            &[retained],
+        );
+        assert_eq!(sweep.num_compressed, 1);
+        assert_eq!(slot_state(&tree, keys[0]), SlotState::Compressed);
+        assert_eq!(slot_state(&tree, keys[1]), SlotState::Compressed);
+        assert_eq!(slot_state(&tree, keys[2]), SlotState::Decompressed);
+    }
+
+    #[test]
+    fn canceled_vacant_load_keeps_the_claim_of_its_siblings() {
+        let mut tree = ChunkClipMap::new(2, StreamingConfig::default());
+        let root_key = NodeKey::new(tree.octree.root_level(), IVec3::ZERO);
+        // A loaded root with just one allocated child, so its other children are vacant and all link to the root.
+        tree.octree
+            .fill_path_to_node_from_root(NodeKey::new(0, IVec3::ZERO), |key, entry| {
+                let (_ptr, node) =
+                    entry.or_insert_with(|| ChunkNode::new_empty(NodeState::new_zeroed()));
+                if key == root_key {
+                    node.state_mut().descendant_is_loading.set_all();
+                }
+                VisitCommand::Continue
+            });
+        let root_ptr = tree.octree.find_node(root_key).unwrap();
+        let root_is_pending = |tree: &ChunkClipMap| {
+            tree.octree
+                .get_value(root_ptr)
+                .unwrap()
+                .state()
+                .has_load_pending()
+        };
+
+        let observers = [LoadObserver::from(VoxelUnits(Vec3A::splat(16.0)))];
+        let find_vacant_loads = |tree: &ChunkClipMap| -> Vec<_> {
+            tree.near_phase_load_search(&observers, LoadPriority::Nearest)
+                .filter(|load| matches!(load.link_ptr, LinkPointer::LinkToNearestAncestor(_)))
+                .collect()
+        };
+        let mut vacant = find_vacant_loads(&tree);
+        assert_eq!(vacant.len(), 7);
+        assert!(root_is_pending(&tree));
+
+        tree.cancel_pending_load(vacant.pop().unwrap());
+        assert!(root_is_pending(&tree));
+        for load in vacant.into_iter() {
+            tree.cancel_pending_load(load);
+        }
+        assert!(!root_is_pending(&tree));
+
+        // Now they can all be found again.
+        assert_eq!(find_vacant_loads(&tree).len(), 7);
+    }
 }
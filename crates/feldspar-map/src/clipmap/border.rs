@@ -0,0 +1,203 @@
+use crate::chunk::{
+    Chunk, ChunkShape, PaddedChunkShape, AMBIENT_SD8, CHUNK_SHAPE_IVEC3, CHUNK_SIZE,
+    PADDED_CHUNK_SHAPE_IVEC3, PADDED_CHUNK_SIZE,
+};
+use crate::clipmap::ChunkClipMap;
+use crate::coordinates::CUBE_CORNERS;
+use crate::core::glam::IVec3;
+use crate::core::ilattice::prelude::Extent;
+use crate::palette::PaletteId8;
+use crate::sdf::Sd8;
+
+use grid_tree::NodeKey;
+use ndshape::ConstShape;
+
+/// The number of voxels in a [`ChunkBorder`].
+pub const CHUNK_BORDER_SIZE: usize = PADDED_CHUNK_SIZE - CHUNK_SIZE;
+
+/// The voxels past the positive faces of a chunk, i.e. the part of its [`PaddedChunk`](crate::clipmap::PaddedChunk) that
+/// comes from its 7 positive neighbors.
+///
+/// With [`ChunkClipMap::share_borders`], every chunk keeps its border, and writing a chunk also writes the borders of its
+/// negative neighbors. Then a chunk can be meshed as soon as it loads, without waiting for its neighbors. The voxels of
+/// neighbors that are still loading are ambient until they're written.
+#[derive(Clone)]
+pub struct ChunkBorder {
+    pub sdf: [Sd8; CHUNK_BORDER_SIZE],
+    pub palette_ids: [PaletteId8; CHUNK_BORDER_SIZE],
+}
+
+impl Default for ChunkBorder {
+    fn default() -> Self {
+        Self {
+            sdf: [AMBIENT_SD8; CHUNK_BORDER_SIZE],
+            palette_ids: [0; CHUNK_BORDER_SIZE],
+        }
+    }
+}
+
+impl ChunkBorder {
+    /// The index in the border of `p`, a point in [`PaddedChunkShape`] outside of the chunk.
+    ///
+    /// The border is laid out as three slabs: every point past the positive X face, then the rest of the points past the Y
+    /// face, then the rest of the points past the Z face.
+    pub fn index(p: IVec3) -> usize {
+        let [px, py, pz] = PaddedChunkShape::ARRAY;
+        let [cx, cy, cz] = CHUNK_SHAPE_IVEC3.to_array();
+        debug_assert!(p.cmpge(IVec3::ZERO).all() && p.cmplt(PADDED_CHUNK_SHAPE_IVEC3).all());
+        debug_assert!(
+            p.cmpge(CHUNK_SHAPE_IVEC3).any(),
+            "{} is inside of the chunk",
+            p
+        );
+        let x_slab = (px - cx) * py * pz;
+        let y_slab = cx * (py - cy) * pz;
+        let i = if p.x >= cx {
+            ((p.x - cx) * py + p.y) * pz + p.z
+        } else if p.y >= cy {
+            x_slab + (p.x * (py - cy) + p.y - cy) * pz + p.z
+        } else {
+            x_slab + y_slab + (p.x * cy + p.y) * (pz - cz) + p.z - cz
+        };
+        i as usize
+    }
+
+    /// Copies the voxels of the positive neighbor at `CUBE_CORNERS[neighbor_i]` into this border. `None` is an ambient
+    /// chunk.
+    fn copy_from_neighbor(&mut self, neighbor_i: usize, neighbor: Option<&Chunk>) {
+        let offset = CUBE_CORNERS[neighbor_i];
+        let min = offset * CHUNK_SHAPE_IVEC3;
+        let lub = IVec3::select(
+            offset.cmpeq(IVec3::ONE),
+            PADDED_CHUNK_SHAPE_IVEC3,
+            CHUNK_SHAPE_IVEC3,
+        );
+        for p in Extent::from_min_and_lub(min, lub).iter3() {
+            let i = Self::index(p);
+            if let Some(chunk) = neighbor {
+                let j = ChunkShape::linearize((p - min).to_array()) as usize;
+                self.sdf[i] = chunk.sdf[j];
+                self.palette_ids[i] = chunk.palette_ids[j];
+            } else {
+                self.sdf[i] = AMBIENT_SD8;
+                self.palette_ids[i] = 0;
+            }
+        }
+    }
+}
+
+impl ChunkClipMap {
+    /// The border of the chunk at `key`, if [`Self::share_borders`] is set and the chunk was written through the clipmap.
+    pub fn border(&self, key: NodeKey<IVec3>) -> Option<&ChunkBorder> {
+        self.borders.get(&key).map(|b| &**b)
+    }
+
+    /// Copies the chunk that was just written at `key` into the borders of its negative neighbors. If the chunk doesn't have
+    /// a border yet, it's copied from the positive neighbors. Does nothing unless [`Self::share_borders`] is set.
+    pub(crate) fn update_borders(&mut self, key: NodeKey<IVec3>) {
+        if !self.share_borders {
+            return;
+        }
+
+        let chunk = self.border_source(key);
+        for (neighbor_i, &offset) in CUBE_CORNERS.iter().enumerate().skip(1) {
+            let dependent_key = NodeKey::new(key.level, key.coordinates - offset);
+            if !self.borders.contains_key(&dependent_key) {
+                // Only nodes in the octree get borders, and they're complete from the start.
+                if self.octree.find_node(dependent_key).is_some() {
+                    let border = self.new_border(dependent_key);
+                    self.borders.insert(dependent_key, border);
+                }
+                continue;
+            }
+            self.borders
+                .get_mut(&dependent_key)
+                .unwrap()
+                .copy_from_neighbor(neighbor_i, chunk.as_deref());
+        }
+
+        if !self.borders.contains_key(&key) {
+            let border = self.new_border(key);
+            self.borders.insert(key, border);
+        }
+    }
+
+    fn new_border(&self, key: NodeKey<IVec3>) -> Box<ChunkBorder> {
+        let mut border = Box::<ChunkBorder>::default();
+        for (neighbor_i, &offset) in CUBE_CORNERS.iter().enumerate().skip(1) {
+            let neighbor = self.border_source(NodeKey::new(key.level, key.coordinates + offset));
+            border.copy_from_neighbor(neighbor_i, neighbor.as_deref());
+        }
+        border
+    }
+
+    /// The voxels of the node at `key` without decompressing it in place, or `None` if the node is ambient, missing, or still
+    /// loading.
+    fn border_source(&self, key: NodeKey<IVec3>) -> Option<Box<Chunk>> {
+        let ptr = self.octree.find_node(key)?;
+        let node = self.octree.get_value(ptr).unwrap();
+        if node.state().is_loading() {
+            return None;
+        }
+        node.decompressed_copy()
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipmap::{Neighbor, RenderNeighborhood, StreamingConfig};
+    use crate::units::ChunkUnits;
+
+    #[test]
+    fn border_indices_are_dense() {
+        let mut seen = vec![false; CHUNK_BORDER_SIZE];
+        for i in 0..PaddedChunkShape::SIZE {
+            let p = IVec3::from(PaddedChunkShape::delinearize(i));
+            if p.cmplt(CHUNK_SHAPE_IVEC3).all() {
+                continue;
+            }
+            let j = ChunkBorder::index(p);
+            assert!(!seen[j], "{} has the same index as another point", p);
+            seen[j] = true;
+        }
+        assert!(seen.into_iter().all(|s| s));
+    }
+
+    #[test]
+    fn chunk_is_meshed_from_its_border() {
+        let mut clipmap = ChunkClipMap::new(3, StreamingConfig::default());
+        clipmap.share_borders = true;
+        let key = NodeKey::new(0, IVec3::ZERO);
+        clipmap.edit_chunk(ChunkUnits(IVec3::ZERO), |_| ());
+        let border = clipmap.border(key).unwrap();
+        assert!(border.sdf.iter().all(|&s| s == AMBIENT_SD8));
+
+        // Written after the chunk, so it updates the existing border.
+        clipmap.edit_chunk(ChunkUnits(IVec3::X), |chunk| {
+            chunk.set_voxel(IVec3::new(1, 5, 5), 3, Sd8::MIN)
+        });
+        let border = clipmap.border(key).unwrap();
+        let i = ChunkBorder::index(IVec3::new(17, 5, 5));
+        assert_eq!((border.sdf[i], border.palette_ids[i]), (Sd8::MIN, 3));
+
+        // None of the neighbors are needed.
+        let ptr = clipmap.octree.find_node(key).unwrap();
+        let mut neighbors = [Neighbor::Empty { loaded: false }; 8];
+        neighbors[0] = Neighbor::Occupied(ptr.alloc_ptr());
+        let padded = clipmap.copy_padded_neighborhood(&RenderNeighborhood {
+            level: 0,
+            coordinates: ChunkUnits(IVec3::ZERO),
+            neighbors,
+        });
+        let j = PaddedChunkShape::linearize([17, 5, 5]) as usize;
+        assert_eq!((padded.sdf[j], padded.palette_ids[j]), (Sd8::MIN, 3));
+    }
+}
@@ -0,0 +1,129 @@
+use crate::chunk::{Chunk, ChunkEncoding, CompressionCodec};
+use crate::clipmap::{ChunkClipMap, ClipRegion, NodePtr, SlotState, VisitCommand};
+
+use either::Either;
+use grid_tree::NodeKey;
+use std::mem;
+
+/// The number of bytes resident for each decompressed chunk.
+pub const DECOMPRESSED_CHUNK_BYTES: usize = mem::size_of::<Chunk>();
+
+/// The result of [`ChunkClipMap::compress_cold_chunks`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CacheSweep {
+    /// Bytes of decompressed chunk data before compressing.
+    pub resident_bytes: usize,
+    /// The number of chunks that were compressed.
+    pub num_compressed: usize,
+}
+
+/// The result of [`ChunkClipMap::stats`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ClipMapStats {
+    /// The number of nodes at each level, starting with LOD0.
+    pub nodes_per_level: Vec<usize>,
+    /// The number of nodes with chunk data, compressed or not.
+    pub num_resident: usize,
+    pub num_loading: usize,
+    pub num_compressed: usize,
+    /// The total size of all compressed chunks.
+    pub compressed_bytes: usize,
+}
+
+impl ClipMapStats {
+    /// The average size of a compressed chunk as a fraction of a decompressed chunk, or `None` if no chunks are compressed.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        (self.num_compressed > 0).then(|| {
+            self.compressed_bytes as f64 / (self.num_compressed * DECOMPRESSED_CHUNK_BYTES) as f64
+        })
+    }
+}
+
+impl ChunkClipMap {
+    /// Counts the nodes in the whole tree, e.g. for diagnostics. This walks every node, so it shouldn't be done every frame.
+    pub fn stats(&self) -> ClipMapStats {
+        let mut stats = ClipMapStats {
+            nodes_per_level: vec![0; self.octree.root_level() as usize + 1],
+            ..Default::default()
+        };
+        for (root_key, root_node) in self.octree.iter_roots() {
+            self.octree.visit_tree_depth_first(
+                NodePtr::new(root_key.level, root_node.self_ptr),
+                root_key.coordinates,
+                0,
+                |ptr, _coords| {
+                    let node = self.octree.get_value(ptr).unwrap();
+                    stats.nodes_per_level[ptr.level() as usize] += 1;
+                    if node.state().slot_state() != SlotState::Empty {
+                        stats.num_resident += 1;
+                    }
+                    if node.state().is_loading() {
+                        stats.num_loading += 1;
+                    }
+                    if let Some(len) = node.compressed_len() {
+                        stats.num_compressed += 1;
+                        stats.compressed_bytes += len;
+                    }
+                    VisitCommand::Continue
+                },
+            );
+        }
+        stats
+    }
+
+    /// Records `frame` as the last-touched frame of every decompressed chunk that was read since the previous sweep. Then, if
+    /// the decompressed chunks take more than `max_resident_bytes`, the least recently touched chunks are compressed until they
+    /// fit.
+    ///
+    /// The budget is apportioned by the `retained_regions`, sorted from least to most important. Chunks that intersect a more
+    /// important region are only compressed after all of the chunks outside of it, no matter how recently they were touched.
+    ///
+    /// Chunks are compressed in `encoding` with the default [`CompressionCodec`]. Compression is transparent to readers, which
+    /// decompress the chunk inline the next time it's read.
+    pub fn compress_cold_chunks(
+        &mut self,
+        frame: u32,
+        max_resident_bytes: usize,
+        encoding: ChunkEncoding,
+        retained_regions: &[ClipRegion],
+    ) -> CacheSweep {
+        let mut decompressed = Vec::new();
+        for (root_key, root_node) in self.octree.iter_roots() {
+            self.octree.visit_tree_depth_first(
+                NodePtr::new(root_key.level, root_node.self_ptr),
+                root_key.coordinates,
+                0,
+                |ptr, coords| {
+                    let state = self.octree.get_value(ptr).unwrap().state();
+                    if state.slot_state() == SlotState::Decompressed {
+                        let key = NodeKey::new(ptr.level(), coords);
+                        let retention = retained_regions
+                            .iter()
+                            .rposition(|region| region.intersects_node(key))
+                            .map_or(0, |i| i + 1);
+                        decompressed.push(((retention, state.sweep_last_touched(frame)), ptr));
+                    }
+                    VisitCommand::Continue
+                },
+            );
+        }
+
+        let resident_bytes = decompressed.len() * DECOMPRESSED_CHUNK_BYTES;
+        let excess_bytes = resident_bytes.saturating_sub(max_resident_bytes);
+        let num_compressed = excess_bytes.div_ceil(DECOMPRESSED_CHUNK_BYTES);
+        if num_compressed > 0 {
+            decompressed.select_nth_unstable_by_key(num_compressed - 1, |&(order, _)| order);
+            for &(_, ptr) in decompressed[..num_compressed].iter() {
+                let node = self.octree.get_value_mut(ptr).unwrap();
+                if let Some(Either::Left(chunk)) = node.take_chunk() {
+                    node.put_compressed(chunk.compress_as(encoding, CompressionCodec::default()));
+                }
+            }
+        }
+
+        CacheSweep {
+            resident_bytes,
+            num_compressed,
+        }
+    }
+}
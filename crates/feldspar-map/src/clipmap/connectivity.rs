@@ -0,0 +1,254 @@
+use crate::chunk::{ChunkOccupancy, OccupancyColumn, CHUNK_COLUMNS, CHUNK_EDGE};
+use crate::clipmap::{ChunkClipMap, Face};
+use crate::coordinates::chunk_extent_at_level_vec3a;
+use crate::core::geometry::Frustum;
+use crate::core::glam::IVec3;
+use crate::core::SmallKeyHashSet;
+use crate::units::{ChunkUnits, VoxelUnits};
+
+use grid_tree::NodeKey;
+use std::collections::VecDeque;
+
+/// Which faces of a chunk can see each other through its empty voxels.
+///
+/// Two faces are connected if a single region of empty voxels touches both of them. Solid chunks don't connect any faces, and
+/// empty chunks connect all of them. This is enough to flood fill the chunks visible from a camera without looking at voxels;
+/// see [`ChunkClipMap::flood_visible_chunks`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FaceConnectivity {
+    /// Bit `b` of `connections[a]` is set if face `a` is connected to face `b`, indexed in the order of [`Face::ALL`].
+    connections: [u8; 6],
+}
+
+impl FaceConnectivity {
+    pub const NONE: Self = Self {
+        connections: [0; 6],
+    };
+    pub const ALL: Self = Self {
+        connections: [0b11_1111; 6],
+    };
+
+    /// Flood fills every region of empty voxels that touches a face of the chunk.
+    pub fn from_occupancy(occupancy: &ChunkOccupancy) -> Self {
+        if occupancy.is_empty() {
+            return Self::ALL;
+        }
+        if occupancy.is_full() {
+            return Self::NONE;
+        }
+
+        let mut connectivity = Self::NONE;
+        // Same layout as the occupancy columns.
+        let mut visited = [0 as OccupancyColumn; CHUNK_COLUMNS];
+        let mut stack = Vec::new();
+        for z in 0..CHUNK_EDGE {
+            for y in 0..CHUNK_EDGE {
+                for x in 0..CHUNK_EDGE {
+                    let seed = IVec3::new(x, y, z);
+                    // Regions that don't touch the boundary don't connect anything.
+                    let on_boundary = seed.cmpeq(IVec3::ZERO).any()
+                        || seed.cmpeq(IVec3::splat(CHUNK_EDGE - 1)).any();
+                    if !on_boundary || occupancy.is_solid(seed) || is_visited(&visited, seed) {
+                        continue;
+                    }
+
+                    let mut faces = 0;
+                    set_visited(&mut visited, seed);
+                    stack.push(seed);
+                    while let Some(p) = stack.pop() {
+                        for face in Face::ALL {
+                            let q = p + face.normal();
+                            if q[face.axis()] < 0 || q[face.axis()] >= CHUNK_EDGE {
+                                faces |= 1 << face as usize;
+                                continue;
+                            }
+                            if !occupancy.is_solid(q) && !is_visited(&visited, q) {
+                                set_visited(&mut visited, q);
+                                stack.push(q);
+                            }
+                        }
+                    }
+                    connectivity.connect(faces);
+                }
+            }
+        }
+        connectivity
+    }
+
+    /// Returns `true` if something entering the chunk through face `a` can leave through face `b`.
+    pub fn connects(&self, a: Face, b: Face) -> bool {
+        (self.connections[a as usize] >> b as usize) & 1 == 1
+    }
+
+    /// Connects every pair of the faces in the bitmask `faces`.
+    fn connect(&mut self, faces: u8) {
+        for (a, connections) in self.connections.iter_mut().enumerate() {
+            if (faces >> a) & 1 == 1 {
+                *connections |= faces;
+            }
+        }
+    }
+}
+
+fn is_visited(visited: &[OccupancyColumn; CHUNK_COLUMNS], p: IVec3) -> bool {
+    (visited[(p.x + CHUNK_EDGE * p.z) as usize] >> p.y) & 1 == 1
+}
+
+fn set_visited(visited: &mut [OccupancyColumn; CHUNK_COLUMNS], p: IVec3) {
+    visited[(p.x + CHUNK_EDGE * p.z) as usize] |= 1 << p.y;
+}
+
+impl ChunkClipMap {
+    /// The [`FaceConnectivity`] of the node at `key`, or `None` if the node is missing or still loading.
+    ///
+    /// The connectivity is computed along with the occupancy whenever a chunk is written through the clipmap, so this only
+    /// scans the chunk if it was inserted some other way.
+    pub fn face_connectivity(&self, key: NodeKey<IVec3>) -> Option<FaceConnectivity> {
+        if let Some(connectivity) = self.connectivity.get(&key) {
+            return Some(*connectivity);
+        }
+        self.occupancy(key)
+            .map(|occupancy| FaceConnectivity::from_occupancy(&occupancy))
+    }
+
+    /// Finds the chunks that might be visible from the chunk at `camera`, by flood filling through connected faces.
+    ///
+    /// A chunk is only entered from the face it shares with the previous chunk, and the fill never turns back in a direction
+    /// that it already moved away from, so chunks behind solid walls or around the bend of a cave are culled. The fill stays
+    /// within `max_distance` chunks (along every axis) of the camera and on the camera's level. If `frustum` is given (in LOD0
+    /// voxel coordinates), chunks outside of it are skipped as well.
+    ///
+    /// Missing and loading chunks are treated as empty, so they never hide anything. The camera's chunk is always visible, and
+    /// chunks are returned in the order they were reached.
+    pub fn flood_visible_chunks(
+        &self,
+        camera: NodeKey<IVec3>,
+        max_distance: ChunkUnits<i32>,
+        frustum: Option<&Frustum>,
+    ) -> Vec<NodeKey<IVec3>> {
+        let ChunkUnits(max_distance) = max_distance;
+        let level = camera.level;
+
+        let mut visible = Vec::new();
+        let mut reached = SmallKeyHashSet::default();
+        // The face the chunk was entered through, and a bitmask of the directions taken to reach it.
+        let mut queue = VecDeque::new();
+        reached.insert(camera.coordinates);
+        queue.push_back((camera.coordinates, None, 0u8));
+        while let Some((coords, entered, directions)) = queue.pop_front() {
+            let key = NodeKey::new(level, coords);
+            visible.push(key);
+            let connectivity = self.face_connectivity(key).unwrap_or(FaceConnectivity::ALL);
+            for exit in Face::ALL {
+                if (directions >> exit.opposite() as usize) & 1 == 1 {
+                    continue;
+                }
+                if let Some(entered) = entered {
+                    if !connectivity.connects(entered, exit) {
+                        continue;
+                    }
+                }
+                let next = coords + exit.normal();
+                if (next - camera.coordinates).abs().max_element() > max_distance
+                    || reached.contains(&next)
+                {
+                    continue;
+                }
+                if let Some(frustum) = frustum {
+                    let VoxelUnits(extent) = chunk_extent_at_level_vec3a(level, ChunkUnits(next));
+                    if !frustum.intersects_extent(extent) {
+                        continue;
+                    }
+                }
+                reached.insert(next);
+                queue.push_back((
+                    next,
+                    Some(exit.opposite()),
+                    directions | (1 << exit as usize),
+                ));
+            }
+        }
+        visible
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::Chunk;
+    use crate::clipmap::{ChunkNode, NodeState, StreamingConfig, VisitCommand};
+    use crate::sdf::Sd8;
+
+    fn wall_at_x(x: i32) -> ChunkOccupancy {
+        let mut chunk = Chunk::default();
+        for z in 0..CHUNK_EDGE {
+            for y in 0..CHUNK_EDGE {
+                chunk.set_voxel(IVec3::new(x, y, z), 1, Sd8::MIN);
+            }
+        }
+        chunk.occupancy()
+    }
+
+    #[test]
+    fn wall_separates_opposite_faces() {
+        let connectivity = FaceConnectivity::from_occupancy(&wall_at_x(8));
+        assert!(!connectivity.connects(Face::NegX, Face::PosX));
+        assert!(connectivity.connects(Face::NegX, Face::PosY));
+        assert!(connectivity.connects(Face::PosX, Face::NegZ));
+        assert!(connectivity.connects(Face::NegY, Face::PosY));
+
+        // A wall on the face itself hides that face from everything.
+        let connectivity = FaceConnectivity::from_occupancy(&wall_at_x(0));
+        assert!(Face::ALL
+            .into_iter()
+            .all(|face| !connectivity.connects(Face::NegX, face)));
+        assert!(connectivity.connects(Face::PosX, Face::PosY));
+
+        assert_eq!(
+            FaceConnectivity::from_occupancy(&ChunkOccupancy::EMPTY),
+            FaceConnectivity::ALL
+        );
+        assert_eq!(
+            FaceConnectivity::from_occupancy(&ChunkOccupancy::FULL),
+            FaceConnectivity::NONE
+        );
+    }
+
+    #[test]
+    fn flood_fill_stops_at_solid_chunks() {
+        let mut clipmap = ChunkClipMap::new(3, StreamingConfig::default());
+        let root_key = NodeKey::new(clipmap.octree.root_level(), IVec3::ZERO);
+        clipmap
+            .octree
+            .fill_path_to_node_from_root(root_key, |_key, entry| {
+                entry.or_insert_with(|| ChunkNode::new_empty(NodeState::new_zeroed()));
+                VisitCommand::Continue
+            });
+        let wall = NodeKey::new(0, IVec3::new(1, 0, 0));
+        clipmap.edit_chunk(ChunkUnits(wall.coordinates), |chunk| {
+            chunk.sdf.fill(Sd8::MIN)
+        });
+        assert_eq!(
+            clipmap.face_connectivity(wall),
+            Some(FaceConnectivity::NONE)
+        );
+
+        let camera = NodeKey::new(0, IVec3::ZERO);
+        let visible = clipmap.flood_visible_chunks(camera, ChunkUnits(2), None);
+        assert_eq!(visible[0], camera);
+        // The wall itself is visible, but nothing behind it is, since going around would turn back.
+        assert!(visible.contains(&wall));
+        assert!(!visible.contains(&NodeKey::new(0, IVec3::new(2, 0, 0))));
+        assert!(visible.contains(&NodeKey::new(0, IVec3::new(2, 1, 0))));
+        assert!(visible.contains(&NodeKey::new(0, IVec3::new(-2, 0, 0))));
+        // Beyond the max distance.
+        assert!(!visible.contains(&NodeKey::new(0, IVec3::new(-3, 0, 0))));
+    }
+}
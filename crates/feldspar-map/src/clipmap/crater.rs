@@ -0,0 +1,159 @@
+use crate::brush::{Brush, CraterBrush, CraterNoise};
+use crate::clipmap::{ChunkClipMap, MissingChunkPolicy, RegionError};
+use crate::core::geometry::Sphere;
+use crate::core::glam::Vec3A;
+use crate::palette::PaletteId8;
+use crate::sdf::Sd8;
+use crate::units::VoxelUnits;
+
+/// The solid material that was removed by [`ChunkClipMap::carve_sphere_with_falloff`], one entry per palette ID.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Debris {
+    /// Sorted by palette ID.
+    pub materials: Vec<DebrisMaterial>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DebrisMaterial {
+    pub palette_id: PaletteId8,
+    /// The removed volume, in cubic LOD0 voxels. Voxels on the rim of the crater only count for the solid fraction that was
+    /// removed.
+    pub volume: f32,
+    /// The volume-weighted center of the removed voxels, where debris can be spawned.
+    pub centroid: VoxelUnits<Vec3A>,
+}
+
+impl Debris {
+    pub fn is_empty(&self) -> bool {
+        self.materials.is_empty()
+    }
+
+    pub fn total_volume(&self) -> f32 {
+        self.materials.iter().map(|m| m.volume).sum()
+    }
+
+    pub fn get(&self, palette_id: PaletteId8) -> Option<&DebrisMaterial> {
+        self.materials
+            .binary_search_by_key(&palette_id, |m| m.palette_id)
+            .ok()
+            .map(|i| &self.materials[i])
+    }
+}
+
+impl ChunkClipMap {
+    /// Carves a sphere with a noisy rim out of the LOD0 voxels, like an explosion, and returns the [`Debris`] of the solid
+    /// voxels that were removed. The rim is blended into the terrain like a [`CraterBrush`].
+    ///
+    /// Nothing is carved until every chunk the crater overlaps is loaded; [`RegionError::Deferred`] lists the chunks to wait
+    /// for.
+    ///
+    /// The edited chunks are marked dirty and their meshes are marked. To also record
+    /// [`DirtyRegions`](crate::DirtyRegions) and history, queue a [`CraterBrush`] with
+    /// [`MapEdits::apply_brush`](crate::MapEdits::apply_brush) instead, but then no debris is returned.
+    pub fn carve_sphere_with_falloff(
+        &mut self,
+        center: VoxelUnits<Vec3A>,
+        radius: VoxelUnits<f32>,
+        noise: &CraterNoise,
+    ) -> Result<Debris, RegionError> {
+        let brush = CraterBrush {
+            sphere: VoxelUnits(Sphere::new(center.0, radius.0)),
+            noise: *noise,
+        };
+        // Indexed by palette ID.
+        let mut volumes = [0.0f32; 256];
+        let mut moments = [Vec3A::ZERO; 256];
+        self.for_each_voxel_in_mut(brush.extent(), MissingChunkPolicy::Defer, |p, voxel| {
+            let old = *voxel;
+            brush.paint(VoxelUnits(p), &mut voxel.sdf, &mut voxel.palette_id);
+            let removed = solid_fraction(old.sdf) - solid_fraction(voxel.sdf);
+            if removed > 0.0 {
+                let i = usize::from(old.palette_id);
+                volumes[i] += removed;
+                moments[i] += removed * p.as_vec3a();
+            }
+        })?;
+
+        let materials = volumes
+            .iter()
+            .zip(moments.iter())
+            .enumerate()
+            .filter(|(_, (&volume, _))| volume > 0.0)
+            .map(|(i, (&volume, &moment))| DebrisMaterial {
+                palette_id: i as PaletteId8,
+                volume,
+                centroid: VoxelUnits(moment / volume),
+            })
+            .collect();
+        Ok(Debris { materials })
+    }
+}
+
+/// The fraction of a voxel that is solid, assuming the surface crosses it linearly.
+fn solid_fraction(sdf: Sd8) -> f32 {
+    (0.5 - 0.5 * f32::from(sdf)).clamp(0.0, 1.0)
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clipmap::{SdfSampler, StreamingConfig};
+    use crate::core::glam::IVec3;
+    use crate::units::ChunkUnits;
+
+    use grid_tree::NodeKey;
+
+    #[test]
+    fn crater_returns_the_removed_materials() {
+        let mut clipmap = ChunkClipMap::new(2, StreamingConfig::default());
+        for (coords, palette_id) in [(IVec3::ZERO, 3), (IVec3::X, 5)] {
+            clipmap.edit_chunk(ChunkUnits(coords), |chunk| {
+                for i in 0..16 * 16 * 16 {
+                    let p = IVec3::new(i % 16, (i / 16) % 16, i / 256);
+                    chunk.set_voxel(p, palette_id, Sd8::MIN);
+                }
+            });
+        }
+        let noise = CraterNoise {
+            amplitude: VoxelUnits(0.0),
+            ..Default::default()
+        };
+        let center = VoxelUnits(Vec3A::new(16.0, 8.0, 8.0));
+
+        let debris = clipmap
+            .carve_sphere_with_falloff(center, VoxelUnits(4.0), &noise)
+            .unwrap();
+        let sphere_volume = 4.0 / 3.0 * std::f32::consts::PI * 4.0f32.powi(3);
+        let volume = debris.total_volume();
+        assert!(volume > 0.75 * sphere_volume && volume < 1.25 * sphere_volume);
+        // The crater straddles both chunks.
+        let left = debris.get(3).unwrap();
+        let right = debris.get(5).unwrap();
+        assert!(left.centroid.0.x < 16.0 && right.centroid.0.x > 16.0);
+        assert_eq!(debris.materials.len(), 2);
+
+        let (sdf, _) = SdfSampler::new(&clipmap).voxel(VoxelUnits(IVec3::new(16, 8, 8)));
+        assert!(sdf.0 > 0);
+        assert!(clipmap.is_loaded(NodeKey::new(0, IVec3::X)));
+
+        // There's nothing left to remove.
+        let debris = clipmap
+            .carve_sphere_with_falloff(center, VoxelUnits(4.0), &noise)
+            .unwrap();
+        assert!(debris.total_volume() < 1.0);
+
+        let result = clipmap.carve_sphere_with_falloff(
+            VoxelUnits(Vec3A::new(40.0, 8.0, 8.0)),
+            VoxelUnits(4.0),
+            &noise,
+        );
+        assert!(matches!(result, Err(RegionError::Deferred { .. })));
+    }
+}
@@ -0,0 +1,395 @@
+use crate::chunk::Chunk;
+use crate::clipmap::{ChunkClipMap, ChunkNode, NodeState, VisitCommand};
+use crate::coordinates::{child_index, parent_coords};
+use crate::core::glam::IVec3;
+use crate::database::Change;
+use crate::units::ChunkUnits;
+
+use grid_tree::{NodeKey, NodePtr};
+
+/// The result of [`ChunkClipMap::edit_chunk`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EditOutcome {
+    /// The edit was written into the clipmap.
+    Applied,
+    /// The chunk (or one of its ancestors) is still loading from the database. The edit must be retried later, otherwise it
+    /// would be overwritten by the loaded data, or the loaded data would be lost.
+    Deferred,
+    /// The chunk is outside of every tree in the clipmap, so there is nothing to edit.
+    OutOfRange,
+}
+
+impl ChunkClipMap {
+    /// Applies `edit` to the LOD0 chunk at `coords`.
+    ///
+    /// If the chunk is not occupied, `edit` is given an ambient chunk, and any missing nodes on the path from the root are
+    /// created. The edited node is marked dirty, which also marks its dependent meshes and its parent's downsampled chunk as
+    /// stale.
+    pub fn edit_chunk(
+        &mut self,
+        coords: ChunkUnits<IVec3>,
+        edit: impl FnOnce(&mut Chunk),
+    ) -> EditOutcome {
+        let outcome = self.edit_chunk_without_meshes(coords, edit);
+        if outcome == EditOutcome::Applied {
+            self.mark_needs_mesh(NodeKey::new(0, coords.into_inner()));
+        }
+        outcome
+    }
+
+    /// Same as [`Self::edit_chunk`], but meshes are not marked. Callers that know which voxels were edited pass them to
+    /// [`ChunkClipMap::mark_extent_needs_mesh`] instead, so an edit in the middle of a chunk doesn't remesh its neighbors.
+    pub(crate) fn edit_chunk_without_meshes(
+        &mut self,
+        coords: ChunkUnits<IVec3>,
+        edit: impl FnOnce(&mut Chunk),
+    ) -> EditOutcome {
+        let ChunkUnits(coords) = coords;
+        let key = NodeKey::new(0, coords);
+
+        let ptr = match self.find_editable_node(key) {
+            Ok(Some(ptr)) => ptr,
+            // The path to this chunk was never created because an ancestor is empty.
+            Ok(None) => self.create_path_to_node(key),
+            Err(outcome) => return outcome,
+        };
+
+        let node = self.octree.get_value_mut(ptr).unwrap();
+        let mut chunk = node
+            .take_chunk()
+            .map(|c| {
+                c.either(
+                    |decompressed| decompressed,
+                    |compressed| Box::new(compressed.decompress()),
+                )
+            })
+            .unwrap_or_default();
+        edit(&mut *chunk);
+        let occupancy = chunk.occupancy();
+        node.put_decompressed(chunk);
+        node.state().set_dirty();
+        self.set_occupancy(key, Some(occupancy));
+        self.update_borders(key);
+
+        let parent_key = NodeKey::new(1, parent_coords(coords));
+        if let Some(parent_ptr) = self.octree.find_node(parent_key) {
+            let parent_node = self.octree.get_value(parent_ptr).unwrap();
+            parent_node.state().set_needs_downsample();
+        }
+
+        EditOutcome::Applied
+    }
+
+    /// Walks the path from the root to the node at `key`. Returns the node if it exists, or `None` if the path ends at an
+    /// ancestor that is done loading.
+    fn find_editable_node(&self, key: NodeKey<IVec3>) -> Result<Option<NodePtr>, EditOutcome> {
+        let root_level = self.octree.root_level();
+        let level_diff = (root_level - key.level) as i32;
+        let root_key = NodeKey::new(root_level, key.coordinates >> level_diff);
+        let root_node = self
+            .octree
+            .find_root(root_key)
+            .ok_or(EditOutcome::OutOfRange)?;
+
+        let mut ptr = NodePtr::new(root_level, root_node.self_ptr);
+        loop {
+            let state = self.octree.get_value(ptr).unwrap().state();
+            if state.is_loading() || state.has_load_pending() {
+                return Err(EditOutcome::Deferred);
+            }
+            if ptr.level() == key.level {
+                return Ok(Some(ptr));
+            }
+
+            let child_level = ptr.level() - 1;
+            let child_coords = key.coordinates >> (child_level - key.level) as i32;
+            let child_ptr = self
+                .octree
+                .child_pointers(ptr)
+                .and_then(|children| children.get_child(child_index(child_coords)));
+            if let Some(child_ptr) = child_ptr {
+                ptr = child_ptr;
+            } else {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Inserts empty nodes on the path from the root to `key`, which must be in range.
+    fn create_path_to_node(&mut self, key: NodeKey<IVec3>) -> NodePtr {
+        let mut target_ptr = None;
+        self.octree
+            .fill_path_to_node_from_root(key, |node_key, entry| {
+                let (ptr, _node) =
+                    entry.or_insert_with(|| ChunkNode::new_empty(NodeState::new_zeroed()));
+                if node_key == key {
+                    target_ptr = Some(NodePtr::new(key.level, ptr));
+                }
+                VisitCommand::Continue
+            });
+        target_ptr.unwrap()
+    }
+
+    /// Replaces the chunk at `key` with `chunk`, which is assumed to match the database, e.g. after reverting to another
+    /// version.
+    ///
+    /// Nothing happens if the node hasn't been loaded yet, since it will read the new version when it loads. See "Load vs Edit
+    /// Conflict Resolution" in [`ChunkClipMap::complete_pending_load`] for what happens to a load in flight. Dependent meshes
+    /// and the parent's downsampled chunk are marked as stale.
+    pub fn replace_chunk(&mut self, key: NodeKey<IVec3>, chunk: Option<Box<Chunk>>) {
+        let ptr = if let Some(ptr) = self.octree.find_node(key) {
+            ptr
+        } else if chunk.is_some() && self.find_editable_node(key) == Ok(None) {
+            // The node was pruned because an ancestor was empty, so the loader won't visit it again.
+            self.create_path_to_node(key)
+        } else {
+            return;
+        };
+        let node = self.octree.get_value_mut(ptr).unwrap();
+        // A load in flight might have read the old version, so it gets canceled.
+        node.state().clear_loading();
+        let occupancy = chunk.as_ref().map(|chunk| chunk.occupancy());
+        if let Some(chunk) = chunk {
+            node.put_decompressed(chunk);
+        } else {
+            node.take_chunk();
+        }
+        node.state().clear_dirty();
+        self.set_occupancy(key, occupancy);
+        self.update_borders(key);
+
+        self.mark_needs_mesh(key);
+        if key.level < self.octree.root_level() {
+            let parent_key = NodeKey::new(key.level + 1, parent_coords(key.coordinates));
+            if let Some(parent_ptr) = self.octree.find_node(parent_key) {
+                let parent_node = self.octree.get_value(parent_ptr).unwrap();
+                parent_node.state().set_needs_downsample();
+            }
+        }
+    }
+
+    /// Returns a copy of the chunk at `key`, or `None` if the node doesn't exist or it's still loading. Empty nodes are copied
+    /// as ambient chunks.
+    pub fn copy_chunk(&self, key: NodeKey<IVec3>) -> Option<Box<Chunk>> {
+        let ptr = self.octree.find_node(key)?;
+        let node = self.octree.get_value(ptr).unwrap();
+        if node.state().is_loading() {
+            return None;
+        }
+        Some(
+            node.get_decompressed()
+                .map_or_else(Box::default, |chunk| Box::new(*chunk.as_ref())),
+        )
+    }
+
+    /// If the chunk at `key` is dirty, clears the dirty bit and returns a copy of the chunk to write to the database.
+    ///
+    /// Returns `None` if the node doesn't exist or it has no unsaved changes.
+    pub fn take_dirty_chunk(&self, key: NodeKey<IVec3>) -> Option<Change<Box<Chunk>>> {
+        let ptr = self.octree.find_node(key)?;
+        self.take_dirty_chunk_at(ptr)
+    }
+
+    /// Same as [`Self::take_dirty_chunk`] for every node in the clipmap, e.g. to save all unsaved changes before the app exits.
+    pub fn take_all_dirty_chunks(&self) -> Vec<(NodeKey<IVec3>, Change<Box<Chunk>>)> {
+        let mut dirty = Vec::new();
+        for (root_key, root_node) in self.octree.iter_roots() {
+            self.octree.visit_tree_depth_first(
+                NodePtr::new(root_key.level, root_node.self_ptr),
+                root_key.coordinates,
+                0,
+                |ptr, coords| {
+                    if let Some(change) = self.take_dirty_chunk_at(ptr) {
+                        dirty.push((NodeKey::new(ptr.level(), coords), change));
+                    }
+                    VisitCommand::Continue
+                },
+            );
+        }
+        dirty
+    }
+
+    fn take_dirty_chunk_at(&self, ptr: NodePtr) -> Option<Change<Box<Chunk>>> {
+        let node = self.octree.get_value(ptr).unwrap();
+        if !node.state().fetch_and_clear_dirty() {
+            return None;
+        }
+        Some(match node.get_decompressed() {
+            Some(decompressed) => Change::Insert(Box::new(*decompressed.as_ref())),
+            None => Change::Remove,
+        })
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipmap::StreamingConfig;
+    use crate::sdf::Sd8;
+
+    fn insert_root(clipmap: &mut ChunkClipMap, state: NodeState) {
+        let root_key = NodeKey::new(clipmap.octree.root_level(), IVec3::ZERO);
+        let mut state = Some(state);
+        clipmap
+            .octree
+            .fill_path_to_node_from_root(root_key, |_key, entry| {
+                entry.or_insert_with(|| ChunkNode::new_empty(state.take().unwrap()));
+                VisitCommand::Continue
+            });
+    }
+
+    #[test]
+    fn edit_creates_path_under_empty_root() {
+        let mut clipmap = ChunkClipMap::new(3, StreamingConfig::default());
+        insert_root(&mut clipmap, NodeState::new_zeroed());
+
+        let coords = IVec3::new(1, 2, 3);
+        let outcome = clipmap.edit_chunk(ChunkUnits(coords), |chunk| {
+            chunk.set_voxel(IVec3::ZERO, 5, Sd8::MIN);
+        });
+        assert_eq!(outcome, EditOutcome::Applied);
+
+        let key = NodeKey::new(0, coords);
+        match clipmap.take_dirty_chunk(key) {
+            Some(Change::Insert(chunk)) => {
+                assert_eq!(chunk.palette_ids[0], 5);
+                assert_eq!(chunk.sdf[0], Sd8::MIN);
+            }
+            _ => panic!("Edited chunk should be dirty"),
+        }
+        // Dirty bit was cleared.
+        assert!(clipmap.take_dirty_chunk(key).is_none());
+
+        // The parent needs to be downsampled.
+        let parent_ptr = clipmap
+            .octree
+            .find_node(NodeKey::new(1, IVec3::new(0, 1, 1)))
+            .unwrap();
+        assert!(clipmap
+            .octree
+            .get_value(parent_ptr)
+            .unwrap()
+            .state()
+            .needs_downsample());
+    }
+
+    #[test]
+    fn take_all_dirty_chunks_clears_every_dirty_bit() {
+        let mut clipmap = ChunkClipMap::new(3, StreamingConfig::default());
+        insert_root(&mut clipmap, NodeState::new_zeroed());
+
+        for coords in [IVec3::ZERO, IVec3::new(3, 1, 2)] {
+            clipmap.edit_chunk(ChunkUnits(coords), |chunk| {
+                chunk.set_voxel(IVec3::ZERO, 5, Sd8::MIN);
+            });
+        }
+        let mut dirty: Vec<_> = clipmap
+            .take_all_dirty_chunks()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        dirty.sort_by_key(|key| key.coordinates.to_array());
+        assert_eq!(
+            dirty,
+            [
+                NodeKey::new(0, IVec3::ZERO),
+                NodeKey::new(0, IVec3::new(3, 1, 2))
+            ]
+        );
+        assert!(clipmap.take_all_dirty_chunks().is_empty());
+    }
+
+    #[test]
+    fn edit_is_deferred_while_loading() {
+        let mut clipmap = ChunkClipMap::new(3, StreamingConfig::default());
+        insert_root(&mut clipmap, NodeState::new_loading());
+
+        assert_eq!(
+            clipmap.edit_chunk(ChunkUnits(IVec3::ZERO), |_| panic!("Should not edit")),
+            EditOutcome::Deferred
+        );
+    }
+
+    #[test]
+    fn replace_overwrites_unsaved_edits() {
+        let mut clipmap = ChunkClipMap::new(3, StreamingConfig::default());
+        insert_root(&mut clipmap, NodeState::new_zeroed());
+
+        let coords = IVec3::new(1, 2, 3);
+        let key = NodeKey::new(0, coords);
+        clipmap.edit_chunk(ChunkUnits(coords), |chunk| {
+            chunk.set_voxel(IVec3::ZERO, 5, Sd8::MIN);
+        });
+        clipmap.replace_chunk(key, None);
+
+        // The reverted chunk matches the database, so there is nothing to save.
+        assert!(clipmap.take_dirty_chunk(key).is_none());
+        let ptr = clipmap.octree.find_node(key).unwrap();
+        assert!(clipmap
+            .octree
+            .get_value(ptr)
+            .unwrap()
+            .get_decompressed()
+            .is_none());
+
+        // Restoring a chunk under an empty ancestor creates the path to it.
+        let restored_key = NodeKey::new(0, IVec3::new(7, 7, 7));
+        clipmap.replace_chunk(restored_key, Some(Box::new(Chunk::default())));
+        let restored_ptr = clipmap.octree.find_node(restored_key).unwrap();
+        assert!(clipmap
+            .octree
+            .get_value(restored_ptr)
+            .unwrap()
+            .get_decompressed()
+            .is_some());
+    }
+
+    #[test]
+    fn edit_outside_clipmap_is_out_of_range() {
+        let mut clipmap = ChunkClipMap::new(3, StreamingConfig::default());
+
+        assert_eq!(
+            clipmap.edit_chunk(ChunkUnits(IVec3::ZERO), |_| panic!("Should not edit")),
+            EditOutcome::OutOfRange
+        );
+    }
+
+    #[test]
+    fn edit_marks_dependent_meshes() {
+        let mut clipmap = ChunkClipMap::new(3, StreamingConfig::default());
+        insert_root(&mut clipmap, NodeState::new_zeroed());
+
+        let coords = IVec3::new(1, 2, 3);
+        // The negative neighbor is rendering, and its padding reads the edited chunk.
+        let neighbor_coords = coords - IVec3::X;
+        for c in [coords, neighbor_coords] {
+            clipmap.edit_chunk(ChunkUnits(c), |_| ());
+        }
+        fn state(clipmap: &ChunkClipMap, coords: IVec3) -> &NodeState {
+            let ptr = clipmap.octree.find_node(NodeKey::new(0, coords)).unwrap();
+            clipmap.octree.get_value(ptr).unwrap().state()
+        }
+        for c in [coords, neighbor_coords] {
+            state(&clipmap, c).set_rendering();
+        }
+
+        clipmap.edit_chunk_without_meshes(ChunkUnits(coords), |chunk| {
+            chunk.set_voxel(IVec3::ZERO, 5, Sd8::MIN);
+        });
+        assert!(!state(&clipmap, coords).needs_mesh());
+        assert!(!state(&clipmap, neighbor_coords).needs_mesh());
+
+        clipmap.edit_chunk(ChunkUnits(coords), |chunk| {
+            chunk.set_voxel(IVec3::ZERO, 6, Sd8::MIN);
+        });
+        assert!(state(&clipmap, coords).needs_mesh());
+        assert!(state(&clipmap, neighbor_coords).needs_mesh());
+    }
+}
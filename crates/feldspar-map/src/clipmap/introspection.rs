@@ -0,0 +1,206 @@
+use crate::clipmap::{ChunkClipMap, NodeState, SlotState, VisitCommand};
+use crate::core::glam::IVec3;
+
+use grid_tree::{NodeKey, NodePtr};
+
+/// A node's place in the chunk state machine, as drawn in `assets/chunk_fsm.png`. Evicted chunks have no node.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ChunkState {
+    Loading,
+    EmptyClean,
+    EmptyDirty,
+    CompressedClean,
+    /// Not in the diagram, but the cache can compress a dirty chunk before it's persisted.
+    CompressedDirty,
+    DecompressedClean,
+    DecompressedDirty,
+}
+
+impl ChunkState {
+    pub const ALL: [Self; 7] = [
+        Self::Loading,
+        Self::EmptyClean,
+        Self::EmptyDirty,
+        Self::CompressedClean,
+        Self::CompressedDirty,
+        Self::DecompressedClean,
+        Self::DecompressedDirty,
+    ];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// A snapshot of a node's [`NodeState`], taken by [`ChunkClipMap::node_state`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NodeStateSnapshot {
+    pub chunk_state: ChunkState,
+    /// The node has been claimed by a load batch that hasn't been inserted yet.
+    pub load_pending: bool,
+    pub rendering: bool,
+    pub needs_downsample: bool,
+    pub needs_mesh: bool,
+}
+
+impl NodeStateSnapshot {
+    pub fn new(state: &NodeState) -> Self {
+        let dirty = state.is_dirty();
+        let chunk_state = if state.is_loading() {
+            ChunkState::Loading
+        } else {
+            match (state.slot_state(), dirty) {
+                (SlotState::Empty, false) => ChunkState::EmptyClean,
+                (SlotState::Empty, true) => ChunkState::EmptyDirty,
+                (SlotState::Compressed | SlotState::Mapped, false) => ChunkState::CompressedClean,
+                (SlotState::Compressed | SlotState::Mapped, true) => ChunkState::CompressedDirty,
+                (SlotState::Decompressed, false) => ChunkState::DecompressedClean,
+                (SlotState::Decompressed, true) => ChunkState::DecompressedDirty,
+            }
+        };
+        Self {
+            chunk_state,
+            load_pending: state.has_load_pending(),
+            rendering: state.is_rendering(),
+            needs_downsample: state.needs_downsample(),
+            needs_mesh: state.needs_mesh(),
+        }
+    }
+}
+
+/// The number of nodes in each [`ChunkState`], as counted by [`ChunkClipMap::count_node_states`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ChunkStateCounts {
+    counts: [usize; ChunkState::ALL.len()],
+    /// Nodes with a load pending, in any state.
+    pub load_pending: usize,
+    /// Nodes that are being rendered, in any state.
+    pub rendering: usize,
+}
+
+impl ChunkStateCounts {
+    pub fn get(&self, state: ChunkState) -> usize {
+        self.counts[state.index()]
+    }
+
+    /// The number of nodes in the clipmap.
+    pub fn total(&self) -> usize {
+        self.counts.iter().sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ChunkState, usize)> + '_ {
+        ChunkState::ALL
+            .into_iter()
+            .map(|state| (state, self.get(state)))
+    }
+
+    fn add(&mut self, snapshot: NodeStateSnapshot) {
+        self.counts[snapshot.chunk_state.index()] += 1;
+        self.load_pending += usize::from(snapshot.load_pending);
+        self.rendering += usize::from(snapshot.rendering);
+    }
+}
+
+impl ChunkClipMap {
+    /// The state of the node at `key`, or `None` if there is no node, e.g. because it was evicted.
+    pub fn node_state(&self, key: NodeKey<IVec3>) -> Option<NodeStateSnapshot> {
+        let ptr = self.octree.find_node(key)?;
+        Some(NodeStateSnapshot::new(self.octree.get_value(ptr)?.state()))
+    }
+
+    /// Returns `true` if the node at `key` exists and isn't waiting for its chunk to load.
+    pub fn is_loaded(&self, key: NodeKey<IVec3>) -> bool {
+        self.octree
+            .find_node(key)
+            .and_then(|ptr| self.octree.get_value(ptr))
+            .map_or(false, |node| !node.state().is_loading())
+    }
+
+    /// Counts the nodes in each [`ChunkState`] by visiting the whole tree, so it's meant for tests and debug UIs, not for every
+    /// frame.
+    pub fn count_node_states(&self) -> ChunkStateCounts {
+        let mut counts = ChunkStateCounts::default();
+        for (root_key, root_node) in self.octree.iter_roots() {
+            self.octree.visit_tree_depth_first(
+                NodePtr::new(root_key.level, root_node.self_ptr),
+                root_key.coordinates,
+                0,
+                |ptr, _coords| {
+                    let node = self.octree.get_value(ptr).unwrap();
+                    counts.add(NodeStateSnapshot::new(node.state()));
+                    VisitCommand::Continue
+                },
+            );
+        }
+        counts
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipmap::{ChunkNode, StreamingConfig};
+    use crate::sdf::Sd8;
+    use crate::units::ChunkUnits;
+
+    #[test]
+    fn node_states_follow_edits_and_saves() {
+        let mut clipmap = ChunkClipMap::new(3, StreamingConfig::default());
+        let root_key = NodeKey::new(clipmap.octree.root_level(), IVec3::ZERO);
+        clipmap
+            .octree
+            .fill_path_to_node_from_root(root_key, |_key, entry| {
+                entry.or_insert_with(|| ChunkNode::new_empty(NodeState::new_zeroed()));
+                VisitCommand::Continue
+            });
+        assert_eq!(
+            clipmap.node_state(root_key).unwrap().chunk_state,
+            ChunkState::EmptyClean
+        );
+
+        let key = NodeKey::new(0, IVec3::new(1, 2, 3));
+        assert_eq!(clipmap.node_state(key), None);
+        clipmap.edit_chunk(ChunkUnits(key.coordinates), |chunk| {
+            chunk.set_voxel(IVec3::ZERO, 5, Sd8::MIN);
+        });
+        assert_eq!(
+            clipmap.node_state(key),
+            Some(NodeStateSnapshot {
+                chunk_state: ChunkState::DecompressedDirty,
+                load_pending: false,
+                rendering: false,
+                needs_downsample: false,
+                needs_mesh: false,
+            })
+        );
+        let parent = clipmap
+            .node_state(NodeKey::new(1, IVec3::new(0, 1, 1)))
+            .unwrap();
+        assert_eq!(parent.chunk_state, ChunkState::EmptyClean);
+        assert!(parent.needs_downsample);
+
+        let counts = clipmap.count_node_states();
+        assert_eq!(counts.total(), 3);
+        assert_eq!(counts.get(ChunkState::EmptyClean), 2);
+        assert_eq!(counts.get(ChunkState::DecompressedDirty), 1);
+
+        clipmap.take_dirty_chunk(key).unwrap();
+        assert_eq!(
+            clipmap.node_state(key).unwrap().chunk_state,
+            ChunkState::DecompressedClean
+        );
+        assert_eq!(
+            clipmap
+                .count_node_states()
+                .get(ChunkState::DecompressedClean),
+            1
+        );
+    }
+}
@@ -0,0 +1,395 @@
+use crate::chunk::{ChunkShape, LightChunk, CHUNK_SHAPE_IVEC3, CHUNK_SIZE, MAX_LIGHT};
+use crate::clipmap::{ChunkClipMap, SdfSampler};
+use crate::coordinates::{chunk_extent_ivec3, chunk_min, in_chunk};
+use crate::core::glam::{const_ivec3, IVec3};
+use crate::core::ilattice::prelude::Extent;
+use crate::core::{SmallKeyHashMap, SmallKeyHashSet};
+use crate::units::{ChunkUnits, VoxelUnits};
+
+use grid_tree::NodeKey;
+use ndshape::ConstShape;
+use std::collections::VecDeque;
+
+const UP: IVec3 = const_ivec3!([0, 1, 0]);
+const DOWN: IVec3 = const_ivec3!([0, -1, 0]);
+const FACE_OFFSETS: [IVec3; 6] = [
+    const_ivec3!([-1, 0, 0]),
+    const_ivec3!([1, 0, 0]),
+    DOWN,
+    UP,
+    const_ivec3!([0, 0, -1]),
+    const_ivec3!([0, 0, 1]),
+];
+
+impl ChunkClipMap {
+    /// The light levels of the LOD0 chunk at `coords`, or `None` if it hasn't been lit.
+    pub fn light(&self, coords: ChunkUnits<IVec3>) -> Option<&LightChunk> {
+        self.light.get(&coords.into_inner()).map(|l| &**l)
+    }
+
+    /// The light level of the LOD0 voxel at `p`. Voxels in chunks that haven't been lit are assumed to be open to the sky.
+    pub fn light_level(&self, p: VoxelUnits<IVec3>) -> u8 {
+        let (coords, index) = locate(p.into_inner());
+        self.light.get(&coords).map_or(MAX_LIGHT, |l| l[index])
+    }
+
+    /// Replaces the light levels of the LOD0 chunk at `coords` without propagating them, e.g. after reading them from a
+    /// [`LightLayer`](crate::chunk::LightLayer), and marks the meshes that depend on it.
+    pub fn set_light(&mut self, coords: ChunkUnits<IVec3>, light: Box<LightChunk>) {
+        let ChunkUnits(coords) = coords;
+        self.light.insert(coords, light);
+        self.mark_needs_mesh(NodeKey::new(0, coords));
+    }
+
+    pub fn remove_light(&mut self, coords: ChunkUnits<IVec3>) -> Option<Box<LightChunk>> {
+        let ChunkUnits(coords) = coords;
+        let removed = self.light.remove(&coords);
+        if removed.is_some() {
+            self.mark_needs_mesh(NodeKey::new(0, coords));
+        }
+        removed
+    }
+
+    /// Floods light into the LOD0 chunk at `coords` and back out into its lit neighbors. If the chunk was already lit, its
+    /// old light is removed first, along with all of the light that it sent into its neighbors.
+    ///
+    /// Sunlight enters through the top of the chunk if the chunk above isn't lit. It's corrected once the chunk above is lit,
+    /// so chunks should be lit from the top down to avoid flooding caves with light that gets removed later.
+    ///
+    /// Returns `false` without lighting anything if the chunk's node doesn't exist or it's still loading. Meshes are marked
+    /// wherever the light changed.
+    pub fn light_chunk(&mut self, coords: ChunkUnits<IVec3>) -> bool {
+        let ChunkUnits(coords) = coords;
+        let is_loaded = self
+            .octree
+            .find_node(NodeKey::new(0, coords))
+            .map_or(false, |ptr| {
+                !self.octree.get_value(ptr).unwrap().state().is_loading()
+            });
+        if !is_loaded {
+            return false;
+        }
+
+        let VoxelUnits(extent) = chunk_extent_ivec3(ChunkUnits(coords));
+        if self.light.contains_key(&coords) {
+            self.propagate_light(|flood| flood.darken(extent));
+        } else {
+            self.light.insert(coords, Box::new([0; CHUNK_SIZE]));
+        }
+        self.propagate_light(|flood| flood.relight(extent));
+
+        // The top of the chunk below was open to the sky.
+        if self.light.contains_key(&(coords + DOWN)) {
+            let top_layer = Extent::from_min_and_shape(
+                extent.minimum + DOWN,
+                IVec3::new(CHUNK_SHAPE_IVEC3.x, 1, CHUNK_SHAPE_IVEC3.z),
+            );
+            self.propagate_light(|flood| flood.relight(top_layer));
+        }
+        true
+    }
+
+    /// Updates the light after the LOD0 voxels in `extent` were edited. Light is removed from everywhere it came through a
+    /// voxel that became solid, then refilled from the remaining sources, so the cost depends on how far the changed light
+    /// travels rather than on the size of the map.
+    ///
+    /// Only lit chunks are updated. Meshes are marked wherever the light changed.
+    pub fn relight(&mut self, extent: VoxelUnits<Extent<IVec3>>) {
+        let VoxelUnits(extent) = extent;
+        self.propagate_light(|flood| flood.relight(extent));
+    }
+
+    fn propagate_light(&mut self, f: impl FnOnce(&mut LightFlood<'_>)) {
+        let mut light = std::mem::take(&mut self.light);
+        let mut flood = LightFlood {
+            sampler: SdfSampler::new(self),
+            light: &mut light,
+            changed: SmallKeyHashSet::default(),
+        };
+        f(&mut flood);
+        let changed = flood.changed;
+        self.light = light;
+
+        for coords in changed.into_iter() {
+            self.mark_needs_mesh(NodeKey::new(0, coords));
+        }
+    }
+}
+
+/// The chunk containing `p` and the index of `p` in that chunk.
+fn locate(p: IVec3) -> (IVec3, usize) {
+    let ChunkUnits(coords) = in_chunk(VoxelUnits(p));
+    let VoxelUnits(min) = chunk_min(ChunkUnits(coords));
+    (coords, ChunkShape::linearize((p - min).to_array()) as usize)
+}
+
+/// The level that reaches a neighbor after light at `level` moves one voxel in `direction`.
+fn attenuate(level: u8, direction: IVec3) -> u8 {
+    if level == MAX_LIGHT && direction == DOWN {
+        MAX_LIGHT
+    } else {
+        level.saturating_sub(1)
+    }
+}
+
+/// Breadth-first light propagation over the lit LOD0 chunks. Solid voxels (with negative distance) block light and are never
+/// lit.
+struct LightFlood<'a> {
+    sampler: SdfSampler<'a>,
+    light: &'a mut SmallKeyHashMap<IVec3, Box<LightChunk>>,
+    changed: SmallKeyHashSet<IVec3>,
+}
+
+impl LightFlood<'_> {
+    /// `None` if the chunk containing `p` isn't lit.
+    fn get(&self, p: IVec3) -> Option<u8> {
+        let (coords, index) = locate(p);
+        self.light.get(&coords).map(|l| l[index])
+    }
+
+    fn set(&mut self, p: IVec3, level: u8) {
+        let (coords, index) = locate(p);
+        if let Some(light) = self.light.get_mut(&coords) {
+            if light[index] != level {
+                light[index] = level;
+                self.changed.insert(coords);
+            }
+        }
+    }
+
+    fn is_opaque(&mut self, p: IVec3) -> bool {
+        let (sdf, _) = self.sampler.voxel(VoxelUnits(p));
+        sdf.0 < 0
+    }
+
+    /// The brightest light that any neighbor sends to `p`.
+    fn support(&self, p: IVec3) -> u8 {
+        FACE_OFFSETS
+            .iter()
+            .map(|&offset| {
+                let neighbor = p + offset;
+                let level = if offset == UP {
+                    self.get(neighbor).unwrap_or(MAX_LIGHT)
+                } else {
+                    self.get(neighbor).unwrap_or(0)
+                };
+                attenuate(level, -offset)
+            })
+            .max()
+            .unwrap()
+    }
+
+    fn relight(&mut self, extent: Extent<IVec3>) {
+        // Remove light that lost its source.
+        let mut removals = VecDeque::new();
+        for p in extent.iter3() {
+            let level = match self.get(p) {
+                Some(level) if level > 0 => level,
+                _ => continue,
+            };
+            if self.is_opaque(p) || level > self.support(p) {
+                self.set(p, 0);
+                removals.push_back((p, level));
+            }
+        }
+        let mut additions = self.remove(removals);
+
+        // Then fill in light from the neighbors and the sky.
+        for p in extent.iter3() {
+            let level = match self.get(p) {
+                Some(level) => level,
+                None => continue,
+            };
+            if self.is_opaque(p) {
+                continue;
+            }
+            let support = self.support(p);
+            if support > level {
+                self.set(p, support);
+                additions.push_back(p);
+            }
+        }
+        self.spread(additions);
+    }
+
+    /// Darkens every voxel in `extent` and everywhere that its light reached, then spreads the light from the other sources
+    /// back into the darkened voxels outside of `extent`.
+    fn darken(&mut self, extent: Extent<IVec3>) {
+        let mut removals = VecDeque::new();
+        for p in extent.iter3() {
+            if let Some(level) = self.get(p).filter(|&level| level > 0) {
+                self.set(p, 0);
+                removals.push_back((p, level));
+            }
+        }
+        let respread = self.remove(removals);
+        self.spread(respread);
+    }
+
+    /// Darkens every voxel that was lit by one of the `removals`, given with their old levels. Returns the brighter voxels at
+    /// the edge of the darkened region, which have to spread their light into it again.
+    fn remove(&mut self, mut removals: VecDeque<(IVec3, u8)>) -> VecDeque<IVec3> {
+        let mut respread = VecDeque::new();
+        while let Some((p, level)) = removals.pop_front() {
+            for offset in FACE_OFFSETS {
+                let neighbor = p + offset;
+                let neighbor_level = match self.get(neighbor) {
+                    Some(level) if level > 0 => level,
+                    _ => continue,
+                };
+                if neighbor_level < level || (offset == DOWN && level == MAX_LIGHT) {
+                    self.set(neighbor, 0);
+                    removals.push_back((neighbor, neighbor_level));
+                } else {
+                    respread.push_back(neighbor);
+                }
+            }
+        }
+        respread
+    }
+
+    fn spread(&mut self, mut queue: VecDeque<IVec3>) {
+        while let Some(p) = queue.pop_front() {
+            let level = self.get(p).unwrap_or(0);
+            if level == 0 {
+                continue;
+            }
+            for offset in FACE_OFFSETS {
+                let neighbor = p + offset;
+                let neighbor_level = match self.get(neighbor) {
+                    Some(level) => level,
+                    None => continue,
+                };
+                let new_level = attenuate(level, offset);
+                if new_level > neighbor_level && !self.is_opaque(neighbor) {
+                    self.set(neighbor, new_level);
+                    queue.push_back(neighbor);
+                }
+            }
+        }
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::Chunk;
+    use crate::clipmap::{ChunkNode, NodeState, StreamingConfig, VisitCommand};
+    use crate::sdf::Sd8;
+
+    fn insert_chunk(clipmap: &mut ChunkClipMap, coords: IVec3, chunk: Option<Chunk>) {
+        let key = NodeKey::new(0, coords);
+        clipmap
+            .octree
+            .fill_path_to_node_from_root(key, |node_key, entry| {
+                entry.or_insert_with(|| match chunk {
+                    Some(chunk) if node_key == key => {
+                        ChunkNode::new_decompressed(Box::new(chunk), NodeState::new_zeroed())
+                    }
+                    _ => ChunkNode::new_empty(NodeState::new_zeroed()),
+                });
+                VisitCommand::Continue
+            });
+    }
+
+    fn set_solid(clipmap: &mut ChunkClipMap, p: IVec3, solid: bool) {
+        clipmap.edit_chunk(in_chunk(VoxelUnits(p)), |chunk| {
+            let (_, index) = locate(p);
+            chunk.sdf[index] = if solid { Sd8::MIN } else { Sd8::MAX };
+        });
+        clipmap.relight(VoxelUnits(Extent::from_min_and_shape(p, IVec3::ONE)));
+    }
+
+    fn light(clipmap: &ChunkClipMap, p: [i32; 3]) -> u8 {
+        clipmap.light_level(VoxelUnits(IVec3::from(p)))
+    }
+
+    #[test]
+    fn sunlight_fills_columns_and_spreads_under_roofs() {
+        let mut clipmap = ChunkClipMap::new(3, StreamingConfig::default());
+        // A column of two air chunks.
+        insert_chunk(&mut clipmap, IVec3::ZERO, Some(Chunk::default()));
+        insert_chunk(&mut clipmap, UP, Some(Chunk::default()));
+        assert!(clipmap.light_chunk(ChunkUnits(UP)));
+        assert!(clipmap.light_chunk(ChunkUnits(IVec3::ZERO)));
+        assert_eq!(light(&clipmap, [5, 0, 5]), MAX_LIGHT);
+        assert!(clipmap
+            .light(ChunkUnits(IVec3::ZERO))
+            .unwrap()
+            .iter()
+            .all(|&l| l == MAX_LIGHT));
+
+        // Roof over x < 8 at y = 20. Sunlight wraps around the edge of the roof, losing a level per step.
+        for z in 0..16 {
+            for x in 0..8 {
+                set_solid(&mut clipmap, IVec3::new(x, 20, z), true);
+            }
+        }
+        assert_eq!(light(&clipmap, [3, 20, 3]), 0);
+        assert_eq!(light(&clipmap, [8, 5, 3]), MAX_LIGHT);
+        assert_eq!(light(&clipmap, [7, 19, 3]), MAX_LIGHT - 1);
+        assert_eq!(light(&clipmap, [4, 0, 3]), MAX_LIGHT - 4);
+
+        // Opening a hole lets sunlight down again.
+        set_solid(&mut clipmap, IVec3::new(2, 20, 3), false);
+        assert_eq!(light(&clipmap, [2, 0, 3]), MAX_LIGHT);
+        assert_eq!(light(&clipmap, [2, 0, 5]), MAX_LIGHT - 2);
+    }
+
+    #[test]
+    fn lighting_the_chunk_above_shadows_the_chunk_below() {
+        let mut clipmap = ChunkClipMap::new(3, StreamingConfig::default());
+        insert_chunk(&mut clipmap, IVec3::ZERO, Some(Chunk::default()));
+        assert!(clipmap.light_chunk(ChunkUnits(IVec3::ZERO)));
+        assert_eq!(light(&clipmap, [5, 0, 5]), MAX_LIGHT);
+
+        // A solid chunk above covers the whole column.
+        let mut solid = Chunk::default();
+        solid.sdf.fill(Sd8::MIN);
+        insert_chunk(&mut clipmap, UP, Some(solid));
+        assert!(clipmap.light_chunk(ChunkUnits(UP)));
+        assert!(clipmap
+            .light(ChunkUnits(IVec3::ZERO))
+            .unwrap()
+            .iter()
+            .all(|&l| l == 0));
+
+        // Missing chunks can't be lit.
+        assert!(!clipmap.light_chunk(ChunkUnits(IVec3::new(5, 0, 0))));
+    }
+
+    #[test]
+    fn lighting_a_chunk_again_removes_its_old_light_from_the_neighbors() {
+        let mut clipmap = ChunkClipMap::new(3, StreamingConfig::default());
+        let right = IVec3::X;
+        // Two chunks under one roof, so they're dark.
+        let mut roofed = Chunk::default();
+        for z in 0..16 {
+            for x in 0..16 {
+                roofed.set_voxel(IVec3::new(x, 15, z), 1, Sd8::MIN);
+            }
+        }
+        insert_chunk(&mut clipmap, IVec3::ZERO, Some(roofed));
+        insert_chunk(&mut clipmap, right, Some(roofed));
+        assert!(clipmap.light_chunk(ChunkUnits(IVec3::ZERO)));
+        assert!(clipmap.light_chunk(ChunkUnits(right)));
+        assert_eq!(light(&clipmap, [20, 5, 5]), 0);
+
+        // E.g. light read from a layer that was saved before the roof was built spreads into the neighbor.
+        clipmap.set_light(ChunkUnits(IVec3::ZERO), Box::new([MAX_LIGHT; CHUNK_SIZE]));
+        clipmap.relight(chunk_extent_ivec3(ChunkUnits(right)));
+        assert_eq!(light(&clipmap, [16, 5, 5]), MAX_LIGHT - 1);
+
+        assert!(clipmap.light_chunk(ChunkUnits(IVec3::ZERO)));
+        assert_eq!(light(&clipmap, [5, 5, 5]), 0);
+        assert_eq!(light(&clipmap, [16, 5, 5]), 0);
+        assert_eq!(light(&clipmap, [20, 5, 5]), 0);
+    }
+}
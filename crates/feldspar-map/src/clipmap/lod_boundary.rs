@@ -0,0 +1,204 @@
+use crate::clipmap::{ChunkClipMap, Level, NodePtr, VisitCommand};
+use crate::core::glam::{const_ivec3, IVec3};
+use crate::core::SmallKeyHashSet;
+
+use grid_tree::NodeKey;
+
+/// One of the 6 faces of a chunk.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Face {
+    NegX,
+    PosX,
+    NegY,
+    PosY,
+    NegZ,
+    PosZ,
+}
+
+impl Face {
+    pub const ALL: [Self; 6] = [
+        Self::NegX,
+        Self::PosX,
+        Self::NegY,
+        Self::PosY,
+        Self::NegZ,
+        Self::PosZ,
+    ];
+
+    /// The outward unit normal.
+    pub const fn normal(&self) -> IVec3 {
+        match self {
+            Self::NegX => const_ivec3!([-1, 0, 0]),
+            Self::PosX => const_ivec3!([1, 0, 0]),
+            Self::NegY => const_ivec3!([0, -1, 0]),
+            Self::PosY => const_ivec3!([0, 1, 0]),
+            Self::NegZ => const_ivec3!([0, 0, -1]),
+            Self::PosZ => const_ivec3!([0, 0, 1]),
+        }
+    }
+
+    /// The index of the axis perpendicular to this face.
+    pub const fn axis(&self) -> usize {
+        match self {
+            Self::NegX | Self::PosX => 0,
+            Self::NegY | Self::PosY => 1,
+            Self::NegZ | Self::PosZ => 2,
+        }
+    }
+
+    pub const fn is_positive(&self) -> bool {
+        matches!(self, Self::PosX | Self::PosY | Self::PosZ)
+    }
+
+    pub const fn opposite(&self) -> Self {
+        match self {
+            Self::NegX => Self::PosX,
+            Self::PosX => Self::NegX,
+            Self::NegY => Self::PosY,
+            Self::PosY => Self::NegY,
+            Self::NegZ => Self::PosZ,
+            Self::PosZ => Self::NegZ,
+        }
+    }
+}
+
+/// A face where a rendered chunk touches rendered chunks at a finer level of detail.
+///
+/// Meshes generated independently on either side of this face will not line up, so the finer chunks need transition geometry on
+/// the opposite face. See [`resample_transition_face`](crate::transition::resample_transition_face).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LodBoundary {
+    /// The key of the coarser chunk.
+    pub coarse_key: NodeKey<IVec3>,
+    /// The face of the coarser chunk that touches the finer chunks.
+    pub face: Face,
+}
+
+impl ChunkClipMap {
+    /// Enumerates all faces where the rendered cross section of the octree changes level of detail.
+    ///
+    /// Only the coarse side of each boundary is reported. It's assumed that adjacent rendered chunks differ by at most one
+    /// level, which holds as long as [`StreamingConfig::detail`](crate::clipmap::StreamingConfig::detail) is large enough.
+    pub fn lod_boundaries(&self) -> Vec<LodBoundary> {
+        let root_level = self.octree.root_level();
+
+        // Find the rendered cross section, along with all ancestors of rendered nodes.
+        let mut rendering = SmallKeyHashSet::default();
+        let mut has_rendering_descendant = SmallKeyHashSet::default();
+        for (root_key, root_node) in self.octree.iter_roots() {
+            let root_ptr = NodePtr::new(root_level, root_node.self_ptr);
+            self.octree
+                .visit_tree_depth_first(root_ptr, root_key.coordinates, 0, |ptr, coords| {
+                    let node = self.octree.get_value(ptr).unwrap();
+                    if !node.state().is_rendering() {
+                        return VisitCommand::Continue;
+                    }
+                    rendering.insert((ptr.level(), coords));
+                    let mut ancestor = (ptr.level(), coords);
+                    while ancestor.0 < root_level {
+                        ancestor = (ancestor.0 + 1, ancestor.1 >> 1);
+                        if !has_rendering_descendant.insert(ancestor) {
+                            // The rest of this path was already inserted by a sibling.
+                            break;
+                        }
+                    }
+                    VisitCommand::SkipDescendants
+                });
+        }
+
+        let mut boundaries = Vec::new();
+        for &(level, coords) in rendering.iter() {
+            if level == 0 {
+                // Nothing can be finer than level 0.
+                continue;
+            }
+            for face in Face::ALL {
+                let neighbor = (level, coords + face.normal());
+                if !rendering.contains(&neighbor)
+                    && has_rendering_descendant.contains(&neighbor)
+                    && !has_rendering_ancestor(&rendering, root_level, neighbor)
+                {
+                    boundaries.push(LodBoundary {
+                        coarse_key: NodeKey::new(level, coords),
+                        face,
+                    });
+                }
+            }
+        }
+        boundaries
+    }
+}
+
+fn has_rendering_ancestor(
+    rendering: &SmallKeyHashSet<(Level, IVec3)>,
+    root_level: Level,
+    (mut level, mut coords): (Level, IVec3),
+) -> bool {
+    while level < root_level {
+        level += 1;
+        coords >>= 1;
+        if rendering.contains(&(level, coords)) {
+            return true;
+        }
+    }
+    false
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipmap::{ChunkNode, NodeState, StreamingConfig};
+
+    fn insert_rendering(clipmap: &mut ChunkClipMap, key: NodeKey<IVec3>) {
+        clipmap
+            .octree
+            .fill_path_to_node_from_root(key, |node_key, entry| {
+                let (_ptr, node) =
+                    entry.or_insert_with(|| ChunkNode::new_empty(NodeState::new_zeroed()));
+                if node_key == key {
+                    node.state().set_rendering();
+                }
+                VisitCommand::Continue
+            });
+    }
+
+    #[test]
+    fn finer_neighbor_is_a_boundary() {
+        let mut clipmap = ChunkClipMap::new(3, StreamingConfig::default());
+
+        // A coarse chunk at level 1, and the 8 finer chunks of its +X neighbor.
+        insert_rendering(&mut clipmap, NodeKey::new(1, IVec3::ZERO));
+        for z in 0..2 {
+            for y in 0..2 {
+                for x in 2..4 {
+                    insert_rendering(&mut clipmap, NodeKey::new(0, IVec3::new(x, y, z)));
+                }
+            }
+        }
+
+        assert_eq!(
+            clipmap.lod_boundaries(),
+            vec![LodBoundary {
+                coarse_key: NodeKey::new(1, IVec3::ZERO),
+                face: Face::PosX
+            }]
+        );
+    }
+
+    #[test]
+    fn same_level_neighbor_is_not_a_boundary() {
+        let mut clipmap = ChunkClipMap::new(3, StreamingConfig::default());
+
+        insert_rendering(&mut clipmap, NodeKey::new(1, IVec3::ZERO));
+        insert_rendering(&mut clipmap, NodeKey::new(1, IVec3::new(1, 0, 0)));
+
+        assert!(clipmap.lod_boundaries().is_empty());
+    }
+}
@@ -0,0 +1,36 @@
+use crate::chunk::MaterialWeightsChunk;
+use crate::clipmap::ChunkClipMap;
+use crate::core::glam::IVec3;
+
+use grid_tree::NodeKey;
+
+impl ChunkClipMap {
+    /// The [`MaterialWeights`](crate::chunk::MaterialWeights) of the chunk at `key`, if any were set.
+    pub fn material_weights(&self, key: NodeKey<IVec3>) -> Option<&MaterialWeightsChunk> {
+        self.material_weights.get(&key).map(|w| &**w)
+    }
+
+    /// Replaces the material weights of the chunk at `key` and marks the meshes that depend on it.
+    ///
+    /// Weights are kept until the chunk is evicted. They aren't saved with the chunk, but they can be stored in the
+    /// [`MapDb`](crate::database::MapDb) as [`MaterialWeightLayers`](crate::chunk::MaterialWeightLayers).
+    pub fn set_material_weights(
+        &mut self,
+        key: NodeKey<IVec3>,
+        weights: Box<MaterialWeightsChunk>,
+    ) {
+        self.material_weights.insert(key, weights);
+        self.mark_needs_mesh(key);
+    }
+
+    pub fn remove_material_weights(
+        &mut self,
+        key: NodeKey<IVec3>,
+    ) -> Option<Box<MaterialWeightsChunk>> {
+        let removed = self.material_weights.remove(&key);
+        if removed.is_some() {
+            self.mark_needs_mesh(key);
+        }
+        removed
+    }
+}
@@ -0,0 +1,259 @@
+use crate::chunk::{ChunkShape, CHUNK_SIZE};
+use crate::clipmap::{ChunkClipMap, SdfSampler};
+use crate::coordinates::{chunk_min, in_chunk};
+use crate::core::glam::{const_ivec3, IVec3};
+use crate::units::{ChunkUnits, VoxelUnits};
+
+use grid_tree::NodeKey;
+use ndshape::ConstShape;
+use serde::{Deserialize, Serialize};
+
+/// The directions of the links between [`NavCell`]s, in the order of [`NavCell::links`].
+pub const NAV_DIRECTIONS: [IVec3; 4] = [
+    const_ivec3!([-1, 0, 0]),
+    const_ivec3!([1, 0, 0]),
+    const_ivec3!([0, 0, -1]),
+    const_ivec3!([0, 0, 1]),
+];
+
+/// The size and agility of the agents that walk on the terrain, in voxels.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct AgentParams {
+    /// The number of empty voxels that an agent needs to stand in, starting at the voxel right above the ground.
+    pub clearance: u8,
+    /// The highest step that an agent can climb onto a neighboring voxel.
+    pub step_height: u8,
+    /// The deepest drop that an agent can step down to a neighboring voxel.
+    pub max_drop: u8,
+}
+
+impl Default for AgentParams {
+    fn default() -> Self {
+        Self {
+            clearance: 2,
+            step_height: 1,
+            max_drop: 3,
+        }
+    }
+}
+
+/// An empty voxel right above solid ground, with enough room above it for an agent to stand.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NavCell {
+    pub position: VoxelUnits<IVec3>,
+    /// For each of the [`NAV_DIRECTIONS`], the change in height of the step to the neighboring cell, if an agent can walk
+    /// there. Links are one-way, since an agent might be able to drop down a ledge that it can't climb.
+    pub links: [Option<i8>; 4],
+}
+
+impl NavCell {
+    /// The positions of the cells that this cell links to, which might be in neighboring [`NavTile`]s.
+    pub fn neighbors(&self) -> impl Iterator<Item = VoxelUnits<IVec3>> + '_ {
+        let VoxelUnits(p) = self.position;
+        NAV_DIRECTIONS
+            .iter()
+            .zip(self.links.iter())
+            .filter_map(move |(&direction, dy)| {
+                dy.map(|dy| VoxelUnits(p + direction + IVec3::new(0, i32::from(dy), 0)))
+            })
+    }
+}
+
+/// The walkable surface of one LOD0 chunk, as extracted by [`ChunkClipMap::extract_nav_tile`].
+///
+/// A column of voxels can have any number of cells, one on each floor of a cave or under an overhang.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NavTile {
+    pub coordinates: ChunkUnits<IVec3>,
+    /// In the chunk's linear voxel order.
+    pub cells: Vec<NavCell>,
+}
+
+impl NavTile {
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    /// The links that leave this tile, as `(from, to)` positions. Tiles are stitched together by matching the `to` positions
+    /// with the cells of the neighboring tiles.
+    pub fn border_links(
+        &self,
+    ) -> impl Iterator<Item = (VoxelUnits<IVec3>, VoxelUnits<IVec3>)> + '_ {
+        self.cells.iter().flat_map(move |cell| {
+            cell.neighbors()
+                .filter(move |&to| in_chunk(to) != self.coordinates)
+                .map(move |to| (cell.position, to))
+        })
+    }
+}
+
+/// Answers walkability queries about the LOD0 voxels of a [`ChunkClipMap`] for one kind of agent. Voxels in chunks that
+/// aren't loaded are empty.
+pub(crate) struct Walker<'a> {
+    clipmap: &'a ChunkClipMap,
+    sampler: SdfSampler<'a>,
+    agent: AgentParams,
+    /// The chunk of the last voxel that was sampled.
+    last_chunk: Option<IVec3>,
+    /// The first chunk that was sampled without being loaded.
+    pub unloaded: Option<ChunkUnits<IVec3>>,
+}
+
+impl<'a> Walker<'a> {
+    pub fn new(clipmap: &'a ChunkClipMap, agent: AgentParams) -> Self {
+        Self {
+            clipmap,
+            sampler: SdfSampler::new(clipmap),
+            agent,
+            last_chunk: None,
+            unloaded: None,
+        }
+    }
+
+    fn is_solid(&mut self, p: IVec3) -> bool {
+        let ChunkUnits(coords) = in_chunk(VoxelUnits(p));
+        if self.last_chunk != Some(coords) {
+            self.last_chunk = Some(coords);
+            if self.unloaded.is_none() && !self.clipmap.is_loaded(NodeKey::new(0, coords)) {
+                self.unloaded = Some(ChunkUnits(coords));
+            }
+        }
+        let (sdf, _) = self.sampler.voxel(VoxelUnits(p));
+        sdf.0 < 0
+    }
+
+    /// Returns `true` if the `height` voxels from `p` upward are empty.
+    fn is_clear(&mut self, p: IVec3, height: i32) -> bool {
+        (0..height).all(|dy| !self.is_solid(p + IVec3::new(0, dy, 0)))
+    }
+
+    /// Returns `true` if an agent can stand in `p`.
+    pub fn is_walkable(&mut self, p: IVec3) -> bool {
+        self.is_solid(p - IVec3::Y) && self.is_clear(p, i32::from(self.agent.clearance.max(1)))
+    }
+
+    /// The change in height of the step from the walkable `p` to the closest walkable voxel in the neighboring column in
+    /// `direction`. Level steps are preferred, then climbing, then dropping.
+    pub fn step(&mut self, p: IVec3, direction: IVec3) -> Option<i8> {
+        let clearance = i32::from(self.agent.clearance.max(1));
+        let target = p + direction;
+        let climbs = 1..=i32::from(self.agent.step_height);
+        let drops = (1..=i32::from(self.agent.max_drop)).map(|dy| -dy);
+        for dy in std::iter::once(0).chain(climbs).chain(drops) {
+            let q = target + IVec3::new(0, dy, 0);
+            if !self.is_walkable(q) {
+                continue;
+            }
+            // Climbing needs headroom above the agent, and dropping needs the neighboring column to be open down to q.
+            let has_room = match dy {
+                0 => true,
+                dy if dy > 0 => self.is_clear(p, clearance + dy),
+                dy => self.is_clear(q, clearance - dy),
+            };
+            if has_room {
+                return Some(dy as i8);
+            }
+        }
+        None
+    }
+}
+
+impl ChunkClipMap {
+    /// Extracts the walkable surface of the LOD0 chunk at `coords` for agents with `agent` params.
+    ///
+    /// Cells and links depend on the voxels around the chunk, so the tile changes when its neighbors load or are edited.
+    /// Neighbors that aren't loaded are empty, so the tile has no cells on top of them and no links into them.
+    pub fn extract_nav_tile(&self, coords: ChunkUnits<IVec3>, agent: &AgentParams) -> NavTile {
+        let mut walker = Walker::new(self, *agent);
+        let VoxelUnits(min) = chunk_min(coords);
+        let mut cells = Vec::new();
+        for i in 0..CHUNK_SIZE {
+            let p = min + IVec3::from(ChunkShape::delinearize(i as i32));
+            if !walker.is_walkable(p) {
+                continue;
+            }
+            let links = NAV_DIRECTIONS.map(|direction| walker.step(p, direction));
+            cells.push(NavCell {
+                position: VoxelUnits(p),
+                links,
+            });
+        }
+        NavTile {
+            coordinates: coords,
+            cells,
+        }
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipmap::StreamingConfig;
+    use crate::sdf::Sd8;
+
+    fn cell(tile: &NavTile, p: IVec3) -> Option<&NavCell> {
+        tile.cells.iter().find(|c| c.position == VoxelUnits(p))
+    }
+
+    #[test]
+    fn tiles_have_cells_under_overhangs_and_link_across_borders() {
+        let mut clipmap = ChunkClipMap::new(3, StreamingConfig::default());
+        clipmap.edit_chunk(ChunkUnits(IVec3::ZERO), |chunk| {
+            for x in 0..16 {
+                for z in 0..16 {
+                    chunk.set_voxel(IVec3::new(x, 0, z), 1, Sd8::MIN);
+                    // An overhang over the low X half, with room to stand below it.
+                    if x < 8 {
+                        chunk.set_voxel(IVec3::new(x, 4, z), 1, Sd8::MIN);
+                    }
+                }
+            }
+            // A step.
+            chunk.set_voxel(IVec3::new(10, 1, 5), 1, Sd8::MIN);
+        });
+        let agent = AgentParams::default();
+
+        let tile = clipmap.extract_nav_tile(ChunkUnits(IVec3::ZERO), &agent);
+        // Both floors of the overhang are walkable.
+        assert!(cell(&tile, IVec3::new(3, 1, 3)).is_some());
+        assert!(cell(&tile, IVec3::new(3, 5, 3)).is_some());
+        assert!(cell(&tile, IVec3::new(3, 2, 3)).is_none());
+
+        // Climb onto the step and drop back off of it.
+        let below = cell(&tile, IVec3::new(9, 1, 5)).unwrap();
+        assert_eq!(below.links[1], Some(1));
+        let top = cell(&tile, IVec3::new(10, 2, 5)).unwrap();
+        assert_eq!(top.links[0], Some(-1));
+        // The overhang is too high to climb, and dropping off of it is too deep, but the floor below continues.
+        let edge = cell(&tile, IVec3::new(7, 5, 3)).unwrap();
+        assert_eq!(edge.links[1], None);
+        let under = cell(&tile, IVec3::new(7, 1, 3)).unwrap();
+        assert_eq!(under.links[1], Some(0));
+
+        // Nothing to stand on past the border until the neighbor loads.
+        assert_eq!(tile.border_links().count(), 0);
+        clipmap.edit_chunk(ChunkUnits(IVec3::X), |chunk| {
+            for x in 0..16 {
+                for z in 0..16 {
+                    chunk.set_voxel(IVec3::new(x, 0, z), 1, Sd8::MIN);
+                }
+            }
+        });
+        let tile = clipmap.extract_nav_tile(ChunkUnits(IVec3::ZERO), &agent);
+        let links: Vec<_> = tile.border_links().collect();
+        assert_eq!(links.len(), 16);
+        assert!(links.contains(&(
+            VoxelUnits(IVec3::new(15, 1, 3)),
+            VoxelUnits(IVec3::new(16, 1, 3))
+        )));
+        let neighbor = clipmap.extract_nav_tile(ChunkUnits(IVec3::X), &agent);
+        assert!(cell(&neighbor, IVec3::new(16, 1, 3)).is_some());
+    }
+}
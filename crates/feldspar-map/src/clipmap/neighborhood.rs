@@ -0,0 +1,180 @@
+use crate::chunk::{
+    ChunkShape, PaddedChunkShape, AMBIENT_SD8, CHUNK_SHAPE_IVEC3, CHUNK_SHAPE_LOG2_IVEC3,
+    PADDED_CHUNK_SIZE,
+};
+use crate::clipmap::{ChunkClipMap, NodePtr};
+use crate::coordinates::child_index;
+use crate::core::glam::IVec3;
+use crate::palette::PaletteId8;
+use crate::sdf::Sd8;
+
+use grid_tree::NodeKey;
+use ndshape::ConstShape;
+
+/// A copy of a chunk's voxels and the 1-voxel border around it, taken from the 26 neighbors at the same level.
+///
+/// Voxels are addressed relative to the minimum of the central chunk, so every coordinate from `-1` to `CHUNK_SHAPE` (inclusive)
+/// is in the neighborhood. Voxels of empty neighbors are ambient.
+#[derive(Clone)]
+pub struct ChunkNeighborhood {
+    pub key: NodeKey<IVec3>,
+    sdf: [Sd8; PADDED_CHUNK_SIZE],
+    palette_ids: [PaletteId8; PADDED_CHUNK_SIZE],
+}
+
+impl ChunkNeighborhood {
+    /// Returns `true` if `p`, relative to the minimum of the central chunk, is in the neighborhood.
+    pub fn contains(&self, p: IVec3) -> bool {
+        p.cmpge(IVec3::splat(-1)).all() && p.cmple(CHUNK_SHAPE_IVEC3).all()
+    }
+
+    /// # Panics
+    ///
+    /// If `p` is not in the neighborhood.
+    pub fn sdf(&self, p: IVec3) -> Sd8 {
+        self.sdf[Self::linearize(p)]
+    }
+
+    /// # Panics
+    ///
+    /// If `p` is not in the neighborhood.
+    pub fn palette_id(&self, p: IVec3) -> PaletteId8 {
+        self.palette_ids[Self::linearize(p)]
+    }
+
+    fn linearize(p: IVec3) -> usize {
+        assert!(
+            p.cmpge(IVec3::splat(-1)).all() && p.cmple(CHUNK_SHAPE_IVEC3).all(),
+            "{} is outside of the neighborhood",
+            p
+        );
+        PaddedChunkShape::linearize((p + IVec3::ONE).to_array()) as usize
+    }
+}
+
+impl ChunkClipMap {
+    /// Copies the voxels around the chunk at `key`, so meshing and simulations can read across chunk boundaries without
+    /// holding any locks.
+    ///
+    /// Returns `None` until the chunk and all 26 of its neighbors are loaded. Neighbors that are loaded but empty, or that were
+    /// collapsed into an ancestor, are filled with ambient voxels.
+    pub fn neighborhood(&self, key: NodeKey<IVec3>) -> Option<Box<ChunkNeighborhood>> {
+        // Same order as the offsets linearized in a 3x3x3 grid.
+        let mut neighbors = Vec::with_capacity(27);
+        for z in -1..=1 {
+            for y in -1..=1 {
+                for x in -1..=1 {
+                    let neighbor_key =
+                        NodeKey::new(key.level, key.coordinates + IVec3::new(x, y, z));
+                    neighbors.push(self.loaded_node(neighbor_key)?);
+                }
+            }
+        }
+        let chunks: Vec<_> = neighbors
+            .iter()
+            .map(|ptr| ptr.and_then(|ptr| self.octree.get_value(ptr)?.get_decompressed()))
+            .collect();
+
+        let mut nhood = Box::new(ChunkNeighborhood {
+            key,
+            sdf: [AMBIENT_SD8; PADDED_CHUNK_SIZE],
+            palette_ids: [0; PADDED_CHUNK_SIZE],
+        });
+        for i in 0..PaddedChunkShape::SIZE {
+            let p = IVec3::from(PaddedChunkShape::delinearize(i)) - IVec3::ONE;
+            // Each component is -1, 0, or 1.
+            let offset = p >> CHUNK_SHAPE_LOG2_IVEC3;
+            let neighbor_i = ((offset.z + 1) * 9 + (offset.y + 1) * 3 + offset.x + 1) as usize;
+            if let Some(chunk) = &chunks[neighbor_i] {
+                let j = ChunkShape::linearize((p - offset * CHUNK_SHAPE_IVEC3).to_array()) as usize;
+                let chunk = chunk.as_ref();
+                nhood.sdf[i as usize] = chunk.sdf[j];
+                nhood.palette_ids[i as usize] = chunk.palette_ids[j];
+            }
+        }
+        Some(nhood)
+    }
+
+    /// Returns `None` if the node at `key` is still loading, or `Some(None)` if it's loaded but doesn't exist, because it's
+    /// empty.
+    pub(crate) fn loaded_node(&self, key: NodeKey<IVec3>) -> Option<Option<NodePtr>> {
+        if let Some(ptr) = self.octree.find_node(key) {
+            let state = self.octree.get_value(ptr).unwrap().state();
+            return (!state.is_loading()).then(|| Some(ptr));
+        }
+
+        // The node is only empty if its nearest ancestor has finished loading that part of the subtree.
+        for level in key.level + 1..=self.octree.root_level() {
+            let levels_up = i32::from(level - key.level);
+            let ancestor_key = NodeKey::new(level, key.coordinates >> levels_up);
+            if let Some(ptr) = self.octree.find_node(ancestor_key) {
+                let state = self.octree.get_value(ptr).unwrap().state();
+                let child_coords = key.coordinates >> (levels_up - 1);
+                let loaded = !state.is_loading()
+                    && !state
+                        .descendant_is_loading
+                        .bit_is_set(child_index(child_coords));
+                return loaded.then(|| None);
+            }
+        }
+        // Outside of the clipmap.
+        None
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::Chunk;
+    use crate::clipmap::{ChunkNode, NodeState, StreamingConfig, VisitCommand};
+
+    fn insert_chunk(clipmap: &mut ChunkClipMap, key: NodeKey<IVec3>, sdf: Sd8) {
+        clipmap
+            .octree
+            .fill_path_to_node_from_root(key, |node_key, entry| {
+                entry.or_insert_with(|| {
+                    if node_key == key {
+                        let mut chunk = Chunk::default();
+                        chunk.sdf.fill(sdf);
+                        ChunkNode::new_decompressed(Box::new(chunk), NodeState::new_zeroed())
+                    } else {
+                        ChunkNode::new_empty(NodeState::new_zeroed())
+                    }
+                });
+                VisitCommand::Continue
+            });
+    }
+
+    #[test]
+    fn neighborhood_copies_border_from_neighbors() {
+        let mut clipmap = ChunkClipMap::new(3, StreamingConfig::default());
+        let center = NodeKey::new(0, IVec3::ONE);
+        let neighbor = NodeKey::new(0, IVec3::new(2, 1, 1));
+        insert_chunk(&mut clipmap, center, Sd8::MIN);
+        insert_chunk(&mut clipmap, neighbor, Sd8::ZERO);
+
+        let nhood = clipmap.neighborhood(center).unwrap();
+        assert_eq!(nhood.sdf(IVec3::ZERO), Sd8::MIN);
+        assert_eq!(nhood.sdf(IVec3::splat(15)), Sd8::MIN);
+        assert_eq!(nhood.sdf(IVec3::new(16, 5, 5)), Sd8::ZERO);
+        // The other neighbors are empty.
+        assert_eq!(nhood.sdf(IVec3::new(-1, 5, 5)), AMBIENT_SD8);
+        assert_eq!(nhood.sdf(IVec3::new(16, 16, 5)), AMBIENT_SD8);
+        assert!(!nhood.contains(IVec3::new(17, 5, 5)));
+
+        // Not loaded until every neighbor is.
+        let ptr = clipmap.octree.find_node(neighbor).unwrap();
+        clipmap.octree.get_value(ptr).unwrap().state().set_loading();
+        assert!(clipmap.neighborhood(center).is_none());
+
+        // Neighbors outside of the clipmap aren't loaded either.
+        assert!(clipmap.neighborhood(NodeKey::new(0, IVec3::ZERO)).is_none());
+    }
+}
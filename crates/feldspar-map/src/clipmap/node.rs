@@ -1,11 +1,11 @@
-use crate::chunk::{Chunk, CompressedChunk};
+use crate::chunk::{Chunk, CompressedChunk, MappedChunk, UniformChunk};
 use crate::core::bitset::{AtomicBitset8, Bitset8};
 use crate::core::static_assertions::const_assert_eq;
 
 use either::Either;
 use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 use std::mem::{self, ManuallyDrop};
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 /// A single node in the [`ChunkClipMap`](crate::ChunkClipMap).
 ///
@@ -14,6 +14,9 @@ use std::sync::atomic::Ordering;
 ///
 /// While the chunk is compressed, readers will take an exclusive lock and wait for one of the readers to decompress the chunk
 /// before continuing. Decompression should happen at most once per frame.
+///
+/// A chunk can also be [`MappedChunk`], i.e. still in the buffer it was loaded from. It's treated like a compressed chunk,
+/// except that it's copied into an owned [`CompressedChunk`] when it's taken out of the node.
 pub struct ChunkNode {
     chunk: RwLock<ChunkSlot>,
     state: NodeState,
@@ -40,6 +43,7 @@ impl ChunkNode {
 
     pub fn new_empty(state: NodeState) -> Self {
         state.state.clear_bit(StateBit::Occupied as u8);
+        state.state.clear_bit(StateBit::Compressed as u8);
         Self {
             state,
             chunk: RwLock::new(ChunkSlot { empty: () }),
@@ -57,7 +61,19 @@ impl ChunkNode {
         }
     }
 
+    pub fn new_mapped(chunk: MappedChunk, state: NodeState) -> Self {
+        state.state.clear_bit(StateBit::Occupied as u8);
+        state.state.set_bit(StateBit::Compressed as u8);
+        Self {
+            state,
+            chunk: RwLock::new(ChunkSlot {
+                mapped: ManuallyDrop::new(chunk),
+            }),
+        }
+    }
+
     pub fn new_decompressed(chunk: Box<Chunk>, state: NodeState) -> Self {
+        state.touch();
         state.state.set_bit(StateBit::Occupied as u8);
         state.state.clear_bit(StateBit::Compressed as u8);
         Self {
@@ -70,8 +86,9 @@ impl ChunkNode {
 
     /// If the slot is currently compressed, then the compressed value is dropped.
     pub fn get_decompressed(&self) -> Option<DecompressedChunk<'_>> {
+        self.state.touch();
         match self.state.slot_state() {
-            SlotState::Compressed => self.decompress_for_read(),
+            SlotState::Compressed | SlotState::Mapped => self.decompress_for_read(),
             SlotState::Decompressed => {
                 // Fast path for when the chunk is already decompressed.
                 Some(DecompressedChunk {
@@ -82,6 +99,53 @@ impl ChunkNode {
         }
     }
 
+    /// Returns the [`UniformChunk`] in this node without decompressing it. Empty nodes are [`UniformChunk::Air`].
+    ///
+    /// Decompressed chunks are never considered uniform, since that would require scanning every voxel.
+    pub fn uniform(&self) -> Option<UniformChunk> {
+        if self.state.slot_state() == SlotState::Empty {
+            return Some(UniformChunk::Air);
+        }
+        self.with_compressed(|c| c.uniform(), |m| m.uniform())
+            .flatten()
+    }
+
+    /// The number of bytes of the [`CompressedChunk`] in this node, if it's compressed or mapped.
+    pub fn compressed_len(&self) -> Option<usize> {
+        self.with_compressed(|c| c.bytes.len(), |m| m.bytes().len())
+    }
+
+    /// A decompressed copy of the chunk in this node. Unlike [`Self::get_decompressed`], a compressed chunk stays compressed.
+    pub fn decompressed_copy(&self) -> Option<Box<Chunk>> {
+        self.with_compressed(|c| Box::new(c.decompress()), |m| Box::new(m.decompress()))
+            .or_else(|| {
+                self.get_decompressed()
+                    .map(|chunk| Box::new(*chunk.as_ref()))
+            })
+    }
+
+    /// Calls `compressed` or `mapped` on the chunk in this node without decompressing it. Returns `None` if the chunk isn't
+    /// compressed or mapped.
+    fn with_compressed<T>(
+        &self,
+        compressed: impl FnOnce(&CompressedChunk) -> T,
+        mapped: impl FnOnce(&MappedChunk) -> T,
+    ) -> Option<T> {
+        if !matches!(
+            self.state.slot_state(),
+            SlotState::Compressed | SlotState::Mapped
+        ) {
+            return None;
+        }
+        let read_guard = self.chunk.read();
+        // Another reader might have decompressed the chunk before we got the lock.
+        match self.state.slot_state() {
+            SlotState::Compressed => Some(compressed(unsafe { &read_guard.compressed })),
+            SlotState::Mapped => Some(mapped(unsafe { &read_guard.mapped })),
+            SlotState::Empty | SlotState::Decompressed => None,
+        }
+    }
+
     #[cold]
     fn decompress_for_read(&self) -> Option<DecompressedChunk<'_>> {
         let mut write_guard = self.chunk.write();
@@ -103,6 +167,22 @@ impl ChunkNode {
                     read_guard: RwLockWriteGuard::downgrade(write_guard),
                 })
             }
+            SlotState::Mapped => {
+                // The loader checked that the mapped bytes decompress, and they can't change while they're mapped.
+                let decompressed = Box::new(unsafe { &write_guard.mapped }.decompress());
+                unsafe { ManuallyDrop::drop(&mut write_guard.mapped) };
+                write_guard.decompressed = ManuallyDrop::new(decompressed);
+
+                // Flip both bits at once, so readers never see an empty slot in between.
+                self.state
+                    .state
+                    .bits
+                    .fetch_xor(OCCUPIED_MASK | COMPRESSED_MASK, Ordering::SeqCst);
+
+                Some(DecompressedChunk {
+                    read_guard: RwLockWriteGuard::downgrade(write_guard),
+                })
+            }
             SlotState::Decompressed => {
                 // Some other thread already decompressed for us. Downgrade to a read lock.
                 Some(DecompressedChunk {
@@ -126,6 +206,19 @@ impl ChunkNode {
         old_value
     }
 
+    /// Replace the existing chunk value with a [`MappedChunk`].
+    pub fn put_mapped(
+        &mut self,
+        mapped: MappedChunk,
+    ) -> Option<Either<Box<Chunk>, CompressedChunk>> {
+        let old_value = self.replace_slot(ChunkSlot {
+            mapped: ManuallyDrop::new(mapped),
+        });
+        self.state.state.clear_bit(StateBit::Occupied as u8);
+        self.state.state.set_bit(StateBit::Compressed as u8);
+        old_value
+    }
+
     /// Replace the existing chunk value with a [`Box<Chunk>`].
     pub fn put_decompressed(
         &mut self,
@@ -134,15 +227,17 @@ impl ChunkNode {
         let old_value = self.replace_slot(ChunkSlot {
             decompressed: ManuallyDrop::new(decompressed),
         });
+        self.state.touch();
         self.state.state.set_bit(StateBit::Occupied as u8);
         self.state.state.clear_bit(StateBit::Compressed as u8);
         old_value
     }
 
-    /// Take the existing chunk value, leaving the slot empty.
+    /// Take the existing chunk value, leaving the slot empty. A mapped chunk is copied out of its buffer.
     pub fn take_chunk(&mut self) -> Option<Either<Box<Chunk>, CompressedChunk>> {
         let old_value = self.replace_slot(ChunkSlot { empty: () });
         self.state.state.clear_bit(StateBit::Occupied as u8);
+        self.state.state.clear_bit(StateBit::Compressed as u8);
         old_value
     }
 
@@ -152,6 +247,12 @@ impl ChunkNode {
             SlotState::Compressed => Some(Either::Right(ManuallyDrop::into_inner(unsafe {
                 mem::replace(&mut *mut_slot, new_slot).compressed
             }))),
+            SlotState::Mapped => {
+                let mapped = ManuallyDrop::into_inner(unsafe {
+                    mem::replace(&mut *mut_slot, new_slot).mapped
+                });
+                Some(Either::Right(mapped.to_compressed()))
+            }
             SlotState::Decompressed => Some(Either::Left(ManuallyDrop::into_inner(unsafe {
                 mem::replace(&mut *mut_slot, new_slot).decompressed
             }))),
@@ -168,7 +269,8 @@ impl ChunkNode {
 enum StateBit {
     /// This bit is set if there is chunk data in the slot.
     Occupied = 0,
-    /// This bit is set if the node is compressed or in the process of being decompressed.
+    /// This bit is set if the node is compressed or in the process of being decompressed. If [`StateBit::Occupied`] is not
+    /// set, then the chunk is a [`MappedChunk`].
     Compressed = 1,
     /// This bit is set if the node is currently loading.
     Loading = 2,
@@ -177,6 +279,13 @@ enum StateBit {
     LoadPending = 3,
     /// This bit is set if the node is currently being rendered.
     Render = 4,
+    /// This bit is set if the chunk has changed since it was loaded, so it must be persisted before eviction.
+    Dirty = 5,
+    /// This bit is set if any child chunk has changed, so this node's chunk must be regenerated by downsampling its children.
+    NeedsDownsample = 6,
+    /// This bit is set if the chunk or one of its positive neighbors has changed while it was rendering, so its mesh must be
+    /// regenerated.
+    NeedsMesh = 7,
 }
 
 impl StateBit {
@@ -188,9 +297,15 @@ impl StateBit {
 const OCCUPIED_MASK: u8 = StateBit::Occupied.mask();
 const COMPRESSED_MASK: u8 = StateBit::Compressed.mask();
 
+/// The value of `NodeState::last_touched` for a chunk that was read since the last cache sweep.
+const TOUCHED_SINCE_SWEEP: u32 = u32::MAX;
+
 pub struct NodeState {
     pub(crate) state: AtomicBitset8,
     pub(crate) descendant_is_loading: Bitset8,
+    /// The last frame that the chunk was read, as recorded by [`ChunkClipMap::compress_cold_chunks`](crate::ChunkClipMap).
+    /// Fits in the padding at the end of [`ChunkNode`].
+    last_touched: AtomicU32,
 }
 
 impl NodeState {
@@ -199,6 +314,7 @@ impl NodeState {
         Self {
             state: AtomicBitset8::default(),
             descendant_is_loading: Bitset8::default(),
+            last_touched: AtomicU32::new(0),
         }
     }
 
@@ -212,14 +328,13 @@ impl NodeState {
     #[inline]
     pub fn slot_state(&self) -> SlotState {
         const MASK: u8 = OCCUPIED_MASK | COMPRESSED_MASK;
-        let and_mask = self.state.bits.fetch_and(MASK, Ordering::SeqCst);
-        match (
-            and_mask & OCCUPIED_MASK != 0,
-            and_mask & COMPRESSED_MASK != 0,
-        ) {
+        // Only read the bits, since the other bits must survive a change of slot state.
+        let bits = self.state.bits.load(Ordering::SeqCst) & MASK;
+        match (bits & OCCUPIED_MASK != 0, bits & COMPRESSED_MASK != 0) {
             (true, true) => SlotState::Compressed,
             (true, false) => SlotState::Decompressed,
-            (false, _) => SlotState::Empty,
+            (false, true) => SlotState::Mapped,
+            (false, false) => SlotState::Empty,
         }
     }
 
@@ -282,12 +397,86 @@ impl NodeState {
     pub fn is_rendering(&self) -> bool {
         self.state.bit_is_set(StateBit::Render as u8)
     }
+
+    #[inline]
+    pub fn set_dirty(&self) {
+        self.state.set_bit(StateBit::Dirty as u8)
+    }
+
+    #[inline]
+    pub fn clear_dirty(&self) {
+        self.state.clear_bit(StateBit::Dirty as u8)
+    }
+
+    #[inline]
+    pub fn fetch_and_clear_dirty(&self) -> bool {
+        self.state.fetch_and_clear_bit(StateBit::Dirty as u8)
+    }
+
+    #[inline]
+    pub fn is_dirty(&self) -> bool {
+        self.state.bit_is_set(StateBit::Dirty as u8)
+    }
+
+    #[inline]
+    pub fn set_needs_downsample(&self) {
+        self.state.set_bit(StateBit::NeedsDownsample as u8)
+    }
+
+    #[inline]
+    pub fn fetch_and_clear_needs_downsample(&self) -> bool {
+        self.state
+            .fetch_and_clear_bit(StateBit::NeedsDownsample as u8)
+    }
+
+    #[inline]
+    pub fn needs_downsample(&self) -> bool {
+        self.state.bit_is_set(StateBit::NeedsDownsample as u8)
+    }
+
+    #[inline]
+    pub fn set_needs_mesh(&self) {
+        self.state.set_bit(StateBit::NeedsMesh as u8)
+    }
+
+    #[inline]
+    pub fn fetch_and_clear_needs_mesh(&self) -> bool {
+        self.state.fetch_and_clear_bit(StateBit::NeedsMesh as u8)
+    }
+
+    #[inline]
+    pub fn needs_mesh(&self) -> bool {
+        self.state.bit_is_set(StateBit::NeedsMesh as u8)
+    }
+
+    /// Marks the chunk as read, so it's considered recently used by the next cache sweep.
+    #[inline]
+    pub fn touch(&self) {
+        self.last_touched
+            .store(TOUCHED_SINCE_SWEEP, Ordering::Relaxed);
+    }
+
+    /// Returns the last frame that the chunk was read, which is `frame` if it was touched since the previous sweep.
+    #[inline]
+    pub fn sweep_last_touched(&self, frame: u32) -> u32 {
+        match self.last_touched.compare_exchange(
+            TOUCHED_SINCE_SWEEP,
+            frame,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => frame,
+            Err(last_touched) => last_touched,
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum SlotState {
     Empty,
     Compressed,
+    /// Compressed, but the bytes are still in the buffer they were loaded from.
+    Mapped,
     Decompressed,
 }
 
@@ -305,10 +494,11 @@ impl<'a> AsRef<Chunk> for DecompressedChunk<'a> {
 
 /// This slot type is nearly equivalent to this enum:
 /// ```
-/// # use feldspar_map::chunk::{Chunk, CompressedChunk};
+/// # use feldspar_map::chunk::{Chunk, CompressedChunk, MappedChunk};
 /// enum ChunkSlot {
 ///     Empty,
 ///     Compressed(CompressedChunk),
+///     Mapped(MappedChunk),
 ///     Decompressed(Box<Chunk>),
 /// }
 /// ```
@@ -317,6 +507,7 @@ impl<'a> AsRef<Chunk> for DecompressedChunk<'a> {
 union ChunkSlot {
     empty: (),
     compressed: mem::ManuallyDrop<CompressedChunk>,
+    mapped: mem::ManuallyDrop<MappedChunk>,
     decompressed: mem::ManuallyDrop<Box<Chunk>>,
 }
 
@@ -339,6 +530,20 @@ const_assert_eq!(mem::size_of::<ChunkSlot>(), 2 * mem::size_of::<*const ()>());
 mod test {
     use super::*;
 
+    #[test]
+    fn uniform_node_stays_compressed() {
+        let solid = UniformChunk::Solid { palette_id: 2 };
+        let node = ChunkNode::new_compressed(solid.compress(), NodeState::new_zeroed());
+        assert_eq!(node.uniform(), Some(solid));
+        assert_eq!(node.state().slot_state(), SlotState::Compressed);
+
+        assert_eq!(node.get_decompressed().unwrap().as_ref(), &solid.to_chunk());
+        assert_eq!(node.uniform(), None);
+
+        let node = ChunkNode::new_empty(NodeState::new_zeroed());
+        assert_eq!(node.uniform(), Some(UniformChunk::Air));
+    }
+
     #[test]
     fn chunk_node_data_slot_round_trip() {
         let compressed_chunk = Chunk::default().compress();
@@ -373,4 +578,23 @@ mod test {
         assert_eq!(chunk, Some(Either::Right(compressed_chunk.clone())));
         assert_eq!(node.state().slot_state(), SlotState::Empty);
     }
+
+    #[test]
+    fn mapped_chunk_is_copied_when_taken() {
+        let mut chunk = Chunk::default();
+        chunk.sdf[0] = crate::sdf::Sd8::MIN;
+        let compressed = chunk.compress();
+
+        let mut node = ChunkNode::new_mapped(compressed.clone().into(), NodeState::new_zeroed());
+        assert_eq!(node.state().slot_state(), SlotState::Mapped);
+        assert_eq!(node.compressed_len(), Some(compressed.bytes.len()));
+        assert_eq!(node.take_chunk(), Some(Either::Right(compressed.clone())));
+        assert_eq!(node.state().slot_state(), SlotState::Empty);
+
+        node.put_mapped(compressed.into());
+        assert_eq!(node.decompressed_copy().as_deref(), Some(&chunk));
+        assert_eq!(node.state().slot_state(), SlotState::Mapped);
+        assert_eq!(node.get_decompressed().unwrap().as_ref(), &chunk);
+        assert_eq!(node.state().slot_state(), SlotState::Decompressed);
+    }
 }
@@ -0,0 +1,119 @@
+use crate::chunk::{Chunk, ChunkOccupancy, CompressedChunk, MappedChunk, SdfChunk};
+use crate::clipmap::{ChunkClipMap, FaceConnectivity};
+use crate::core::glam::IVec3;
+
+use either::Either;
+use grid_tree::NodeKey;
+use std::borrow::Cow;
+
+impl ChunkClipMap {
+    /// The [`ChunkOccupancy`] of the chunk at `key`, or `None` if the node doesn't exist or it's still loading. Empty nodes are
+    /// entirely empty.
+    ///
+    /// Occupancy is updated whenever a chunk is loaded, edited, replaced, or downsampled, so this usually doesn't touch the
+    /// SDF. Nodes that were written directly through the [`octree`](Self::octree) are scanned instead.
+    pub fn occupancy(&self, key: NodeKey<IVec3>) -> Option<Cow<'_, ChunkOccupancy>> {
+        let ptr = self.octree.find_node(key)?;
+        let node = self.octree.get_value(ptr).unwrap();
+        if node.state().is_loading() {
+            return None;
+        }
+        if let Some(occupancy) = self.occupancy.get(&key) {
+            return Some(Cow::Borrowed(&**occupancy));
+        }
+        let occupancy = if let Some(uniform) = node.uniform() {
+            ChunkOccupancy::from_uniform(uniform)
+        } else {
+            node.get_decompressed()
+                .map_or(ChunkOccupancy::EMPTY, |chunk| chunk.occupancy())
+        };
+        Some(Cow::Owned(occupancy))
+    }
+
+    /// Records the occupancy of the chunk that was just written at `key`, where `None` means the node was emptied.
+    pub(crate) fn set_occupancy(&mut self, key: NodeKey<IVec3>, occupancy: Option<ChunkOccupancy>) {
+        if let Some(occupancy) = occupancy {
+            self.connectivity
+                .insert(key, FaceConnectivity::from_occupancy(&occupancy));
+            self.occupancy.insert(key, Box::new(occupancy));
+        } else {
+            // Empty nodes are known to be empty without scanning anything.
+            self.occupancy.remove(&key);
+            self.connectivity.remove(&key);
+        }
+    }
+}
+
+/// The occupancy of a chunk in either representation. Only the SDF channel of a compressed chunk is decompressed.
+pub(crate) fn either_occupancy(chunk: Either<&Chunk, &CompressedChunk>) -> ChunkOccupancy {
+    match chunk {
+        Either::Left(decompressed) => decompressed.occupancy(),
+        Either::Right(compressed) => compressed.uniform().map_or_else(
+            || sdf_occupancy(compressed.try_decompress_sdf()),
+            ChunkOccupancy::from_uniform,
+        ),
+    }
+}
+
+/// The occupancy of a [`MappedChunk`]. Like a compressed chunk, only the SDF channel is decompressed.
+pub(crate) fn mapped_occupancy(chunk: &MappedChunk) -> ChunkOccupancy {
+    chunk.uniform().map_or_else(
+        || sdf_occupancy(chunk.try_decompress_sdf()),
+        ChunkOccupancy::from_uniform,
+    )
+}
+
+/// Chunks are checked when they're loaded, so a corrupt SDF is unexpected. It's treated as full, so no query skips it.
+fn sdf_occupancy(sdf: Option<SdfChunk>) -> ChunkOccupancy {
+    sdf.as_ref().map_or_else(
+        || {
+            log::error!("Corrupt SDF channel, treating the chunk as full");
+            ChunkOccupancy::FULL
+        },
+        ChunkOccupancy::from_sdf,
+    )
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clipmap::{ChunkNode, NodeState, StreamingConfig, VisitCommand};
+    use crate::sdf::Sd8;
+    use crate::units::ChunkUnits;
+
+    #[test]
+    fn edits_update_occupancy() {
+        let mut clipmap = ChunkClipMap::new(2, StreamingConfig::default());
+        let root_key = NodeKey::new(clipmap.octree.root_level(), IVec3::ZERO);
+        clipmap
+            .octree
+            .fill_path_to_node_from_root(root_key, |_key, entry| {
+                entry.or_insert_with(|| ChunkNode::new_empty(NodeState::new_zeroed()));
+                VisitCommand::Continue
+            });
+
+        let key = NodeKey::new(0, IVec3::ONE);
+        assert!(clipmap.occupancy(key).is_none());
+
+        clipmap.edit_chunk(ChunkUnits(key.coordinates), |chunk| {
+            chunk.set_voxel(IVec3::new(3, 4, 5), 1, Sd8::MIN);
+        });
+        let occupancy = clipmap.occupancy(key).unwrap();
+        assert!(matches!(occupancy, Cow::Borrowed(_)));
+        assert_eq!(occupancy.column_top(3, 5), Some(4));
+
+        clipmap.edit_chunk(ChunkUnits(key.coordinates), |chunk| {
+            chunk.set_voxel(IVec3::new(3, 4, 5), 0, Sd8::MAX);
+        });
+        let occupancy = clipmap.occupancy(key).unwrap();
+        assert!(matches!(occupancy, Cow::Borrowed(_)));
+        assert!(occupancy.is_empty());
+    }
+}
@@ -0,0 +1,185 @@
+use super::navmesh::{Walker, NAV_DIRECTIONS};
+use crate::clipmap::{AgentParams, ChunkClipMap};
+use crate::coordinates::in_chunk;
+use crate::core::glam::IVec3;
+use crate::core::SmallKeyHashMap;
+use crate::units::{ChunkUnits, VoxelUnits};
+
+use grid_tree::NodeKey;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// The most voxels that [`ChunkClipMap::find_path`] visits before it gives up.
+pub const MAX_PATH_SEARCH_VOXELS: usize = 1 << 16;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PathError {
+    /// The path might go through this LOD0 chunk, but it isn't loaded.
+    NotLoaded(ChunkUnits<IVec3>),
+    /// An agent can't stand at the start or goal.
+    NotWalkable(VoxelUnits<IVec3>),
+    /// The goal can't be reached through the loaded chunks.
+    NoPath,
+    /// The search visited [`MAX_PATH_SEARCH_VOXELS`] without reaching the goal.
+    TooFar,
+}
+
+impl ChunkClipMap {
+    /// Finds the shortest walk from `start` to `goal` over the loaded LOD0 voxels, for an agent with `agent` params.
+    ///
+    /// The path is every voxel that the agent stands in along the way, including `start` and `goal`. Agents walk along the X
+    /// and Z axes, with the same steps as the links between [`NavCell`](crate::clipmap::NavCell)s. Each step costs one, plus
+    /// the change in height.
+    ///
+    /// If the goal can't be reached, but the search ran into chunks that aren't loaded, then [`PathError::NotLoaded`] is
+    /// returned instead of [`PathError::NoPath`], since the path could be there.
+    pub fn find_path(
+        &self,
+        start: VoxelUnits<IVec3>,
+        goal: VoxelUnits<IVec3>,
+        agent: &AgentParams,
+    ) -> Result<Vec<VoxelUnits<IVec3>>, PathError> {
+        for p in [start, goal] {
+            let chunk = in_chunk(p);
+            if !self.is_loaded(NodeKey::new(0, chunk.into_inner())) {
+                return Err(PathError::NotLoaded(chunk));
+            }
+        }
+        let mut walker = Walker::new(self, *agent);
+        for p in [start, goal] {
+            if !walker.is_walkable(p.into_inner()) {
+                return Err(PathError::NotWalkable(p));
+            }
+        }
+
+        let (VoxelUnits(start), VoxelUnits(goal)) = (start, goal);
+        let heuristic = |p: IVec3| (goal - p).abs().to_array().iter().sum::<i32>() as u32;
+        // Ties are broken by the lowest heuristic, then by position, so the same path is found every time.
+        let mut open = BinaryHeap::new();
+        let mut costs = SmallKeyHashMap::default();
+        let mut came_from = SmallKeyHashMap::default();
+        open.push(Reverse((
+            heuristic(start),
+            heuristic(start),
+            start.to_array(),
+        )));
+        costs.insert(start, 0);
+        while let Some(Reverse((estimate, h, p))) = open.pop() {
+            let p = IVec3::from(p);
+            let cost = costs[&p];
+            // Skip stale entries for voxels that were reached more cheaply after they were pushed.
+            if estimate - h > cost {
+                continue;
+            }
+            if p == goal {
+                return Ok(trace_path(&came_from, goal));
+            }
+            if costs.len() >= MAX_PATH_SEARCH_VOXELS {
+                return Err(PathError::TooFar);
+            }
+            for direction in NAV_DIRECTIONS {
+                let dy = if let Some(dy) = walker.step(p, direction) {
+                    dy
+                } else {
+                    continue;
+                };
+                let q = p + direction + IVec3::new(0, i32::from(dy), 0);
+                let q_cost = cost + 1 + u32::from(dy.unsigned_abs());
+                if costs.get(&q).map_or(false, |&c| c <= q_cost) {
+                    continue;
+                }
+                costs.insert(q, q_cost);
+                came_from.insert(q, p);
+                let q_h = heuristic(q);
+                open.push(Reverse((q_cost + q_h, q_h, q.to_array())));
+            }
+        }
+        match walker.unloaded {
+            Some(chunk) => Err(PathError::NotLoaded(chunk)),
+            None => Err(PathError::NoPath),
+        }
+    }
+}
+
+fn trace_path(came_from: &SmallKeyHashMap<IVec3, IVec3>, goal: IVec3) -> Vec<VoxelUnits<IVec3>> {
+    let mut path = vec![VoxelUnits(goal)];
+    let mut p = goal;
+    while let Some(&prev) = came_from.get(&p) {
+        path.push(VoxelUnits(prev));
+        p = prev;
+    }
+    path.reverse();
+    path
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipmap::StreamingConfig;
+    use crate::sdf::Sd8;
+
+    /// One chunk with a thick floor, walled in on every side, and split by a wall at X = 7 that has a gap at Z = 12 if
+    /// `gap` is set. Agents walk at Y = 4.
+    fn arena(gap: bool) -> ChunkClipMap {
+        let mut clipmap = ChunkClipMap::new(3, StreamingConfig::default());
+        clipmap.edit_chunk(ChunkUnits(IVec3::ZERO), |chunk| {
+            for x in 0..16 {
+                for z in 0..16 {
+                    let is_border = x == 0 || x == 15 || z == 0 || z == 15;
+                    let is_wall = is_border || (x == 7 && !(gap && z == 12));
+                    let height = if is_wall { 10 } else { 4 };
+                    for y in 0..height {
+                        chunk.set_voxel(IVec3::new(x, y, z), 1, Sd8::MIN);
+                    }
+                }
+            }
+        });
+        clipmap
+    }
+
+    #[test]
+    fn path_goes_through_the_gap() {
+        let clipmap = arena(true);
+        let agent = AgentParams::default();
+        let (start, goal) = (IVec3::new(2, 4, 2), IVec3::new(12, 4, 2));
+        let path = clipmap
+            .find_path(VoxelUnits(start), VoxelUnits(goal), &agent)
+            .unwrap();
+        // 5 + 10 steps to the gap, and 5 + 10 steps back.
+        assert_eq!(path.len(), 31);
+        assert_eq!(path.first(), Some(&VoxelUnits(start)));
+        assert_eq!(path.last(), Some(&VoxelUnits(goal)));
+        assert!(path.contains(&VoxelUnits(IVec3::new(7, 4, 12))));
+        for step in path.windows(2) {
+            let d = (step[1].0 - step[0].0).abs();
+            assert_eq!(d.x + d.z, 1);
+        }
+    }
+
+    #[test]
+    fn path_errors() {
+        let agent = AgentParams::default();
+        let start = VoxelUnits(IVec3::new(2, 4, 2));
+        assert_eq!(
+            arena(false).find_path(start, VoxelUnits(IVec3::new(12, 4, 2)), &agent),
+            Err(PathError::NoPath)
+        );
+
+        let clipmap = arena(true);
+        assert_eq!(
+            clipmap.find_path(start, VoxelUnits(IVec3::new(7, 4, 5)), &agent),
+            Err(PathError::NotWalkable(VoxelUnits(IVec3::new(7, 4, 5))))
+        );
+        assert_eq!(
+            clipmap.find_path(start, VoxelUnits(IVec3::new(20, 4, 2)), &agent),
+            Err(PathError::NotLoaded(ChunkUnits(IVec3::X)))
+        );
+    }
+}
@@ -1,15 +1,95 @@
+use super::sdf_sampler::SdfSampler;
 use crate::core::geometry::Ray;
-use crate::core::glam::IVec3;
+use crate::core::glam::{IVec3, Vec3A};
+use crate::core::ilattice::prelude::Extent;
 use crate::{
-    clipmap::{ChunkClipMap, Level, NodePtr},
+    clipmap::{ChunkClipMap, Level, NodePtr, SlotState},
     coordinates::chunk_extent_at_level_vec3a,
+    palette::PaletteId8,
+    sdf::Sd8,
     units::*,
 };
 
 use float_ord::FloatOrd;
 use std::collections::BinaryHeap;
 
+/// The first surface hit by [`ChunkClipMap::cast_ray`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RayHit {
+    /// Distance along the ray from its origin.
+    pub t: f32,
+    pub position: VoxelUnits<Vec3A>,
+    /// Unit normal of the surface, pointing out of the solid.
+    pub normal: Vec3A,
+    /// The solid voxel closest to the hit.
+    pub voxel: VoxelUnits<IVec3>,
+    pub sdf: Sd8,
+    pub palette_id: PaletteId8,
+}
+
+/// The smallest step taken while sphere tracing, so we don't stall when grazing a surface.
+const MIN_SPHERE_TRACE_STEP: f32 = 1.0 / 16.0;
+
 impl ChunkClipMap {
+    /// Finds the first point within `max_t` voxels of `origin` along `dir` where the LOD0 SDF crosses into solid space.
+    ///
+    /// The octree is traversed in order of entry time, so only occupied LOD0 chunks (padded by one voxel) are sphere traced.
+    /// Chunks that aren't loaded at LOD0 are treated as ambient space. If `origin` is already inside of a solid, the hit is at
+    /// `t = 0`.
+    pub fn cast_ray(&self, origin: VoxelUnits<Vec3A>, dir: Vec3A, max_t: f32) -> Option<RayHit> {
+        let VoxelUnits(origin) = origin;
+        let ray = Ray::new(origin, dir.normalize());
+        let cast_at_node = |level: Level, coords: IVec3| {
+            let VoxelUnits(extent) = chunk_extent_at_level_vec3a(level, ChunkUnits(coords));
+            // Surfaces can cross the boundary between a solid voxel and its ambient neighbor in another chunk.
+            let padded = Extent::from_min_and_shape(extent.minimum - 1.0, extent.shape + 2.0);
+            ray.cast_at_extent(padded)
+                .map(|[tmin, tmax]| [tmin.max(0.0), tmax.min(max_t)])
+                .filter(|[tmin, tmax]| tmin <= tmax)
+        };
+
+        let mut heap = BinaryHeap::new();
+        for (root_key, root_node) in self.octree.iter_roots() {
+            if let Some(time_window) = cast_at_node(root_key.level, root_key.coordinates) {
+                heap.push(RayTraceHeapElem {
+                    ptr: NodePtr::new(root_key.level, root_node.self_ptr),
+                    coords: root_key.coordinates,
+                    time_window,
+                });
+            }
+        }
+
+        let mut sampler = SdfSampler::new(self);
+        let mut tracer = SphereTracer::default();
+        while let Some(elem) = heap.pop() {
+            if elem.ptr.level() == 0 {
+                let node = self.octree.get_value(elem.ptr).unwrap();
+                if node.state().slot_state() != SlotState::Empty {
+                    if let Some(t) = tracer.trace(&ray, elem.time_window, &mut sampler) {
+                        return Some(make_ray_hit(&ray, t, &mut sampler));
+                    }
+                }
+                continue;
+            }
+
+            self.octree.visit_children_with_coordinates(
+                elem.ptr,
+                elem.coords,
+                |child_ptr, child_coords| {
+                    if let Some(time_window) = cast_at_node(child_ptr.level(), child_coords) {
+                        heap.push(RayTraceHeapElem {
+                            ptr: child_ptr,
+                            coords: child_coords,
+                            time_window,
+                        });
+                    }
+                },
+            );
+        }
+
+        None
+    }
+
     pub fn earliest_ray_intersection(
         &self,
         ray: VoxelUnits<Ray>,
@@ -79,6 +159,70 @@ impl ChunkClipMap {
     }
 }
 
+fn make_ray_hit(ray: &Ray, t: f32, sampler: &mut SdfSampler) -> RayHit {
+    let position = VoxelUnits(ray.position_at(t));
+    let normal = sampler
+        .gradient(position)
+        .try_normalize()
+        .unwrap_or(-ray.velocity());
+    let (voxel, sdf, palette_id) = sampler.min_corner_voxel(position);
+    RayHit {
+        t,
+        position,
+        normal,
+        voxel,
+        sdf,
+        palette_id,
+    }
+}
+
+/// Marches monotonically along a ray through a sequence of (possibly overlapping) time windows, given in order of entry time.
+#[derive(Default)]
+struct SphereTracer {
+    /// Everything before this time has already been traced.
+    traced_until: f32,
+    /// The last sample taken (time and distance), if it ended exactly where the next window begins.
+    prev_sample: Option<(f32, f32)>,
+}
+
+impl SphereTracer {
+    fn trace(
+        &mut self,
+        ray: &Ray,
+        [tmin, tmax]: [f32; 2],
+        sampler: &mut SdfSampler,
+    ) -> Option<f32> {
+        if tmax <= self.traced_until {
+            return None;
+        }
+        if tmin > self.traced_until {
+            self.prev_sample = None;
+        }
+
+        let mut t = tmin.max(self.traced_until);
+        loop {
+            let d = sampler.distance(VoxelUnits(ray.position_at(t)));
+            if d <= 0.0 {
+                // Linearly interpolate the zero crossing between the last two samples.
+                return Some(match self.prev_sample {
+                    Some((prev_t, prev_d)) if prev_d > 0.0 => {
+                        prev_t + (t - prev_t) * prev_d / (prev_d - d)
+                    }
+                    _ => t,
+                });
+            }
+            self.prev_sample = Some((t, d));
+            if t >= tmax {
+                break;
+            }
+            t = (t + d.max(MIN_SPHERE_TRACE_STEP)).min(tmax);
+        }
+        self.traced_until = tmax;
+
+        None
+    }
+}
+
 #[derive(Clone, Copy)]
 struct RayTraceHeapElem {
     ptr: NodePtr,
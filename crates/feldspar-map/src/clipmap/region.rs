@@ -0,0 +1,257 @@
+use crate::chunk::{ChunkShape, AMBIENT_SD8};
+use crate::clipmap::{ChunkClipMap, EditOutcome, Level};
+use crate::coordinates::{chunk_extent_ivec3, in_chunk_extent};
+use crate::core::glam::IVec3;
+use crate::core::ilattice::prelude::Extent;
+use crate::palette::PaletteId8;
+use crate::sdf::Sd8;
+use crate::units::{ChunkUnits, VoxelUnits};
+
+use grid_tree::NodeKey;
+use ndshape::ConstShape;
+
+/// The values stored for a single voxel.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Voxel {
+    pub sdf: Sd8,
+    pub palette_id: PaletteId8,
+}
+
+impl Voxel {
+    pub const AMBIENT: Self = Self {
+        sdf: AMBIENT_SD8,
+        palette_id: 0,
+    };
+}
+
+/// What [`ChunkClipMap::for_each_voxel_in`] and [`ChunkClipMap::for_each_voxel_in_mut`] do with chunks that aren't loaded,
+/// i.e. chunks that are still loading or outside of every tree. Chunks that are loaded but empty are always visited as
+/// ambient voxels.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MissingChunkPolicy {
+    /// The voxels of chunks that aren't loaded are not visited.
+    Skip,
+    /// Nothing is visited until every chunk in the extent is loaded. Returns [`RegionError::Deferred`] so the caller can retry
+    /// on a later frame, like [`EditOutcome::Deferred`].
+    Defer,
+    /// Stops at the first chunk that isn't loaded and returns [`RegionError::NotLoaded`]. The chunks before it were already
+    /// visited.
+    Error,
+}
+
+/// The error from visiting the voxels of an extent.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RegionError {
+    /// Every chunk in the extent that isn't loaded yet.
+    Deferred {
+        unloaded: Vec<ChunkUnits<IVec3>>,
+    },
+    NotLoaded(ChunkUnits<IVec3>),
+}
+
+impl ChunkClipMap {
+    /// Calls `visitor` with the coordinates and value of every voxel in `extent`, in the voxel units of `lod`.
+    ///
+    /// Voxels are visited chunk by chunk, in no particular order. Each chunk is decompressed at most once.
+    pub fn for_each_voxel_in(
+        &self,
+        extent: VoxelUnits<Extent<IVec3>>,
+        lod: Level,
+        policy: MissingChunkPolicy,
+        mut visitor: impl FnMut(IVec3, Voxel),
+    ) -> Result<(), RegionError> {
+        let ChunkUnits(chunks) = in_chunk_extent(extent);
+        if policy == MissingChunkPolicy::Defer {
+            self.check_loaded(chunks, lod)?;
+        }
+
+        for coords in chunks.iter3() {
+            let ptr = match self.loaded_node(NodeKey::new(lod, coords)) {
+                Some(ptr) => ptr,
+                None if policy == MissingChunkPolicy::Error => {
+                    return Err(RegionError::NotLoaded(ChunkUnits(coords)))
+                }
+                None => continue,
+            };
+            let chunk = ptr.and_then(|ptr| self.octree.get_value(ptr)?.get_decompressed());
+            visit_chunk_voxels(extent, ChunkUnits(coords), |p, i| {
+                let voxel = chunk.as_ref().map_or(Voxel::AMBIENT, |chunk| {
+                    let chunk = chunk.as_ref();
+                    Voxel {
+                        sdf: chunk.sdf[i],
+                        palette_id: chunk.palette_ids[i],
+                    }
+                });
+                visitor(p, voxel)
+            });
+        }
+        Ok(())
+    }
+
+    /// Same as [`Self::for_each_voxel_in`], but `visitor` can change each voxel in `extent` at LOD0. Only LOD0 can be edited,
+    /// since the other levels are downsampled from it.
+    ///
+    /// Every visited chunk is edited like with [`Self::edit_chunk`], so missing paths are created and the edits are saved
+    /// like any other, but only the meshes of the edited voxels are marked.
+    pub fn for_each_voxel_in_mut(
+        &mut self,
+        extent: VoxelUnits<Extent<IVec3>>,
+        policy: MissingChunkPolicy,
+        mut visitor: impl FnMut(IVec3, &mut Voxel),
+    ) -> Result<(), RegionError> {
+        let ChunkUnits(chunks) = in_chunk_extent(extent);
+        if policy == MissingChunkPolicy::Defer {
+            self.check_loaded(chunks, 0)?;
+        }
+
+        let mut result = Ok(());
+        for coords in chunks.iter3() {
+            let outcome = self.edit_chunk_without_meshes(ChunkUnits(coords), |chunk| {
+                visit_chunk_voxels(extent, ChunkUnits(coords), |p, i| {
+                    let mut voxel = Voxel {
+                        sdf: chunk.sdf[i],
+                        palette_id: chunk.palette_ids[i],
+                    };
+                    visitor(p, &mut voxel);
+                    chunk.sdf[i] = voxel.sdf;
+                    chunk.palette_ids[i] = voxel.palette_id;
+                });
+            });
+            if outcome != EditOutcome::Applied && policy == MissingChunkPolicy::Error {
+                result = Err(RegionError::NotLoaded(ChunkUnits(coords)));
+                break;
+            }
+        }
+        self.mark_extent_needs_mesh(extent);
+        result
+    }
+
+    fn check_loaded(&self, chunks: Extent<IVec3>, lod: Level) -> Result<(), RegionError> {
+        let unloaded: Vec<_> = chunks
+            .iter3()
+            .filter(|&coords| self.loaded_node(NodeKey::new(lod, coords)).is_none())
+            .map(ChunkUnits)
+            .collect();
+        if unloaded.is_empty() {
+            Ok(())
+        } else {
+            Err(RegionError::Deferred { unloaded })
+        }
+    }
+}
+
+/// Calls `visitor` with the coordinates and chunk index of every voxel in both `extent` and the chunk at `coords`.
+fn visit_chunk_voxels(
+    extent: VoxelUnits<Extent<IVec3>>,
+    coords: ChunkUnits<IVec3>,
+    mut visitor: impl FnMut(IVec3, usize),
+) {
+    let VoxelUnits(extent) = extent;
+    let VoxelUnits(chunk_extent) = chunk_extent_ivec3(coords);
+    for p in extent.intersection(&chunk_extent).iter3() {
+        let offset = p - chunk_extent.minimum;
+        visitor(p, ChunkShape::linearize(offset.to_array()) as usize);
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clipmap::{ChunkNode, NodeState, StreamingConfig, VisitCommand};
+
+    fn clipmap_with_root() -> ChunkClipMap {
+        let mut clipmap = ChunkClipMap::new(2, StreamingConfig::default());
+        let root_key = NodeKey::new(clipmap.octree.root_level(), IVec3::ZERO);
+        clipmap
+            .octree
+            .fill_path_to_node_from_root(root_key, |_key, entry| {
+                entry.or_insert_with(|| ChunkNode::new_empty(NodeState::new_zeroed()));
+                VisitCommand::Continue
+            });
+        clipmap
+    }
+
+    #[test]
+    fn visits_voxels_across_chunk_boundaries() {
+        let mut clipmap = clipmap_with_root();
+        // Straddles the boundary between chunks at x = 16.
+        let extent = VoxelUnits(Extent::from_min_and_shape(
+            IVec3::new(14, 2, 2),
+            IVec3::new(4, 1, 1),
+        ));
+        clipmap
+            .for_each_voxel_in_mut(extent, MissingChunkPolicy::Error, |p, voxel| {
+                voxel.sdf = Sd8::MIN;
+                voxel.palette_id = p.x as PaletteId8;
+            })
+            .unwrap();
+
+        let mut visited = Vec::new();
+        clipmap
+            .for_each_voxel_in(
+                VoxelUnits(Extent::from_min_and_shape(
+                    IVec3::new(13, 2, 2),
+                    IVec3::new(6, 1, 1),
+                )),
+                0,
+                MissingChunkPolicy::Error,
+                |p, voxel| visited.push((p.x, voxel)),
+            )
+            .unwrap();
+        visited.sort_by_key(|(x, _)| *x);
+        assert_eq!(visited.len(), 6);
+        assert_eq!(visited[0], (13, Voxel::AMBIENT));
+        assert_eq!(
+            visited[3],
+            (
+                16,
+                Voxel {
+                    sdf: Sd8::MIN,
+                    palette_id: 16
+                }
+            )
+        );
+        assert_eq!(visited[5], (18, Voxel::AMBIENT));
+    }
+
+    #[test]
+    fn missing_chunk_policies() {
+        let clipmap = clipmap_with_root();
+        // The second chunk is outside of the only tree.
+        let extent = VoxelUnits(Extent::from_min_and_shape(
+            IVec3::new(30, 0, 0),
+            IVec3::new(4, 1, 1),
+        ));
+
+        let mut num_visited = 0;
+        clipmap
+            .for_each_voxel_in(extent, 0, MissingChunkPolicy::Skip, |_, _| num_visited += 1)
+            .unwrap();
+        assert_eq!(num_visited, 2);
+
+        let mut num_visited = 0;
+        let result = clipmap.for_each_voxel_in(extent, 0, MissingChunkPolicy::Defer, |_, _| {
+            num_visited += 1
+        });
+        assert_eq!(
+            result,
+            Err(RegionError::Deferred {
+                unloaded: vec![ChunkUnits(IVec3::new(2, 0, 0))]
+            })
+        );
+        assert_eq!(num_visited, 0);
+
+        let result = clipmap.for_each_voxel_in(extent, 0, MissingChunkPolicy::Error, |_, _| {});
+        assert_eq!(
+            result,
+            Err(RegionError::NotLoaded(ChunkUnits(IVec3::new(2, 0, 0))))
+        );
+    }
+}
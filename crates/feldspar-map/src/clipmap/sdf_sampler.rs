@@ -0,0 +1,106 @@
+use crate::chunk::{ChunkShape, AMBIENT_SD8};
+use crate::clipmap::{ChunkClipMap, DecompressedChunk};
+use crate::coordinates::{chunk_min, in_chunk, CUBE_CORNERS};
+use crate::core::glam::{IVec3, Vec3A};
+use crate::palette::PaletteId8;
+use crate::sdf::Sd8;
+use crate::units::*;
+
+use grid_tree::NodeKey;
+use ndshape::ConstShape;
+
+/// Samples the LOD0 SDF of a [`ChunkClipMap`] at arbitrary voxel coordinates, treating missing or unloaded chunks as ambient.
+///
+/// Voxel values live on the integer lattice, and continuous samples are trilinearly interpolated between them. The most
+/// recently used chunk is kept locked for reading, so queries that stay spatially coherent only search the octree once per
+/// chunk.
+pub struct SdfSampler<'a> {
+    clipmap: &'a ChunkClipMap,
+    cached: Option<(IVec3, Option<DecompressedChunk<'a>>)>,
+}
+
+impl<'a> SdfSampler<'a> {
+    pub fn new(clipmap: &'a ChunkClipMap) -> Self {
+        Self {
+            clipmap,
+            cached: None,
+        }
+    }
+
+    /// The SDF value and palette ID of the voxel at `p`.
+    pub fn voxel(&mut self, p: VoxelUnits<IVec3>) -> (Sd8, PaletteId8) {
+        let ChunkUnits(chunk_coords) = in_chunk(p);
+        if !matches!(&self.cached, Some((coords, _)) if *coords == chunk_coords) {
+            let chunk = self
+                .clipmap
+                .octree
+                .find_node(NodeKey::new(0, chunk_coords))
+                .and_then(|ptr| self.clipmap.octree.get_value(ptr))
+                .and_then(|node| node.get_decompressed());
+            // Release the old lock before caching the new one.
+            self.cached = None;
+            self.cached = Some((chunk_coords, chunk));
+        }
+
+        let (_, chunk) = self.cached.as_ref().unwrap();
+        if let Some(chunk) = chunk {
+            let VoxelUnits(min) = chunk_min(ChunkUnits(chunk_coords));
+            let VoxelUnits(p) = p;
+            let index = ChunkShape::linearize((p - min).to_array()) as usize;
+            let chunk = chunk.as_ref();
+            (chunk.sdf[index], chunk.palette_ids[index])
+        } else {
+            (AMBIENT_SD8, 0)
+        }
+    }
+
+    /// The trilinearly interpolated signed distance at `x`, in voxels. Saturates at 1 voxel away from the surface.
+    pub fn distance(&mut self, x: VoxelUnits<Vec3A>) -> f32 {
+        let VoxelUnits(x) = x;
+        let floor = x.floor();
+        let fract = x - floor;
+        let floor = floor.as_ivec3();
+
+        let mut distance = 0.0;
+        for corner in CUBE_CORNERS {
+            let c = corner.as_vec3a();
+            let w = c * fract + (Vec3A::ONE - c) * (Vec3A::ONE - fract);
+            let (sdf, _) = self.voxel(VoxelUnits(floor + corner));
+            distance += w.x * w.y * w.z * f32::from(sdf);
+        }
+        distance
+    }
+
+    /// The gradient of [`Self::distance`] at `x`, estimated with central differences. Not normalized, and zero inside of
+    /// ambient space.
+    pub fn gradient(&mut self, x: VoxelUnits<Vec3A>) -> Vec3A {
+        const H: f32 = 0.5;
+        let VoxelUnits(x) = x;
+        let mut gradient = Vec3A::ZERO;
+        for axis in 0..3 {
+            let mut dx = Vec3A::ZERO;
+            dx[axis] = H;
+            gradient[axis] =
+                (self.distance(VoxelUnits(x + dx)) - self.distance(VoxelUnits(x - dx))) / (2.0 * H);
+        }
+        gradient
+    }
+
+    /// The corner of the lattice cell containing `x` with the smallest SDF value. Next to a surface, this is a solid voxel.
+    pub fn min_corner_voxel(
+        &mut self,
+        x: VoxelUnits<Vec3A>,
+    ) -> (VoxelUnits<IVec3>, Sd8, PaletteId8) {
+        let VoxelUnits(x) = x;
+        let floor = x.floor().as_ivec3();
+        let mut best = None;
+        for corner in CUBE_CORNERS {
+            let p = VoxelUnits(floor + corner);
+            let (sdf, palette_id) = self.voxel(p);
+            if best.map_or(true, |(_, best_sdf, _): (_, Sd8, _)| sdf.0 < best_sdf.0) {
+                best = Some((p, sdf, palette_id));
+            }
+        }
+        best.unwrap()
+    }
+}
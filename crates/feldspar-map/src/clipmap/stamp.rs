@@ -0,0 +1,86 @@
+use crate::clipmap::{ChunkClipMap, MissingChunkPolicy, RegionError};
+use crate::core::glam::IVec3;
+use crate::core::ilattice::prelude::Extent;
+use crate::stamp::{StampBlendMode, VoxelStamp};
+use crate::units::VoxelUnits;
+
+impl ChunkClipMap {
+    /// Places `stamp` with its anchor at the LOD0 voxel `position`, blending it into the terrain with `mode`. Returns the
+    /// edited voxels.
+    ///
+    /// The stamp can cross any number of chunk boundaries. A structure that is only partially placed would be broken, so
+    /// nothing is written until every chunk it overlaps is loaded; [`RegionError::Deferred`] lists the chunks to wait for.
+    ///
+    /// The edited chunks are marked dirty, so they are written to the database when they are saved or evicted, and their
+    /// meshes are marked. To also record [`DirtyRegions`](crate::DirtyRegions) and history, queue the stamp with
+    /// [`MapEdits::stamp`](crate::MapEdits::stamp) instead.
+    pub fn stamp(
+        &mut self,
+        position: VoxelUnits<IVec3>,
+        stamp: &VoxelStamp,
+        mode: StampBlendMode,
+    ) -> Result<VoxelUnits<Extent<IVec3>>, RegionError> {
+        let extent = stamp.extent_at(position);
+        let VoxelUnits(min) = extent.map(|e| e.minimum);
+        self.for_each_voxel_in_mut(extent, MissingChunkPolicy::Defer, |p, voxel| {
+            stamp.blend(p - min, mode, voxel);
+        })?;
+        Ok(extent)
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clipmap::{ChunkNode, NodeState, StreamingConfig, VisitCommand, Voxel};
+    use crate::database::Change;
+    use crate::sdf::Sd8;
+
+    use grid_tree::NodeKey;
+
+    #[test]
+    fn stamp_crosses_chunk_boundaries() {
+        let mut clipmap = ChunkClipMap::new(2, StreamingConfig::default());
+        let root_key = NodeKey::new(clipmap.octree.root_level(), IVec3::ZERO);
+        clipmap
+            .octree
+            .fill_path_to_node_from_root(root_key, |_key, entry| {
+                entry.or_insert_with(|| ChunkNode::new_empty(NodeState::new_zeroed()));
+                VisitCommand::Continue
+            });
+
+        let solid = Voxel {
+            sdf: Sd8::MIN,
+            palette_id: 4,
+        };
+        let stamp = VoxelStamp::from_fn(IVec3::splat(4), IVec3::splat(2), |_| solid);
+        let extent = clipmap
+            .stamp(VoxelUnits(IVec3::splat(16)), &stamp, StampBlendMode::Add)
+            .unwrap();
+        assert_eq!(extent.0.minimum, IVec3::splat(14));
+
+        // Every one of the 8 chunks around the corner got a 2x2x2 piece of the stamp.
+        for coords in Extent::from_min_and_shape(IVec3::ZERO, IVec3::splat(2)).iter3() {
+            match clipmap.take_dirty_chunk(NodeKey::new(0, coords)) {
+                Some(Change::Insert(chunk)) => {
+                    assert_eq!(chunk.occupancy().count(), 8);
+                }
+                _ => panic!("Stamped chunk should be dirty"),
+            }
+        }
+
+        // Chunks outside of the tree defer the whole stamp.
+        let result = clipmap.stamp(VoxelUnits(IVec3::splat(31)), &stamp, StampBlendMode::Add);
+        assert!(matches!(result, Err(RegionError::Deferred { .. })));
+        assert!(clipmap
+            .take_dirty_chunk(NodeKey::new(0, IVec3::ONE))
+            .is_none());
+    }
+}
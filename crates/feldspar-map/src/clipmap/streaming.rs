@@ -1,7 +1,15 @@
+mod clip_region;
+mod downsample_search;
+mod evict_search;
 mod load_search;
+mod remesh_search;
 mod render_search;
 
+pub use clip_region::*;
+pub use downsample_search::*;
+pub use evict_search::*;
 pub use load_search::*;
+pub use remesh_search::*;
 pub use render_search::*;
 
 use crate::units::VoxelUnits;
@@ -22,8 +30,12 @@ pub struct StreamingConfig {
     ///   - `R` is the radius of the chunk's bounding sphere (in LOD0 space)
     pub detail: VoxelUnits<f32>,
     /// The radius of the clip [`Sphere`](crate::core::geometry::Sphere), i.e. the sphere centered at the observer outside of
-    /// which terrain is not loaded.
+    /// which terrain is not loaded. This is only the default; each `Witness` may override it.
     pub clip_sphere_radius: VoxelUnits<f32>,
+    /// If set, the near phase load search defers LOD0 chunks that are buried at least this deep under the known
+    /// [surface](crate::clipmap::ChunkClipMap::surface_height) of every column over them, so they're only loaded after every
+    /// chunk that could be visible. Underground chunks are rarely seen until they're dug into.
+    pub defer_buried_depth: Option<VoxelUnits<i32>>,
 }
 
 impl Default for StreamingConfig {
@@ -31,6 +43,7 @@ impl Default for StreamingConfig {
         Self {
             detail: VoxelUnits(6.0),
             clip_sphere_radius: VoxelUnits(1000.0),
+            defer_buried_depth: None,
         }
     }
 }
@@ -0,0 +1,241 @@
+use crate::clipmap::Level;
+use crate::core::geometry::{Cone, Sphere};
+use crate::core::glam::{IVec3, Vec3A};
+use crate::core::ilattice::prelude::Extent;
+use crate::core::SmallKeyHashSet;
+use crate::{
+    coordinates::{chunk_bounding_sphere, extent_intersecting_ancestor_chunk_extent},
+    units::*,
+};
+
+use grid_tree::NodeKey;
+use smallvec::SmallVec;
+
+/// The shape of an observer's clip region, centered at the observer. Lengths are in voxels.
+///
+/// Spheres suit free-flying observers. Strategy and 2.5D games may prefer a box or a vertical cylinder, which can cover the
+/// whole height of the map without loading just as far horizontally.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClipShape {
+    Sphere {
+        radius: f32,
+    },
+    /// An axis-aligned box.
+    Box {
+        half_extent: Vec3A,
+    },
+    /// A cylinder whose axis is parallel to the Y axis.
+    VerticalCylinder {
+        radius: f32,
+        half_height: f32,
+    },
+}
+
+impl ClipShape {
+    /// The radius of the smallest sphere centered at the observer that contains the shape.
+    pub fn bounding_radius(&self) -> f32 {
+        match *self {
+            Self::Sphere { radius } => radius,
+            Self::Box { half_extent } => half_extent.length(),
+            Self::VerticalCylinder {
+                radius,
+                half_height,
+            } => radius.hypot(half_height),
+        }
+    }
+
+    /// The half extent of the bounding box of the shape.
+    pub fn half_extent(&self) -> Vec3A {
+        match *self {
+            Self::Sphere { radius } => Vec3A::splat(radius),
+            Self::Box { half_extent } => half_extent,
+            Self::VerticalCylinder {
+                radius,
+                half_height,
+            } => Vec3A::new(radius, half_height, radius),
+        }
+    }
+
+    /// The bounding box of the shape when it's centered at `center`.
+    pub fn aabb(&self, center: Vec3A) -> Extent<Vec3A> {
+        let half_extent = self.half_extent();
+        Extent::from_min_and_lub(center - half_extent, center + half_extent)
+    }
+
+    /// Returns `true` if `sphere` intersects the shape when it's centered at `center`.
+    pub fn intersects_sphere(&self, center: Vec3A, sphere: &Sphere) -> bool {
+        let offset = sphere.center - center;
+        // The distance from the sphere's center to the closest point of the shape.
+        let dist = match *self {
+            Self::Sphere { radius } => offset.length() - radius,
+            Self::Box { half_extent } => (offset.abs() - half_extent).max(Vec3A::ZERO).length(),
+            Self::VerticalCylinder {
+                radius,
+                half_height,
+            } => {
+                let horizontal = (offset.x.hypot(offset.z) - radius).max(0.0);
+                let vertical = (offset.y.abs() - half_height).max(0.0);
+                horizontal.hypot(vertical)
+            }
+        };
+        dist < sphere.radius
+    }
+}
+
+/// The union of the clip shapes of all observers.
+///
+/// Streaming searches test nodes against the whole region at once, so a node that is covered by multiple overlapping clip
+/// shapes is only considered once.
+///
+/// The region may also be extended by prefetch cones, which cover the space that moving observers are likely to enter soon.
+#[derive(Clone, Debug, Default)]
+pub struct ClipRegion {
+    shapes: SmallVec<[(Vec3A, ClipShape); 4]>,
+    prefetch_cones: SmallVec<[Cone; 4]>,
+}
+
+impl ClipRegion {
+    /// The region where every observer has a clip sphere of the same `clip_radius`.
+    pub fn new(observers: &[VoxelUnits<Vec3A>], clip_radius: VoxelUnits<f32>) -> Self {
+        let VoxelUnits(clip_radius) = clip_radius;
+        Self::from_spheres(
+            observers
+                .iter()
+                .map(|&VoxelUnits(observer)| VoxelUnits(Sphere::new(observer, clip_radius))),
+        )
+    }
+
+    /// The region where each observer has its own clip sphere.
+    pub fn from_spheres(spheres: impl IntoIterator<Item = VoxelUnits<Sphere>>) -> Self {
+        Self::from_shapes(
+            spheres.into_iter().map(|VoxelUnits(s)| {
+                (VoxelUnits(s.center), ClipShape::Sphere { radius: s.radius })
+            }),
+        )
+    }
+
+    /// The region where each observer has its own [`ClipShape`], centered at the observer.
+    pub fn from_shapes(shapes: impl IntoIterator<Item = (VoxelUnits<Vec3A>, ClipShape)>) -> Self {
+        Self {
+            shapes: shapes
+                .into_iter()
+                .map(|(VoxelUnits(center), shape)| (center, shape))
+                .collect(),
+            prefetch_cones: SmallVec::new(),
+        }
+    }
+
+    /// Extends the region with `cones`.
+    pub fn with_prefetch_cones(
+        mut self,
+        cones: impl IntoIterator<Item = VoxelUnits<Cone>>,
+    ) -> Self {
+        self.prefetch_cones
+            .extend(cones.into_iter().map(|VoxelUnits(c)| c));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shapes.is_empty()
+    }
+
+    /// Each observer's center and [`ClipShape`].
+    pub fn shapes(&self) -> &[(Vec3A, ClipShape)] {
+        &self.shapes
+    }
+
+    pub fn prefetch_cones(&self) -> &[Cone] {
+        &self.prefetch_cones
+    }
+
+    /// Returns `true` if `sphere` intersects any of the clip shapes or prefetch cones.
+    pub fn intersects_sphere(&self, sphere: &VoxelUnits<Sphere>) -> bool {
+        let VoxelUnits(sphere) = sphere;
+        self.shapes
+            .iter()
+            .any(|(center, shape)| shape.intersects_sphere(*center, sphere))
+            || self
+                .prefetch_cones
+                .iter()
+                .any(|c| c.intersects_sphere(sphere))
+    }
+
+    /// Returns `true` if the bounding sphere of the node at `key` intersects any of the clip shapes or prefetch cones.
+    pub fn intersects_node(&self, key: NodeKey<IVec3>) -> bool {
+        self.intersects_sphere(&chunk_bounding_sphere(
+            key.level,
+            ChunkUnits(key.coordinates),
+        ))
+    }
+
+    /// Returns the deduplicated set of chunk coordinates at `level` whose extents might intersect the region.
+    pub fn intersecting_chunk_coords(&self, level: Level) -> SmallKeyHashSet<IVec3> {
+        let mut coords = SmallKeyHashSet::default();
+        let shape_bounds = self
+            .shapes
+            .iter()
+            .map(|(center, shape)| shape.aabb(*center));
+        let cone_bounds = self
+            .prefetch_cones
+            .iter()
+            .map(|c| c.bounding_sphere().aabb());
+        for aabb in shape_bounds.chain(cone_bounds) {
+            let ChunkUnits(extent) =
+                extent_intersecting_ancestor_chunk_extent(VoxelUnits(aabb), level);
+            coords.extend(extent.iter3());
+        }
+        coords
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn box_and_cylinder_intersect_nearby_spheres() {
+        let unit_sphere = |x, y, z| Sphere::new(Vec3A::new(x, y, z), 1.0);
+
+        let flat_box = ClipShape::Box {
+            half_extent: Vec3A::new(100.0, 10.0, 100.0),
+        };
+        assert!(flat_box.intersects_sphere(Vec3A::ZERO, &unit_sphere(100.5, 10.5, 0.0)));
+        assert!(!flat_box.intersects_sphere(Vec3A::ZERO, &unit_sphere(0.0, 12.0, 0.0)));
+        // Just outside of the corner, but inside of the bounding box of the sphere.
+        assert!(!flat_box.intersects_sphere(Vec3A::ZERO, &unit_sphere(100.9, 10.9, 100.9)));
+
+        let cylinder = ClipShape::VerticalCylinder {
+            radius: 10.0,
+            half_height: 100.0,
+        };
+        let center = Vec3A::new(0.0, 50.0, 0.0);
+        assert!(cylinder.intersects_sphere(center, &unit_sphere(0.0, 150.5, 0.0)));
+        assert!(cylinder.intersects_sphere(center, &unit_sphere(7.5, 0.0, 7.5)));
+        assert!(!cylinder.intersects_sphere(center, &unit_sphere(8.0, 0.0, 8.0)));
+        assert!(!cylinder.intersects_sphere(center, &unit_sphere(0.0, -52.0, 0.0)));
+    }
+
+    #[test]
+    fn flat_box_covers_fewer_roots_than_its_bounding_sphere() {
+        let center = VoxelUnits(Vec3A::ZERO);
+        let half_extent = Vec3A::new(200.0, 20.0, 200.0);
+        let flat_box = ClipShape::Box { half_extent };
+        let bounding_sphere = ClipShape::Sphere {
+            radius: flat_box.bounding_radius(),
+        };
+
+        let box_region = ClipRegion::from_shapes([(center, flat_box)]);
+        let sphere_region = ClipRegion::from_shapes([(center, bounding_sphere)]);
+        let box_coords = box_region.intersecting_chunk_coords(2);
+        let sphere_coords = sphere_region.intersecting_chunk_coords(2);
+        assert!(box_coords.len() < sphere_coords.len());
+        assert!(box_coords.is_subset(&sphere_coords));
+    }
+}
@@ -0,0 +1,186 @@
+use crate::chunk::Chunk;
+use crate::clipmap::{ChildIndex, ChunkClipMap, Level, VisitCommand, CHILDREN_USIZE};
+use crate::core::glam::IVec3;
+use crate::coordinates::{parent_coords, visit_children};
+use crate::sampling::OctantKernel;
+
+use grid_tree::{NodeKey, NodePtr};
+use std::ops::RangeInclusive;
+
+/// The children of a node that must be downsampled to regenerate the node's chunk.
+///
+/// These are copies of the child chunks, so the job can run on another thread without holding any locks on the clipmap.
+pub struct DownsampleJob {
+    pub key: NodeKey<IVec3>,
+    /// Indexed by [`ChildIndex`](crate::clipmap::ChildIndex). `None` means the child is empty (ambient).
+    pub children: [Option<Box<Chunk>>; CHILDREN_USIZE],
+}
+
+impl DownsampleJob {
+    /// Returns the downsampled parent chunk, or `None` if every child is empty.
+    pub fn downsample(self) -> Option<Chunk> {
+        if self.children.iter().all(Option::is_none) {
+            return None;
+        }
+
+        let ambient = Chunk::default();
+        let mut kernel = OctantKernel::new();
+        let mut parent_chunk = Chunk::default();
+        let children = &self.children;
+        visit_children(self.key.coordinates, |child_index, child_coords| {
+            let child_chunk = children[child_index as usize]
+                .as_deref()
+                .unwrap_or(&ambient);
+            child_chunk.downsample_into(
+                &mut kernel,
+                child_coords,
+                self.key.coordinates,
+                &mut parent_chunk,
+            );
+        });
+        Some(parent_chunk)
+    }
+}
+
+impl ChunkClipMap {
+    /// Finds up to `max_jobs` nodes in `levels` that need to be downsampled, finest levels first.
+    ///
+    /// A node is only downsampled once all of its descendants have finished loading. The "needs downsample" bit of each
+    /// returned node is cleared, so any later changes to its children will mark it again.
+    pub fn downsample_search(
+        &self,
+        levels: RangeInclusive<Level>,
+        max_jobs: usize,
+    ) -> Vec<DownsampleJob> {
+        let root_level = self.octree.root_level();
+        // Level 0 has no children to downsample.
+        let min_level = (*levels.start()).max(1);
+
+        let mut candidates = Vec::new();
+        for (root_key, root_node) in self.octree.iter_roots() {
+            let root_ptr = NodePtr::new(root_level, root_node.self_ptr);
+            self.octree.visit_tree_depth_first(
+                root_ptr,
+                root_key.coordinates,
+                min_level,
+                |ptr, coords| {
+                    let level = ptr.level();
+                    if level > *levels.end() {
+                        return VisitCommand::Continue;
+                    }
+                    let state = self.octree.get_value(ptr).unwrap().state();
+                    if state.needs_downsample()
+                        && !state.is_loading()
+                        && !state.has_load_pending()
+                        && state.descendant_is_loading.none()
+                    {
+                        candidates.push((ptr, NodeKey::new(level, coords)));
+                    }
+                    VisitCommand::Continue
+                },
+            );
+        }
+
+        // Children must be downsampled before their parents.
+        candidates.sort_by_key(|(_, key)| key.level);
+        candidates.truncate(max_jobs);
+
+        candidates
+            .into_iter()
+            .map(|(ptr, key)| {
+                let node = self.octree.get_value(ptr).unwrap();
+                node.state().fetch_and_clear_needs_downsample();
+
+                let mut children: [Option<Box<Chunk>>; CHILDREN_USIZE] = Default::default();
+                if let Some(child_pointers) = self.octree.child_pointers(ptr) {
+                    for (child_index, child) in children.iter_mut().enumerate() {
+                        *child = child_pointers
+                            .get_child(child_index as ChildIndex)
+                            .and_then(|child_ptr| self.octree.get_value(child_ptr))
+                            .and_then(|child_node| child_node.get_decompressed())
+                            .map(|decompressed| Box::new(*decompressed.as_ref()));
+                    }
+                }
+                DownsampleJob { key, children }
+            })
+            .collect()
+    }
+
+    /// Writes the result of a [`DownsampleJob`] back into the node at `key`.
+    ///
+    /// The node is marked dirty so the downsampled chunk gets persisted, and its parent is marked as needing downsampling in
+    /// turn. Nothing happens if the node was evicted or started loading while the job was running.
+    pub fn complete_downsample(&mut self, key: NodeKey<IVec3>, chunk: Option<Chunk>) {
+        let ptr = if let Some(ptr) = self.octree.find_node(key) {
+            ptr
+        } else {
+            return;
+        };
+        let node = self.octree.get_value_mut(ptr).unwrap();
+        if node.state().is_loading() || node.state().has_load_pending() {
+            return;
+        }
+
+        let occupancy = chunk.as_ref().map(Chunk::occupancy);
+        if let Some(chunk) = chunk {
+            node.put_decompressed(Box::new(chunk));
+        } else {
+            node.take_chunk();
+        }
+        node.state().set_dirty();
+        self.set_occupancy(key, occupancy);
+        self.update_borders(key);
+        self.mark_needs_mesh(key);
+
+        if key.level < self.octree.root_level() {
+            let parent_key = NodeKey::new(key.level + 1, parent_coords(key.coordinates));
+            if let Some(parent_ptr) = self.octree.find_node(parent_key) {
+                let parent_node = self.octree.get_value(parent_ptr).unwrap();
+                parent_node.state().set_needs_downsample();
+            }
+        }
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::CHUNK_SIZE;
+    use crate::sdf::Sd8;
+
+    #[test]
+    fn downsample_all_empty_children_is_empty() {
+        let job = DownsampleJob {
+            key: NodeKey::new(1, IVec3::ZERO),
+            children: Default::default(),
+        };
+        assert_eq!(job.downsample(), None);
+    }
+
+    #[test]
+    fn downsample_solid_children_is_solid() {
+        let solid = Chunk {
+            sdf: [Sd8::MIN; CHUNK_SIZE],
+            palette_ids: [3; CHUNK_SIZE],
+        };
+        let mut children: [Option<Box<Chunk>>; CHILDREN_USIZE] = Default::default();
+        for child in children.iter_mut() {
+            *child = Some(Box::new(solid));
+        }
+        let job = DownsampleJob {
+            key: NodeKey::new(1, IVec3::new(2, -1, 0)),
+            children,
+        };
+
+        let parent = job.downsample().unwrap();
+        assert!(parent.sdf.iter().all(|s| s.0 < 0));
+        assert!(parent.palette_ids.iter().all(|&p| p == 3));
+    }
+}
@@ -0,0 +1,101 @@
+use crate::chunk::{Chunk, ChunkEncoding, CompressedChunk, CompressionCodec};
+use crate::clipmap::{ChunkClipMap, ChunkNode, ClipRegion, VisitCommand};
+use crate::core::glam::IVec3;
+
+use either::Either;
+use grid_tree::{NodeKey, NodePtr, Relation};
+
+/// A chunk that was removed from the [`ChunkClipMap`] because its root node left the clip sphere of every observer.
+pub struct EvictedChunk {
+    pub key: NodeKey<IVec3>,
+    /// `None` if the chunk slot was empty at the time of eviction.
+    pub chunk: Option<Either<Box<Chunk>, CompressedChunk>>,
+    /// If the chunk changed since it was loaded, then it must be written back to the database.
+    pub is_dirty: bool,
+}
+
+impl EvictedChunk {
+    /// Compresses the chunk with `encoding` and `codec`. Chunks that were already compressed are only re-encoded if they used a
+    /// different codec.
+    pub fn compress(
+        self,
+        encoding: ChunkEncoding,
+        codec: CompressionCodec,
+    ) -> Option<CompressedChunk> {
+        self.chunk.map(|c| {
+            c.either(
+                |decompressed| decompressed.compress_as(encoding, codec),
+                |compressed| compressed.recompress(encoding, codec),
+            )
+        })
+    }
+}
+
+impl ChunkClipMap {
+    /// Finds root nodes that don't intersect the `clip_region`.
+    ///
+    /// Trees with a load pending on any node are skipped, since the pending load may need to link into the tree when it
+    /// completes. They will be found again by a later search.
+    pub fn eviction_search(&self, clip_region: &ClipRegion) -> Vec<NodeKey<IVec3>> {
+        let root_level = self.octree.root_level();
+
+        let mut evict_keys = Vec::new();
+        for (root_key, root_node) in self.octree.iter_roots() {
+            if clip_region.intersects_node(*root_key) {
+                continue;
+            }
+
+            let root_ptr = NodePtr::new(root_level, root_node.self_ptr);
+            if !self.tree_has_load_pending(root_ptr, root_key.coordinates) {
+                evict_keys.push(*root_key);
+            }
+        }
+        evict_keys
+    }
+
+    /// Removes the tree rooted at `root_key`, calling `visitor` on every chunk that was removed. The material weights, light,
+    /// occupancy, connectivity, borders, and edit generations of those chunks are dropped.
+    pub fn evict_root(&mut self, root_key: NodeKey<IVec3>, mut visitor: impl FnMut(EvictedChunk)) {
+        let relation = Relation {
+            parent: None,
+            child: root_key,
+        };
+        let material_weights = &mut self.material_weights;
+        let light = &mut self.light;
+        let occupancy = &mut self.occupancy;
+        let borders = &mut self.borders;
+        let edit_generations = self.edit_generations.get_mut();
+        let connectivity = &mut self.connectivity;
+        self.octree
+            .remove_tree(&relation, |key, mut node: ChunkNode| {
+                material_weights.remove(&key);
+                occupancy.remove(&key);
+                borders.remove(&key);
+                edit_generations.remove(&key);
+                connectivity.remove(&key);
+                if key.level == 0 {
+                    light.remove(&key.coordinates);
+                }
+                let is_dirty = node.state().is_dirty();
+                visitor(EvictedChunk {
+                    key,
+                    chunk: node.take_chunk(),
+                    is_dirty,
+                });
+            });
+    }
+
+    fn tree_has_load_pending(&self, root_ptr: NodePtr, root_coords: IVec3) -> bool {
+        let mut load_pending = false;
+        self.octree
+            .visit_tree_depth_first(root_ptr, root_coords, 0, |ptr, _coords| {
+                if load_pending {
+                    return VisitCommand::SkipDescendants;
+                }
+                let node = self.octree.get_value(ptr).unwrap();
+                load_pending = node.state().has_load_pending();
+                VisitCommand::Continue
+            });
+        load_pending
+    }
+}
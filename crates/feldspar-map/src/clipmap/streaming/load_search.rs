@@ -1,21 +1,97 @@
-use crate::clipmap::ChunkClipMap;
-use crate::core::geometry::Sphere;
-use crate::core::glam::{IVec3, Vec3A};
+use crate::clipmap::{node_is_in_bounds, ChunkClipMap};
+use crate::core::glam::{IVec2, IVec3, Vec3A};
+use crate::core::ilattice::prelude::Extent;
+use crate::core::SmallKeyHashMap;
 use crate::{
+    chunk::CHUNK_SHAPE_LOG2_IVEC3,
     clipmap::{
-        ChunkNode, Level, LinkPointer, NodeState, PendingLoad, StreamingConfig, VisitCommand,
-    },
-    coordinates::{
-        chunk_bounding_sphere, sphere_intersecting_ancestor_chunk_extent, visit_children,
+        ChunkNode, ClipRegion, Level, LinkPointer, NodeState, PendingLoad, StreamingConfig,
+        VisitCommand,
     },
+    coordinates::{chunk_bounding_sphere, visit_children},
     units::*,
 };
 
 use float_ord::FloatOrd;
 use grid_tree::{AllocPtr, NodeKey, NodePtr, OctreeI32};
-use std::cmp::Ordering;
+use parking_lot::Mutex;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, Reverse};
 use std::collections::BinaryHeap;
 
+/// Determines the order in which the [`NearPhaseLoadSearch`] visits (and therefore loads) nodes.
+///
+/// Distances are always measured from the *nearest* observer to the closest point on the node's bounding sphere, after applying
+/// the observer's [`LoadObserver::distance_scale`]. Either way, the LOD0 chunks deferred by
+/// [`StreamingConfig::defer_buried_depth`] come after every other node.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum LoadPriority {
+    /// Nodes are ordered by squared distance, regardless of level.
+    Nearest,
+    /// Nodes at coarser levels are loaded first, and nodes on the same level are ordered by squared distance.
+    CoarsestThenNearest,
+}
+
+impl Default for LoadPriority {
+    fn default() -> Self {
+        Self::Nearest
+    }
+}
+
+impl LoadPriority {
+    /// Returns a key where the **smallest** key has the highest priority.
+    pub fn key(&self, level: Level, closest_dist_to_observer: VoxelUnits<f32>) -> LoadPriorityKey {
+        let VoxelUnits(dist) = closest_dist_to_observer;
+        // The observer can be inside of the bounding sphere, making the distance negative.
+        let dist = dist.max(0.0);
+        let dist_sq = FloatOrd(dist * dist);
+        match self {
+            Self::Nearest => LoadPriorityKey {
+                buried: false,
+                level: Reverse(0),
+                dist_sq,
+            },
+            Self::CoarsestThenNearest => LoadPriorityKey {
+                buried: false,
+                level: Reverse(level),
+                dist_sq,
+            },
+        }
+    }
+}
+
+/// An observer of the [`NearPhaseLoadSearch`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LoadObserver {
+    pub position: VoxelUnits<Vec3A>,
+    /// Loads are prioritized as if this observer were `distance_scale` times farther from every node, so the nodes near an
+    /// observer with a smaller scale are loaded first. The scale doesn't change the detail of the nodes that get loaded.
+    pub distance_scale: f32,
+}
+
+impl LoadObserver {
+    pub fn new(position: VoxelUnits<Vec3A>, distance_scale: f32) -> Self {
+        Self {
+            position,
+            distance_scale,
+        }
+    }
+}
+
+impl From<VoxelUnits<Vec3A>> for LoadObserver {
+    fn from(position: VoxelUnits<Vec3A>) -> Self {
+        Self::new(position, 1.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub struct LoadPriorityKey {
+    buried: bool,
+    level: Reverse<Level>,
+    dist_sq: FloatOrd<f32>,
+}
+
 pub struct NodeSlot {
     pub coordinates: ChunkUnits<IVec3>,
     pub level: Level,
@@ -30,40 +106,29 @@ impl NodeSlot {
 }
 
 impl ChunkClipMap {
-    /// Inserts root nodes that entered the clip sphere this frame.
-    pub fn broad_phase_load_search(
-        &mut self,
-        old_observer: VoxelUnits<Vec3A>,
-        new_observer: VoxelUnits<Vec3A>,
-    ) {
-        let VoxelUnits(old_observer) = old_observer;
-        let VoxelUnits(new_observer) = new_observer;
-        let VoxelUnits(clip_radius) = self.stream_config.clip_sphere_radius;
-        let root_level = self.octree.root_level();
-
-        let old_clip_sphere = Sphere::new(old_observer, clip_radius);
-        let new_clip_sphere = Sphere::new(new_observer, clip_radius);
-        let ChunkUnits(new_root_level_extent) =
-            sphere_intersecting_ancestor_chunk_extent(VoxelUnits(new_clip_sphere), root_level);
+    /// The union of the clip spheres centered at each of the `observers`, which all have the default `clip_sphere_radius`.
+    pub fn clip_region(&self, observers: &[VoxelUnits<Vec3A>]) -> ClipRegion {
+        ClipRegion::new(observers, self.stream_config.clip_sphere_radius)
+    }
 
-        for root_coords in new_root_level_extent.iter3() {
-            let VoxelUnits(root_sphere) =
-                chunk_bounding_sphere(root_level, ChunkUnits(root_coords));
+    /// Inserts root nodes that entered the clip region this frame. Roots outside of the `world_bounds` are never inserted.
+    ///
+    /// Every root is considered at most once, even if it's covered by multiple overlapping clip shapes.
+    pub fn broad_phase_load_search(&mut self, old_region: &ClipRegion, new_region: &ClipRegion) {
+        let root_level = self.octree.root_level();
 
-            let dist_to_new_clip_sphere = root_sphere.center.distance(new_clip_sphere.center);
-            let node_intersects_new_clip_sphere =
-                dist_to_new_clip_sphere - root_sphere.radius < new_clip_sphere.radius;
+        for root_coords in new_region.intersecting_chunk_coords(root_level) {
+            let root_key = NodeKey::new(root_level, root_coords);
+            let root_sphere = chunk_bounding_sphere(root_level, ChunkUnits(root_coords));
 
-            if !node_intersects_new_clip_sphere {
-                return;
+            if !new_region.intersects_sphere(&root_sphere)
+                || !node_is_in_bounds(self.world_bounds, root_level, ChunkUnits(root_coords))
+            {
+                continue;
             }
 
-            // Only insert if the node didn't already intersect the clip sphere.
-            let dist_to_old_clip_sphere = root_sphere.center.distance(old_clip_sphere.center);
-            let node_intersects_old_clip_sphere =
-                dist_to_old_clip_sphere - root_sphere.radius < old_clip_sphere.radius;
-            if !node_intersects_old_clip_sphere {
-                let root_key = NodeKey::new(root_level, root_coords);
+            // Only insert if the node didn't already intersect the clip region.
+            if !old_region.intersects_sphere(&root_sphere) {
                 self.octree.fill_root(root_key, |entry| {
                     entry.or_insert_with(|| ChunkNode::new_empty(NodeState::new_loading()));
                     VisitCommand::SkipDescendants
@@ -72,27 +137,160 @@ impl ChunkClipMap {
         }
     }
 
-    pub fn near_phase_load_search(&self, observer: VoxelUnits<Vec3A>) -> NearPhaseLoadSearch<'_> {
-        let mut candidate_heap = BinaryHeap::new();
-        for (root_key, root_node) in self.octree.iter_roots() {
-            candidate_heap.push(LoadSearchNode::new(
-                root_key.level,
-                ChunkUnits(root_key.coordinates),
-                Some(root_node.self_ptr),
-                None,
-                observer,
-            ));
+    /// Searches for nodes to load near any of the `observers`, in order of `priority`.
+    pub fn near_phase_load_search<'a>(
+        &'a self,
+        observers: &'a [LoadObserver],
+        priority: LoadPriority,
+    ) -> NearPhaseLoadSearch<'a> {
+        self.near_phase_load_search_from_roots(
+            self.octree
+                .iter_roots()
+                .map(|(root_key, root_node)| (*root_key, root_node.self_ptr)),
+            observers,
+            priority,
+        )
+    }
+
+    /// Finds up to `max_loads` nodes to load, in the same order as [`Self::near_phase_load_search`], but the root subtrees are
+    /// split into `num_partitions` groups that are searched in parallel.
+    ///
+    /// Each group finds up to `max_loads` candidates of its own, and only the `max_loads` highest priority candidates of all
+    /// groups are kept. The rest are released so they can be found again by a later search.
+    pub fn par_near_phase_load_search(
+        &self,
+        observers: &[LoadObserver],
+        priority: LoadPriority,
+        max_loads: usize,
+        num_partitions: usize,
+    ) -> Vec<PendingLoad> {
+        // Deal the roots out in priority order, so every group gets a similar share of the nearby (deep) subtrees.
+        let mut roots: Vec<_> = self
+            .octree
+            .iter_roots()
+            .map(|(root_key, root_node)| {
+                let search_node = LoadSearchNode::new(
+                    root_key.level,
+                    ChunkUnits(root_key.coordinates),
+                    None,
+                    None,
+                    observers,
+                    priority,
+                );
+                (search_node.priority_key, *root_key, root_node.self_ptr)
+            })
+            .collect();
+        roots.sort_by_key(|&(key, _, _)| key);
+        let num_partitions = num_partitions.clamp(1, roots.len().max(1));
+        let mut partitions = vec![Vec::new(); num_partitions];
+        for (i, &(_, root_key, root_ptr)) in roots.iter().enumerate() {
+            partitions[i % num_partitions].push((root_key, root_ptr));
+        }
+
+        let found: Vec<Vec<_>> = partitions
+            .into_par_iter()
+            .map(|partition| {
+                let mut search = self.near_phase_load_search_from_roots(
+                    partition.into_iter(),
+                    observers,
+                    priority,
+                );
+                let mut found = Vec::new();
+                while found.len() < max_loads {
+                    if let Some(prioritized_load) = search.next_prioritized() {
+                        found.push(prioritized_load);
+                    } else {
+                        break;
+                    }
+                }
+                found
+            })
+            .collect();
+
+        // Stable, so ties are still broken in the order of each group's search.
+        let mut merged: Vec<_> = found.into_iter().flatten().collect();
+        merged.sort_by_key(|(key, _)| *key);
+        let discarded = merged.split_off(max_loads.min(merged.len()));
+        let loads: Vec<_> = merged.into_iter().map(|(_, load)| load).collect();
+        for (_, load) in discarded.into_iter() {
+            self.release_load_claim(&load.link_ptr);
         }
+        loads
+    }
+
+    fn near_phase_load_search_from_roots<'a>(
+        &'a self,
+        roots: impl Iterator<Item = (NodeKey<IVec3>, AllocPtr)>,
+        observers: &'a [LoadObserver],
+        priority: LoadPriority,
+    ) -> NearPhaseLoadSearch<'a> {
+        let candidate_heap = roots
+            .map(|(root_key, root_ptr)| {
+                LoadSearchNode::new(
+                    root_key.level,
+                    ChunkUnits(root_key.coordinates),
+                    Some(root_ptr),
+                    None,
+                    observers,
+                    priority,
+                )
+            })
+            .collect();
         NearPhaseLoadSearch {
             octree: &self.octree,
+            ancestor_claims: &self.ancestor_load_claims,
             config: self.stream_config,
-            observer,
+            world_bounds: self.world_bounds,
+            observers,
+            priority,
+            buried_chunks: BuriedChunks {
+                clipmap: self,
+                lowest_surfaces: SmallKeyHashMap::default(),
+            },
             candidate_heap,
             num_load_slots: 0,
         }
     }
 }
 
+/// Counts the vacant loads that link to each nearest ancestor.
+///
+/// Vacant siblings all claim their ancestor's "load pending" bit, so it's only cleared when the last of their loads is
+/// completed or released. Locked by the load searches, which only borrow the clipmap.
+#[derive(Default)]
+pub(crate) struct AncestorLoadClaims {
+    counts: Mutex<SmallKeyHashMap<(Level, AllocPtr), usize>>,
+}
+
+impl AncestorLoadClaims {
+    pub fn claim(&self, ancestor_ptr: NodePtr, ancestor: &NodeState) {
+        let key = (ancestor_ptr.level(), ancestor_ptr.alloc_ptr());
+        *self.counts.lock().entry(key).or_insert(0) += 1;
+        ancestor.set_load_pending();
+    }
+
+    /// Releases one claim on the ancestor at `ancestor_ptr`, and clears its "load pending" bit if that was the last one.
+    ///
+    /// Returns `false` if the ancestor wasn't claimed.
+    pub fn release(&self, ancestor_ptr: NodePtr, ancestor: Option<&NodeState>) -> bool {
+        let key = (ancestor_ptr.level(), ancestor_ptr.alloc_ptr());
+        let mut counts = self.counts.lock();
+        let count = if let Some(count) = counts.get_mut(&key) {
+            count
+        } else {
+            return false;
+        };
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(&key);
+            if let Some(ancestor) = ancestor {
+                ancestor.clear_load_pending();
+            }
+        }
+        true
+    }
+}
+
 /// Searches for nodes marked as "loading." It is up to the caller to subsequently complete the load and supply an
 /// `Option<Chunk>`.
 ///
@@ -100,8 +298,12 @@ impl ChunkClipMap {
 /// same time on the same tree.
 pub struct NearPhaseLoadSearch<'a> {
     octree: &'a OctreeI32<ChunkNode>,
+    ancestor_claims: &'a AncestorLoadClaims,
     config: StreamingConfig,
-    observer: VoxelUnits<Vec3A>,
+    world_bounds: Option<VoxelUnits<Extent<IVec3>>>,
+    observers: &'a [LoadObserver],
+    priority: LoadPriority,
+    buried_chunks: BuriedChunks<'a>,
     candidate_heap: BinaryHeap<LoadSearchNode>,
     num_load_slots: usize,
 }
@@ -112,19 +314,34 @@ impl<'a> NearPhaseLoadSearch<'a> {
     }
 
     pub fn check_next_candidate(&mut self) -> Option<PendingLoad> {
+        self.check_next_prioritized_candidate()
+            .map(|(_, load)| load)
+    }
+
+    fn check_next_prioritized_candidate(&mut self) -> Option<(LoadPriorityKey, PendingLoad)> {
         self.candidate_heap.pop().and_then(|search_node| {
+            let priority_key = search_node.priority_key;
             let ptr_and_node = search_node.ptr.and_then(|p| {
                 let node_ptr = NodePtr::new(search_node.level, p);
                 self.octree.get_value(node_ptr).map(|n| (node_ptr, n))
             });
-            if let Some((ptr, node)) = ptr_and_node {
+            let found = if let Some((ptr, node)) = ptr_and_node {
                 self.search_occupied_candidate(search_node, ptr, node)
             } else {
                 self.search_vacant_candidate(search_node)
-            }
+            };
+            found.map(|load| (priority_key, load))
         })
     }
 
+    fn next_prioritized(&mut self) -> Option<(LoadPriorityKey, PendingLoad)> {
+        let mut next_find = None;
+        while next_find.is_none() && !self.is_done() {
+            next_find = self.check_next_prioritized_candidate();
+        }
+        next_find
+    }
+
     fn search_occupied_candidate(
         &mut self,
         search_node: LoadSearchNode,
@@ -158,6 +375,8 @@ impl<'a> NearPhaseLoadSearch<'a> {
             self.num_load_slots += 1;
             return Some(PendingLoad {
                 chunk: None,
+                mapped: None,
+                occupancy: None,
                 loaded_key: NodeKey::new(level, coordinates.into_inner()),
                 link_ptr: LinkPointer::OverwriteNode {
                     child: ptr,
@@ -170,15 +389,21 @@ impl<'a> NearPhaseLoadSearch<'a> {
         if let Some(child_pointers) = self.octree.child_pointers(ptr) {
             let child_level = level - 1;
             visit_children(coordinates.into_inner(), |child_index, child_coords| {
-                if node.state().descendant_is_loading.bit_is_set(child_index) {
+                if node.state().descendant_is_loading.bit_is_set(child_index)
+                    && node_is_in_bounds(self.world_bounds, child_level, ChunkUnits(child_coords))
+                {
                     let child_ptr = child_pointers.get_child(child_index);
-                    self.candidate_heap.push(LoadSearchNode::new(
+                    let mut candidate = LoadSearchNode::new(
                         child_level,
                         ChunkUnits(child_coords),
                         child_ptr.map(|p| p.alloc_ptr()),
                         Some(ptr),
-                        self.observer,
-                    ));
+                        self.observers,
+                        self.priority,
+                    );
+                    self.buried_chunks
+                        .defer(self.config.defer_buried_depth, &mut candidate);
+                    self.candidate_heap.push(candidate);
                 }
             })
         }
@@ -204,10 +429,13 @@ impl<'a> NearPhaseLoadSearch<'a> {
             // the broad phase load search.
             let ancestor_ptr = nearest_ancestor.unwrap();
             let nearest_ancestor_node = self.octree.get_value(ancestor_ptr).unwrap();
-            nearest_ancestor_node.state().set_load_pending();
+            self.ancestor_claims
+                .claim(ancestor_ptr, nearest_ancestor_node.state());
             self.num_load_slots += 1;
             return Some(PendingLoad {
                 chunk: None,
+                mapped: None,
+                occupancy: None,
                 loaded_key: NodeKey::new(level, coordinates.into_inner()),
                 link_ptr: LinkPointer::LinkToNearestAncestor(nearest_ancestor.unwrap()),
             });
@@ -216,13 +444,20 @@ impl<'a> NearPhaseLoadSearch<'a> {
         // We need to enumerate all child corners because this node doesn't exist, but we know it needs to be loaded.
         let child_level = level - 1;
         visit_children(coordinates.into_inner(), |_child_index, child_coords| {
-            self.candidate_heap.push(LoadSearchNode::new(
+            if !node_is_in_bounds(self.world_bounds, child_level, ChunkUnits(child_coords)) {
+                return;
+            }
+            let mut candidate = LoadSearchNode::new(
                 child_level,
                 ChunkUnits(child_coords),
                 None,
                 nearest_ancestor,
-                self.observer,
-            ));
+                self.observers,
+                self.priority,
+            );
+            self.buried_chunks
+                .defer(self.config.defer_buried_depth, &mut candidate);
+            self.candidate_heap.push(candidate);
         });
         None
     }
@@ -232,11 +467,39 @@ impl<'a> Iterator for NearPhaseLoadSearch<'a> {
     type Item = PendingLoad;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut next_find = None;
-        while next_find.is_none() && !self.is_done() {
-            next_find = self.check_next_candidate();
+        self.next_prioritized().map(|(_, load)| load)
+    }
+}
+
+/// Defers the LOD0 candidates of a [`NearPhaseLoadSearch`] that are buried under the surface.
+struct BuriedChunks<'a> {
+    clipmap: &'a ChunkClipMap,
+    /// The [`ChunkClipMap::lowest_surface_height`] of each chunk column that had a LOD0 candidate, so neighboring candidates in
+    /// the same column don't search it again.
+    lowest_surfaces: SmallKeyHashMap<IVec2, Option<i32>>,
+}
+
+impl<'a> BuriedChunks<'a> {
+    fn defer(&mut self, depth: Option<VoxelUnits<i32>>, candidate: &mut LoadSearchNode) {
+        let VoxelUnits(depth) = if let Some(depth) = depth {
+            depth
+        } else {
+            return;
+        };
+        if candidate.level != 0 {
+            return;
+        }
+        let ChunkUnits(coords) = candidate.coordinates;
+        let column = IVec2::new(coords.x, coords.z);
+        let clipmap = self.clipmap;
+        let lowest_surface = *self
+            .lowest_surfaces
+            .entry(column)
+            .or_insert_with(|| clipmap.lowest_surface_height(ChunkUnits(column)));
+        let chunk_top = ((coords.y + 1) << CHUNK_SHAPE_LOG2_IVEC3.y) - 1;
+        if let Some(lowest_surface) = lowest_surface {
+            candidate.priority_key.buried = chunk_top + depth <= lowest_surface;
         }
-        next_find
     }
 }
 
@@ -245,8 +508,8 @@ struct LoadSearchNode {
     level: Level,
     coordinates: ChunkUnits<IVec3>,
     center_dist_to_observer: VoxelUnits<f32>,
-    closest_dist_to_observer: VoxelUnits<f32>,
     bounding_radius: VoxelUnits<f32>,
+    priority_key: LoadPriorityKey,
     // Optional because we might search into vacant space.
     ptr: Option<AllocPtr>,
     nearest_ancestor: Option<NodePtr>,
@@ -258,15 +521,23 @@ impl LoadSearchNode {
         coordinates: ChunkUnits<IVec3>,
         ptr: Option<AllocPtr>,
         nearest_ancestor: Option<NodePtr>,
-        observer: VoxelUnits<Vec3A>,
+        observers: &[LoadObserver],
+        priority: LoadPriority,
     ) -> Self {
-        let VoxelUnits(observer) = observer;
         let VoxelUnits(bounding_sphere) = chunk_bounding_sphere(level, coordinates);
 
-        let center_dist_to_observer = observer.distance(bounding_sphere.center);
+        // Only the nearest observer matters, since it demands the most detail.
+        let mut center_dist_to_observer = f32::INFINITY;
         // Subtract the bounding sphere's radius to estimate the distance from the observer to the *closest point* on the chunk.
-        // This should make it more fair for higher LODs.
-        let closest_dist_to_observer = center_dist_to_observer - bounding_sphere.radius;
+        // This should make it more fair for higher LODs. The observer can be inside of the bounding sphere.
+        let mut closest_dist_to_observer = f32::INFINITY;
+        for observer in observers {
+            let VoxelUnits(position) = observer.position;
+            let center_dist = position.distance(bounding_sphere.center);
+            center_dist_to_observer = center_dist_to_observer.min(center_dist);
+            let scaled_dist = center_dist * observer.distance_scale - bounding_sphere.radius;
+            closest_dist_to_observer = closest_dist_to_observer.min(scaled_dist);
+        }
 
         Self {
             level,
@@ -274,8 +545,8 @@ impl LoadSearchNode {
             ptr,
             nearest_ancestor,
             center_dist_to_observer: VoxelUnits(center_dist_to_observer),
-            closest_dist_to_observer: VoxelUnits(closest_dist_to_observer),
             bounding_radius: VoxelUnits(bounding_sphere.radius),
+            priority_key: priority.key(level, VoxelUnits(closest_dist_to_observer)),
         }
     }
 }
@@ -289,24 +560,279 @@ impl Eq for LoadSearchNode {}
 
 impl PartialOrd for LoadSearchNode {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        VoxelUnits::map2(
-            self.closest_dist_to_observer,
-            other.closest_dist_to_observer,
-            |d1, d2| FloatOrd(d1).partial_cmp(&FloatOrd(d2)),
-        )
-        .into_inner()
-        .map(Ordering::reverse)
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for LoadSearchNode {
     fn cmp(&self, other: &Self) -> Ordering {
-        VoxelUnits::map2(
-            self.closest_dist_to_observer,
-            other.closest_dist_to_observer,
-            |d1, d2| FloatOrd(d1).cmp(&FloatOrd(d2)),
-        )
-        .into_inner()
-        .reverse()
+        // Smallest key is the highest priority in the max-heap.
+        self.priority_key.cmp(&other.priority_key).reverse()
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::geometry::Sphere;
+    use crate::sdf::Sd8;
+
+    #[test]
+    fn nearest_priority_ignores_level() {
+        let p = LoadPriority::Nearest;
+        assert!(p.key(5, VoxelUnits(10.0)) < p.key(0, VoxelUnits(20.0)));
+        assert!(p.key(0, VoxelUnits(10.0)) < p.key(5, VoxelUnits(20.0)));
+    }
+
+    #[test]
+    fn coarsest_priority_orders_by_level_then_distance() {
+        let p = LoadPriority::CoarsestThenNearest;
+        assert!(p.key(5, VoxelUnits(20.0)) < p.key(0, VoxelUnits(10.0)));
+        assert!(p.key(3, VoxelUnits(10.0)) < p.key(3, VoxelUnits(20.0)));
+    }
+
+    #[test]
+    fn buried_lod0_chunks_are_deferred() {
+        let mut clipmap = ChunkClipMap::new(2, StreamingConfig::default());
+        let root_key = NodeKey::new(clipmap.octree.root_level(), IVec3::ZERO);
+        clipmap
+            .octree
+            .fill_path_to_node_from_root(root_key, |_key, entry| {
+                entry.or_insert_with(|| ChunkNode::new_empty(NodeState::new_zeroed()));
+                VisitCommand::Continue
+            });
+        // The surface of every column is at y = 31.
+        clipmap.edit_chunk(ChunkUnits(IVec3::new(0, 1, 0)), |chunk| {
+            for z in 0..16 {
+                for x in 0..16 {
+                    chunk.set_voxel(IVec3::new(x, 15, z), 1, Sd8::MIN);
+                }
+            }
+        });
+
+        let observers = [LoadObserver::from(VoxelUnits(Vec3A::ZERO))];
+        let candidate = |level, coords| {
+            LoadSearchNode::new(
+                level,
+                ChunkUnits(coords),
+                None,
+                None,
+                &observers,
+                LoadPriority::Nearest,
+            )
+        };
+        let mut buried_chunks = BuriedChunks {
+            clipmap: &clipmap,
+            lowest_surfaces: SmallKeyHashMap::default(),
+        };
+        let depth = Some(VoxelUnits(8));
+
+        let mut deep = candidate(0, IVec3::ZERO);
+        buried_chunks.defer(depth, &mut deep);
+        let mut surface = candidate(0, IVec3::new(0, 1, 0));
+        buried_chunks.defer(depth, &mut surface);
+        let mut coarse = candidate(1, IVec3::ZERO);
+        buried_chunks.defer(depth, &mut coarse);
+        // Unknown surface.
+        let mut unknown = candidate(0, IVec3::new(1, 0, 0));
+        buried_chunks.defer(depth, &mut unknown);
+
+        assert!(deep.priority_key.buried);
+        assert!(!surface.priority_key.buried);
+        assert!(!coarse.priority_key.buried);
+        assert!(!unknown.priority_key.buried);
+        // Even though it's closer to the observer.
+        assert!(surface.priority_key < deep.priority_key);
+
+        let mut not_deferred = candidate(0, IVec3::ZERO);
+        buried_chunks.defer(None, &mut not_deferred);
+        assert!(!not_deferred.priority_key.buried);
+    }
+
+    fn clipmap_with_roots(observers: &[VoxelUnits<Vec3A>]) -> ChunkClipMap {
+        let mut clipmap = ChunkClipMap::new(
+            3,
+            StreamingConfig {
+                clip_sphere_radius: VoxelUnits(50.0),
+                ..Default::default()
+            },
+        );
+        let new_region = clipmap.clip_region(observers);
+        clipmap.broad_phase_load_search(&ClipRegion::default(), &new_region);
+        clipmap
+    }
+
+    fn num_roots(clipmap: &ChunkClipMap) -> usize {
+        clipmap.octree.iter_root_keys().count()
+    }
+
+    #[test]
+    fn overlapping_witnesses_insert_each_root_once() {
+        let a = VoxelUnits(Vec3A::ZERO);
+        let b = VoxelUnits(Vec3A::new(10.0, 0.0, 0.0));
+
+        let clipmap_a = clipmap_with_roots(&[a]);
+        let clipmap_ab = clipmap_with_roots(&[a, b]);
+        assert!(num_roots(&clipmap_a) > 0);
+        assert_eq!(num_roots(&clipmap_ab), num_roots(&clipmap_a));
+
+        // Every root is loaded exactly once, no matter how many witnesses can see it.
+        let loads_a = clipmap_a
+            .near_phase_load_search(&[a.into()], LoadPriority::Nearest)
+            .count();
+        let loads_aa = clipmap_ab
+            .near_phase_load_search(&[a.into(), a.into()], LoadPriority::Nearest)
+            .count();
+        assert_eq!(loads_a, num_roots(&clipmap_a));
+        assert_eq!(loads_aa, loads_a);
+    }
+
+    #[test]
+    fn disjoint_witnesses_insert_union_of_roots() {
+        let a = VoxelUnits(Vec3A::ZERO);
+        let b = VoxelUnits(Vec3A::new(1000.0, 0.0, 0.0));
+
+        let num_a = num_roots(&clipmap_with_roots(&[a]));
+        let num_b = num_roots(&clipmap_with_roots(&[b]));
+        let clipmap_ab = clipmap_with_roots(&[a, b]);
+        assert_eq!(num_roots(&clipmap_ab), num_a + num_b);
+
+        let loads = clipmap_ab
+            .near_phase_load_search(&[a.into(), b.into()], LoadPriority::Nearest)
+            .count();
+        assert_eq!(loads, num_a + num_b);
+    }
+
+    #[test]
+    fn parallel_search_finds_same_loads_as_serial_search() {
+        let observers = [
+            VoxelUnits(Vec3A::new(3.3, 7.1, 1.7)),
+            VoxelUnits(Vec3A::new(200.0, 0.0, 0.0)),
+        ];
+        let key_order = |key: &NodeKey<IVec3>| (key.level, key.coordinates.to_array());
+
+        let serial_clipmap = clipmap_with_roots(&observers);
+        let mut serial: Vec<_> = serial_clipmap
+            .near_phase_load_search(&observers.map(LoadObserver::from), LoadPriority::Nearest)
+            .map(|l| l.loaded_key)
+            .collect();
+        serial.sort_by_key(key_order);
+
+        let parallel_clipmap = clipmap_with_roots(&observers);
+        let mut parallel: Vec<_> = parallel_clipmap
+            .par_near_phase_load_search(
+                &observers.map(LoadObserver::from),
+                LoadPriority::Nearest,
+                usize::MAX,
+                4,
+            )
+            .into_iter()
+            .map(|l| l.loaded_key)
+            .collect();
+        parallel.sort_by_key(key_order);
+        assert_eq!(parallel, serial);
+    }
+
+    #[test]
+    fn parallel_search_releases_discarded_loads() {
+        let a = VoxelUnits(Vec3A::ZERO);
+        let clipmap = clipmap_with_roots(&[a]);
+        let num_roots = num_roots(&clipmap);
+        assert!(num_roots > 3);
+
+        let first = clipmap.par_near_phase_load_search(&[a.into()], LoadPriority::Nearest, 3, 4);
+        assert_eq!(first.len(), 3);
+        // Every other group also found candidates, but those were not kept, so they are found again.
+        let rest =
+            clipmap.par_near_phase_load_search(&[a.into()], LoadPriority::Nearest, usize::MAX, 4);
+        assert_eq!(rest.len(), num_roots - 3);
+    }
+
+    #[test]
+    fn stationary_witness_inserts_no_new_roots() {
+        let a = VoxelUnits(Vec3A::ZERO);
+        let mut clipmap = clipmap_with_roots(&[a]);
+        let num_before = num_roots(&clipmap);
+
+        let region = clipmap.clip_region(&[a]);
+        clipmap.broad_phase_load_search(&region, &region);
+        assert_eq!(num_roots(&clipmap), num_before);
+    }
+
+    #[test]
+    fn each_witness_has_its_own_clip_radius() {
+        // A whole number of root chunks apart, so spheres of the same radius cover the same number of roots.
+        let a = Vec3A::ZERO;
+        let b = Vec3A::new(1024.0, 0.0, 0.0);
+        let num_a = num_roots(&clipmap_with_roots(&[VoxelUnits(a)]));
+
+        let mut clipmap = clipmap_with_roots(&[]);
+        let mixed_region = ClipRegion::from_spheres([
+            VoxelUnits(Sphere::new(a, 50.0)),
+            VoxelUnits(Sphere::new(b, 200.0)),
+        ]);
+        clipmap.broad_phase_load_search(&ClipRegion::default(), &mixed_region);
+        let num_b = num_roots(&clipmap) - num_a;
+        assert!(num_b > num_a);
+
+        // Shrinking the large sphere back to the default evicts everything outside of it.
+        let uniform_region = clipmap.clip_region(&[VoxelUnits(a), VoxelUnits(b)]);
+        let evicted = clipmap.eviction_search(&uniform_region);
+        assert_eq!(evicted.len(), num_b - num_a);
+    }
+
+    #[test]
+    fn scaled_observers_load_last() {
+        let a = VoxelUnits(Vec3A::ZERO);
+        let b = VoxelUnits(Vec3A::new(1024.0, 0.0, 0.0));
+        let clipmap = clipmap_with_roots(&[a, b]);
+        let num_roots = num_roots(&clipmap);
+
+        // Every root near the unscaled observer comes before the roots near the scaled one.
+        let observers = [a.into(), LoadObserver::new(b, 100.0)];
+        let loads: Vec<_> = clipmap
+            .near_phase_load_search(&observers, LoadPriority::Nearest)
+            .collect();
+        assert_eq!(loads.len(), num_roots);
+        let near_a = |load: &PendingLoad| load.loaded_key.coordinates.x < 8;
+        let first_b = loads.iter().position(|l| !near_a(l)).unwrap();
+        assert_eq!(first_b, num_roots / 2);
+        assert!(loads[first_b..].iter().all(|l| !near_a(l)));
+    }
+
+    #[test]
+    fn roots_outside_world_bounds_are_not_inserted() {
+        let a = VoxelUnits(Vec3A::ZERO);
+        let num_unbounded = num_roots(&clipmap_with_roots(&[a]));
+
+        // Only the space above y = 0.
+        let mut clipmap = clipmap_with_roots(&[]);
+        clipmap.world_bounds = Some(VoxelUnits(Extent::from_min_and_shape(
+            IVec3::new(-10_000, 0, -10_000),
+            IVec3::splat(20_000),
+        )));
+        let region = clipmap.clip_region(&[a]);
+        clipmap.broad_phase_load_search(&ClipRegion::default(), &region);
+        let num_bounded = num_roots(&clipmap);
+        assert!(num_bounded > 0);
+        assert!(num_bounded < num_unbounded);
+        assert!(clipmap
+            .octree
+            .iter_root_keys()
+            .all(|key| key.coordinates.y >= 0));
+    }
+
+    #[test]
+    fn observer_inside_bounding_sphere_has_zero_distance() {
+        let p = LoadPriority::Nearest;
+        assert_eq!(p.key(0, VoxelUnits(-5.0)), p.key(0, VoxelUnits(0.0)));
+        assert!(p.key(0, VoxelUnits(-5.0)) < p.key(0, VoxelUnits(1.0)));
     }
 }
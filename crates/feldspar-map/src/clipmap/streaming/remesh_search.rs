@@ -0,0 +1,222 @@
+use crate::chunk::{CHUNK_SHAPE_IVEC3, PADDED_CHUNK_SHAPE_IVEC3};
+use crate::clipmap::{ChunkClipMap, Neighbor, NodePtr, RenderNeighborhood, VisitCommand};
+use crate::coordinates::{in_chunk_extent, CUBE_CORNERS};
+use crate::core::glam::IVec3;
+use crate::core::ilattice::prelude::Extent;
+use crate::units::{ChunkUnits, VoxelUnits};
+
+use grid_tree::NodeKey;
+
+impl ChunkClipMap {
+    /// Marks the meshes that depend on the chunk at `key` as needing to be regenerated.
+    ///
+    /// Besides the chunk's own mesh, the meshes of its negative neighbors depend on it, since the chunk is part of their
+    /// padding. Only nodes that are currently rendering are marked; the rest will get fresh meshes when they start rendering.
+    /// Each marked node's [`Self::edit_generation`] is incremented.
+    pub fn mark_needs_mesh(&self, key: NodeKey<IVec3>) {
+        let mut generations = self.edit_generations.lock();
+        for offset in CUBE_CORNERS {
+            let dependent_key = NodeKey::new(key.level, key.coordinates - offset);
+            if let Some(ptr) = self.octree.find_node(dependent_key) {
+                let state = self.octree.get_value(ptr).unwrap().state();
+                if state.is_rendering() {
+                    state.set_needs_mesh();
+                    *generations.entry(dependent_key).or_default() += 1;
+                }
+            }
+        }
+    }
+
+    /// Marks the meshes of the rendering LOD0 chunks that depend on any voxel in `extent`, i.e. whose padded extents intersect
+    /// it.
+    ///
+    /// This is more precise than [`Self::mark_needs_mesh`], which marks every dependent of a whole chunk, so an edit in the
+    /// middle of a chunk doesn't remesh its neighbors.
+    pub fn mark_extent_needs_mesh(&self, extent: VoxelUnits<Extent<IVec3>>) {
+        let VoxelUnits(extent) = extent;
+        // A chunk's mesh also reads the layers of voxels past its positive faces.
+        let padding = PADDED_CHUNK_SHAPE_IVEC3 - CHUNK_SHAPE_IVEC3;
+        let dependents =
+            Extent::from_min_and_lub(extent.minimum - padding, extent.least_upper_bound());
+        let ChunkUnits(chunks) = in_chunk_extent(VoxelUnits(dependents));
+        let mut generations = self.edit_generations.lock();
+        for coords in chunks.iter3() {
+            let key = NodeKey::new(0, coords);
+            if let Some(ptr) = self.octree.find_node(key) {
+                let state = self.octree.get_value(ptr).unwrap().state();
+                if state.is_rendering() {
+                    state.set_needs_mesh();
+                    *generations.entry(key).or_default() += 1;
+                }
+            }
+        }
+    }
+
+    /// The number of times the node at `key` was marked as needing a mesh.
+    ///
+    /// A mesher should read this when it copies a chunk's neighborhood, and drop the finished mesh if the generation has
+    /// changed since, because the mesh no longer matches the voxels. The chunk is still marked, so the next
+    /// [`Self::remesh_search`] finds it again.
+    pub fn edit_generation(&self, key: NodeKey<IVec3>) -> u64 {
+        self.edit_generations.lock().get(&key).copied().unwrap_or(0)
+    }
+
+    /// Finds up to `max_chunks` rendering chunks whose meshes need to be regenerated because their voxels changed.
+    ///
+    /// The "needs mesh" bit of each returned chunk is cleared.
+    pub fn remesh_search(&self, max_chunks: usize) -> Vec<RenderNeighborhood> {
+        let root_level = self.octree.root_level();
+
+        let mut found = Vec::new();
+        for (root_key, root_node) in self.octree.iter_roots() {
+            if found.len() >= max_chunks {
+                break;
+            }
+            let root_ptr = NodePtr::new(root_level, root_node.self_ptr);
+            self.octree
+                .visit_tree_depth_first(root_ptr, root_key.coordinates, 0, |ptr, coords| {
+                    if found.len() >= max_chunks {
+                        return VisitCommand::SkipDescendants;
+                    }
+                    let state = self.octree.get_value(ptr).unwrap().state();
+                    if !state.is_rendering() {
+                        return VisitCommand::Continue;
+                    }
+                    if state.fetch_and_clear_needs_mesh() {
+                        found.push(self.rendering_neighborhood(ptr, coords));
+                    }
+                    // Descendants of a rendering node can't be rendering.
+                    VisitCommand::SkipDescendants
+                });
+        }
+        found
+    }
+
+    fn rendering_neighborhood(&self, min_ptr: NodePtr, coords: IVec3) -> RenderNeighborhood {
+        let level = min_ptr.level();
+        let mut neighbors = [Neighbor::Empty { loaded: true }; 8];
+        neighbors[0] = Neighbor::Occupied(min_ptr.alloc_ptr());
+        for (&offset, neighbor) in CUBE_CORNERS.iter().zip(neighbors.iter_mut()).skip(1) {
+            if let Some(ptr) = self.octree.find_node(NodeKey::new(level, coords + offset)) {
+                *neighbor = Neighbor::Occupied(ptr.alloc_ptr());
+            }
+        }
+        RenderNeighborhood {
+            level,
+            coordinates: ChunkUnits(coords),
+            neighbors,
+        }
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipmap::{ChunkNode, NodeState, StreamingConfig};
+
+    fn insert_node(clipmap: &mut ChunkClipMap, key: NodeKey<IVec3>, rendering: bool) {
+        clipmap
+            .octree
+            .fill_path_to_node_from_root(key, |node_key, entry| {
+                let (_ptr, node) =
+                    entry.or_insert_with(|| ChunkNode::new_empty(NodeState::new_zeroed()));
+                if node_key == key && rendering {
+                    node.state().set_rendering();
+                }
+                VisitCommand::Continue
+            });
+    }
+
+    #[test]
+    fn changed_chunk_remeshes_itself_and_rendering_negative_neighbors() {
+        let mut clipmap = ChunkClipMap::new(3, StreamingConfig::default());
+
+        let changed = NodeKey::new(0, IVec3::new(1, 1, 1));
+        insert_node(&mut clipmap, changed, true);
+        insert_node(&mut clipmap, NodeKey::new(0, IVec3::new(0, 1, 1)), true);
+        // Not rendering, so it doesn't need a mesh.
+        insert_node(&mut clipmap, NodeKey::new(0, IVec3::new(1, 0, 1)), false);
+        // Not a negative neighbor, so its mesh doesn't depend on the changed chunk.
+        insert_node(&mut clipmap, NodeKey::new(0, IVec3::new(2, 1, 1)), true);
+
+        clipmap.mark_needs_mesh(changed);
+
+        let mut remeshed: Vec<_> = clipmap
+            .remesh_search(usize::MAX)
+            .into_iter()
+            .map(|nhood| nhood.coordinates.into_inner())
+            .collect();
+        remeshed.sort_by_key(|c| c.to_array());
+        assert_eq!(remeshed, vec![IVec3::new(0, 1, 1), IVec3::new(1, 1, 1)]);
+
+        // The bits were cleared.
+        assert!(clipmap.remesh_search(usize::MAX).is_empty());
+    }
+
+    #[test]
+    fn marking_a_chunk_advances_the_edit_generations_of_its_rendering_dependents() {
+        let mut clipmap = ChunkClipMap::new(3, StreamingConfig::default());
+        let changed = NodeKey::new(0, IVec3::new(1, 1, 1));
+        let neighbor = NodeKey::new(0, IVec3::new(0, 1, 1));
+        let hidden = NodeKey::new(0, IVec3::new(1, 0, 1));
+        insert_node(&mut clipmap, changed, true);
+        insert_node(&mut clipmap, neighbor, true);
+        insert_node(&mut clipmap, hidden, false);
+
+        // A mesh copied at this generation is stale after the next edit.
+        let copied = clipmap.edit_generation(changed);
+        clipmap.mark_needs_mesh(changed);
+        assert_ne!(clipmap.edit_generation(changed), copied);
+        assert_eq!(clipmap.edit_generation(neighbor), 1);
+        assert_eq!(clipmap.edit_generation(hidden), 0);
+
+        clipmap.mark_extent_needs_mesh(VoxelUnits(Extent::from_min_and_shape(
+            IVec3::splat(20),
+            IVec3::ONE,
+        )));
+        assert_eq!(clipmap.edit_generation(changed), 2);
+        assert_eq!(clipmap.edit_generation(neighbor), 1);
+    }
+
+    #[test]
+    fn changed_extent_only_remeshes_chunks_that_read_it() {
+        let mut clipmap = ChunkClipMap::new(3, StreamingConfig::default());
+        for coords in [
+            IVec3::new(0, 1, 1),
+            IVec3::new(1, 1, 1),
+            IVec3::new(2, 1, 1),
+        ] {
+            insert_node(&mut clipmap, NodeKey::new(0, coords), true);
+        }
+
+        let remeshed = |clipmap: &ChunkClipMap| {
+            let mut remeshed: Vec<_> = clipmap
+                .remesh_search(usize::MAX)
+                .into_iter()
+                .map(|nhood| nhood.coordinates.into_inner())
+                .collect();
+            remeshed.sort_by_key(|c| c.to_array());
+            remeshed
+        };
+
+        // The middle of chunk (1, 1, 1) isn't in the padding of any neighbor.
+        let middle = Extent::from_min_and_shape(IVec3::new(20, 20, 20), IVec3::splat(4));
+        clipmap.mark_extent_needs_mesh(VoxelUnits(middle));
+        assert_eq!(remeshed(&clipmap), vec![IVec3::new(1, 1, 1)]);
+
+        // The minimum face of chunk (1, 1, 1) is in the padding of chunk (0, 1, 1).
+        let face = Extent::from_min_and_shape(IVec3::new(16, 20, 20), IVec3::ONE);
+        clipmap.mark_extent_needs_mesh(VoxelUnits(face));
+        assert_eq!(
+            remeshed(&clipmap),
+            vec![IVec3::new(0, 1, 1), IVec3::new(1, 1, 1)]
+        );
+    }
+}
@@ -1,19 +1,25 @@
+use crate::chunk::{
+    ChunkShape, MaterialWeights, PaddedChunkShape, UniformChunk, AMBIENT_SD8, CHUNK_SHAPE_IVEC3,
+    MAX_LIGHT, PADDED_CHUNK_SIZE,
+};
 use crate::clipmap::neighborhood_subdiv::{NEIGHBORHOODS, NEIGHBORHOODS_PARENTS};
-use crate::clipmap::{ChunkClipMap, NodeState};
-use crate::core::geometry::Sphere;
+use crate::clipmap::{ChunkBorder, ChunkClipMap, NodeState};
 use crate::core::glam::{IVec3, Vec3A};
 use crate::{
     clipmap::{ChildIndex, ChunkNode, Level, NodeLocation, StreamingConfig, VisitCommand},
-    coordinates::{chunk_bounding_sphere, CUBE_CORNERS},
+    coordinates::{chunk_bounding_sphere, min_child_coords, CUBE_CORNERS},
+    palette::PaletteId8,
+    sdf::Sd8,
     units::*,
 };
 
 use float_ord::FloatOrd;
 use grid_tree::{AllocPtr, NodeKey, NodePtr, OctreeI32};
+use ndshape::ConstShape;
 use smallvec::SmallVec;
 use std::collections::BinaryHeap;
 
-/// A chunk's desired sample rate has changed based on proximity to the center of the clip sphere.
+/// A chunk's desired sample rate has changed based on proximity to the nearest observer.
 #[derive(Clone, Debug, PartialEq)]
 pub enum LodChange {
     /// The desired sample rate for this chunk decreased this frame.
@@ -30,6 +36,8 @@ pub enum LodChange {
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct RenderNeighborhood {
     pub level: Level,
+    /// The coordinates of the minimal neighbor, i.e. the chunk that gets meshed.
+    pub coordinates: ChunkUnits<IVec3>,
     pub neighbors: [Neighbor; 8],
 }
 
@@ -50,6 +58,20 @@ impl Neighbor {
     }
 }
 
+/// The voxels of a chunk, plus the 2 layers of voxels from its positive neighbors that are needed to generate its mesh.
+///
+/// Laid out in [`PaddedChunkShape`], where the chunk's own voxels start at `[0, 0, 0]`.
+#[derive(Clone)]
+pub struct PaddedChunk {
+    pub sdf: [Sd8; PADDED_CHUNK_SIZE],
+    pub palette_ids: [PaletteId8; PADDED_CHUNK_SIZE],
+    /// `None` unless one of the neighbors has [`MaterialWeights`]. Voxels of the other neighbors have the default weights.
+    pub material_weights: Option<Box<[MaterialWeights; PADDED_CHUNK_SIZE]>>,
+    /// `None` unless the chunk is at LOD0 and one of the neighbors has been
+    /// [lit](crate::clipmap::ChunkClipMap::light_chunk). Voxels of the other neighbors are [`MAX_LIGHT`].
+    pub light: Option<Box<[u8; PADDED_CHUNK_SIZE]>>,
+}
+
 /// Split `old_chunk` into children `new_chunks`.
 #[derive(Clone, Debug, PartialEq)]
 pub struct SplitChunk {
@@ -66,19 +88,129 @@ pub struct MergeChunks {
 }
 
 impl ChunkClipMap {
+    /// Copies the voxels of `nhood` into a [`PaddedChunk`] so it can be meshed without holding any locks. Empty neighbors are
+    /// filled with ambient voxels.
+    ///
+    /// If the chunk has a [`ChunkBorder`], the padding comes from the border instead of the neighbors' chunks.
+    pub fn copy_padded_neighborhood(&self, nhood: &RenderNeighborhood) -> Box<PaddedChunk> {
+        let border = self.border(NodeKey::new(nhood.level, nhood.coordinates.into_inner()));
+        // The chunk's own voxels are all that's needed from a neighborhood with a border.
+        let num_neighbors = if border.is_some() { 1 } else { 8 };
+        let mut neighbors: [_; 8] = Default::default();
+        for (neighbor, chunk) in nhood
+            .neighbors
+            .iter()
+            .zip(neighbors.iter_mut())
+            .take(num_neighbors)
+        {
+            *chunk = match *neighbor {
+                Neighbor::Occupied(ptr) => self
+                    .octree
+                    .get_value(NodePtr::new(nhood.level, ptr))
+                    .and_then(|node| node.get_decompressed()),
+                Neighbor::Empty { .. } => None,
+            };
+        }
+        let neighbor_weights = CUBE_CORNERS.map(|offset| {
+            let key = NodeKey::new(nhood.level, nhood.coordinates.into_inner() + offset);
+            self.material_weights(key)
+        });
+        let neighbor_light = CUBE_CORNERS.map(|offset| {
+            if nhood.level > 0 {
+                return None;
+            }
+            self.light(ChunkUnits(nhood.coordinates.into_inner() + offset))
+        });
+
+        let mut padded = Box::new(PaddedChunk {
+            sdf: [AMBIENT_SD8; PADDED_CHUNK_SIZE],
+            palette_ids: [0; PADDED_CHUNK_SIZE],
+            material_weights: neighbor_weights
+                .iter()
+                .any(Option::is_some)
+                .then(|| Box::new([MaterialWeights::default(); PADDED_CHUNK_SIZE])),
+            light: neighbor_light
+                .iter()
+                .any(Option::is_some)
+                .then(|| Box::new([MAX_LIGHT; PADDED_CHUNK_SIZE])),
+        });
+        for i in 0..PaddedChunkShape::SIZE {
+            let p = IVec3::from(PaddedChunkShape::delinearize(i));
+            // Same order as CUBE_CORNERS.
+            let neighbor_i = (p.x >= CHUNK_SHAPE_IVEC3.x) as usize
+                | ((p.y >= CHUNK_SHAPE_IVEC3.y) as usize) << 1
+                | ((p.z >= CHUNK_SHAPE_IVEC3.z) as usize) << 2;
+            let offset = p - CUBE_CORNERS[neighbor_i] * CHUNK_SHAPE_IVEC3;
+            let j = ChunkShape::linearize(offset.to_array()) as usize;
+            if let Some(border) = border.filter(|_| neighbor_i > 0) {
+                let b = ChunkBorder::index(p);
+                padded.sdf[i as usize] = border.sdf[b];
+                padded.palette_ids[i as usize] = border.palette_ids[b];
+            } else if let Some(neighbor) = &neighbors[neighbor_i] {
+                let chunk = neighbor.as_ref();
+                padded.sdf[i as usize] = chunk.sdf[j];
+                padded.palette_ids[i as usize] = chunk.palette_ids[j];
+            }
+            if let (Some(dst), Some(src)) =
+                (&mut padded.material_weights, neighbor_weights[neighbor_i])
+            {
+                dst[i as usize] = src[j];
+            }
+            if let (Some(dst), Some(src)) = (&mut padded.light, neighbor_light[neighbor_i]) {
+                dst[i as usize] = src[j];
+            }
+        }
+        padded
+    }
+
+    /// Returns the [`UniformChunk`] that fills all of `nhood`, if there is one. Then it has no surface and doesn't need a mesh.
+    ///
+    /// Empty neighbors are [`UniformChunk::Air`].
+    pub fn uniform_neighborhood(&self, nhood: &RenderNeighborhood) -> Option<UniformChunk> {
+        let mut uniform = None;
+        for neighbor in nhood.neighbors {
+            let neighbor_uniform = match neighbor {
+                Neighbor::Occupied(ptr) => self
+                    .octree
+                    .get_value(NodePtr::new(nhood.level, ptr))
+                    .map_or(Some(UniformChunk::Air), |node| node.uniform())?,
+                Neighbor::Empty { .. } => UniformChunk::Air,
+            };
+            if uniform.map_or(false, |u| u != neighbor_uniform) {
+                return None;
+            }
+            uniform = Some(neighbor_uniform);
+        }
+        uniform
+    }
+
     /// Searches for up to `budget` nodes whose render detail should change.
     ///
     /// This only includes nodes whose entire "chunk neighborhood" is loaded, since we need to reference voxel neighborhoods to
-    /// generate correct meshes.
-    pub fn render_search(&self, observer: VoxelUnits<Vec3A>, budget: usize) -> RenderSearch<'_> {
-        RenderSearch::new(self.stream_config, &self.octree, observer, budget)
+    /// generate correct meshes. With [`Self::share_borders`], only the nodes themselves need to be loaded, since their
+    /// [`ChunkBorder`]s hold the rest of the neighborhood.
+    ///
+    /// The render detail of each node is determined by the nearest of the `observers`.
+    pub fn render_search<'a>(
+        &'a self,
+        observers: &'a [VoxelUnits<Vec3A>],
+        budget: usize,
+    ) -> RenderSearch<'a> {
+        RenderSearch::new(
+            self.stream_config,
+            self.share_borders,
+            &self.octree,
+            observers,
+            budget,
+        )
     }
 }
 
 pub struct RenderSearch<'a> {
     config: StreamingConfig,
+    share_borders: bool,
     octree: &'a OctreeI32<ChunkNode>,
-    clip_sphere: VoxelUnits<Sphere>,
+    observers: &'a [VoxelUnits<Vec3A>],
     budget: usize,
     candidate_heap: BinaryHeap<RenderSearchNode>,
     num_render_chunks: usize,
@@ -99,17 +231,16 @@ impl<'a> Iterator for RenderSearch<'a> {
 impl<'a> RenderSearch<'a> {
     fn new(
         config: StreamingConfig,
+        share_borders: bool,
         octree: &'a OctreeI32<ChunkNode>,
-        observer: VoxelUnits<Vec3A>,
+        observers: &'a [VoxelUnits<Vec3A>],
         budget: usize,
     ) -> Self {
-        let VoxelUnits(observer) = observer;
-        let VoxelUnits(clip_radius) = config.clip_sphere_radius;
-        let clip_sphere = VoxelUnits(Sphere::new(observer, clip_radius));
         let mut search = Self {
             config,
+            share_borders,
             octree,
-            clip_sphere,
+            observers,
             budget,
             candidate_heap: BinaryHeap::new(),
             num_render_chunks: 0,
@@ -119,8 +250,6 @@ impl<'a> RenderSearch<'a> {
     }
 
     fn add_root_neighborhoods_to_heap(&mut self) {
-        let VoxelUnits(clip_sphere) = &self.clip_sphere;
-
         // Put root neighborhoods in the candidate heap.
         for root_key in self.octree.iter_root_keys() {
             let mut neighborhood = [Neighbor::Empty { loaded: false }; 8];
@@ -134,7 +263,7 @@ impl<'a> RenderSearch<'a> {
                 root_key.level,
                 ChunkUnits(root_key.coordinates),
                 neighborhood,
-                VoxelUnits(clip_sphere.center),
+                self.observers,
             ));
         }
     }
@@ -162,6 +291,7 @@ impl<'a> RenderSearch<'a> {
 
             let nhood = RenderNeighborhood {
                 level,
+                coordinates: ChunkUnits(coordinates),
                 neighbors: neighborhood,
             };
 
@@ -185,7 +315,7 @@ impl<'a> RenderSearch<'a> {
                 (true, true) => None,
                 // Old and new frames agree this node is not active. Keep searching down this path if possible.
                 (false, false) => {
-                    self.add_child_neighborhoods_to_heap(coordinates, &nhood, min_neighbor_ptr);
+                    self.add_child_neighborhoods_to_heap(&nhood, min_neighbor_ptr);
                     None
                 }
                 // This node just became inactive, and none of its ancestors were active, so it must have active descendants.
@@ -215,22 +345,19 @@ impl<'a> RenderSearch<'a> {
 
     fn add_child_neighborhoods_to_heap(
         &mut self,
-        parent_coords: IVec3,
         parent_nhood: &RenderNeighborhood,
         min_neighbor_ptr: NodePtr,
     ) {
         // Add all child neighborhoods to the heap.
         let child_neighborhoods =
-            self.construct_child_neighborhoods(min_neighbor_ptr, &parent_nhood.neighbors);
-        for (&child_offset, n) in CUBE_CORNERS.iter().zip(child_neighborhoods.into_iter()) {
-            if let Some(child_neighborhood) = n {
-                self.candidate_heap.push(RenderSearchNode::new(
-                    child_neighborhood.level,
-                    ChunkUnits(parent_coords + child_offset),
-                    child_neighborhood.neighbors,
-                    self.clip_sphere.map(|s| s.center),
-                ));
-            }
+            self.construct_child_neighborhoods(min_neighbor_ptr, parent_nhood);
+        for child_neighborhood in child_neighborhoods.into_iter().flatten() {
+            self.candidate_heap.push(RenderSearchNode::new(
+                child_neighborhood.level,
+                child_neighborhood.coordinates,
+                child_neighborhood.neighbors,
+                self.observers,
+            ));
         }
     }
 
@@ -245,8 +372,7 @@ impl<'a> RenderSearch<'a> {
         // of render chunk budget. To be fair to other chunks in the queue that need to be split, we will only split
         // by one level for now.
 
-        let child_neighborhoods =
-            self.construct_child_neighborhoods(min_neighbor_ptr, &nhood.neighbors);
+        let child_neighborhoods = self.construct_child_neighborhoods(min_neighbor_ptr, nhood);
 
         // Make sure all child neighborhoods are loaded.
         for nhood in child_neighborhoods.iter().flatten() {
@@ -277,10 +403,15 @@ impl<'a> RenderSearch<'a> {
     ) -> LodChange {
         // This node might have active descendants. Merge those active descendants into this node.
         let mut deactivate_nodes = SmallVec::<[NodeLocation; 8]>::new();
-        self.octree
-            .visit_children(min_neighbor_ptr, |child_ptr, _| {
-                self.octree
-                    .visit_tree_depth_first(child_ptr, coords, 0, |node_ptr, node_coords| {
+        self.octree.visit_children_with_coordinates(
+            min_neighbor_ptr,
+            coords,
+            |child_ptr, child_coords| {
+                self.octree.visit_tree_depth_first(
+                    child_ptr,
+                    child_coords,
+                    0,
+                    |node_ptr, node_coords| {
                         let descendant_node = self.octree.get_value(node_ptr).unwrap();
                         let descendant_was_active =
                             descendant_node.state().fetch_and_clear_rendering();
@@ -291,8 +422,10 @@ impl<'a> RenderSearch<'a> {
                         } else {
                             VisitCommand::Continue
                         }
-                    })
-            });
+                    },
+                )
+            },
+        );
 
         self.num_render_chunks += 1;
 
@@ -309,8 +442,11 @@ impl<'a> RenderSearch<'a> {
     fn construct_child_neighborhoods(
         &self,
         min_neighbor_ptr: NodePtr,
-        neighborhood: &[Neighbor; 8],
+        parent_nhood: &RenderNeighborhood,
     ) -> [Option<RenderNeighborhood>; 8] {
+        let neighborhood = &parent_nhood.neighbors;
+        let ChunkUnits(parent_coords) = parent_nhood.coordinates;
+        let min_child = min_child_coords(parent_coords);
         debug_assert!(min_neighbor_ptr.level() > 0);
         let parent_level = min_neighbor_ptr.level();
 
@@ -361,6 +497,7 @@ impl<'a> RenderSearch<'a> {
 
             child_neighborhoods[child_index] = Some(RenderNeighborhood {
                 level: child_level,
+                coordinates: ChunkUnits(min_child + CUBE_CORNERS[child_index]),
                 neighbors: child_neighborhood,
             });
         }
@@ -369,8 +506,10 @@ impl<'a> RenderSearch<'a> {
     }
 
     fn neighborhood_is_loaded(&self, nhood: &RenderNeighborhood) -> bool {
+        // The border of the minimal neighbor is enough to mesh it.
+        let num_needed = if self.share_borders { 1 } else { 8 };
         // PERF: This does redundant checks of the same node.
-        for neighbor in nhood.neighbors {
+        for neighbor in nhood.neighbors.into_iter().take(num_needed) {
             match neighbor {
                 Neighbor::Occupied(ptr) => {
                     let ptr = NodePtr::new(nhood.level, ptr);
@@ -405,12 +544,15 @@ impl RenderSearchNode {
         level: Level,
         coordinates: ChunkUnits<IVec3>,
         neighborhood: [Neighbor; 8],
-        observer: VoxelUnits<Vec3A>,
+        observers: &[VoxelUnits<Vec3A>],
     ) -> Self {
-        let VoxelUnits(observer) = observer;
         let VoxelUnits(bounding_sphere) = chunk_bounding_sphere(level, coordinates);
 
-        let center_dist_to_observer = observer.distance(bounding_sphere.center);
+        // Only the nearest observer matters, since it demands the most detail.
+        let center_dist_to_observer = observers
+            .iter()
+            .map(|VoxelUnits(observer)| observer.distance(bounding_sphere.center))
+            .fold(f32::INFINITY, f32::min);
         // Subtract the bounding sphere's radius to estimate the distance from the observer to the *closest point* on the chunk.
         // This should make it more fair for higher LODs.
         let closest_dist_to_observer = center_dist_to_observer - bounding_sphere.radius;
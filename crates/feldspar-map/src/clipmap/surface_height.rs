@@ -0,0 +1,186 @@
+use crate::chunk::{CHUNK_EDGE, CHUNK_EDGE_LOG2};
+use crate::clipmap::ChunkClipMap;
+use crate::core::glam::{IVec2, IVec3};
+use crate::units::{ChunkUnits, VoxelUnits};
+
+use grid_tree::NodeKey;
+use std::cmp::Reverse;
+
+const CHUNK_EDGE_MASK: i32 = CHUNK_EDGE - 1;
+
+impl ChunkClipMap {
+    /// The `y` of the highest solid LOD0 voxel in the column at `(x, z)`, or `None` if no loaded chunk has a solid voxel in
+    /// that column.
+    ///
+    /// Chunks are searched from the top of the highest tree down, and their [`ChunkOccupancy`](crate::chunk::ChunkOccupancy)
+    /// is used instead of the SDF. Chunks that are missing or still loading are skipped, so the result can be below the real
+    /// surface until the whole column is loaded.
+    pub fn surface_height(&self, x: i32, z: i32) -> Option<i32> {
+        self.surface_heights(VoxelUnits(IVec2::new(x, z)), VoxelUnits(IVec2::ONE))
+            .pop()
+            .flatten()
+    }
+
+    /// The lowest [`Self::surface_height`] of the columns over the LOD0 chunks at `column` (chunk coordinates along X and Z),
+    /// or `None` if the height of any of those columns is unknown.
+    pub fn lowest_surface_height(&self, column: ChunkUnits<IVec2>) -> Option<i32> {
+        let ChunkUnits(column) = column;
+        self.surface_heights(
+            VoxelUnits(column << CHUNK_EDGE_LOG2),
+            VoxelUnits(IVec2::splat(CHUNK_EDGE)),
+        )
+        .into_iter()
+        .try_fold(i32::MAX, |lowest, height| height.map(|h| lowest.min(h)))
+    }
+
+    /// Same as [`Self::surface_height`] for every column in the rectangle of `size` (along X and Z) starting at `min`. The
+    /// height of column `(x, z)` is at index `(x - min.x) + size.x * (z - min.z)`.
+    ///
+    /// Each chunk in the rectangle is visited at most once, so this is much faster than querying the columns one at a time.
+    pub fn surface_heights(
+        &self,
+        min: VoxelUnits<IVec2>,
+        size: VoxelUnits<IVec2>,
+    ) -> Vec<Option<i32>> {
+        let VoxelUnits(min) = min;
+        let VoxelUnits(size) = size;
+        let size = size.max(IVec2::ZERO);
+        let mut heights = vec![None; (size.x * size.y) as usize];
+        if heights.is_empty() {
+            return heights;
+        }
+        let max = min + size - IVec2::ONE;
+
+        let root_level = self.octree.root_level() as i32;
+        let mut roots: Vec<IVec3> = self
+            .octree
+            .iter_roots()
+            .map(|(key, _)| key.coordinates)
+            .collect();
+        roots.sort_by_key(|coords| Reverse(coords.y));
+
+        let min_column = min >> CHUNK_EDGE_LOG2;
+        let max_column = max >> CHUNK_EDGE_LOG2;
+        for column_z in min_column.y..=max_column.y {
+            for column_x in min_column.x..=max_column.x {
+                let column = IVec2::new(column_x, column_z);
+                let voxels_min = (column << CHUNK_EDGE_LOG2).max(min);
+                let voxels_max = ((column << CHUNK_EDGE_LOG2) + CHUNK_EDGE_MASK).min(max);
+                let voxels_size = voxels_max - voxels_min + IVec2::ONE;
+                let mut unresolved = voxels_size.x * voxels_size.y;
+
+                let root_column = column >> root_level;
+                let column_roots = roots
+                    .iter()
+                    .filter(|root| root.x == root_column.x && root.z == root_column.y);
+                'roots: for root in column_roots {
+                    let bottom = root.y << root_level;
+                    let top = ((root.y + 1) << root_level) - 1;
+                    for chunk_y in (bottom..=top).rev() {
+                        let key = NodeKey::new(0, IVec3::new(column.x, chunk_y, column.y));
+                        let occupancy = match self.occupancy(key) {
+                            Some(occupancy) if !occupancy.is_empty() => occupancy,
+                            _ => continue,
+                        };
+                        for z in voxels_min.y..=voxels_max.y {
+                            for x in voxels_min.x..=voxels_max.x {
+                                let height =
+                                    &mut heights[((x - min.x) + size.x * (z - min.y)) as usize];
+                                if height.is_some() {
+                                    continue;
+                                }
+                                if let Some(y) =
+                                    occupancy.column_top(x & CHUNK_EDGE_MASK, z & CHUNK_EDGE_MASK)
+                                {
+                                    *height = Some((chunk_y << CHUNK_EDGE_LOG2) + y);
+                                    unresolved -= 1;
+                                }
+                            }
+                        }
+                        if unresolved == 0 {
+                            break 'roots;
+                        }
+                    }
+                }
+            }
+        }
+        heights
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clipmap::{ChunkNode, NodeState, StreamingConfig, VisitCommand};
+    use crate::sdf::Sd8;
+    use crate::units::ChunkUnits;
+
+    #[test]
+    fn surface_heights_find_the_highest_solid_voxels() {
+        let mut clipmap = ChunkClipMap::new(2, StreamingConfig::default());
+        let root_key = NodeKey::new(clipmap.octree.root_level(), IVec3::ZERO);
+        clipmap
+            .octree
+            .fill_path_to_node_from_root(root_key, |_key, entry| {
+                entry.or_insert_with(|| ChunkNode::new_empty(NodeState::new_zeroed()));
+                VisitCommand::Continue
+            });
+
+        clipmap.edit_chunk(ChunkUnits(IVec3::new(0, 1, 0)), |chunk| {
+            chunk.set_voxel(IVec3::new(3, 4, 5), 1, Sd8::MIN);
+        });
+        clipmap.edit_chunk(ChunkUnits(IVec3::ZERO), |chunk| {
+            chunk.set_voxel(IVec3::new(3, 10, 5), 1, Sd8::MIN);
+            chunk.set_voxel(IVec3::new(6, 2, 5), 1, Sd8::MIN);
+        });
+
+        assert_eq!(clipmap.surface_height(3, 5), Some(20));
+        assert_eq!(clipmap.surface_height(6, 5), Some(2));
+        assert_eq!(clipmap.surface_height(7, 5), None);
+        // Outside of every tree.
+        assert_eq!(clipmap.surface_height(-1, 5), None);
+
+        let heights =
+            clipmap.surface_heights(VoxelUnits(IVec2::new(2, 4)), VoxelUnits(IVec2::new(20, 3)));
+        assert_eq!(heights.len(), 60);
+        assert_eq!(heights[1 + 20], Some(20));
+        assert_eq!(heights[4 + 20], Some(2));
+        assert_eq!(heights.iter().flatten().count(), 2);
+    }
+
+    #[test]
+    fn lowest_surface_height_needs_every_column() {
+        let mut clipmap = ChunkClipMap::new(2, StreamingConfig::default());
+        let root_key = NodeKey::new(clipmap.octree.root_level(), IVec3::ZERO);
+        clipmap
+            .octree
+            .fill_path_to_node_from_root(root_key, |_key, entry| {
+                entry.or_insert_with(|| ChunkNode::new_empty(NodeState::new_zeroed()));
+                VisitCommand::Continue
+            });
+
+        clipmap.edit_chunk(ChunkUnits(IVec3::new(0, 1, 0)), |chunk| {
+            chunk.set_voxel(IVec3::new(3, 4, 5), 1, Sd8::MIN);
+        });
+        assert_eq!(clipmap.lowest_surface_height(ChunkUnits(IVec2::ZERO)), None);
+
+        clipmap.edit_chunk(ChunkUnits(IVec3::ZERO), |chunk| {
+            for z in 0..16 {
+                for x in 0..16 {
+                    chunk.set_voxel(IVec3::new(x, 7, z), 1, Sd8::MIN);
+                }
+            }
+        });
+        assert_eq!(
+            clipmap.lowest_surface_height(ChunkUnits(IVec2::ZERO)),
+            Some(7)
+        );
+    }
+}
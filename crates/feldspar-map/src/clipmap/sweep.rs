@@ -0,0 +1,140 @@
+use super::sdf_sampler::SdfSampler;
+use crate::core::geometry::{Capsule, Sphere};
+use crate::core::glam::{IVec3, Vec3A};
+use crate::core::ilattice::prelude::Extent;
+use crate::{clipmap::ChunkClipMap, sdf::Sd8, units::*};
+
+/// The first contact found by a shape cast like [`ChunkClipMap::cast_sphere`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SweepHit {
+    /// Time of impact: how far the shape moved along the sweep direction before touching a solid.
+    pub toi: f32,
+    /// The approximate point of contact on the surface.
+    pub contact: VoxelUnits<Vec3A>,
+    /// Unit normal of the surface at the contact, pointing out of the solid.
+    pub normal: Vec3A,
+}
+
+/// Shapes that are closer than this to a surface are touching it.
+const CONTACT_EPSILON: f32 = 1.0 / 32.0;
+/// The smallest step taken while sweeping, so we don't stall when grazing a surface.
+const MIN_SWEEP_STEP: f32 = 1.0 / 16.0;
+const TOI_BISECTION_ITERATIONS: u32 = 6;
+
+impl ChunkClipMap {
+    /// Sweeps `sphere` along `dir` for up to `max_t` voxels and returns the first contact with solid LOD0 voxels.
+    ///
+    /// Chunks that aren't loaded at LOD0 are treated as ambient space. If the sphere already overlaps a solid, the contact is at
+    /// `toi = 0`.
+    pub fn cast_sphere(
+        &self,
+        sphere: VoxelUnits<Sphere>,
+        dir: Vec3A,
+        max_t: f32,
+    ) -> Option<SweepHit> {
+        let VoxelUnits(sphere) = sphere;
+        self.cast_capsule(
+            VoxelUnits(Capsule::new(sphere.center, sphere.center, sphere.radius)),
+            dir,
+            max_t,
+        )
+    }
+
+    /// Same as [`Self::cast_sphere`], but for a [`Capsule`], the usual shape of a kinematic character controller.
+    ///
+    /// The distance between the capsule and the surface is estimated from every voxel near the capsule, so the cost of each
+    /// step grows with the capsule's volume. [`Sd8`] saturates one voxel away from the surface, so steps are at most one voxel
+    /// long.
+    pub fn cast_capsule(
+        &self,
+        capsule: VoxelUnits<Capsule>,
+        dir: Vec3A,
+        max_t: f32,
+    ) -> Option<SweepHit> {
+        let VoxelUnits(capsule) = capsule;
+        let dir = dir.normalize();
+        let capsule_at =
+            |t: f32| Capsule::new(capsule.a + t * dir, capsule.b + t * dir, capsule.radius);
+        let mut sampler = SdfSampler::new(self);
+
+        let mut t = 0.0;
+        let mut prev_t = 0.0;
+        loop {
+            let (gap, _) = capsule_gap(&mut sampler, &capsule_at(t));
+            if gap <= CONTACT_EPSILON {
+                // We stepped into contact some time after `prev_t`, so find the moment it started.
+                let mut lo = prev_t;
+                let mut hi = t;
+                for _ in 0..TOI_BISECTION_ITERATIONS {
+                    if hi <= lo {
+                        break;
+                    }
+                    let mid = 0.5 * (lo + hi);
+                    if capsule_gap(&mut sampler, &capsule_at(mid)).0 <= CONTACT_EPSILON {
+                        hi = mid;
+                    } else {
+                        lo = mid;
+                    }
+                }
+                return Some(make_sweep_hit(&mut sampler, &capsule_at(hi), hi, dir));
+            }
+            if t >= max_t {
+                return None;
+            }
+            prev_t = t;
+            t = (t + gap.clamp(MIN_SWEEP_STEP, 1.0)).min(max_t);
+        }
+    }
+}
+
+/// Estimates the distance between `capsule` and the nearest solid, as the minimum of (capsule distance + SDF) over the voxels
+/// near the capsule. Negative values mean the capsule overlaps a solid. Also returns the voxel that gave the estimate.
+///
+/// Saturated ambient voxels don't say how far away the surface is, so the estimate is at most 1 voxel.
+fn capsule_gap(sampler: &mut SdfSampler, capsule: &Capsule) -> (f32, Option<IVec3>) {
+    let aabb = capsule.aabb();
+    let extent = Extent::from_min_and_max(
+        (aabb.minimum - 1.0).floor().as_ivec3(),
+        (aabb.least_upper_bound() + 1.0).ceil().as_ivec3(),
+    );
+
+    let mut gap = 1.0;
+    let mut nearest = None;
+    for p in extent.iter3() {
+        let shape_dist = capsule.signed_distance(p.as_vec3a());
+        if shape_dist - 1.0 >= gap {
+            // Even a solid voxel here couldn't improve the estimate.
+            continue;
+        }
+        let (sdf, _) = sampler.voxel(VoxelUnits(p));
+        if sdf == Sd8::MAX {
+            continue;
+        }
+        let dist = shape_dist + f32::from(sdf);
+        if dist < gap {
+            gap = dist;
+            nearest = Some(p);
+        }
+    }
+    (gap, nearest)
+}
+
+fn make_sweep_hit(sampler: &mut SdfSampler, capsule: &Capsule, toi: f32, dir: Vec3A) -> SweepHit {
+    // A contact is only possible within 1 voxel of the surface, so there is always a nearest voxel.
+    let (_, nearest) = capsule_gap(sampler, capsule);
+    let p = nearest.unwrap().as_vec3a();
+
+    let axis_point = capsule.closest_point_on_segment(p);
+    let approx_normal = (axis_point - p).try_normalize().unwrap_or(-dir);
+    let contact = axis_point - capsule.radius * approx_normal;
+    let normal = sampler
+        .gradient(VoxelUnits(contact))
+        .try_normalize()
+        .unwrap_or(approx_normal);
+
+    SweepHit {
+        toi,
+        contact: VoxelUnits(contact),
+        normal,
+    }
+}
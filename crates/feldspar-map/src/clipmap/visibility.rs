@@ -0,0 +1,96 @@
+use crate::clipmap::{ChunkClipMap, ChunkNode, NodeKey, NodePtr};
+use crate::coordinates::chunk_extent_at_level_vec3a;
+use crate::core::geometry::Frustum;
+use crate::core::glam::IVec3;
+use crate::units::{ChunkUnits, VoxelUnits};
+
+use grid_tree::OctreeI32;
+
+impl ChunkClipMap {
+    /// Walks the octree from the roots and yields the nodes that intersect `frustum` (in LOD0 voxel coordinates) at the level of
+    /// detail chosen by `lod_fn`.
+    ///
+    /// `lod_fn` is called on each visible node above LOD0, and it returns `true` if the node is detailed enough to be yielded,
+    /// or `false` to descend into its children. Nodes without children are always yielded. If a node is refined but some of its
+    /// children don't exist, only the existing children are yielded.
+    pub fn iter_visible<'a, F>(&'a self, frustum: &Frustum, lod_fn: F) -> VisibleNodes<'a, F>
+    where
+        F: FnMut(NodeKey<IVec3>) -> bool,
+    {
+        let mut nodes = VisibleNodes {
+            octree: &self.octree,
+            frustum: *frustum,
+            lod_fn,
+            stack: Vec::new(),
+        };
+        for (root_key, root_node) in self.octree.iter_roots() {
+            push_if_visible(
+                &nodes.frustum,
+                &mut nodes.stack,
+                NodeKey::new(root_key.level, root_key.coordinates),
+                NodePtr::new(root_key.level, root_node.self_ptr),
+            );
+        }
+        nodes
+    }
+}
+
+/// The iterator returned by [`ChunkClipMap::iter_visible`].
+pub struct VisibleNodes<'a, F> {
+    octree: &'a OctreeI32<ChunkNode>,
+    frustum: Frustum,
+    lod_fn: F,
+    stack: Vec<(NodeKey<IVec3>, NodePtr)>,
+}
+
+fn push_if_visible(
+    frustum: &Frustum,
+    stack: &mut Vec<(NodeKey<IVec3>, NodePtr)>,
+    key: NodeKey<IVec3>,
+    ptr: NodePtr,
+) {
+    let VoxelUnits(extent) = chunk_extent_at_level_vec3a(key.level, ChunkUnits(key.coordinates));
+    if frustum.intersects_extent(extent) {
+        stack.push((key, ptr));
+    }
+}
+
+impl<'a, F> Iterator for VisibleNodes<'a, F>
+where
+    F: FnMut(NodeKey<IVec3>) -> bool,
+{
+    type Item = (NodeKey<IVec3>, NodePtr);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((key, ptr)) = self.stack.pop() {
+            if key.level == 0 || (self.lod_fn)(key) {
+                return Some((key, ptr));
+            }
+
+            let Self {
+                octree,
+                frustum,
+                stack,
+                ..
+            } = self;
+            let mut has_children = false;
+            octree.visit_children_with_coordinates(
+                ptr,
+                key.coordinates,
+                |child_ptr, child_coords| {
+                    has_children = true;
+                    push_if_visible(
+                        frustum,
+                        stack,
+                        NodeKey::new(child_ptr.level(), child_coords),
+                        child_ptr,
+                    );
+                },
+            );
+            if !has_children {
+                return Some((key, ptr));
+            }
+        }
+        None
+    }
+}
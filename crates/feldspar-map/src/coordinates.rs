@@ -2,7 +2,7 @@ use crate::core::geometry::Sphere;
 use crate::core::glam::{const_ivec3, IVec3, Vec3A};
 use crate::core::ilattice::prelude::Extent;
 use crate::{
-    chunk::{CHUNK_SHAPE_IVEC3, CHUNK_SHAPE_LOG2_IVEC3, CHUNK_SHAPE_VEC3A},
+    chunk::{CHUNK_SHAPE_IVEC3, CHUNK_SHAPE_VEC3A},
     clipmap::{ChildIndex, Level},
     units::*,
 };
@@ -49,7 +49,7 @@ pub fn chunk_extent_at_level_ivec3(
 }
 
 pub fn chunk_min(coordinates: ChunkUnits<IVec3>) -> VoxelUnits<IVec3> {
-    VoxelUnits(coordinates.0 << CHUNK_SHAPE_LOG2_IVEC3)
+    coordinates.min_voxel()
 }
 
 pub fn chunk_extent_ivec3(coordinates: ChunkUnits<IVec3>) -> VoxelUnits<Extent<IVec3>> {
@@ -63,15 +63,12 @@ pub fn chunk_extent_vec3a(coordinates: ChunkUnits<IVec3>) -> VoxelUnits<Extent<V
 /// Transforms a [`VoxelUnits`] extent `e` into a [`ChunkUnits`] extent `e'` that contains the coordinates of all chunks
 /// intersected by `e`.
 pub fn in_chunk_extent(e: VoxelUnits<Extent<IVec3>>) -> ChunkUnits<Extent<IVec3>> {
-    ChunkUnits(Extent::from_min_and_max(
-        e.0.minimum >> CHUNK_SHAPE_LOG2_IVEC3,
-        e.0.max() >> CHUNK_SHAPE_LOG2_IVEC3,
-    ))
+    e.touched_chunks()
 }
 
 /// Returns the [`ChunkUnits`] coordinates of the chunk that contains `p`.
 pub fn in_chunk(p: VoxelUnits<IVec3>) -> ChunkUnits<IVec3> {
-    ChunkUnits(p.0 >> CHUNK_SHAPE_LOG2_IVEC3)
+    p.floor_chunk()
 }
 
 pub fn ancestor_extent(levels_up: Level, extent: Extent<IVec3>) -> Extent<IVec3> {
@@ -108,10 +105,14 @@ pub fn visit_children(parent_coords: IVec3, mut visitor: impl FnMut(ChildIndex,
     }
 }
 
+/// Returns the extent at LOD0 of the chunk at `(level, coords)`.
+pub fn chunk_lod0_extent(level: Level, coords: ChunkUnits<IVec3>) -> VoxelUnits<Extent<IVec3>> {
+    chunk_extent_at_level_ivec3(level, coords).map(|e| descendant_extent(level, e))
+}
+
 /// Returns a sphere at LOD0 that bounds the chunk at `(level, coords)`.
 pub fn chunk_bounding_sphere(level: Level, coords: ChunkUnits<IVec3>) -> VoxelUnits<Sphere> {
-    chunk_extent_at_level_ivec3(level, coords).map(|e| {
-        let lod0_extent = descendant_extent(level, e);
+    chunk_lod0_extent(level, coords).map(|lod0_extent| {
         let center = (lod0_extent.minimum + (lod0_extent.shape >> 1i32)).as_vec3a();
         let radius = (lod0_extent.shape.max_element() >> 1) as f32 * 3f32.sqrt();
         Sphere { center, radius }
@@ -123,6 +124,14 @@ pub fn sphere_intersecting_ancestor_chunk_extent(
     lod0_sphere: VoxelUnits<Sphere>,
     level: Level,
 ) -> ChunkUnits<Extent<IVec3>> {
-    let sphere_extent = in_chunk_extent(lod0_sphere.map(|s| s.aabb().containing_integer_extent()));
-    sphere_extent.map(|e| ancestor_extent(level, e))
+    extent_intersecting_ancestor_chunk_extent(lod0_sphere.map(|s| s.aabb()), level)
+}
+
+/// Returns the extent covering all chunks at `level` which intersect `lod0_extent`.
+pub fn extent_intersecting_ancestor_chunk_extent(
+    lod0_extent: VoxelUnits<Extent<Vec3A>>,
+    level: Level,
+) -> ChunkUnits<Extent<IVec3>> {
+    let chunk_extent = in_chunk_extent(lod0_extent.map(|e| e.containing_integer_extent()));
+    chunk_extent.map(|e| ancestor_extent(level, e))
 }
@@ -1,39 +1,113 @@
+pub mod archive_file;
+mod backend;
 mod backup_tree;
+mod branch_tree;
 mod change_encoder;
+mod checksum_tree;
 mod chunk_key;
+pub mod delta_file;
+mod encryption;
+mod integrity;
+mod layer_tree;
+#[cfg(not(target_arch = "wasm32"))]
+mod mapped_archive;
+mod memory;
 mod meta_tree;
+mod metadata_tree;
+mod migration;
+mod overlay;
+pub mod region_file;
+mod remote;
+mod sequence_tree;
+mod transaction;
 mod version_change_tree;
 mod version_graph_tree;
 mod working_tree;
 
+pub use backend::{BackendError, MapBackend};
 pub use change_encoder::*;
+pub use checksum_tree::ChunkReadError;
 pub use chunk_key::ChunkDbKey;
+pub use delta_file::DeltaFileError;
+pub use encryption::{ChunkCipher, EncryptionKey};
+pub use integrity::{IntegrityReport, RepairReport};
+#[cfg(not(target_arch = "wasm32"))]
+pub use mapped_archive::{MappedArchive, MappedArchiveBackend};
+pub use memory::MemoryBackend;
+pub use metadata_tree::{ChunkMetadata, MetadataError};
+pub use migration::{Migration, MigrationProgress, MAP_DB_FORMAT_VERSION, MIGRATIONS};
+pub use overlay::OverlayBackend;
+pub use region_file::RegionFileError;
+#[cfg(feature = "http")]
+pub use remote::HttpRangeSource;
+pub use remote::{ChunkCache, FileRangeSource, RangeSource, RemoteBackend};
+pub use transaction::{MapTransaction, MapTransactionError};
 pub use version_change_tree::VersionChanges;
 
+use archive_file::{write_archive_entry, write_archive_header, ArchiveEntry};
 use backup_tree::{
     clear_backup, commit_backup, open_backup_tree, write_changes_to_backup_tree, BackupKeyCache,
 };
-use meta_tree::{open_meta_tree, write_meta};
-use version_change_tree::{archive_version, open_version_change_tree, remove_archived_version};
+use branch_tree::{
+    open_branch_tree, read_all_branch_heads, read_branch_head, remove_branch_head,
+    write_branch_head,
+};
+use checksum_tree::{open_checksum_tree, record_checksum, verify_record};
+use delta_file::{
+    read_delta_header, read_delta_record, write_delta_header, write_delta_record, DeltaHeader,
+    DELTA_FORMAT_VERSION,
+};
+use encryption::{is_encrypted, ChunkEncryption};
+use layer_tree::{open_layer_tree, read_layers, remove_layers, write_layers};
+use meta_tree::{
+    clear_encryption_pending, next_sequence_number, open_meta_tree, read_chunk_edge, read_codec,
+    read_current_branch, read_delta_head, read_encryption_pending, read_layer_schema,
+    read_load_journal, read_material_ids, write_chunk_edge, write_codec, write_current_branch,
+    write_delta_head, write_layer_schema, write_load_journal, write_material_ids, write_meta,
+};
+use metadata_tree::{open_metadata_tree, read_metadata, remove_metadata, write_metadata};
+use region_file::{
+    read_region_header, read_region_record, write_region_header, write_region_record, RegionHeader,
+    REGION_FORMAT_VERSION,
+};
+use sequence_tree::{
+    append_record, latest_seq, open_sequence_tree, prune_records_before, read_record_at_or_before,
+    rewrite_records,
+};
+use version_change_tree::{
+    archive_version, open_version_change_tree, read_archived_version, remove_archived_version,
+};
 use version_graph_tree::{
-    find_path_between_versions, link_version, open_version_graph_tree, VersionNode,
+    find_path_between_versions, find_path_to_root, link_version, open_version_graph_tree,
+    VersionNode,
 };
 use working_tree::{open_working_tree, write_changes_to_working_tree};
 
 use crate::core::archived_buf::ArchivedBuf;
+use crate::core::glam::IVec3;
+use crate::core::ilattice::prelude::Extent;
 use crate::core::rkyv::{Archive, Deserialize, Infallible, Serialize};
-use crate::chunk::CompressedChunk;
+use crate::chunk::{
+    Chunk, ChunkEdge, ChunkLayers, CompressedChunk, CompressedLayers, CompressionCodec,
+    MappedChunk, UniformChunk, VoxelLayerSchema,
+};
 use crate::clipmap::Level;
+use crate::material::MaterialIds;
 use crate::units::*;
-use crate::vox::convert_vox_model_to_chunks;
+use crate::vox::{convert_vox_model_to_chunks, place_vox_model_in_chunks, VoxPalette};
 
+use grid_tree::NodeKey;
 use itertools::Itertools;
-use sled::transaction::{abort, TransactionError};
+use sled::transaction::{abort, ConflictableTransactionError, TransactionError};
 use sled::{IVec, Transactional, Tree};
 use std::collections::BTreeSet;
+use std::io::{Read, Write};
 
 use self::meta_tree::MapDbMetadata;
 
+/// The number of chunks written in each batch by [`MapDb::bulk_write_chunks`].
+pub const BULK_WRITE_BATCH_SIZE: usize = 4096;
+
 type ArchivedIVec<T> = ArchivedBuf<T, IVec>;
 
 #[derive(
@@ -53,6 +127,37 @@ impl Version {
     pub const fn into_sled_key(self) -> [u8; 8] {
         self.number.to_be_bytes()
     }
+
+    /// Returns an error if `bytes` wasn't written by [`Self::into_sled_key`].
+    pub fn from_sled_key(bytes: &[u8]) -> sled::Result<Self> {
+        let bytes = bytes.try_into().map_err(|_| corrupt_value("version"))?;
+        Ok(Self::new(u64::from_be_bytes(bytes)))
+    }
+}
+
+/// The error for a stored value that couldn't have been written by this crate, e.g. because of a bad disk.
+pub(crate) fn corrupt_value(description: impl std::fmt::Display) -> sled::Error {
+    sled::Error::Io(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("corrupt {} in the map database", description),
+    ))
+}
+
+/// Stores every removal of a chunk that `is_shadowed` as air, for backends that write on top of a read-only layer of chunks.
+/// Removing a shadowed chunk from the writable layer would reveal the chunk underneath again.
+pub(crate) fn store_removals_as_air(
+    changes: Vec<(ChunkDbKey, Change<CompressedChunk>)>,
+    mut is_shadowed: impl FnMut(ChunkDbKey) -> Result<bool, BackendError>,
+) -> Result<Vec<(ChunkDbKey, Change<CompressedChunk>)>, BackendError> {
+    changes
+        .into_iter()
+        .map(|(key, change)| match change {
+            Change::Remove if is_shadowed(key)? => {
+                Ok((key, Change::Insert(UniformChunk::Air.compress())))
+            }
+            change => Ok((key, change)),
+        })
+        .collect()
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -63,6 +168,39 @@ pub enum AbortReason {
     NoPathExistsToRoot,
     /// Tried to reference [`VersionChanges`] that don't exist in the change tree.
     MissingVersionChanges,
+    /// Tried to create a branch with the same name as an existing branch.
+    BranchAlreadyExists,
+    /// Tried to reference a branch that doesn't exist in the branch tree.
+    MissingBranch,
+    /// Tried to create a branch before any version was committed.
+    NoCommittedVersion,
+    /// Tried to open a database written by a newer format than [`MAP_DB_FORMAT_VERSION`].
+    UnsupportedFormatVersion(u32),
+    /// Tried to register a [`VoxelLayerSchema`] that doesn't extend the schema of the stored layers.
+    LayerSchemaMismatch,
+    /// Tried to register [`MaterialIds`] that don't extend the stored IDs.
+    MaterialIdsMismatch,
+    /// Tried to create or open a map whose chunks have a different edge length than [`ChunkEdge::COMPILED`].
+    UnsupportedChunkEdge(u32),
+    /// Tried to append changes to a version that isn't the working version.
+    NotWorkingVersion(Version),
+    /// Tried to append a change to `key` at a sequence number that isn't after the `latest` change appended to it.
+    OutOfOrder { key: ChunkDbKey, latest: u64 },
+    /// Tried to open a database whose chunks are encrypted without an [`EncryptionKey`].
+    MissingEncryptionKey,
+    /// Tried to open a database whose chunks are encrypted with a different [`EncryptionKey`].
+    WrongEncryptionKey,
+}
+
+/// The result of [`MapDb::merge_oldest_version`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CompactionStep {
+    /// The oldest version was merged. There are still `excess_versions` more than the limit.
+    Merged { excess_versions: usize },
+    /// The history of the working version is within the limit.
+    Done,
+    /// The oldest version can't be merged because other versions or branches depend on it.
+    Blocked,
 }
 
 /// # Map Database
@@ -90,41 +228,385 @@ pub enum AbortReason {
 /// version (except for the root version). To "revert" to a parent version, all of the backed up values must be re-applied in
 /// reverse order, while the corresponding newer values are archived. By transitivity, any archived version can be reached from
 /// the current working version.
+///
+/// ### Branch Tree
+///
+/// Versions can be given names by forking *branches*. A branch only stores its head version, and the chunks that were not
+/// changed since the fork are shared with all other branches through the version tree, so forking is free and switching
+/// branches only rewrites the chunks that differ. While the working version is on a branch, every commit advances that
+/// branch's head.
+///
+/// ### Checksum Tree
+///
+/// Every record written to the working tree gets a checksum in the "checksum tree," under the same key. Records are verified
+/// before they're deserialized, so a corrupt record is reported as a [`ChunkReadError::Corrupt`] instead of being decompressed
+/// into garbage. Records written before checksums existed can't be verified. [`MapDb::verify_all`] checks every record.
+///
+/// ### Metadata Tree
+///
+/// Games can attach serialized [`ChunkMetadata`] to any chunk. Entries are keyed by the chunk key and the metadata type's
+/// name, and they only exist in the latest state of the map, outside of the version tree.
+///
+/// ## Transactions
+///
+/// Every write to the working version happens in a single [`sled`] transaction, so it's either persisted completely or not at
+/// all, even if the process crashes. [`MapDb::transaction`] stages the changes to many chunks, e.g. a large structure, so they
+/// can be committed by one write.
+///
+/// ## Format Versions
+///
+/// The meta tree stores the [`MAP_DB_FORMAT_VERSION`] that the database was written with. Opening a database from an older
+/// version upgrades it in place by running the [`MIGRATIONS`], and opening one from a newer version fails.
+///
+/// ## Chunk Shape
+///
+/// The meta tree also stores the [`ChunkEdge`] that the map was created with, since the records of chunks with different
+/// shapes aren't compatible. The edge is chosen when the crate is built, so opening a map whose chunks don't match
+/// [`ChunkEdge::COMPILED`] fails, e.g. a 64³ map in a build without the `chunk_edge_64` feature.
+///
+/// ## Encryption
+///
+/// A map opened with [`MapDb::open_with_key`] encrypts every chunk it writes with the [`ChunkCipher`] recorded in the meta
+/// tree, and it decrypts chunks as they're read, so the rest of the API works with plain compressed chunks. Once a map is
+/// encrypted, it can't be opened without its key. The chunks that a map already has when it's first opened with a key are
+/// encrypted right away, and after that, a chunk that isn't encrypted is rejected as corrupt. Chunks archived by older
+/// versions are encrypted when they're restored. Exported regions and archives are decrypted, since they're read by tools
+/// that don't have the key.
 pub struct MapDb {
     meta_tree: Tree,
     working_tree: Tree,
     backup_tree: Tree,
+    checksum_tree: Tree,
+    /// The [`ChunkLayers`] of each chunk, which aren't versioned.
+    layer_tree: Tree,
+    /// The [`ChunkMetadata`] of each chunk, which isn't versioned either.
+    metadata_tree: Tree,
+    /// Every change written by [`MapDb::append_changes`], by chunk and sequence number.
+    sequence_tree: Tree,
 
     // We keep the change tree and graph trees separate so that finding a path between versions does not require reading all of
     // the changes associated with each version.
     version_change_tree: Tree,
     version_graph_tree: Tree,
+    branch_tree: Tree,
 
     /// HACK: We only have this type to work around sled's lack of transactional iteration. When archiving a version, we iterate
     /// over this set of keys and put the entries into the archive.
     backup_key_cache: BackupKeyCache,
     // Zero-copy isn't super important for this tiny struct, so we just copy it for convenience.
     cached_meta: MapDbMetadata,
+    cached_current_branch: Option<String>,
+    cached_codec: CompressionCodec,
+    cached_layer_schema: VoxelLayerSchema,
+    cached_material_ids: MaterialIds,
+    cached_chunk_edge: ChunkEdge,
+    encryption: Option<ChunkEncryption>,
+    /// Whether chunks that were written before the map was encrypted may still be stored in the clear.
+    encrypting: bool,
 }
 
 impl MapDb {
-    /// Opens the database. On first open, a single working version will be created with no parent version.
+    /// Opens the database. On first open, a single working version will be created with no parent version, and the map's
+    /// chunks will have the [`ChunkEdge::COMPILED`] edge length.
     pub fn open(db: &sled::Db, map_name: &str) -> Result<Self, TransactionError<AbortReason>> {
+        Self::open_with_progress(db, map_name, |_| ())
+    }
+
+    /// Like [`MapDb::open`], but a new map records `chunk_edge` as the edge length of its chunks. Fails with
+    /// [`AbortReason::UnsupportedChunkEdge`] before touching `db` if the crate wasn't built for `chunk_edge`, i.e. it isn't
+    /// [`ChunkEdge::COMPILED`].
+    pub fn create(
+        db: &sled::Db,
+        map_name: &str,
+        chunk_edge: ChunkEdge,
+    ) -> Result<Self, TransactionError<AbortReason>> {
+        Self::open_inner(db, map_name, chunk_edge, None, false, &mut |_| ())
+    }
+
+    /// Like [`MapDb::open`], but chunks are encrypted with `key`. A map that wasn't encrypted yet is encrypted from now on,
+    /// starting with the chunks it already has. Fails with [`AbortReason::WrongEncryptionKey`] if the map is encrypted
+    /// with a different key.
+    pub fn open_with_key(
+        db: &sled::Db,
+        map_name: &str,
+        key: EncryptionKey,
+    ) -> Result<Self, TransactionError<AbortReason>> {
+        Self::open_inner(
+            db,
+            map_name,
+            ChunkEdge::COMPILED,
+            Some(&key),
+            false,
+            &mut |_| (),
+        )
+    }
+
+    /// Like [`MapDb::open`], but reports the progress of any [`Migration`]s that upgrade the database.
+    pub fn open_with_progress(
+        db: &sled::Db,
+        map_name: &str,
+        mut progress: impl FnMut(MigrationProgress),
+    ) -> Result<Self, TransactionError<AbortReason>> {
+        Self::open_inner(
+            db,
+            map_name,
+            ChunkEdge::COMPILED,
+            None,
+            false,
+            &mut progress,
+        )
+    }
+
+    /// Like [`MapDb::open_with_progress`], but with an optional `key`, as in [`MapDb::open_with_key`].
+    pub fn open_with_key_and_progress(
+        db: &sled::Db,
+        map_name: &str,
+        key: Option<&EncryptionKey>,
+        mut progress: impl FnMut(MigrationProgress),
+    ) -> Result<Self, TransactionError<AbortReason>> {
+        Self::open_inner(db, map_name, ChunkEdge::COMPILED, key, false, &mut progress)
+    }
+
+    /// Like [`MapDb::open_with_key_and_progress`], for a map that's only read, e.g. the base of an [`OverlayBackend`]. `key`
+    /// is only used if the map is already encrypted, so a map that isn't stays that way, and nothing about the encryption is
+    /// written.
+    pub fn open_read_only_with_key_and_progress(
+        db: &sled::Db,
+        map_name: &str,
+        key: Option<&EncryptionKey>,
+        mut progress: impl FnMut(MigrationProgress),
+    ) -> Result<Self, TransactionError<AbortReason>> {
+        Self::open_inner(db, map_name, ChunkEdge::COMPILED, key, true, &mut progress)
+    }
+
+    fn open_inner(
+        db: &sled::Db,
+        map_name: &str,
+        new_chunk_edge: ChunkEdge,
+        key: Option<&EncryptionKey>,
+        read_only: bool,
+        progress: &mut dyn FnMut(MigrationProgress),
+    ) -> Result<Self, TransactionError<AbortReason>> {
+        if new_chunk_edge != ChunkEdge::COMPILED {
+            return Err(TransactionError::Abort(AbortReason::UnsupportedChunkEdge(
+                new_chunk_edge.length(),
+            )));
+        }
+
         let (meta_tree, cached_meta) = open_meta_tree(map_name, db)?;
+        let cached_chunk_edge = if let Some(length) = read_chunk_edge(&meta_tree)? {
+            ChunkEdge::from_length(length)
+                .filter(|&edge| edge == ChunkEdge::COMPILED)
+                .ok_or(TransactionError::Abort(AbortReason::UnsupportedChunkEdge(
+                    length,
+                )))?
+        } else {
+            write_chunk_edge(&meta_tree, new_chunk_edge)?;
+            new_chunk_edge
+        };
         let version_change_tree = open_version_change_tree(map_name, db)?;
         let version_graph_tree = open_version_graph_tree(map_name, db)?;
         let (backup_tree, backup_key_cache) = open_backup_tree(map_name, db)?;
         let working_tree = open_working_tree(map_name, db)?;
+        let checksum_tree = open_checksum_tree(map_name, db)?;
+        let layer_tree = open_layer_tree(map_name, db)?;
+        let metadata_tree = open_metadata_tree(map_name, db)?;
+        let sequence_tree = open_sequence_tree(map_name, db)?;
+        let branch_tree = open_branch_tree(map_name, db)?;
+        let cached_current_branch = read_current_branch(&meta_tree)?;
+        let cached_codec = read_codec(&meta_tree)?;
+        let cached_layer_schema = read_layer_schema(&meta_tree)?;
+        let cached_material_ids = read_material_ids(&meta_tree)?;
+        let mut encryption = ChunkEncryption::open(&meta_tree, key)?;
+        if let (None, Some(key), false) = (&encryption, key, read_only) {
+            let new_encryption = ChunkEncryption::new(key);
+            new_encryption.record(&meta_tree)?;
+            log::info!("Encrypting map {}", map_name);
+            encryption = Some(new_encryption);
+        }
+        let encrypting = encryption.is_some() && read_encryption_pending(&meta_tree)?;
 
-        Ok(Self {
+        let map = Self {
             meta_tree,
             working_tree,
             backup_tree,
+            checksum_tree,
+            layer_tree,
+            metadata_tree,
+            sequence_tree,
             version_change_tree,
             version_graph_tree,
+            branch_tree,
             backup_key_cache,
             cached_meta,
-        })
+            cached_current_branch,
+            cached_codec,
+            cached_layer_schema,
+            cached_material_ids,
+            cached_chunk_edge,
+            encryption,
+            encrypting,
+        };
+        migration::run_migrations(&map, progress)?;
+        if map.encrypting && !read_only {
+            map.encrypt_existing_chunks()?;
+        }
+        Ok(map)
+    }
+
+    /// The names of every map that was ever opened in `db`, in order. Each map is a separate world, or "dimension", with its
+    /// own chunks, versions, and layers, so one database can hold e.g. an overworld and an editor's scratch space.
+    pub fn map_names(db: &sled::Db) -> Vec<String> {
+        let mut names: Vec<_> = db
+            .tree_names()
+            .into_iter()
+            .filter_map(|name| {
+                let name = std::str::from_utf8(&name).ok()?;
+                Some(name.strip_suffix("-meta")?.to_owned())
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// The edge length of the map's chunks, as recorded when it was created.
+    pub fn chunk_edge(&self) -> ChunkEdge {
+        self.cached_chunk_edge
+    }
+
+    /// The codec that chunks should be compressed with before they're written to this database. Chunks compressed with any
+    /// other codec can still be written and read.
+    pub fn codec(&self) -> CompressionCodec {
+        self.cached_codec
+    }
+
+    /// Stores `codec` in the database header. Existing chunks are not rewritten.
+    pub fn set_codec(&mut self, codec: CompressionCodec) -> sled::Result<()> {
+        write_codec(&self.meta_tree, codec)?;
+        self.cached_codec = codec;
+        Ok(())
+    }
+
+    /// The cipher that chunks are encrypted with before they're written to this database.
+    pub fn cipher(&self) -> ChunkCipher {
+        if self.encryption.is_some() {
+            ChunkCipher::Aes256Gcm
+        } else {
+            ChunkCipher::None
+        }
+    }
+
+    /// The schema of the voxel layers stored in this database. Empty until [`MapDb::register_layers`] is called.
+    pub fn layer_schema(&self) -> &VoxelLayerSchema {
+        &self.cached_layer_schema
+    }
+
+    /// Stores the schema of this map's voxel layers. The new `schema` may append layers to the stored schema, but any other
+    /// change fails with [`AbortReason::LayerSchemaMismatch`], since the stored layers would be misread.
+    pub fn register_layers(
+        &mut self,
+        schema: VoxelLayerSchema,
+    ) -> Result<(), TransactionError<AbortReason>> {
+        if !self.cached_layer_schema.is_prefix_of(&schema) {
+            return Err(TransactionError::Abort(AbortReason::LayerSchemaMismatch));
+        }
+        if schema != self.cached_layer_schema {
+            write_layer_schema(&self.meta_tree, &schema)?;
+            self.cached_layer_schema = schema;
+        }
+        Ok(())
+    }
+
+    /// The IDs of the named voxel materials in this database. Empty until [`MapDb::register_material_ids`] is called.
+    pub fn material_ids(&self) -> &MaterialIds {
+        &self.cached_material_ids
+    }
+
+    /// Stores the IDs of this map's voxel materials. The new `ids` may assign IDs to new materials, but any other change fails
+    /// with [`AbortReason::MaterialIdsMismatch`], since the stored chunks would show the wrong materials.
+    pub fn register_material_ids(
+        &mut self,
+        ids: MaterialIds,
+    ) -> Result<(), TransactionError<AbortReason>> {
+        if !self.cached_material_ids.is_prefix_of(&ids) {
+            return Err(TransactionError::Abort(AbortReason::MaterialIdsMismatch));
+        }
+        if ids != self.cached_material_ids {
+            write_material_ids(&self.meta_tree, &ids)?;
+            self.cached_material_ids = ids;
+        }
+        Ok(())
+    }
+
+    /// Replaces the voxel layers of the chunk at `key`.
+    ///
+    /// Unlike chunks, layers aren't versioned. Every version and branch shares the latest layers, and they aren't included in
+    /// exports, so they're best suited for simulation state that can be rebuilt.
+    pub fn write_chunk_layers(
+        &self,
+        key: ChunkDbKey,
+        layers: &CompressedLayers,
+    ) -> sled::Result<()> {
+        write_layers(&self.layer_tree, key, layers)
+    }
+
+    /// Reads and decompresses the voxel layers of the chunk at `key` with the [`MapDb::layer_schema`].
+    pub fn read_chunk_layers(
+        &self,
+        key: ChunkDbKey,
+    ) -> Result<Option<ChunkLayers>, ChunkReadError> {
+        let compressed = if let Some(compressed) = read_layers(&self.layer_tree, key)? {
+            compressed
+        } else {
+            return Ok(None);
+        };
+        compressed
+            .decompress(&self.cached_layer_schema)
+            .map(Some)
+            .ok_or(ChunkReadError::Corrupt(key))
+    }
+
+    pub fn remove_chunk_layers(&self, key: ChunkDbKey) -> sled::Result<()> {
+        remove_layers(&self.layer_tree, key)
+    }
+
+    /// Replaces the `M` metadata of the chunk at `key`.
+    ///
+    /// Like layers, metadata isn't versioned or exported, so every version and branch shares the latest metadata.
+    pub fn write_chunk_metadata<M: ChunkMetadata>(
+        &self,
+        key: ChunkDbKey,
+        metadata: &M,
+    ) -> Result<(), MetadataError> {
+        write_metadata(&self.metadata_tree, key, metadata)
+    }
+
+    /// Reads the `M` metadata of the chunk at `key`, migrating it if it was written with an older [`ChunkMetadata::VERSION`].
+    pub fn read_chunk_metadata<M: ChunkMetadata>(
+        &self,
+        key: ChunkDbKey,
+    ) -> Result<Option<M>, MetadataError> {
+        read_metadata(&self.metadata_tree, key)
+    }
+
+    pub fn remove_chunk_metadata<M: ChunkMetadata>(&self, key: ChunkDbKey) -> sled::Result<()> {
+        remove_metadata::<M>(&self.metadata_tree, key)
+    }
+
+    /// Blocks until every write to the underlying [`sled::Db`] is durable on disk. Returns the number of bytes flushed.
+    pub fn flush(&self) -> sled::Result<usize> {
+        // Trees share the same log, so flushing any of them flushes the whole database.
+        self.working_tree.flush()
+    }
+
+    /// Replaces the load journal, a hint of which chunks to read ahead of time the next time this map is opened.
+    pub fn write_load_journal(&self, keys: &[ChunkDbKey]) -> sled::Result<()> {
+        write_load_journal(&self.meta_tree, keys)
+    }
+
+    /// The keys from the last [`MapDb::write_load_journal`], or none if it was never written.
+    pub fn read_load_journal(&self) -> sled::Result<Vec<ChunkDbKey>> {
+        read_load_journal(&self.meta_tree)
     }
 
     /// Writes all data from `model` into `target_lod` of the working version.
@@ -133,11 +615,60 @@ impl MapDb {
         // Write the chunks into the database.
         let mut encoder = ChangeEncoder::default();
         for (ChunkUnits(chunk_coords), chunk) in chunks.into_iter() {
-            encoder.add_compressed_change(ChunkDbKey::new(target_lod, chunk_coords.into()), Change::Insert(chunk.compress()));
+            encoder.add_compressed_change(
+                ChunkDbKey::new(target_lod, chunk_coords.into()),
+                Change::Insert(chunk.compress_with(self.cached_codec)),
+            );
         }
         self.write_working_version(encoder.encode())
     }
 
+    /// Writes all voxels from `model`, translated by `offset`, into LOD0 of the working version, with colors mapped through
+    /// `palette`. Unlike [`MapDb::import_vox`], the existing voxels around the model are kept.
+    ///
+    /// Returns the chunks that were written, so that any loaded copies can be replaced.
+    pub fn import_vox_model(
+        &mut self,
+        model: &vox_format::types::Model,
+        offset: VoxelUnits<IVec3>,
+        palette: &VoxPalette,
+    ) -> Result<Vec<(ChunkUnits<IVec3>, Chunk)>, TransactionError> {
+        let chunks = place_vox_model_in_chunks(model, offset, palette, |chunk_coords| {
+            let ChunkUnits(coords) = chunk_coords;
+            let key = ChunkDbKey::new(0, coords.into());
+            let read = self.read_working_version(key).and_then(|change| {
+                let archived = change.as_ref().and_then(|c| c.as_ref().get_insert_data());
+                archived
+                    .map(|archived| {
+                        archived
+                            .try_decompress()
+                            .ok_or(ChunkReadError::Corrupt(key))
+                    })
+                    .transpose()
+            });
+            let chunk = match read {
+                Ok(chunk) => chunk,
+                Err(ChunkReadError::Database(e)) => return Err(e),
+                Err(ChunkReadError::Corrupt(key)) => {
+                    // The model is placed in an empty chunk instead, which replaces the corrupt record.
+                    log::error!("Corrupt record of {:?} overwritten by import", key);
+                    None
+                }
+            };
+            Ok::<_, sled::Error>(chunk.unwrap_or_default())
+        })?;
+
+        let mut encoder = ChangeEncoder::default();
+        for (ChunkUnits(coords), chunk) in chunks.iter() {
+            let key = ChunkDbKey::new(0, (*coords).into());
+            encoder
+                .add_compressed_change(key, Change::Insert(chunk.compress_with(self.cached_codec)));
+        }
+        self.write_working_version(encoder.encode())?;
+
+        Ok(chunks.into_iter().collect())
+    }
+
     pub fn cached_meta(&self) -> &MapDbMetadata {
         &self.cached_meta
     }
@@ -148,16 +679,22 @@ impl MapDb {
         changes: EncodedChanges<CompressedChunk>,
     ) -> Result<(), TransactionError> {
         log::trace!("Writing to {:?}", self.cached_meta.working_version);
+        let changes = self.encrypt_changes(changes);
         let Self {
             working_tree,
             backup_tree,
+            checksum_tree,
             backup_key_cache,
             ..
         } = self;
-        let new_backup_keys: Vec<_> =
-            (&*working_tree, &*backup_tree).transaction(|(working_txn, backup_txn)| {
-                let reverse_changes =
-                    write_changes_to_working_tree(working_txn, backup_key_cache, changes.clone())?;
+        let new_backup_keys: Vec<_> = (&*working_tree, &*backup_tree, &*checksum_tree)
+            .transaction(|(working_txn, backup_txn, checksum_txn)| {
+                let reverse_changes = write_changes_to_working_tree(
+                    working_txn,
+                    checksum_txn,
+                    backup_key_cache,
+                    changes.clone(),
+                )?;
                 let new_backup_keys = reverse_changes
                     .changes
                     .iter()
@@ -174,174 +711,1137 @@ impl MapDb {
         Ok(())
     }
 
-    /// Reads the compressed bytes of the chunk at `key` for the working version.
-    pub fn read_working_version(
-        &self,
-        key: ChunkDbKey,
-    ) -> Result<Option<ArchivedChangeIVec<CompressedChunk>>, sled::Error> {
-        let bytes = self.working_tree.get(IVec::from(&key.into_sled_key()))?;
-        Ok(bytes.map(|b| unsafe { ArchivedIVec::<Change<CompressedChunk>>::new(b) }))
+    /// Returns a sequence number that's greater than every one returned before, for [`MapDb::append_changes`].
+    ///
+    /// An edit producer should take its sequence number before it reads the chunks that it edits, so if another producer
+    /// appends changes to the same chunks in the meantime, the edit that was based on the older chunks is rejected.
+    pub fn next_sequence_number(&self) -> sled::Result<u64> {
+        next_sequence_number(&self.meta_tree)
     }
 
-    /// Archives the backup tree entries into a [`VersionChanges`] that gets serialized and stored in the version change tree
-    /// with the current working [`Version`]. A new working version is generated and the old working version becomes the parent
-    /// version.
+    /// Like [`MapDb::write_working_version`], but all of `changes` are appended at sequence number `seq`, so concurrent edit
+    /// producers, e.g. a brush and a script, can't overwrite each other's newer changes.
     ///
-    /// Nothing happens if the working version has no changes.
-    pub fn commit_working_version(&mut self) -> Result<(), TransactionError<AbortReason>> {
-        if self.backup_key_cache.keys.is_empty() {
-            return Ok(());
+    /// Fails with [`AbortReason::OutOfOrder`] if a change to any of the same chunks was already appended at or after `seq`,
+    /// or with [`AbortReason::NotWorkingVersion`] if `version` was committed since the producer started. Either way, nothing
+    /// is written.
+    pub fn append_changes(
+        &mut self,
+        version: Version,
+        seq: u64,
+        changes: EncodedChanges<CompressedChunk>,
+    ) -> Result<(), TransactionError<AbortReason>> {
+        if version != self.cached_meta.working_version {
+            return Err(TransactionError::Abort(AbortReason::NotWorkingVersion(
+                version,
+            )));
         }
 
-        log::trace!(
-            "Committing non-empty {:?}",
-            self.cached_meta.working_version
+        log::trace!("Appending to {:?} at {}", version, seq);
+        let changes = self.encrypt_changes(changes);
+        let Self {
+            working_tree,
+            backup_tree,
+            checksum_tree,
+            sequence_tree,
+            backup_key_cache,
+            ..
+        } = self;
+        let trees = (
+            &*working_tree,
+            &*backup_tree,
+            &*checksum_tree,
+            &*sequence_tree,
         );
-
-        let new_meta = (
-            &self.backup_tree,
-            &self.version_graph_tree,
-            &self.version_change_tree,
-            &self.meta_tree,
-        )
-            .transaction(|(backup_txn, graph_txn, changes_txn, meta_txn)| {
-                if let Some(parent) = self.cached_meta.parent_version {
-                    log::trace!("Archiving {:?} from backup", parent);
-                    archive_version(
-                        changes_txn,
-                        parent,
-                        &commit_backup(backup_txn, &self.backup_key_cache)?,
-                    )?;
-                } else {
-                    // We only need to do this once, but it's important for correctness.
-                    clear_backup(backup_txn, &self.backup_key_cache)?;
+        let new_backup_keys: Vec<_> =
+            trees.transaction(|(working_txn, backup_txn, checksum_txn, sequence_txn)| {
+                for (key_bytes, change) in changes.changes.iter() {
+                    let key = ChunkDbKey::from_sled_key(key_bytes);
+                    if let Some(latest) = latest_seq(sequence_txn, key)? {
+                        if latest >= seq {
+                            return abort(AbortReason::OutOfOrder { key, latest });
+                        }
+                    }
+                    append_record(sequence_txn, key, seq, change.clone().take_bytes())?;
                 }
-                link_version(
-                    graph_txn,
-                    self.cached_meta.working_version,
-                    VersionNode {
-                        parent_version: self.cached_meta.parent_version,
-                    },
+                let reverse_changes = write_changes_to_working_tree(
+                    working_txn,
+                    checksum_txn,
+                    backup_key_cache,
+                    changes.clone(),
                 )?;
-                let new_meta = MapDbMetadata {
-                    grandparent_version: self.cached_meta.parent_version,
-                    parent_version: Some(self.cached_meta.working_version),
-                    working_version: Version::new(graph_txn.generate_id()?),
-                };
-                write_meta(meta_txn, &new_meta)?;
-                Ok(new_meta)
+                let new_backup_keys = reverse_changes
+                    .changes
+                    .iter()
+                    .map(|(key, _)| ChunkDbKey::from_sled_key(key))
+                    .collect();
+                write_changes_to_backup_tree(backup_txn, reverse_changes)?;
+                Ok(new_backup_keys)
             })?;
-        self.backup_key_cache.keys.clear();
-        self.cached_meta = new_meta;
+        for key in new_backup_keys.into_iter() {
+            debug_assert!(!backup_key_cache.keys.contains(&key));
+            backup_key_cache.keys.insert(key);
+        }
         Ok(())
     }
 
-    /// Sets the parent version to `new_parent_version` and generates a new (empty) working child version.
+    /// The latest change to `key` that was appended at or before `seq`, along with the sequence number it was appended at.
+    /// Changes that were written without a sequence number aren't included.
+    pub fn read_at_or_before(
+        &self,
+        key: ChunkDbKey,
+        seq: u64,
+    ) -> Result<Option<(u64, ArchivedChangeIVec<CompressedChunk>)>, ChunkReadError> {
+        let record = read_record_at_or_before(&self.sequence_tree, key, seq)?;
+        record
+            .map(|(seq, bytes)| {
+                let record = unsafe { ArchivedChangeIVec::<CompressedChunk>::new(bytes) };
+                Ok((seq, self.decrypted_record(&key.into_sled_key(), record)?))
+            })
+            .transpose()
+    }
+
+    /// Removes the appended changes that were replaced at or before `seq`, since they can only be read at earlier sequence
+    /// numbers. Returns the number of changes removed.
+    pub fn prune_appended_changes(&self, seq: u64) -> sled::Result<usize> {
+        prune_records_before(&self.sequence_tree, seq)
+    }
+
+    /// Runs `f` with a [`MapTransaction`] and then writes all of the chunks it staged to the working version atomically. If `f`
+    /// returns an error, nothing is written.
+    pub fn transaction<R, E>(
+        &mut self,
+        f: impl FnOnce(&mut MapTransaction) -> Result<R, E>,
+    ) -> Result<R, MapTransactionError<E>> {
+        let mut txn = MapTransaction::new(self);
+        let output = f(&mut txn).map_err(MapTransactionError::Abort)?;
+        if txn.num_changes() > 0 {
+            let changes = txn.into_encoded_changes();
+            self.write_working_version(changes)
+                .map_err(MapTransactionError::Database)?;
+        }
+        Ok(output)
+    }
+
+    /// Writes `chunks` straight into the working tree in batches of [`BULK_WRITE_BATCH_SIZE`], bypassing the backup tree.
+    /// Returns the number of chunks written.
     ///
-    /// This will always `commit_working_version` before migrating to a new parent. If there is no parent for the current
-    /// working version, then nothing happens.
-    pub fn branch_from_version(
+    /// This is much faster than [`MapDb::write_working_version`] for populating a new map, but the chunks don't belong to any
+    /// version's changes, so they can't be reverted. Each batch is atomic, but if one fails, the earlier batches stay written.
+    pub fn bulk_write_chunks(
         &mut self,
-        new_parent_version: Version,
-    ) -> Result<(), TransactionError<AbortReason>> {
-        // After committing, we may end up with a new empty working version. But it's not linked into the graph yet. We can just
-        // abandon it, since it is empty.
-        self.commit_working_version()?;
+        chunks: impl Iterator<Item = (NodeKey<IVec3>, CompressedChunk)>,
+    ) -> sled::Result<usize> {
+        let mut num_written = 0;
+        for batch_chunks in &chunks.chunks(BULK_WRITE_BATCH_SIZE) {
+            let mut batch = sled::Batch::default();
+            let mut checksums = sled::Batch::default();
+            for (key, chunk) in batch_chunks {
+                let key_bytes = ChunkDbKey::from(key).into_sled_key();
+                let record = Change::Insert(self.encrypt_chunk(&key_bytes, chunk)).serialize();
+                checksums.insert(key_bytes.as_ref(), record_checksum(&record).as_ref());
+                batch.insert(key_bytes.as_ref(), record.as_ref());
+                num_written += 1;
+            }
+            (&self.working_tree, &self.checksum_tree)
+                .transaction(|(working_txn, checksum_txn)| {
+                    working_txn.apply_batch(&batch)?;
+                    checksum_txn.apply_batch(&checksums)?;
+                    Ok::<_, ConflictableTransactionError>(())
+                })
+                .map_err(|e| match e {
+                    TransactionError::Storage(e) => e,
+                    TransactionError::Abort(()) => unreachable!(),
+                })?;
+        }
+        log::trace!("Bulk wrote {} chunks", num_written);
+        Ok(num_written)
+    }
 
-        let old_meta = self.cached_meta;
+    /// Writes all LOD0 chunks of the working version in `extent` to `writer` in the [region file format](region_file).
+    /// Returns the number of chunks written.
+    pub fn export_region(
+        &self,
+        extent: ChunkUnits<Extent<IVec3>>,
+        mut writer: impl Write,
+    ) -> Result<usize, RegionFileError> {
+        let ChunkUnits(extent) = extent;
 
-        if let Some(old_parent_version) = old_meta.parent_version {
-            let new_meta = (
-                &self.meta_tree,
-                &self.version_graph_tree,
-                &self.version_change_tree,
-                &self.working_tree,
-            )
-                .transaction(|(meta_txn, graph_txn, change_txn, working_txn)| {
-                    // Apply the archived changes from all versions between the old parent version and the new parent version,
-                    // leaving behind the inverse changes.
-                    let path = find_path_between_versions(
-                        graph_txn,
-                        old_parent_version,
-                        new_parent_version,
-                    )?;
-                    let empty_backup_keys = BackupKeyCache {
-                        keys: BTreeSet::default(),
-                    };
-                    log::trace!(
-                        "Migrating from parent {:?} to parent {:?}",
-                        old_parent_version,
-                        new_parent_version
-                    );
-                    for (&prev_version, &next_version) in path.path.iter().tuple_windows() {
-                        if let Some(changes) = remove_archived_version(change_txn, next_version)? {
-                            let mut encoder = ChangeEncoder::default();
-                            for (key, change) in changes.as_ref().changes.iter() {
-                                let key: ChunkDbKey = key.deserialize(&mut Infallible).unwrap();
-                                // PERF: in principle we should be able to copy the compressed bytes directly from the archived
-                                // change, but the types aren't set up for that yet
-                                let change = change.deserialize(&mut Infallible).unwrap();
-                                encoder.add_compressed_change(key, change);
-                            }
-                            let reverse_changes = write_changes_to_working_tree(
-                                working_txn,
-                                &empty_backup_keys,
-                                encoder.encode(),
-                            )?;
-                            let prev_version_changes = VersionChanges::from(&reverse_changes);
-                            log::trace!("Archiving {:?} from working tree", prev_version,);
-                            archive_version(change_txn, prev_version, &prev_version_changes)?;
-                        } else {
-                            return abort(AbortReason::MissingVersionChanges);
-                        }
-                    }
-                    let new_working_version = Version::new(graph_txn.generate_id()?);
-                    let new_meta = MapDbMetadata {
-                        grandparent_version: path.end_parent,
-                        parent_version: Some(new_parent_version),
-                        working_version: new_working_version,
-                    };
-                    write_meta(meta_txn, &new_meta)?;
-                    Ok(new_meta)
-                })?;
-            self.cached_meta = new_meta;
+        // The Morton range covers more than the extent, so we need to filter it.
+        let key_range = ChunkDbKey::extent_range(0, extent);
+        let min_key = IVec::from(key_range.start().into_sled_key().as_ref());
+        let max_key = IVec::from(key_range.end().into_sled_key().as_ref());
+        let mut records = Vec::new();
+        for entry in self.working_tree.range(min_key..=max_key) {
+            let (key_bytes, value) = entry?;
+            let key: NodeKey<IVec3> = ChunkDbKey::from_sled_key(&key_bytes).into();
+            if !extent.contains(key.coordinates) {
+                continue;
+            }
+            let change = self.verified_record(&key_bytes, value)?;
+            if let Some(compressed) = change.as_ref().get_insert_data() {
+                // Chunks are stored with this database's codec and encoding, but region files always hold the same form.
+                let chunk = Chunk::try_from_compressed_bytes(&compressed.bytes)
+                    .ok_or_else(|| RegionFileError::Corrupt(key.into()))?;
+                records.push((key.coordinates - extent.minimum, chunk.compress().bytes));
+            }
         }
 
-        Ok(())
+        write_region_header(
+            &mut writer,
+            &RegionHeader {
+                format_version: REGION_FORMAT_VERSION,
+                extent,
+                num_records: records.len() as u64,
+            },
+        )?;
+        for (offset, bytes) in records.iter() {
+            write_region_record(&mut writer, *offset, bytes)?;
+        }
+        Ok(records.len())
     }
-}
 
-// ████████╗███████╗███████╗████████╗
-// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
-//    ██║   █████╗  ███████╗   ██║
-//    ██║   ██╔══╝  ╚════██║   ██║
-//    ██║   ███████╗███████║   ██║
-//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+    /// Writes every chunk of the working version, at all levels of detail, to `writer` in the [archive format](archive_file),
+    /// which can be streamed by a [`RemoteBackend`] or memory-mapped by a [`MappedArchive`]. Returns the number of chunks
+    /// written.
+    pub fn export_archive(&self, mut writer: impl Write) -> Result<usize, BackendError> {
+        // Index the chunks first, so the chunk bytes can be copied in a second pass without buffering them all.
+        let mut index = Vec::new();
+        let mut offset = 0;
+        for entry in self.working_tree.iter() {
+            let (key_bytes, value) = entry?;
+            let change = self.verified_record(&key_bytes, value)?;
+            if let Some(compressed) = change.as_ref().get_insert_data() {
+                let num_bytes = compressed.bytes.len() as u32;
+                let key = ChunkDbKey::from_sled_key(&key_bytes);
+                index.push((key, ArchiveEntry { offset, num_bytes }));
+                offset += u64::from(num_bytes);
+            }
+        }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::chunk::Chunk;
-    use crate::core::glam::IVec3;
+        write_archive_header(&mut writer, index.len() as u64)?;
+        for &(key, entry) in index.iter() {
+            write_archive_entry(&mut writer, key, entry)?;
+        }
+        for &(key, _) in index.iter() {
+            // The chunk could have been removed since it was indexed, and then the index would be wrong.
+            let change = self.read_working_version(key)?;
+            let chunk = change.as_ref().and_then(|c| c.as_ref().get_insert_data());
+            writer.write_all(&chunk.ok_or(ChunkReadError::Corrupt(key))?.bytes)?;
+        }
+        Ok(index.len())
+    }
 
-    #[test]
-    fn write_and_read_changes_same_version() {
-        let db = sled::Config::default().temporary(true).open().unwrap();
-        let mut map = MapDb::open(&db, "mymap").unwrap();
+    /// Reads a file written by [`MapDb::export_region`] and writes its chunks into LOD0 of the working version, translated so
+    /// the region's minimum lands on `destination`. Returns the extent of the imported region.
+    ///
+    /// Chunks that were empty in the exported region are left as is, so regions can be stamped like prefabs.
+    pub fn import_region(
+        &mut self,
+        mut reader: impl Read,
+        destination: ChunkUnits<IVec3>,
+    ) -> Result<ChunkUnits<Extent<IVec3>>, RegionFileError> {
+        let ChunkUnits(destination) = destination;
+        let header = read_region_header(&mut reader)?;
+        log::debug!(
+            "Importing {} chunks from region {:?} (format version {})",
+            header.num_records,
+            header.extent,
+            header.format_version
+        );
 
-        let chunk_key = ChunkDbKey::new(1, IVec3::ZERO.into());
         let mut encoder = ChangeEncoder::default();
-        encoder.add_compressed_change(chunk_key, Change::Insert(Chunk::default().compress()));
-        map.write_working_version(encoder.encode()).unwrap();
+        let mut batch_size = 0;
+        for _ in 0..header.num_records {
+            let (offset, bytes) = read_region_record(&mut reader)?;
+            let key = ChunkDbKey::new(0, (destination + offset).into());
+            encoder.add_compressed_change(key, Change::Insert(CompressedChunk { bytes }));
+            batch_size += 1;
+            if batch_size == BULK_WRITE_BATCH_SIZE {
+                self.write_working_version(std::mem::take(&mut encoder).encode())?;
+                batch_size = 0;
+            }
+        }
+        self.write_working_version(encoder.encode())?;
 
-        let chunk_compressed_bytes = map.read_working_version(chunk_key).unwrap().unwrap();
-        assert_eq!(
-            chunk_compressed_bytes.deserialize(),
-            Change::Insert(Chunk::default().compress())
-        );
+        Ok(ChunkUnits(Extent::from_min_and_shape(
+            destination,
+            header.extent.shape,
+        )))
     }
 
-    #[test]
+    /// Commits the working version, then writes every chunk that changed between `since` and the new parent version to
+    /// `writer` in the [delta format](delta_file). Without `since`, every chunk of the map is written, so the first backup
+    /// of a map has no base. Returns the version that the delta ends at, which should be passed as `since` for the next
+    /// backup.
+    ///
+    /// Each changed chunk is written once, in its latest state, no matter how many versions changed it. `since` can be any
+    /// committed version, even one on another branch. Fails with [`AbortReason::NoCommittedVersion`] if nothing was ever
+    /// committed.
+    ///
+    /// Chunks are written as they're stored, so the chunks of an encrypted map are never written in the clear.
+    pub fn export_changes_since(
+        &mut self,
+        since: Option<Version>,
+        mut writer: impl Write,
+    ) -> Result<Version, DeltaFileError> {
+        self.commit_working_version()?;
+        let head = self
+            .cached_meta
+            .parent_version
+            .ok_or(TransactionError::Abort(AbortReason::NoCommittedVersion))?;
+
+        let keys = if let Some(since) = since {
+            self.keys_changed_between(since, head)?
+        } else {
+            let mut keys = BTreeSet::default();
+            for key_bytes in self.working_tree.iter().keys() {
+                keys.insert(ChunkDbKey::from_sled_key(&key_bytes?));
+            }
+            keys
+        };
+        log::debug!(
+            "Exporting {} chunks changed from {:?} to {:?}",
+            keys.len(),
+            since,
+            head
+        );
+
+        write_delta_header(
+            &mut writer,
+            &DeltaHeader {
+                format_version: DELTA_FORMAT_VERSION,
+                base_version: since,
+                head_version: head,
+                num_records: keys.len() as u64,
+            },
+        )?;
+        for &key in keys.iter() {
+            let key_bytes = key.into_sled_key();
+            let record = self.working_tree.get(IVec::from(&key_bytes))?;
+            if let Some(record) = &record {
+                verify_record(&self.checksum_tree, &key_bytes, record)?;
+            }
+            let change = record.map(|r| unsafe { ArchivedChangeIVec::<CompressedChunk>::new(r) });
+            let bytes = change.as_ref().and_then(|c| c.as_ref().get_insert_data());
+            write_delta_record(&mut writer, key, bytes.map(|chunk| &*chunk.bytes))?;
+        }
+        Ok(head)
+    }
+
+    /// Reads a file written by [`MapDb::export_changes_since`] and writes its chunks into the working version. Returns the
+    /// version that the delta ends at.
+    ///
+    /// A backup is restored by applying the delta without a base to an empty map, and then every later delta in order. Fails
+    /// with [`DeltaFileError::WrongBase`] before writing anything if the delta doesn't start where the last applied delta
+    /// ended. Encrypted chunks fail with [`AbortReason::MissingEncryptionKey`] or [`AbortReason::WrongEncryptionKey`]
+    /// unless this map has the key that they were exported with. If applying fails partway through, the same delta can be
+    /// applied again.
+    pub fn apply_changes(&mut self, mut reader: impl Read) -> Result<Version, DeltaFileError> {
+        let header = read_delta_header(&mut reader)?;
+        if let Some(base) = header.base_version {
+            let expected = read_delta_head(&self.meta_tree)?;
+            if expected != Some(base) {
+                return Err(DeltaFileError::WrongBase {
+                    expected,
+                    found: Some(base),
+                });
+            }
+        }
+        log::debug!(
+            "Applying {} changes from {:?} to {:?} (format version {})",
+            header.num_records,
+            header.base_version,
+            header.head_version,
+            header.format_version
+        );
+
+        let mut encoder = ChangeEncoder::default();
+        let mut batch_size = 0;
+        for _ in 0..header.num_records {
+            let (key, bytes) = read_delta_record(&mut reader)?;
+            if let Some(bytes) = bytes.as_deref().filter(|bytes| is_encrypted(bytes)) {
+                let encryption = self
+                    .encryption
+                    .as_ref()
+                    .ok_or(TransactionError::Abort(AbortReason::MissingEncryptionKey))?;
+                if encryption
+                    .decrypt_chunk(&key.into_sled_key(), bytes)
+                    .is_none()
+                {
+                    return Err(TransactionError::Abort(AbortReason::WrongEncryptionKey).into());
+                }
+            }
+            let change = match bytes {
+                Some(bytes) => Change::Insert(CompressedChunk { bytes }),
+                None => Change::Remove,
+            };
+            encoder.add_compressed_change(key, change);
+            batch_size += 1;
+            if batch_size == BULK_WRITE_BATCH_SIZE {
+                self.write_working_version(std::mem::take(&mut encoder).encode())?;
+                batch_size = 0;
+            }
+        }
+        self.write_working_version(encoder.encode())?;
+        write_delta_head(&self.meta_tree, header.head_version)?;
+
+        Ok(header.head_version)
+    }
+
+    /// The keys of every chunk that differs between the committed versions `start` and `end`, which must be the parent
+    /// version.
+    fn keys_changed_between(
+        &self,
+        start: Version,
+        end: Version,
+    ) -> Result<BTreeSet<ChunkDbKey>, TransactionError<AbortReason>> {
+        let trees = (&self.version_graph_tree, &self.version_change_tree);
+        trees.transaction(|(graph_txn, change_txn)| {
+            let path = find_path_between_versions(graph_txn, start, end)?;
+            let mut keys = BTreeSet::default();
+            // Every version except the parent has archived the changes from its neighbor toward the parent, so the keys
+            // along the path are all of the keys that changed.
+            for &version in path.path[..path.path.len() - 1].iter() {
+                if let Some(changes) = read_archived_version(change_txn, version)? {
+                    for (key, _) in changes.as_ref().changes.iter() {
+                        keys.insert(key.deserialize(&mut Infallible).unwrap());
+                    }
+                } else {
+                    return abort(AbortReason::MissingVersionChanges);
+                }
+            }
+            Ok(keys)
+        })
+    }
+
+    /// Reads the compressed bytes of the chunk at `key` for the working version.
+    pub fn read_working_version(
+        &self,
+        key: ChunkDbKey,
+    ) -> Result<Option<ArchivedChangeIVec<CompressedChunk>>, ChunkReadError> {
+        let key_bytes = key.into_sled_key();
+        let bytes = self.working_tree.get(IVec::from(&key_bytes))?;
+        bytes
+            .map(|b| self.verified_record(&key_bytes, b))
+            .transpose()
+    }
+
+    /// Like [`Self::read_working_version`], but an inserted chunk is left in the record that was read from the tree, so its
+    /// bytes are never copied. The record is still verified against its checksum.
+    pub fn read_working_version_mapped(
+        &self,
+        key: ChunkDbKey,
+    ) -> Result<Option<Change<MappedChunk>>, ChunkReadError> {
+        Ok(self.read_working_version(key)?.map(|record| {
+            if record.as_ref().get_insert_data().is_some() {
+                Change::Insert(MappedChunk::new(record))
+            } else {
+                Change::Remove
+            }
+        }))
+    }
+
+    /// Checks every record of the working version against its checksum. Returns the keys of the corrupt records.
+    ///
+    /// Corrupt chunks can be repaired by writing new chunks over them, e.g. by editing them or importing a backup.
+    pub fn verify_all(&self) -> sled::Result<Vec<ChunkDbKey>> {
+        let mut corrupt = Vec::new();
+        for entry in self.working_tree.iter() {
+            let (key_bytes, value) = entry?;
+            match verify_record(&self.checksum_tree, &key_bytes, &value) {
+                Ok(()) => (),
+                Err(ChunkReadError::Corrupt(key)) => corrupt.push(key),
+                Err(ChunkReadError::Database(e)) => return Err(e),
+            }
+        }
+        if !corrupt.is_empty() {
+            log::error!("Found {} corrupt chunk records", corrupt.len());
+        }
+        Ok(corrupt)
+    }
+
+    fn verified_record(
+        &self,
+        key_bytes: &[u8],
+        record: IVec,
+    ) -> Result<ArchivedChangeIVec<CompressedChunk>, ChunkReadError> {
+        verify_record(&self.checksum_tree, key_bytes, &record)?;
+        self.decrypted_record(key_bytes, unsafe {
+            ArchivedChangeIVec::<CompressedChunk>::new(record)
+        })
+    }
+
+    /// Checksums cover the stored bytes, so records are decrypted after they're verified. Records that weren't encrypted are
+    /// returned as is, unless the map is encrypted and all of its existing chunks have been too, in which case they must
+    /// have been written by someone without the key.
+    fn decrypted_record(
+        &self,
+        key_bytes: &[u8],
+        record: ArchivedChangeIVec<CompressedChunk>,
+    ) -> Result<ArchivedChangeIVec<CompressedChunk>, ChunkReadError> {
+        let bytes = match record.as_ref().get_insert_data() {
+            Some(chunk) if is_encrypted(&chunk.bytes) => &chunk.bytes,
+            Some(_) if self.encryption.is_some() && !self.encrypting => {
+                return Err(ChunkReadError::Corrupt(ChunkDbKey::from_sled_key(
+                    key_bytes,
+                )));
+            }
+            _ => return Ok(record),
+        };
+        let chunk = self
+            .encryption
+            .as_ref()
+            .and_then(|encryption| encryption.decrypt_chunk(key_bytes, bytes))
+            .ok_or_else(|| ChunkReadError::Corrupt(ChunkDbKey::from_sled_key(key_bytes)))?;
+        let record = Change::Insert(chunk).serialize();
+        Ok(unsafe { ArchivedChangeIVec::new(IVec::from(record.as_ref())) })
+    }
+
+    fn encrypt_chunk(&self, key_bytes: &[u8], chunk: CompressedChunk) -> CompressedChunk {
+        match &self.encryption {
+            Some(encryption) => encryption.encrypt_chunk(key_bytes, chunk),
+            None => chunk,
+        }
+    }
+
+    /// Encrypts the record of an inserted chunk at `key_bytes`, unless it's already encrypted. Returns `None` if the record
+    /// doesn't need to change.
+    fn encrypt_record(
+        encryption: &ChunkEncryption,
+        key_bytes: &[u8],
+        record: &ArchivedChangeIVec<CompressedChunk>,
+    ) -> Option<ArchivedChangeIVec<CompressedChunk>> {
+        let chunk = record.as_ref().get_insert_data()?;
+        if is_encrypted(&chunk.bytes) {
+            return None;
+        }
+        let chunk = CompressedChunk {
+            bytes: chunk.bytes.to_vec().into_boxed_slice(),
+        };
+        let encrypted = Change::Insert(encryption.encrypt_chunk(key_bytes, chunk)).serialize();
+        Some(unsafe { ArchivedChangeIVec::new(IVec::from(encrypted.as_ref())) })
+    }
+
+    /// Encrypts every chunk record in the working tree and the sequence tree that's still in the clear, and then records
+    /// that the map is fully encrypted. If this is interrupted, it picks up where it left off the next time the map is
+    /// opened with its key.
+    ///
+    /// Records of older versions are encrypted when they're restored to the working tree.
+    fn encrypt_existing_chunks(&mut self) -> Result<(), TransactionError<AbortReason>> {
+        let encryption = self.encryption.as_ref().unwrap();
+        let write_batch = |records: Vec<(IVec, IVec)>| {
+            let mut batch = sled::Batch::default();
+            let mut checksums = sled::Batch::default();
+            for (key_bytes, record) in records.iter() {
+                checksums.insert(key_bytes.clone(), record_checksum(record).as_ref());
+                batch.insert(key_bytes.clone(), record.clone());
+            }
+            (&self.working_tree, &self.checksum_tree).transaction(
+                |(working_txn, checksum_txn)| {
+                    working_txn.apply_batch(&batch)?;
+                    checksum_txn.apply_batch(&checksums)?;
+                    Ok::<_, ConflictableTransactionError<AbortReason>>(())
+                },
+            )?;
+            Ok::<_, TransactionError<AbortReason>>(records.len())
+        };
+
+        let mut num_encrypted = 0;
+        let mut records = Vec::new();
+        for entry in self.working_tree.iter() {
+            let (key_bytes, value) = entry?;
+            if verify_record(&self.checksum_tree, &key_bytes, &value).is_err() {
+                // Left as is for the integrity check to report.
+                continue;
+            }
+            let record = unsafe { ArchivedChangeIVec::<CompressedChunk>::new(value) };
+            if let Some(encrypted) = Self::encrypt_record(encryption, &key_bytes, &record) {
+                records.push((key_bytes, encrypted.take_bytes()));
+            }
+            if records.len() == BULK_WRITE_BATCH_SIZE {
+                num_encrypted += write_batch(std::mem::take(&mut records))?;
+            }
+        }
+        num_encrypted += write_batch(records)?;
+
+        rewrite_records(&self.sequence_tree, |key_bytes, value| {
+            let record = unsafe { ArchivedChangeIVec::<CompressedChunk>::new(value.clone()) };
+            Self::encrypt_record(encryption, key_bytes, &record).map(|record| record.take_bytes())
+        })?;
+
+        clear_encryption_pending(&self.meta_tree)?;
+        self.encrypting = false;
+        log::info!("Encrypted {} existing chunks", num_encrypted);
+        Ok(())
+    }
+
+    /// Encrypts the inserted chunks of `changes`, if this map is encrypted.
+    fn encrypt_changes(
+        &self,
+        changes: EncodedChanges<CompressedChunk>,
+    ) -> EncodedChanges<CompressedChunk> {
+        let encryption = if let Some(encryption) = &self.encryption {
+            encryption
+        } else {
+            return changes;
+        };
+        let changes = changes
+            .changes
+            .into_iter()
+            .map(|(key_bytes, record)| {
+                let record =
+                    Self::encrypt_record(encryption, &key_bytes, &record).unwrap_or(record);
+                (key_bytes, record)
+            })
+            .collect();
+        EncodedChanges { changes }
+    }
+
+    /// Archives the backup tree entries into a [`VersionChanges`] that gets serialized and stored in the version change tree
+    /// with the current working [`Version`]. A new working version is generated and the old working version becomes the parent
+    /// version.
+    ///
+    /// Nothing happens if the working version has no changes.
+    pub fn commit_working_version(&mut self) -> Result<(), TransactionError<AbortReason>> {
+        if self.backup_key_cache.keys.is_empty() {
+            return Ok(());
+        }
+
+        log::trace!(
+            "Committing non-empty {:?}",
+            self.cached_meta.working_version
+        );
+
+        let new_meta = (
+            &self.backup_tree,
+            &self.version_graph_tree,
+            &self.version_change_tree,
+            &self.meta_tree,
+            &self.branch_tree,
+        )
+            .transaction(
+                |(backup_txn, graph_txn, changes_txn, meta_txn, branch_txn)| {
+                    if let Some(parent) = self.cached_meta.parent_version {
+                        log::trace!("Archiving {:?} from backup", parent);
+                        archive_version(
+                            changes_txn,
+                            parent,
+                            &commit_backup(backup_txn, &self.backup_key_cache)?,
+                        )?;
+                    } else {
+                        // We only need to do this once, but it's important for correctness.
+                        clear_backup(backup_txn, &self.backup_key_cache)?;
+                    }
+                    link_version(
+                        graph_txn,
+                        self.cached_meta.working_version,
+                        VersionNode {
+                            parent_version: self.cached_meta.parent_version,
+                        },
+                    )?;
+                    let new_meta = MapDbMetadata {
+                        grandparent_version: self.cached_meta.parent_version,
+                        parent_version: Some(self.cached_meta.working_version),
+                        working_version: Version::new(graph_txn.generate_id()?),
+                    };
+                    write_meta(meta_txn, &new_meta)?;
+                    if let Some(branch) = &self.cached_current_branch {
+                        write_branch_head(branch_txn, branch, self.cached_meta.working_version)?;
+                    }
+                    Ok(new_meta)
+                },
+            )?;
+        self.backup_key_cache.keys.clear();
+        self.cached_meta = new_meta;
+        Ok(())
+    }
+
+    /// Sets the parent version to `new_parent_version` and generates a new (empty) working child version.
+    ///
+    /// This will always `commit_working_version` before migrating to a new parent. If there is no parent for the current
+    /// working version, then nothing happens.
+    ///
+    /// If the working version is on a branch, the branch's head is moved to `new_parent_version`.
+    ///
+    /// Returns the keys of all chunks that changed in the working tree, so any cached copies can be refreshed.
+    pub fn branch_from_version(
+        &mut self,
+        new_parent_version: Version,
+    ) -> Result<BTreeSet<ChunkDbKey>, TransactionError<AbortReason>> {
+        // After committing, we may end up with a new empty working version. But it's not linked into the graph yet. We can just
+        // abandon it, since it is empty.
+        self.commit_working_version()?;
+
+        let old_meta = self.cached_meta;
+
+        let mut changed_keys = BTreeSet::default();
+        if let Some(old_parent_version) = old_meta.parent_version {
+            let (new_meta, new_changed_keys) = (
+                &self.meta_tree,
+                &self.version_graph_tree,
+                &self.version_change_tree,
+                &self.working_tree,
+                &self.checksum_tree,
+                &self.branch_tree,
+            )
+                .transaction(
+                    |(meta_txn, graph_txn, change_txn, working_txn, checksum_txn, branch_txn)| {
+                        // Apply the archived changes from all versions between the old parent version and the new parent
+                        // version, leaving behind the inverse changes.
+                        let path = find_path_between_versions(
+                            graph_txn,
+                            old_parent_version,
+                            new_parent_version,
+                        )?;
+                        let empty_backup_keys = BackupKeyCache {
+                            keys: BTreeSet::default(),
+                        };
+                        let mut changed_keys = BTreeSet::default();
+                        log::trace!(
+                            "Migrating from parent {:?} to parent {:?}",
+                            old_parent_version,
+                            new_parent_version
+                        );
+                        for (&prev_version, &next_version) in path.path.iter().tuple_windows() {
+                            if let Some(changes) =
+                                remove_archived_version(change_txn, next_version)?
+                            {
+                                let mut encoder = ChangeEncoder::default();
+                                for (key, change) in changes.as_ref().changes.iter() {
+                                    let key: ChunkDbKey = key.deserialize(&mut Infallible).unwrap();
+                                    changed_keys.insert(key);
+                                    // PERF: in principle we should be able to copy the compressed bytes directly from the
+                                    // archived change, but the types aren't set up for that yet
+                                    let change = change.deserialize(&mut Infallible).unwrap();
+                                    encoder.add_compressed_change(key, change);
+                                }
+                                // Versions archived before the map was encrypted hold chunks in the clear.
+                                let reverse_changes = write_changes_to_working_tree(
+                                    working_txn,
+                                    checksum_txn,
+                                    &empty_backup_keys,
+                                    self.encrypt_changes(encoder.encode()),
+                                )?;
+                                let prev_version_changes = VersionChanges::from(&reverse_changes);
+                                log::trace!("Archiving {:?} from working tree", prev_version,);
+                                archive_version(change_txn, prev_version, &prev_version_changes)?;
+                            } else {
+                                return abort(AbortReason::MissingVersionChanges);
+                            }
+                        }
+                        let new_working_version = Version::new(graph_txn.generate_id()?);
+                        let new_meta = MapDbMetadata {
+                            grandparent_version: path.end_parent,
+                            parent_version: Some(new_parent_version),
+                            working_version: new_working_version,
+                        };
+                        write_meta(meta_txn, &new_meta)?;
+                        if let Some(branch) = &self.cached_current_branch {
+                            write_branch_head(branch_txn, branch, new_parent_version)?;
+                        }
+                        Ok((new_meta, changed_keys))
+                    },
+                )?;
+            self.cached_meta = new_meta;
+            changed_keys = new_changed_keys;
+        }
+
+        Ok(changed_keys)
+    }
+
+    /// If the parent version has more than `max_versions` ancestors (including itself), merges the root version into its child,
+    /// which becomes the new root. The changes archived for the root version are deleted, so it can no longer be reverted to.
+    ///
+    /// Each call merges a single version, so callers sharing the database can interleave other reads and writes between
+    /// steps. The parent and grandparent versions are always kept, so `max_versions` is at least 2.
+    pub fn merge_oldest_version(
+        &mut self,
+        max_versions: usize,
+    ) -> Result<CompactionStep, TransactionError<AbortReason>> {
+        let max_versions = max_versions.max(2);
+        let parent_version = if let Some(parent) = self.cached_meta.parent_version {
+            parent
+        } else {
+            return Ok(CompactionStep::Done);
+        };
+        let ancestors = self
+            .version_graph_tree
+            .transaction(|graph_txn| find_path_to_root(graph_txn, parent_version))?;
+        if ancestors.len() <= max_versions {
+            return Ok(CompactionStep::Done);
+        }
+        let root = ancestors[ancestors.len() - 1];
+        let new_root = ancestors[ancestors.len() - 2];
+
+        // The graph only links children to parents, so we need to scan it for any other child of the root. Sled doesn't support
+        // transactional iteration, but nobody else can write while we hold `&mut self`.
+        for entry in self.version_graph_tree.iter() {
+            let (version_bytes, node_bytes) = entry?;
+            let node = unsafe { ArchivedIVec::<VersionNode>::new(node_bytes) }.deserialize();
+            let version = Version::from_sled_key(&version_bytes)?;
+            if node.parent_version == Some(root) && version != new_root {
+                return Ok(CompactionStep::Blocked);
+            }
+        }
+        if read_all_branch_heads(&self.branch_tree)?
+            .into_iter()
+            .any(|(_name, head)| head == root)
+        {
+            return Ok(CompactionStep::Blocked);
+        }
+
+        log::trace!("Merging root {:?} into {:?}", root, new_root);
+        (&self.version_graph_tree, &self.version_change_tree).transaction(
+            |(graph_txn, change_txn)| {
+                graph_txn.remove(&root.into_sled_key())?;
+                link_version(
+                    graph_txn,
+                    new_root,
+                    VersionNode {
+                        parent_version: None,
+                    },
+                )?;
+                remove_archived_version(change_txn, root)?;
+                Ok(())
+            },
+        )?;
+
+        Ok(CompactionStep::Merged {
+            excess_versions: ancestors.len() - 1 - max_versions,
+        })
+    }
+
+    /// The branch that the working version is on, if any.
+    pub fn current_branch(&self) -> Option<&str> {
+        self.cached_current_branch.as_deref()
+    }
+
+    /// All branches with their head versions, sorted by name.
+    pub fn branches(&self) -> sled::Result<Vec<(String, Version)>> {
+        read_all_branch_heads(&self.branch_tree)
+    }
+
+    /// Commits the working version, then creates a branch called `name` whose head is the new parent version.
+    ///
+    /// The working version stays on the current branch; use [`MapDb::switch_branch`] to continue on the new branch.
+    pub fn fork_branch(&mut self, name: &str) -> Result<(), TransactionError<AbortReason>> {
+        self.commit_working_version()?;
+
+        let head = if let Some(head) = self.cached_meta.parent_version {
+            head
+        } else {
+            return Err(TransactionError::Abort(AbortReason::NoCommittedVersion));
+        };
+        self.branch_tree.transaction(|branch_txn| {
+            if read_branch_head(branch_txn, name)?.is_some() {
+                return abort(AbortReason::BranchAlreadyExists);
+            }
+            write_branch_head(branch_txn, name, head)?;
+            Ok(())
+        })
+    }
+
+    /// Commits the working version, then branches from the head of the branch called `name` and makes it the current branch.
+    ///
+    /// Returns the keys of all chunks that changed in the working tree, like [`MapDb::branch_from_version`].
+    pub fn switch_branch(
+        &mut self,
+        name: &str,
+    ) -> Result<BTreeSet<ChunkDbKey>, TransactionError<AbortReason>> {
+        let head = if let Some(head) = self.branch_tree.get(name.as_bytes())? {
+            Version::from_sled_key(&head)?
+        } else {
+            return Err(TransactionError::Abort(AbortReason::MissingBranch));
+        };
+
+        // Commit on the old branch before leaving it.
+        self.commit_working_version()?;
+        self.set_current_branch(None)?;
+        let changed_keys = self.branch_from_version(head)?;
+        self.set_current_branch(Some(name))?;
+
+        Ok(changed_keys)
+    }
+
+    /// Leaves the current branch, so that new commits don't move any branch head.
+    pub fn detach_branch(&mut self) -> Result<(), TransactionError<AbortReason>> {
+        self.set_current_branch(None)
+    }
+
+    /// Deletes the branch called `name`. The versions on the branch are kept, since they might be shared with other branches.
+    ///
+    /// If the working version is on the deleted branch, it becomes detached.
+    pub fn delete_branch(&mut self, name: &str) -> Result<(), TransactionError<AbortReason>> {
+        let is_current = self.current_branch() == Some(name);
+        (&self.branch_tree, &self.meta_tree).transaction(|(branch_txn, meta_txn)| {
+            if remove_branch_head(branch_txn, name)?.is_none() {
+                return abort(AbortReason::MissingBranch);
+            }
+            if is_current {
+                write_current_branch(meta_txn, None)?;
+            }
+            Ok(())
+        })?;
+        if is_current {
+            self.cached_current_branch = None;
+        }
+        Ok(())
+    }
+
+    fn set_current_branch(
+        &mut self,
+        name: Option<&str>,
+    ) -> Result<(), TransactionError<AbortReason>> {
+        self.meta_tree.transaction(|meta_txn| {
+            write_current_branch(meta_txn, name)?;
+            Ok(())
+        })?;
+        self.cached_current_branch = name.map(ToOwned::to_owned);
+        Ok(())
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+/// Opens `map_name` in a temporary database, which is deleted when the map is dropped.
+#[cfg(test)]
+pub(crate) fn open_temporary_map(map_name: &str) -> MapDb {
+    let db = sled::Config::default().temporary(true).open().unwrap();
+    MapDb::open(&db, map_name).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::Chunk;
+    use crate::core::glam::IVec3;
+
+    #[test]
+    fn write_and_read_changes_same_version() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let mut map = MapDb::open(&db, "mymap").unwrap();
+
+        let chunk_key = ChunkDbKey::new(1, IVec3::ZERO.into());
+        let mut encoder = ChangeEncoder::default();
+        encoder.add_compressed_change(chunk_key, Change::Insert(Chunk::default().compress()));
+        map.write_working_version(encoder.encode()).unwrap();
+
+        let chunk_compressed_bytes = map.read_working_version(chunk_key).unwrap().unwrap();
+        assert_eq!(
+            chunk_compressed_bytes.deserialize(),
+            Change::Insert(Chunk::default().compress())
+        );
+    }
+
+    #[test]
+    fn mapped_read_has_the_written_bytes() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let mut map = MapDb::open(&db, "mymap").unwrap();
+
+        let chunk_key = ChunkDbKey::new(1, IVec3::ZERO.into());
+        let mut chunk = Chunk::default();
+        chunk.sdf[7] = crate::sdf::Sd8::MIN;
+        let mut encoder = ChangeEncoder::default();
+        encoder.add_compressed_change(chunk_key, Change::Insert(chunk.compress()));
+        map.write_working_version(encoder.encode()).unwrap();
+
+        let mapped = map.read_working_version_mapped(chunk_key).unwrap().unwrap();
+        assert_eq!(mapped.into_insert().unwrap().decompress(), chunk);
+        let missing = ChunkDbKey::new(1, IVec3::ONE.into());
+        assert!(map.read_working_version_mapped(missing).unwrap().is_none());
+    }
+
+    #[test]
+    fn appended_changes_are_ordered_per_chunk() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let mut map = MapDb::open(&db, "mymap").unwrap();
+        let version = map.cached_meta().working_version;
+        let brush_seq = map.next_sequence_number().unwrap();
+        let script_seq = map.next_sequence_number().unwrap();
+        assert!(script_seq > brush_seq);
+
+        let key = ChunkDbKey::new(0, IVec3::ZERO.into());
+        let other_key = ChunkDbKey::new(0, IVec3::ONE.into());
+        let changes = |key, chunk: &Chunk| {
+            let mut encoder = ChangeEncoder::default();
+            encoder.add_compressed_change(key, Change::Insert(chunk.compress()));
+            encoder.encode()
+        };
+        let mut script_chunk = Chunk::default();
+        script_chunk.sdf[0] = crate::sdf::Sd8::MIN;
+        map.append_changes(version, script_seq, changes(key, &script_chunk))
+            .unwrap();
+
+        // The brush started before the script wrote, so its change to the same chunk is stale.
+        assert_eq!(
+            map.append_changes(version, brush_seq, changes(key, &Chunk::default())),
+            Err(TransactionError::Abort(AbortReason::OutOfOrder {
+                key,
+                latest: script_seq
+            }))
+        );
+        map.append_changes(version, brush_seq, changes(other_key, &Chunk::default()))
+            .unwrap();
+        let read = map.read_working_version(key).unwrap().unwrap();
+        assert_eq!(
+            read.deserialize().into_insert().unwrap().decompress(),
+            script_chunk
+        );
+
+        assert!(map.read_at_or_before(key, brush_seq).unwrap().is_none());
+        let (seq, read) = map.read_at_or_before(key, script_seq).unwrap().unwrap();
+        assert_eq!(seq, script_seq);
+        assert_eq!(
+            read.deserialize().into_insert().unwrap().decompress(),
+            script_chunk
+        );
+
+        let later_seq = map.next_sequence_number().unwrap();
+        map.append_changes(version, later_seq, changes(key, &Chunk::default()))
+            .unwrap();
+        assert_eq!(map.prune_appended_changes(later_seq).unwrap(), 1);
+        assert!(map.read_at_or_before(key, script_seq).unwrap().is_none());
+        assert_eq!(
+            map.read_at_or_before(key, later_seq).unwrap().unwrap().0,
+            later_seq
+        );
+
+        map.commit_working_version().unwrap();
+        assert_eq!(
+            map.append_changes(version, later_seq + 1, changes(key, &Chunk::default())),
+            Err(TransactionError::Abort(AbortReason::NotWorkingVersion(
+                version
+            )))
+        );
+    }
+
+    #[test]
+    fn maps_in_one_db_are_separate() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let mut overworld = MapDb::open(&db, "overworld").unwrap();
+        let nether = MapDb::open(&db, "nether").unwrap();
+        assert_eq!(
+            MapDb::map_names(&db),
+            vec!["nether".to_owned(), "overworld".to_owned()]
+        );
+
+        let chunk_key = ChunkDbKey::new(0, IVec3::ZERO.into());
+        let mut encoder = ChangeEncoder::default();
+        encoder.add_compressed_change(chunk_key, Change::Insert(Chunk::default().compress()));
+        overworld.write_working_version(encoder.encode()).unwrap();
+
+        assert!(overworld.read_working_version(chunk_key).unwrap().is_some());
+        assert!(nether.read_working_version(chunk_key).unwrap().is_none());
+    }
+
+    #[test]
+    fn chunk_edge_is_recorded_when_the_map_is_created() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let map = MapDb::create(&db, "mymap", ChunkEdge::COMPILED).unwrap();
+        assert_eq!(map.chunk_edge(), ChunkEdge::COMPILED);
+        drop(map);
+        assert_eq!(
+            MapDb::open(&db, "mymap").unwrap().chunk_edge(),
+            ChunkEdge::COMPILED
+        );
+
+        let other_edge = if ChunkEdge::COMPILED == ChunkEdge::Edge64 {
+            ChunkEdge::Edge16
+        } else {
+            ChunkEdge::Edge64
+        };
+        assert!(matches!(
+            MapDb::create(&db, "other", other_edge),
+            Err(TransactionError::Abort(AbortReason::UnsupportedChunkEdge(length)))
+                if length == other_edge.length()
+        ));
+        assert!(!MapDb::map_names(&db).contains(&"other".to_owned()));
+
+        // A map created by a build with other chunks.
+        db.open_tree("other-meta")
+            .unwrap()
+            .insert("CHUNK_EDGE", other_edge.length().to_le_bytes().as_ref())
+            .unwrap();
+        assert!(matches!(
+            MapDb::open(&db, "other"),
+            Err(TransactionError::Abort(AbortReason::UnsupportedChunkEdge(length)))
+                if length == other_edge.length()
+        ));
+    }
+
+    #[test]
+    fn detect_corrupt_chunk_records() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let mut map = MapDb::open(&db, "mymap").unwrap();
+
+        let intact_key = ChunkDbKey::new(0, IVec3::ZERO.into());
+        let corrupt_key = ChunkDbKey::new(0, IVec3::ONE.into());
+        let mut encoder = ChangeEncoder::default();
+        encoder.add_compressed_change(intact_key, Change::Insert(Chunk::default().compress()));
+        encoder.add_compressed_change(corrupt_key, Change::Insert(Chunk::default().compress()));
+        map.write_working_version(encoder.encode()).unwrap();
+
+        // Flip a bit behind the database's back.
+        let key_bytes = corrupt_key.into_sled_key();
+        let mut record = map.working_tree.get(key_bytes).unwrap().unwrap().to_vec();
+        record[0] ^= 1;
+        map.working_tree.insert(key_bytes, record).unwrap();
+
+        assert!(map.read_working_version(intact_key).unwrap().is_some());
+        assert_eq!(
+            map.read_working_version(corrupt_key),
+            Err(ChunkReadError::Corrupt(corrupt_key))
+        );
+        assert_eq!(map.verify_all().unwrap(), vec![corrupt_key]);
+
+        // Overwriting the corrupt record repairs it, and the corrupt value isn't backed up.
+        map.commit_working_version().unwrap();
+        let mut encoder = ChangeEncoder::default();
+        encoder.add_compressed_change(corrupt_key, Change::Insert(Chunk::default().compress()));
+        map.write_working_version(encoder.encode()).unwrap();
+        assert!(map.verify_all().unwrap().is_empty());
+        assert!(map.read_working_version(corrupt_key).unwrap().is_some());
+    }
+
+    #[test]
+    fn read_chunks_written_with_different_codecs() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let mut map = MapDb::open(&db, "mymap").unwrap();
+        assert_eq!(map.codec(), CompressionCodec::default());
+
+        let mut chunk = Chunk::default();
+        chunk.set_voxel(IVec3::new(1, 2, 3), 7, crate::sdf::Sd8::MIN);
+        let lz4_key = ChunkDbKey::new(0, IVec3::ZERO.into());
+        let mut encoder = ChangeEncoder::default();
+        encoder.add_compressed_change(lz4_key, Change::Insert(chunk.compress_with(map.codec())));
+        map.write_working_version(encoder.encode()).unwrap();
+
+        let zstd = CompressionCodec::Zstd { level: 3 };
+        map.set_codec(zstd).unwrap();
+        let zstd_key = ChunkDbKey::new(0, IVec3::ONE.into());
+        let mut encoder = ChangeEncoder::default();
+        encoder.add_compressed_change(zstd_key, Change::Insert(chunk.compress_with(map.codec())));
+        map.write_working_version(encoder.encode()).unwrap();
+
+        // The codec is kept in the header.
+        drop(map);
+        let map = MapDb::open(&db, "mymap").unwrap();
+        assert_eq!(map.codec(), zstd);
+
+        for key in [lz4_key, zstd_key] {
+            let change = map.read_working_version(key).unwrap().unwrap();
+            let decompressed = change.as_ref().get_insert_data().unwrap().decompress();
+            assert_eq!(decompressed, chunk);
+        }
+    }
+
+    #[test]
     fn commit_empty_working_version_does_nothing() {
         let db = sled::Config::default().temporary(true).open().unwrap();
         let mut map = MapDb::open(&db, "mymap").unwrap();
@@ -367,6 +1867,324 @@ mod tests {
         );
     }
 
+    #[test]
+    fn fork_and_switch_named_branches() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let mut map = MapDb::open(&db, "mymap").unwrap();
+        assert_eq!(
+            map.fork_branch("main"),
+            Err(TransactionError::Abort(AbortReason::NoCommittedVersion))
+        );
+
+        let chunk_key = ChunkDbKey::new(1, IVec3::ZERO.into());
+        let mut encoder = ChangeEncoder::default();
+        encoder.add_compressed_change(chunk_key, Change::Insert(Chunk::default().compress()));
+        map.write_working_version(encoder.encode()).unwrap();
+        map.fork_branch("main").unwrap();
+        map.fork_branch("experiment").unwrap();
+        assert_eq!(
+            map.fork_branch("experiment"),
+            Err(TransactionError::Abort(AbortReason::BranchAlreadyExists))
+        );
+
+        // Make a destructive change on the experimental branch.
+        map.switch_branch("experiment").unwrap();
+        let mut encoder = ChangeEncoder::default();
+        encoder.add_compressed_change(chunk_key, Change::Remove);
+        map.write_working_version(encoder.encode()).unwrap();
+
+        // Switching back commits the change on the experiment, then restores the chunk.
+        let changed_keys = map.switch_branch("main").unwrap();
+        assert!(changed_keys.contains(&chunk_key));
+        assert!(map.read_working_version(chunk_key).unwrap().is_some());
+        assert_eq!(map.current_branch(), Some("main"));
+
+        // The experiment's head moved past the fork.
+        let branches = map.branches().unwrap();
+        assert_eq!(branches.len(), 2);
+        assert_ne!(branches[0].1, branches[1].1);
+
+        map.switch_branch("experiment").unwrap();
+        assert_eq!(map.read_working_version(chunk_key).unwrap(), None);
+
+        // The current branch is persisted.
+        drop(map);
+        let mut map = MapDb::open(&db, "mymap").unwrap();
+        assert_eq!(map.current_branch(), Some("experiment"));
+
+        map.delete_branch("experiment").unwrap();
+        assert_eq!(map.current_branch(), None);
+        assert_eq!(
+            map.switch_branch("experiment"),
+            Err(TransactionError::Abort(AbortReason::MissingBranch))
+        );
+    }
+
+    #[test]
+    fn merge_oldest_versions_down_to_limit() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let mut map = MapDb::open(&db, "mymap").unwrap();
+
+        let chunk_key = ChunkDbKey::new(1, IVec3::ZERO.into());
+        let mut versions = Vec::new();
+        for i in 0..4 {
+            let mut chunk = Chunk::default();
+            chunk.palette_ids[0] = i;
+            let mut encoder = ChangeEncoder::default();
+            encoder.add_compressed_change(chunk_key, Change::Insert(chunk.compress()));
+            map.write_working_version(encoder.encode()).unwrap();
+            versions.push(map.cached_meta().working_version);
+            map.commit_working_version().unwrap();
+        }
+
+        assert_eq!(
+            map.merge_oldest_version(2),
+            Ok(CompactionStep::Merged { excess_versions: 1 })
+        );
+        assert_eq!(
+            map.merge_oldest_version(2),
+            Ok(CompactionStep::Merged { excess_versions: 0 })
+        );
+        assert_eq!(map.merge_oldest_version(2), Ok(CompactionStep::Done));
+
+        // The merged versions are gone, but the remaining history still works.
+        assert!(map.branch_from_version(versions[0]).is_err());
+        map.branch_from_version(versions[2]).unwrap();
+        let change = map.read_working_version(chunk_key).unwrap().unwrap();
+        let chunk = change.as_ref().get_insert_data().unwrap().decompress();
+        assert_eq!(chunk.palette_ids[0], 2);
+    }
+
+    #[test]
+    fn merge_is_blocked_by_sibling_branch() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let mut map = MapDb::open(&db, "mymap").unwrap();
+
+        let chunk_key = ChunkDbKey::new(1, IVec3::ZERO.into());
+        let write = |map: &mut MapDb| {
+            let mut encoder = ChangeEncoder::default();
+            encoder.add_compressed_change(chunk_key, Change::Insert(Chunk::default().compress()));
+            map.write_working_version(encoder.encode()).unwrap();
+            map.commit_working_version().unwrap();
+        };
+        write(&mut map);
+        let v0 = map.cached_meta().parent_version.unwrap();
+        write(&mut map);
+
+        // Fork a sibling of the second version.
+        map.branch_from_version(v0).unwrap();
+        write(&mut map);
+        write(&mut map);
+
+        assert_eq!(map.merge_oldest_version(2), Ok(CompactionStep::Blocked));
+    }
+
+    #[test]
+    fn bulk_write_many_batches() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let mut map = MapDb::open(&db, "mymap").unwrap();
+
+        let num_chunks = BULK_WRITE_BATCH_SIZE + 10;
+        let chunks = (0..num_chunks as i32).map(|x| {
+            (
+                NodeKey::new(0, IVec3::new(x, 0, 0)),
+                Chunk::default().compress(),
+            )
+        });
+        assert_eq!(map.bulk_write_chunks(chunks).unwrap(), num_chunks);
+
+        let last_key = ChunkDbKey::new(0, IVec3::new(num_chunks as i32 - 1, 0, 0).into());
+        let change = map.read_working_version(last_key).unwrap().unwrap();
+        assert_eq!(
+            change.as_ref().get_insert_data().unwrap().decompress(),
+            Chunk::default()
+        );
+
+        // Nothing was backed up, so there is nothing to commit.
+        map.commit_working_version().unwrap();
+        assert_eq!(map.cached_meta().parent_version, None);
+    }
+
+    #[test]
+    fn export_and_import_region_at_offset() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let mut src_map = MapDb::open(&db, "src").unwrap();
+        let mut dst_map = MapDb::open(&db, "dst").unwrap();
+
+        let mut chunk = Chunk::default();
+        chunk.palette_ids[0] = 7;
+        let mut encoder = ChangeEncoder::default();
+        for p in [
+            IVec3::new(1, 1, 1),
+            IVec3::new(2, 1, 1),
+            IVec3::new(5, 5, 5),
+        ] {
+            let key = ChunkDbKey::new(0, p.into());
+            encoder.add_compressed_change(key, Change::Insert(chunk.compress()));
+        }
+        src_map.write_working_version(encoder.encode()).unwrap();
+
+        // The last chunk is outside of the extent.
+        let extent = Extent::from_min_and_shape(IVec3::ONE, IVec3::splat(2));
+        let mut file = Vec::new();
+        assert_eq!(
+            src_map
+                .export_region(ChunkUnits(extent), &mut file)
+                .unwrap(),
+            2
+        );
+
+        let destination = IVec3::new(-10, 0, 3);
+        let ChunkUnits(imported) = dst_map
+            .import_region(file.as_slice(), ChunkUnits(destination))
+            .unwrap();
+        assert_eq!(
+            imported,
+            Extent::from_min_and_shape(destination, IVec3::splat(2))
+        );
+
+        let read = |p: IVec3| {
+            dst_map
+                .read_working_version(ChunkDbKey::new(0, p.into()))
+                .unwrap()
+        };
+        for p in [destination, destination + IVec3::X] {
+            let change = read(p).unwrap();
+            assert_eq!(
+                change.as_ref().get_insert_data().unwrap().decompress(),
+                chunk
+            );
+        }
+        assert_eq!(read(destination + IVec3::splat(4)), None);
+    }
+
+    #[test]
+    fn export_region_in_canonical_form() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let mut map = MapDb::open(&db, "mymap").unwrap();
+
+        let mut chunk = Chunk::default();
+        chunk.set_voxel(IVec3::new(1, 2, 3), 4, crate::sdf::Sd8::MIN);
+        let encoding = crate::chunk::ChunkEncoding::Paletted;
+        let compressed = chunk.compress_as(encoding, CompressionCodec::Zstd { level: 3 });
+        let mut encoder = ChangeEncoder::default();
+        let key = ChunkDbKey::new(0, IVec3::ZERO.into());
+        encoder.add_compressed_change(key, Change::Insert(compressed));
+        map.write_working_version(encoder.encode()).unwrap();
+
+        let mut file = Vec::new();
+        let extent = Extent::from_min_and_shape(IVec3::ZERO, IVec3::ONE);
+        assert_eq!(map.export_region(ChunkUnits(extent), &mut file).unwrap(), 1);
+        let mut reader = file.as_slice();
+        read_region_header(&mut reader).unwrap();
+        let (_, bytes) = read_region_record(&mut reader).unwrap();
+        assert_eq!(bytes, chunk.compress().bytes);
+
+        // Lengths that no chunk could have are rejected before they're allocated.
+        let mut file = Vec::new();
+        write_region_record(&mut file, IVec3::ZERO, &[]).unwrap();
+        file[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(read_region_record(file.as_slice()).is_err());
+    }
+
+    #[test]
+    fn backup_and_restore_incremental_changes() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let mut src_map = MapDb::open(&db, "src").unwrap();
+        let mut dst_map = MapDb::open(&db, "dst").unwrap();
+
+        let mut chunk = Chunk::default();
+        chunk.palette_ids[0] = 7;
+        let keys = [1, 2, 3].map(|x| ChunkDbKey::new(0, IVec3::new(x, 0, 0).into()));
+        let write = |map: &mut MapDb, changes: &[(ChunkDbKey, Change<CompressedChunk>)]| {
+            let mut encoder = ChangeEncoder::default();
+            for (key, change) in changes.iter() {
+                encoder.add_compressed_change(*key, change.clone());
+            }
+            map.write_working_version(encoder.encode()).unwrap();
+        };
+        let num_records = |file: &[u8]| read_delta_header(file).unwrap().num_records;
+
+        let insert = Change::Insert(chunk.compress());
+        write(
+            &mut src_map,
+            &[(keys[0], insert.clone()), (keys[1], insert.clone())],
+        );
+        let mut full = Vec::new();
+        let v1 = src_map.export_changes_since(None, &mut full).unwrap();
+        assert_eq!(num_records(&full), 2);
+
+        // Only the chunks written and removed since the last backup are exported, even across several versions.
+        write(&mut src_map, &[(keys[2], insert)]);
+        src_map.commit_working_version().unwrap();
+        write(&mut src_map, &[(keys[0], Change::Remove)]);
+        let mut delta = Vec::new();
+        let v2 = src_map.export_changes_since(Some(v1), &mut delta).unwrap();
+        assert_eq!(num_records(&delta), 2);
+        let mut empty_delta = Vec::new();
+        assert_eq!(
+            src_map
+                .export_changes_since(Some(v2), &mut empty_delta)
+                .unwrap(),
+            v2
+        );
+        assert_eq!(num_records(&empty_delta), 0);
+
+        // Deltas must be applied in order.
+        assert!(matches!(
+            dst_map.apply_changes(delta.as_slice()),
+            Err(DeltaFileError::WrongBase {
+                expected: None,
+                found: Some(v)
+            }) if v == v1
+        ));
+        assert_eq!(dst_map.apply_changes(full.as_slice()).unwrap(), v1);
+        assert_eq!(dst_map.apply_changes(delta.as_slice()).unwrap(), v2);
+        assert!(dst_map.apply_changes(delta.as_slice()).is_err());
+
+        assert_eq!(dst_map.read_working_version(keys[0]).unwrap(), None);
+        for &key in keys[1..].iter() {
+            let change = dst_map.read_working_version(key).unwrap().unwrap();
+            assert_eq!(
+                change.as_ref().get_insert_data().unwrap().decompress(),
+                chunk
+            );
+        }
+    }
+
+    #[test]
+    fn import_rejects_other_files() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let mut map = MapDb::open(&db, "mymap").unwrap();
+
+        let file = b"not a region file";
+        let result = map.import_region(file.as_ref(), ChunkUnits(IVec3::ZERO));
+        assert!(matches!(result, Err(RegionFileError::BadMagic)));
+    }
+
+    #[test]
+    fn branch_reports_changed_keys() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let mut map = MapDb::open(&db, "mymap").unwrap();
+
+        let v0 = map.cached_meta().working_version;
+        let chunk_key = ChunkDbKey::new(1, IVec3::ZERO.into());
+        let mut encoder = ChangeEncoder::default();
+        encoder.add_compressed_change(chunk_key, Change::Insert(Chunk::default().compress()));
+        map.write_working_version(encoder.encode()).unwrap();
+        map.commit_working_version().unwrap();
+
+        let untouched_key = ChunkDbKey::new(2, IVec3::ZERO.into());
+        let mut encoder = ChangeEncoder::default();
+        encoder.add_compressed_change(chunk_key, Change::Remove);
+        map.write_working_version(encoder.encode()).unwrap();
+        map.commit_working_version().unwrap();
+
+        let changed_keys = map.branch_from_version(v0).unwrap();
+        assert!(changed_keys.contains(&chunk_key));
+        assert!(!changed_keys.contains(&untouched_key));
+    }
+
     #[test]
     fn commit_multiple_versions_with_changes_and_branch() {
         let db = sled::Config::default().temporary(true).open().unwrap();
@@ -0,0 +1,98 @@
+//! A read-only file format for every chunk of a map, written by [`MapDb::export_archive`](crate::database::MapDb) and read by
+//! the [`RemoteBackend`](crate::database::RemoteBackend) or a [`MappedArchive`](crate::database::MappedArchive).
+//!
+//! The index comes first so a reader can find any chunk after fetching the header and index, then fetch each chunk with one
+//! range request. All integers are little-endian.
+//!
+//! ```text
+//! magic:          [u8; 8] = "FELDARC\0"
+//! format_version: u32
+//! num_entries:    u64
+//! index: [
+//!     key:        [u8; 13]  // ChunkDbKey::into_sled_key, sorted
+//!     offset:     u64       // relative to the end of the index
+//!     num_bytes:  u32
+//! ]
+//! data:           [u8]      // the compressed chunks, as in CompressedChunk
+//! ```
+//!
+//! Removed chunks have no entry.
+
+use super::{BackendError, ChunkDbKey};
+use crate::core::SmallKeyHashMap;
+
+use std::io::{self, Write};
+
+const MAGIC: [u8; 8] = *b"FELDARC\0";
+
+/// The version in the header of new archives. [`read_archive_header`] fails with [`BackendError::UnsupportedVersion`] for
+/// archives from newer versions, whose index might not be laid out the same way.
+pub const ARCHIVE_FORMAT_VERSION: u32 = 1;
+
+/// The number of bytes before the index.
+pub const HEADER_BYTES: u64 = 8 + 4 + 8;
+/// The number of bytes of each index entry.
+pub const ENTRY_BYTES: u64 = 13 + 8 + 4;
+
+/// The location of a chunk's bytes, relative to the end of the index.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ArchiveEntry {
+    pub offset: u64,
+    pub num_bytes: u32,
+}
+
+pub fn write_archive_header(mut writer: impl Write, num_entries: u64) -> io::Result<()> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&ARCHIVE_FORMAT_VERSION.to_le_bytes())?;
+    writer.write_all(&num_entries.to_le_bytes())
+}
+
+/// Returns the number of index entries.
+pub fn read_archive_header(bytes: &[u8]) -> Result<u64, BackendError> {
+    if bytes.len() < HEADER_BYTES as usize || bytes[..8] != MAGIC {
+        return Err(BackendError::BadArchive);
+    }
+    let format_version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+    if format_version > ARCHIVE_FORMAT_VERSION {
+        return Err(BackendError::UnsupportedVersion(format_version));
+    }
+    Ok(u64::from_le_bytes(bytes[12..20].try_into().unwrap()))
+}
+
+/// The offset of the data in an archive of `archive_bytes` with `num_entries` index entries. Fails if the index wouldn't
+/// fit, so a corrupt header can't make a reader allocate more than the archive.
+pub fn archive_data_start(num_entries: u64, archive_bytes: u64) -> Result<u64, BackendError> {
+    num_entries
+        .checked_mul(ENTRY_BYTES)
+        .and_then(|index_bytes| index_bytes.checked_add(HEADER_BYTES))
+        .filter(|&data_start| data_start <= archive_bytes)
+        .ok_or(BackendError::BadArchive)
+}
+
+pub fn write_archive_entry(
+    mut writer: impl Write,
+    key: ChunkDbKey,
+    entry: ArchiveEntry,
+) -> io::Result<()> {
+    writer.write_all(&key.into_sled_key())?;
+    writer.write_all(&entry.offset.to_le_bytes())?;
+    writer.write_all(&entry.num_bytes.to_le_bytes())
+}
+
+pub fn read_archive_index(
+    bytes: &[u8],
+    num_entries: u64,
+) -> Result<SmallKeyHashMap<ChunkDbKey, ArchiveEntry>, BackendError> {
+    if num_entries.checked_mul(ENTRY_BYTES) != Some(bytes.len() as u64) {
+        return Err(BackendError::BadArchive);
+    }
+    Ok(bytes
+        .chunks_exact(ENTRY_BYTES as usize)
+        .map(|entry| {
+            let key = ChunkDbKey::from_sled_key(&entry[..13]);
+            let offset = u64::from_le_bytes(entry[13..21].try_into().unwrap());
+            let num_bytes = u32::from_le_bytes(entry[21..25].try_into().unwrap());
+            (key, ArchiveEntry { offset, num_bytes })
+        })
+        .collect())
+}
@@ -0,0 +1,133 @@
+use super::{Change, ChangeEncoder, ChunkDbKey, ChunkReadError, MapDb};
+use crate::chunk::{CompressedChunk, CompressionCodec, MappedChunk};
+
+use parking_lot::RwLock;
+use sled::transaction::TransactionError;
+use std::io;
+
+#[derive(Debug)]
+pub enum BackendError {
+    Io(io::Error),
+    Database(TransactionError),
+    /// An archive doesn't start with the magic bytes, or its index doesn't match its size.
+    BadArchive,
+    /// An archive was written by a newer version of the format.
+    UnsupportedVersion(u32),
+    /// The stored chunk doesn't match its checksum, or a chunk that was fetched from an archive can't be decompressed.
+    Corrupt(ChunkDbKey),
+}
+
+impl From<io::Error> for BackendError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<sled::Error> for BackendError {
+    fn from(e: sled::Error) -> Self {
+        Self::Database(e.into())
+    }
+}
+
+impl From<ChunkReadError> for BackendError {
+    fn from(e: ChunkReadError) -> Self {
+        match e {
+            ChunkReadError::Database(e) => e.into(),
+            ChunkReadError::Corrupt(key) => Self::Corrupt(key),
+        }
+    }
+}
+
+impl From<TransactionError> for BackendError {
+    fn from(e: TransactionError) -> Self {
+        Self::Database(e)
+    }
+}
+
+/// Where the working version of each chunk is read from and written to by the loader, the saver, and edit flushes.
+///
+/// [`MapDb`] is the default backend. Others only need to store chunks, since history, imports, and compaction always operate
+/// on a [`MapDb`].
+pub trait MapBackend: Send + Sync {
+    /// The codec that chunks should be compressed with before they're written.
+    fn codec(&self) -> CompressionCodec;
+
+    /// Reads the chunk at `key`. Returns `None` if the backend has no entry for it.
+    fn read_chunk(&self, key: ChunkDbKey) -> Result<Option<Change<CompressedChunk>>, BackendError>;
+
+    /// Reads the chunks at all of `keys`, in the same order. Backends with high latency per read, e.g. over a network,
+    /// should override this to fetch the whole batch at once.
+    fn read_chunks(
+        &self,
+        keys: &[ChunkDbKey],
+    ) -> Result<Vec<Option<Change<CompressedChunk>>>, BackendError> {
+        keys.iter().map(|&key| self.read_chunk(key)).collect()
+    }
+
+    /// Like [`Self::read_chunk`], but the chunk may stay in the buffer it was read into. By default, this just wraps the
+    /// owned chunk, so only backends that can avoid the copy need to override it.
+    fn read_chunk_mapped(
+        &self,
+        key: ChunkDbKey,
+    ) -> Result<Option<Change<MappedChunk>>, BackendError> {
+        Ok(self.read_chunk(key)?.map(|c| c.map(MappedChunk::from)))
+    }
+
+    /// Like [`Self::read_chunks`], but the chunks may stay in the buffers they were read into.
+    fn read_chunks_mapped(
+        &self,
+        keys: &[ChunkDbKey],
+    ) -> Result<Vec<Option<Change<MappedChunk>>>, BackendError> {
+        Ok(self
+            .read_chunks(keys)?
+            .into_iter()
+            .map(|c| c.map(|c| c.map(MappedChunk::from)))
+            .collect())
+    }
+
+    /// Writes all of `changes` atomically.
+    fn write_chunks(
+        &self,
+        changes: Vec<(ChunkDbKey, Change<CompressedChunk>)>,
+    ) -> Result<(), BackendError>;
+}
+
+impl MapBackend for RwLock<MapDb> {
+    fn codec(&self) -> CompressionCodec {
+        self.read().codec()
+    }
+
+    fn read_chunk(&self, key: ChunkDbKey) -> Result<Option<Change<CompressedChunk>>, BackendError> {
+        let change = self.read().read_working_version(key)?;
+        Ok(change.map(|c| c.deserialize()))
+    }
+
+    fn read_chunk_mapped(
+        &self,
+        key: ChunkDbKey,
+    ) -> Result<Option<Change<MappedChunk>>, BackendError> {
+        Ok(self.read().read_working_version_mapped(key)?)
+    }
+
+    fn read_chunks_mapped(
+        &self,
+        keys: &[ChunkDbKey],
+    ) -> Result<Vec<Option<Change<MappedChunk>>>, BackendError> {
+        let db = self.read();
+        keys.iter()
+            .map(|&key| Ok(db.read_working_version_mapped(key)?))
+            .collect()
+    }
+
+    fn write_chunks(
+        &self,
+        changes: Vec<(ChunkDbKey, Change<CompressedChunk>)>,
+    ) -> Result<(), BackendError> {
+        let mut encoder = ChangeEncoder::default();
+        for (key, change) in changes.into_iter() {
+            encoder.add_compressed_change(key, change);
+        }
+        self.write().write_working_version(encoder.encode())?;
+        Ok(())
+    }
+}
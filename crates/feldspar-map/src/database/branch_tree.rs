@@ -0,0 +1,108 @@
+use super::{corrupt_value, Version};
+
+use sled::{
+    transaction::{TransactionalTree, UnabortableTransactionError},
+    Tree,
+};
+
+/// Maps each branch name to its head, the most recent version committed on that branch.
+pub fn open_branch_tree(map_name: &str, db: &sled::Db) -> sled::Result<Tree> {
+    db.open_tree(format!("{}-branches", map_name))
+}
+
+pub fn write_branch_head(
+    txn: &TransactionalTree,
+    name: &str,
+    head: Version,
+) -> Result<(), UnabortableTransactionError> {
+    txn.insert(name.as_bytes(), &head.into_sled_key())?;
+    Ok(())
+}
+
+pub fn read_branch_head(
+    txn: &TransactionalTree,
+    name: &str,
+) -> Result<Option<Version>, UnabortableTransactionError> {
+    Ok(txn
+        .get(name.as_bytes())?
+        .map(|b| Version::from_sled_key(&b))
+        .transpose()?)
+}
+
+pub fn remove_branch_head(
+    txn: &TransactionalTree,
+    name: &str,
+) -> Result<Option<Version>, UnabortableTransactionError> {
+    Ok(txn
+        .remove(name.as_bytes())?
+        .map(|b| Version::from_sled_key(&b))
+        .transpose()?)
+}
+
+pub fn read_all_branch_heads(tree: &Tree) -> sled::Result<Vec<(String, Version)>> {
+    tree.iter()
+        .map(|entry| {
+            let (name, head) = entry?;
+            let name =
+                String::from_utf8(name.to_vec()).map_err(|_| corrupt_value("branch name"))?;
+            Ok((name, Version::from_sled_key(&head)?))
+        })
+        .collect()
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sled::transaction::TransactionError;
+
+    #[test]
+    fn write_read_and_remove_branch_heads() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let tree = open_branch_tree("mymap", &db).unwrap();
+
+        let _: Result<(), TransactionError<()>> = tree.transaction(|txn| {
+            write_branch_head(txn, "autosave", Version::new(3))?;
+            write_branch_head(txn, "experiment", Version::new(7))?;
+            assert_eq!(read_branch_head(txn, "autosave")?, Some(Version::new(3)));
+            Ok(())
+        });
+        assert_eq!(
+            read_all_branch_heads(&tree).unwrap(),
+            vec![
+                ("autosave".to_owned(), Version::new(3)),
+                ("experiment".to_owned(), Version::new(7)),
+            ]
+        );
+
+        let _: Result<(), TransactionError<()>> = tree.transaction(|txn| {
+            assert_eq!(remove_branch_head(txn, "autosave")?, Some(Version::new(3)));
+            assert_eq!(read_branch_head(txn, "autosave")?, None);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn corrupt_branch_heads_are_errors() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let tree = open_branch_tree("mymap", &db).unwrap();
+
+        tree.insert("short", &[1, 2, 3][..]).unwrap();
+        assert!(read_all_branch_heads(&tree).is_err());
+        let result: Result<_, TransactionError<()>> =
+            tree.transaction(|txn| Ok(read_branch_head(txn, "short")?));
+        assert!(result.is_err());
+
+        tree.clear().unwrap();
+        tree.insert(&[0xFF, 0xFE][..], &Version::new(1).into_sled_key())
+            .unwrap();
+        assert!(read_all_branch_heads(&tree).is_err());
+    }
+}
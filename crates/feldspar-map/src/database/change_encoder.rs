@@ -1,5 +1,5 @@
 use super::{ArchivedIVec, ChunkDbKey};
-use crate::chunk::CompressedChunk;
+use crate::chunk::{CompressedBytes, CompressedChunk};
 use crate::core::rkyv::{
     ser::{serializers::CoreSerializer, Serializer},
     AlignedBytes, AlignedVec, Archive, Archived, Deserialize, Serialize,
@@ -16,10 +16,11 @@ pub enum Change<T> {
 }
 
 impl<T> Change<T> {
-    pub fn unwrap_insert(self) -> T {
+    /// The inserted value, or `None` for a [`Change::Remove`].
+    pub fn into_insert(self) -> Option<T> {
         match self {
-            Change::Insert(x) => x,
-            Change::Remove => panic!("Unwrapped on Change::Remove"),
+            Change::Insert(x) => Some(x),
+            Change::Remove => None,
         }
     }
 
@@ -119,6 +120,16 @@ pub struct EncodedChanges<T> {
 /// tree to the backup tree.
 pub type ArchivedChangeIVec<T> = ArchivedIVec<Change<T>>;
 
+/// Lets a working tree record be kept as a [`MappedChunk`](crate::chunk::MappedChunk). A [`Change::Remove`] has no bytes.
+impl CompressedBytes for ArchivedChangeIVec<CompressedChunk> {
+    fn compressed_bytes(&self) -> &[u8] {
+        match self.as_ref() {
+            ArchivedChange::Insert(chunk) => &*chunk.bytes,
+            ArchivedChange::Remove => &[],
+        }
+    }
+}
+
 // ████████╗███████╗███████╗████████╗
 // ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
 //    ██║   █████╗  ███████╗   ██║
@@ -0,0 +1,49 @@
+use super::ChunkDbKey;
+
+use sled::Tree;
+use xxhash_rust::xxh3::xxh3_64;
+
+/// The error from reading a chunk of a [`MapDb`](super::MapDb).
+#[derive(Debug, PartialEq)]
+pub enum ChunkReadError {
+    Database(sled::Error),
+    /// The chunk's record doesn't match the checksum that was written with it, so it was not deserialized. Encrypted chunks
+    /// that fail to authenticate are also corrupt.
+    Corrupt(ChunkDbKey),
+}
+
+impl From<sled::Error> for ChunkReadError {
+    fn from(e: sled::Error) -> Self {
+        Self::Database(e)
+    }
+}
+
+pub fn open_checksum_tree(map_name: &str, db: &sled::Db) -> sled::Result<Tree> {
+    db.open_tree(format!("{}-checksums", map_name))
+}
+
+/// The checksum of the serialized [`Change`](super::Change) stored for a chunk in the working tree.
+pub fn record_checksum(record: &[u8]) -> [u8; 8] {
+    xxh3_64(record).to_le_bytes()
+}
+
+/// Returns `false` if `checksum` was written for a different record. Records written before checksums have no checksum, so
+/// they can't be verified.
+pub fn matches_checksum(checksum: Option<&[u8]>, record: &[u8]) -> bool {
+    checksum.map_or(true, |checksum| checksum == record_checksum(record))
+}
+
+pub fn verify_record(
+    checksum_tree: &Tree,
+    key_bytes: &[u8],
+    record: &[u8],
+) -> Result<(), ChunkReadError> {
+    let checksum = checksum_tree.get(key_bytes)?;
+    if matches_checksum(checksum.as_deref(), record) {
+        Ok(())
+    } else {
+        Err(ChunkReadError::Corrupt(ChunkDbKey::from_sled_key(
+            key_bytes,
+        )))
+    }
+}
@@ -0,0 +1,174 @@
+//! A file format for the chunks that changed between two versions of a map, written by
+//! [`MapDb::export_changes_since`](crate::database::MapDb) and applied by [`MapDb::apply_changes`](crate::database::MapDb).
+//! Each delta only holds the latest state of the changed chunks, so it's much smaller than a copy of the whole database,
+//! which makes it good for incremental backups.
+//!
+//! All integers are little-endian.
+//!
+//! ```text
+//! magic:          [u8; 8] = "FELDDLT\0"
+//! format_version: u32
+//! has_base:       u8        // 0 if the delta has every chunk of the map
+//! base_version:   u64       // the version the delta starts from, if has_base is 1
+//! head_version:   u64       // the version the delta ends at
+//! num_records:    u64
+//! records: [
+//!     key:        [u8; 13]  // ChunkDbKey::into_sled_key, sorted
+//!     num_bytes:  u32       // 0 if the chunk was removed
+//!     bytes:      [u8; num_bytes]  // the compressed chunk, as it's stored in the database
+//! ]
+//! ```
+//!
+//! Chunks of an encrypted map stay encrypted, so a delta can only be applied to a map with the same
+//! [`EncryptionKey`](crate::database::EncryptionKey). Records longer than [`MAX_COMPRESSED_CHUNK_BYTES`] are rejected.
+
+use super::{AbortReason, ChunkDbKey, ChunkReadError, Version};
+use crate::chunk::MAX_COMPRESSED_CHUNK_BYTES;
+
+use sled::transaction::TransactionError;
+use std::io::{self, Read, Write};
+
+const MAGIC: [u8; 8] = *b"FELDDLT\0";
+
+/// The version in the header of exported deltas. A delta from a newer version can't be applied, and
+/// [`read_delta_header`] fails with [`DeltaFileError::UnsupportedVersion`] before any of its records are read.
+pub const DELTA_FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum DeltaFileError {
+    Io(io::Error),
+    Database(TransactionError<AbortReason>),
+    /// The file doesn't start with the magic bytes, so it's probably not a delta file.
+    BadMagic,
+    /// The file was written by a newer version of the format.
+    UnsupportedVersion(u32),
+    /// A changed chunk doesn't match its checksum, so it can't be exported.
+    Corrupt(ChunkDbKey),
+    /// The delta starts from the `found` version, but the last delta applied to the map ended at the `expected` version, so
+    /// applying it would skip or repeat changes. `expected` is `None` if no delta was applied yet.
+    WrongBase {
+        expected: Option<Version>,
+        found: Option<Version>,
+    },
+}
+
+impl From<io::Error> for DeltaFileError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<sled::Error> for DeltaFileError {
+    fn from(e: sled::Error) -> Self {
+        Self::Database(e.into())
+    }
+}
+
+impl From<ChunkReadError> for DeltaFileError {
+    fn from(e: ChunkReadError) -> Self {
+        match e {
+            ChunkReadError::Database(e) => e.into(),
+            ChunkReadError::Corrupt(key) => Self::Corrupt(key),
+        }
+    }
+}
+
+impl From<TransactionError<AbortReason>> for DeltaFileError {
+    fn from(e: TransactionError<AbortReason>) -> Self {
+        Self::Database(e)
+    }
+}
+
+impl From<TransactionError> for DeltaFileError {
+    fn from(e: TransactionError) -> Self {
+        match e {
+            TransactionError::Storage(e) => e.into(),
+            TransactionError::Abort(()) => unreachable!(),
+        }
+    }
+}
+
+pub struct DeltaHeader {
+    pub format_version: u32,
+    pub base_version: Option<Version>,
+    pub head_version: Version,
+    pub num_records: u64,
+}
+
+pub fn write_delta_header(mut writer: impl Write, header: &DeltaHeader) -> io::Result<()> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&header.format_version.to_le_bytes())?;
+    writer.write_all(&[header.base_version.is_some() as u8])?;
+    let base = header.base_version.map_or(0, |v| v.number);
+    writer.write_all(&base.to_le_bytes())?;
+    writer.write_all(&header.head_version.number.to_le_bytes())?;
+    writer.write_all(&header.num_records.to_le_bytes())
+}
+
+pub fn read_delta_header(mut reader: impl Read) -> Result<DeltaHeader, DeltaFileError> {
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(DeltaFileError::BadMagic);
+    }
+    let mut format_version = [0; 4];
+    reader.read_exact(&mut format_version)?;
+    let format_version = u32::from_le_bytes(format_version);
+    if format_version > DELTA_FORMAT_VERSION {
+        return Err(DeltaFileError::UnsupportedVersion(format_version));
+    }
+    let mut has_base = [0; 1];
+    reader.read_exact(&mut has_base)?;
+    let base = read_u64(&mut reader)?;
+    let head = read_u64(&mut reader)?;
+    let num_records = read_u64(&mut reader)?;
+    Ok(DeltaHeader {
+        format_version,
+        base_version: (has_base[0] != 0).then(|| Version::new(base)),
+        head_version: Version::new(head),
+        num_records,
+    })
+}
+
+/// Writes the compressed `bytes` of the chunk at `key`, or a removal if there are no `bytes`.
+pub fn write_delta_record(
+    mut writer: impl Write,
+    key: ChunkDbKey,
+    bytes: Option<&[u8]>,
+) -> io::Result<()> {
+    let bytes = bytes.unwrap_or(&[]);
+    writer.write_all(&key.into_sled_key())?;
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(bytes)
+}
+
+/// Returns the key of the next record, and its compressed bytes unless the chunk was removed.
+pub fn read_delta_record(mut reader: impl Read) -> io::Result<(ChunkDbKey, Option<Box<[u8]>>)> {
+    let mut key_bytes = [0; 13];
+    reader.read_exact(&mut key_bytes)?;
+    let key = ChunkDbKey::from_sled_key(&key_bytes);
+    let mut num_bytes = [0; 4];
+    reader.read_exact(&mut num_bytes)?;
+    let num_bytes = u32::from_le_bytes(num_bytes) as usize;
+    if num_bytes == 0 {
+        return Ok((key, None));
+    }
+    if num_bytes > MAX_COMPRESSED_CHUNK_BYTES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "delta record of {} bytes is too long for a chunk",
+                num_bytes
+            ),
+        ));
+    }
+    let mut bytes = vec![0; num_bytes];
+    reader.read_exact(&mut bytes)?;
+    Ok((key, Some(bytes.into_boxed_slice())))
+}
+
+fn read_u64(mut reader: impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
@@ -0,0 +1,344 @@
+use super::meta_tree::{
+    read_cipher, read_key_check, write_cipher, write_encryption_pending, write_key_check,
+};
+use super::AbortReason;
+use crate::chunk::CompressedChunk;
+use crate::core::rkyv::{Archive, Deserialize, Serialize};
+
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::Tree;
+use std::fmt;
+
+/// Prefix of chunks encrypted with [`ChunkCipher::Aes256Gcm`]. It's followed by the nonce, then the ciphertext and its tag.
+const ENCRYPTED_MAGIC: [u8; 4] = *b"FSEN";
+const NONCE_BYTES: usize = 12;
+/// Encrypted with the key that a database was first opened with, so opening it with any other key fails up front instead of
+/// every chunk failing to decrypt.
+const KEY_CHECK_PLAINTEXT: &[u8] = b"feldspar key check";
+const KEY_CHECK_AAD: &[u8] = b"KEY_CHECK";
+
+/// The cipher that chunks are encrypted with before they're written to a [`MapDb`](super::MapDb). The database header
+/// stores it next to the [`CompressionCodec`](crate::chunk::CompressionCodec).
+#[derive(
+    Archive,
+    Clone,
+    Copy,
+    Debug,
+    Deserialize,
+    Eq,
+    PartialEq,
+    Serialize,
+    serde::Deserialize,
+    serde::Serialize,
+)]
+#[archive(crate = "crate::core::rkyv")]
+pub enum ChunkCipher {
+    /// Chunks are stored as they were compressed.
+    None,
+    /// AES-256 in Galois/Counter Mode, with a random 96-bit nonce for each chunk. The chunk's key is authenticated along
+    /// with its bytes, so an encrypted chunk can't be copied to another key without being detected.
+    Aes256Gcm,
+}
+
+impl Default for ChunkCipher {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+/// The 256-bit key of a [`MapDb`](super::MapDb) that's encrypted with [`ChunkCipher::Aes256Gcm`]. Inserted as a resource
+/// before the `MapPlugin` starts, it's used to open the map's database.
+///
+/// Only the compressed chunks are encrypted. Layers, metadata, and the version graph are stored in the clear.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+pub struct ChunkEncryption {
+    cipher: Aes256Gcm,
+}
+
+impl ChunkEncryption {
+    pub fn new(key: &EncryptionKey) -> Self {
+        Self {
+            cipher: Aes256Gcm::new(Key::from_slice(&key.0)),
+        }
+    }
+
+    /// The encryption of the map in `meta_tree`, or `None` if it isn't encrypted, even if there is a `key`. Fails if the map
+    /// is encrypted with a different key, or if it's encrypted and there is no `key`. Nothing is written.
+    pub fn open(
+        meta_tree: &Tree,
+        key: Option<&EncryptionKey>,
+    ) -> Result<Option<Self>, TransactionError<AbortReason>> {
+        match (read_cipher(meta_tree)?, key) {
+            (ChunkCipher::None, _) => Ok(None),
+            (ChunkCipher::Aes256Gcm, None) => {
+                Err(TransactionError::Abort(AbortReason::MissingEncryptionKey))
+            }
+            (ChunkCipher::Aes256Gcm, Some(key)) => {
+                let encryption = Self::new(key);
+                let key_check = read_key_check(meta_tree)?;
+                let decrypted =
+                    key_check.and_then(|bytes| encryption.decrypt(KEY_CHECK_AAD, &bytes));
+                if decrypted.as_deref() != Some(KEY_CHECK_PLAINTEXT) {
+                    return Err(TransactionError::Abort(AbortReason::WrongEncryptionKey));
+                }
+                Ok(Some(encryption))
+            }
+        }
+    }
+
+    /// Records in `meta_tree` that the map is encrypted with this key, and that its existing chunks still have to be
+    /// encrypted. It's all written in one transaction, so a map is never left encrypted without a way to check its key.
+    pub fn record(&self, meta_tree: &Tree) -> Result<(), TransactionError<AbortReason>> {
+        let key_check = self.encrypt(KEY_CHECK_AAD, KEY_CHECK_PLAINTEXT);
+        meta_tree.transaction(|txn| {
+            write_key_check(txn, &key_check)?;
+            write_cipher(txn, ChunkCipher::Aes256Gcm)?;
+            write_encryption_pending(txn)?;
+            Ok::<_, ConflictableTransactionError<AbortReason>>(())
+        })
+    }
+
+    /// Encrypts `chunk`, which is stored at `key_bytes`. Chunks that are already encrypted are returned as is.
+    pub fn encrypt_chunk(&self, key_bytes: &[u8], chunk: CompressedChunk) -> CompressedChunk {
+        if is_encrypted(&chunk.bytes) {
+            return chunk;
+        }
+        CompressedChunk {
+            bytes: self.encrypt(key_bytes, &chunk.bytes).into_boxed_slice(),
+        }
+    }
+
+    /// Returns `None` if `bytes` weren't encrypted by this key for `key_bytes`, or if they were modified.
+    pub fn decrypt_chunk(&self, key_bytes: &[u8], bytes: &[u8]) -> Option<CompressedChunk> {
+        let bytes = self.decrypt(key_bytes, bytes)?;
+        Some(CompressedChunk {
+            bytes: bytes.into_boxed_slice(),
+        })
+    }
+
+    fn encrypt(&self, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let mut nonce = [0; NONCE_BYTES];
+        getrandom::getrandom(&mut nonce).expect("Failed to generate nonce");
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .expect("Chunk too large to encrypt");
+
+        let mut bytes = Vec::with_capacity(ENCRYPTED_MAGIC.len() + NONCE_BYTES + ciphertext.len());
+        bytes.extend_from_slice(&ENCRYPTED_MAGIC);
+        bytes.extend_from_slice(&nonce);
+        bytes.extend_from_slice(&ciphertext);
+        bytes
+    }
+
+    fn decrypt(&self, aad: &[u8], bytes: &[u8]) -> Option<Vec<u8>> {
+        let bytes = bytes.strip_prefix(&ENCRYPTED_MAGIC)?;
+        if bytes.len() < NONCE_BYTES {
+            return None;
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_BYTES);
+        self.cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .ok()
+    }
+}
+
+/// Whether `bytes` of a compressed chunk were encrypted by a [`ChunkEncryption`].
+pub fn is_encrypted(bytes: &[u8]) -> bool {
+    bytes.starts_with(&ENCRYPTED_MAGIC)
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::Chunk;
+    use crate::core::glam::IVec3;
+    use crate::database::{
+        Change, ChangeEncoder, ChunkDbKey, ChunkReadError, DeltaFileError, MapDb,
+    };
+    use crate::sdf::Sd8;
+
+    use grid_tree::NodeKey;
+
+    #[test]
+    fn encrypted_chunks_need_the_right_key() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let key = EncryptionKey::new([7; 32]);
+        let plain_key = ChunkDbKey::from(NodeKey::new(0, IVec3::ZERO));
+        let secret_key = ChunkDbKey::from(NodeKey::new(0, IVec3::ONE));
+        let mut chunk = Chunk::default();
+        chunk.set_voxel(IVec3::new(1, 2, 3), 7, Sd8::MIN);
+
+        // Written before the map was encrypted.
+        let mut map = MapDb::open(&db, "mymap").unwrap();
+        assert_eq!(map.cipher(), ChunkCipher::None);
+        map.bulk_write_chunks([(plain_key.into(), chunk.compress())].into_iter())
+            .unwrap();
+        drop(map);
+
+        let mut map = MapDb::open_with_key(&db, "mymap", key.clone()).unwrap();
+        assert_eq!(map.cipher(), ChunkCipher::Aes256Gcm);
+        map.bulk_write_chunks([(secret_key.into(), chunk.compress())].into_iter())
+            .unwrap();
+        let stored = map
+            .working_tree
+            .get(secret_key.into_sled_key())
+            .unwrap()
+            .unwrap();
+        assert!(!stored.windows(4).any(|w| w == b"FSCH"));
+        for key in [plain_key, secret_key] {
+            let change = map.read_working_version(key).unwrap().unwrap();
+            assert_eq!(
+                change.as_ref().get_insert_data().unwrap().decompress(),
+                chunk
+            );
+        }
+
+        // An encrypted chunk that's moved to another key doesn't authenticate.
+        map.working_tree
+            .insert(plain_key.into_sled_key(), stored)
+            .unwrap();
+        map.checksum_tree.remove(plain_key.into_sled_key()).unwrap();
+        assert_eq!(
+            map.read_working_version(plain_key).err(),
+            Some(ChunkReadError::Corrupt(plain_key))
+        );
+        drop(map);
+
+        assert_eq!(
+            MapDb::open(&db, "mymap").err(),
+            Some(TransactionError::Abort(AbortReason::MissingEncryptionKey))
+        );
+        assert_eq!(
+            MapDb::open_with_key(&db, "mymap", EncryptionKey::new([8; 32])).err(),
+            Some(TransactionError::Abort(AbortReason::WrongEncryptionKey))
+        );
+        assert!(MapDb::open_with_key(&db, "mymap", key).is_ok());
+    }
+
+    #[test]
+    fn existing_chunks_are_encrypted_and_plaintext_is_rejected() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let key = EncryptionKey::new([7; 32]);
+        let chunk_key = ChunkDbKey::from(NodeKey::new(0, IVec3::ZERO));
+        let mut chunk = Chunk::default();
+        chunk.set_voxel(IVec3::new(1, 2, 3), 7, Sd8::MIN);
+
+        let mut map = MapDb::open(&db, "mymap").unwrap();
+        map.bulk_write_chunks([(chunk_key.into(), chunk.compress())].into_iter())
+            .unwrap();
+        let plaintext = map
+            .working_tree
+            .get(chunk_key.into_sled_key())
+            .unwrap()
+            .unwrap();
+        drop(map);
+
+        // Only reading the map with a key doesn't encrypt it.
+        let map =
+            MapDb::open_read_only_with_key_and_progress(&db, "mymap", Some(&key), |_| ()).unwrap();
+        assert_eq!(map.cipher(), ChunkCipher::None);
+        assert_eq!(read_cipher(&map.meta_tree).unwrap(), ChunkCipher::None);
+        assert_eq!(read_key_check(&map.meta_tree).unwrap(), None);
+        drop(map);
+
+        let map = MapDb::open_with_key(&db, "mymap", key.clone()).unwrap();
+        let stored = map
+            .working_tree
+            .get(chunk_key.into_sled_key())
+            .unwrap()
+            .unwrap();
+        assert!(!stored.windows(4).any(|w| w == b"FSCH"));
+        let change = map.read_working_version(chunk_key).unwrap().unwrap();
+        assert_eq!(
+            change.as_ref().get_insert_data().unwrap().decompress(),
+            chunk
+        );
+
+        // A chunk written in the clear, e.g. by someone without the key, isn't trusted.
+        map.working_tree
+            .insert(chunk_key.into_sled_key(), plaintext)
+            .unwrap();
+        map.checksum_tree.remove(chunk_key.into_sled_key()).unwrap();
+        assert_eq!(
+            map.read_working_version(chunk_key).err(),
+            Some(ChunkReadError::Corrupt(chunk_key))
+        );
+    }
+
+    #[test]
+    fn deltas_of_encrypted_maps_stay_encrypted() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let key = EncryptionKey::new([7; 32]);
+        let chunk_key = ChunkDbKey::from(NodeKey::new(0, IVec3::ZERO));
+        let mut chunk = Chunk::default();
+        chunk.set_voxel(IVec3::new(1, 2, 3), 7, Sd8::MIN);
+
+        let mut map = MapDb::open_with_key(&db, "mymap", key.clone()).unwrap();
+        let mut encoder = ChangeEncoder::default();
+        encoder.add_compressed_change(chunk_key, Change::Insert(chunk.compress()));
+        map.write_working_version(encoder.encode()).unwrap();
+        let mut delta = Vec::new();
+        map.export_changes_since(None, &mut delta).unwrap();
+        assert!(!delta.windows(4).any(|w| w == b"FSCH"));
+
+        let mut plain_map = MapDb::open(&db, "plain").unwrap();
+        assert!(matches!(
+            plain_map.apply_changes(delta.as_slice()),
+            Err(DeltaFileError::Database(TransactionError::Abort(
+                AbortReason::MissingEncryptionKey
+            )))
+        ));
+        let mut other_map =
+            MapDb::open_with_key(&db, "other", EncryptionKey::new([8; 32])).unwrap();
+        assert!(matches!(
+            other_map.apply_changes(delta.as_slice()),
+            Err(DeltaFileError::Database(TransactionError::Abort(
+                AbortReason::WrongEncryptionKey
+            )))
+        ));
+
+        let mut restored = MapDb::open_with_key(&db, "restored", key).unwrap();
+        restored.apply_changes(delta.as_slice()).unwrap();
+        let change = restored.read_working_version(chunk_key).unwrap().unwrap();
+        assert_eq!(
+            change.as_ref().get_insert_data().unwrap().decompress(),
+            chunk
+        );
+    }
+}
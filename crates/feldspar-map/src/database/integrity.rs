@@ -0,0 +1,336 @@
+use super::branch_tree::{read_all_branch_heads, remove_branch_head};
+use super::version_graph_tree::{link_version, VersionNode};
+use super::{
+    AbortReason, ArchivedIVec, Change, ChangeEncoder, ChunkDbKey, ChunkReadError, MapDb, Version,
+};
+use crate::chunk::Chunk;
+use crate::clipmap::{DownsampleJob, CHILDREN_USIZE};
+use crate::coordinates::visit_children;
+use crate::core::glam::IVec3;
+
+use grid_tree::NodeKey;
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::Transactional;
+use std::collections::BTreeSet;
+
+/// The problems found by [`MapDb::check`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct IntegrityReport {
+    /// Chunks in the working version whose records don't match their checksums, or whose chunks can't be decompressed.
+    pub unreadable_chunks: Vec<ChunkDbKey>,
+    /// Checksums of chunks that have no record in the working version.
+    pub dangling_checksums: Vec<ChunkDbKey>,
+    /// Versions whose archived changes are stored, but that aren't in the version graph, so nothing can revert to them.
+    pub orphaned_versions: Vec<Version>,
+    /// Versions whose parent isn't in the version graph, along with that parent. No path reaches the root from them.
+    pub missing_parents: Vec<(Version, Version)>,
+    /// Branches whose head isn't in the version graph.
+    pub dangling_branches: Vec<(String, Version)>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.unreadable_chunks.is_empty()
+            && self.dangling_checksums.is_empty()
+            && self.orphaned_versions.is_empty()
+            && self.missing_parents.is_empty()
+            && self.dangling_branches.is_empty()
+    }
+}
+
+/// The result of [`MapDb::repair`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RepairReport {
+    /// Everything that was repaired.
+    pub found: IntegrityReport,
+    /// Unreadable chunks that were downsampled again from their children.
+    pub rederived_chunks: Vec<ChunkDbKey>,
+    /// Unreadable chunks that were removed, because they're at LOD0 or one of their children is unreadable too.
+    pub dropped_chunks: Vec<ChunkDbKey>,
+}
+
+impl MapDb {
+    /// Scans every record of the working version and the version history for damage, e.g. after a crash or a bad disk. This
+    /// reads and decompresses every chunk, so it's meant for maintenance tools, not for running while the map is streamed.
+    pub fn check(&self) -> sled::Result<IntegrityReport> {
+        let mut report = IntegrityReport::default();
+
+        for entry in self.working_tree.iter() {
+            let (key_bytes, _) = entry?;
+            let key = ChunkDbKey::from_sled_key(&key_bytes);
+            match self.read_working_version_mapped(key) {
+                Ok(Some(Change::Insert(chunk))) if chunk.try_decompress().is_none() => {
+                    report.unreadable_chunks.push(key)
+                }
+                Ok(_) => (),
+                Err(ChunkReadError::Corrupt(key)) => report.unreadable_chunks.push(key),
+                Err(ChunkReadError::Database(e)) => return Err(e),
+            }
+        }
+        for entry in self.checksum_tree.iter() {
+            let (key_bytes, _) = entry?;
+            if !self.working_tree.contains_key(&key_bytes)? {
+                report
+                    .dangling_checksums
+                    .push(ChunkDbKey::from_sled_key(&key_bytes));
+            }
+        }
+
+        for entry in self.version_change_tree.iter() {
+            let (version_bytes, _) = entry?;
+            if !self.version_graph_tree.contains_key(&version_bytes)? {
+                report
+                    .orphaned_versions
+                    .push(Version::from_sled_key(&version_bytes)?);
+            }
+        }
+        for entry in self.version_graph_tree.iter() {
+            let (version_bytes, node_bytes) = entry?;
+            let node = unsafe { ArchivedIVec::<VersionNode>::new(node_bytes) }.deserialize();
+            if let Some(parent) = node.parent_version {
+                if !self
+                    .version_graph_tree
+                    .contains_key(parent.into_sled_key())?
+                {
+                    report
+                        .missing_parents
+                        .push((Version::from_sled_key(&version_bytes)?, parent));
+                }
+            }
+        }
+        for (name, head) in read_all_branch_heads(&self.branch_tree)? {
+            if !self.version_graph_tree.contains_key(head.into_sled_key())? {
+                report.dangling_branches.push((name, head));
+            }
+        }
+
+        if !report.is_ok() {
+            log::error!("Integrity check found problems: {:?}", report);
+        }
+        Ok(report)
+    }
+
+    /// Runs [`Self::check`] and repairs everything it found:
+    ///
+    /// - Unreadable chunks above LOD0 are downsampled again from their children, if all of them are readable. Missing
+    ///   children are empty, like they are for the downsampler. Other unreadable chunks are removed, so they're generated
+    ///   again when they're loaded. Both are written like any other change of the working version.
+    /// - Dangling checksums, orphaned versions, and dangling branches are removed. If the working version is on a dangling
+    ///   branch, it becomes detached.
+    /// - Versions with missing parents become roots, so they can still be reverted to from their descendants. The history
+    ///   before them is lost.
+    pub fn repair(&mut self) -> Result<RepairReport, TransactionError<AbortReason>> {
+        let found = self.check()?;
+        let mut report = RepairReport::default();
+
+        let unreadable: BTreeSet<_> = found.unreadable_chunks.iter().copied().collect();
+        let mut encoder = ChangeEncoder::default();
+        for &key in found.unreadable_chunks.iter() {
+            match self.rederive_chunk(key, &unreadable)? {
+                Some(Some(chunk)) => {
+                    encoder.add_compressed_change(
+                        key,
+                        Change::Insert(chunk.compress_with(self.codec())),
+                    );
+                    report.rederived_chunks.push(key);
+                }
+                Some(None) => {
+                    encoder.add_compressed_change(key, Change::Remove);
+                    report.rederived_chunks.push(key);
+                }
+                None => {
+                    encoder.add_compressed_change(key, Change::Remove);
+                    report.dropped_chunks.push(key);
+                }
+            }
+        }
+        if !found.unreadable_chunks.is_empty() {
+            self.write_working_version(encoder.encode())
+                .map_err(|e| match e {
+                    TransactionError::Storage(e) => e,
+                    TransactionError::Abort(()) => unreachable!(),
+                })?;
+        }
+
+        (
+            &self.checksum_tree,
+            &self.version_change_tree,
+            &self.version_graph_tree,
+            &self.branch_tree,
+        )
+            .transaction(|(checksum_txn, change_txn, graph_txn, branch_txn)| {
+                for key in found.dangling_checksums.iter() {
+                    checksum_txn.remove(key.into_sled_key().as_ref())?;
+                }
+                for version in found.orphaned_versions.iter() {
+                    change_txn.remove(version.into_sled_key().as_ref())?;
+                }
+                for &(version, _) in found.missing_parents.iter() {
+                    link_version(
+                        graph_txn,
+                        version,
+                        VersionNode {
+                            parent_version: None,
+                        },
+                    )?;
+                }
+                for (name, _) in found.dangling_branches.iter() {
+                    remove_branch_head(branch_txn, name)?;
+                }
+                Ok::<_, ConflictableTransactionError<AbortReason>>(())
+            })?;
+        let on_dangling_branch = found
+            .dangling_branches
+            .iter()
+            .any(|(name, _)| self.current_branch() == Some(name.as_str()));
+        if on_dangling_branch {
+            self.detach_branch()?;
+        }
+
+        log::info!(
+            "Repaired map: re-derived {} chunks and dropped {}",
+            report.rederived_chunks.len(),
+            report.dropped_chunks.len()
+        );
+        report.found = found;
+        Ok(report)
+    }
+
+    /// Downsamples the chunk at `key` from its children in the working version. Returns `None` if it can't be re-derived,
+    /// and `Some(None)` if every child is empty.
+    fn rederive_chunk(
+        &self,
+        key: ChunkDbKey,
+        unreadable: &BTreeSet<ChunkDbKey>,
+    ) -> sled::Result<Option<Option<Chunk>>> {
+        let node_key: NodeKey<IVec3> = key.into();
+        if node_key.level == 0 {
+            return Ok(None);
+        }
+
+        let mut child_keys = Vec::with_capacity(CHILDREN_USIZE);
+        visit_children(node_key.coordinates, |child_index, child_coords| {
+            child_keys.push((child_index, NodeKey::new(node_key.level - 1, child_coords)));
+        });
+        let mut children: [Option<Box<Chunk>>; CHILDREN_USIZE] = Default::default();
+        for (child_index, child_key) in child_keys.into_iter() {
+            let child_key = ChunkDbKey::from(child_key);
+            if unreadable.contains(&child_key) {
+                return Ok(None);
+            }
+            let child = match self.read_working_version_mapped(child_key) {
+                Ok(Some(Change::Insert(chunk))) => chunk,
+                Ok(_) => continue,
+                Err(ChunkReadError::Corrupt(_)) => return Ok(None),
+                Err(ChunkReadError::Database(e)) => return Err(e),
+            };
+            match child.try_decompress() {
+                Some(chunk) => children[child_index as usize] = Some(Box::new(chunk)),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(
+            DownsampleJob {
+                key: node_key,
+                children,
+            }
+            .downsample(),
+        ))
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::open_temporary_map;
+    use crate::sdf::Sd8;
+
+    fn corrupt(db: &MapDb, key: NodeKey<IVec3>) {
+        let key = ChunkDbKey::from(key);
+        db.working_tree
+            .insert(key.into_sled_key(), b"not a chunk".as_ref())
+            .unwrap();
+    }
+
+    #[test]
+    fn repair_rederives_chunks_and_drops_bad_records() {
+        let mut db = open_temporary_map("map");
+        let mut chunk = Chunk::default();
+        chunk.set_voxel(IVec3::new(1, 2, 3), 7, Sd8::MIN);
+        let parent = NodeKey::new(1, IVec3::ZERO);
+        let mut children = Vec::new();
+        visit_children(parent.coordinates, |_, coords| {
+            children.push(NodeKey::new(0, coords))
+        });
+        let far_away = NodeKey::new(0, IVec3::splat(100));
+        db.bulk_write_chunks(
+            children
+                .iter()
+                .chain([parent, far_away].iter())
+                .map(|&key| (key, chunk.compress())),
+        )
+        .unwrap();
+        assert!(db.check().unwrap().is_ok());
+
+        corrupt(&db, parent);
+        corrupt(&db, far_away);
+        let dangling = ChunkDbKey::from(NodeKey::new(0, IVec3::splat(-100)));
+        db.checksum_tree
+            .insert(dangling.into_sled_key(), [0; 8].as_ref())
+            .unwrap();
+        let orphan = Version::new(1000);
+        db.version_change_tree
+            .insert(orphan.into_sled_key(), [0; 8].as_ref())
+            .unwrap();
+        let unrooted = Version::new(1001);
+        let node = VersionNode {
+            parent_version: Some(Version::new(1002)),
+        };
+        db.version_graph_tree
+            .insert(unrooted.into_sled_key(), node.serialize().as_ref())
+            .unwrap();
+        db.branch_tree
+            .insert("lost", Version::new(1003).into_sled_key().as_ref())
+            .unwrap();
+
+        let report = db.check().unwrap();
+        let mut unreadable = report.unreadable_chunks.clone();
+        unreadable.sort();
+        let mut expected = vec![ChunkDbKey::from(parent), ChunkDbKey::from(far_away)];
+        expected.sort();
+        assert_eq!(unreadable, expected);
+        assert_eq!(report.dangling_checksums, [dangling]);
+        assert_eq!(report.orphaned_versions, [orphan]);
+        assert_eq!(report.missing_parents, [(unrooted, Version::new(1002))]);
+        assert_eq!(
+            report.dangling_branches,
+            [("lost".to_owned(), Version::new(1003))]
+        );
+
+        let repaired = db.repair().unwrap();
+        assert_eq!(repaired.found, report);
+        assert_eq!(repaired.rederived_chunks, [ChunkDbKey::from(parent)]);
+        assert_eq!(repaired.dropped_chunks, [ChunkDbKey::from(far_away)]);
+        assert!(db.check().unwrap().is_ok());
+
+        let expected_parent = DownsampleJob {
+            key: parent,
+            children: [(); CHILDREN_USIZE].map(|_| Some(Box::new(chunk))),
+        }
+        .downsample()
+        .unwrap();
+        let read = db.read_working_version(parent.into()).unwrap().unwrap();
+        assert_eq!(
+            read.deserialize().into_insert().unwrap().decompress(),
+            expected_parent
+        );
+        assert!(db.read_working_version(far_away.into()).unwrap().is_none());
+    }
+}
@@ -0,0 +1,95 @@
+use super::ChunkDbKey;
+use crate::chunk::CompressedLayers;
+
+use sled::Tree;
+
+pub fn open_layer_tree(map_name: &str, db: &sled::Db) -> sled::Result<Tree> {
+    db.open_tree(format!("{}-layers", map_name))
+}
+
+pub fn write_layers(tree: &Tree, key: ChunkDbKey, layers: &CompressedLayers) -> sled::Result<()> {
+    tree.insert(key.into_sled_key(), layers.bytes.as_ref())?;
+    Ok(())
+}
+
+pub fn read_layers(tree: &Tree, key: ChunkDbKey) -> sled::Result<Option<CompressedLayers>> {
+    let data = tree.get(key.into_sled_key())?;
+    Ok(data.map(|b| CompressedLayers {
+        bytes: b.as_ref().into(),
+    }))
+}
+
+pub fn remove_layers(tree: &Tree, key: ChunkDbKey) -> sled::Result<()> {
+    tree.remove(key.into_sled_key())?;
+    Ok(())
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use crate::chunk::{ChunkLayers, CompressionCodec, LayerId, VoxelLayerSchema};
+    use crate::core::glam::IVec3;
+    use crate::database::{AbortReason, ChunkDbKey, ChunkReadError, MapDb};
+
+    use sled::transaction::TransactionError;
+
+    #[test]
+    fn write_and_read_chunk_layers() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let mut schema = VoxelLayerSchema::default();
+        let owner: LayerId<u16> = schema.register("owner", 10);
+
+        let key = ChunkDbKey::new(0, IVec3::new(1, -2, 3).into());
+        {
+            let mut map = MapDb::open(&db, "mymap").unwrap();
+            map.register_layers(schema.clone()).unwrap();
+            assert_eq!(map.read_chunk_layers(key).unwrap(), None);
+
+            let mut layers = ChunkLayers::new(&schema);
+            layers.set(owner, IVec3::new(2, 4, 6), 513);
+            map.write_chunk_layers(key, &layers.compress(CompressionCodec::default()))
+                .unwrap();
+        }
+
+        // The schema is stored with the map.
+        let mut map = MapDb::open(&db, "mymap").unwrap();
+        assert_eq!(map.layer_schema(), &schema);
+        let layers = map.read_chunk_layers(key).unwrap().unwrap();
+        assert_eq!(layers.get(owner, IVec3::new(2, 4, 6)), 513);
+
+        // Layers can be appended, but not changed.
+        let mut appended = schema.clone();
+        appended.register::<bool>("burning", 1);
+        map.register_layers(appended.clone()).unwrap();
+        let mut changed = VoxelLayerSchema::default();
+        changed.register::<u8>("owner", 8);
+        assert!(matches!(
+            map.register_layers(changed),
+            Err(TransactionError::Abort(AbortReason::LayerSchemaMismatch))
+        ));
+        assert_eq!(map.layer_schema(), &appended);
+
+        map.remove_chunk_layers(key).unwrap();
+        assert_eq!(map.read_chunk_layers(key).unwrap(), None);
+
+        // Garbage can't be decompressed.
+        super::write_layers(
+            &map.layer_tree,
+            key,
+            &crate::chunk::CompressedLayers {
+                bytes: vec![0xAB; 16].into(),
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            map.read_chunk_layers(key),
+            Err(ChunkReadError::Corrupt(key))
+        );
+    }
+}
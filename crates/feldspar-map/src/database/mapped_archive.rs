@@ -0,0 +1,234 @@
+use super::archive_file::{
+    archive_data_start, read_archive_header, read_archive_index, ArchiveEntry, HEADER_BYTES,
+};
+use super::{
+    store_removals_as_air, BackendError, Change, ChunkDbKey, ChunkReadError, MapBackend, MapDb,
+};
+use crate::chunk::{CompressedBytes, CompressedChunk, CompressionCodec, MappedChunk};
+use crate::core::SmallKeyHashMap;
+
+use memmap2::Mmap;
+use parking_lot::RwLock;
+use std::fs::File;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// An [archive](super::archive_file) that's memory-mapped instead of read into buffers.
+///
+/// Chunks are read straight out of the mapping, so the only copy of their bytes is in the page cache, and the OS can evict
+/// them under memory pressure. This is meant for multi-GB worlds, where reading every chunk through the database would
+/// buffer it twice.
+pub struct MappedArchive {
+    map: Arc<Mmap>,
+    index: SmallKeyHashMap<ChunkDbKey, ArchiveEntry>,
+    data_start: u64,
+}
+
+impl MappedArchive {
+    /// Maps all of `file`.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may write to or truncate `file` until the archive and every [`MappedChunk`] read from it are dropped, not
+    /// even another process. The chunks are slices of the mapping, so a change would alter or unmap memory that's borrowed.
+    pub unsafe fn open(file: &File) -> Result<Self, BackendError> {
+        // SAFE: The caller guarantees that the file isn't modified, and the mapping is read-only.
+        let map = Mmap::map(file)?;
+        Self::new(map)
+    }
+
+    /// Reads the header and index of the archive in `map`.
+    pub fn new(map: Mmap) -> Result<Self, BackendError> {
+        let num_entries = read_archive_header(&map)?;
+        let index_end = archive_data_start(num_entries, map.len() as u64)?;
+        let index_bytes = &map[HEADER_BYTES as usize..index_end as usize];
+        let index = read_archive_index(index_bytes, num_entries)?;
+        // Every entry must be in bounds, so reads never have to check.
+        let data_len = map.len() as u64 - index_end;
+        let in_bounds = |entry: &ArchiveEntry| {
+            entry
+                .offset
+                .checked_add(u64::from(entry.num_bytes))
+                .map_or(false, |end| end <= data_len)
+        };
+        if !index.values().all(in_bounds) {
+            return Err(BackendError::BadArchive);
+        }
+        log::info!("Mapped archive with {} chunks", num_entries);
+        Ok(Self {
+            map: Arc::new(map),
+            index,
+            data_start: index_end,
+        })
+    }
+
+    /// The number of chunks in the archive.
+    pub fn num_chunks(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn contains(&self, key: ChunkDbKey) -> bool {
+        self.index.contains_key(&key)
+    }
+
+    /// The chunk at `key`, without copying it out of the mapping.
+    pub fn read(&self, key: ChunkDbKey) -> Option<MappedChunk> {
+        let entry = self.index.get(&key)?;
+        let start = (self.data_start + entry.offset) as usize;
+        Some(MappedChunk::new(MappedRange {
+            map: Arc::clone(&self.map),
+            range: start..start + entry.num_bytes as usize,
+        }))
+    }
+}
+
+/// The bytes of one chunk in a [`MappedArchive`]. Keeps the whole mapping alive.
+struct MappedRange {
+    map: Arc<Mmap>,
+    range: Range<usize>,
+}
+
+impl CompressedBytes for MappedRange {
+    fn compressed_bytes(&self) -> &[u8] {
+        &self.map[self.range.clone()]
+    }
+}
+
+/// A [`MapBackend`] that reads chunks from a [`MappedArchive`], e.g. an export of a huge world, and writes them to a
+/// [`MapDb`].
+///
+/// Like the [`RemoteBackend`](super::RemoteBackend), the archive is read-only, so chunks in the working version of the
+/// database take precedence over the archive. But chunks are never cached in the database, since reading the mapping is
+/// already as fast as reading the database.
+pub struct MappedArchiveBackend {
+    archive: MappedArchive,
+    db: Arc<RwLock<MapDb>>,
+}
+
+impl MappedArchiveBackend {
+    pub fn new(archive: MappedArchive, db: Arc<RwLock<MapDb>>) -> Self {
+        Self { archive, db }
+    }
+
+    pub fn archive(&self) -> &MappedArchive {
+        &self.archive
+    }
+
+    /// The database that changes are written to.
+    pub fn db(&self) -> &Arc<RwLock<MapDb>> {
+        &self.db
+    }
+}
+
+impl MapBackend for MappedArchiveBackend {
+    fn codec(&self) -> CompressionCodec {
+        self.db.read().codec()
+    }
+
+    fn read_chunk(&self, key: ChunkDbKey) -> Result<Option<Change<CompressedChunk>>, BackendError> {
+        let change = self.read_chunk_mapped(key)?;
+        Ok(change.map(|c| c.map(|mapped| mapped.to_compressed())))
+    }
+
+    fn read_chunk_mapped(
+        &self,
+        key: ChunkDbKey,
+    ) -> Result<Option<Change<MappedChunk>>, BackendError> {
+        match self.db.read().read_working_version_mapped(key) {
+            Ok(Some(written)) => return Ok(Some(written)),
+            Ok(None) => (),
+            Err(ChunkReadError::Corrupt(key)) if self.archive.contains(key) => {
+                // The archived chunk is older, but it's better than nothing.
+                log::error!("Corrupt copy of {:?}, reading the archive instead", key);
+            }
+            Err(e) => return Err(e.into()),
+        }
+        Ok(self.archive.read(key).map(Change::Insert))
+    }
+
+    fn read_chunks_mapped(
+        &self,
+        keys: &[ChunkDbKey],
+    ) -> Result<Vec<Option<Change<MappedChunk>>>, BackendError> {
+        keys.iter()
+            .map(|&key| self.read_chunk_mapped(key))
+            .collect()
+    }
+
+    fn write_chunks(
+        &self,
+        changes: Vec<(ChunkDbKey, Change<CompressedChunk>)>,
+    ) -> Result<(), BackendError> {
+        let changes = store_removals_as_air(changes, |key| Ok(self.archive.contains(key)))?;
+        self.db.write_chunks(changes)
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::{Chunk, UniformChunk};
+    use crate::core::glam::IVec3;
+    use crate::database::open_temporary_map;
+    use crate::sdf::Sd8;
+
+    use grid_tree::NodeKey;
+    use memmap2::MmapMut;
+
+    fn map_bytes(bytes: &[u8]) -> Mmap {
+        let mut map = MmapMut::map_anon(bytes.len()).unwrap();
+        map.copy_from_slice(bytes);
+        map.make_read_only().unwrap()
+    }
+
+    #[test]
+    fn read_chunks_from_mapped_archive() {
+        let mut exported = open_temporary_map("exported");
+        let mut chunk = Chunk::default();
+        chunk.set_voxel(IVec3::new(1, 2, 3), 7, Sd8::MIN);
+        let keys = [
+            NodeKey::new(0, IVec3::ZERO),
+            NodeKey::new(1, IVec3::new(3, -2, 0)),
+        ];
+        exported
+            .bulk_write_chunks(keys.iter().map(|&key| (key, chunk.compress())))
+            .unwrap();
+        let mut bytes = Vec::new();
+        exported.export_archive(&mut bytes).unwrap();
+
+        let archive = MappedArchive::new(map_bytes(&bytes)).unwrap();
+        assert_eq!(archive.num_chunks(), 2);
+        // Truncated archives are caught when they're opened.
+        assert!(MappedArchive::new(map_bytes(&bytes[..bytes.len() - 1])).is_err());
+        // So are entries whose end overflows.
+        let mut overflowing = bytes.clone();
+        let offset_start = (HEADER_BYTES + 13) as usize;
+        overflowing[offset_start..offset_start + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(MappedArchive::new(map_bytes(&overflowing)).is_err());
+
+        let backend =
+            MappedArchiveBackend::new(archive, Arc::new(RwLock::new(open_temporary_map("map"))));
+        let key = ChunkDbKey::from(keys[1]);
+        let read = backend.read_chunk_mapped(key).unwrap().unwrap();
+        assert_eq!(read.into_insert().unwrap().decompress(), chunk);
+        let missing = ChunkDbKey::from(NodeKey::new(0, IVec3::ONE));
+        assert!(backend.read_chunk(missing).unwrap().is_none());
+
+        // Writes shadow the archive.
+        backend.write_chunks(vec![(key, Change::Remove)]).unwrap();
+        let read = backend
+            .read_chunk(key)
+            .unwrap()
+            .unwrap()
+            .into_insert()
+            .unwrap();
+        assert_eq!(read.uniform(), Some(UniformChunk::Air));
+    }
+}
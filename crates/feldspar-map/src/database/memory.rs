@@ -0,0 +1,113 @@
+use super::{BackendError, Change, ChunkDbKey, MapBackend};
+use crate::chunk::{CompressedChunk, CompressionCodec};
+use crate::core::SmallKeyHashMap;
+
+use parking_lot::RwLock;
+
+/// A [`MapBackend`] that keeps every chunk in memory, without touching the filesystem.
+///
+/// Useful for tests, and for servers that persist the map somewhere else. There is no version history, so everything is
+/// lost when it's dropped unless the chunks are copied out with [`MemoryBackend::iter_chunks`].
+#[derive(Default)]
+pub struct MemoryBackend {
+    chunks: RwLock<SmallKeyHashMap<ChunkDbKey, CompressedChunk>>,
+    codec: CompressionCodec,
+}
+
+impl MemoryBackend {
+    pub fn new(codec: CompressionCodec) -> Self {
+        Self {
+            chunks: Default::default(),
+            codec,
+        }
+    }
+
+    pub fn num_chunks(&self) -> usize {
+        self.chunks.read().len()
+    }
+
+    /// Inserts `chunk` unless there's already a chunk at `key`. Returns the chunk that's stored at `key` now.
+    pub fn insert_if_absent(&self, key: ChunkDbKey, chunk: CompressedChunk) -> CompressedChunk {
+        self.chunks.write().entry(key).or_insert(chunk).clone()
+    }
+
+    /// Calls `visitor` on a snapshot of every stored chunk, in no particular order.
+    pub fn iter_chunks(&self, mut visitor: impl FnMut(ChunkDbKey, &CompressedChunk)) {
+        for (&key, chunk) in self.chunks.read().iter() {
+            visitor(key, chunk);
+        }
+    }
+}
+
+impl MapBackend for MemoryBackend {
+    fn codec(&self) -> CompressionCodec {
+        self.codec
+    }
+
+    fn read_chunk(&self, key: ChunkDbKey) -> Result<Option<Change<CompressedChunk>>, BackendError> {
+        Ok(self.chunks.read().get(&key).cloned().map(Change::Insert))
+    }
+
+    fn write_chunks(
+        &self,
+        changes: Vec<(ChunkDbKey, Change<CompressedChunk>)>,
+    ) -> Result<(), BackendError> {
+        let mut chunks = self.chunks.write();
+        for (key, change) in changes.into_iter() {
+            match change {
+                Change::Insert(chunk) => {
+                    chunks.insert(key, chunk);
+                }
+                Change::Remove => {
+                    chunks.remove(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::Chunk;
+    use crate::core::glam::IVec3;
+    use crate::sdf::Sd8;
+
+    use grid_tree::NodeKey;
+
+    #[test]
+    fn write_read_and_remove_chunks() {
+        let backend = MemoryBackend::new(CompressionCodec::Zstd { level: 3 });
+        let key = ChunkDbKey::from(NodeKey::new(0, IVec3::new(1, -2, 3)));
+        assert!(backend.read_chunk(key).unwrap().is_none());
+
+        let mut chunk = Chunk::default();
+        chunk.set_voxel(IVec3::ONE, 4, Sd8::MIN);
+        backend
+            .write_chunks(vec![(
+                key,
+                Change::Insert(chunk.compress_with(backend.codec())),
+            )])
+            .unwrap();
+        assert_eq!(backend.num_chunks(), 1);
+        let read = backend
+            .read_chunk(key)
+            .unwrap()
+            .unwrap()
+            .into_insert()
+            .unwrap();
+        assert_eq!(read.decompress(), chunk);
+
+        backend.write_chunks(vec![(key, Change::Remove)]).unwrap();
+        assert!(backend.read_chunk(key).unwrap().is_none());
+        assert_eq!(backend.num_chunks(), 0);
+    }
+}
@@ -1,15 +1,31 @@
-use super::{AbortReason, ArchivedIVec, Version};
+use super::migration::{MAP_DB_FORMAT_VERSION, UNVERSIONED_FORMAT};
+use super::{corrupt_value, AbortReason, ArchivedIVec, ChunkCipher, ChunkDbKey, Version};
+use crate::chunk::{ChunkEdge, CompressionCodec, VoxelLayerSchema};
 use crate::core::rkyv::{
     ser::{serializers::CoreSerializer, Serializer},
     Archive, Deserialize, Serialize,
 };
+use crate::material::MaterialIds;
 
 use sled::{
     transaction::{TransactionError, TransactionalTree, UnabortableTransactionError},
-    Tree,
+    IVec, Tree,
 };
 
 const META_KEY: &str = "META";
+const CURRENT_BRANCH_KEY: &str = "CURRENT_BRANCH";
+// Stored separately from the metadata so that databases written before the codec was configurable are still readable.
+const CODEC_KEY: &str = "CODEC";
+const CIPHER_KEY: &str = "CIPHER";
+const KEY_CHECK_KEY: &str = "KEY_CHECK";
+const ENCRYPTION_PENDING_KEY: &str = "ENCRYPTION_PENDING";
+const LOAD_JOURNAL_KEY: &str = "LOAD_JOURNAL";
+const FORMAT_VERSION_KEY: &str = "FORMAT_VERSION";
+const LAYER_SCHEMA_KEY: &str = "LAYER_SCHEMA";
+const MATERIAL_IDS_KEY: &str = "MATERIAL_IDS";
+const CHUNK_EDGE_KEY: &str = "CHUNK_EDGE";
+const SEQUENCE_NUMBER_KEY: &str = "SEQUENCE_NUMBER";
+const DELTA_HEAD_KEY: &str = "DELTA_HEAD";
 
 #[derive(Archive, Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
 #[archive(crate = "crate::core::rkyv")]
@@ -38,6 +54,11 @@ pub fn open_meta_tree(
                 working_version,
             };
             write_meta(txn, &meta)?;
+            // New databases don't need any migrations.
+            txn.insert(
+                FORMAT_VERSION_KEY,
+                MAP_DB_FORMAT_VERSION.to_le_bytes().as_ref(),
+            )?;
             Ok(meta)
         }
     })?;
@@ -67,6 +88,227 @@ pub fn read_meta(
     Ok(data.map(|b| unsafe { ArchivedIVec::<MapDbMetadata>::new(b) }))
 }
 
+/// Sets the branch whose head follows the parent version. `None` means the working version isn't on any branch.
+pub fn write_current_branch(
+    txn: &TransactionalTree,
+    branch: Option<&str>,
+) -> Result<(), UnabortableTransactionError> {
+    if let Some(branch) = branch {
+        txn.insert(CURRENT_BRANCH_KEY, branch.as_bytes())?;
+    } else {
+        txn.remove(CURRENT_BRANCH_KEY)?;
+    }
+    Ok(())
+}
+
+pub fn read_current_branch(tree: &Tree) -> sled::Result<Option<String>> {
+    let data = tree.get(CURRENT_BRANCH_KEY)?;
+    data.map(|b| String::from_utf8(b.to_vec()).map_err(|_| corrupt_meta_value(CURRENT_BRANCH_KEY)))
+        .transpose()
+}
+
+/// Sets the codec used for new chunk writes.
+pub fn write_codec(tree: &Tree, codec: CompressionCodec) -> sled::Result<()> {
+    let mut serializer = CoreSerializer::<16, 0>::default();
+    serializer.serialize_value(&codec).unwrap();
+    let bytes = serializer.into_serializer().into_inner();
+
+    tree.insert(CODEC_KEY, bytes.as_ref())?;
+
+    Ok(())
+}
+
+/// Returns the default codec if none was ever written.
+pub fn read_codec(tree: &Tree) -> sled::Result<CompressionCodec> {
+    let data = tree.get(CODEC_KEY)?;
+    Ok(data
+        .map(|b| unsafe { ArchivedIVec::<CompressionCodec>::new(b) }.deserialize())
+        .unwrap_or_default())
+}
+
+/// Sets the cipher used for new chunk writes.
+pub fn write_cipher(
+    txn: &TransactionalTree,
+    cipher: ChunkCipher,
+) -> Result<(), UnabortableTransactionError> {
+    let mut serializer = CoreSerializer::<16, 0>::default();
+    serializer.serialize_value(&cipher).unwrap();
+    let bytes = serializer.into_serializer().into_inner();
+
+    txn.insert(CIPHER_KEY, bytes.as_ref())?;
+
+    Ok(())
+}
+
+/// Returns [`ChunkCipher::None`] if no cipher was ever written.
+pub fn read_cipher(tree: &Tree) -> sled::Result<ChunkCipher> {
+    let data = tree.get(CIPHER_KEY)?;
+    Ok(data
+        .map(|b| unsafe { ArchivedIVec::<ChunkCipher>::new(b) }.deserialize())
+        .unwrap_or_default())
+}
+
+/// Stores a value encrypted with the key of the [`ChunkCipher`], which is checked when the database is opened.
+pub fn write_key_check(
+    txn: &TransactionalTree,
+    bytes: &[u8],
+) -> Result<(), UnabortableTransactionError> {
+    txn.insert(KEY_CHECK_KEY, bytes)?;
+    Ok(())
+}
+
+pub fn read_key_check(tree: &Tree) -> sled::Result<Option<IVec>> {
+    tree.get(KEY_CHECK_KEY)
+}
+
+/// Marks that chunks written before the map was encrypted may still be stored in the clear.
+pub fn write_encryption_pending(
+    txn: &TransactionalTree,
+) -> Result<(), UnabortableTransactionError> {
+    txn.insert(ENCRYPTION_PENDING_KEY, &[])?;
+    Ok(())
+}
+
+pub fn clear_encryption_pending(tree: &Tree) -> sled::Result<()> {
+    tree.remove(ENCRYPTION_PENDING_KEY)?;
+    Ok(())
+}
+
+pub fn read_encryption_pending(tree: &Tree) -> sled::Result<bool> {
+    tree.contains_key(ENCRYPTION_PENDING_KEY)
+}
+
+/// Replaces the schema of the voxel layers stored in the layer tree.
+pub fn write_layer_schema(tree: &Tree, schema: &VoxelLayerSchema) -> sled::Result<()> {
+    let bytes = crate::core::rkyv::to_bytes::<_, 256>(schema).unwrap();
+
+    tree.insert(LAYER_SCHEMA_KEY, bytes.as_ref())?;
+
+    Ok(())
+}
+
+/// Returns an empty schema if none was ever written.
+pub fn read_layer_schema(tree: &Tree) -> sled::Result<VoxelLayerSchema> {
+    let data = tree.get(LAYER_SCHEMA_KEY)?;
+    Ok(data
+        .map(|b| unsafe { ArchivedIVec::<VoxelLayerSchema>::new(b) }.deserialize())
+        .unwrap_or_default())
+}
+
+/// Replaces the IDs of the named voxel materials.
+pub fn write_material_ids(tree: &Tree, ids: &MaterialIds) -> sled::Result<()> {
+    let bytes = crate::core::rkyv::to_bytes::<_, 256>(ids).unwrap();
+
+    tree.insert(MATERIAL_IDS_KEY, bytes.as_ref())?;
+
+    Ok(())
+}
+
+/// Returns no IDs if none were ever written.
+pub fn read_material_ids(tree: &Tree) -> sled::Result<MaterialIds> {
+    let data = tree.get(MATERIAL_IDS_KEY)?;
+    Ok(data
+        .map(|b| unsafe { ArchivedIVec::<MaterialIds>::new(b) }.deserialize())
+        .unwrap_or_default())
+}
+
+/// Records that the database was upgraded to `version`.
+pub fn write_format_version(tree: &Tree, version: u32) -> sled::Result<()> {
+    tree.insert(FORMAT_VERSION_KEY, version.to_le_bytes().as_ref())?;
+    Ok(())
+}
+
+/// Databases written before the format version was stored are [`UNVERSIONED_FORMAT`].
+pub fn read_format_version(tree: &Tree) -> sled::Result<u32> {
+    let data = tree.get(FORMAT_VERSION_KEY)?;
+    data.map_or(Ok(UNVERSIONED_FORMAT), |b| {
+        fixed_bytes(&b, FORMAT_VERSION_KEY).map(u32::from_le_bytes)
+    })
+}
+
+/// Records the edge length of the chunks in the database. Only written when the map is created.
+pub fn write_chunk_edge(tree: &Tree, edge: ChunkEdge) -> sled::Result<()> {
+    tree.insert(CHUNK_EDGE_KEY, edge.length().to_le_bytes().as_ref())?;
+    Ok(())
+}
+
+/// Returns `None` if the edge length was never written, i.e. the map is new or it was created before the edge length was
+/// stored, when every chunk was 16 voxels long.
+pub fn read_chunk_edge(tree: &Tree) -> sled::Result<Option<u32>> {
+    let data = tree.get(CHUNK_EDGE_KEY)?;
+    data.map(|b| fixed_bytes(&b, CHUNK_EDGE_KEY).map(u32::from_le_bytes))
+        .transpose()
+}
+
+/// Increments the stored sequence number and returns it. The first sequence number is 1.
+///
+/// A corrupt sequence number is left as it is, and returned as an error.
+pub fn next_sequence_number(tree: &Tree) -> sled::Result<u64> {
+    let mut next = Ok(1);
+    // The closure runs again if another writer changed the number first.
+    tree.update_and_fetch(SEQUENCE_NUMBER_KEY, |old| {
+        next = old
+            .map_or(Ok(0), |b| {
+                fixed_bytes(b, SEQUENCE_NUMBER_KEY).map(u64::from_le_bytes)
+            })
+            .map(|n| n + 1);
+        match &next {
+            Ok(n) => Some(n.to_le_bytes().to_vec()),
+            Err(_) => old.map(<[u8]>::to_vec),
+        }
+    })?;
+    next
+}
+
+/// Records the head version of the last delta file applied to the map.
+pub fn write_delta_head(tree: &Tree, version: Version) -> sled::Result<()> {
+    tree.insert(DELTA_HEAD_KEY, version.number.to_le_bytes().as_ref())?;
+    Ok(())
+}
+
+/// Returns `None` if no delta file was ever applied.
+pub fn read_delta_head(tree: &Tree) -> sled::Result<Option<Version>> {
+    let data = tree.get(DELTA_HEAD_KEY)?;
+    data.map(|b| {
+        Ok(Version::new(u64::from_le_bytes(fixed_bytes(
+            &b,
+            DELTA_HEAD_KEY,
+        )?)))
+    })
+    .transpose()
+}
+
+/// Replaces the load journal with `keys`, stored as concatenated sled keys.
+pub fn write_load_journal(tree: &Tree, keys: &[ChunkDbKey]) -> sled::Result<()> {
+    let mut bytes = Vec::with_capacity(13 * keys.len());
+    for key in keys.iter() {
+        bytes.extend_from_slice(&key.into_sled_key());
+    }
+
+    tree.insert(LOAD_JOURNAL_KEY, bytes)?;
+
+    Ok(())
+}
+
+/// Returns an empty journal if none was ever written.
+pub fn read_load_journal(tree: &Tree) -> sled::Result<Vec<ChunkDbKey>> {
+    let data = tree.get(LOAD_JOURNAL_KEY)?;
+    Ok(data
+        .map(|b| b.chunks_exact(13).map(ChunkDbKey::from_sled_key).collect())
+        .unwrap_or_default())
+}
+
+/// The bytes of a fixed-size value stored at `key`. Fails if the value has the wrong size, which means the database is
+/// corrupt, since every value is written with its size.
+fn fixed_bytes<const N: usize>(bytes: &[u8], key: &str) -> sled::Result<[u8; N]> {
+    bytes.try_into().map_err(|_| corrupt_meta_value(key))
+}
+
+/// The error for a value at `key` that couldn't have been written by this module.
+fn corrupt_meta_value(key: &str) -> sled::Error {
+    corrupt_value(format_args!("{} value in the meta tree", key))
+}
+
 // ████████╗███████╗███████╗████████╗
 // ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
 //    ██║   █████╗  ███████╗   ██║
@@ -77,6 +319,9 @@ pub fn read_meta(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::glam::IVec3;
+
+    use grid_tree::NodeKey;
 
     #[test]
     fn open_write_and_reopen_meta_tree() {
@@ -99,4 +344,74 @@ mod tests {
         let (_tree, cached_meta) = open_meta_tree("mymap", &db).unwrap();
         assert_eq!(cached_meta, new_meta);
     }
+
+    #[test]
+    fn write_and_read_codec() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let (tree, _) = open_meta_tree("mymap", &db).unwrap();
+
+        assert_eq!(read_codec(&tree).unwrap(), CompressionCodec::default());
+
+        let codec = CompressionCodec::Zstd { level: 9 };
+        write_codec(&tree, codec).unwrap();
+        assert_eq!(read_codec(&tree).unwrap(), codec);
+    }
+
+    #[test]
+    fn write_and_read_layer_schema() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let (tree, _) = open_meta_tree("mymap", &db).unwrap();
+
+        assert!(read_layer_schema(&tree).unwrap().is_empty());
+
+        let mut schema = VoxelLayerSchema::default();
+        schema.register::<u8>("light", 4);
+        schema.register::<i16>("temperature", 12);
+        write_layer_schema(&tree, &schema).unwrap();
+        assert_eq!(read_layer_schema(&tree).unwrap(), schema);
+    }
+
+    #[test]
+    fn write_and_read_load_journal() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let (tree, _) = open_meta_tree("mymap", &db).unwrap();
+
+        assert!(read_load_journal(&tree).unwrap().is_empty());
+
+        let keys = [
+            ChunkDbKey::from(NodeKey::new(0, IVec3::new(-1, 2, 3))),
+            ChunkDbKey::from(NodeKey::new(4, IVec3::new(5, -6, 7))),
+        ];
+        write_load_journal(&tree, &keys).unwrap();
+        assert_eq!(read_load_journal(&tree).unwrap(), keys);
+    }
+
+    #[test]
+    fn corrupt_values_are_errors() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let (tree, _) = open_meta_tree("mymap", &db).unwrap();
+
+        assert_eq!(read_delta_head(&tree).unwrap(), None);
+        write_delta_head(&tree, Version::new(3)).unwrap();
+        assert_eq!(read_delta_head(&tree).unwrap(), Some(Version::new(3)));
+        tree.insert(DELTA_HEAD_KEY, &[1, 2, 3][..]).unwrap();
+        assert!(read_delta_head(&tree).is_err());
+
+        tree.insert(CURRENT_BRANCH_KEY, &[0xFF, 0xFE][..]).unwrap();
+        assert!(read_current_branch(&tree).is_err());
+
+        tree.insert(FORMAT_VERSION_KEY, &[1][..]).unwrap();
+        assert!(read_format_version(&tree).is_err());
+        tree.insert(CHUNK_EDGE_KEY, &[1][..]).unwrap();
+        assert!(read_chunk_edge(&tree).is_err());
+
+        assert_eq!(next_sequence_number(&tree).unwrap(), 1);
+        tree.insert(SEQUENCE_NUMBER_KEY, &[1, 2, 3][..]).unwrap();
+        assert!(next_sequence_number(&tree).is_err());
+        // The corrupt number isn't replaced.
+        assert_eq!(
+            &*tree.get(SEQUENCE_NUMBER_KEY).unwrap().unwrap(),
+            &[1, 2, 3][..]
+        );
+    }
 }
@@ -0,0 +1,189 @@
+use super::ChunkDbKey;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sled::Tree;
+
+/// Game data that is stored with a chunk, like chests, spawners, or navmesh fragments.
+///
+/// Each type is stored separately under its [`ChunkMetadata::NAME`], so several types can be attached to the same chunk. Blobs
+/// are serialized with `bincode` and tagged with the [`ChunkMetadata::VERSION`] they were written with.
+pub trait ChunkMetadata: Serialize + DeserializeOwned + Send + Sync + 'static {
+    /// Must be unique among the metadata types of a map.
+    const NAME: &'static str;
+    /// Increment this when the serialized form changes, and handle the old versions in [`ChunkMetadata::migrate`].
+    const VERSION: u32;
+
+    /// Reads a blob written with an older `version`. By default, old blobs can't be read.
+    fn migrate(version: u32, bytes: &[u8]) -> Option<Self> {
+        let _ = (version, bytes);
+        None
+    }
+}
+
+/// The error from reading or writing [`ChunkMetadata`].
+#[derive(Debug)]
+pub enum MetadataError {
+    Database(sled::Error),
+    Serialization(bincode::Error),
+    /// The blob was written with a version that [`ChunkMetadata::migrate`] can't read, or it's too short to have a version.
+    UnsupportedVersion {
+        name: &'static str,
+        version: u32,
+    },
+}
+
+impl From<sled::Error> for MetadataError {
+    fn from(e: sled::Error) -> Self {
+        Self::Database(e)
+    }
+}
+
+impl From<bincode::Error> for MetadataError {
+    fn from(e: bincode::Error) -> Self {
+        Self::Serialization(e)
+    }
+}
+
+pub fn open_metadata_tree(map_name: &str, db: &sled::Db) -> sled::Result<Tree> {
+    db.open_tree(format!("{}-metadata", map_name))
+}
+
+/// Entries are keyed by the chunk's sled key followed by the metadata name, so all of a chunk's metadata is contiguous.
+fn metadata_key<M: ChunkMetadata>(key: ChunkDbKey) -> Vec<u8> {
+    let mut bytes = key.into_sled_key().to_vec();
+    bytes.extend_from_slice(M::NAME.as_bytes());
+    bytes
+}
+
+pub fn write_metadata<M: ChunkMetadata>(
+    tree: &Tree,
+    key: ChunkDbKey,
+    metadata: &M,
+) -> Result<(), MetadataError> {
+    let mut bytes = M::VERSION.to_le_bytes().to_vec();
+    bincode::serialize_into(&mut bytes, metadata)?;
+    tree.insert(metadata_key::<M>(key), bytes)?;
+    Ok(())
+}
+
+pub fn read_metadata<M: ChunkMetadata>(
+    tree: &Tree,
+    key: ChunkDbKey,
+) -> Result<Option<M>, MetadataError> {
+    let bytes = if let Some(bytes) = tree.get(metadata_key::<M>(key))? {
+        bytes
+    } else {
+        return Ok(None);
+    };
+    if bytes.len() < 4 {
+        return Err(MetadataError::UnsupportedVersion {
+            name: M::NAME,
+            version: 0,
+        });
+    }
+    let (version_bytes, blob) = bytes.split_at(4);
+    let version = u32::from_le_bytes(version_bytes.try_into().unwrap());
+    if version == M::VERSION {
+        return Ok(Some(bincode::deserialize(blob)?));
+    }
+    M::migrate(version, blob)
+        .map(Some)
+        .ok_or(MetadataError::UnsupportedVersion {
+            name: M::NAME,
+            version,
+        })
+}
+
+pub fn remove_metadata<M: ChunkMetadata>(tree: &Tree, key: ChunkDbKey) -> sled::Result<()> {
+    tree.remove(metadata_key::<M>(key))?;
+    Ok(())
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::glam::IVec3;
+    use crate::database::MapDb;
+
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Chests {
+        positions: Vec<[i32; 3]>,
+    }
+
+    impl ChunkMetadata for Chests {
+        const NAME: &'static str = "chests";
+        const VERSION: u32 = 2;
+
+        fn migrate(version: u32, bytes: &[u8]) -> Option<Self> {
+            // Version 1 only stored a single chest.
+            if version != 1 {
+                return None;
+            }
+            Some(Self {
+                positions: vec![bincode::deserialize(bytes).ok()?],
+            })
+        }
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Spawner(u32);
+
+    impl ChunkMetadata for Spawner {
+        const NAME: &'static str = "spawner";
+        const VERSION: u32 = 1;
+    }
+
+    #[test]
+    fn write_read_and_migrate_metadata() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let map = MapDb::open(&db, "mymap").unwrap();
+        let key = ChunkDbKey::new(0, IVec3::new(1, -2, 3).into());
+
+        assert_eq!(map.read_chunk_metadata::<Chests>(key).unwrap(), None);
+        let chests = Chests {
+            positions: vec![[1, 2, 3], [4, 5, 6]],
+        };
+        map.write_chunk_metadata(key, &chests).unwrap();
+        map.write_chunk_metadata(key, &Spawner(9)).unwrap();
+        assert_eq!(map.read_chunk_metadata(key).unwrap(), Some(chests));
+        assert_eq!(map.read_chunk_metadata(key).unwrap(), Some(Spawner(9)));
+
+        // A blob from version 1 is migrated.
+        let mut old = 1u32.to_le_bytes().to_vec();
+        bincode::serialize_into(&mut old, &[7, 8, 9]).unwrap();
+        map.metadata_tree
+            .insert(metadata_key::<Chests>(key), old)
+            .unwrap();
+        assert_eq!(
+            map.read_chunk_metadata(key).unwrap(),
+            Some(Chests {
+                positions: vec![[7, 8, 9]]
+            })
+        );
+
+        // A blob from a newer version can't be read.
+        map.metadata_tree
+            .insert(metadata_key::<Spawner>(key), 5u32.to_le_bytes().to_vec())
+            .unwrap();
+        assert!(matches!(
+            map.read_chunk_metadata::<Spawner>(key),
+            Err(MetadataError::UnsupportedVersion {
+                name: "spawner",
+                version: 5
+            })
+        ));
+
+        map.remove_chunk_metadata::<Chests>(key).unwrap();
+        assert_eq!(map.read_chunk_metadata::<Chests>(key).unwrap(), None);
+    }
+}
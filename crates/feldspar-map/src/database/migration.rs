@@ -0,0 +1,159 @@
+//! Upgrades databases written by older versions of this crate.
+//!
+//! The meta tree stores the format version of the database. [`MapDb::open`] runs every [`Migration`] from that version up to
+//! [`MAP_DB_FORMAT_VERSION`], in order, and records the new version after each one, so an interrupted upgrade picks up where
+//! it left off. Migrations must be safe to run again after they're interrupted.
+//!
+//! Any change to the chunk encoding, the key layout, or the trees must bump [`MAP_DB_FORMAT_VERSION`] and register a migration
+//! in [`MIGRATIONS`].
+
+use super::checksum_tree::record_checksum;
+use super::meta_tree::{read_format_version, write_format_version};
+use super::{AbortReason, MapDb, BULK_WRITE_BATCH_SIZE};
+
+use sled::transaction::TransactionError;
+
+/// The newest version of the database format, which is written by new databases.
+pub const MAP_DB_FORMAT_VERSION: u32 = 2;
+
+/// The version of databases written before the format version was stored.
+pub const UNVERSIONED_FORMAT: u32 = 1;
+
+/// Upgrades a database from `from_version` to `from_version + 1` in place.
+pub struct Migration {
+    pub from_version: u32,
+    pub description: &'static str,
+    /// Reports progress after every batch of records.
+    pub run: fn(&MapDb, &mut dyn FnMut(MigrationProgress)) -> sled::Result<()>,
+}
+
+/// All migrations, sorted by `from_version`. Every version older than [`MAP_DB_FORMAT_VERSION`] has exactly one.
+pub const MIGRATIONS: &[Migration] = &[Migration {
+    from_version: 1,
+    description: "Add checksums to chunk records",
+    run: add_checksums,
+}];
+
+/// How far a [`Migration`] has gotten.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MigrationProgress {
+    /// The version that the database is being upgraded to.
+    pub to_version: u32,
+    pub description: &'static str,
+    pub records_done: usize,
+    pub records_total: usize,
+}
+
+/// Upgrades `map` to [`MAP_DB_FORMAT_VERSION`]. Fails without changing anything if `map` was written by a newer format.
+pub fn run_migrations(
+    map: &MapDb,
+    progress: &mut dyn FnMut(MigrationProgress),
+) -> Result<(), TransactionError<AbortReason>> {
+    let version = read_format_version(&map.meta_tree)?;
+    if version > MAP_DB_FORMAT_VERSION {
+        return Err(TransactionError::Abort(
+            AbortReason::UnsupportedFormatVersion(version),
+        ));
+    }
+    for migration in MIGRATIONS.iter().filter(|m| m.from_version >= version) {
+        log::info!(
+            "Migrating map from format version {}: {}",
+            migration.from_version,
+            migration.description
+        );
+        (migration.run)(map, progress)?;
+        write_format_version(&map.meta_tree, migration.from_version + 1)?;
+    }
+    Ok(())
+}
+
+/// Version 2 verifies every record of the working tree against a checksum. Records that don't have one get it now.
+fn add_checksums(map: &MapDb, progress: &mut dyn FnMut(MigrationProgress)) -> sled::Result<()> {
+    let mut report = MigrationProgress {
+        to_version: 2,
+        description: MIGRATIONS[0].description,
+        records_done: 0,
+        records_total: map.working_tree.len(),
+    };
+    let mut batch = sled::Batch::default();
+    let mut batch_size = 0;
+    for entry in map.working_tree.iter() {
+        let (key_bytes, record) = entry?;
+        if !map.checksum_tree.contains_key(&key_bytes)? {
+            batch.insert(key_bytes, record_checksum(&record).as_ref());
+        }
+        batch_size += 1;
+        if batch_size == BULK_WRITE_BATCH_SIZE {
+            map.checksum_tree.apply_batch(std::mem::take(&mut batch))?;
+            report.records_done += batch_size;
+            batch_size = 0;
+            progress(report);
+        }
+    }
+    map.checksum_tree.apply_batch(batch)?;
+    report.records_done += batch_size;
+    progress(report);
+    Ok(())
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::Chunk;
+    use crate::core::glam::IVec3;
+    use crate::database::{Change, ChangeEncoder, ChunkDbKey};
+
+    #[test]
+    fn migrations_cover_every_old_version() {
+        for (migration, version) in MIGRATIONS.iter().zip(UNVERSIONED_FORMAT..) {
+            assert_eq!(migration.from_version, version);
+        }
+        assert_eq!(
+            UNVERSIONED_FORMAT + MIGRATIONS.len() as u32,
+            MAP_DB_FORMAT_VERSION
+        );
+    }
+
+    #[test]
+    fn upgrade_unversioned_database() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let key = ChunkDbKey::new(0, IVec3::ZERO.into());
+        {
+            let mut map = MapDb::open(&db, "mymap").unwrap();
+            let mut encoder = ChangeEncoder::default();
+            encoder.add_compressed_change(key, Change::Insert(Chunk::default().compress()));
+            map.write_working_version(encoder.encode()).unwrap();
+
+            // Make it look like it was written before checksums and format versions.
+            map.checksum_tree.clear().unwrap();
+            map.meta_tree.remove("FORMAT_VERSION").unwrap();
+        }
+
+        let mut reports = Vec::new();
+        let map = MapDb::open_with_progress(&db, "mymap", |p| reports.push(p)).unwrap();
+        assert_eq!(
+            read_format_version(&map.meta_tree).unwrap(),
+            MAP_DB_FORMAT_VERSION
+        );
+        assert_eq!(reports.last().unwrap().records_done, 1);
+        assert_eq!(reports.last().unwrap().records_total, 1);
+        assert!(map.checksum_tree.contains_key(key.into_sled_key()).unwrap());
+
+        // Newer databases are refused.
+        write_format_version(&map.meta_tree, MAP_DB_FORMAT_VERSION + 1).unwrap();
+        drop(map);
+        assert!(matches!(
+            MapDb::open(&db, "mymap"),
+            Err(TransactionError::Abort(
+                AbortReason::UnsupportedFormatVersion(_)
+            ))
+        ));
+    }
+}
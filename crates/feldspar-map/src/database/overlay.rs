@@ -0,0 +1,116 @@
+use super::{store_removals_as_air, BackendError, Change, ChunkDbKey, MapBackend};
+use crate::chunk::{CompressedChunk, CompressionCodec};
+
+use std::sync::Arc;
+
+/// A [`MapBackend`] that reads through to a `base` backend, which is never written, and stores all changes in an `overlay`.
+///
+/// Useful for shipping a base map on read-only media while persisting player changes locally, or for letting players edit
+/// a map without saving anything with a [`MemoryBackend`](super::MemoryBackend) overlay.
+pub struct OverlayBackend {
+    base: Arc<dyn MapBackend>,
+    overlay: Arc<dyn MapBackend>,
+}
+
+impl OverlayBackend {
+    pub fn new(base: Arc<dyn MapBackend>, overlay: Arc<dyn MapBackend>) -> Self {
+        Self { base, overlay }
+    }
+
+    pub fn base(&self) -> &Arc<dyn MapBackend> {
+        &self.base
+    }
+
+    pub fn overlay(&self) -> &Arc<dyn MapBackend> {
+        &self.overlay
+    }
+}
+
+impl MapBackend for OverlayBackend {
+    fn codec(&self) -> CompressionCodec {
+        self.overlay.codec()
+    }
+
+    fn read_chunk(&self, key: ChunkDbKey) -> Result<Option<Change<CompressedChunk>>, BackendError> {
+        if let Some(change) = self.overlay.read_chunk(key)? {
+            return Ok(Some(change));
+        }
+        self.base.read_chunk(key)
+    }
+
+    fn write_chunks(
+        &self,
+        changes: Vec<(ChunkDbKey, Change<CompressedChunk>)>,
+    ) -> Result<(), BackendError> {
+        let changes = store_removals_as_air(changes, |key| {
+            Ok(matches!(
+                self.base.read_chunk(key)?,
+                Some(Change::Insert(_))
+            ))
+        })?;
+        self.overlay.write_chunks(changes)
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::{Chunk, UniformChunk};
+    use crate::core::glam::IVec3;
+    use crate::database::MemoryBackend;
+    use crate::sdf::Sd8;
+
+    #[test]
+    fn changes_only_go_to_the_overlay() {
+        let base = Arc::new(MemoryBackend::default());
+        let overlay = Arc::new(MemoryBackend::default());
+        let backend = OverlayBackend::new(base.clone(), overlay.clone());
+
+        let mut chunk = Chunk::default();
+        chunk.set_voxel(IVec3::new(1, 2, 3), 7, Sd8::MIN);
+        let base_key = ChunkDbKey::new(0, IVec3::ZERO.into());
+        let new_key = ChunkDbKey::new(0, IVec3::ONE.into());
+        base.write_chunks(vec![(base_key, Change::Insert(chunk.compress()))])
+            .unwrap();
+
+        // Reads fall through to the base.
+        assert_eq!(
+            backend.read_chunk(base_key).unwrap(),
+            Some(Change::Insert(chunk.compress()))
+        );
+
+        backend
+            .write_chunks(vec![
+                (base_key, Change::Remove),
+                (new_key, Change::Insert(chunk.compress())),
+            ])
+            .unwrap();
+        assert_eq!(
+            backend.read_chunk(base_key).unwrap(),
+            Some(Change::Insert(UniformChunk::Air.compress()))
+        );
+        assert_eq!(
+            backend.read_chunk(new_key).unwrap(),
+            Some(Change::Insert(chunk.compress()))
+        );
+        assert_eq!(base.num_chunks(), 1);
+        assert_eq!(overlay.num_chunks(), 2);
+
+        // Chunks that aren't in the base are really removed.
+        backend
+            .write_chunks(vec![(new_key, Change::Remove)])
+            .unwrap();
+        assert_eq!(backend.read_chunk(new_key).unwrap(), None);
+        assert_eq!(
+            base.read_chunk(base_key).unwrap(),
+            Some(Change::Insert(chunk.compress()))
+        );
+    }
+}
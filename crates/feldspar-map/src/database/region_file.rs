@@ -0,0 +1,151 @@
+//! A portable file format for regions of LOD0 chunks, written by [`MapDb::export_region`](crate::database::MapDb) and read by
+//! [`MapDb::import_region`](crate::database::MapDb).
+//!
+//! All integers are little-endian.
+//!
+//! ```text
+//! magic:          [u8; 8] = "FELDRGN\0"
+//! format_version: u32
+//! extent_min:     [i32; 3]  // the region's original minimum, in LOD0 chunk coordinates
+//! extent_shape:   [i32; 3]
+//! num_records:    u64
+//! records: [
+//!     offset:     [i32; 3]  // relative to extent_min
+//!     num_bytes:  u32
+//!     bytes:      [u8; num_bytes]  // as in Chunk::compress, i.e. dense and LZ4-compressed
+//! ]
+//! ```
+//!
+//! Empty chunks have no record. Records longer than [`MAX_COMPRESSED_CHUNK_BYTES`] are rejected.
+
+use super::{ChunkDbKey, ChunkReadError};
+use crate::chunk::MAX_COMPRESSED_CHUNK_BYTES;
+use crate::core::glam::IVec3;
+use crate::core::ilattice::prelude::Extent;
+
+use sled::transaction::TransactionError;
+use std::io::{self, Read, Write};
+
+const MAGIC: [u8; 8] = *b"FELDRGN\0";
+
+/// The newest version of the format. Readers accept all versions up to this one.
+pub const REGION_FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum RegionFileError {
+    Io(io::Error),
+    Database(TransactionError),
+    /// The file doesn't start with the magic bytes, so it's probably not a region file.
+    BadMagic,
+    /// The file was written by a newer version of the format.
+    UnsupportedVersion(u32),
+    /// A chunk in the exported region doesn't match its checksum, or it can't be decompressed.
+    Corrupt(ChunkDbKey),
+}
+
+impl From<io::Error> for RegionFileError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<sled::Error> for RegionFileError {
+    fn from(e: sled::Error) -> Self {
+        Self::Database(e.into())
+    }
+}
+
+impl From<ChunkReadError> for RegionFileError {
+    fn from(e: ChunkReadError) -> Self {
+        match e {
+            ChunkReadError::Database(e) => e.into(),
+            ChunkReadError::Corrupt(key) => Self::Corrupt(key),
+        }
+    }
+}
+
+impl From<TransactionError> for RegionFileError {
+    fn from(e: TransactionError) -> Self {
+        Self::Database(e)
+    }
+}
+
+pub struct RegionHeader {
+    pub format_version: u32,
+    pub extent: Extent<IVec3>,
+    pub num_records: u64,
+}
+
+pub fn write_region_header(mut writer: impl Write, header: &RegionHeader) -> io::Result<()> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&header.format_version.to_le_bytes())?;
+    write_ivec3(&mut writer, header.extent.minimum)?;
+    write_ivec3(&mut writer, header.extent.shape)?;
+    writer.write_all(&header.num_records.to_le_bytes())
+}
+
+pub fn read_region_header(mut reader: impl Read) -> Result<RegionHeader, RegionFileError> {
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(RegionFileError::BadMagic);
+    }
+    let format_version = read_u32(&mut reader)?;
+    if format_version > REGION_FORMAT_VERSION {
+        return Err(RegionFileError::UnsupportedVersion(format_version));
+    }
+    let minimum = read_ivec3(&mut reader)?;
+    let shape = read_ivec3(&mut reader)?;
+    let mut num_records = [0; 8];
+    reader.read_exact(&mut num_records)?;
+    Ok(RegionHeader {
+        format_version,
+        extent: Extent::from_min_and_shape(minimum, shape),
+        num_records: u64::from_le_bytes(num_records),
+    })
+}
+
+pub fn write_region_record(mut writer: impl Write, offset: IVec3, bytes: &[u8]) -> io::Result<()> {
+    write_ivec3(&mut writer, offset)?;
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(bytes)
+}
+
+/// Returns the offset and compressed bytes of the next record.
+pub fn read_region_record(mut reader: impl Read) -> io::Result<(IVec3, Box<[u8]>)> {
+    let offset = read_ivec3(&mut reader)?;
+    let num_bytes = read_u32(&mut reader)? as usize;
+    if num_bytes > MAX_COMPRESSED_CHUNK_BYTES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "region record of {} bytes is too long for a chunk",
+                num_bytes
+            ),
+        ));
+    }
+    let mut bytes = vec![0; num_bytes];
+    reader.read_exact(&mut bytes)?;
+    Ok((offset, bytes.into_boxed_slice()))
+}
+
+fn write_ivec3(mut writer: impl Write, v: IVec3) -> io::Result<()> {
+    for c in v.to_array() {
+        writer.write_all(&c.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_ivec3(mut reader: impl Read) -> io::Result<IVec3> {
+    let mut v = [0; 3];
+    for c in v.iter_mut() {
+        *c = read_u32(&mut reader)? as i32;
+    }
+    Ok(IVec3::from(v))
+}
+
+fn read_u32(mut reader: impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
@@ -0,0 +1,517 @@
+use super::archive_file::{
+    archive_data_start, read_archive_header, read_archive_index, ArchiveEntry, HEADER_BYTES,
+};
+use super::{
+    store_removals_as_air, BackendError, Change, ChunkDbKey, ChunkReadError, MapBackend, MapDb,
+    MemoryBackend,
+};
+use crate::chunk::{CompressedChunk, CompressionCodec};
+use crate::core::SmallKeyHashMap;
+
+use parking_lot::{Mutex, RwLock};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+
+/// Random access to the bytes of an [archive](super::archive_file), e.g. a file or an object in a remote store.
+pub trait RangeSource: Send + Sync {
+    /// Reads exactly `len` bytes starting at `offset`.
+    fn read_range(&self, offset: u64, len: usize) -> io::Result<Vec<u8>>;
+
+    /// The total number of bytes.
+    fn size(&self) -> io::Result<u64>;
+}
+
+pub struct FileRangeSource {
+    file: Mutex<File>,
+}
+
+impl FileRangeSource {
+    pub fn new(file: File) -> Self {
+        Self {
+            file: Mutex::new(file),
+        }
+    }
+}
+
+impl RangeSource for FileRangeSource {
+    fn read_range(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut file = self.file.lock();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut bytes = vec![0; len];
+        file.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.file.lock().metadata()?.len())
+    }
+}
+
+/// Fetches ranges of an object over HTTP(S) with `Range` requests. This works for any static file server, and for S3 and
+/// compatible object stores with public or presigned URLs.
+#[cfg(feature = "http")]
+pub struct HttpRangeSource {
+    agent: ureq::Agent,
+    url: String,
+}
+
+#[cfg(feature = "http")]
+impl HttpRangeSource {
+    pub fn new(url: String) -> Self {
+        Self {
+            agent: ureq::Agent::new(),
+            url,
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+impl RangeSource for HttpRangeSource {
+    fn read_range(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let last = offset + len as u64 - 1;
+        let response = self
+            .agent
+            .get(&self.url)
+            .set("Range", &format!("bytes={}-{}", offset, last))
+            .call()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        // A server that doesn't support ranges would send the whole object.
+        if response.status() != 206 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{} doesn't support range requests", self.url),
+            ));
+        }
+        let mut bytes = vec![0; len];
+        response.into_reader().read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        let response = self
+            .agent
+            .head(&self.url)
+            .call()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        response
+            .header("Content-Length")
+            .and_then(|length| length.parse().ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} has no Content-Length", self.url),
+                )
+            })
+    }
+}
+
+/// Where a [`RemoteBackend`] keeps the chunks that it fetched, and the chunks that were written to it.
+pub trait ChunkCache: MapBackend {
+    /// Stores a chunk that was just fetched from the archive, unless a chunk was written to `key` while it was fetched, and
+    /// returns the chunk that's cached now. A corrupt copy is replaced.
+    ///
+    /// The check and the insert have to be atomic, or a write that lands during the fetch is replaced by the archived chunk.
+    fn insert_fetched(
+        &self,
+        key: ChunkDbKey,
+        chunk: CompressedChunk,
+    ) -> Result<Change<CompressedChunk>, BackendError>;
+}
+
+impl ChunkCache for RwLock<MapDb> {
+    fn insert_fetched(
+        &self,
+        key: ChunkDbKey,
+        chunk: CompressedChunk,
+    ) -> Result<Change<CompressedChunk>, BackendError> {
+        // Every write takes the lock, so nothing is written between the read and the insert.
+        let mut db = self.write();
+        match db.read_working_version(key) {
+            Ok(Some(cached)) => return Ok(cached.deserialize()),
+            Ok(None) | Err(ChunkReadError::Corrupt(_)) => (),
+            Err(e) => return Err(e.into()),
+        }
+        // Fetched chunks match the archive, so they don't need to be versioned.
+        db.bulk_write_chunks(std::iter::once((key.into(), chunk.clone())))?;
+        Ok(Change::Insert(chunk))
+    }
+}
+
+/// Keeps everything in memory, e.g. where there's no filesystem for a [`MapDb`].
+impl ChunkCache for MemoryBackend {
+    fn insert_fetched(
+        &self,
+        key: ChunkDbKey,
+        chunk: CompressedChunk,
+    ) -> Result<Change<CompressedChunk>, BackendError> {
+        Ok(Change::Insert(self.insert_if_absent(key, chunk)))
+    }
+}
+
+/// A [`MapBackend`] that streams chunks on demand from an [archive](super::archive_file) that isn't stored locally, so thin
+/// clients can explore huge worlds.
+///
+/// Only the archive's index is fetched when it's opened. Each chunk is fetched the first time it's read, then kept in a local
+/// [`ChunkCache`], which is a [`MapDb`] unless the backend was opened with [`RemoteBackend::open_with_cache`]. The archive
+/// is read-only, so writes are kept in the cache, and they take precedence over the archive from then on.
+pub struct RemoteBackend<S, C = RwLock<MapDb>> {
+    source: S,
+    index: SmallKeyHashMap<ChunkDbKey, ArchiveEntry>,
+    data_start: u64,
+    /// The size of the archive.
+    num_bytes: u64,
+    cache: C,
+}
+
+impl<S: RangeSource> RemoteBackend<S> {
+    /// Fetches the header and index of the archive in `source`. Chunks are cached in the working version of `cache`.
+    pub fn open(source: S, cache: MapDb) -> Result<Self, BackendError> {
+        Self::open_with_cache(source, RwLock::new(cache))
+    }
+}
+
+impl<S: RangeSource, C: ChunkCache> RemoteBackend<S, C> {
+    /// Like [`RemoteBackend::open`], but chunks are cached in `cache`, e.g. a [`MemoryBackend`].
+    pub fn open_with_cache(source: S, cache: C) -> Result<Self, BackendError> {
+        let num_bytes = source.size()?;
+        let header = source.read_range(0, HEADER_BYTES as usize)?;
+        let num_entries = read_archive_header(&header)?;
+        let data_start = archive_data_start(num_entries, num_bytes)?;
+        let index_bytes = source.read_range(HEADER_BYTES, (data_start - HEADER_BYTES) as usize)?;
+        let index = read_archive_index(&index_bytes, num_entries)?;
+        log::info!("Opened remote archive with {} chunks", num_entries);
+        Ok(Self {
+            source,
+            index,
+            data_start,
+            num_bytes,
+            cache,
+        })
+    }
+
+    /// The number of chunks in the archive.
+    pub fn num_archived_chunks(&self) -> usize {
+        self.index.len()
+    }
+
+    /// The cached chunk at `key`. A corrupt copy counts as missing.
+    fn read_cached(
+        &self,
+        key: ChunkDbKey,
+    ) -> Result<Option<Change<CompressedChunk>>, BackendError> {
+        match self.cache.read_chunk(key) {
+            // Fetching it again replaces the corrupt copy, but any edits of it are lost.
+            Err(BackendError::Corrupt(_)) => {
+                log::error!("Corrupt cached copy of {:?}", key);
+                Ok(None)
+            }
+            result => result,
+        }
+    }
+
+    /// Where the chunk of `entry` is in the archive.
+    fn entry_range(&self, entry: &ArchiveEntry) -> Result<Range<u64>, BackendError> {
+        let start = self
+            .data_start
+            .checked_add(entry.offset)
+            .ok_or(BackendError::BadArchive)?;
+        let end = start
+            .checked_add(u64::from(entry.num_bytes))
+            .filter(|&end| end <= self.num_bytes)
+            .ok_or(BackendError::BadArchive)?;
+        Ok(start..end)
+    }
+
+    /// Caches the `bytes` that were fetched for the chunk at `key`, unless they're corrupt, since a cached chunk is never
+    /// fetched again.
+    fn cache_fetched(
+        &self,
+        key: ChunkDbKey,
+        bytes: Vec<u8>,
+    ) -> Result<Change<CompressedChunk>, BackendError> {
+        let chunk = CompressedChunk {
+            bytes: bytes.into_boxed_slice(),
+        };
+        if chunk.try_decompress().is_none() {
+            return Err(BackendError::Corrupt(key));
+        }
+        self.cache.insert_fetched(key, chunk)
+    }
+}
+
+impl<S: RangeSource, C: ChunkCache> MapBackend for RemoteBackend<S, C> {
+    fn codec(&self) -> CompressionCodec {
+        self.cache.codec()
+    }
+
+    fn read_chunk(&self, key: ChunkDbKey) -> Result<Option<Change<CompressedChunk>>, BackendError> {
+        Ok(self.read_chunks(&[key])?.pop().unwrap())
+    }
+
+    /// Fetches all of the chunks that aren't cached yet with one range request, which spans every byte between the first and
+    /// the last of them. The archive is sorted by LOD and Morton code, so nearby chunks of one LOD are close together.
+    fn read_chunks(
+        &self,
+        keys: &[ChunkDbKey],
+    ) -> Result<Vec<Option<Change<CompressedChunk>>>, BackendError> {
+        let mut chunks = Vec::with_capacity(keys.len());
+        let mut fetches = Vec::new();
+        for (i, &key) in keys.iter().enumerate() {
+            let cached = self.read_cached(key)?;
+            if cached.is_none() {
+                if let Some(entry) = self.index.get(&key) {
+                    fetches.push((i, self.entry_range(entry)?));
+                }
+            }
+            chunks.push(cached);
+        }
+
+        let start = if let Some(start) = fetches.iter().map(|(_, range)| range.start).min() {
+            start
+        } else {
+            return Ok(chunks);
+        };
+        let end = fetches.iter().map(|(_, range)| range.end).max().unwrap();
+        let bytes = self.source.read_range(start, (end - start) as usize)?;
+        for (i, range) in fetches.into_iter() {
+            let chunk_bytes = &bytes[(range.start - start) as usize..(range.end - start) as usize];
+            chunks[i] = Some(self.cache_fetched(keys[i], chunk_bytes.to_vec())?);
+        }
+        Ok(chunks)
+    }
+
+    fn write_chunks(
+        &self,
+        changes: Vec<(ChunkDbKey, Change<CompressedChunk>)>,
+    ) -> Result<(), BackendError> {
+        let changes = store_removals_as_air(changes, |key| Ok(self.index.contains_key(&key)))?;
+        self.cache.write_chunks(changes)
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::{Chunk, UniformChunk};
+    use crate::core::glam::IVec3;
+    use crate::database::open_temporary_map;
+    use crate::sdf::Sd8;
+
+    use grid_tree::NodeKey;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts the requests so tests can check what gets fetched.
+    struct MemoryRangeSource {
+        bytes: Vec<u8>,
+        num_requests: AtomicUsize,
+    }
+
+    impl RangeSource for MemoryRangeSource {
+        fn read_range(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+            self.num_requests.fetch_add(1, Ordering::Relaxed);
+            let start = offset as usize;
+            Ok(self.bytes[start..start + len].to_vec())
+        }
+
+        fn size(&self) -> io::Result<u64> {
+            Ok(self.bytes.len() as u64)
+        }
+    }
+
+    #[test]
+    fn stream_chunks_from_exported_archive() {
+        let mut server_db = open_temporary_map("server");
+        let mut chunk = Chunk::default();
+        chunk.set_voxel(IVec3::new(1, 2, 3), 7, Sd8::MIN);
+        let keys = [
+            NodeKey::new(0, IVec3::ZERO),
+            NodeKey::new(2, IVec3::new(-1, 4, 2)),
+        ];
+        server_db
+            .bulk_write_chunks(keys.iter().map(|&key| (key, chunk.compress())))
+            .unwrap();
+        let mut archive = Vec::new();
+        assert_eq!(server_db.export_archive(&mut archive).unwrap(), 2);
+
+        let source = MemoryRangeSource {
+            bytes: archive,
+            num_requests: AtomicUsize::new(0),
+        };
+        let backend = RemoteBackend::open(source, open_temporary_map("client")).unwrap();
+        assert_eq!(backend.num_archived_chunks(), 2);
+        assert_eq!(backend.source.num_requests.load(Ordering::Relaxed), 2);
+
+        let key = ChunkDbKey::from(keys[1]);
+        let read = backend
+            .read_chunk(key)
+            .unwrap()
+            .unwrap()
+            .into_insert()
+            .unwrap();
+        assert_eq!(read.decompress(), chunk);
+        // Cached after the first read.
+        backend.read_chunk(key).unwrap();
+        assert_eq!(backend.source.num_requests.load(Ordering::Relaxed), 3);
+
+        let missing = ChunkDbKey::from(NodeKey::new(0, IVec3::ONE));
+        assert!(backend.read_chunk(missing).unwrap().is_none());
+
+        // Removals shadow the archive.
+        let key = ChunkDbKey::from(keys[0]);
+        backend.write_chunks(vec![(key, Change::Remove)]).unwrap();
+        let read = backend
+            .read_chunk(key)
+            .unwrap()
+            .unwrap()
+            .into_insert()
+            .unwrap();
+        assert_eq!(read.uniform(), Some(UniformChunk::Air));
+    }
+
+    #[test]
+    fn fetch_batch_with_one_request() {
+        let mut server_db = open_temporary_map("server");
+        let keys: Vec<_> = (0..4)
+            .map(|x| NodeKey::new(0, IVec3::new(x, 0, 0)))
+            .collect();
+        let mut chunk = Chunk::default();
+        chunk.set_voxel(IVec3::new(1, 2, 3), 7, Sd8::MIN);
+        server_db
+            .bulk_write_chunks(keys.iter().map(|&key| (key, chunk.compress())))
+            .unwrap();
+        let mut archive = Vec::new();
+        server_db.export_archive(&mut archive).unwrap();
+
+        let source = MemoryRangeSource {
+            bytes: archive,
+            num_requests: AtomicUsize::new(0),
+        };
+        let cache = MemoryBackend::new(CompressionCodec::default());
+        let backend = RemoteBackend::open_with_cache(source, cache).unwrap();
+        let missing = ChunkDbKey::from(NodeKey::new(0, IVec3::ONE));
+        let cached = ChunkDbKey::from(keys[3]);
+        backend.read_chunk(cached).unwrap();
+        assert_eq!(backend.source.num_requests.load(Ordering::Relaxed), 3);
+
+        let batch = [
+            keys[2].into(),
+            missing,
+            keys[0].into(),
+            cached,
+            keys[1].into(),
+        ];
+        let chunks = backend.read_chunks(&batch).unwrap();
+        assert_eq!(backend.source.num_requests.load(Ordering::Relaxed), 4);
+        assert!(chunks[1].is_none());
+        for i in [0, 2, 3, 4] {
+            let read = chunks[i].clone().unwrap().into_insert().unwrap();
+            assert_eq!(read.decompress(), chunk);
+        }
+        assert_eq!(backend.cache.num_chunks(), 4);
+    }
+
+    #[test]
+    fn corrupt_fetched_chunks_are_not_cached() {
+        let mut server_db = open_temporary_map("server");
+        let key = NodeKey::new(0, IVec3::ZERO);
+        let mut chunk = Chunk::default();
+        chunk.set_voxel(IVec3::new(1, 2, 3), 7, Sd8::MIN);
+        server_db
+            .bulk_write_chunks(std::iter::once((key, chunk.compress())))
+            .unwrap();
+        let mut archive = Vec::new();
+        server_db.export_archive(&mut archive).unwrap();
+        let data_start = archive_data_start(1, archive.len() as u64).unwrap() as usize;
+        archive[data_start..].fill(0xFF);
+
+        let source = MemoryRangeSource {
+            bytes: archive,
+            num_requests: AtomicUsize::new(0),
+        };
+        let cache = MemoryBackend::new(CompressionCodec::default());
+        let backend = RemoteBackend::open_with_cache(source, cache).unwrap();
+        let key = ChunkDbKey::from(key);
+        assert!(matches!(
+            backend.read_chunk(key),
+            Err(BackendError::Corrupt(k)) if k == key
+        ));
+        assert_eq!(backend.cache.num_chunks(), 0);
+    }
+
+    #[test]
+    fn stream_chunks_into_memory_cache() {
+        let mut server_db = open_temporary_map("server");
+        let key = NodeKey::new(0, IVec3::ZERO);
+        server_db
+            .bulk_write_chunks(std::iter::once((key, Chunk::default().compress())))
+            .unwrap();
+        let mut archive = Vec::new();
+        server_db.export_archive(&mut archive).unwrap();
+
+        let source = MemoryRangeSource {
+            bytes: archive,
+            num_requests: AtomicUsize::new(0),
+        };
+        let cache = MemoryBackend::new(CompressionCodec::default());
+        let backend = RemoteBackend::open_with_cache(source, cache).unwrap();
+        let key = ChunkDbKey::from(key);
+        assert!(backend.read_chunk(key).unwrap().is_some());
+        assert_eq!(backend.cache.num_chunks(), 1);
+        backend.read_chunk(key).unwrap();
+        assert_eq!(backend.source.num_requests.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn fetched_chunks_dont_replace_writes() {
+        let archived = Chunk::default().compress();
+        let mut written = Chunk::default();
+        written.set_voxel(IVec3::ONE, 3, Sd8::MIN);
+        let key = ChunkDbKey::from(NodeKey::new(0, IVec3::ZERO));
+
+        // E.g. the chunk was written while it was fetched.
+        let cache = MemoryBackend::new(CompressionCodec::default());
+        cache
+            .write_chunks(vec![(key, Change::Insert(written.compress()))])
+            .unwrap();
+        let cached = cache.insert_fetched(key, archived.clone()).unwrap();
+        assert_eq!(cached.into_insert().unwrap().decompress(), written);
+
+        let db = RwLock::new(open_temporary_map("client"));
+        db.write_chunks(vec![(key, Change::Insert(written.compress()))])
+            .unwrap();
+        let cached = db.insert_fetched(key, archived).unwrap();
+        assert_eq!(cached.into_insert().unwrap().decompress(), written);
+    }
+
+    #[test]
+    fn reject_index_larger_than_archive() {
+        let mut archive = Vec::new();
+        open_temporary_map("server")
+            .export_archive(&mut archive)
+            .unwrap();
+        // Claim so many entries that the size of the index overflows.
+        let num_entries_start = HEADER_BYTES as usize - 8;
+        archive[num_entries_start..HEADER_BYTES as usize].copy_from_slice(&u64::MAX.to_le_bytes());
+
+        let source = MemoryRangeSource {
+            bytes: archive,
+            num_requests: AtomicUsize::new(0),
+        };
+        let cache = MemoryBackend::new(CompressionCodec::default());
+        assert!(matches!(
+            RemoteBackend::open_with_cache(source, cache),
+            Err(BackendError::BadArchive)
+        ));
+    }
+}
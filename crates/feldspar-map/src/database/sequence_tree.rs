@@ -0,0 +1,154 @@
+use super::{corrupt_value, ChunkDbKey};
+
+use sled::transaction::{TransactionalTree, UnabortableTransactionError};
+use sled::{IVec, Tree};
+
+/// The number of bytes of a [`ChunkDbKey`] in the sequence tree, followed by a big-endian sequence number for each record.
+const KEY_BYTES: usize = 13;
+
+pub fn open_sequence_tree(map_name: &str, db: &sled::Db) -> sled::Result<Tree> {
+    db.open_tree(format!("{}-sequences", map_name))
+}
+
+/// The sequence tree has two kinds of entries for each chunk. The bare chunk key maps to the latest sequence number that was
+/// appended, which can be read in a transaction. Then every appended record is stored under the chunk key followed by its
+/// sequence number, so a chunk's records sort by sequence number.
+fn record_key(key: ChunkDbKey, seq: u64) -> [u8; KEY_BYTES + 8] {
+    let mut bytes = [0; KEY_BYTES + 8];
+    bytes[..KEY_BYTES].copy_from_slice(&key.into_sled_key());
+    bytes[KEY_BYTES..].copy_from_slice(&seq.to_be_bytes());
+    bytes
+}
+
+/// Returns an error if `bytes` isn't a sequence number, e.g. because of a bad disk.
+fn decode_seq(bytes: &[u8]) -> sled::Result<u64> {
+    let bytes = bytes
+        .try_into()
+        .map_err(|_| corrupt_value("sequence number"))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// The sequence number at the end of a [`record_key`].
+fn record_seq(key_bytes: &[u8]) -> sled::Result<u64> {
+    decode_seq(key_bytes.get(KEY_BYTES..).unwrap_or_default())
+}
+
+/// The latest sequence number appended for `key`, if any.
+pub fn latest_seq(
+    txn: &TransactionalTree,
+    key: ChunkDbKey,
+) -> Result<Option<u64>, UnabortableTransactionError> {
+    let seq = txn.get(&key.into_sled_key())?;
+    Ok(seq.map(|b| decode_seq(&b)).transpose()?)
+}
+
+/// Stores `record` as the change to `key` at `seq`, which must be greater than the [`latest_seq`].
+pub fn append_record(
+    txn: &TransactionalTree,
+    key: ChunkDbKey,
+    seq: u64,
+    record: IVec,
+) -> Result<(), UnabortableTransactionError> {
+    txn.insert(key.into_sled_key().as_ref(), seq.to_be_bytes().as_ref())?;
+    txn.insert(record_key(key, seq).as_ref(), record)?;
+    Ok(())
+}
+
+/// The latest record of `key` that was appended at or before `seq`, along with its own sequence number.
+pub fn read_record_at_or_before(
+    tree: &Tree,
+    key: ChunkDbKey,
+    seq: u64,
+) -> sled::Result<Option<(u64, IVec)>> {
+    let entry = tree
+        .range(record_key(key, 0)..=record_key(key, seq))
+        .next_back()
+        .transpose()?;
+    entry
+        .map(|(key_bytes, record)| Ok((record_seq(&key_bytes)?, record)))
+        .transpose()
+}
+
+/// Removes every record before `seq` that's been replaced by a later record that's still at or before `seq`, so reads at or
+/// after `seq` see the same records. Returns the number of records removed.
+pub fn prune_records_before(tree: &Tree, seq: u64) -> sled::Result<usize> {
+    let mut removed = 0;
+    // The record of the current chunk that's kept so far, if it's before `seq`.
+    let mut kept: Option<IVec> = None;
+    for entry in tree.iter() {
+        let (key_bytes, _) = entry?;
+        if key_bytes.len() == KEY_BYTES {
+            // The latest sequence number of the next chunk, which sorts before all of its records.
+            kept = None;
+            continue;
+        }
+        if record_seq(&key_bytes)? > seq {
+            continue;
+        }
+        if let Some(replaced) = kept.replace(key_bytes) {
+            tree.remove(replaced)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Replaces each appended record with the result of `f`, which takes the key of the record's chunk and the record. Records
+/// for which `f` returns `None` are left as they are.
+pub fn rewrite_records(
+    tree: &Tree,
+    mut f: impl FnMut(&[u8], &IVec) -> Option<IVec>,
+) -> sled::Result<()> {
+    for entry in tree.iter() {
+        let (key_bytes, record) = entry?;
+        if key_bytes.len() == KEY_BYTES {
+            continue;
+        }
+        if let Some(new_record) = f(&key_bytes[..KEY_BYTES], &record) {
+            tree.insert(key_bytes, new_record)?;
+        }
+    }
+    Ok(())
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::glam::IVec3;
+
+    use grid_tree::NodeKey;
+    use sled::transaction::TransactionError;
+
+    #[test]
+    fn corrupt_sequence_numbers_are_errors() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let tree = open_sequence_tree("mymap", &db).unwrap();
+        let key = ChunkDbKey::from(NodeKey::new(0, IVec3::ZERO));
+
+        let _: Result<(), TransactionError<()>> = tree.transaction(|txn| {
+            append_record(txn, key, 3, IVec::from(&[7][..]))?;
+            assert_eq!(latest_seq(txn, key)?, Some(3));
+            Ok(())
+        });
+        let (seq, record) = read_record_at_or_before(&tree, key, 5).unwrap().unwrap();
+        assert_eq!((seq, &*record), (3, &[7][..]));
+
+        tree.insert(key.into_sled_key(), &[1, 2, 3][..]).unwrap();
+        let result: Result<_, TransactionError<()>> =
+            tree.transaction(|txn| Ok(latest_seq(txn, key)?));
+        assert!(result.is_err());
+
+        // A record key that's too long for a sequence number.
+        let long_key = [&record_key(key, 4)[..], &[0xFF]].concat();
+        tree.insert(long_key, &[8][..]).unwrap();
+        assert!(read_record_at_or_before(&tree, key, 5).is_err());
+        assert!(prune_records_before(&tree, 5).is_err());
+    }
+}
@@ -0,0 +1,127 @@
+use super::{Change, ChangeEncoder, ChunkDbKey, ChunkReadError, EncodedChanges, MapDb};
+use crate::chunk::{Chunk, CompressedChunk};
+use crate::core::SmallKeyHashMap;
+
+use sled::transaction::TransactionError;
+
+/// Chunk changes that are staged by [`MapDb::transaction`] and written to the working version all at once.
+pub struct MapTransaction<'a> {
+    db: &'a MapDb,
+    changes: SmallKeyHashMap<ChunkDbKey, Change<CompressedChunk>>,
+}
+
+/// The error from [`MapDb::transaction`]. Nothing is written in either case.
+#[derive(Debug)]
+pub enum MapTransactionError<E> {
+    /// The transaction closure returned an error.
+    Abort(E),
+    Database(TransactionError),
+}
+
+impl<'a> MapTransaction<'a> {
+    pub(crate) fn new(db: &'a MapDb) -> Self {
+        Self {
+            db,
+            changes: Default::default(),
+        }
+    }
+
+    /// Compresses `chunk` with the database's codec and stages it.
+    pub fn insert_chunk(&mut self, key: ChunkDbKey, chunk: &Chunk) {
+        let compressed = chunk.compress_with(self.db.codec());
+        self.write_chunk(key, Change::Insert(compressed));
+    }
+
+    pub fn remove_chunk(&mut self, key: ChunkDbKey) {
+        self.write_chunk(key, Change::Remove);
+    }
+
+    /// Stages `change`, replacing any change that was staged for `key` before.
+    pub fn write_chunk(&mut self, key: ChunkDbKey, change: Change<CompressedChunk>) {
+        self.changes.insert(key, change);
+    }
+
+    /// Reads the chunk at `key`, seeing the changes staged by this transaction.
+    pub fn read_chunk(
+        &self,
+        key: ChunkDbKey,
+    ) -> Result<Option<Change<CompressedChunk>>, ChunkReadError> {
+        if let Some(change) = self.changes.get(&key) {
+            return Ok(Some(change.clone()));
+        }
+        Ok(self.db.read_working_version(key)?.map(|c| c.deserialize()))
+    }
+
+    /// The number of chunks with staged changes.
+    pub fn num_changes(&self) -> usize {
+        self.changes.len()
+    }
+
+    pub(crate) fn into_encoded_changes(self) -> EncodedChanges<CompressedChunk> {
+        let mut encoder = ChangeEncoder::default();
+        for (key, change) in self.changes.into_iter() {
+            encoder.add_compressed_change(key, change);
+        }
+        encoder.encode()
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::glam::IVec3;
+    use crate::sdf::Sd8;
+
+    #[test]
+    fn transaction_commits_all_chunks_or_none() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let mut map = MapDb::open(&db, "mymap").unwrap();
+        let keys: Vec<_> = (0..8)
+            .map(|x| ChunkDbKey::new(0, IVec3::new(x, 0, 0).into()))
+            .collect();
+        let mut chunk = Chunk::default();
+        chunk.set_voxel(IVec3::new(1, 2, 3), 7, Sd8::MIN);
+
+        // An aborted transaction writes nothing, even the chunks staged before the error.
+        let result = map.transaction(|txn| {
+            for &key in &keys[..4] {
+                txn.insert_chunk(key, &chunk);
+            }
+            Err::<(), _>("out of space")
+        });
+        assert!(matches!(
+            result,
+            Err(MapTransactionError::Abort("out of space"))
+        ));
+        for &key in &keys {
+            assert!(map.read_working_version(key).unwrap().is_none());
+        }
+
+        let num_staged = map
+            .transaction(|txn| {
+                for &key in &keys {
+                    txn.insert_chunk(key, &chunk);
+                }
+                txn.remove_chunk(keys[7]);
+                assert_eq!(txn.read_chunk(keys[7]).unwrap(), Some(Change::Remove));
+                // Staged changes aren't visible outside of the transaction until it commits.
+                assert!(txn.db.read_working_version(keys[0]).unwrap().is_none());
+                Ok::<_, ()>(txn.num_changes())
+            })
+            .unwrap();
+        assert_eq!(num_staged, 8);
+        for &key in &keys[..7] {
+            let change = map.read_working_version(key).unwrap().unwrap();
+            let decompressed = change.as_ref().get_insert_data().unwrap().decompress();
+            assert_eq!(decompressed, chunk);
+        }
+        assert!(map.read_working_version(keys[7]).unwrap().is_none());
+    }
+}
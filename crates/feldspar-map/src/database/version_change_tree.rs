@@ -52,6 +52,14 @@ pub fn archive_version(
     Ok(())
 }
 
+pub fn read_archived_version(
+    txn: &TransactionalTree,
+    version: Version,
+) -> Result<Option<ArchivedIVec<VersionChanges>>, UnabortableTransactionError> {
+    let bytes = txn.get(&version.into_sled_key())?;
+    Ok(bytes.map(|b| unsafe { ArchivedIVec::<VersionChanges>::new(b) }))
+}
+
 pub fn remove_archived_version(
     txn: &TransactionalTree,
     version: Version,
@@ -139,6 +139,27 @@ pub fn find_ancestor_path(
     abort(AbortReason::NoPathExistsToRoot)
 }
 
+/// Finds the path from `start_version` to the root ancestor, inclusive.
+pub fn find_path_to_root(
+    txn: &TransactionalTree,
+    start_version: Version,
+) -> Result<Vec<Version>, ConflictableTransactionError<AbortReason>> {
+    let mut path = vec![start_version];
+    let mut current_version = start_version;
+    while let Some(node_bytes) = txn.get(current_version.into_sled_key())? {
+        let node = unsafe { ArchivedIVec::<VersionNode>::new(node_bytes) }.deserialize();
+        if let Some(parent) = node.parent_version {
+            path.push(parent);
+            current_version = parent;
+        } else {
+            return Ok(path);
+        }
+    }
+
+    // We expect all nodes to have a path to the root.
+    abort(AbortReason::NoPathExistsToRoot)
+}
+
 pub enum PathResult {
     FoundRoot,
     FoundEnd,
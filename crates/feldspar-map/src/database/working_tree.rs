@@ -1,3 +1,4 @@
+use super::checksum_tree::{matches_checksum, record_checksum};
 use super::{
     ArchivedChange, ArchivedChangeIVec, ArchivedIVec, BackupKeyCache, Change, ChunkDbKey,
     EncodedChanges,
@@ -11,10 +12,13 @@ pub fn open_working_tree(map_name: &str, db: &sled::Db) -> sled::Result<Tree> {
     db.open_tree(format!("{}-working", map_name))
 }
 
-/// Inserts any previously unseen entries from `changes` into the backup tree (`txn`) and returns the [`EncodedChanges`] that
-/// can reverse the transformation.
+/// Writes `changes` into the working tree (`txn`), along with their checksums (`checksum_txn`), and returns the
+/// [`EncodedChanges`] that can reverse the transformation for any keys that aren't in the backup tree yet.
+///
+/// Old values that don't match their checksums are reversed with a [`Change::Remove`], since they can't be deserialized.
 pub fn write_changes_to_working_tree(
     txn: &TransactionalTree,
+    checksum_txn: &TransactionalTree,
     backup_key_cache: &BackupKeyCache,
     changes: EncodedChanges<CompressedChunk>,
 ) -> Result<EncodedChanges<CompressedChunk>, UnabortableTransactionError> {
@@ -27,9 +31,15 @@ pub fn write_changes_to_working_tree(
     for (key_bytes, change) in changes.changes.into_iter() {
         let key = ChunkDbKey::from_sled_key(&key_bytes);
 
-        let old_value = match change.as_ref() {
-            ArchivedChange::Insert(_) => txn.insert(&key_bytes, change.take_bytes())?,
-            ArchivedChange::Remove => txn.remove(&key_bytes)?,
+        let (old_value, old_checksum) = match change.as_ref() {
+            ArchivedChange::Insert(_) => {
+                let checksum = record_checksum(change.as_bytes());
+                (
+                    txn.insert(&key_bytes, change.take_bytes())?,
+                    checksum_txn.insert(&key_bytes, checksum.as_ref())?,
+                )
+            }
+            ArchivedChange::Remove => (txn.remove(&key_bytes)?, checksum_txn.remove(&key_bytes)?),
         };
 
         if backup_key_cache.keys.contains(&key) {
@@ -37,6 +47,13 @@ pub fn write_changes_to_working_tree(
             continue;
         }
 
+        let old_value = old_value.filter(|value| {
+            let intact = matches_checksum(old_checksum.as_deref(), value);
+            if !intact {
+                log::error!("Overwrote corrupt record of {:?}", key);
+            }
+            intact
+        });
         if let Some(old_value) = old_value {
             reverse_changes.push((key_bytes, unsafe {
                 ArchivedChangeIVec::<CompressedChunk>::new(old_value)
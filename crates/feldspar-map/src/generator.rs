@@ -0,0 +1,319 @@
+use crate::biome::BiomeMap;
+use crate::chunk::{Chunk, ChunkShape, UniformChunk, CHUNK_SHAPE_LOG2_IVEC3, CHUNK_SIZE};
+use crate::coordinates::chunk_lod0_extent;
+use crate::core::glam::IVec3;
+use crate::core::ilattice::prelude::Extent;
+use crate::units::{ChunkUnits, VoxelUnits};
+use crate::{palette::PaletteId8, sdf::Sd8};
+
+use grid_tree::NodeKey;
+use ndshape::ConstShape;
+
+/// Procedurally generates chunks that have no entry in the [`MapDb`](crate::database::MapDb).
+///
+/// Generators are invoked on background threads, so they must be pure functions of the node key. The same key should always
+/// produce the same chunk, since generated chunks are not persisted unless they are edited.
+pub trait ChunkGenerator: Send + Sync {
+    /// Generates the chunk at `key`. Returns `None` if the chunk would only contain ambient voxels.
+    fn generate_chunk(&self, key: NodeKey<IVec3>) -> Option<Chunk>;
+}
+
+/// Generates an infinite, flat ground plane.
+///
+/// All voxels below `surface_height` (in LOD0 voxel units) are solid with material `palette_id`.
+#[derive(Clone, Copy, Debug)]
+pub struct FlatWorldGenerator {
+    pub surface_height: f32,
+    pub palette_id: PaletteId8,
+}
+
+impl ChunkGenerator for FlatWorldGenerator {
+    fn generate_chunk(&self, key: NodeKey<IVec3>) -> Option<Chunk> {
+        // Distances are measured in voxel edge lengths at the chunk's level.
+        let voxel_size = (1 << key.level) as f32;
+        let min_y = ((key.coordinates.y << CHUNK_SHAPE_LOG2_IVEC3.y) << key.level) as f32;
+        let max_y = min_y + voxel_size * (ChunkShape::ARRAY[1] - 1) as f32;
+        if min_y - self.surface_height >= voxel_size {
+            // The whole chunk is far enough above the surface to be ambient.
+            return None;
+        }
+        if max_y - self.surface_height <= -voxel_size {
+            // The whole chunk is solid.
+            return Some(Chunk {
+                sdf: [Sd8::MIN; CHUNK_SIZE],
+                palette_ids: [self.palette_id; CHUNK_SIZE],
+            });
+        }
+
+        let mut chunk = Chunk::default();
+        for i in 0..ChunkShape::SIZE {
+            let [_, y, _] = ChunkShape::delinearize(i);
+            let voxel_y = min_y + voxel_size * y as f32;
+            let sdf = Sd8::from((voxel_y - self.surface_height) / voxel_size);
+            chunk.sdf[i as usize] = sdf;
+            if sdf.0 < 0 {
+                chunk.palette_ids[i as usize] = self.palette_id;
+            }
+        }
+        Some(chunk)
+    }
+}
+
+/// The inputs of a [`GenStage`] for a single chunk.
+#[derive(Clone, Copy, Debug)]
+pub struct GenContext<'a> {
+    pub key: NodeKey<IVec3>,
+    /// The LOD0 voxels covered by the chunk.
+    pub extent: VoxelUnits<Extent<IVec3>>,
+    /// A seed that only depends on the pipeline seed, the stage name, and the key, so every stage gets its own stable random
+    /// stream for every chunk.
+    pub seed: u64,
+    /// The biomes given to [`WorldGenPipeline::with_biomes`], if any.
+    pub biomes: Option<&'a BiomeMap>,
+}
+
+impl GenContext<'_> {
+    /// The edge length of the chunk's voxels in LOD0 voxel units.
+    pub fn voxel_size(&self) -> i32 {
+        1 << self.key.level
+    }
+
+    /// The LOD0 position of the voxel at `offset` from the chunk's minimum.
+    pub fn voxel_position(&self, offset: IVec3) -> VoxelUnits<IVec3> {
+        let VoxelUnits(extent) = self.extent;
+        VoxelUnits(extent.minimum + (offset << self.key.level as i32))
+    }
+}
+
+/// One layer of a [`WorldGenPipeline`], e.g. base terrain, caves, ores, or structures.
+///
+/// Each stage edits the chunk left by the stages before it. Like [`ChunkGenerator`], stages must be pure functions of their
+/// [`GenContext`] and the input chunk, and any randomness must come from [`GenContext::seed`].
+pub trait GenStage: Send + Sync {
+    fn generate(&self, ctx: &GenContext, chunk: &mut Chunk);
+}
+
+impl<F> GenStage for F
+where
+    F: Fn(&GenContext, &mut Chunk) + Send + Sync,
+{
+    fn generate(&self, ctx: &GenContext, chunk: &mut Chunk) {
+        self(ctx, chunk)
+    }
+}
+
+/// Uses a [`ChunkGenerator`] as a [`GenStage`]. The generated chunk replaces the input chunk, so this is usually the first
+/// stage.
+#[derive(Clone, Copy, Debug)]
+pub struct GeneratorStage<G>(pub G);
+
+impl<G: ChunkGenerator> GenStage for GeneratorStage<G> {
+    fn generate(&self, ctx: &GenContext, chunk: &mut Chunk) {
+        *chunk = self.0.generate_chunk(ctx.key).unwrap_or_default();
+    }
+}
+
+/// A [`ChunkGenerator`] made of named [`GenStage`]s that run in the order they were added, starting from an ambient chunk.
+///
+/// The pipeline only runs for chunks that have no entry in the [`MapDb`](crate::database::MapDb), when they are loaded. Each
+/// stage is seeded by its name rather than its index, so adding or removing a stage doesn't change what the other stages
+/// generate.
+///
+/// Insert it as the generator resource, i.e. `Arc<dyn ChunkGenerator>`.
+#[derive(Default)]
+pub struct WorldGenPipeline {
+    seed: u64,
+    stages: Vec<(u64, Box<dyn GenStage>)>,
+    biomes: Option<BiomeMap>,
+}
+
+impl WorldGenPipeline {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            stages: Vec::new(),
+            biomes: None,
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Appends a stage. Panics if there is already a stage called `name`.
+    pub fn add_stage(&mut self, name: &str, stage: impl GenStage + 'static) -> &mut Self {
+        let stage_seed = hash_name(name) ^ self.seed;
+        assert!(
+            self.stages.iter().all(|(s, _)| *s != stage_seed),
+            "Duplicate world generation stage {name:?}"
+        );
+        self.stages.push((stage_seed, Box::new(stage)));
+        self
+    }
+
+    pub fn with_stage(mut self, name: &str, stage: impl GenStage + 'static) -> Self {
+        self.add_stage(name, stage);
+        self
+    }
+
+    /// Gives every stage access to `biomes` through [`GenContext::biomes`].
+    pub fn with_biomes(mut self, biomes: BiomeMap) -> Self {
+        self.biomes = Some(biomes);
+        self
+    }
+
+    pub fn biomes(&self) -> Option<&BiomeMap> {
+        self.biomes.as_ref()
+    }
+
+    pub fn num_stages(&self) -> usize {
+        self.stages.len()
+    }
+}
+
+impl ChunkGenerator for WorldGenPipeline {
+    fn generate_chunk(&self, key: NodeKey<IVec3>) -> Option<Chunk> {
+        let mut chunk = Chunk::default();
+        let extent = chunk_lod0_extent(key.level, ChunkUnits(key.coordinates));
+        for (stage_seed, stage) in &self.stages {
+            let ctx = GenContext {
+                key,
+                extent,
+                seed: key_seed(*stage_seed, key),
+                biomes: self.biomes.as_ref(),
+            };
+            stage.generate(&ctx, &mut chunk);
+        }
+        (chunk.uniform() != Some(UniformChunk::Air)).then(|| chunk)
+    }
+}
+
+/// FNV-1a, which unlike [`std::hash::Hasher`] implementations is guaranteed to be the same on every platform and version.
+fn hash_name(name: &str) -> u64 {
+    name.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn key_seed(stage_seed: u64, key: NodeKey<IVec3>) -> u64 {
+    let mut seed = splitmix64(stage_seed ^ key.level as u64);
+    for c in key.coordinates.to_array() {
+        seed = splitmix64(seed ^ c as u32 as u64);
+    }
+    seed
+}
+
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    const GENERATOR: FlatWorldGenerator = FlatWorldGenerator {
+        surface_height: 4.5,
+        palette_id: 1,
+    };
+
+    #[test]
+    fn flat_world_chunk_above_surface_is_ambient() {
+        assert_eq!(
+            GENERATOR.generate_chunk(NodeKey::new(0, IVec3::new(0, 1, 0))),
+            None
+        );
+        assert_eq!(
+            GENERATOR.generate_chunk(NodeKey::new(2, IVec3::new(3, 1, -7))),
+            None
+        );
+    }
+
+    #[test]
+    fn flat_world_chunk_below_surface_is_solid() {
+        let chunk = GENERATOR
+            .generate_chunk(NodeKey::new(0, IVec3::new(0, -1, 0)))
+            .unwrap();
+        assert!(chunk.sdf.iter().all(|&s| s == Sd8::MIN));
+        assert!(chunk.palette_ids.iter().all(|&p| p == 1));
+    }
+
+    #[test]
+    fn flat_world_chunk_crossing_surface_changes_sign_at_height() {
+        let chunk = GENERATOR
+            .generate_chunk(NodeKey::new(0, IVec3::ZERO))
+            .unwrap();
+        let sdf = chunk.sdf_view();
+        let palette = chunk.palette_view();
+        assert!(sdf[IVec3::new(3, 4, 3)].0 < 0);
+        assert_eq!(palette[IVec3::new(3, 4, 3)], 1);
+        assert!(sdf[IVec3::new(3, 5, 3)].0 > 0);
+        assert_eq!(palette[IVec3::new(3, 5, 3)], 0);
+    }
+
+    #[test]
+    fn pipeline_runs_stages_in_order() {
+        let pipeline = WorldGenPipeline::new(7)
+            .with_stage("terrain", GeneratorStage(GENERATOR))
+            // Carves a single voxel out of the terrain at the chunk minimum.
+            .with_stage("caves", |_ctx: &GenContext, chunk: &mut Chunk| {
+                chunk.set_voxel(IVec3::ZERO, 0, Sd8::MAX)
+            });
+        assert_eq!(pipeline.num_stages(), 2);
+
+        let chunk = pipeline
+            .generate_chunk(NodeKey::new(0, IVec3::new(0, -1, 0)))
+            .unwrap();
+        assert_eq!(chunk.sdf[0], Sd8::MAX);
+        assert_eq!(chunk.sdf[1], Sd8::MIN);
+
+        // Chunks that stay ambient aren't returned.
+        let empty = WorldGenPipeline::new(7).with_stage("terrain", GeneratorStage(GENERATOR));
+        assert_eq!(
+            empty.generate_chunk(NodeKey::new(0, IVec3::new(0, 1, 0))),
+            None
+        );
+    }
+
+    /// Records the seed of every chunk it generates.
+    #[derive(Clone, Default)]
+    struct RecordSeeds(Arc<Mutex<Vec<u64>>>);
+
+    impl GenStage for RecordSeeds {
+        fn generate(&self, ctx: &GenContext, _chunk: &mut Chunk) {
+            self.0.lock().unwrap().push(ctx.seed);
+        }
+    }
+
+    #[test]
+    fn stage_seeds_are_stable() {
+        let record = RecordSeeds::default();
+        let key = NodeKey::new(1, IVec3::new(-3, 2, 5));
+
+        let pipeline = WorldGenPipeline::new(1).with_stage("ores", record.clone());
+        pipeline.generate_chunk(key);
+        pipeline.generate_chunk(key);
+        pipeline.generate_chunk(NodeKey::new(1, IVec3::new(-3, 2, 6)));
+        let other_seed = WorldGenPipeline::new(2).with_stage("ores", record.clone());
+        other_seed.generate_chunk(key);
+        let other_name = WorldGenPipeline::new(1).with_stage("caves", record.clone());
+        other_name.generate_chunk(key);
+
+        let seeds = record.0.lock().unwrap();
+        assert_eq!(seeds[0], seeds[1]);
+        assert_ne!(seeds[0], seeds[2]);
+        assert_ne!(seeds[0], seeds[3]);
+        assert_ne!(seeds[0], seeds[4]);
+    }
+}
@@ -0,0 +1,288 @@
+//! Terrain generated from 2D heightmaps.
+//!
+//! A [`Heightmap`] can be loaded from any image format supported by the `image` crate that is enabled for this crate (PNG and
+//! OpenEXR). PNG samples are normalized to `[0, 1]`, while EXR samples keep their floating point values, so real elevations
+//! from DEM data can be used with a [`HeightmapConfig::height_scale`] that converts them to voxels.
+
+use crate::chunk::{Chunk, CHUNK_EDGE};
+use crate::coordinates::chunk_min;
+use crate::core::glam::{IVec2, IVec3, Vec2};
+use crate::database::{MapDb, BULK_WRITE_BATCH_SIZE};
+use crate::palette::PaletteId8;
+use crate::sdf::Sd8;
+use crate::units::{ChunkUnits, VoxelUnits};
+
+use grid_tree::NodeKey;
+use image::{DynamicImage, ImageResult};
+use std::path::Path;
+
+/// A grid of height samples, in row-major order.
+#[derive(Clone, Debug)]
+pub struct Heightmap {
+    width: u32,
+    height: u32,
+    samples: Vec<f32>,
+}
+
+impl Heightmap {
+    pub fn new(width: u32, height: u32, samples: Vec<f32>) -> Self {
+        assert!(width > 0 && height > 0);
+        assert_eq!(samples.len(), (width * height) as usize);
+        Self {
+            width,
+            height,
+            samples,
+        }
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> ImageResult<Self> {
+        Ok(Self::from_image(&image::open(path)?))
+    }
+
+    /// Converts `image` to grayscale samples.
+    pub fn from_image(image: &DynamicImage) -> Self {
+        let luma = image.to_luma32f();
+        let (width, height) = luma.dimensions();
+        Self::new(width, height, luma.into_raw())
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Bilinearly interpolates the samples at `uv`, where `[0, 1]²` covers the whole image. Coordinates outside of the image
+    /// are clamped to the edge.
+    pub fn sample(&self, uv: Vec2) -> f32 {
+        let size = Vec2::new(self.width as f32, self.height as f32);
+        let max_pixel = size - Vec2::ONE;
+        let pixel = (uv * size - Vec2::splat(0.5)).clamp(Vec2::ZERO, max_pixel);
+        let p0 = pixel.floor();
+        let t = pixel - p0;
+        let p1 = (p0 + Vec2::ONE).min(max_pixel);
+
+        let get = |x: f32, y: f32| self.samples[x as usize + self.width as usize * y as usize];
+        let top = get(p0.x, p0.y) * (1.0 - t.x) + get(p1.x, p0.y) * t.x;
+        let bottom = get(p0.x, p1.y) * (1.0 - t.x) + get(p1.x, p1.y) * t.x;
+        top * (1.0 - t.y) + bottom * t.y
+    }
+}
+
+/// Picks the material of each terrain column by the height of its surface.
+#[derive(Clone, Debug)]
+pub struct MaterialRamp {
+    /// Each stop applies to surfaces at or above its height, relative to [`HeightmapConfig::offset`], until the next stop.
+    /// Sorted by height.
+    stops: Vec<(VoxelUnits<f32>, PaletteId8)>,
+}
+
+impl MaterialRamp {
+    /// A ramp that uses `palette_id` at every height.
+    pub fn uniform(palette_id: PaletteId8) -> Self {
+        Self {
+            stops: vec![(VoxelUnits(f32::NEG_INFINITY), palette_id)],
+        }
+    }
+
+    /// Adds a stop that uses `palette_id` for surfaces at or above `height`.
+    pub fn with_stop(mut self, height: VoxelUnits<f32>, palette_id: PaletteId8) -> Self {
+        let i = self.stops.partition_point(|(h, _)| h.0 <= height.0);
+        self.stops.insert(i, (height, palette_id));
+        self
+    }
+
+    pub fn get(&self, height: VoxelUnits<f32>) -> PaletteId8 {
+        let VoxelUnits(height) = height;
+        let i = self.stops.partition_point(|(h, _)| h.0 <= height);
+        self.stops[i.saturating_sub(1)].1
+    }
+}
+
+/// Where and how a [`Heightmap`] is converted into terrain.
+#[derive(Clone, Debug)]
+pub struct HeightmapConfig {
+    /// The LOD0 voxel at the minimum corner of the terrain. The heightmap's first pixel is at the minimum X and Z, and zero
+    /// height is at this Y coordinate.
+    pub offset: VoxelUnits<IVec3>,
+    /// The size of the terrain along X and Z. The heightmap is stretched to cover it.
+    pub size: VoxelUnits<IVec2>,
+    /// Converts samples into heights.
+    pub height_scale: VoxelUnits<f32>,
+    pub ramp: MaterialRamp,
+}
+
+impl HeightmapConfig {
+    /// The LOD0 chunk columns (X and Z) covered by the terrain.
+    fn chunk_columns(&self) -> impl Iterator<Item = IVec2> {
+        let VoxelUnits(offset) = self.offset;
+        let VoxelUnits(size) = self.size;
+        let min_x = offset.x.div_euclid(CHUNK_EDGE);
+        let min_z = offset.z.div_euclid(CHUNK_EDGE);
+        let max_x = (offset.x + size.x - 1).div_euclid(CHUNK_EDGE);
+        let max_z = (offset.z + size.y - 1).div_euclid(CHUNK_EDGE);
+        (min_z..=max_z).flat_map(move |z| (min_x..=max_x).map(move |x| IVec2::new(x, z)))
+    }
+}
+
+/// Generates the LOD0 chunks of the terrain described by `heightmap` and `config`.
+///
+/// Voxels below the surface are solid down to the bottom of the chunks containing [`HeightmapConfig::offset`], and chunks
+/// above the surface are skipped. Chunks that only partly overlap the terrain area are filled by clamping the heightmap to its
+/// edges.
+pub fn heightmap_chunks<'a>(
+    heightmap: &'a Heightmap,
+    config: &'a HeightmapConfig,
+) -> impl Iterator<Item = (ChunkUnits<IVec3>, Chunk)> + 'a {
+    config.chunk_columns().flat_map(move |column| {
+        let surface = column_surface(heightmap, config, column);
+        let max_height = surface.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+
+        // The SDF saturates one voxel above the surface.
+        let VoxelUnits(offset) = config.offset;
+        let min_layer = offset.y.div_euclid(CHUNK_EDGE);
+        let max_layer = (max_height.ceil() as i32 + 1).div_euclid(CHUNK_EDGE);
+        (min_layer..=max_layer).map(move |layer| {
+            let coords = IVec3::new(column.x, layer, column.y);
+            (
+                ChunkUnits(coords),
+                chunk_from_surface(&surface, config, ChunkUnits(coords)),
+            )
+        })
+    })
+}
+
+/// Writes the terrain described by `heightmap` and `config` into LOD0 of the working version with
+/// [`MapDb::bulk_write_chunks`], overwriting any chunks in the way. Returns the number of chunks written.
+///
+/// Chunks are generated lazily, one batch of [`BULK_WRITE_BATCH_SIZE`] at a time, so large terrains don't need to fit in
+/// memory. `visitor` sees each chunk before it's written.
+pub fn write_heightmap(
+    db: &mut MapDb,
+    heightmap: &Heightmap,
+    config: &HeightmapConfig,
+    mut visitor: impl FnMut(ChunkUnits<IVec3>, &Chunk),
+) -> sled::Result<usize> {
+    log::debug!(
+        "Writing {}x{} heightmap in batches of {}",
+        heightmap.width(),
+        heightmap.height(),
+        BULK_WRITE_BATCH_SIZE
+    );
+    db.bulk_write_chunks(
+        heightmap_chunks(heightmap, config).map(|(ChunkUnits(coords), chunk)| {
+            visitor(ChunkUnits(coords), &chunk);
+            (NodeKey::new(0, coords), chunk.compress())
+        }),
+    )
+}
+
+/// The absolute surface height of every voxel column in a chunk column.
+fn column_surface(
+    heightmap: &Heightmap,
+    config: &HeightmapConfig,
+    column: IVec2,
+) -> [f32; (CHUNK_EDGE * CHUNK_EDGE) as usize] {
+    let VoxelUnits(offset) = config.offset;
+    let VoxelUnits(size) = config.size;
+    let VoxelUnits(height_scale) = config.height_scale;
+    let terrain_min = IVec2::new(offset.x, offset.z);
+
+    let mut surface = [0.0; (CHUNK_EDGE * CHUNK_EDGE) as usize];
+    for z in 0..CHUNK_EDGE {
+        for x in 0..CHUNK_EDGE {
+            let p = column * CHUNK_EDGE + IVec2::new(x, z);
+            // Sample at voxel centers.
+            let uv = ((p - terrain_min).as_vec2() + Vec2::splat(0.5)) / size.as_vec2();
+            surface[(x + CHUNK_EDGE * z) as usize] =
+                offset.y as f32 + height_scale * heightmap.sample(uv);
+        }
+    }
+    surface
+}
+
+fn chunk_from_surface(
+    surface: &[f32; (CHUNK_EDGE * CHUNK_EDGE) as usize],
+    config: &HeightmapConfig,
+    coords: ChunkUnits<IVec3>,
+) -> Chunk {
+    let VoxelUnits(offset) = config.offset;
+    let VoxelUnits(min) = chunk_min(coords);
+
+    let mut chunk = Chunk::default();
+    for z in 0..CHUNK_EDGE {
+        for x in 0..CHUNK_EDGE {
+            let surface_height = surface[(x + CHUNK_EDGE * z) as usize];
+            let palette_id = config
+                .ramp
+                .get(VoxelUnits(surface_height - offset.y as f32));
+            for y in 0..CHUNK_EDGE {
+                // Vertical distance is a good enough SDF for gentle slopes, and it's exact where the SDF saturates.
+                let dist = (min.y + y) as f32 - surface_height;
+                chunk.set_voxel(IVec3::new(x, y, z), palette_id, Sd8::from(dist));
+            }
+        }
+    }
+    chunk
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::{ChunkShape, AMBIENT_SD8};
+
+    use ndshape::ConstShape;
+
+    fn flat_config(height_scale: f32) -> HeightmapConfig {
+        HeightmapConfig {
+            offset: VoxelUnits(IVec3::ZERO),
+            size: VoxelUnits(IVec2::new(32, 16)),
+            height_scale: VoxelUnits(height_scale),
+            ramp: MaterialRamp::uniform(1).with_stop(VoxelUnits(10.0), 2),
+        }
+    }
+
+    #[test]
+    fn bilinear_sample_interpolates_between_pixels() {
+        let heightmap = Heightmap::new(2, 1, vec![0.0, 1.0]);
+        assert_eq!(heightmap.sample(Vec2::new(0.25, 0.5)), 0.0);
+        assert_eq!(heightmap.sample(Vec2::new(0.5, 0.5)), 0.5);
+        // Clamped to the edge.
+        assert_eq!(heightmap.sample(Vec2::new(2.0, 0.5)), 1.0);
+    }
+
+    #[test]
+    fn ramp_picks_highest_stop_below_surface() {
+        let ramp = MaterialRamp::uniform(1).with_stop(VoxelUnits(10.0), 2);
+        assert_eq!(ramp.get(VoxelUnits(-5.0)), 1);
+        assert_eq!(ramp.get(VoxelUnits(10.0)), 2);
+        assert_eq!(ramp.get(VoxelUnits(100.0)), 2);
+    }
+
+    #[test]
+    fn flat_heightmap_fills_below_surface() {
+        let heightmap = Heightmap::new(1, 1, vec![0.5]);
+        let config = flat_config(40.0);
+
+        let chunks: Vec<_> = heightmap_chunks(&heightmap, &config).collect();
+        // Two chunk columns, with layers up to the one containing the voxel above the surface at y = 20.
+        assert_eq!(chunks.len(), 2 * 2);
+
+        let (ChunkUnits(coords), chunk) = &chunks[1];
+        assert_eq!(*coords, IVec3::new(0, 1, 0));
+        let index = |y: i32| ChunkShape::linearize([0, y - 16, 0]) as usize;
+        assert_eq!(chunk.sdf[index(18)], Sd8::MIN);
+        assert_eq!(chunk.sdf[index(20)], Sd8::ZERO);
+        assert_eq!(chunk.sdf[index(22)], AMBIENT_SD8);
+        assert_eq!(chunk.palette_ids[index(18)], 2);
+    }
+}
@@ -17,7 +17,15 @@
 //!
 //! A voxel's [`PaletteId8`](crate::PaletteId8) is used to look up arbitrary attributes about a voxel via a `Palette8`. Only 256
 //! materials are supported in a single map. The attributes often consist of textures and physical properties like chemical
-//! makeup.
+//! makeup. Named materials keep their IDs across sessions through the [`MaterialIds`](crate::material::MaterialIds) stored in
+//! the [`MapDb`].
+//!
+//! ## Layer Voxels
+//!
+//! Simulations can register extra attribute layers in a [`VoxelLayerSchema`](crate::chunk::VoxelLayerSchema), like
+//! temperature or ownership, each with its own bit width. [`ChunkLayers`](crate::chunk::ChunkLayers) are stored per chunk next
+//! to the SDF and palette IDs in the [`MapDb`]. The plugin's optional fluid simulation stores fluid levels in the
+//! [`FluidLayer`](crate::chunk::FluidLayer).
 //!
 //! ## Tile Voxels
 //!
@@ -57,17 +65,25 @@
 //! other Bevy ECS systems to both edit and query the currently loaded map without having to worry about the details of
 //! streaming data and managing transactions.
 
+pub mod biome;
+pub mod brush;
 pub mod chunk;
 pub mod clipmap;
 pub mod coordinates;
 pub mod database;
+pub mod generator;
+pub mod heightmap;
+pub mod material;
 pub mod ndview;
 pub mod palette;
 pub mod sampling;
 pub mod sdf;
+pub mod stamp;
+pub mod transition;
 pub mod units;
 pub mod vox;
 pub mod voxel_attributes;
+pub mod world_transform;
 
 #[cfg(feature = "bevy_plugin")]
 mod plugin;
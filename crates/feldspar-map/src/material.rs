@@ -0,0 +1,214 @@
+use crate::core::rkyv::{Archive, Deserialize, Serialize};
+use crate::palette::PaletteId8;
+
+/// The [`PaletteId8`] of each named voxel material.
+///
+/// A [`MapDb`](crate::database::MapDb) stores these in its header, so a material keeps its ID across sessions, even when
+/// materials are added by mods or the material list is reordered. IDs are never reused, so a material that is no longer
+/// defined keeps its ID reserved.
+#[derive(Archive, Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[archive(crate = "crate::core::rkyv")]
+pub struct MaterialIds {
+    /// Indexed by ID.
+    names: Vec<String>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MaterialError {
+    /// All 256 IDs are assigned.
+    TooManyMaterials,
+    /// Two materials were defined with the same name.
+    DuplicateName(String),
+}
+
+impl MaterialIds {
+    pub const MAX_MATERIALS: usize = PaletteId8::MAX as usize + 1;
+
+    pub fn get(&self, name: &str) -> Option<PaletteId8> {
+        let index = self.names.iter().position(|n| n == name)?;
+        Some(index as PaletteId8)
+    }
+
+    pub fn name(&self, id: PaletteId8) -> Option<&str> {
+        self.names.get(usize::from(id)).map(String::as_str)
+    }
+
+    /// Returns the ID of `name`, assigning the next free ID if it doesn't have one yet.
+    pub fn assign(&mut self, name: &str) -> Result<PaletteId8, MaterialError> {
+        if let Some(id) = self.get(name) {
+            return Ok(id);
+        }
+        if self.names.len() == Self::MAX_MATERIALS {
+            return Err(MaterialError::TooManyMaterials);
+        }
+        self.names.push(name.to_owned());
+        Ok((self.names.len() - 1) as PaletteId8)
+    }
+
+    /// The number of assigned IDs.
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (PaletteId8, &str)> {
+        self.names
+            .iter()
+            .enumerate()
+            .map(|(id, name)| (id as PaletteId8, name.as_str()))
+    }
+
+    /// Returns `true` if `other` has all of the IDs in `self`, and maybe more.
+    pub fn is_prefix_of(&self, other: &Self) -> bool {
+        other.names.starts_with(&self.names)
+    }
+}
+
+/// How the faces of a material are culled and drawn by the renderer.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum MaterialClass {
+    /// Hides the faces of every voxel behind it.
+    Opaque,
+    /// Has fully transparent holes, like leaves, so it doesn't hide any faces, not even its own.
+    Cutout,
+    /// Partly transparent, like glass. Only hides the faces of its own material, so the inside of a glass wall isn't drawn.
+    Transparent,
+    /// Culled like [`MaterialClass::Transparent`], but meshed on its own so it can be drawn with a fluid material.
+    Liquid,
+}
+
+impl Default for MaterialClass {
+    fn default() -> Self {
+        Self::Opaque
+    }
+}
+
+impl MaterialClass {
+    pub const ALL: [Self; 4] = [Self::Opaque, Self::Cutout, Self::Transparent, Self::Liquid];
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// The [`MaterialClass`] of every [`PaletteId8`]. All materials are [`MaterialClass::Opaque`] by default.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MaterialClasses {
+    classes: [MaterialClass; MaterialIds::MAX_MATERIALS],
+}
+
+impl Default for MaterialClasses {
+    fn default() -> Self {
+        Self {
+            classes: [MaterialClass::Opaque; MaterialIds::MAX_MATERIALS],
+        }
+    }
+}
+
+impl MaterialClasses {
+    pub fn get(&self, id: PaletteId8) -> MaterialClass {
+        self.classes[usize::from(id)]
+    }
+
+    pub fn set(&mut self, id: PaletteId8, class: MaterialClass) {
+        self.classes[usize::from(id)] = class;
+    }
+
+    /// Returns `true` if the face of a solid voxel with `material` is hidden by the solid voxel in front of it, which has
+    /// `neighbor`.
+    pub fn hides(&self, neighbor: PaletteId8, material: PaletteId8) -> bool {
+        match self.get(neighbor) {
+            MaterialClass::Opaque => true,
+            MaterialClass::Cutout => false,
+            MaterialClass::Transparent | MaterialClass::Liquid => neighbor == material,
+        }
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{AbortReason, MapDb};
+
+    use sled::transaction::TransactionError;
+
+    #[test]
+    fn ids_are_stable_and_never_reused() {
+        let mut ids = MaterialIds::default();
+        assert_eq!(ids.assign("stone"), Ok(0));
+        assert_eq!(ids.assign("dirt"), Ok(1));
+        let stored = ids.clone();
+
+        // A later session that lists the materials in another order, with a new one from a mod.
+        let mut ids = stored.clone();
+        assert_eq!(ids.assign("mod:glass"), Ok(2));
+        assert_eq!(ids.assign("dirt"), Ok(1));
+        assert_eq!(ids.assign("stone"), Ok(0));
+        assert!(stored.is_prefix_of(&ids));
+        assert_eq!(ids.name(2), Some("mod:glass"));
+
+        for i in ids.len()..MaterialIds::MAX_MATERIALS {
+            ids.assign(&format!("filler{}", i)).unwrap();
+        }
+        assert_eq!(
+            ids.assign("one_too_many"),
+            Err(MaterialError::TooManyMaterials)
+        );
+        assert_eq!(ids.get("filler255"), Some(255));
+    }
+
+    #[test]
+    fn faces_are_culled_by_class() {
+        let (stone, glass, water, leaves) = (0, 1, 2, 3);
+        let mut classes = MaterialClasses::default();
+        classes.set(glass, MaterialClass::Transparent);
+        classes.set(water, MaterialClass::Liquid);
+        classes.set(leaves, MaterialClass::Cutout);
+
+        // Opaque voxels hide everything, and everything else shows opaque faces.
+        for material in [stone, glass, water, leaves] {
+            assert!(classes.hides(stone, material));
+        }
+        for neighbor in [glass, water, leaves] {
+            assert!(!classes.hides(neighbor, stone));
+        }
+        // Glass and water only hide their own faces.
+        assert!(classes.hides(glass, glass));
+        assert!(classes.hides(water, water));
+        assert!(!classes.hides(glass, water));
+        assert!(!classes.hides(water, glass));
+        // Leaves don't even hide themselves.
+        assert!(!classes.hides(leaves, leaves));
+        assert!(!classes.hides(leaves, glass));
+    }
+
+    #[test]
+    fn database_only_accepts_new_ids() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let mut map = MapDb::open(&db, "mymap").unwrap();
+        let mut ids = MaterialIds::default();
+        ids.assign("stone").unwrap();
+        map.register_material_ids(ids.clone()).unwrap();
+        drop(map);
+
+        let mut map = MapDb::open(&db, "mymap").unwrap();
+        assert_eq!(map.material_ids(), &ids);
+        let mut renumbered = MaterialIds::default();
+        renumbered.assign("dirt").unwrap();
+        renumbered.assign("stone").unwrap();
+        assert!(matches!(
+            map.register_material_ids(renumbered),
+            Err(TransactionError::Abort(AbortReason::MaterialIdsMismatch))
+        ));
+    }
+}
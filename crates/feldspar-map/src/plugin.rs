@@ -1,56 +1,409 @@
+mod autosave;
+mod cache;
+mod chunk_entities;
+mod commands;
+mod compaction;
 mod config;
+#[cfg(feature = "config_asset")]
+mod config_asset;
+mod diagnostics;
+mod dirty_regions;
+mod downsampler;
+mod edits;
+mod events;
+mod fluid;
+mod history;
+mod import;
+mod light;
 mod loader;
+mod maps;
+#[cfg(feature = "material_asset")]
+mod materials;
+mod metadata;
+mod navmesh;
+#[cfg(feature = "physics")]
+mod physics;
+mod saver;
+mod tasks;
+mod unsaved;
+mod validation;
+mod warm_start;
 mod witness;
 
-use std::sync::Arc;
-pub use config::MapConfig;
-pub use loader::LoaderConfig;
-pub use witness::Witness;
+pub use autosave::{AutosaveEvent, AutosavePlugin, AutosaveTrigger};
+pub use cache::CacheConfig;
+pub use chunk_entities::{
+    ChunkBound, ChunkEntities, ChunkEntitySpawner, ChunkEntitySpawners, ChunkSpawnContext,
+};
+pub use commands::{CommandConflicts, MapCommands};
+pub use compaction::{CompactionConfig, CompactionProgress};
+pub use config::{AoQuality, MapConfig, MapStorage, MeshConfig, MeshMode, OpenMode};
+#[cfg(feature = "config_asset")]
+pub use config_asset::{MapConfigAsset, MapConfigAssetPlugin, MapConfigHandle, MapConfigLoader};
+pub use diagnostics::MapDiagnosticsPlugin;
+pub use dirty_regions::DirtyRegions;
+pub use downsampler::DownsamplingConfig;
+pub use edits::{EditConfig, MapEdits};
+pub use events::{ChunkEvent, MapError, TeleportEvent};
+pub use fluid::{FluidConfig, FluidSim};
+pub use history::MapHistory;
+pub use import::MapImports;
+pub use light::{LightConfig, PendingLightChunks};
+pub use loader::{ErrorPolicy, LoaderConfig};
+pub use maps::{ActiveMap, MapDescriptor, MapId, Maps};
+#[cfg(feature = "material_asset")]
+pub use materials::{
+    MaterialDef, MaterialRegistry, MaterialRegistryPlugin, MaterialsAsset, MaterialsHandle,
+    MaterialsLoader,
+};
+pub use metadata::{ChunkMetadataEvent, ChunkMetadataPlugin, ChunkMetadataWrites};
+pub use navmesh::{NavMeshConfig, NavTileEvent, NavTiles};
+#[cfg(feature = "physics")]
+pub use physics::{ChunkCollider, ChunkColliders, PhysicsConfig};
+pub use saver::SaverConfig;
+pub use validation::{
+    EditApplied, EditInfo, EditRejected, EditTag, EditValidator, ProtectedRegions,
+};
+pub use warm_start::WarmStart;
+pub use witness::{Witness, WitnessPriority};
 
-use loader::loader_system;
+use cache::{cache_system, CacheState};
+use chunk_entities::chunk_entity_system;
+use commands::map_commands_system;
+use compaction::{compaction_system, CompactionState};
+use dirty_regions::dirty_regions_system;
+use downsampler::{downsampler_system, PendingDownsampleTasks};
+use edits::{edit_system, PendingFlushTask};
+use fluid::{fluid_exit_system, fluid_system, PendingFluidReads};
+use history::history_system;
+use import::import_system;
+use light::light_system;
+use loader::{load_cancellation_system, loader_system, PendingLoadTasks};
+use maps::{maps_last_system, maps_update_system};
+use navmesh::navmesh_system;
+#[cfg(feature = "physics")]
+use physics::collider_system;
+use saver::{saver_system, PendingSaveTasks};
+use unsaved::UnsavedChunks;
+use warm_start::warm_start_system;
 use witness::witness_system;
 
-use bevy::prelude::{Commands, CoreStage, Plugin, Res};
-use bevy::tasks::{IoTaskPool, TaskPoolBuilder};
+use crate::chunk::{FluidLayer, VoxelLayerSchema};
 use crate::clipmap::ChunkClipMap;
-use crate::database::MapDb;
-use crate::plugin::loader::PendingLoadTasks;
+use crate::database::{
+    EncryptionKey, MapBackend, MapDb, MemoryBackend, MigrationProgress, OverlayBackend,
+};
+use crate::world_transform::VoxelWorldTransform;
+
+use bevy::ecs::schedule::{IntoSystemDescriptor, SystemDescriptor};
+use bevy::prelude::{
+    Commands, CoreStage, ExclusiveSystemDescriptorCoercion, IntoExclusiveSystem,
+    ParallelSystemDescriptorCoercion, Plugin, Res, ResMut, StageLabel, SystemStage,
+};
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 #[derive(Default)]
 pub struct MapPlugin {
     config: MapConfig,
+    voxel_layers: VoxelLayerSchema,
 }
 
 impl MapPlugin {
     pub fn new(config: MapConfig) -> Self {
-        Self { config }
+        Self {
+            config,
+            voxel_layers: VoxelLayerSchema::default(),
+        }
     }
+
+    /// Registers extra per-voxel attribute layers with the [`MapDb`] on startup. The schema is also inserted as a resource, so
+    /// systems can look up the [`LayerId`](crate::chunk::LayerId) of each layer. The [`FluidLayer`] is appended if
+    /// [`FluidConfig::enabled`] is set and `schema` doesn't have it.
+    pub fn with_voxel_layers(mut self, schema: VoxelLayerSchema) -> Self {
+        self.voxel_layers = schema;
+        self
+    }
+}
+
+/// The stages that the [`MapPlugin`] adds when [`MapConfig::deterministic`] is set. Each runs the map's systems one at a
+/// time, in a fixed order: `Update` right after [`CoreStage::Update`], and `Last` right before [`CoreStage::Last`].
+/// Otherwise, the systems run in those core stages.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, StageLabel)]
+pub enum MapStage {
+    Update,
+    Last,
 }
 
 impl Plugin for MapPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
+        let (update, last) = if self.config.deterministic {
+            app.add_stage_after(
+                CoreStage::Update,
+                MapStage::Update,
+                SystemStage::single_threaded(),
+            )
+            .add_stage_before(
+                CoreStage::Last,
+                MapStage::Last,
+                SystemStage::single_threaded(),
+            );
+            (MapStage::Update.as_label(), MapStage::Last.as_label())
+        } else {
+            (CoreStage::Update.as_label(), CoreStage::Last.as_label())
+        };
+
+        let mut voxel_layers = self.voxel_layers.clone();
+        if self.config.fluid.enabled && FluidLayer::find(&voxel_layers).is_none() {
+            FluidLayer::register(&mut voxel_layers);
+        }
+
         app.insert_resource(self.config.clone())
-            .add_startup_system(plugin_startup)
-            .add_system_to_stage(CoreStage::Update, loader_system)
-            .add_system_to_stage(CoreStage::Last, witness_system);
+            .insert_resource(voxel_layers)
+            .insert_resource(MapCommands::default())
+            .insert_resource(MapEdits::default())
+            .insert_resource(MapHistory::default())
+            .insert_resource(MapImports::default())
+            .insert_resource(DirtyRegions::default())
+            .insert_resource(CacheState::default())
+            .insert_resource(PendingLightChunks::default())
+            .insert_resource(FluidSim::default())
+            .insert_resource(PendingFluidReads::default())
+            .insert_resource(NavTiles::default())
+            .insert_resource(ChunkEntitySpawners::default())
+            .insert_resource(ChunkEntities::default())
+            // Kept if the app already has one, so the voxel grid can be placed before the plugin is added.
+            .init_resource::<VoxelWorldTransform>()
+            .add_event::<ChunkEvent>()
+            .add_event::<CompactionProgress>()
+            .add_event::<EditApplied>()
+            .add_event::<EditRejected>()
+            .add_event::<MapError>()
+            .add_event::<NavTileEvent>()
+            .add_event::<TeleportEvent>()
+            .insert_resource(Maps::default())
+            .insert_resource(SledDbs::default())
+            .insert_resource(ActiveMap(MapId::PRIMARY))
+            .add_startup_system(plugin_startup);
+
+        #[cfg(feature = "physics")]
+        app.insert_resource(ChunkColliders::default());
+
+        let (update_systems, last_systems) = map_systems();
+        for system in update_systems {
+            app.add_system_to_stage(update, system);
+        }
+        for system in last_systems {
+            app.add_system_to_stage(last, system);
+        }
+        app.add_system_to_stage(update, maps_update_system.exclusive_system().at_end())
+            .add_system_to_stage(last, maps_last_system.exclusive_system().at_end());
     }
 }
 
-fn plugin_startup(mut commands: Commands, config: Res<MapConfig>) {
-    let db = sled::Config::default()
-        .path("tmp".to_owned())
-        .use_compression(false)
-        .mode(sled::Mode::LowSpace)
-        .open()
-        .expect("Failed to open world DB");
-
-    let mapdb = MapDb::open(&db, "main").expect("Failed to load main level");
-    commands.insert_resource(
-        Arc::new(mapdb)
+/// The systems that stream a map, for the `update` and `last` stages. Every map in [`Maps`] runs its own copy of them.
+fn map_systems() -> (Vec<SystemDescriptor>, Vec<SystemDescriptor>) {
+    #[allow(unused_mut)]
+    let mut update = vec![
+        load_cancellation_system
+            .before(loader_system)
+            .into_descriptor(),
+        loader_system.into_descriptor(),
+        map_commands_system.before(edit_system).into_descriptor(),
+        edit_system.after(loader_system).into_descriptor(),
+        dirty_regions_system.after(edit_system).into_descriptor(),
+        // Waits for the regions, so it never sees edited chunks that aren't queued to be flushed yet.
+        history_system.after(dirty_regions_system).into_descriptor(),
+        import_system.after(history_system).into_descriptor(),
+        downsampler_system
+            .after(loader_system)
+            .after(edit_system)
+            .after(history_system)
+            .after(import_system)
+            .into_descriptor(),
+        light_system
+            .after(loader_system)
+            .after(dirty_regions_system)
+            .into_descriptor(),
+        fluid_system
+            .after(loader_system)
+            .after(dirty_regions_system)
+            .into_descriptor(),
+        navmesh_system
+            .after(loader_system)
+            .after(edit_system)
+            .after(history_system)
+            .after(import_system)
+            .into_descriptor(),
+        saver_system.into_descriptor(),
+        chunk_entity_system
+            .after(loader_system)
+            .after(saver_system)
+            .into_descriptor(),
+        compaction_system.into_descriptor(),
+    ];
+    #[cfg(feature = "physics")]
+    update.push(
+        collider_system
+            .after(loader_system)
+            .after(edit_system)
+            .after(history_system)
+            .after(import_system)
+            .into_descriptor(),
     );
-    let chunk_clip_map = ChunkClipMap::new(config.num_lods, config.streaming);
+    let last = vec![
+        witness_system.into_descriptor(),
+        cache_system.into_descriptor(),
+        warm_start_system.into_descriptor(),
+        fluid_exit_system.into_descriptor(),
+    ];
+    (update, last)
+}
+
+/// Chunks are read and written through the `Arc<dyn MapBackend>` resource, if one was inserted before startup, e.g. a
+/// [`RemoteBackend`](crate::database::RemoteBackend). Otherwise the backend is chosen by [`MapConfig::storage`] and
+/// [`MapConfig::open_mode`]. Sled databases are opened with the [`EncryptionKey`] resource, if one was inserted.
+fn plugin_startup(
+    mut commands: Commands,
+    config: Res<MapConfig>,
+    voxel_layers: Res<VoxelLayerSchema>,
+    backend: Option<Res<Arc<dyn MapBackend>>>,
+    encryption_key: Option<Res<EncryptionKey>>,
+    mut sled_dbs: ResMut<SledDbs>,
+) {
+    let key = encryption_key.as_deref();
+    // `db` is the database that can be written, if any.
+    let (db, default_backend): (Option<Arc<RwLock<MapDb>>>, Arc<dyn MapBackend>) =
+        match (config.storage, &config.open_mode) {
+            (MapStorage::Memory, _) => (
+                None,
+                Arc::new(MemoryBackend::new(config.codec.unwrap_or_default())),
+            ),
+            (MapStorage::Sled, OpenMode::ReadWrite) => {
+                let db = Arc::new(RwLock::new(open_map_db(
+                    &config,
+                    sled_dbs.open(&config.db_path),
+                    key,
+                    Some(voxel_layers.clone()),
+                )));
+                (Some(db.clone()), db)
+            }
+            (MapStorage::Sled, OpenMode::ReadOnly) => {
+                let base = open_map_db(&config, sled_dbs.open(&config.db_path), key, None);
+                let overlay = MemoryBackend::new(config.codec.unwrap_or_else(|| base.codec()));
+                (
+                    None,
+                    Arc::new(OverlayBackend::new(
+                        Arc::new(RwLock::new(base)),
+                        Arc::new(overlay),
+                    )),
+                )
+            }
+            (MapStorage::Sled, OpenMode::Overlay(overlay_path)) => {
+                let base = open_map_db(&config, sled_dbs.open(&config.db_path), key, None);
+                let overlay = Arc::new(RwLock::new(open_map_db(
+                    &config,
+                    sled_dbs.open(overlay_path),
+                    key,
+                    Some(voxel_layers.clone()),
+                )));
+                (
+                    Some(overlay.clone()),
+                    Arc::new(OverlayBackend::new(Arc::new(RwLock::new(base)), overlay)),
+                )
+            }
+        };
+    let backend: Arc<dyn MapBackend> = if let Some(backend) = backend {
+        Arc::clone(&backend)
+    } else {
+        commands.insert_resource(default_backend.clone());
+        default_backend
+    };
+    match db {
+        Some(db) => {
+            commands.insert_resource(if config.warm_start {
+                WarmStart::read_journal(db.clone(), backend, config.deterministic)
+            } else {
+                WarmStart::disabled()
+            });
+            commands.insert_resource(db);
+        }
+        // Without a database, there is no history, no imports, and no load journal.
+        None => commands.insert_resource(WarmStart::disabled()),
+    }
+    let mut chunk_clip_map = ChunkClipMap::new(config.num_lods, config.streaming);
+    chunk_clip_map.world_bounds = config.world_bounds;
+    chunk_clip_map.share_borders = config.mesh.share_borders;
     commands.insert_resource(chunk_clip_map);
 
-    let task_pool = PendingLoadTasks::new();
-    commands.insert_resource(task_pool);
-}
\ No newline at end of file
+    commands.insert_resource(PendingLoadTasks::new(&config.loader));
+    commands.insert_resource(PendingSaveTasks::new());
+    commands.insert_resource(UnsavedChunks::default());
+    commands.insert_resource(PendingDownsampleTasks::new());
+    commands.insert_resource(PendingFlushTask::default());
+    commands.insert_resource(CompactionState::new(&config.compaction));
+}
+
+/// The sled databases that maps were opened from, by path. Sled can't open a directory that's already open, so maps in
+/// different dimensions of one database share its handle.
+#[derive(Default)]
+pub(crate) struct SledDbs {
+    dbs: HashMap<PathBuf, sled::Db>,
+}
+
+impl SledDbs {
+    fn open(&mut self, path: &Path) -> &sled::Db {
+        self.dbs.entry(path.to_owned()).or_insert_with(|| {
+            sled::Config::default()
+                .path(path)
+                .use_compression(false)
+                .mode(sled::Mode::LowSpace)
+                .open()
+                .expect("Failed to open world DB")
+        })
+    }
+}
+
+/// Opens the map in the [`MapConfig::dimension`] of `db`. Without `voxel_layers`, the map is opened read-only: it's still
+/// migrated from an older format version, but the codec and layers aren't written, and a `key` only decrypts a map that's
+/// already encrypted.
+fn open_map_db(
+    config: &MapConfig,
+    db: &sled::Db,
+    key: Option<&EncryptionKey>,
+    voxel_layers: Option<VoxelLayerSchema>,
+) -> MapDb {
+    let log_progress = |progress: MigrationProgress| {
+        log::info!(
+            "{} (format version {}): {}/{} records",
+            progress.description,
+            progress.to_version,
+            progress.records_done,
+            progress.records_total
+        )
+    };
+    let mut mapdb = if voxel_layers.is_some() {
+        MapDb::open_with_key_and_progress(db, &config.dimension, key, log_progress)
+    } else {
+        MapDb::open_read_only_with_key_and_progress(db, &config.dimension, key, log_progress)
+    }
+    .expect("Failed to load map dimension");
+    let voxel_layers = if let Some(voxel_layers) = voxel_layers {
+        voxel_layers
+    } else {
+        return mapdb;
+    };
+    if let Some(codec) = config.codec {
+        if codec != mapdb.codec() {
+            mapdb
+                .set_codec(codec)
+                .expect("Failed to write compression codec");
+        }
+    }
+    mapdb
+        .register_layers(voxel_layers)
+        .expect("Failed to register voxel layers");
+    mapdb
+}
@@ -0,0 +1,202 @@
+use super::config::MapConfig;
+use super::edits::PendingFlushTask;
+use super::maps::Maps;
+use super::tasks::MapTask;
+use super::unsaved::{UnsavedBatch, UnsavedChunks};
+use crate::chunk::ChunkEncoding;
+use crate::clipmap::ChunkClipMap;
+use crate::database::{MapBackend, MapDb};
+
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use bevy::utils::Instant;
+use futures_lite::future;
+use parking_lot::RwLock;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Periodically writes every dirty chunk in the [`ChunkClipMap`] to the [`MapBackend`], including downsampled chunks that
+/// would otherwise only be saved when they're evicted, and flushes the [`MapDb`] to disk. Everything is also saved when the
+/// app exits, blocking until it's written, and that includes the other open [`Maps`].
+///
+/// Requires the [`MapPlugin`](crate::MapPlugin).
+pub struct AutosavePlugin {
+    /// The time between autosaves, or `None` to only save on exit.
+    pub interval: Option<Duration>,
+    pub save_on_exit: bool,
+}
+
+impl Default for AutosavePlugin {
+    fn default() -> Self {
+        Self {
+            interval: Some(Duration::from_secs(60)),
+            save_on_exit: true,
+        }
+    }
+}
+
+impl Plugin for AutosavePlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(AutosaveState {
+            timer: self.interval.map(|interval| Timer::new(interval, false)),
+            save_on_exit: self.save_on_exit,
+            task: None,
+            save_maps: false,
+        })
+        .add_event::<AutosaveEvent>()
+        .add_system_to_stage(CoreStage::Last, autosave_system)
+        .add_system_to_stage(
+            CoreStage::Last,
+            save_maps_on_exit_system.exclusive_system().at_end(),
+        );
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AutosaveTrigger {
+    Timer,
+    Exit,
+}
+
+/// Sent when an autosave finishes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AutosaveEvent {
+    pub trigger: AutosaveTrigger,
+    pub num_chunks: usize,
+    /// The compressed size of the saved chunks.
+    pub bytes_written: usize,
+    /// From when the chunks were copied out of the clipmap until they were flushed to disk.
+    pub duration: Duration,
+    /// The debug representation of the error, if the save failed.
+    pub error: Option<String>,
+}
+
+struct AutosaveState {
+    timer: Option<Timer>,
+    save_on_exit: bool,
+    task: Option<MapTask<AutosaveEvent>>,
+    /// Set when the primary map was saved on exit, so the other [`Maps`] are saved at the end of the stage.
+    save_maps: bool,
+}
+
+fn autosave_system(
+    config: Res<MapConfig>,
+    time: Res<Time>,
+    backend: Res<Arc<dyn MapBackend>>,
+    db: Option<Res<Arc<RwLock<MapDb>>>>,
+    clipmap: Res<ChunkClipMap>,
+    mut flush_task: ResMut<PendingFlushTask>,
+    unsaved: Res<UnsavedChunks>,
+    mut state: ResMut<AutosaveState>,
+    mut exit_events: EventReader<AppExit>,
+    mut autosave_events: EventWriter<AutosaveEvent>,
+) {
+    let db = db.map(|db| Arc::clone(&db));
+
+    if exit_events.iter().next().is_some() && state.save_on_exit {
+        // Everything must be written before the process ends, and in order, so the flushes in flight have to finish first.
+        if let Some(task) = state.task.take() {
+            send_report(&mut autosave_events, future::block_on(task));
+        }
+        flush_task.wait();
+        let batch = unsaved.insert_changes(clipmap.take_all_dirty_chunks());
+        let report = save_chunks(
+            AutosaveTrigger::Exit,
+            &unsaved,
+            batch,
+            config.encoding,
+            Arc::clone(&backend),
+            db,
+        );
+        send_report(&mut autosave_events, report);
+        state.save_maps = true;
+        return;
+    }
+
+    if let Some(task) = &mut state.task {
+        if let Some(report) = future::block_on(future::poll_once(task)) {
+            send_report(&mut autosave_events, report);
+            state.task = None;
+        } else {
+            return;
+        }
+    }
+
+    let timer = if let Some(timer) = &mut state.timer {
+        timer
+    } else {
+        return;
+    };
+    timer.tick(time.delta());
+    if !timer.finished() {
+        return;
+    }
+    timer.reset();
+
+    let batch = unsaved.insert_changes(clipmap.take_all_dirty_chunks());
+    let unsaved = UnsavedChunks::clone(&unsaved);
+    let encoding = config.encoding;
+    let backend = Arc::clone(&backend);
+    state.task = Some(MapTask::io(config.deterministic, async move {
+        save_chunks(
+            AutosaveTrigger::Timer,
+            &unsaved,
+            batch,
+            encoding,
+            backend,
+            db,
+        )
+    }));
+}
+
+pub(crate) fn save_chunks(
+    trigger: AutosaveTrigger,
+    unsaved: &UnsavedChunks,
+    batch: UnsavedBatch,
+    encoding: ChunkEncoding,
+    backend: Arc<dyn MapBackend>,
+    db: Option<Arc<RwLock<MapDb>>>,
+) -> AutosaveEvent {
+    let start = Instant::now();
+    let num_chunks = batch.len();
+    let mut bytes_written = 0;
+    let mut error = None;
+    if !batch.is_empty() {
+        match unsaved.write(batch, encoding, &*backend) {
+            Ok(num_bytes) => bytes_written = num_bytes,
+            Err(e) => error = Some(format!("{:?}", e)),
+        }
+    }
+    if let Some(db) = db {
+        if let Err(e) = db.read().flush() {
+            error.get_or_insert_with(|| format!("{:?}", e));
+        }
+    }
+    AutosaveEvent {
+        trigger,
+        num_chunks,
+        bytes_written,
+        duration: start.elapsed(),
+        error,
+    }
+}
+
+/// Saves every open map in [`Maps`] after [`autosave_system`] saved the primary map on exit.
+fn save_maps_on_exit_system(world: &mut World) {
+    if std::mem::take(&mut world.resource_mut::<AutosaveState>().save_maps) {
+        Maps::save_all(world);
+    }
+}
+
+fn send_report(autosave_events: &mut EventWriter<AutosaveEvent>, report: AutosaveEvent) {
+    match &report.error {
+        Some(error) => log::error!("Autosave of {} chunks failed: {}", report.num_chunks, error),
+        None => log::info!(
+            "Autosaved {} chunks ({} bytes) in {:?}",
+            report.num_chunks,
+            report.bytes_written,
+            report.duration
+        ),
+    }
+    autosave_events.send(report);
+}
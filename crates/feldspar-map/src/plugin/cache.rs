@@ -0,0 +1,64 @@
+use super::config::MapConfig;
+use super::maps::ActiveMap;
+use super::witness::{Witness, WitnessObservers};
+use crate::clipmap::ChunkClipMap;
+use crate::world_transform::VoxelWorldTransform;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub struct CacheConfig {
+    /// The maximum number of bytes of decompressed chunk data kept in the [`ChunkClipMap`]. When there are more, the least
+    /// recently read chunks are compressed at the end of the frame, starting with those that only lower priority witnesses can
+    /// see.
+    pub max_resident_bytes: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_resident_bytes: 256 << 20,
+        }
+    }
+}
+
+/// Counts frames for the chunk cache, so the `cache_system` can tell when each chunk was last read.
+#[derive(Default)]
+pub struct CacheState {
+    frame: u32,
+}
+
+/// Compresses cold chunks when the decompressed chunks in the [`ChunkClipMap`] exceed [`CacheConfig::max_resident_bytes`].
+///
+/// This runs after every other system has read the clipmap for the frame.
+pub fn cache_system(
+    config: Res<MapConfig>,
+    world_transform: Res<VoxelWorldTransform>,
+    witness_transforms: Query<(&Witness, &Transform)>,
+    active_map: Res<ActiveMap>,
+    mut clipmap: ResMut<ChunkClipMap>,
+    mut state: ResMut<CacheState>,
+) {
+    state.frame = state.frame.wrapping_add(1);
+    let witnesses = WitnessObservers::new(
+        witness_transforms.iter(),
+        active_map.0,
+        &world_transform,
+        &config.loader,
+        clipmap.stream_config.clip_sphere_radius,
+    );
+    let sweep = clipmap.compress_cold_chunks(
+        state.frame,
+        config.cache.max_resident_bytes,
+        config.encoding,
+        &witnesses.clip_regions_by_priority(),
+    );
+    if sweep.num_compressed > 0 {
+        log::debug!(
+            "Compressed {} cold chunks; {} bytes were resident",
+            sweep.num_compressed,
+            sweep.resident_bytes
+        );
+    }
+}
@@ -0,0 +1,303 @@
+use super::events::ChunkEvent;
+use crate::clipmap::{ChunkClipMap, Level};
+use crate::coordinates::ancestor_extent;
+use crate::core::glam::IVec3;
+use crate::core::ilattice::prelude::Extent;
+use crate::core::SmallKeyHashMap;
+use crate::units::{ChunkUnits, VoxelUnits};
+use crate::world_transform::VoxelWorldTransform;
+
+use bevy::ecs::system::{Command, EntityCommands};
+use bevy::hierarchy::despawn_with_children_recursive;
+use bevy::prelude::*;
+use grid_tree::NodeKey;
+
+/// Ties an entity to a LOD0 chunk, so it's despawned along with its children when the chunk is evicted.
+///
+/// Entities spawned by a [`ChunkEntitySpawner`] get this component automatically, but it can be inserted on any entity.
+#[derive(Clone, Component, Copy, Debug, Eq, PartialEq)]
+pub struct ChunkBound(pub NodeKey<IVec3>);
+
+/// Spawns the entities that belong to a LOD0 chunk, like props tied to the terrain, when the chunk is loaded.
+///
+/// Spawners are called once for every [`ChunkEvent::Loaded`] at LOD0, in the order they were added to the
+/// [`ChunkEntitySpawners`]. A chunk that's evicted and loaded again gets new entities.
+pub trait ChunkEntitySpawner: Send + Sync + 'static {
+    fn spawn(&self, ctx: &mut ChunkSpawnContext);
+}
+
+impl<F> ChunkEntitySpawner for F
+where
+    F: Fn(&mut ChunkSpawnContext) + Send + Sync + 'static,
+{
+    fn spawn(&self, ctx: &mut ChunkSpawnContext) {
+        self(ctx)
+    }
+}
+
+/// What a [`ChunkEntitySpawner`] can see and do for the chunk that was loaded.
+pub struct ChunkSpawnContext<'a, 'w, 's> {
+    pub key: NodeKey<IVec3>,
+    pub clipmap: &'a ChunkClipMap,
+    /// Places the chunk's voxels in the world, e.g. with [`VoxelWorldTransform::voxel_local_transform`].
+    pub world_transform: &'a VoxelWorldTransform,
+    commands: &'a mut Commands<'w, 's>,
+    spawned: &'a mut Vec<Entity>,
+}
+
+impl<'a, 'w, 's> ChunkSpawnContext<'a, 'w, 's> {
+    /// Spawns an entity with `bundle` and a [`ChunkBound`] for this chunk.
+    pub fn spawn_bundle(&mut self, bundle: impl Bundle) -> EntityCommands<'w, 's, '_> {
+        let entity_commands = self.commands.spawn_bundle((bundle, ChunkBound(self.key)));
+        self.spawned.push(entity_commands.id());
+        entity_commands
+    }
+
+    /// For any other commands. Entities spawned this way aren't bound to the chunk unless they get a [`ChunkBound`].
+    pub fn commands(&mut self) -> &mut Commands<'w, 's> {
+        self.commands
+    }
+}
+
+/// The [`ChunkEntitySpawner`]s that are run for every loaded LOD0 chunk.
+#[derive(Default)]
+pub struct ChunkEntitySpawners {
+    spawners: Vec<Box<dyn ChunkEntitySpawner>>,
+}
+
+impl ChunkEntitySpawners {
+    pub fn add(&mut self, spawner: impl ChunkEntitySpawner) -> &mut Self {
+        self.spawners.push(Box::new(spawner));
+        self
+    }
+}
+
+/// A spatial hash of the entities that belong to loaded chunks, at every level of detail, so game code can find e.g. the
+/// collider at a voxel position.
+///
+/// Entities with a [`ChunkBound`] are indexed by the `chunk_entity_system`. Systems that spawn and despawn one entity per
+/// chunk on their own, like the `collider_system` and the renderer's mesher, index their entities with
+/// [`ChunkEntities::insert`] and [`ChunkEntities::remove`]. Either way, the index is updated when the commands of the system
+/// are queued, so it can name entities that don't exist until the commands are applied.
+#[derive(Default)]
+pub struct ChunkEntities {
+    /// Every indexed entity, by level and then by chunk coordinates.
+    levels: Vec<SmallKeyHashMap<IVec3, Vec<Entity>>>,
+    /// The entities with a [`ChunkBound`] for each LOD0 chunk.
+    bound: SmallKeyHashMap<IVec3, Vec<Entity>>,
+}
+
+impl ChunkEntities {
+    /// The entities bound to the LOD0 chunk at `coords`, including entities despawned by other systems since the last frame.
+    pub fn get(&self, coords: IVec3) -> &[Entity] {
+        self.bound.get(&coords).map_or(&[], Vec::as_slice)
+    }
+
+    /// The first entity indexed for the chunk at `key`. When a chunk only has one entity, e.g. its mesh, that's the one.
+    pub fn entity_at(&self, key: NodeKey<IVec3>) -> Option<Entity> {
+        self.entities_at(key).first().copied()
+    }
+
+    /// Every entity indexed for the chunk at `key`, in the order they were inserted.
+    pub fn entities_at(&self, key: NodeKey<IVec3>) -> &[Entity] {
+        self.levels
+            .get(key.level as usize)
+            .and_then(|chunks| chunks.get(&key.coordinates))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// The entities of every chunk, at any level, that shares a voxel with `extent`, along with the keys of their chunks.
+    pub fn entities_in(&self, extent: VoxelUnits<Extent<IVec3>>) -> Vec<(NodeKey<IVec3>, Entity)> {
+        let mut found = Vec::new();
+        if extent.0.is_empty() {
+            return found;
+        }
+        let ChunkUnits(lod0_chunks) = extent.touched_chunks();
+        for (level, chunks) in self.levels.iter().enumerate() {
+            let level = level as Level;
+            let level_chunks = ancestor_extent(level, lod0_chunks);
+            let mut push = |coords: IVec3, entities: &Vec<Entity>| {
+                found.extend(entities.iter().map(|&e| (NodeKey::new(level, coords), e)));
+            };
+            // Only look up every chunk in the extent if there are fewer of them than indexed chunks.
+            if level_chunks.volume() as usize <= chunks.len() {
+                for coords in level_chunks.iter3() {
+                    if let Some(entities) = chunks.get(&coords) {
+                        push(coords, entities);
+                    }
+                }
+            } else {
+                for (&coords, entities) in chunks.iter() {
+                    if level_chunks.contains(coords) {
+                        push(coords, entities);
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    /// Indexes `entity` under the chunk at `key`, unless it already is.
+    pub fn insert(&mut self, key: NodeKey<IVec3>, entity: Entity) {
+        let level = key.level as usize;
+        if self.levels.len() <= level {
+            self.levels.resize_with(level + 1, Default::default);
+        }
+        let entities = self.levels[level].entry(key.coordinates).or_default();
+        if !entities.contains(&entity) {
+            entities.push(entity);
+        }
+    }
+
+    /// Removes `entity` from the chunk at `key`, e.g. right before it's despawned.
+    pub fn remove(&mut self, key: NodeKey<IVec3>, entity: Entity) {
+        let chunks = if let Some(chunks) = self.levels.get_mut(key.level as usize) {
+            chunks
+        } else {
+            return;
+        };
+        if let Some(entities) = chunks.get_mut(&key.coordinates) {
+            entities.retain(|&e| e != entity);
+            if entities.is_empty() {
+                chunks.remove(&key.coordinates);
+            }
+        }
+    }
+
+    fn bind(&mut self, coords: IVec3, entity: Entity) {
+        let entities = self.bound.entry(coords).or_default();
+        if !entities.contains(&entity) {
+            entities.push(entity);
+        }
+        self.insert(NodeKey::new(0, coords), entity);
+    }
+
+    /// Removes the entities bound to the LOD0 chunk at `coords` and returns them.
+    fn unbind_chunk(&mut self, coords: IVec3) -> Vec<Entity> {
+        let entities = self.bound.remove(&coords).unwrap_or_default();
+        for &entity in entities.iter() {
+            self.remove(NodeKey::new(0, coords), entity);
+        }
+        entities
+    }
+}
+
+/// Runs the [`ChunkEntitySpawners`] for loaded chunks, and despawns the [`ChunkBound`] entities of evicted chunks.
+pub fn chunk_entity_system(
+    clipmap: Res<ChunkClipMap>,
+    world_transform: Res<VoxelWorldTransform>,
+    spawners: Res<ChunkEntitySpawners>,
+    mut chunk_entities: ResMut<ChunkEntities>,
+    new_bound: Query<(Entity, &ChunkBound), Added<ChunkBound>>,
+    mut commands: Commands,
+    mut chunk_events: EventReader<ChunkEvent>,
+) {
+    // Entities that were bound by other systems.
+    for (entity, &ChunkBound(key)) in new_bound.iter() {
+        if key.level == 0 {
+            chunk_entities.bind(key.coordinates, entity);
+        }
+    }
+
+    for event in chunk_events.iter() {
+        match *event {
+            ChunkEvent::Loaded(key) if key.level == 0 => {
+                let mut spawned = Vec::new();
+                let mut ctx = ChunkSpawnContext {
+                    key,
+                    clipmap: &clipmap,
+                    world_transform: &world_transform,
+                    commands: &mut commands,
+                    spawned: &mut spawned,
+                };
+                for spawner in spawners.spawners.iter() {
+                    spawner.spawn(&mut ctx);
+                }
+                for entity in spawned {
+                    chunk_entities.bind(key.coordinates, entity);
+                }
+            }
+            ChunkEvent::Evicted(key) if key.level == 0 => {
+                for entity in chunk_entities.unbind_chunk(key.coordinates) {
+                    commands.add(DespawnIfExists(entity));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Bound entities might have been despawned by other systems, or not be spawned yet if the chunk was loaded and evicted in
+/// the same frame.
+struct DespawnIfExists(Entity);
+
+impl Command for DespawnIfExists {
+    fn write(self, world: &mut World) {
+        if world.get_entity(self.0).is_some() {
+            despawn_with_children_recursive(world, self.0);
+        }
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_entities_by_chunk_and_extent() {
+        let mut index = ChunkEntities::default();
+        let mesh = Entity::from_raw(0);
+        let prop = Entity::from_raw(1);
+        let far_mesh = Entity::from_raw(2);
+        let lod1_mesh = Entity::from_raw(3);
+        index.insert(NodeKey::new(0, IVec3::ZERO), mesh);
+        index.bind(IVec3::ZERO, prop);
+        index.insert(NodeKey::new(0, IVec3::splat(100)), far_mesh);
+        index.insert(NodeKey::new(1, IVec3::new(-1, 0, 0)), lod1_mesh);
+
+        assert_eq!(index.entity_at(NodeKey::new(0, IVec3::ZERO)), Some(mesh));
+        assert_eq!(
+            index.entities_at(NodeKey::new(0, IVec3::ZERO)),
+            [mesh, prop]
+        );
+        assert_eq!(index.get(IVec3::ZERO), [prop]);
+        assert_eq!(index.entity_at(NodeKey::new(2, IVec3::ZERO)), None);
+
+        // Touches LOD0 chunks -1 and 0 along X, so the LOD1 chunk at -1 too.
+        let extent = VoxelUnits(Extent::from_min_and_shape(
+            IVec3::new(-1, 0, 0),
+            IVec3::splat(2),
+        ));
+        let mut found = index.entities_in(extent);
+        found.sort_by_key(|(_, e)| e.id());
+        assert_eq!(
+            found,
+            [
+                (NodeKey::new(0, IVec3::ZERO), mesh),
+                (NodeKey::new(0, IVec3::ZERO), prop),
+                (NodeKey::new(1, IVec3::new(-1, 0, 0)), lod1_mesh),
+            ]
+        );
+        // Larger than the number of indexed chunks, so the index is scanned instead.
+        let everything = VoxelUnits(Extent::from_min_and_shape(
+            IVec3::splat(-4096),
+            IVec3::splat(8192),
+        ));
+        assert_eq!(index.entities_in(everything).len(), 4);
+
+        assert_eq!(index.unbind_chunk(IVec3::ZERO), [prop]);
+        index.remove(NodeKey::new(0, IVec3::splat(100)), far_mesh);
+        assert_eq!(index.entities_at(NodeKey::new(0, IVec3::ZERO)), [mesh]);
+        assert!(index
+            .entities_in(everything)
+            .iter()
+            .all(|&(_, e)| e != far_mesh));
+    }
+}
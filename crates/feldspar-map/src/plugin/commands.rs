@@ -0,0 +1,251 @@
+use super::config::MapConfig;
+use super::edits::{Edit, MapEdits};
+use super::validation::{EditInfo, EditRejected, EditTag};
+use crate::brush::{Brush, ClearBrush};
+use crate::core::glam::IVec3;
+use crate::core::ilattice::prelude::Extent;
+use crate::palette::PaletteId8;
+use crate::sdf::Sd8;
+use crate::stamp::{PlacedStamp, StampBlendMode, VoxelStamp};
+use crate::units::VoxelUnits;
+
+use bevy::prelude::*;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::sync::Arc;
+
+/// How the `map_commands_system` resolves [`MapCommands`] that were queued in the same frame and might change the same
+/// voxels.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum CommandConflicts {
+    /// Every command is applied, in order of priority, and commands with the same priority in the order they were queued. So
+    /// for each voxel, the last command with the highest priority wins.
+    Overwrite,
+    /// A command that overlaps a command with a higher priority, or with the same priority that was queued earlier, is
+    /// rejected with an [`EditRejected`] event. So the commands that are applied never overlap, and each author sees whether
+    /// its command was applied as a whole.
+    Reject,
+}
+
+impl Default for CommandConflicts {
+    fn default() -> Self {
+        Self::Overwrite
+    }
+}
+
+/// A handle to a queue of map mutations, like Bevy's `Commands`, for code that can't borrow the [`MapEdits`] mutably, e.g. a
+/// Lua or WASM scripting layer.
+///
+/// The handle is cheap to clone and can be sent to other threads. Every clone shares the same queue, which is drained into
+/// the [`MapEdits`] once per frame, right before the `edit_system` runs, so commands are applied at the same point in the
+/// frame no matter where they were queued. Commands queued after that are applied on the next frame. Within a frame,
+/// overlapping commands are resolved by [`EditConfig::command_conflicts`](crate::EditConfig::command_conflicts), and then
+/// they go through the [`EditValidator`](crate::EditValidator) like any other edit.
+///
+/// Each map in [`Maps`](crate::Maps) has its own queue, so a script should keep the handle of the map that it edits.
+#[derive(Clone, Default)]
+pub struct MapCommands {
+    queue: Arc<Mutex<Vec<MapCommand>>>,
+    tag: EditTag,
+    priority: i32,
+}
+
+struct MapCommand {
+    tag: EditTag,
+    priority: i32,
+    edit: Edit,
+}
+
+impl MapCommands {
+    /// A handle to the same queue whose commands have `tag`, e.g. the ID of the mod that queued them.
+    pub fn with_tag(&self, tag: EditTag) -> Self {
+        Self {
+            tag,
+            ..self.clone()
+        }
+    }
+
+    /// A handle to the same queue whose commands win conflicts against commands with a lower `priority`. The default is 0.
+    pub fn with_priority(&self, priority: i32) -> Self {
+        Self {
+            priority,
+            ..self.clone()
+        }
+    }
+
+    pub fn tag(&self) -> EditTag {
+        self.tag
+    }
+
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    pub fn set_sdf(&self, p: VoxelUnits<IVec3>, sdf: Sd8) {
+        self.push(Edit::SetSdf(p, sdf));
+    }
+
+    pub fn set_material(&self, p: VoxelUnits<IVec3>, palette_id: PaletteId8) {
+        self.push(Edit::SetMaterial(p, palette_id));
+    }
+
+    pub fn apply_brush(&self, brush: impl Brush) {
+        self.push(Edit::Brush(Arc::new(brush)));
+    }
+
+    /// Like [`MapEdits::stamp`].
+    pub fn stamp(&self, position: VoxelUnits<IVec3>, stamp: Arc<VoxelStamp>, mode: StampBlendMode) {
+        self.apply_brush(PlacedStamp {
+            stamp,
+            position,
+            mode,
+        });
+    }
+
+    /// Resets every voxel in `extent` to air with a [`ClearBrush`].
+    pub fn clear_region(&self, extent: VoxelUnits<Extent<IVec3>>) {
+        self.apply_brush(ClearBrush { extent });
+    }
+
+    /// The number of commands that haven't been applied yet.
+    pub fn len(&self) -> usize {
+        self.queue.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.lock().is_empty()
+    }
+
+    fn push(&self, edit: Edit) {
+        self.queue.lock().push(MapCommand {
+            tag: self.tag,
+            priority: self.priority,
+            edit,
+        });
+    }
+
+    fn take(&self) -> Vec<MapCommand> {
+        std::mem::take(&mut *self.queue.lock())
+    }
+}
+
+/// Sorts `commands` into the order they should be applied, and removes the ones that lose a conflict under `policy`.
+fn resolve_conflicts(
+    mut commands: Vec<MapCommand>,
+    policy: CommandConflicts,
+) -> (Vec<MapCommand>, Vec<MapCommand>) {
+    match policy {
+        CommandConflicts::Overwrite => {
+            // Stable, so commands with the same priority stay in the order they were queued.
+            commands.sort_by_key(|c| c.priority);
+            (commands, Vec::new())
+        }
+        CommandConflicts::Reject => {
+            // Consider the winners first, so every command only has to be checked against commands that were accepted.
+            commands.sort_by_key(|c| Reverse(c.priority));
+            let mut accepted: Vec<(MapCommand, Extent<IVec3>)> = Vec::new();
+            let mut rejected = Vec::new();
+            for command in commands.into_iter() {
+                let VoxelUnits(extent) = command.edit.extent();
+                if accepted
+                    .iter()
+                    .any(|(_, other)| !extent.intersection(other).is_empty())
+                {
+                    rejected.push(command);
+                } else {
+                    accepted.push((command, extent));
+                }
+            }
+            // None of them overlap, so the order doesn't matter, but low priorities still go first like the other policy.
+            let accepted = accepted.into_iter().rev().map(|(c, _)| c).collect();
+            (accepted, rejected)
+        }
+    }
+}
+
+/// Moves the [`MapCommands`] into the [`MapEdits`].
+pub fn map_commands_system(
+    config: Res<MapConfig>,
+    commands: Res<MapCommands>,
+    mut edits: ResMut<MapEdits>,
+    mut rejections: EventWriter<EditRejected>,
+) {
+    let queued = commands.take();
+    if queued.is_empty() {
+        return;
+    }
+
+    let (accepted, rejected) = resolve_conflicts(queued, config.edits.command_conflicts);
+    if !rejected.is_empty() {
+        log::debug!("Rejected {} conflicting map commands", rejected.len());
+    }
+    rejections.send_batch(rejected.into_iter().map(|command| EditRejected {
+        edit: EditInfo {
+            tag: command.tag,
+            extent: command.edit.extent(),
+        },
+        reason: "conflicts with another command".into(),
+    }));
+    for command in accepted.into_iter() {
+        edits.push(command.tag, command.edit);
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clear_cube(commands: &MapCommands, min: i32) {
+        commands.clear_region(VoxelUnits(Extent::from_min_and_shape(
+            IVec3::splat(min),
+            IVec3::splat(4),
+        )));
+    }
+
+    fn authors(commands: &[MapCommand]) -> Vec<u64> {
+        commands.iter().map(|c| c.tag.author).collect()
+    }
+
+    #[test]
+    fn conflicting_commands_resolve_by_priority() {
+        let commands = MapCommands::default();
+        let author = |author| EditTag {
+            author,
+            sequence: 0,
+        };
+        clear_cube(&commands.with_tag(author(1)).with_priority(5), 0);
+        clear_cube(&commands.with_tag(author(2)), 2);
+        clear_cube(&commands.with_tag(author(3)), 0);
+        // Doesn't overlap any other command.
+        clear_cube(&commands.with_tag(author(4)), 100);
+        assert_eq!(commands.len(), 4);
+
+        let (applied, rejected) = resolve_conflicts(commands.take(), CommandConflicts::Overwrite);
+        assert!(commands.is_empty());
+        assert_eq!(authors(&applied), [2, 3, 4, 1]);
+        assert!(rejected.is_empty());
+
+        for author_id in 1..=4 {
+            clear_cube(&commands.with_tag(author(author_id)).with_priority(5), 0);
+        }
+        let (applied, rejected) = resolve_conflicts(commands.take(), CommandConflicts::Reject);
+        assert_eq!(authors(&applied), [1]);
+        assert_eq!(authors(&rejected), [2, 3, 4]);
+
+        clear_cube(&commands.with_tag(author(1)).with_priority(5), 0);
+        clear_cube(&commands.with_tag(author(2)), 2);
+        clear_cube(&commands.with_tag(author(3)), 3);
+        clear_cube(&commands.with_tag(author(4)), 100);
+        let (applied, rejected) = resolve_conflicts(commands.take(), CommandConflicts::Reject);
+        assert_eq!(authors(&applied), [4, 1]);
+        assert_eq!(authors(&rejected), [2, 3]);
+    }
+}
@@ -0,0 +1,124 @@
+use super::config::MapConfig;
+use super::tasks::MapTask;
+use crate::database::{AbortReason, CompactionStep, MapDb};
+
+use bevy::prelude::*;
+use futures_lite::future;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use sled::transaction::TransactionError;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub struct CompactionConfig {
+    /// The maximum number of committed versions kept in the history of the working version. Older versions are merged.
+    pub max_versions: usize,
+    /// The time between compaction passes.
+    pub interval: Duration,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        Self {
+            max_versions: 256,
+            interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Sent after each step of a compaction pass.
+#[derive(Clone, Copy, Debug)]
+pub struct CompactionProgress {
+    /// The number of versions merged so far in this pass.
+    pub merged_versions: usize,
+    /// The number of versions that still exceed [`CompactionConfig::max_versions`], or zero when the pass is finished.
+    pub excess_versions: usize,
+    /// True for the last event of the pass. The pass ends early if the oldest version is needed by another branch.
+    pub finished: bool,
+}
+
+pub struct CompactionState {
+    timer: Timer,
+    merged_versions: usize,
+    task: Option<MapTask<Result<CompactionStep, TransactionError<AbortReason>>>>,
+}
+
+impl CompactionState {
+    pub fn new(config: &CompactionConfig) -> Self {
+        Self {
+            timer: Timer::new(config.interval, true),
+            merged_versions: 0,
+            task: None,
+        }
+    }
+}
+
+/// Periodically merges the oldest versions in the [`MapDb`], one version per IO task, so loads and saves can take the
+/// database lock between steps.
+pub fn compaction_system(
+    time: Res<Time>,
+    config: Res<MapConfig>,
+    db: Option<Res<Arc<RwLock<MapDb>>>>,
+    mut state: ResMut<CompactionState>,
+    mut progress: EventWriter<CompactionProgress>,
+) {
+    let db = if let Some(db) = db {
+        db
+    } else {
+        return;
+    };
+
+    let CompactionState {
+        timer,
+        merged_versions,
+        task,
+    } = &mut *state;
+
+    // Complete the pending step.
+    let continue_pass = if let Some(t) = task {
+        if let Some(result) = future::block_on(future::poll_once(t)) {
+            *task = None;
+            let excess_versions = match result {
+                Ok(CompactionStep::Merged { excess_versions }) => {
+                    *merged_versions += 1;
+                    excess_versions
+                }
+                Ok(CompactionStep::Done) => 0,
+                Ok(CompactionStep::Blocked) => {
+                    log::debug!("Compaction blocked by a branch of the oldest version");
+                    0
+                }
+                Err(e) => {
+                    log::error!("Failed to merge oldest version: {:?}", e);
+                    0
+                }
+            };
+            let finished = excess_versions == 0;
+            progress.send(CompactionProgress {
+                merged_versions: *merged_versions,
+                excess_versions,
+                finished,
+            });
+            !finished
+        } else {
+            return;
+        }
+    } else {
+        false
+    };
+
+    let start_pass = timer.tick(time.delta()).just_finished();
+    if !(continue_pass || start_pass) {
+        return;
+    }
+    if !continue_pass {
+        *merged_versions = 0;
+    }
+
+    let max_versions = config.compaction.max_versions;
+    let db_clone = db.clone();
+    *task = Some(MapTask::io(config.deterministic, async move {
+        db_clone.write().merge_oldest_version(max_versions)
+    }));
+}
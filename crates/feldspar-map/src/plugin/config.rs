@@ -1,21 +1,242 @@
-use super::LoaderConfig;
+use super::{
+    CacheConfig, CompactionConfig, DownsamplingConfig, EditConfig, FluidConfig, LightConfig,
+    LoaderConfig, NavMeshConfig, SaverConfig,
+};
+use crate::chunk::{ChunkEncoding, CompressionCodec};
 use crate::clipmap::StreamingConfig;
+use crate::core::glam::IVec3;
+use crate::core::ilattice::prelude::Extent;
+use crate::units::VoxelUnits;
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::path::PathBuf;
 
-#[derive(Clone, Copy, Deserialize, Serialize)]
+/// Sections that are missing when deserializing keep their defaults.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct MapConfig {
     pub num_lods: u8,
+    pub cache: CacheConfig,
+    /// If set, replaces the codec stored in the database header on startup. Existing chunks keep their codec.
+    pub codec: Option<CompressionCodec>,
+    pub compaction: CompactionConfig,
+    /// The directory of the [`MapStorage::Sled`] database. [`Maps`](super::Maps) can share a database if each of them has its
+    /// own [`Self::dimension`].
+    pub db_path: PathBuf,
+    /// Runs every task of the map's systems to completion as soon as it's started, on the system's own thread, and runs the
+    /// systems one at a time in a fixed order. Loads, saves, and downsampling never wait on a task pool, so headless
+    /// simulations and tests get the same results from the same inputs. Each task's results are still applied on the frame
+    /// after it's started, and the loader ignores its frame time budget.
+    pub deterministic: bool,
+    /// The map in the database to stream, as listed by [`MapDb::map_names`](crate::database::MapDb::map_names). Every
+    /// dimension of a database is a separate world, with its own chunks, versions, and layers.
+    pub dimension: String,
+    pub downsampling: DownsamplingConfig,
+    pub edits: EditConfig,
+    /// The layout of chunks when they're compressed in memory or written to the database. [`ChunkEncoding::Paletted`] is much
+    /// smaller for blocky worlds. Chunks written with either encoding can always be read.
+    pub encoding: ChunkEncoding,
+    pub fluid: FluidConfig,
+    pub light: LightConfig,
     pub loader: LoaderConfig,
+    pub mesh: MeshConfig,
+    pub navmesh: NavMeshConfig,
+    /// How the database is opened with [`MapStorage::Sled`].
+    pub open_mode: OpenMode,
+    #[cfg(feature = "physics")]
+    pub physics: super::PhysicsConfig,
+    pub saver: SaverConfig,
+    pub storage: MapStorage,
     pub streaming: StreamingConfig,
+    /// Writes the keys of all resident chunks to the database when the app exits, and reads those chunks ahead of time on the
+    /// next startup so the area around the witnesses loads without waiting for the database.
+    pub warm_start: bool,
+    /// If set, no chunks are loaded outside of this extent (at LOD0), even inside of a witness's clip shape. This keeps the
+    /// clipmap from growing when witnesses fly above the terrain ceiling or dig below bedrock. For a height band, make the
+    /// extent as wide as the world in X and Z. Shrinking the bounds at runtime doesn't evict chunks that are already loaded.
+    #[serde(with = "optional_extent")]
+    pub world_bounds: Option<VoxelUnits<Extent<IVec3>>>,
 }
 
 impl Default for MapConfig {
     fn default() -> Self {
         Self {
             num_lods: 10,
+            cache: CacheConfig::default(),
+            codec: None,
+            compaction: CompactionConfig::default(),
+            db_path: PathBuf::from("tmp"),
+            deterministic: false,
+            dimension: "main".to_owned(),
+            downsampling: DownsamplingConfig::default(),
+            edits: EditConfig::default(),
+            encoding: ChunkEncoding::default(),
+            fluid: FluidConfig::default(),
+            light: LightConfig::default(),
             loader: LoaderConfig::default(),
+            mesh: MeshConfig::default(),
+            navmesh: NavMeshConfig::default(),
+            open_mode: OpenMode::default(),
+            #[cfg(feature = "physics")]
+            physics: super::PhysicsConfig::default(),
+            saver: SaverConfig::default(),
+            storage: MapStorage::default(),
             streaming: StreamingConfig::default(),
+            warm_start: false,
+            world_bounds: None,
+        }
+    }
+}
+
+/// Serializes an extent as its `minimum` and `shape` arrays, which don't depend on `glam`'s serde support.
+mod optional_extent {
+    use super::*;
+
+    #[derive(Deserialize, Serialize)]
+    struct ExtentDef {
+        minimum: [i32; 3],
+        shape: [i32; 3],
+    }
+
+    pub fn serialize<S: Serializer>(
+        extent: &Option<VoxelUnits<Extent<IVec3>>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        extent
+            .map(|VoxelUnits(e)| ExtentDef {
+                minimum: e.minimum.to_array(),
+                shape: e.shape.to_array(),
+            })
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<VoxelUnits<Extent<IVec3>>>, D::Error> {
+        let def = Option::<ExtentDef>::deserialize(deserializer)?;
+        Ok(def.map(|def| {
+            VoxelUnits(Extent::from_min_and_shape(
+                IVec3::from(def.minimum),
+                IVec3::from(def.shape),
+            ))
+        }))
+    }
+}
+
+/// Where the chunks of the map are stored. Browsers have no filesystem for the database, so the default on `wasm32` is
+/// [`MapStorage::Memory`].
+///
+/// NOTE: The crate doesn't build for `wasm32` yet, since it still depends on sled. There is also no
+/// [`RangeSource`](crate::database::RangeSource) that can fetch from the browser, so a
+/// [`RemoteBackend`](crate::database::RemoteBackend) can't stream a world there.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum MapStorage {
+    /// A versioned [`MapDb`](crate::database::MapDb) on disk.
+    Sled,
+    /// A [`MemoryBackend`](crate::database::MemoryBackend) that never touches the filesystem, for tests, CI, and servers that
+    /// persist the map elsewhere. Loading, editing, and saving work as usual, but [`MapHistory`](super::MapHistory) requests,
+    /// [`MapImports`](super::MapImports), compaction, and warm starts need a database, so they're ignored.
+    Memory,
+}
+
+impl Default for MapStorage {
+    fn default() -> Self {
+        if cfg!(target_arch = "wasm32") {
+            Self::Memory
+        } else {
+            Self::Sled
         }
     }
 }
+
+/// How the [`MapStorage::Sled`] database is opened.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum OpenMode {
+    ReadWrite,
+    /// Nothing is written to the database. Changes are kept in memory until the app exits, and the
+    /// [`MapHistory`](super::MapHistory), [`MapImports`](super::MapImports), compaction, and warm starts are ignored.
+    ReadOnly,
+    /// The database is opened as a read-only base map, and all changes are written to a separate overlay database at this
+    /// path, through an [`OverlayBackend`](crate::database::OverlayBackend). The history and everything else that needs a
+    /// [`MapDb`](crate::database::MapDb) operates on the overlay.
+    Overlay(PathBuf),
+}
+
+impl Default for OpenMode {
+    fn default() -> Self {
+        Self::ReadWrite
+    }
+}
+
+/// Configures the chunk mesher, which is implemented by the renderer.
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MeshConfig {
+    pub mode: MeshMode,
+    /// The maximum number of render LOD changes handled in a single frame (batch).
+    pub mesh_batch_size: usize,
+    /// The maximum number of pending mesh tasks.
+    pub max_pending_mesh_tasks: usize,
+    /// The maximum number of finished meshes that are uploaded (spawned as mesh assets) in a single frame. The rest wait in a
+    /// queue for later frames.
+    pub max_mesh_uploads_per_frame: usize,
+    /// The maximum number of vertex and index bytes uploaded in a single frame. At least one mesh is always uploaded, even if
+    /// it's larger than this.
+    pub max_mesh_upload_bytes_per_frame: usize,
+    /// Adds the [`MaterialWeights`](crate::chunk::MaterialWeights) of the voxels around each vertex to every chunk mesh, for
+    /// shaders that blend terrain materials. Chunks without weights get the default weights, so every mesh has the same
+    /// vertex layout.
+    pub material_weights: bool,
+    /// Bakes ambient occlusion into the vertex colors of every chunk mesh.
+    pub ambient_occlusion: AoQuality,
+    /// Keeps a copy of the voxels past the positive faces of every chunk, so chunks are meshed as soon as they load instead of
+    /// waiting for their 7 positive neighbors, and remeshed when the neighbors load. Only read on startup. See
+    /// [`ChunkBorder`](crate::clipmap::ChunkBorder).
+    pub share_borders: bool,
+}
+
+impl Default for MeshConfig {
+    fn default() -> Self {
+        Self {
+            mode: MeshMode::SurfaceNets,
+            mesh_batch_size: 64,
+            max_pending_mesh_tasks: 8,
+            max_mesh_uploads_per_frame: 32,
+            max_mesh_upload_bytes_per_frame: 8 << 20,
+            material_weights: false,
+            ambient_occlusion: AoQuality::Off,
+            share_borders: false,
+        }
+    }
+}
+
+/// The kind of geometry generated for each chunk.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum MeshMode {
+    /// Smooth isosurfaces estimated from the SDF with Naive Surface Nets.
+    SurfaceNets,
+    /// Axis-aligned cube faces for every solid voxel (negative SDF), merged into larger quads where possible. Each face keeps the
+    /// material of its voxel.
+    GreedyQuads,
+    /// The same cube faces as [`MeshMode::GreedyQuads`], but unmerged and drawn with GPU instancing from a buffer of packed
+    /// faces, so changing a chunk never builds a triangle mesh. Best for very dynamic blocky worlds. Material weights and light
+    /// aren't supported.
+    InstancedFaces,
+}
+
+/// How much of the SDF around each vertex is sampled to estimate ambient occlusion.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub enum AoQuality {
+    Off,
+    /// Only the voxels touching each vertex, which is exact for [`MeshMode::GreedyQuads`].
+    Low,
+    /// Also samples voxels further along the normal of [`MeshMode::SurfaceNets`] vertices, so large overhangs and crevices
+    /// darken. Greedy quads are the same as [`AoQuality::Low`].
+    High,
+}
+
+impl Default for AoQuality {
+    fn default() -> Self {
+        Self::Off
+    }
+}
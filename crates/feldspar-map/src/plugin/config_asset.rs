@@ -0,0 +1,192 @@
+use super::config::MapConfig;
+use crate::clipmap::ChunkClipMap;
+
+use bevy::asset::{AssetLoader, LoadContext, LoadedAsset};
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::utils::BoxedFuture;
+
+/// Loads the [`MapConfig`] resource from a RON or TOML asset, and applies it again whenever the asset changes, so streaming can
+/// be tuned without recompiling.
+///
+/// Files must end in `.map.ron` or `.map.toml`. Omitted sections keep their defaults. Changes are only seen if the
+/// `AssetServerSettings` watch for changes.
+///
+/// Requires the [`MapPlugin`](crate::MapPlugin) and Bevy's `AssetPlugin`. Until the asset loads, the map uses the config that
+/// was given to the [`MapPlugin`](crate::MapPlugin).
+pub struct MapConfigAssetPlugin {
+    pub path: String,
+}
+
+impl MapConfigAssetPlugin {
+    pub fn new(path: impl Into<String>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl Plugin for MapConfigAssetPlugin {
+    fn build(&self, app: &mut App) {
+        let path = self.path.clone();
+        app.add_asset::<MapConfigAsset>()
+            .add_asset_loader(MapConfigLoader)
+            .add_startup_system(move |mut commands: Commands, assets: Res<AssetServer>| {
+                let handle: Handle<MapConfigAsset> = assets.load(path.as_str());
+                commands.insert_resource(MapConfigHandle(handle));
+            })
+            // Every system that reads the config runs in the update stage.
+            .add_system_to_stage(CoreStage::PreUpdate, config_asset_system);
+    }
+}
+
+#[derive(Clone, TypeUuid)]
+#[uuid = "0b6d6a8e-53c4-4a5e-8a48-7d7d6a3d1f60"]
+pub struct MapConfigAsset(pub MapConfig);
+
+/// The asset that the [`MapConfigAssetPlugin`] keeps applying.
+pub struct MapConfigHandle(pub Handle<MapConfigAsset>);
+
+#[derive(Default)]
+pub struct MapConfigLoader;
+
+impl AssetLoader for MapConfigLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let is_toml = load_context
+                .path()
+                .extension()
+                .map_or(false, |ext| ext == "toml");
+            let config = if is_toml {
+                toml::from_slice(bytes)?
+            } else {
+                ron::de::from_bytes(bytes)?
+            };
+            load_context.set_default_asset(LoadedAsset::new(MapConfigAsset(config)));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["map.ron", "map.toml"]
+    }
+}
+
+fn config_asset_system(
+    handle: Option<Res<MapConfigHandle>>,
+    assets: Res<Assets<MapConfigAsset>>,
+    mut asset_events: EventReader<AssetEvent<MapConfigAsset>>,
+    mut config: ResMut<MapConfig>,
+    mut clipmap: ResMut<ChunkClipMap>,
+) {
+    let handle = if let Some(handle) = handle {
+        handle
+    } else {
+        return;
+    };
+    let changed = asset_events.iter().any(|event| match event {
+        AssetEvent::Created { handle: h } | AssetEvent::Modified { handle: h } => *h == handle.0,
+        AssetEvent::Removed { .. } => false,
+    });
+    if !changed {
+        return;
+    }
+    if let Some(MapConfigAsset(new_config)) = assets.get(&handle.0) {
+        log::info!("Applying map config from {:?}", handle.0);
+        *config = applicable_config(&config, new_config.clone());
+        clipmap.stream_config = config.streaming;
+        clipmap.world_bounds = config.world_bounds;
+    }
+}
+
+/// Replaces everything in `old` that can change while the map is running. The rest is only read on startup.
+fn applicable_config(old: &MapConfig, mut new: MapConfig) -> MapConfig {
+    if new.num_lods != old.num_lods
+        || new.storage != old.storage
+        || new.codec != old.codec
+        || new.open_mode != old.open_mode
+        || new.db_path != old.db_path
+        || new.dimension != old.dimension
+        || new.deterministic != old.deterministic
+        || new.warm_start != old.warm_start
+        || new.mesh.share_borders != old.mesh.share_borders
+    {
+        log::warn!(
+            "Changes to num_lods, storage, codec, open_mode, db_path, dimension, deterministic, warm_start, and \
+             mesh.share_borders are only applied on startup"
+        );
+    }
+    new.num_lods = old.num_lods;
+    new.storage = old.storage;
+    new.codec = old.codec;
+    new.open_mode = old.open_mode.clone();
+    new.db_path = old.db_path.clone();
+    new.dimension = old.dimension.clone();
+    new.deterministic = old.deterministic;
+    new.warm_start = old.warm_start;
+    new.mesh.share_borders = old.mesh.share_borders;
+    new
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::glam::IVec3;
+    use crate::units::VoxelUnits;
+    use crate::{ErrorPolicy, LoaderConfig, MapStorage, MeshConfig, MeshMode, OpenMode};
+
+    #[test]
+    fn partial_configs_keep_defaults() {
+        let config: MapConfig =
+            ron::from_str("(num_lods: 4, loader: (load_batch_size: 32))").unwrap();
+        assert_eq!(config.num_lods, 4);
+        assert_eq!(config.loader.load_batch_size, 32);
+        assert_eq!(
+            config.loader.max_pending_load_tasks,
+            LoaderConfig::default().max_pending_load_tasks
+        );
+
+        let config: MapConfig =
+            toml::from_str("warm_start = true\n[loader]\nerror_policy = \"Skip\"").unwrap();
+        assert_eq!(config.loader.error_policy, ErrorPolicy::Skip);
+        assert_eq!(config.storage, MapStorage::Sled);
+
+        // Only the runtime settings are applied.
+        let applied = applicable_config(&MapConfig::default(), config);
+        assert_eq!(applied.loader.error_policy, ErrorPolicy::Skip);
+        assert!(!applied.warm_start);
+
+        let config: MapConfig =
+            toml::from_str("[world_bounds]\nminimum = [0, -64, 0]\nshape = [512, 128, 512]")
+                .unwrap();
+        let VoxelUnits(bounds) = config.world_bounds.unwrap();
+        assert_eq!(bounds.minimum, IVec3::new(0, -64, 0));
+        assert_eq!(bounds.shape, IVec3::new(512, 128, 512));
+
+        let config: MapConfig = ron::from_str("(mesh: (mode: GreedyQuads))").unwrap();
+        assert_eq!(config.mesh.mode, MeshMode::GreedyQuads);
+        assert_eq!(
+            config.mesh.max_mesh_uploads_per_frame,
+            MeshConfig::default().max_mesh_uploads_per_frame
+        );
+
+        let config: MapConfig = ron::from_str("(open_mode: Overlay(\"saves/overlay\"))").unwrap();
+        assert_eq!(config.open_mode, OpenMode::Overlay("saves/overlay".into()));
+        let applied = applicable_config(&MapConfig::default(), config);
+        assert_eq!(applied.open_mode, OpenMode::ReadWrite);
+
+        let config: MapConfig = ron::from_str("(dimension: \"nether\")").unwrap();
+        assert_eq!(config.dimension, "nether");
+        let applied = applicable_config(&MapConfig::default(), config);
+        assert_eq!(applied.dimension, "main");
+    }
+}
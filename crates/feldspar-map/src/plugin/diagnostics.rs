@@ -0,0 +1,215 @@
+use super::config::MapConfig;
+use super::events::ChunkEvent;
+use super::loader::PendingLoadTasks;
+use crate::clipmap::{ChunkClipMap, Level};
+
+use bevy::diagnostic::{Diagnostic, DiagnosticId, Diagnostics};
+use bevy::prelude::*;
+use std::time::Duration;
+
+/// Registers [`Diagnostics`] for the loader and the [`ChunkClipMap`], so streaming stutters can be traced to a slow backend,
+/// a full task queue, or a tree that's grown too large.
+///
+/// Requires the [`MapPlugin`](crate::MapPlugin), and Bevy's `DiagnosticsPlugin` to store the measurements. Add Bevy's
+/// `LogDiagnosticsPlugin` to print them.
+pub struct MapDiagnosticsPlugin {
+    /// How often the whole clipmap is walked to count its nodes. The other diagnostics are measured every frame.
+    pub clipmap_sample_interval: Duration,
+}
+
+impl Default for MapDiagnosticsPlugin {
+    fn default() -> Self {
+        Self {
+            clipmap_sample_interval: Duration::from_millis(500),
+        }
+    }
+}
+
+impl MapDiagnosticsPlugin {
+    pub const PENDING_LOAD_TASKS: DiagnosticId =
+        DiagnosticId::from_u128(0xe7c98e0f29c24050b14d137065ae4f4c);
+    /// Loaded chunks that are waiting to be inserted into the clipmap. See
+    /// [`LoaderConfig::max_insert_backlog`](crate::LoaderConfig::max_insert_backlog).
+    pub const INSERT_BACKLOG: DiagnosticId =
+        DiagnosticId::from_u128(0x3c1f6a2e8d5b4f0e9a7c2b6d4e8f1a35);
+    /// The size of the next load batch, which shrinks while the insert backlog is too long.
+    pub const LOAD_BATCH_SIZE: DiagnosticId =
+        DiagnosticId::from_u128(0xa5d2e47b19c34e6f8b0d3c5a7e9f2b41);
+    pub const CHUNKS_LOADED_PER_SECOND: DiagnosticId =
+        DiagnosticId::from_u128(0xf6c94b603ce34ca1871feac9c94bdb7f);
+    /// Nodes with chunk data, compressed or not.
+    pub const RESIDENT_CHUNKS: DiagnosticId =
+        DiagnosticId::from_u128(0x60d3c99291f242d3aa8131519b458c51);
+    /// Milliseconds per call to the backend. A call reads a whole load batch, unless one of its chunks was corrupt.
+    pub const READ_LATENCY_P50: DiagnosticId =
+        DiagnosticId::from_u128(0xfce73667bfc5437f9aa7c3839a82b1b6);
+    pub const READ_LATENCY_P90: DiagnosticId =
+        DiagnosticId::from_u128(0x4b4ef11b89f246eeb5d981b1706eba65);
+    pub const READ_LATENCY_P99: DiagnosticId =
+        DiagnosticId::from_u128(0x9f25868c46ee44b2bfc604e9bfcc754f);
+    /// The average size of the compressed chunks in the clipmap, as a fraction of a decompressed chunk.
+    pub const COMPRESSION_RATIO: DiagnosticId =
+        DiagnosticId::from_u128(0x2895e90c7d5f4b4181f2a9a0f2fd4192);
+
+    /// The number of nodes at `level` of the clipmap.
+    pub fn nodes_at_level(level: Level) -> DiagnosticId {
+        DiagnosticId::from_u128(LEVEL_NODES_BASE_ID + u128::from(level))
+    }
+}
+
+/// The IDs of [`MapDiagnosticsPlugin::nodes_at_level`] only differ in the lowest byte.
+const LEVEL_NODES_BASE_ID: u128 = 0x8f81513bd58b4a1999909a4ec56ed600;
+
+const MAX_HISTORY_LENGTH: usize = 20;
+
+impl Plugin for MapDiagnosticsPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ClipMapSampler {
+            timer: Timer::new(self.clipmap_sample_interval, true),
+        })
+        .add_startup_system(setup_diagnostics_system)
+        .add_system_to_stage(CoreStage::PostUpdate, diagnostics_system);
+    }
+}
+
+struct ClipMapSampler {
+    timer: Timer,
+}
+
+fn setup_diagnostics_system(config: Res<MapConfig>, mut diagnostics: ResMut<Diagnostics>) {
+    let new = |id, name| Diagnostic::new(id, name, MAX_HISTORY_LENGTH);
+    diagnostics.add(new(
+        MapDiagnosticsPlugin::PENDING_LOAD_TASKS,
+        "pending_load_tasks",
+    ));
+    diagnostics.add(new(MapDiagnosticsPlugin::INSERT_BACKLOG, "insert_backlog"));
+    diagnostics.add(new(
+        MapDiagnosticsPlugin::LOAD_BATCH_SIZE,
+        "load_batch_size",
+    ));
+    diagnostics.add(new(
+        MapDiagnosticsPlugin::CHUNKS_LOADED_PER_SECOND,
+        "chunks_loaded_per_second",
+    ));
+    diagnostics.add(new(
+        MapDiagnosticsPlugin::RESIDENT_CHUNKS,
+        "resident_chunks",
+    ));
+    for (id, name) in [
+        (MapDiagnosticsPlugin::READ_LATENCY_P50, "read_latency_p50"),
+        (MapDiagnosticsPlugin::READ_LATENCY_P90, "read_latency_p90"),
+        (MapDiagnosticsPlugin::READ_LATENCY_P99, "read_latency_p99"),
+    ] {
+        diagnostics.add(new(id, name).with_suffix("ms"));
+    }
+    diagnostics.add(new(
+        MapDiagnosticsPlugin::COMPRESSION_RATIO,
+        "compression_ratio",
+    ));
+    for level in 0..config.num_lods {
+        diagnostics.add(Diagnostic::new(
+            MapDiagnosticsPlugin::nodes_at_level(level),
+            format!("lod{}_nodes", level),
+            MAX_HISTORY_LENGTH,
+        ));
+    }
+}
+
+fn diagnostics_system(
+    time: Res<Time>,
+    clipmap: Res<ChunkClipMap>,
+    load_tasks: Res<PendingLoadTasks>,
+    mut sampler: ResMut<ClipMapSampler>,
+    mut chunk_events: EventReader<ChunkEvent>,
+    mut diagnostics: ResMut<Diagnostics>,
+) {
+    diagnostics.add_measurement(
+        MapDiagnosticsPlugin::PENDING_LOAD_TASKS,
+        load_tasks.num_tasks() as f64,
+    );
+    diagnostics.add_measurement(
+        MapDiagnosticsPlugin::INSERT_BACKLOG,
+        load_tasks.insert_backlog() as f64,
+    );
+    diagnostics.add_measurement(
+        MapDiagnosticsPlugin::LOAD_BATCH_SIZE,
+        load_tasks.load_batch_size() as f64,
+    );
+
+    let num_loaded = chunk_events
+        .iter()
+        .filter(|event| matches!(event, ChunkEvent::Loaded(_)))
+        .count();
+    let delta_seconds = time.delta_seconds_f64();
+    if delta_seconds > 0.0 {
+        diagnostics.add_measurement(
+            MapDiagnosticsPlugin::CHUNKS_LOADED_PER_SECOND,
+            num_loaded as f64 / delta_seconds,
+        );
+    }
+
+    let mut latencies: Vec<_> = load_tasks.read_latencies().collect();
+    latencies.sort_unstable();
+    for (id, p) in [
+        (MapDiagnosticsPlugin::READ_LATENCY_P50, 0.5),
+        (MapDiagnosticsPlugin::READ_LATENCY_P90, 0.9),
+        (MapDiagnosticsPlugin::READ_LATENCY_P99, 0.99),
+    ] {
+        if let Some(latency) = percentile(&latencies, p) {
+            diagnostics.add_measurement(id, latency.as_secs_f64() * 1000.0);
+        }
+    }
+
+    if !sampler.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    let stats = clipmap.stats();
+    diagnostics.add_measurement(
+        MapDiagnosticsPlugin::RESIDENT_CHUNKS,
+        stats.num_resident as f64,
+    );
+    for (level, &num_nodes) in stats.nodes_per_level.iter().enumerate() {
+        diagnostics.add_measurement(
+            MapDiagnosticsPlugin::nodes_at_level(level as Level),
+            num_nodes as f64,
+        );
+    }
+    if let Some(ratio) = stats.compression_ratio() {
+        diagnostics.add_measurement(MapDiagnosticsPlugin::COMPRESSION_RATIO, ratio);
+    }
+}
+
+/// The nearest-rank percentile `p` (from 0 to 1) of the `sorted` durations.
+fn percentile(sorted: &[Duration], p: f64) -> Option<Duration> {
+    let last = sorted.len().checked_sub(1)?;
+    Some(sorted[(p * last as f64).round() as usize])
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_of_sorted_latencies() {
+        assert_eq!(percentile(&[], 0.5), None);
+
+        let latencies: Vec<_> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&latencies, 0.0), Some(Duration::from_millis(1)));
+        assert_eq!(percentile(&latencies, 0.5), Some(Duration::from_millis(51)));
+        assert_eq!(
+            percentile(&latencies, 0.99),
+            Some(Duration::from_millis(99))
+        );
+        assert_eq!(
+            percentile(&latencies, 1.0),
+            Some(Duration::from_millis(100))
+        );
+    }
+}
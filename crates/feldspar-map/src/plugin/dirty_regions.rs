@@ -0,0 +1,249 @@
+use super::edits::MapEdits;
+use crate::chunk::ChunkDelta;
+use crate::clipmap::ChunkClipMap;
+use crate::coordinates::in_chunk_extent;
+use crate::core::glam::IVec3;
+use crate::core::ilattice::prelude::Extent;
+use crate::core::{SmallKeyHashMap, SmallKeyHashSet};
+use crate::units::{ChunkUnits, VoxelUnits};
+
+use bevy::prelude::*;
+use grid_tree::NodeKey;
+use std::collections::hash_map::Entry;
+
+/// The extents of LOD0 voxels that changed this frame, from edits, undo and redo, and imports.
+///
+/// Extents are collected as they're inserted, then the `dirty_regions_system` coalesces overlapping and adjacent extents once
+/// per frame and uses them to find the meshes that need to be regenerated and the chunks that need to be saved. A brush that
+/// touches the same area many times in one frame only remeshes each affected chunk once, and only the neighbors whose padding
+/// it actually touched.
+///
+/// Other systems can read the coalesced regions of the last frame with [`DirtyRegions::iter`], e.g. to rebuild navigation
+/// meshes.
+///
+/// Where the old voxels were known, e.g. for edits and undo of resident chunks, the exact changes are also recorded as a
+/// [`ChunkDelta`] per LOD0 chunk. Other changes, like imports, only have extents, which are also kept apart in
+/// [`DirtyRegions::iter_without_deltas`].
+#[derive(Default)]
+pub struct DirtyRegions {
+    inserted: Vec<Extent<IVec3>>,
+    coalesced: Vec<Extent<IVec3>>,
+    inserted_without_deltas: Vec<Extent<IVec3>>,
+    coalesced_without_deltas: Vec<Extent<IVec3>>,
+    inserted_deltas: SmallKeyHashMap<IVec3, ChunkDelta>,
+    deltas: SmallKeyHashMap<IVec3, ChunkDelta>,
+}
+
+impl DirtyRegions {
+    /// Records changes in `extent` that aren't recorded as a [`ChunkDelta`].
+    pub fn insert(&mut self, extent: VoxelUnits<Extent<IVec3>>) {
+        let VoxelUnits(extent) = extent;
+        if !extent.is_empty() {
+            self.inserted.push(extent);
+            self.inserted_without_deltas.push(extent);
+        }
+    }
+
+    /// Records the changes in `extent` to the LOD0 chunk at `coords`, after any that were already recorded this frame. Every
+    /// voxel in `extent` that changed must be in the `delta`.
+    pub fn insert_delta(
+        &mut self,
+        coords: ChunkUnits<IVec3>,
+        extent: VoxelUnits<Extent<IVec3>>,
+        delta: ChunkDelta,
+    ) {
+        let VoxelUnits(extent) = extent;
+        if !extent.is_empty() {
+            self.inserted.push(extent);
+        }
+        if delta.is_empty() {
+            return;
+        }
+        let entry = self.inserted_deltas.entry(coords.into_inner());
+        match entry {
+            Entry::Occupied(mut occupied) => {
+                let composed = occupied.get().then(&delta);
+                occupied.insert(composed);
+            }
+            Entry::Vacant(vacant) => {
+                vacant.insert(delta);
+            }
+        }
+    }
+
+    /// The coalesced regions that changed during the last frame.
+    pub fn iter(&self) -> impl Iterator<Item = VoxelUnits<Extent<IVec3>>> + '_ {
+        self.coalesced.iter().copied().map(VoxelUnits)
+    }
+
+    /// The coalesced regions that changed during the last frame without a [delta](Self::deltas). They can overlap chunks
+    /// that also have a delta, e.g. when a chunk was edited and imported in the same frame.
+    pub fn iter_without_deltas(&self) -> impl Iterator<Item = VoxelUnits<Extent<IVec3>>> + '_ {
+        self.coalesced_without_deltas
+            .iter()
+            .copied()
+            .map(VoxelUnits)
+    }
+
+    /// The recorded changes to LOD0 chunks during the last frame.
+    pub fn deltas(&self) -> impl Iterator<Item = (ChunkUnits<IVec3>, &ChunkDelta)> {
+        self.deltas
+            .iter()
+            .map(|(&coords, delta)| (ChunkUnits(coords), delta))
+    }
+
+    /// The coordinates of all LOD0 chunks that intersect [`Self::iter`].
+    pub fn chunks(&self) -> SmallKeyHashSet<IVec3> {
+        let mut chunks = SmallKeyHashSet::default();
+        for region in self.iter() {
+            let ChunkUnits(chunks_extent) = in_chunk_extent(region);
+            chunks.extend(chunks_extent.iter3());
+        }
+        chunks
+    }
+
+    /// Replaces the regions and deltas of the last frame with the ones inserted since.
+    fn coalesce(&mut self) {
+        self.coalesced = coalesce_extents(std::mem::take(&mut self.inserted));
+        self.coalesced_without_deltas =
+            coalesce_extents(std::mem::take(&mut self.inserted_without_deltas));
+        self.deltas = std::mem::take(&mut self.inserted_deltas);
+    }
+}
+
+/// Merges every pair of extents that overlap or touch into their bounding box, until no pairs are left.
+///
+/// Merging distant extents could mark far more voxels than were changed, so extents that are separated by at least one voxel
+/// are kept apart.
+pub fn coalesce_extents(mut extents: Vec<Extent<IVec3>>) -> Vec<Extent<IVec3>> {
+    let mut coalesced: Vec<Extent<IVec3>> = Vec::with_capacity(extents.len());
+    while let Some(mut extent) = extents.pop() {
+        // Absorbing an extent grows this one, so it can start touching extents that it didn't touch before.
+        loop {
+            let grown =
+                Extent::from_min_and_lub(extent.minimum - 1, extent.least_upper_bound() + 1);
+            let num_before = coalesced.len();
+            coalesced.retain(|other| {
+                if grown.intersection(other).is_empty() {
+                    return true;
+                }
+                extent = Extent::from_min_and_lub(
+                    extent.minimum.min(other.minimum),
+                    extent.least_upper_bound().max(other.least_upper_bound()),
+                );
+                false
+            });
+            if coalesced.len() == num_before {
+                break;
+            }
+        }
+        coalesced.push(extent);
+    }
+    coalesced
+}
+
+/// Coalesces the [`DirtyRegions`] inserted this frame, then marks the affected meshes and queues the edited chunks to be saved.
+pub fn dirty_regions_system(
+    clipmap: Res<ChunkClipMap>,
+    mut regions: ResMut<DirtyRegions>,
+    mut edits: ResMut<MapEdits>,
+) {
+    regions.coalesce();
+
+    for region in regions.iter() {
+        clipmap.mark_extent_needs_mesh(region);
+    }
+
+    // Chunks from undo, redo, and imports already match the database, so only the dirty ones are saved.
+    for coords in regions.chunks().into_iter() {
+        let is_dirty = clipmap
+            .octree
+            .find_node(NodeKey::new(0, coords))
+            .map_or(false, |ptr| {
+                clipmap.octree.get_value(ptr).unwrap().state().is_dirty()
+            });
+        if is_dirty {
+            edits.mark_unflushed(ChunkUnits(coords));
+        }
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::Chunk;
+    use crate::sdf::Sd8;
+
+    fn cube(min: [i32; 3], edge: i32) -> Extent<IVec3> {
+        Extent::from_min_and_shape(IVec3::from(min), IVec3::splat(edge))
+    }
+
+    #[test]
+    fn coalesce_overlapping_and_adjacent_extents() {
+        let extents = vec![
+            cube([0, 0, 0], 4),
+            // Overlapping the first.
+            cube([2, 2, 2], 4),
+            // Adjacent to the second.
+            cube([6, 2, 2], 2),
+            // Far away.
+            cube([20, 0, 0], 2),
+            // Exactly the same as the previous one, like a brush applied twice.
+            cube([20, 0, 0], 2),
+        ];
+
+        let mut coalesced = coalesce_extents(extents);
+        coalesced.sort_by_key(|e| e.minimum.x);
+        assert_eq!(
+            coalesced,
+            vec![
+                Extent::from_min_and_lub(IVec3::ZERO, IVec3::new(8, 6, 6)),
+                cube([20, 0, 0], 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn chunks_of_coalesced_regions() {
+        let mut regions = DirtyRegions::default();
+        regions.insert(VoxelUnits(cube([14, 0, 0], 4)));
+        regions.insert(VoxelUnits(cube([15, 0, 0], 1)));
+        regions.coalesced = coalesce_extents(std::mem::take(&mut regions.inserted));
+
+        assert_eq!(regions.iter().count(), 1);
+        let mut chunks: Vec<_> = regions.chunks().into_iter().collect();
+        chunks.sort_by_key(|c| c.x);
+        assert_eq!(chunks, vec![IVec3::ZERO, IVec3::X]);
+    }
+
+    #[test]
+    fn regions_without_deltas_are_kept_apart() {
+        let mut regions = DirtyRegions::default();
+        let edited = cube([2, 2, 2], 2);
+        let mut old = Chunk::default();
+        old.set_voxel(IVec3::splat(2), 1, Sd8::MIN);
+        let delta = ChunkDelta::between(&Chunk::default(), &old);
+        regions.insert_delta(ChunkUnits(IVec3::ZERO), VoxelUnits(edited), delta);
+        // Imported over the same chunk.
+        regions.insert(VoxelUnits(cube([8, 0, 0], 4)));
+        regions.coalesce();
+
+        assert_eq!(regions.iter().count(), 2);
+        assert_eq!(regions.deltas().count(), 1);
+        assert_eq!(
+            regions.iter_without_deltas().collect::<Vec<_>>(),
+            vec![VoxelUnits(cube([8, 0, 0], 4))]
+        );
+
+        regions.coalesce();
+        assert_eq!(regions.iter().count(), 0);
+        assert_eq!(regions.iter_without_deltas().count(), 0);
+    }
+}
@@ -0,0 +1,92 @@
+use super::config::MapConfig;
+use super::tasks::MapTask;
+use crate::chunk::Chunk;
+use crate::clipmap::{ChunkClipMap, Level};
+
+use feldspar_core::glam::IVec3;
+
+use bevy::prelude::*;
+use futures_lite::future;
+use grid_tree::NodeKey;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub struct DownsamplingConfig {
+    /// The finest level that gets downsampled chunks. Must be at least 1.
+    pub min_level: Level,
+    /// The coarsest level that gets downsampled chunks. Coarser levels are only resident if they were loaded from the
+    /// database.
+    pub max_level: Level,
+    /// The number of nodes to start downsampling in a single frame (batch).
+    pub downsample_batch_size: usize,
+    /// The maximum number of pending downsample tasks.
+    pub max_pending_downsample_tasks: usize,
+}
+
+impl Default for DownsamplingConfig {
+    fn default() -> Self {
+        Self {
+            min_level: 1,
+            max_level: Level::MAX,
+            downsample_batch_size: 64,
+            max_pending_downsample_tasks: 4,
+        }
+    }
+}
+
+pub struct PendingDownsampleTasks {
+    tasks: VecDeque<MapTask<Vec<(NodeKey<IVec3>, Option<Chunk>)>>>,
+}
+
+impl PendingDownsampleTasks {
+    pub fn new() -> Self {
+        PendingDownsampleTasks {
+            tasks: VecDeque::new(),
+        }
+    }
+}
+
+/// Regenerates coarse chunks from their children whenever the children change.
+pub fn downsampler_system(
+    config: Res<MapConfig>,
+    mut clipmap: ResMut<ChunkClipMap>,
+    mut downsample_tasks: ResMut<PendingDownsampleTasks>,
+) {
+    let PendingDownsampleTasks { tasks } = &mut *downsample_tasks;
+
+    // Complete pending downsample tasks in queue order.
+    while let Some(mut task) = tasks.pop_front() {
+        if let Some(downsampled) = future::block_on(future::poll_once(&mut task)) {
+            for (key, chunk) in downsampled.into_iter() {
+                clipmap.complete_downsample(key, chunk);
+            }
+        } else {
+            tasks.push_front(task);
+            break;
+        }
+    }
+
+    if tasks.len() >= config.downsampling.max_pending_downsample_tasks {
+        return;
+    }
+
+    let DownsamplingConfig {
+        min_level,
+        max_level,
+        downsample_batch_size,
+        ..
+    } = config.downsampling;
+    let jobs = clipmap.downsample_search(min_level..=max_level, downsample_batch_size);
+    if jobs.is_empty() {
+        return;
+    }
+
+    // Spawn a new task to downsample those nodes.
+    let task = MapTask::compute(config.deterministic, async move {
+        jobs.into_iter()
+            .map(|job| (job.key, job.downsample()))
+            .collect()
+    });
+    tasks.push_back(task);
+}
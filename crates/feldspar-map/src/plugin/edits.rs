@@ -0,0 +1,383 @@
+use super::commands::CommandConflicts;
+use super::config::MapConfig;
+use super::dirty_regions::DirtyRegions;
+use super::events::ChunkEvent;
+use super::history::MapHistory;
+use super::import::MapImports;
+use super::tasks::MapTask;
+use super::unsaved::UnsavedChunks;
+use super::validation::{EditApplied, EditInfo, EditRejected, EditTag, EditValidator};
+use crate::brush::Brush;
+use crate::chunk::{Chunk, ChunkDelta, ChunkShape};
+use crate::clipmap::{ChunkClipMap, EditOutcome};
+use crate::coordinates::{chunk_extent_ivec3, in_chunk, in_chunk_extent};
+use crate::core::glam::IVec3;
+use crate::core::ilattice::prelude::Extent;
+use crate::core::{SmallKeyHashMap, SmallKeyHashSet};
+use crate::database::{BackendError, MapBackend};
+use crate::palette::PaletteId8;
+use crate::sdf::Sd8;
+use crate::stamp::{PlacedStamp, StampBlendMode, VoxelStamp};
+use crate::units::{ChunkUnits, VoxelUnits};
+
+use bevy::prelude::*;
+use futures_lite::future;
+use grid_tree::NodeKey;
+use ndshape::ConstShape;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(default)]
+pub struct EditConfig {
+    /// How [`MapCommands`](crate::MapCommands) that were queued in the same frame are resolved when they overlap.
+    pub command_conflicts: CommandConflicts,
+    /// The maximum number of edited chunks to write to the database in a single batch.
+    pub flush_batch_size: usize,
+}
+
+impl Default for EditConfig {
+    fn default() -> Self {
+        Self {
+            command_conflicts: CommandConflicts::default(),
+            flush_batch_size: 64,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) enum Edit {
+    SetSdf(VoxelUnits<IVec3>, Sd8),
+    SetMaterial(VoxelUnits<IVec3>, PaletteId8),
+    Brush(Arc<dyn Brush>),
+    Delta(ChunkUnits<IVec3>, Arc<ChunkDelta>),
+}
+
+impl Edit {
+    fn visit_chunks(&self, mut visitor: impl FnMut(ChunkUnits<IVec3>)) {
+        match self {
+            Self::SetSdf(p, _) | Self::SetMaterial(p, _) => visitor(in_chunk(*p)),
+            Self::Delta(coords, _) => visitor(*coords),
+            Self::Brush(brush) => {
+                let ChunkUnits(chunks_extent) = in_chunk_extent(brush.extent());
+                for coords in chunks_extent.iter3() {
+                    visitor(ChunkUnits(coords));
+                }
+            }
+        }
+    }
+
+    /// All voxels that could be changed by this edit.
+    pub(crate) fn extent(&self) -> VoxelUnits<Extent<IVec3>> {
+        match self {
+            Self::SetSdf(p, _) | Self::SetMaterial(p, _) => {
+                p.map(|p| Extent::from_min_and_shape(p, IVec3::ONE))
+            }
+            Self::Brush(brush) => brush.extent(),
+            Self::Delta(coords, delta) => delta.extent(*coords),
+        }
+    }
+
+    fn apply_to_chunk(&self, chunk_coords: ChunkUnits<IVec3>, chunk: &mut Chunk) {
+        let VoxelUnits(chunk_extent) = chunk_extent_ivec3(chunk_coords);
+        let index =
+            |p: IVec3| ChunkShape::linearize((p - chunk_extent.minimum).to_array()) as usize;
+        match self {
+            Self::SetSdf(VoxelUnits(p), sdf) => chunk.sdf[index(*p)] = *sdf,
+            Self::SetMaterial(VoxelUnits(p), palette_id) => {
+                chunk.palette_ids[index(*p)] = *palette_id
+            }
+            Self::Brush(brush) => {
+                let VoxelUnits(brush_extent) = brush.extent();
+                for p in chunk_extent.intersection(&brush_extent).iter3() {
+                    let i = index(p);
+                    brush.paint(VoxelUnits(p), &mut chunk.sdf[i], &mut chunk.palette_ids[i]);
+                }
+            }
+            Self::Delta(_, delta) => delta.apply(chunk),
+        }
+    }
+}
+
+/// Voxel edits that will be written into the [`ChunkClipMap`] by the `edit_system`.
+///
+/// All writes target LOD0 voxels. Coarser levels of detail are regenerated by downsampling, and the edited voxels are added to
+/// the [`DirtyRegions`], which marks the affected meshes and queues the edited chunks to be written back to the
+/// [`MapBackend`] in batches, so multiple edits of one chunk only cost one write.
+///
+/// Edits of chunks that are still loading are retried on later frames. Edits of chunks outside of the clipmap are dropped.
+///
+/// If there is an [`EditValidator`], each edit is validated before it's applied, and rejected edits are dropped.
+#[derive(Default)]
+pub struct MapEdits {
+    queued: Vec<(EditTag, Edit)>,
+    /// Edits of chunks that were still loading, keyed by LOD0 chunk coordinates, in the order they were queued.
+    deferred: SmallKeyHashMap<IVec3, Vec<(EditTag, Edit)>>,
+    /// LOD0 chunks that were edited but not yet written to the database.
+    unflushed: SmallKeyHashSet<IVec3>,
+}
+
+impl MapEdits {
+    pub fn set_sdf(&mut self, p: VoxelUnits<IVec3>, sdf: Sd8) {
+        self.queued.push((EditTag::LOCAL, Edit::SetSdf(p, sdf)));
+    }
+
+    pub fn set_material(&mut self, p: VoxelUnits<IVec3>, palette_id: PaletteId8) {
+        self.queued
+            .push((EditTag::LOCAL, Edit::SetMaterial(p, palette_id)));
+    }
+
+    pub fn apply_brush(&mut self, brush: impl Brush) {
+        self.apply_brush_as(EditTag::LOCAL, brush);
+    }
+
+    /// Like [`Self::apply_brush`], but the [`EditValidator`](crate::EditValidator) sees `tag`.
+    pub fn apply_brush_as(&mut self, tag: EditTag, brush: impl Brush) {
+        self.queued.push((tag, Edit::Brush(Arc::new(brush))));
+    }
+
+    /// Places `stamp` with its anchor at `position`. Chunks that are still loading get their part of the stamp when they
+    /// finish, like any other brush. Use [`ChunkClipMap::stamp`] to place a stamp only once all of its chunks are loaded.
+    pub fn stamp(
+        &mut self,
+        position: VoxelUnits<IVec3>,
+        stamp: Arc<VoxelStamp>,
+        mode: StampBlendMode,
+    ) {
+        self.apply_brush(PlacedStamp {
+            stamp,
+            position,
+            mode,
+        });
+    }
+
+    /// Writes the new values of the voxels changed by `delta` into the LOD0 chunk at `coords`, e.g. to replay changes that
+    /// were made on another machine.
+    pub fn apply_delta(&mut self, coords: ChunkUnits<IVec3>, delta: ChunkDelta) {
+        self.apply_delta_as(EditTag::LOCAL, coords, delta);
+    }
+
+    /// Like [`Self::apply_delta`], but the [`EditValidator`](crate::EditValidator) sees `tag`.
+    pub fn apply_delta_as(&mut self, tag: EditTag, coords: ChunkUnits<IVec3>, delta: ChunkDelta) {
+        self.queued
+            .push((tag, Edit::Delta(coords, Arc::new(delta))));
+    }
+
+    pub(crate) fn push(&mut self, tag: EditTag, edit: Edit) {
+        self.queued.push((tag, edit));
+    }
+
+    /// The number of edits that are waiting for their chunks to load.
+    pub fn num_deferred(&self) -> usize {
+        self.deferred.values().map(Vec::len).sum()
+    }
+
+    pub(crate) fn mark_unflushed(&mut self, coords: ChunkUnits<IVec3>) {
+        self.unflushed.insert(coords.into_inner());
+    }
+
+    /// True when every edit has been applied to the clipmap and handed off to a flush task.
+    pub(crate) fn is_flushed(&self) -> bool {
+        self.queued.is_empty() && self.deferred.is_empty() && self.unflushed.is_empty()
+    }
+}
+
+/// The bounding box of all voxels in the chunk at `coords` that could be changed by `edits`.
+fn edited_extent(
+    coords: ChunkUnits<IVec3>,
+    edits: &[(EditTag, Edit)],
+) -> VoxelUnits<Extent<IVec3>> {
+    let VoxelUnits(chunk_extent) = chunk_extent_ivec3(coords);
+    let mut min = IVec3::splat(i32::MAX);
+    let mut lub = IVec3::splat(i32::MIN);
+    for (_, edit) in edits.iter() {
+        let VoxelUnits(extent) = edit.extent();
+        let extent = extent.intersection(&chunk_extent);
+        min = min.min(extent.minimum);
+        lub = lub.max(extent.least_upper_bound());
+    }
+    VoxelUnits(Extent::from_min_and_lub(min, lub))
+}
+
+pub struct FlushedBatch {
+    num_chunks: usize,
+    result: Result<(), BackendError>,
+}
+
+#[derive(Default)]
+pub struct PendingFlushTask {
+    /// Only one flush is in flight at a time, so that batches are written to the database in the order they were edited.
+    task: Option<MapTask<FlushedBatch>>,
+}
+
+impl PendingFlushTask {
+    pub(crate) fn is_idle(&self) -> bool {
+        self.task.is_none()
+    }
+
+    /// Blocks until the flush in flight, if any, is written.
+    pub(crate) fn wait(&mut self) {
+        if let Some(task) = self.task.take() {
+            let flushed_batch = future::block_on(task);
+            if let Err(e) = flushed_batch.result {
+                log::error!(
+                    "Failed to flush batch of {} edited chunks: {:?}",
+                    flushed_batch.num_chunks,
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Writes the [`MapEdits`] into the [`ChunkClipMap`] and flushes the edited chunks to the [`MapBackend`].
+///
+/// Edits are held in the queue while the [`MapHistory`] is moving between versions, or while [`MapImports`] are being
+/// written.
+pub fn edit_system(
+    config: Res<MapConfig>,
+    backend: Res<Arc<dyn MapBackend>>,
+    history: Res<MapHistory>,
+    imports: Res<MapImports>,
+    mut clipmap: ResMut<ChunkClipMap>,
+    mut edits: ResMut<MapEdits>,
+    mut flush_task: ResMut<PendingFlushTask>,
+    unsaved: Res<UnsavedChunks>,
+    validator: Option<Res<Arc<dyn EditValidator>>>,
+    mut dirty_regions: ResMut<DirtyRegions>,
+    mut chunk_events: EventWriter<ChunkEvent>,
+    mut rejections: EventWriter<EditRejected>,
+    mut applied: EventWriter<EditApplied>,
+) {
+    if history.is_busy() || imports.is_busy() {
+        // Those only start once all edits are flushed, so there is nothing else to do.
+        return;
+    }
+
+    let MapEdits {
+        queued,
+        deferred,
+        unflushed,
+    } = &mut *edits;
+
+    // Group the edits by chunk. Deferred edits are older, so they go first. They were already validated.
+    let mut chunk_edits = std::mem::take(deferred);
+    for (tag, edit) in queued.drain(..) {
+        if let Some(validator) = &validator {
+            let info = EditInfo {
+                tag,
+                extent: edit.extent(),
+            };
+            if let Err(reason) = validator.validate(&info) {
+                log::debug!("Rejected edit {:?}: {}", info, reason);
+                rejections.send(EditRejected { edit: info, reason });
+                continue;
+            }
+        }
+        edit.visit_chunks(|ChunkUnits(coords)| {
+            chunk_edits
+                .entry(coords)
+                .or_default()
+                .push((tag, edit.clone()));
+        });
+    }
+
+    for (coords, edits_in_chunk) in chunk_edits.into_iter() {
+        let mut delta = ChunkDelta::default();
+        // The meshes of the edited voxels are marked by the `dirty_regions_system`.
+        let outcome = clipmap.edit_chunk_without_meshes(ChunkUnits(coords), |chunk| {
+            let old = *chunk;
+            for (_, edit) in edits_in_chunk.iter() {
+                edit.apply_to_chunk(ChunkUnits(coords), chunk);
+            }
+            delta = ChunkDelta::between(&old, chunk);
+        });
+        match outcome {
+            EditOutcome::Applied => {
+                let extent = edited_extent(ChunkUnits(coords), &edits_in_chunk);
+                dirty_regions.insert_delta(ChunkUnits(coords), extent, delta);
+                chunk_events.send(ChunkEvent::Edited(extent));
+                applied.send_batch(
+                    edits_in_chunk
+                        .iter()
+                        .filter(|(tag, _)| !tag.is_local())
+                        .map(|&(tag, _)| EditApplied {
+                            tag,
+                            chunk: ChunkUnits(coords),
+                        }),
+                );
+            }
+            EditOutcome::Deferred => {
+                deferred.insert(coords, edits_in_chunk);
+            }
+            EditOutcome::OutOfRange => {
+                log::warn!(
+                    "Dropped {} edits of chunk {:?} outside of the clipmap",
+                    edits_in_chunk.len(),
+                    coords
+                );
+                rejections.send_batch(
+                    edits_in_chunk
+                        .iter()
+                        .filter(|(tag, _)| !tag.is_local())
+                        .map(|(tag, edit)| EditRejected {
+                            edit: EditInfo {
+                                tag: *tag,
+                                extent: edit.extent(),
+                            },
+                            reason: "outside of the clipmap".into(),
+                        }),
+                );
+            }
+        }
+    }
+
+    // Complete the pending flush.
+    if let Some(task) = &mut flush_task.task {
+        if let Some(flushed_batch) = future::block_on(future::poll_once(task)) {
+            if let Err(e) = flushed_batch.result {
+                log::error!(
+                    "Failed to flush batch of {} edited chunks: {:?}",
+                    flushed_batch.num_chunks,
+                    e
+                );
+            }
+            flush_task.task = None;
+        } else {
+            return;
+        }
+    }
+
+    // Copy a batch of edited chunks. Chunks that were already evicted are saved by the saver. Both write through the
+    // unsaved set, so if a chunk is edited again and evicted while its flush is in flight, the older flush doesn't overwrite
+    // the evicted chunk.
+    let batch_coords: Vec<_> = unflushed
+        .iter()
+        .take(config.edits.flush_batch_size)
+        .copied()
+        .collect();
+    let mut changes = Vec::with_capacity(batch_coords.len());
+    for coords in batch_coords.into_iter() {
+        unflushed.remove(&coords);
+        let key = NodeKey::new(0, coords);
+        if let Some(change) = clipmap.take_dirty_chunk(key) {
+            changes.push((key, change));
+        }
+    }
+
+    if changes.is_empty() {
+        return;
+    }
+
+    // Spawn a new task to compress and write those chunks.
+    let batch = unsaved.insert_changes(changes);
+    let unsaved = UnsavedChunks::clone(&unsaved);
+    let backend_clone = Arc::clone(&backend);
+    let encoding = config.encoding;
+    flush_task.task = Some(MapTask::io(config.deterministic, async move {
+        FlushedBatch {
+            num_chunks: batch.len(),
+            result: unsaved.write(batch, encoding, &*backend_clone).map(|_| ()),
+        }
+    }));
+}
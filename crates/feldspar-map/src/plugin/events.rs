@@ -0,0 +1,61 @@
+use crate::core::glam::{IVec3, Vec3A};
+use crate::core::ilattice::prelude::Extent;
+use crate::database::ChunkDbKey;
+use crate::units::VoxelUnits;
+
+use bevy::prelude::Entity;
+use grid_tree::NodeKey;
+
+/// Sent when the contents of the [`ChunkClipMap`](crate::clipmap::ChunkClipMap) change, so other systems can react
+/// incrementally instead of polling the clipmap.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChunkEvent {
+    /// The loader inserted this node into the clipmap. Canceled loads and loads superseded by an edit are not sent.
+    Loaded(NodeKey<IVec3>),
+    /// LOD0 voxels in this extent were changed by the [`MapEdits`](crate::MapEdits), [`MapHistory`](crate::MapHistory), or
+    /// [`MapImports`](crate::MapImports). Downsampled ancestors change later, without an event.
+    Edited(VoxelUnits<Extent<IVec3>>),
+    /// The renderer replaced the mesh of this node. The node might not have a surface, in which case the mesh was only
+    /// removed.
+    Meshed(NodeKey<IVec3>),
+    /// The saver removed this node from the clipmap.
+    Evicted(NodeKey<IVec3>),
+}
+
+/// Sent when the plugin fails to load part of the stored map. How the loader recovers is chosen by the [`ErrorPolicy`] in
+/// its config, so a single bad record can't crash the game loop. The app can warn the player or repair the map, e.g. with
+/// [`MapDb::verify_all`](crate::database::MapDb::verify_all).
+///
+/// [`ErrorPolicy`]: crate::ErrorPolicy
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MapError {
+    /// The backend failed to read these chunks, e.g. because of an IO error.
+    ReadFailed {
+        keys: Vec<ChunkDbKey>,
+        /// The debug representation of the [`BackendError`](crate::database::BackendError).
+        error: String,
+    },
+    /// The stored chunk doesn't match its checksum.
+    CorruptChunk(ChunkDbKey),
+    /// The stored chunk matches its checksum, if it has one, but it can't be decompressed.
+    UndecodableChunk(ChunkDbKey),
+}
+
+/// Sent when a [`Witness`](crate::Witness) jumps farther than the [`LoaderConfig::teleport_distance`] in one frame, e.g. to
+/// show a loading screen while its new surroundings stream in.
+///
+/// Instead of streaming in incrementally from the old position, every tree outside of the new clip shapes is evicted at
+/// once, and any loads that completed outside of them are dropped unless they were already inserted.
+///
+/// [`LoaderConfig::teleport_distance`]: crate::LoaderConfig::teleport_distance
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TeleportEvent {
+    /// The witness moved between these voxel positions since the previous frame.
+    Started {
+        witness: Entity,
+        from: VoxelUnits<Vec3A>,
+        to: VoxelUnits<Vec3A>,
+    },
+    /// Everything around all witnesses is loaded, for the first time since this witness teleported.
+    Finished { witness: Entity },
+}
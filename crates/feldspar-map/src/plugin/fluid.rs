@@ -0,0 +1,571 @@
+use super::config::MapConfig;
+use super::dirty_regions::DirtyRegions;
+use super::maps::ActiveMap;
+use super::tasks::MapTask;
+use super::witness::Witness;
+use crate::chunk::{
+    ChunkLayers, ChunkShape, FluidChunk, FluidLayer, VoxelLayerSchema, CHUNK_SIZE, MAX_FLUID,
+};
+use crate::clipmap::{ChunkClipMap, SdfSampler};
+use crate::coordinates::{chunk_min, in_chunk};
+use crate::core::glam::{const_ivec3, IVec3};
+use crate::core::{SmallKeyHashMap, SmallKeyHashSet};
+use crate::database::{ChunkReadError, MapDb};
+use crate::units::{ChunkUnits, VoxelUnits};
+use crate::world_transform::VoxelWorldTransform;
+
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use futures_lite::future;
+use grid_tree::NodeKey;
+use ndshape::ConstShape;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+const DOWN: IVec3 = const_ivec3!([0, -1, 0]);
+const FACE_OFFSETS: [IVec3; 6] = [
+    const_ivec3!([-1, 0, 0]),
+    const_ivec3!([1, 0, 0]),
+    DOWN,
+    const_ivec3!([0, 1, 0]),
+    const_ivec3!([0, 0, -1]),
+    const_ivec3!([0, 0, 1]),
+];
+/// Fluid falls before it spreads.
+const FLOW_OFFSETS: [IVec3; 5] = [
+    DOWN,
+    const_ivec3!([-1, 0, 0]),
+    const_ivec3!([1, 0, 0]),
+    const_ivec3!([0, 0, -1]),
+    const_ivec3!([0, 0, 1]),
+];
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub struct FluidConfig {
+    /// Simulates the fluid levels stored in the [`FluidLayer`], which is registered with the map's other voxel layers if this
+    /// is enabled on startup.
+    pub enabled: bool,
+    /// Fluid is only simulated in the loaded LOD0 chunks within this many chunks of any [`Witness`], measured along each axis.
+    /// Chunks outside of this range are frozen, and fluid can't flow into them.
+    pub active_radius_chunks: u32,
+    /// The maximum number of awake chunks that are stepped on each frame.
+    pub max_chunks_per_frame: usize,
+    /// A chunk falls asleep once this many of its steps in a row didn't move any fluid, and it's written to the database. It
+    /// wakes up when fluid flows in from a neighbor, when it's edited, or when [`FluidSim::set`] is called on it.
+    pub settle_steps: u32,
+}
+
+impl Default for FluidConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            active_radius_chunks: 3,
+            max_chunks_per_frame: 32,
+            settle_steps: 4,
+        }
+    }
+}
+
+struct FluidChunkState {
+    levels: Box<FluidChunk>,
+    /// The layers that were read from the database, so the other layers are written back unchanged.
+    layers: Option<ChunkLayers>,
+    /// The layers couldn't be read, so the chunk is never written back, or its other stored layers would be replaced with
+    /// defaults.
+    read_failed: bool,
+    /// The number of steps in a row that didn't change this chunk.
+    quiet_steps: u32,
+    awake: bool,
+    /// Changed since it was last written to the database.
+    dirty: bool,
+}
+
+/// The fluid in every LOD0 chunk that's being simulated by the `fluid_system`.
+///
+/// Each voxel holds from 0 to [`MAX_FLUID`] units of fluid. On every step of a chunk, each of its voxels drops as much fluid
+/// as fits into the open voxel below, and then gives one unit to each open neighbor on its sides with at least two units
+/// less. Solid voxels and voxels in chunks that aren't simulated are never open. Fluid is never created or destroyed by a
+/// step, so it settles into level pools.
+///
+/// Only awake chunks are stepped, which bounds the work on each frame to the fluid that's still moving.
+#[derive(Default)]
+pub struct FluidSim {
+    chunks: SmallKeyHashMap<IVec3, FluidChunkState>,
+    /// Awake chunks, in the order they're stepped.
+    awake: VecDeque<IVec3>,
+}
+
+impl FluidSim {
+    /// The fluid level of the voxel at `p`. Voxels in chunks that aren't simulated are dry.
+    pub fn level(&self, p: VoxelUnits<IVec3>) -> u8 {
+        let (coords, index) = locate(p.into_inner());
+        self.chunks.get(&coords).map_or(0, |c| c.levels[index])
+    }
+
+    /// Sets the fluid level of the voxel at `p` and wakes the chunks that it could flow into, e.g. to place a spring.
+    ///
+    /// Returns `false` without changing anything if the voxel's chunk isn't being simulated.
+    pub fn set(&mut self, p: VoxelUnits<IVec3>, level: u8) -> bool {
+        let VoxelUnits(p) = p;
+        let (coords, index) = locate(p);
+        let chunk = if let Some(chunk) = self.chunks.get_mut(&coords) {
+            chunk
+        } else {
+            return false;
+        };
+        chunk.levels[index] = level.min(MAX_FLUID);
+        chunk.dirty = true;
+        let mut woken = SmallKeyHashSet::default();
+        note_change(p, &mut woken);
+        self.wake_all(woken);
+        true
+    }
+
+    pub fn is_simulated(&self, coords: ChunkUnits<IVec3>) -> bool {
+        self.chunks.contains_key(&coords.into_inner())
+    }
+
+    pub fn is_awake(&self, coords: ChunkUnits<IVec3>) -> bool {
+        self.chunks
+            .get(&coords.into_inner())
+            .map_or(false, |c| c.awake)
+    }
+
+    pub fn num_awake(&self) -> usize {
+        self.awake.len()
+    }
+
+    /// Makes the chunk at `coords` step again, if it's simulated.
+    pub fn wake(&mut self, coords: ChunkUnits<IVec3>) {
+        let ChunkUnits(coords) = coords;
+        if let Some(chunk) = self.chunks.get_mut(&coords) {
+            chunk.quiet_steps = 0;
+            if !chunk.awake {
+                chunk.awake = true;
+                self.awake.push_back(coords);
+            }
+        }
+    }
+
+    /// Steps up to `max_chunks` awake chunks, in the order they woke up, and returns the chunks that fell asleep because they
+    /// went `settle_steps` steps without changing.
+    pub fn step(
+        &mut self,
+        clipmap: &ChunkClipMap,
+        max_chunks: usize,
+        settle_steps: u32,
+    ) -> Vec<ChunkUnits<IVec3>> {
+        let mut sampler = SdfSampler::new(clipmap);
+        let mut asleep = Vec::new();
+        for _ in 0..max_chunks.min(self.awake.len()) {
+            let coords = self.awake.pop_front().unwrap();
+            let mut woken = self.step_chunk(&mut sampler, coords);
+            let changed = woken.remove(&coords);
+            self.wake_all(woken);
+
+            let chunk = self.chunks.get_mut(&coords).unwrap();
+            if changed {
+                chunk.quiet_steps = 0;
+            } else {
+                chunk.quiet_steps += 1;
+            }
+            if chunk.quiet_steps >= settle_steps {
+                chunk.awake = false;
+                asleep.push(ChunkUnits(coords));
+            } else {
+                self.awake.push_back(coords);
+            }
+        }
+        asleep
+    }
+
+    /// Moves the fluid in one chunk, possibly into its neighbors. Returns the chunks that need to be stepped again because
+    /// fluid moved in or next to them.
+    fn step_chunk(&mut self, sampler: &mut SdfSampler, coords: IVec3) -> SmallKeyHashSet<IVec3> {
+        let mut woken = SmallKeyHashSet::default();
+        let VoxelUnits(min) = chunk_min(ChunkUnits(coords));
+        for i in 0..CHUNK_SIZE {
+            let mut level = self.chunks[&coords].levels[i];
+            if level == 0 {
+                continue;
+            }
+            let p = min + IVec3::from(ChunkShape::delinearize(i as i32));
+            for offset in FLOW_OFFSETS {
+                if level == 0 {
+                    break;
+                }
+                let q = p + offset;
+                let q_level = if let Some(q_level) = self.open_level(sampler, q) {
+                    q_level
+                } else {
+                    continue;
+                };
+                let amount = if offset == DOWN {
+                    level.min(MAX_FLUID - q_level)
+                } else {
+                    u8::from(q_level + 1 < level)
+                };
+                if amount == 0 {
+                    continue;
+                }
+                level -= amount;
+                self.add_level(p, level, 0);
+                self.add_level(q, q_level, amount);
+                note_change(p, &mut woken);
+                note_change(q, &mut woken);
+            }
+        }
+        woken
+    }
+
+    /// The fluid level at `p`, or `None` if `p` is solid or not simulated.
+    fn open_level(&self, sampler: &mut SdfSampler, p: IVec3) -> Option<u8> {
+        let (coords, index) = locate(p);
+        let level = self.chunks.get(&coords)?.levels[index];
+        let (sdf, _) = sampler.voxel(VoxelUnits(p));
+        (sdf.0 >= 0).then_some(level)
+    }
+
+    /// Sets the level at `p` to `level + amount`.
+    fn add_level(&mut self, p: IVec3, level: u8, amount: u8) {
+        let (coords, index) = locate(p);
+        let chunk = self.chunks.get_mut(&coords).unwrap();
+        chunk.levels[index] = level + amount;
+        chunk.dirty = true;
+    }
+
+    /// Wakes `chunks` in a fixed order, so every run steps them in the same order.
+    fn wake_all(&mut self, chunks: SmallKeyHashSet<IVec3>) {
+        let mut chunks: Vec<_> = chunks.into_iter().collect();
+        chunks.sort_by_key(|c| c.to_array());
+        for coords in chunks.into_iter() {
+            self.wake(ChunkUnits(coords));
+        }
+    }
+
+    /// Starts simulating the chunk at `coords`. It's awake if it has any fluid.
+    fn insert(&mut self, coords: IVec3, levels: Box<FluidChunk>, layers: Option<ChunkLayers>) {
+        let has_fluid = levels.iter().any(|&l| l > 0);
+        self.chunks.insert(
+            coords,
+            FluidChunkState {
+                levels,
+                layers,
+                quiet_steps: 0,
+                awake: false,
+                read_failed: false,
+                dirty: false,
+            },
+        );
+        if has_fluid {
+            self.wake(ChunkUnits(coords));
+        }
+    }
+
+    /// Simulates the chunk at `coords` as dry after its layers failed to be read, so it isn't read again on every frame.
+    fn insert_unreadable(&mut self, coords: IVec3) {
+        self.insert(coords, Box::new([0; CHUNK_SIZE]), None);
+        self.chunks.get_mut(&coords).unwrap().read_failed = true;
+    }
+
+    fn remove(&mut self, coords: IVec3) -> Option<FluidChunkState> {
+        let removed = self.chunks.remove(&coords)?;
+        if removed.awake {
+            self.awake.retain(|&c| c != coords);
+        }
+        Some(removed)
+    }
+}
+
+fn locate(p: IVec3) -> (IVec3, usize) {
+    let ChunkUnits(coords) = in_chunk(VoxelUnits(p));
+    let VoxelUnits(min) = chunk_min(ChunkUnits(coords));
+    (coords, ChunkShape::linearize((p - min).to_array()) as usize)
+}
+
+/// Adds the chunk of `p` and the chunks across any faces that `p` touches to `woken`.
+fn note_change(p: IVec3, woken: &mut SmallKeyHashSet<IVec3>) {
+    woken.insert(in_chunk(VoxelUnits(p)).into_inner());
+    for offset in FACE_OFFSETS {
+        woken.insert(in_chunk(VoxelUnits(p + offset)).into_inner());
+    }
+}
+
+type FluidReads = Vec<(IVec3, Result<Option<ChunkLayers>, ChunkReadError>)>;
+
+/// The reads of chunks that came into range of a witness, waiting to start being simulated.
+#[derive(Default)]
+pub struct PendingFluidReads {
+    tasks: Vec<MapTask<FluidReads>>,
+    reading: SmallKeyHashSet<IVec3>,
+}
+
+/// Simulates the [`FluidSim`] in the loaded LOD0 chunks near each [`Witness`].
+///
+/// Chunks are read from the database as they come into range, and they're written back when they fall asleep, when they go
+/// out of range or get evicted, and when the app exits. Edited chunks are woken up so fluid can flow into new holes.
+pub fn fluid_system(
+    config: Res<MapConfig>,
+    schema: Res<VoxelLayerSchema>,
+    regions: Res<DirtyRegions>,
+    clipmap: Res<ChunkClipMap>,
+    db: Option<Res<Arc<RwLock<MapDb>>>>,
+    world_transform: Res<VoxelWorldTransform>,
+    active_map: Res<ActiveMap>,
+    witnesses: Query<(&Witness, &Transform)>,
+    mut sim: ResMut<FluidSim>,
+    mut pending: ResMut<PendingFluidReads>,
+) {
+    if !config.fluid.enabled {
+        return;
+    }
+    let layer = if let Some(layer) = FluidLayer::find(&schema) {
+        layer
+    } else {
+        return;
+    };
+
+    let radius = config.fluid.active_radius_chunks as i32;
+    let mut active = SmallKeyHashSet::default();
+    for (_, tfm) in witnesses.iter().filter(|(w, _)| w.is_in(&active_map)) {
+        let position = world_transform.transform_to_voxel(tfm);
+        let ChunkUnits(extent) = position.floor_chunk().extent_around(radius);
+        active.extend(
+            extent
+                .iter3()
+                .filter(|&c| clipmap.is_loaded(NodeKey::new(0, c))),
+        );
+    }
+
+    let inactive: Vec<_> = sim
+        .chunks
+        .keys()
+        .filter(|coords| !active.contains(coords))
+        .copied()
+        .collect();
+    for coords in inactive.into_iter() {
+        let mut chunk = sim.remove(coords).unwrap();
+        if let Some(db) = &db {
+            write_chunk(&db.read(), &schema, layer, coords, &mut chunk);
+        }
+    }
+
+    let PendingFluidReads { tasks, reading } = &mut *pending;
+    let mut i = 0;
+    while i < tasks.len() {
+        let reads = if let Some(reads) = future::block_on(future::poll_once(&mut tasks[i])) {
+            tasks.swap_remove(i);
+            reads
+        } else {
+            i += 1;
+            continue;
+        };
+        for (coords, result) in reads.into_iter() {
+            reading.remove(&coords);
+            if !active.contains(&coords) {
+                continue;
+            }
+            match result {
+                Ok(Some(layers)) => sim.insert(coords, layer.read(&layers), Some(layers)),
+                Ok(None) => sim.insert(coords, Box::new([0; CHUNK_SIZE]), None),
+                Err(e) => {
+                    log::error!("Failed to read fluid for chunk {}: {:?}", coords, e);
+                    sim.insert_unreadable(coords);
+                }
+            }
+        }
+    }
+
+    let mut unread: Vec<_> = active
+        .iter()
+        .filter(|&coords| !sim.chunks.contains_key(coords) && !reading.contains(coords))
+        .copied()
+        .collect();
+    unread.sort_by_key(|c| c.to_array());
+    if let Some(db) = &db {
+        if !unread.is_empty() {
+            reading.extend(unread.iter().copied());
+            let db = Arc::clone(db);
+            tasks.push(MapTask::io(config.deterministic, async move {
+                let db = db.read();
+                unread
+                    .into_iter()
+                    .map(|coords| (coords, db.read_chunk_layers(NodeKey::new(0, coords).into())))
+                    .collect()
+            }));
+        }
+    } else {
+        for coords in unread.into_iter() {
+            sim.insert(coords, Box::new([0; CHUNK_SIZE]), None);
+        }
+    }
+
+    for region in regions.iter() {
+        // Fluid next to the region can flow into it.
+        let ChunkUnits(chunks) = region.padded(1).touched_chunks();
+        for coords in chunks.iter3() {
+            sim.wake(ChunkUnits(coords));
+        }
+    }
+
+    let asleep = sim.step(
+        &clipmap,
+        config.fluid.max_chunks_per_frame,
+        config.fluid.settle_steps,
+    );
+    if let Some(db) = &db {
+        let db = db.read();
+        for ChunkUnits(coords) in asleep.into_iter() {
+            let chunk = sim.chunks.get_mut(&coords).unwrap();
+            write_chunk(&db, &schema, layer, coords, chunk);
+        }
+    }
+}
+
+/// Writes the fluid that's still awake, or hasn't been written since it fell asleep, when the app exits.
+pub fn fluid_exit_system(
+    config: Res<MapConfig>,
+    schema: Res<VoxelLayerSchema>,
+    db: Option<Res<Arc<RwLock<MapDb>>>>,
+    mut sim: ResMut<FluidSim>,
+    mut exit_events: EventReader<AppExit>,
+) {
+    let (db, layer) = match (db, FluidLayer::find(&schema)) {
+        (Some(db), Some(layer)) if config.fluid.enabled => (db, layer),
+        _ => return,
+    };
+    if exit_events.iter().next().is_none() {
+        return;
+    }
+    let db = db.read();
+    for (&coords, chunk) in sim.chunks.iter_mut() {
+        write_chunk(&db, &schema, layer, coords, chunk);
+    }
+}
+
+/// Writes `chunk` to the layer tree if it changed since it was last written, unless its layers couldn't be read. Layers are
+/// small, so like metadata, they're written straight to sled's page cache.
+fn write_chunk(
+    db: &MapDb,
+    schema: &VoxelLayerSchema,
+    layer: FluidLayer,
+    coords: IVec3,
+    chunk: &mut FluidChunkState,
+) {
+    if !chunk.dirty || chunk.read_failed {
+        return;
+    }
+    let layers = chunk.layers.get_or_insert_with(|| ChunkLayers::new(schema));
+    layer.write(&chunk.levels, layers);
+    let key = NodeKey::new(0, coords).into();
+    match db.write_chunk_layers(key, &layers.compress(db.codec())) {
+        Ok(()) => chunk.dirty = false,
+        Err(e) => log::error!("Failed to write fluid for chunk {}: {:?}", coords, e),
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipmap::StreamingConfig;
+    use crate::sdf::Sd8;
+
+    fn floored_clipmap(chunks: &[IVec3]) -> ChunkClipMap {
+        let mut clipmap = ChunkClipMap::new(3, StreamingConfig::default());
+        for &coords in chunks {
+            clipmap.edit_chunk(ChunkUnits(coords), |chunk| {
+                for x in 0..16 {
+                    for z in 0..16 {
+                        chunk.set_voxel(IVec3::new(x, 0, z), 1, Sd8::MIN);
+                    }
+                }
+            });
+        }
+        clipmap
+    }
+
+    fn total_fluid(sim: &FluidSim) -> u32 {
+        sim.chunks
+            .values()
+            .flat_map(|c| c.levels.iter())
+            .map(|&l| u32::from(l))
+            .sum()
+    }
+
+    fn step_until_asleep(sim: &mut FluidSim, clipmap: &ChunkClipMap) -> Vec<ChunkUnits<IVec3>> {
+        let mut asleep = Vec::new();
+        for _ in 0..1000 {
+            asleep.extend(sim.step(clipmap, 8, 4));
+            if sim.num_awake() == 0 {
+                return asleep;
+            }
+        }
+        panic!("fluid never settled");
+    }
+
+    #[test]
+    fn fluid_falls_and_settles_on_the_floor() {
+        let coords = IVec3::ZERO;
+        let clipmap = floored_clipmap(&[coords]);
+        let mut sim = FluidSim::default();
+        sim.insert(coords, Box::new([0; CHUNK_SIZE]), None);
+        assert!(!sim.is_awake(ChunkUnits(coords)));
+        assert!(!sim.set(VoxelUnits(IVec3::new(-1, 5, 0)), 3));
+
+        assert!(sim.set(VoxelUnits(IVec3::new(8, 10, 8)), MAX_FLUID));
+        assert!(sim.is_awake(ChunkUnits(coords)));
+        let asleep = step_until_asleep(&mut sim, &clipmap);
+        assert_eq!(asleep, vec![ChunkUnits(coords)]);
+
+        assert_eq!(total_fluid(&sim), u32::from(MAX_FLUID));
+        // Nothing is left in the air or inside the floor, and the pool is level.
+        for i in 0..CHUNK_SIZE {
+            let p = IVec3::from(ChunkShape::delinearize(i as i32));
+            let level = sim.level(VoxelUnits(p));
+            if p.y != 1 {
+                assert_eq!(level, 0, "{}", p);
+            }
+            for offset in [IVec3::X, IVec3::Z] {
+                let q = p + offset;
+                if q.cmplt(IVec3::splat(16)).all() {
+                    assert!(level.abs_diff(sim.level(VoxelUnits(q))) <= 1);
+                }
+            }
+        }
+        assert!(sim.chunks[&coords].dirty);
+    }
+
+    #[test]
+    fn fluid_wakes_the_chunks_it_flows_into() {
+        let (left, right) = (IVec3::ZERO, IVec3::X);
+        let clipmap = floored_clipmap(&[left, right]);
+        let mut sim = FluidSim::default();
+        sim.insert(left, Box::new([0; CHUNK_SIZE]), None);
+        sim.insert(right, Box::new([0; CHUNK_SIZE]), None);
+
+        sim.set(VoxelUnits(IVec3::new(15, 1, 3)), 9);
+        // Touching the boundary also wakes the neighbor.
+        assert!(sim.is_awake(ChunkUnits(right)));
+        step_until_asleep(&mut sim, &clipmap);
+        assert_eq!(total_fluid(&sim), 9);
+        assert!(sim.level(VoxelUnits(IVec3::new(16, 1, 3))) > 0);
+
+        // Without the neighbor, the boundary is a wall.
+        let mut levels = Box::new([0; CHUNK_SIZE]);
+        levels[ChunkShape::linearize([15, 1, 3]) as usize] = 9;
+        sim.remove(right);
+        sim.insert(left, levels, None);
+        step_until_asleep(&mut sim, &clipmap);
+        assert_eq!(total_fluid(&sim), 9);
+        assert!(!sim.is_simulated(ChunkUnits(right)));
+    }
+}
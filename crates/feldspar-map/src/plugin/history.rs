@@ -0,0 +1,335 @@
+use super::config::MapConfig;
+use super::dirty_regions::DirtyRegions;
+use super::edits::{MapEdits, PendingFlushTask};
+use super::events::{ChunkEvent, MapError};
+use super::import::MapImports;
+use super::tasks::MapTask;
+use super::unsaved::UnsavedChunks;
+use crate::chunk::{Chunk, ChunkDelta};
+use crate::clipmap::ChunkClipMap;
+use crate::coordinates::chunk_extent_at_level_ivec3;
+use crate::core::glam::IVec3;
+use crate::database::{AbortReason, ChunkReadError, MapDb, Version};
+use crate::units::ChunkUnits;
+
+use bevy::prelude::*;
+use futures_lite::future;
+use grid_tree::NodeKey;
+use parking_lot::RwLock;
+use sled::transaction::TransactionError;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+
+#[derive(Clone, Debug)]
+enum HistoryRequest {
+    Commit,
+    Checkpoint(String),
+    Undo,
+    Redo,
+    RestoreCheckpoint(String),
+}
+
+/// What the history task does after committing the working version.
+enum HistoryMove {
+    Stay,
+    Undo,
+    Redo(Version),
+    Restore(Version),
+}
+
+struct HistoryTransition {
+    /// Whether the working version had changes that were committed before moving.
+    committed_changes: bool,
+    /// The parent version before moving.
+    from_version: Option<Version>,
+    /// The parent version after moving.
+    to_version: Option<Version>,
+    /// Chunks that changed in the working version, read back after moving. `None` means the chunk was removed.
+    changed_chunks: Vec<(NodeKey<IVec3>, Option<Box<Chunk>>)>,
+    /// Changed chunks that failed their checksums or couldn't be decompressed, so they were replaced as if they were removed.
+    errors: Vec<MapError>,
+}
+
+pub struct HistoryTaskOutput {
+    request: HistoryRequest,
+    result: Result<HistoryTransition, TransactionError<AbortReason>>,
+}
+
+/// Undo and redo for map edits, backed by the version log of the [`MapDb`].
+///
+/// Each committed version is one undo step. Undoing branches the working version from the grandparent version, so the
+/// newest committed changes are reverted in the database and the affected chunks are replaced in the [`ChunkClipMap`], which
+/// also schedules their meshes to be regenerated. Committing new changes after an undo discards the redo steps.
+///
+/// Requests are handled in order by the `history_system`, one at a time, after all pending [`MapEdits`] have been flushed.
+/// New edits are held until the request completes.
+///
+/// The first committed version has no parent to revert to, so it can't be undone. Versions older than
+/// [`CompactionConfig::max_versions`](crate::CompactionConfig::max_versions) can be merged by compaction, after which undoing
+/// or restoring a checkpoint past them fails.
+#[derive(Default)]
+pub struct MapHistory {
+    requests: VecDeque<HistoryRequest>,
+    /// Versions that were undone, most recent last.
+    redo_stack: Vec<Version>,
+    checkpoints: BTreeMap<String, Version>,
+    task: Option<MapTask<HistoryTaskOutput>>,
+}
+
+impl MapHistory {
+    /// Commits all flushed edits as a new undo step.
+    pub fn commit(&mut self) {
+        self.requests.push_back(HistoryRequest::Commit);
+    }
+
+    /// Commits all flushed edits and remembers the resulting version as `name`, replacing any older checkpoint with the same
+    /// name.
+    pub fn checkpoint(&mut self, name: impl Into<String>) {
+        self.requests
+            .push_back(HistoryRequest::Checkpoint(name.into()));
+    }
+
+    /// Commits all flushed edits, then reverts the most recent undo step.
+    pub fn undo(&mut self) {
+        self.requests.push_back(HistoryRequest::Undo);
+    }
+
+    /// Re-applies the most recently undone step, unless new edits were committed since then.
+    pub fn redo(&mut self) {
+        self.requests.push_back(HistoryRequest::Redo);
+    }
+
+    /// Commits all flushed edits, then moves to the version saved by [`MapHistory::checkpoint`]. This discards the redo steps,
+    /// but the abandoned versions can still be reached by their own checkpoints.
+    pub fn restore_checkpoint(&mut self, name: impl Into<String>) {
+        self.requests
+            .push_back(HistoryRequest::RestoreCheckpoint(name.into()));
+    }
+
+    pub fn checkpoint_names(&self) -> impl Iterator<Item = &str> {
+        self.checkpoints.keys().map(String::as_str)
+    }
+
+    pub fn num_redo_steps(&self) -> usize {
+        self.redo_stack.len()
+    }
+
+    /// True while a request is moving the database between versions.
+    pub(crate) fn is_busy(&self) -> bool {
+        self.task.is_some()
+    }
+}
+
+/// Handles [`MapHistory`] requests and replaces the chunks that changed in the [`ChunkClipMap`].
+pub fn history_system(
+    config: Res<MapConfig>,
+    db: Option<Res<Arc<RwLock<MapDb>>>>,
+    edits: Res<MapEdits>,
+    flush_task: Res<PendingFlushTask>,
+    unsaved: Res<UnsavedChunks>,
+    imports: Res<MapImports>,
+    mut clipmap: ResMut<ChunkClipMap>,
+    mut history: ResMut<MapHistory>,
+    mut dirty_regions: ResMut<DirtyRegions>,
+    mut chunk_events: EventWriter<ChunkEvent>,
+    mut db_errors: EventWriter<MapError>,
+) {
+    let MapHistory {
+        requests,
+        redo_stack,
+        checkpoints,
+        task,
+    } = &mut *history;
+
+    // Complete the pending request.
+    if let Some(t) = task {
+        if let Some(output) = future::block_on(future::poll_once(t)) {
+            *task = None;
+            match output.result {
+                Ok(transition) => {
+                    db_errors.send_batch(transition.errors.iter().cloned());
+                    apply_transition(
+                        output.request,
+                        transition,
+                        &mut clipmap,
+                        &mut dirty_regions,
+                        &mut chunk_events,
+                        redo_stack,
+                        checkpoints,
+                    );
+                }
+                Err(e) => {
+                    log::error!("Failed history request {:?}: {:?}", output.request, e);
+                }
+            }
+        } else {
+            return;
+        }
+    }
+
+    // The committed version must include every edit made before the request, and every save that started before it has to
+    // finish, or it could write an older chunk into the new working version.
+    if !edits.is_flushed() || !flush_task.is_idle() || !unsaved.is_idle() || imports.is_busy() {
+        return;
+    }
+
+    let db = if let Some(db) = db {
+        db
+    } else {
+        if !requests.is_empty() {
+            log::warn!(
+                "Dropped {} history requests without a MapDb",
+                requests.len()
+            );
+            requests.clear();
+        }
+        return;
+    };
+
+    let request = if let Some(request) = requests.pop_front() {
+        request
+    } else {
+        return;
+    };
+    let history_move = match &request {
+        HistoryRequest::Commit | HistoryRequest::Checkpoint(_) => HistoryMove::Stay,
+        HistoryRequest::Undo => HistoryMove::Undo,
+        HistoryRequest::Redo => {
+            if let Some(version) = redo_stack.pop() {
+                HistoryMove::Redo(version)
+            } else {
+                return;
+            }
+        }
+        HistoryRequest::RestoreCheckpoint(name) => {
+            if let Some(&version) = checkpoints.get(name) {
+                HistoryMove::Restore(version)
+            } else {
+                log::warn!("No checkpoint named {:?}", name);
+                return;
+            }
+        }
+    };
+
+    let db_clone = db.clone();
+    *task = Some(MapTask::io(config.deterministic, async move {
+        let result = move_working_version(&mut db_clone.write(), history_move);
+        HistoryTaskOutput { request, result }
+    }));
+}
+
+fn move_working_version(
+    db: &mut MapDb,
+    history_move: HistoryMove,
+) -> Result<HistoryTransition, TransactionError<AbortReason>> {
+    let old_parent = db.cached_meta().parent_version;
+    db.commit_working_version()?;
+    let from_version = db.cached_meta().parent_version;
+    let committed_changes = from_version != old_parent;
+
+    let target = match history_move {
+        HistoryMove::Stay => None,
+        HistoryMove::Undo => db.cached_meta().grandparent_version,
+        // Redoing on top of new changes would lose them.
+        HistoryMove::Redo(version) => {
+            if committed_changes {
+                None
+            } else {
+                Some(version)
+            }
+        }
+        HistoryMove::Restore(version) => Some(version),
+    };
+
+    let mut changed_chunks = Vec::new();
+    let mut errors = Vec::new();
+    if let Some(target) = target {
+        for key in db.branch_from_version(target)? {
+            let change = match db.read_working_version(key) {
+                Ok(change) => change,
+                Err(ChunkReadError::Database(e)) => return Err(e.into()),
+                Err(ChunkReadError::Corrupt(key)) => {
+                    log::error!("Replacing corrupt chunk {:?} with an empty chunk", key);
+                    errors.push(MapError::CorruptChunk(key));
+                    None
+                }
+            };
+            let chunk = change.and_then(|change| {
+                let archived = change.as_ref().get_insert_data()?;
+                let chunk = archived.try_decompress();
+                if chunk.is_none() {
+                    log::error!("Replacing undecodable chunk {:?} with an empty chunk", key);
+                    errors.push(MapError::UndecodableChunk(key));
+                }
+                chunk.map(Box::new)
+            });
+            changed_chunks.push((key.into(), chunk));
+        }
+    }
+
+    Ok(HistoryTransition {
+        committed_changes,
+        from_version,
+        to_version: db.cached_meta().parent_version,
+        changed_chunks,
+        errors,
+    })
+}
+
+fn apply_transition(
+    request: HistoryRequest,
+    transition: HistoryTransition,
+    clipmap: &mut ChunkClipMap,
+    dirty_regions: &mut DirtyRegions,
+    chunk_events: &mut EventWriter<ChunkEvent>,
+    redo_stack: &mut Vec<Version>,
+    checkpoints: &mut BTreeMap<String, Version>,
+) {
+    if transition.committed_changes {
+        redo_stack.clear();
+    }
+    let moved = transition.from_version != transition.to_version;
+    match request {
+        HistoryRequest::Commit | HistoryRequest::Redo => {}
+        HistoryRequest::Checkpoint(name) => {
+            if let Some(version) = transition.to_version {
+                checkpoints.insert(name, version);
+            }
+        }
+        HistoryRequest::Undo => {
+            if moved {
+                redo_stack.extend(transition.from_version);
+            }
+        }
+        HistoryRequest::RestoreCheckpoint(_) => {
+            if moved {
+                redo_stack.clear();
+            }
+        }
+    }
+
+    for (key, chunk) in transition.changed_chunks.into_iter() {
+        // When the old version of a resident LOD0 chunk is known, only the voxels that actually changed are dirty.
+        let old_chunk = if key.level == 0 {
+            clipmap.copy_chunk(key)
+        } else {
+            None
+        };
+        let extent = if let Some(old_chunk) = old_chunk {
+            let coords = ChunkUnits(key.coordinates);
+            let new_chunk = chunk.as_deref().copied().unwrap_or_default();
+            let delta = ChunkDelta::between(&old_chunk, &new_chunk);
+            let extent = delta.extent(coords);
+            dirty_regions.insert_delta(coords, extent, delta);
+            extent
+        } else {
+            let extent = chunk_extent_at_level_ivec3(key.level, ChunkUnits(key.coordinates));
+            dirty_regions.insert(extent);
+            extent
+        };
+        clipmap.replace_chunk(key, chunk);
+        if !extent.into_inner().is_empty() {
+            chunk_events.send(ChunkEvent::Edited(extent));
+        }
+    }
+}
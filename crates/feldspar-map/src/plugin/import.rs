@@ -0,0 +1,242 @@
+use super::config::MapConfig;
+use super::dirty_regions::DirtyRegions;
+use super::edits::{MapEdits, PendingFlushTask};
+use super::events::ChunkEvent;
+use super::history::MapHistory;
+use super::tasks::MapTask;
+use crate::chunk::Chunk;
+use crate::clipmap::ChunkClipMap;
+use crate::coordinates::chunk_extent_ivec3;
+use crate::core::glam::IVec3;
+use crate::core::SmallKeyHashSet;
+use crate::database::MapDb;
+use crate::heightmap::{write_heightmap, Heightmap, HeightmapConfig};
+use crate::units::{ChunkUnits, VoxelUnits};
+use crate::vox::VoxPalette;
+
+use bevy::prelude::*;
+use futures_lite::future;
+use grid_tree::NodeKey;
+use parking_lot::RwLock;
+use sled::transaction::TransactionError;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+enum MapImport {
+    Vox {
+        path: PathBuf,
+        offset: VoxelUnits<IVec3>,
+        palette: VoxPalette,
+    },
+    Heightmap {
+        path: PathBuf,
+        config: HeightmapConfig,
+    },
+}
+
+impl MapImport {
+    fn path(&self) -> &PathBuf {
+        match self {
+            Self::Vox { path, .. } | Self::Heightmap { path, .. } => path,
+        }
+    }
+
+    /// Writes the imported chunks to the working version. Returns copies of the chunks in the `loaded_roots`.
+    fn write(
+        &self,
+        db: &RwLock<MapDb>,
+        loaded_roots: &LoadedRoots,
+    ) -> Result<Vec<(ChunkUnits<IVec3>, Chunk)>, ImportError> {
+        match self {
+            Self::Vox {
+                path,
+                offset,
+                palette,
+            } => {
+                let vox_data = vox_format::from_file(path).map_err(ImportError::Vox)?;
+                let mut db = db.write();
+                let mut loaded = Vec::new();
+                for model in vox_data.models.iter() {
+                    let written = db
+                        .import_vox_model(model, *offset, palette)
+                        .map_err(ImportError::Database)?;
+                    loaded.extend(
+                        written
+                            .into_iter()
+                            .filter(|(coords, _)| loaded_roots.contains(*coords)),
+                    );
+                }
+                Ok(loaded)
+            }
+            Self::Heightmap { path, config } => {
+                let heightmap = Heightmap::open(path).map_err(ImportError::Image)?;
+                let mut loaded = Vec::new();
+                write_heightmap(&mut db.write(), &heightmap, config, |coords, chunk| {
+                    if loaded_roots.contains(coords) {
+                        loaded.push((coords, *chunk));
+                    }
+                })
+                .map_err(|e| ImportError::Database(e.into()))?;
+                Ok(loaded)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum ImportError {
+    Vox(vox_format::reader::Error),
+    Image(image::ImageError),
+    Database(TransactionError),
+}
+
+/// The roots of the clipmap when an import started, so we only keep copies of the imported chunks that might be loaded.
+///
+/// NOTE: Trees that are inserted while the import is in flight could load chunks from before the import.
+struct LoadedRoots {
+    root_level: u8,
+    roots: SmallKeyHashSet<IVec3>,
+}
+
+impl LoadedRoots {
+    fn new(clipmap: &ChunkClipMap) -> Self {
+        Self {
+            root_level: clipmap.octree.root_level(),
+            roots: clipmap
+                .octree
+                .iter_roots()
+                .map(|(root_key, _)| root_key.coordinates)
+                .collect(),
+        }
+    }
+
+    fn contains(&self, ChunkUnits(coords): ChunkUnits<IVec3>) -> bool {
+        self.roots.contains(&(coords >> self.root_level as i32))
+    }
+}
+
+pub struct ImportedChunks {
+    path: PathBuf,
+    result: Result<Vec<(ChunkUnits<IVec3>, Chunk)>, ImportError>,
+}
+
+/// Imports voxel assets and terrain from files into the [`MapDb`].
+///
+/// Imported chunks are written to LOD0 of the working version. Any copies already loaded in the [`ChunkClipMap`] are replaced,
+/// which also schedules their meshes and downsampled ancestors to be regenerated.
+///
+/// Imports are handled in order by the `import_system`, one at a time, after all pending [`MapEdits`] have been flushed. New
+/// edits are held until the import completes.
+#[derive(Default)]
+pub struct MapImports {
+    queued: VecDeque<MapImport>,
+    task: Option<MapTask<ImportedChunks>>,
+}
+
+impl MapImports {
+    /// Imports all models in the MagicaVoxel file at `path`, translated by `offset`, with colors mapped through `palette`.
+    ///
+    /// Every voxel in the models becomes solid. The voxels around the models are kept. The import can be undone with the
+    /// [`MapHistory`].
+    pub fn import_vox_file(
+        &mut self,
+        path: impl Into<PathBuf>,
+        offset: VoxelUnits<IVec3>,
+        palette: VoxPalette,
+    ) {
+        self.queued.push_back(MapImport::Vox {
+            path: path.into(),
+            offset,
+            palette,
+        });
+    }
+
+    /// Generates terrain from the heightmap image at `path`, as described by [`write_heightmap`].
+    ///
+    /// Heightmaps are meant for bootstrapping large terrains, so they bypass the change log and can't be undone.
+    pub fn import_heightmap_file(&mut self, path: impl Into<PathBuf>, config: HeightmapConfig) {
+        self.queued.push_back(MapImport::Heightmap {
+            path: path.into(),
+            config,
+        });
+    }
+
+    /// The number of imports that haven't completed.
+    pub fn num_pending(&self) -> usize {
+        self.queued.len() + self.task.is_some() as usize
+    }
+
+    /// True while an import is writing to the database.
+    pub(crate) fn is_busy(&self) -> bool {
+        self.task.is_some()
+    }
+}
+
+/// Handles [`MapImports`] and replaces the imported chunks in the [`ChunkClipMap`].
+pub fn import_system(
+    config: Res<MapConfig>,
+    db: Option<Res<Arc<RwLock<MapDb>>>>,
+    edits: Res<MapEdits>,
+    flush_task: Res<PendingFlushTask>,
+    history: Res<MapHistory>,
+    mut clipmap: ResMut<ChunkClipMap>,
+    mut imports: ResMut<MapImports>,
+    mut dirty_regions: ResMut<DirtyRegions>,
+    mut chunk_events: EventWriter<ChunkEvent>,
+) {
+    let MapImports { queued, task } = &mut *imports;
+
+    // Complete the pending import.
+    if let Some(t) = task {
+        if let Some(imported) = future::block_on(future::poll_once(t)) {
+            *task = None;
+            match imported.result {
+                Ok(chunks) => {
+                    log::info!("Imported {} chunks from {:?}", chunks.len(), imported.path);
+                    for (coords, chunk) in chunks.into_iter() {
+                        clipmap.replace_chunk(NodeKey::new(0, coords.0), Some(Box::new(chunk)));
+                        let extent = chunk_extent_ivec3(coords);
+                        dirty_regions.insert(extent);
+                        chunk_events.send(ChunkEvent::Edited(extent));
+                    }
+                }
+                Err(e) => {
+                    log::error!("Failed to import {:?}: {:?}", imported.path, e);
+                }
+            }
+        } else {
+            return;
+        }
+    }
+
+    // Imports merge with the voxels in the database, so they must see every edit made before them.
+    if !edits.is_flushed() || !flush_task.is_idle() || history.is_busy() {
+        return;
+    }
+
+    let db = if let Some(db) = db {
+        db
+    } else {
+        if !queued.is_empty() {
+            log::warn!("Dropped {} imports without a MapDb", queued.len());
+            queued.clear();
+        }
+        return;
+    };
+
+    let import = if let Some(import) = queued.pop_front() {
+        import
+    } else {
+        return;
+    };
+
+    let loaded_roots = LoadedRoots::new(&clipmap);
+    let db_clone = db.clone();
+    *task = Some(MapTask::io(config.deterministic, async move {
+        ImportedChunks {
+            path: import.path().clone(),
+            result: import.write(&db_clone, &loaded_roots),
+        }
+    }));
+}
@@ -0,0 +1,88 @@
+use super::config::MapConfig;
+use super::dirty_regions::DirtyRegions;
+use super::events::ChunkEvent;
+use crate::clipmap::ChunkClipMap;
+use crate::coordinates::in_chunk_extent;
+use crate::core::glam::IVec3;
+use crate::units::ChunkUnits;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub struct LightConfig {
+    /// Floods sunlight through the LOD0 chunks as they load, and updates it when they're edited. The renderer can then shade
+    /// each vertex with the light around it, like in blocky games.
+    pub enabled: bool,
+    /// The maximum number of chunks that are lit for the first time on each frame. Edits are always relit on the frame they're
+    /// applied.
+    pub max_chunks_per_frame: usize,
+}
+
+impl Default for LightConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_chunks_per_frame: 16,
+        }
+    }
+}
+
+/// The LOD0 chunks waiting to be lit for the first time.
+#[derive(Default)]
+pub struct PendingLightChunks {
+    queue: VecDeque<IVec3>,
+}
+
+/// Lights LOD0 chunks in the [`ChunkClipMap`] as they load, and relights the [`DirtyRegions`] of each frame.
+///
+/// Loaded chunks are lit from the top down, since sunlight enters through the chunk above. Chunks that are evicted lose their
+/// light with the rest of their data.
+pub fn light_system(
+    config: Res<MapConfig>,
+    regions: Res<DirtyRegions>,
+    mut clipmap: ResMut<ChunkClipMap>,
+    mut pending: ResMut<PendingLightChunks>,
+    mut chunk_events: EventReader<ChunkEvent>,
+) {
+    if !config.light.enabled {
+        return;
+    }
+
+    for event in chunk_events.iter() {
+        if let ChunkEvent::Loaded(key) = event {
+            if key.level == 0 {
+                pending.queue.push_back(key.coordinates);
+            }
+        }
+    }
+
+    for region in regions.iter() {
+        clipmap.relight(region);
+        // Editing empty space creates chunks that were never lit.
+        let ChunkUnits(chunks) = in_chunk_extent(region);
+        for coords in chunks.iter3() {
+            if clipmap.light(ChunkUnits(coords)).is_none() {
+                pending.queue.push_back(coords);
+            }
+        }
+    }
+
+    let PendingLightChunks { queue } = &mut *pending;
+    queue
+        .make_contiguous()
+        .sort_by_key(|coords| std::cmp::Reverse(coords.y));
+    let mut num_lit = 0;
+    while num_lit < config.light.max_chunks_per_frame {
+        let coords = if let Some(coords) = queue.pop_front() {
+            ChunkUnits(coords)
+        } else {
+            break;
+        };
+        // Chunks that were already lit or evicted since they were queued are skipped.
+        if clipmap.light(coords).is_none() && clipmap.light_chunk(coords) {
+            num_lit += 1;
+        }
+    }
+}
@@ -1,112 +1,1049 @@
 use super::config::MapConfig;
-use super::Witness;
-use crate::clipmap::{ChunkClipMap, PendingLoad};
-use crate::database::MapDb;
+use super::events::{ChunkEvent, MapError, TeleportEvent};
+use super::maps::ActiveMap;
+use super::tasks::MapTask;
+use super::unsaved::UnsavedChunks;
+use super::warm_start::WarmStart;
+use super::witness::{Witness, WitnessObservers};
+use crate::chunk::{Chunk, CompressedChunk, MappedChunk, UniformChunk};
+use crate::clipmap::{ChunkClipMap, ClipRegion, LoadPriority, PendingLoad};
+use crate::database::{BackendError, Change, ChunkDbKey, MapBackend};
+use crate::generator::ChunkGenerator;
 use crate::units::VoxelUnits;
+use crate::world_transform::VoxelWorldTransform;
 
-use feldspar_core::glam::Vec3A;
+use feldspar_core::glam::IVec3;
 
 use bevy::prelude::*;
-use bevy::tasks::{IoTaskPool, Task};
+use bevy::tasks::AsyncComputeTaskPool;
+use bevy::utils::Instant;
+use crossbeam_channel::{Receiver, Sender};
+use either::Either;
 use futures_lite::future;
+use grid_tree::NodeKey;
 use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
 
 #[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(default)]
 pub struct LoaderConfig {
     /// The number of chunks to start loading in a single frame (batch).
     pub load_batch_size: usize,
+    /// The smallest batch that the loader shrinks to when loaded chunks can't be inserted as fast as they're read.
+    pub min_load_batch_size: usize,
+    /// When more than this many loaded chunks are waiting to be inserted into the clipmap, because inserting them goes over
+    /// `frame_time_budget_us`, no new batches are started and the batch size is halved. It grows back to `load_batch_size`
+    /// whenever the backlog drains.
+    pub max_insert_backlog: usize,
     /// The maximum number of pending load tasks.
     pub max_pending_load_tasks: usize,
+    /// The order in which nodes are loaded, relative to the nearest witness.
+    pub priority: LoadPriority,
+    /// How far (in voxels) past the clip shape to prefetch chunks in the direction that a witness is moving.
+    ///
+    /// Set to zero to disable prefetching.
+    pub prefetch_distance: VoxelUnits<f32>,
+    /// The half-angle (in radians) of the cone along a witness's direction of motion in which chunks are prefetched. Must be
+    /// less than `PI / 2`.
+    pub prefetch_cone_angle: f32,
+    /// A witness that moves farther than this (in voxels) in one frame is treated as teleported. Rather than streaming from
+    /// its old position, everything outside of the new clip shapes is dropped at once and the new clip shape is loaded from
+    /// scratch, with a [`TeleportEvent`] sent when it starts and finishes.
+    ///
+    /// Set to zero to disable teleport detection.
+    pub teleport_distance: VoxelUnits<f32>,
+    /// The maximum time (in microseconds) spent inserting loaded chunks into the clipmap each frame. Any remaining loads are
+    /// inserted on the next frame.
+    pub frame_time_budget_us: u32,
+    /// The number of groups of root subtrees that the near phase load search splits into and searches in parallel. Large clip
+    /// spheres have many roots, and the search can take a significant part of the frame.
+    ///
+    /// Set to one to search serially on the loader's thread.
+    pub search_partitions: usize,
+    /// What to do when a chunk can't be loaded.
+    pub error_policy: ErrorPolicy,
+    /// Keep chunks in the buffers they were read into, instead of decompressing them on the load task. A chunk is copied out
+    /// of its buffer the first time it's written, and decompressed the first time it's read.
+    ///
+    /// This saves memory when most loaded chunks are never read, e.g. far from any mesh. It doesn't save any work, since
+    /// each chunk is still decompressed once on the load task, so one that can't be decompressed is caught by the
+    /// [`ErrorPolicy`].
+    pub zero_copy_reads: bool,
+    /// Load every batch on one long-lived IO thread, in the order the batches are started, instead of spawning a task for
+    /// each batch on the [`IoTaskPool`](bevy::tasks::IoTaskPool). Ignored by deterministic runs, which load every batch
+    /// inline, and in the browser, which has no threads.
+    pub io_worker: bool,
 }
 
 impl Default for LoaderConfig {
     fn default() -> Self {
         Self {
             load_batch_size: 256,
+            min_load_batch_size: 16,
+            max_insert_backlog: 1024,
             max_pending_load_tasks: 16,
+            priority: LoadPriority::default(),
+            prefetch_distance: VoxelUnits(250.0),
+            prefetch_cone_angle: 0.5,
+            teleport_distance: VoxelUnits(500.0),
+            frame_time_budget_us: 2000,
+            search_partitions: 1,
+            error_policy: ErrorPolicy::default(),
+            zero_copy_reads: false,
+            io_worker: false,
         }
     }
 }
 
+/// How the loader recovers from a [`MapError`]. Every error is also sent as an event, unless the policy panics.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum ErrorPolicy {
+    /// Reads that fail or return bad chunks are repeated, up to `max_attempts` reads in total. After that, failed reads are
+    /// canceled so the nodes get searched again on a later frame, and bad chunks are loaded as if they were missing.
+    Retry { max_attempts: u32 },
+    /// Every chunk that can't be loaded is loaded as if it was missing, so it's empty or generated, and it's only replaced in
+    /// the backend if it's edited.
+    Skip,
+    /// Panics on the first error, e.g. to catch bad data in tests.
+    Panic,
+}
+
+impl Default for ErrorPolicy {
+    fn default() -> Self {
+        Self::Retry { max_attempts: 3 }
+    }
+}
+
+impl ErrorPolicy {
+    fn max_attempts(&self) -> u32 {
+        match *self {
+            Self::Retry { max_attempts } => max_attempts.max(1),
+            Self::Skip | Self::Panic => 1,
+        }
+    }
+}
+
+/// A loaded chunk, as it's stored in [`PendingLoad::chunk`].
+pub(crate) type ChunkSlot = Option<Either<Box<Chunk>, CompressedChunk>>;
+
 pub struct LoadedBatch {
     reads: Vec<PendingLoad>,
+    /// Loads that were skipped because the batch was canceled before they were read, or because their reads failed.
+    canceled: Vec<PendingLoad>,
+    errors: Vec<MapError>,
+    /// The duration of each call to the backend, including any retries.
+    read_latencies: Vec<Duration>,
+}
+
+/// A shared flag that tells a load task to skip any reads it hasn't started yet.
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    canceled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.canceled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_canceled(&self) -> bool {
+        self.canceled.load(Ordering::Relaxed)
+    }
+}
+
+/// A batch that's being loaded, either by its own task or by the [`IoWorker`].
+pub struct LoadTask {
+    /// Sent back with the [`LoadedBatch`] when the batch is finished.
+    id: u64,
+    /// The keys of all nodes being loaded by the task.
+    keys: Vec<NodeKey<IVec3>>,
+    cancel_token: CancelToken,
+}
+
+/// Every load task sends its batch over a channel when it's finished, so the loader only has to drain the channel instead of
+/// polling each task.
+type FinishedBatch = (u64, LoadedBatch);
+
+/// A long-lived thread that loads the batches it's sent one at a time, when [`LoaderConfig::io_worker`] is set.
+///
+/// Dropping the worker waits for the thread to finish the batches it was already sent, so none of them still read the
+/// backend after the map is closed.
+struct IoWorker {
+    /// Only `None` while the worker is dropped.
+    jobs: Option<Sender<(u64, LoadJob)>>,
+    thread: Option<JoinHandle<()>>,
 }
 
+impl IoWorker {
+    fn spawn(finished: Sender<FinishedBatch>) -> Self {
+        let (jobs, job_receiver) = crossbeam_channel::unbounded::<(u64, LoadJob)>();
+        let thread = std::thread::Builder::new()
+            .name("feldspar-loader".into())
+            .spawn(move || {
+                // Exits when the worker is dropped along with the `PendingLoadTasks`, after the jobs already sent.
+                for (id, job) in job_receiver {
+                    if finished
+                        .send((id, future::block_on(load_batch(job))))
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            })
+            .expect("Failed to spawn the loader's IO worker");
+        Self {
+            jobs: Some(jobs),
+            thread: Some(thread),
+        }
+    }
+
+    fn send(&self, id: u64, job: LoadJob) {
+        self.jobs
+            .as_ref()
+            .expect("The loader's IO worker was dropped")
+            .send((id, job))
+            .expect("The loader's IO worker exited");
+    }
+}
+
+impl Drop for IoWorker {
+    fn drop(&mut self) {
+        // Disconnect the job channel so the thread exits once it's empty.
+        self.jobs = None;
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                log::error!("The loader's IO worker panicked");
+            }
+        }
+    }
+}
+
+/// A load whose task has finished, but it's still waiting to be inserted into the clipmap.
+enum CompletedLoad {
+    Read(PendingLoad),
+    Canceled(PendingLoad),
+}
+
+/// The number of backend reads whose latencies are kept for diagnostics.
+const READ_LATENCY_WINDOW: usize = 256;
+
 pub struct PendingLoadTasks {
-    tasks: VecDeque<Task<LoadedBatch>>,
+    tasks: VecDeque<LoadTask>,
+    /// Loads that could not be inserted within the frame time budget are carried over to the next frame.
+    completed: VecDeque<CompletedLoad>,
+    /// The latencies of the most recent backend reads, oldest first.
+    read_latencies: VecDeque<Duration>,
+    batch_size: BatchSizeController,
+    /// Spawned by the first batch that's loaded with [`LoaderConfig::io_worker`].
+    io_worker: Option<IoWorker>,
+    next_task_id: u64,
+    finished_sender: Sender<FinishedBatch>,
+    finished: Receiver<FinishedBatch>,
+    /// Witnesses that teleported and haven't been sent [`TeleportEvent::Finished`] yet.
+    teleporting: Vec<Entity>,
 }
 
 impl PendingLoadTasks {
-    pub fn new() -> Self {
+    pub fn new(config: &LoaderConfig) -> Self {
+        let (finished_sender, finished) = crossbeam_channel::unbounded();
         PendingLoadTasks {
-            tasks: VecDeque::new()
+            tasks: VecDeque::new(),
+            completed: VecDeque::new(),
+            read_latencies: VecDeque::with_capacity(READ_LATENCY_WINDOW),
+            batch_size: BatchSizeController::new(config),
+            io_worker: None,
+            next_task_id: 0,
+            finished_sender,
+            finished,
+            teleporting: Vec::new(),
+        }
+    }
+
+    pub(crate) fn num_tasks(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// The number of loaded chunks that are waiting to be inserted into the clipmap.
+    pub(crate) fn insert_backlog(&self) -> usize {
+        self.completed.len()
+    }
+
+    /// The size of the next load batch, as adapted to the insert backlog.
+    pub(crate) fn load_batch_size(&self) -> usize {
+        self.batch_size.batch_size
+    }
+
+    pub(crate) fn read_latencies(&self) -> impl Iterator<Item = Duration> + '_ {
+        self.read_latencies.iter().copied()
+    }
+}
+
+impl Drop for PendingLoadTasks {
+    fn drop(&mut self) {
+        // Nothing is going to insert the batches in flight, so skip their remaining reads. The IO worker is joined after
+        // this, when it's dropped.
+        for load_task in self.tasks.iter() {
+            load_task.cancel_token.cancel();
         }
     }
 }
 
 pub fn loader_system(
     config: Res<MapConfig>,
-    witness_transforms: Query<(&Witness, &Transform)>,
+    world_transform: Res<VoxelWorldTransform>,
+    witness_transforms: Query<(Entity, &Witness, &Transform)>,
+    active_map: Res<ActiveMap>,
     // io_pool: Res<IoTaskPool>,
-    db: Res<Arc<MapDb>>, // PERF: better option than Arc?
+    backend: Res<Arc<dyn MapBackend>>,
+    generator: Option<Res<Arc<dyn ChunkGenerator>>>,
     mut clipmap: ResMut<ChunkClipMap>,
     mut load_tasks: ResMut<PendingLoadTasks>,
+    warm_start: Res<WarmStart>,
+    unsaved: Res<UnsavedChunks>,
+    mut chunk_events: EventWriter<ChunkEvent>,
+    mut map_errors: EventWriter<MapError>,
+    mut teleport_events: EventWriter<TeleportEvent>,
 ) {
-    let PendingLoadTasks { tasks } = &mut *load_tasks;
-
-    // Complete pending load tasks in queue order.
-    // PERF: is this the best way to poll a sequence of futures?
-    while let Some(mut task) = tasks.pop_front() {
-        if let Some(loaded_batch) = future::block_on(future::poll_once(&mut task)) {
-            // Insert the chunks into the clipmap and mark the nodes as loaded.
-            for pending_load in loaded_batch.reads.into_iter() {
-                clipmap.complete_pending_load(pending_load)
+    let PendingLoadTasks {
+        tasks,
+        completed,
+        read_latencies,
+        batch_size: batch_size_controller,
+        io_worker,
+        next_task_id,
+        finished_sender,
+        finished,
+        teleporting,
+    } = &mut *load_tasks;
+
+    let frame_start = Instant::now();
+    let frame_budget = Duration::from_micros(config.loader.frame_time_budget_us.into());
+
+    // Merge all witness clip shapes so that overlapping regions don't get searched redundantly.
+    //
+    // PERF: the old region doesn't include last frame's prefetch cones, so roots inside of the cones get revisited every frame
+    // while a witness is moving.
+    let witnesses = WitnessObservers::new(
+        witness_transforms
+            .iter()
+            .map(|(_, witness, tfm)| (witness, tfm)),
+        active_map.0,
+        &world_transform,
+        &config.loader,
+        clipmap.stream_config.clip_sphere_radius,
+    );
+    let old_region = ClipRegion::from_shapes(
+        witness_transforms
+            .iter()
+            .filter_map(|(_, witness, _)| witness.previous_placed_clip_shape(&world_transform)),
+    );
+    let new_region = witnesses.clip_region();
+
+    // Teleported witnesses skip the incremental streaming from their old positions. The saver evicts everything outside of
+    // the new region, and the near phase search below starts over around the new positions.
+    if witnesses.teleported {
+        for (entity, witness, tfm) in witness_transforms.iter() {
+            if !witness.is_in(&active_map) {
+                continue;
             }
-        } else {
-            tasks.push_front(task);
+            if let Some((from, to)) = witness.teleport(tfm, &world_transform, &config.loader) {
+                log::info!(
+                    "Witness {:?} teleported from {:?} to {:?}",
+                    entity,
+                    from,
+                    to
+                );
+                teleport_events.send(TeleportEvent::Started {
+                    witness: entity,
+                    from,
+                    to,
+                });
+                if !teleporting.contains(&entity) {
+                    teleporting.push(entity);
+                }
+            }
+        }
+
+        // Loads that completed around the old positions would only be evicted again, so release them instead of spending
+        // the next frames inserting them.
+        for completed_load in std::mem::take(completed).into_iter() {
+            match completed_load {
+                CompletedLoad::Read(pending_load)
+                    if !new_region.intersects_node(pending_load.loaded_key) =>
+                {
+                    clipmap.cancel_pending_load(pending_load)
+                }
+                completed_load => completed.push_back(completed_load),
+            }
+        }
+    }
+
+    // Complete pending load tasks in the order they finished, until we run out of time.
+    'insert: loop {
+        while let Some(completed_load) = completed.pop_front() {
+            // Deterministic runs insert everything, so the results don't depend on how fast the frame is.
+            if !config.deterministic && frame_start.elapsed() >= frame_budget {
+                completed.push_front(completed_load);
+                break 'insert;
+            }
+            match completed_load {
+                // Insert the chunk into the clipmap and mark the node as loaded.
+                CompletedLoad::Read(pending_load) => {
+                    let key = pending_load.loaded_key;
+                    if clipmap.complete_pending_load(pending_load) {
+                        chunk_events.send(ChunkEvent::Loaded(key));
+                    }
+                }
+                // Canceled loads must release their claim on the tree so they can be retried.
+                CompletedLoad::Canceled(pending_load) => clipmap.cancel_pending_load(pending_load),
+            }
+        }
+
+        if !collect_finished_batch(
+            &config.loader,
+            tasks,
+            finished,
+            completed,
+            read_latencies,
+            &mut map_errors,
+        ) {
             break;
         }
     }
+    // Batches that finished while we were out of time still count towards the backlog.
+    while collect_finished_batch(
+        &config.loader,
+        tasks,
+        finished,
+        completed,
+        read_latencies,
+        &mut map_errors,
+    ) {}
 
-    // PERF: this does a bunch of redundant work when the clip spheres of multiple witnesses overlap
-    for (witness, tfm) in witness_transforms.iter() {
-        if let Some(prev_tfm) = witness.previous_transform.as_ref() {
-            // TODO: use .as_vec3a()
-            let old_witness_pos = VoxelUnits(Vec3A::from(prev_tfm.translation.to_array()));
-            let new_witness_pos = VoxelUnits(Vec3A::from(tfm.translation.to_array()));
+    // Insert new root nodes that intersect the clip region, including any that will soon be reached by moving witnesses.
+    clipmap.broad_phase_load_search(&old_region, &new_region);
 
-            // Insert new root nodes that intersect the clip sphere.
-            clipmap.broad_phase_load_search(old_witness_pos, new_witness_pos);
+    if witnesses.observers.is_empty() || tasks.len() >= config.loader.max_pending_load_tasks {
+        return;
+    }
+    let batch_size = if let Some(size) =
+        batch_size_controller.next_batch_size(&config.loader, completed.len())
+    {
+        size
+    } else {
+        return;
+    };
 
-            if tasks.len() >= config.loader.max_pending_load_tasks {
-                continue;
+    // Find a batch of nodes to load, prioritized by distance to the nearest witness. Predicted witness positions are also
+    // searched so that chunks are resident before a fast-moving witness arrives.
+    let search_observers = witnesses.search_observers();
+    let pending_loads: Vec<_> = if config.loader.search_partitions > 1 {
+        clipmap.par_near_phase_load_search(
+            &search_observers,
+            config.loader.priority,
+            batch_size,
+            config.loader.search_partitions,
+        )
+    } else {
+        let search = clipmap.near_phase_load_search(&search_observers, config.loader.priority);
+        search.take(batch_size).collect()
+    };
+    if pending_loads.is_empty() {
+        // Everything around the witnesses is loaded, so any chunks left over from the warm start aren't needed.
+        warm_start.close();
+        if tasks.is_empty() && completed.is_empty() {
+            teleport_events.send_batch(
+                teleporting
+                    .drain(..)
+                    .map(|witness| TeleportEvent::Finished { witness }),
+            );
+        }
+        return;
+    }
+
+    // Start loading those nodes, on a new task or on the IO worker.
+    let keys = pending_loads.iter().map(|l| l.loaded_key).collect();
+    let cancel_token = CancelToken::default();
+    let job = LoadJob {
+        pending_loads,
+        cancel_token: cancel_token.clone(),
+        backend: Arc::clone(&backend),
+        generator: generator.map(|g| Arc::clone(&g)),
+        warm_start: WarmStart::clone(&warm_start),
+        unsaved: UnsavedChunks::clone(&unsaved),
+        error_policy: config.loader.error_policy,
+        zero_copy_reads: config.loader.zero_copy_reads,
+        deterministic: config.deterministic,
+    };
+    let id = *next_task_id;
+    *next_task_id += 1;
+    if config.loader.io_worker && !config.deterministic && cfg!(not(target_arch = "wasm32")) {
+        io_worker
+            .get_or_insert_with(|| IoWorker::spawn(finished_sender.clone()))
+            .send(id, job);
+    } else {
+        let finished_sender = finished_sender.clone();
+        MapTask::io(config.deterministic, async move {
+            // The receiver is only dropped along with the `PendingLoadTasks`, and then nothing needs the batch.
+            let _ = finished_sender.send((id, load_batch(job).await));
+        })
+        .detach();
+    }
+    tasks.push_back(LoadTask {
+        id,
+        keys,
+        cancel_token,
+    });
+}
+
+/// Everything needed to load a batch of nodes, off of the loader's thread.
+struct LoadJob {
+    pending_loads: Vec<PendingLoad>,
+    cancel_token: CancelToken,
+    backend: Arc<dyn MapBackend>,
+    generator: Option<Arc<dyn ChunkGenerator>>,
+    warm_start: WarmStart,
+    unsaved: UnsavedChunks,
+    error_policy: ErrorPolicy,
+    zero_copy_reads: bool,
+    deterministic: bool,
+}
+
+/// Reads, decompresses, and generates the chunks of `job`.
+async fn load_batch(job: LoadJob) -> LoadedBatch {
+    let LoadJob {
+        pending_loads,
+        cancel_token,
+        backend,
+        generator,
+        warm_start,
+        unsaved,
+        error_policy,
+        zero_copy_reads,
+        deterministic,
+    } = job;
+    let mut batch = LoadedBatch {
+        reads: Vec::with_capacity(pending_loads.len()),
+        canceled: Vec::new(),
+        errors: Vec::new(),
+        read_latencies: Vec::new(),
+    };
+    let mut unread = Vec::with_capacity(pending_loads.len());
+    for mut pending_load in pending_loads.into_iter() {
+        if cancel_token.is_canceled() {
+            batch.canceled.push(pending_load);
+        } else if let Some(slot) = unsaved.get(pending_load.loaded_key) {
+            // Newer than anything in the backend or the warm start.
+            pending_load.chunk = slot;
+            pending_load.measure_occupancy();
+            batch.reads.push(pending_load);
+        } else if let Some(slot) = warm_start.take(pending_load.loaded_key) {
+            pending_load.chunk = slot;
+            pending_load.measure_occupancy();
+            batch.reads.push(pending_load);
+        } else {
+            unread.push(pending_load);
+        }
+    }
+
+    // The rest are read in one batch, so backends with high latency only pay it once.
+    let mut missing = Vec::new();
+    let mut place = |mut pending_load: PendingLoad, maybe_slot: Option<ChunkSlot>| {
+        if let Some(slot) = maybe_slot {
+            pending_load.chunk = slot;
+            batch.reads.push(pending_load);
+        } else if generator.is_some() {
+            missing.push(pending_load);
+        } else {
+            batch.reads.push(pending_load);
+        }
+    };
+    let attempts = error_policy.max_attempts();
+    if cancel_token.is_canceled() {
+        batch.canceled.extend(unread);
+    } else if !unread.is_empty() {
+        let keys: Vec<_> = unread.iter().map(|l| l.loaded_key).collect();
+        let db_keys: Vec<_> = keys.iter().map(|&key| ChunkDbKey::from(key)).collect();
+        let read_start = Instant::now();
+        let changes = with_retries(attempts, || backend.read_chunks_mapped(&db_keys));
+        batch.read_latencies.push(read_start.elapsed());
+        match changes {
+            Ok(changes) => {
+                let reads = unread.into_iter().zip(changes).collect();
+                for (mut pending_load, found) in
+                    decompress_in_parallel(reads, deterministic, zero_copy_reads).await
+                {
+                    match found {
+                        Ok(true) => {
+                            let slot = pending_load.chunk.take();
+                            place(pending_load, Some(slot));
+                        }
+                        Ok(false) => place(pending_load, None),
+                        Err(error) => {
+                            log::error!("Loading chunk as missing: {:?}", error);
+                            batch.errors.push(error);
+                            place(pending_load, None);
+                        }
+                    }
+                }
             }
+            Err(BackendError::Corrupt(_)) => {
+                // Read the chunks one at a time to find the corrupt ones, so the rest of the batch still loads.
+                for pending_load in unread.into_iter() {
+                    let key = pending_load.loaded_key;
+                    let read_start = Instant::now();
+                    let slot = with_retries(attempts, || read_chunk_slot(&*backend, key));
+                    batch.read_latencies.push(read_start.elapsed());
+                    match slot {
+                        Ok(maybe_slot) => place(pending_load, maybe_slot),
+                        Err(error @ MapError::ReadFailed { .. })
+                            if error_policy != ErrorPolicy::Skip =>
+                        {
+                            log::error!("Failed to read chunk {:?}: {:?}", key, error);
+                            batch.errors.push(error);
+                            batch.canceled.push(pending_load);
+                        }
+                        Err(error) => {
+                            log::error!("Loading chunk as missing: {:?}", error);
+                            batch.errors.push(error);
+                            place(pending_load, None);
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                log::error!("Failed to read a batch of {} chunks: {:?}", keys.len(), e);
+                batch.errors.push(MapError::ReadFailed {
+                    keys: keys.iter().map(|&key| key.into()).collect(),
+                    error: format!("{:?}", e),
+                });
+                if error_policy == ErrorPolicy::Skip {
+                    for pending_load in unread.into_iter() {
+                        place(pending_load, None);
+                    }
+                } else {
+                    // The next search will try again.
+                    batch.canceled.extend(unread);
+                }
+            }
+        }
+    }
 
-            // Find a batch of nodes to load.
-            let search = clipmap.near_phase_load_search(new_witness_pos);
-            let pending_loads: Vec<_> = search.take(config.loader.load_batch_size).collect();
-
-            // Spawn a new task to load those nodes.
-            let db_clone = db.clone();
-            let io_pool = IoTaskPool::get();
-            let load_task = io_pool.spawn(async move {
-                // PERF: Should this batch be a single task?
-                LoadedBatch {
-                    reads: pending_loads
-                        .into_iter()
-                        .map(move |mut pending_load| {
-                            pending_load.chunk = db_clone
-                                .read_working_version(pending_load.loaded_key.into())
-                                .unwrap()
-                                .map(|c| c.deserialize().unwrap_insert());
-                            pending_load
-                        })
-                        .collect(),
+    // Generate any chunks that aren't in the database. This is CPU-bound, so it doesn't belong on the IO pool. Chunks that
+    // were edited to be empty are saved as air, so they aren't generated again.
+    if let Some(generator) = generator {
+        if !missing.is_empty() {
+            let generated = MapTask::compute(deterministic, async move {
+                for pending_load in missing.iter_mut() {
+                    pending_load.chunk = generator
+                        .generate_chunk(pending_load.loaded_key)
+                        .and_then(|chunk| match chunk.uniform() {
+                            Some(uniform) => uniform_slot(uniform),
+                            None => Some(Either::Left(Box::new(chunk))),
+                        });
+                    pending_load.measure_occupancy();
                 }
-            });
-            tasks.push_back(load_task);
+                missing
+            })
+            .await;
+            batch.reads.extend(generated);
+        }
+    }
+    batch
+}
+
+/// Moves the loads of the next finished task into `completed`. Returns `false` if no task has finished.
+fn collect_finished_batch(
+    config: &LoaderConfig,
+    tasks: &mut VecDeque<LoadTask>,
+    finished: &Receiver<FinishedBatch>,
+    completed: &mut VecDeque<CompletedLoad>,
+    read_latencies: &mut VecDeque<Duration>,
+    map_errors: &mut EventWriter<MapError>,
+) -> bool {
+    let (id, loaded_batch) = if let Ok(finished) = finished.try_recv() {
+        finished
+    } else {
+        return false;
+    };
+    // Tasks on the pool can finish in any order.
+    if let Some(i) = tasks.iter().position(|load_task| load_task.id == id) {
+        tasks.remove(i);
+    } else {
+        // A batch that isn't pending anymore isn't inserted, but its loads still have to release their claims.
+        log::warn!("Discarding finished load batch {} that isn't pending", id);
+        completed.extend(
+            loaded_batch
+                .reads
+                .into_iter()
+                .chain(loaded_batch.canceled)
+                .map(CompletedLoad::Canceled),
+        );
+        return true;
+    }
+    completed.extend(loaded_batch.reads.into_iter().map(CompletedLoad::Read));
+    completed.extend(
+        loaded_batch
+            .canceled
+            .into_iter()
+            .map(CompletedLoad::Canceled),
+    );
+    if config.error_policy == ErrorPolicy::Panic {
+        if let Some(error) = loaded_batch.errors.first() {
+            panic!("Failed to load the map: {:?}", error);
+        }
+    }
+    map_errors.send_batch(loaded_batch.errors.into_iter());
+    for latency in loaded_batch.read_latencies {
+        if read_latencies.len() == READ_LATENCY_WINDOW {
+            read_latencies.pop_front();
+        }
+        read_latencies.push_back(latency);
+    }
+    true
+}
+
+/// Adapts the load batch size to how fast loaded chunks are inserted into the clipmap, so finished batches don't pile up.
+struct BatchSizeController {
+    batch_size: usize,
+}
+
+impl BatchSizeController {
+    fn new(config: &LoaderConfig) -> Self {
+        Self {
+            batch_size: config.load_batch_size,
+        }
+    }
+
+    /// Returns the size of the batch to start this frame, or `None` if no batch should be started while `backlog` loads are
+    /// waiting to be inserted.
+    fn next_batch_size(&mut self, config: &LoaderConfig, backlog: usize) -> Option<usize> {
+        let max_size = config.load_batch_size.max(1);
+        let min_size = config.min_load_batch_size.clamp(1, max_size);
+        if backlog > config.max_insert_backlog {
+            self.batch_size = (self.batch_size / 2).max(min_size);
+            return None;
+        }
+        if backlog == 0 {
+            self.batch_size = self.batch_size.saturating_mul(2);
+        }
+        // The config can change at runtime.
+        self.batch_size = self.batch_size.clamp(min_size, max_size);
+        Some(self.batch_size)
+    }
+}
+
+/// Reads the chunk at `key` from `backend`, in the representation that it should be stored in the clipmap.
+///
+/// Returns `None` if the backend has no entry for `key`.
+pub(crate) fn read_chunk_slot(
+    backend: &dyn MapBackend,
+    key: NodeKey<IVec3>,
+) -> Result<Option<ChunkSlot>, MapError> {
+    let db_key = ChunkDbKey::from(key);
+    match backend.read_chunk_mapped(db_key) {
+        Ok(change) => change_slot(db_key, change),
+        Err(BackendError::Corrupt(key)) => Err(MapError::CorruptChunk(key)),
+        Err(e) => Err(MapError::ReadFailed {
+            keys: vec![db_key],
+            error: format!("{:?}", e),
+        }),
+    }
+}
+
+type ReadChunk = (PendingLoad, Option<Change<MappedChunk>>);
+/// The load, with its chunk and occupancy filled in if the backend had an entry for it.
+type DecompressedChunk = (PendingLoad, Result<bool, MapError>);
+
+/// Decompresses the chunks read for a batch and measures their occupancy on the [`AsyncComputeTaskPool`], split into one
+/// group per thread, so the loader only has to link them into the clipmap. Only the chunks that can't be decompressed fail
+/// on their own. Deterministic runs decompress everything in one group on the current thread.
+///
+/// With `zero_copy`, chunks that aren't uniform are left mapped. They're still decompressed once here, and dropped, since
+/// the clipmap decompresses them when they're first read, where a corrupt chunk couldn't fail its load anymore.
+async fn decompress_in_parallel(
+    reads: Vec<ReadChunk>,
+    deterministic: bool,
+    zero_copy: bool,
+) -> Vec<DecompressedChunk> {
+    let num_reads = reads.len();
+    let num_groups = if deterministic {
+        1
+    } else {
+        AsyncComputeTaskPool::get().thread_num().max(1)
+    };
+    let group_size = num_reads.div_ceil(num_groups).max(1);
+
+    let mut reads = reads.into_iter();
+    let mut tasks = Vec::with_capacity(num_groups);
+    loop {
+        let group: Vec<_> = reads.by_ref().take(group_size).collect();
+        if group.is_empty() {
+            break;
+        }
+        tasks.push(MapTask::compute(deterministic, async move {
+            group
+                .into_iter()
+                .map(|(mut pending_load, change)| {
+                    let key = ChunkDbKey::from(pending_load.loaded_key);
+                    let found = match change {
+                        Some(Change::Insert(mapped)) if zero_copy && mapped.uniform().is_none() => {
+                            if mapped.try_decompress().is_some() {
+                                pending_load.mapped = Some(mapped);
+                                Ok(true)
+                            } else {
+                                Err(MapError::UndecodableChunk(key))
+                            }
+                        }
+                        change => change_slot(key, change).map(|maybe_slot| {
+                            maybe_slot.map(|slot| pending_load.chunk = slot).is_some()
+                        }),
+                    };
+                    if let Ok(true) = found {
+                        pending_load.measure_occupancy();
+                    }
+                    (pending_load, found)
+                })
+                .collect::<Vec<_>>()
+        }));
+    }
+
+    let mut decompressed = Vec::with_capacity(num_reads);
+    for task in tasks {
+        decompressed.extend(task.await);
+    }
+    decompressed
+}
+
+/// Calls `read` until it succeeds, at most `attempts` times.
+fn with_retries<T, E>(attempts: u32, mut read: impl FnMut() -> Result<T, E>) -> Result<T, E> {
+    let mut result = read();
+    for _ in 1..attempts {
+        if result.is_ok() {
+            break;
+        }
+        result = read();
+    }
+    result
+}
+
+fn change_slot(
+    key: ChunkDbKey,
+    change: Option<Change<MappedChunk>>,
+) -> Result<Option<ChunkSlot>, MapError> {
+    // Uniform chunks are never decompressed.
+    match change {
+        Some(Change::Insert(compressed)) => match compressed.uniform() {
+            Some(uniform) => Ok(Some(uniform_slot(uniform))),
+            None => match compressed.try_decompress() {
+                Some(chunk) => Ok(Some(Some(Either::Left(Box::new(chunk))))),
+                None => Err(MapError::UndecodableChunk(key)),
+            },
+        },
+        Some(Change::Remove) => Ok(Some(None)),
+        None => Ok(None),
+    }
+}
+
+/// Air chunks are left empty, and solid chunks stay compressed as sentinels, so neither allocates a full chunk buffer.
+fn uniform_slot(uniform: UniformChunk) -> ChunkSlot {
+    match uniform {
+        UniformChunk::Air => None,
+        UniformChunk::Solid { .. } => Some(Either::Right(uniform.compress())),
+    }
+}
+
+/// Cancels any pending load tasks whose nodes are all outside of every witness's clip shape and prefetch cone.
+pub fn load_cancellation_system(
+    config: Res<MapConfig>,
+    world_transform: Res<VoxelWorldTransform>,
+    witness_transforms: Query<(&Witness, &Transform)>,
+    active_map: Res<ActiveMap>,
+    clipmap: Res<ChunkClipMap>,
+    load_tasks: Res<PendingLoadTasks>,
+) {
+    let witnesses = WitnessObservers::new(
+        witness_transforms.iter(),
+        active_map.0,
+        &world_transform,
+        &config.loader,
+        clipmap.stream_config.clip_sphere_radius,
+    );
+    let clip_region = witnesses.clip_region();
+
+    for load_task in load_tasks.tasks.iter() {
+        if load_task.cancel_token.is_canceled() {
+            continue;
+        }
+        let still_needed = load_task
+            .keys
+            .iter()
+            .any(|&key| clip_region.intersects_node(key));
+        if !still_needed {
+            load_task.cancel_token.cancel();
+        }
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::ChunkEncoding;
+    use crate::clipmap::{LinkPointer, NodePtr, EMPTY_ALLOC_PTR};
+    use crate::database::MemoryBackend;
+    use crate::generator::FlatWorldGenerator;
+    use crate::sdf::Sd8;
+
+    fn pending_load(key: NodeKey<IVec3>) -> PendingLoad {
+        PendingLoad {
+            loaded_key: key,
+            link_ptr: LinkPointer::LinkToNearestAncestor(NodePtr::new(0, EMPTY_ALLOC_PTR)),
+            chunk: None,
+            mapped: None,
+            occupancy: None,
+        }
+    }
+
+    fn load_job(
+        key: NodeKey<IVec3>,
+        backend: Arc<dyn MapBackend>,
+        generator: Option<Arc<dyn ChunkGenerator>>,
+        unsaved: UnsavedChunks,
+    ) -> LoadJob {
+        LoadJob {
+            pending_loads: vec![pending_load(key)],
+            cancel_token: CancelToken::default(),
+            backend,
+            generator,
+            warm_start: WarmStart::disabled(),
+            unsaved,
+            error_policy: ErrorPolicy::Panic,
+            zero_copy_reads: false,
+            deterministic: true,
+        }
+    }
+
+    fn load_one(job: LoadJob) -> ChunkSlot {
+        let mut batch = future::block_on(load_batch(job));
+        assert!(batch.errors.is_empty());
+        batch.reads.pop().unwrap().chunk
+    }
+
+    #[test]
+    fn evicted_chunks_are_reloaded_before_they_are_saved() {
+        let key = NodeKey::new(0, IVec3::ZERO);
+        let mut chunk = Chunk::default();
+        chunk.set_voxel(IVec3::new(1, 2, 3), 7, Sd8::MIN);
+        let backend = Arc::new(MemoryBackend::new(Default::default()));
+        let unsaved = UnsavedChunks::default();
+        let batch = unsaved.insert([(key, Some(Either::Left(Box::new(chunk))))]);
+
+        let job = || load_job(key, backend.clone(), None, unsaved.clone());
+
+        // The witness comes back while the batch is still being saved.
+        let loaded = load_one(job()).unwrap().left().unwrap();
+        assert_eq!(*loaded, chunk);
+        assert_eq!(backend.num_chunks(), 0);
+
+        unsaved
+            .write(batch, ChunkEncoding::default(), &*backend)
+            .unwrap();
+        assert!(unsaved.is_empty());
+        let loaded = load_one(job()).unwrap().left().unwrap();
+        assert_eq!(*loaded, chunk);
+    }
+
+    #[test]
+    fn chunks_edited_to_be_empty_are_not_generated_again() {
+        let key = NodeKey::new(0, IVec3::ZERO);
+        let backend = Arc::new(MemoryBackend::new(Default::default()));
+        let generator: Option<Arc<dyn ChunkGenerator>> = Some(Arc::new(FlatWorldGenerator {
+            surface_height: 8.0,
+            palette_id: 1,
+        }));
+        let unsaved = UnsavedChunks::default();
+        let job = || load_job(key, backend.clone(), generator.clone(), unsaved.clone());
+        assert!(load_one(job()).is_some());
+
+        // Everything in the generated chunk is dug out.
+        let batch = unsaved.insert_changes([(key, Change::Remove)]);
+        unsaved
+            .write(batch, ChunkEncoding::default(), &*backend)
+            .unwrap();
+        assert_eq!(backend.num_chunks(), 1);
+        assert!(load_one(job()).is_none());
+    }
+
+    #[test]
+    fn corrupt_mapped_chunks_fail_their_load() {
+        let key = NodeKey::new(0, IVec3::ZERO);
+        let garbage = CompressedChunk {
+            bytes: vec![0xAB; 64].into(),
+        };
+        let reads = vec![(
+            pending_load(key),
+            Some(Change::Insert(MappedChunk::new(garbage))),
+        )];
+
+        let mut decompressed = future::block_on(decompress_in_parallel(reads, true, true));
+        let (pending_load, found) = decompressed.pop().unwrap();
+        assert!(matches!(found, Err(MapError::UndecodableChunk(k)) if k == ChunkDbKey::from(key)));
+        assert!(pending_load.mapped.is_none());
+    }
+
+    #[test]
+    fn batch_size_shrinks_under_backlog_and_grows_when_drained() {
+        let config = LoaderConfig {
+            load_batch_size: 256,
+            min_load_batch_size: 16,
+            max_insert_backlog: 1000,
+            ..Default::default()
+        };
+        let mut controller = BatchSizeController::new(&config);
+        assert_eq!(controller.next_batch_size(&config, 0), Some(256));
+        assert_eq!(controller.next_batch_size(&config, 500), Some(256));
+
+        // Spawning pauses while the backlog is over the limit.
+        for _ in 0..10 {
+            assert_eq!(controller.next_batch_size(&config, 1001), None);
         }
+        assert_eq!(controller.batch_size, 16);
+
+        // A backlog under the limit holds the size until it drains.
+        assert_eq!(controller.next_batch_size(&config, 10), Some(16));
+        assert_eq!(controller.next_batch_size(&config, 0), Some(32));
+        assert_eq!(controller.next_batch_size(&config, 0), Some(64));
+        for _ in 0..10 {
+            controller.next_batch_size(&config, 0);
+        }
+        assert_eq!(controller.next_batch_size(&config, 0), Some(256));
+    }
+
+    #[test]
+    fn dropped_io_worker_finishes_the_jobs_it_was_sent() {
+        let (finished_sender, finished) = crossbeam_channel::unbounded();
+        let worker = IoWorker::spawn(finished_sender);
+        let backend: Arc<dyn MapBackend> = Arc::new(MemoryBackend::new(Default::default()));
+        for id in 0..4 {
+            let key = NodeKey::new(0, IVec3::splat(id as i32));
+            let job = load_job(key, backend.clone(), None, UnsavedChunks::default());
+            worker.send(id, job);
+        }
+
+        // Joins the thread, so every batch was sent back by now.
+        drop(worker);
+        let ids: Vec<_> = finished.try_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![0, 1, 2, 3]);
     }
 }
@@ -0,0 +1,500 @@
+use super::autosave::{save_chunks, AutosaveTrigger};
+use super::cache::CacheState;
+use super::chunk_entities::ChunkEntities;
+use super::commands::MapCommands;
+use super::compaction::{CompactionProgress, CompactionState};
+use super::config::MapConfig;
+use super::dirty_regions::DirtyRegions;
+use super::downsampler::PendingDownsampleTasks;
+use super::edits::{MapEdits, PendingFlushTask};
+use super::events::{ChunkEvent, MapError, TeleportEvent};
+use super::fluid::{FluidSim, PendingFluidReads};
+use super::history::MapHistory;
+use super::import::MapImports;
+use super::light::PendingLightChunks;
+use super::loader::PendingLoadTasks;
+use super::navmesh::{NavTileEvent, NavTiles};
+#[cfg(feature = "physics")]
+use super::physics::ChunkColliders;
+use super::saver::PendingSaveTasks;
+use super::unsaved::UnsavedChunks;
+use super::validation::{EditApplied, EditRejected, EditValidator};
+use super::warm_start::WarmStart;
+use super::{map_systems, plugin_startup, MapStage};
+use crate::clipmap::ChunkClipMap;
+use crate::database::{MapBackend, MapDb};
+use crate::generator::ChunkGenerator;
+use crate::world_transform::VoxelWorldTransform;
+
+use bevy::ecs::event::Events;
+use bevy::ecs::schedule::Stage;
+use bevy::ecs::system::System;
+use bevy::prelude::*;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Identifies one of the maps streamed by the [`MapPlugin`](crate::MapPlugin). The map configured by the plugin is
+/// [`MapId::PRIMARY`], and the others are opened with [`Maps::open`].
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize,
+)]
+pub struct MapId(pub u32);
+
+impl MapId {
+    pub const PRIMARY: Self = Self(0);
+}
+
+/// The map whose resources are in the `World` while its systems run. Each [`Witness`](crate::Witness) only streams the map
+/// that it belongs to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ActiveMap(pub MapId);
+
+/// How to open a map with [`Maps::open`].
+pub struct MapDescriptor {
+    /// Needs its own [`MapConfig::dimension`] or [`MapConfig::db_path`].
+    pub config: MapConfig,
+    pub world_transform: VoxelWorldTransform,
+    /// Like the `Arc<dyn MapBackend>` resource of the primary map. `None` chooses the backend from the config.
+    pub backend: Option<Arc<dyn MapBackend>>,
+    pub generator: Option<Arc<dyn ChunkGenerator>>,
+    pub validator: Option<Arc<dyn EditValidator>>,
+}
+
+impl MapDescriptor {
+    pub fn new(config: MapConfig) -> Self {
+        Self {
+            config,
+            world_transform: VoxelWorldTransform::default(),
+            backend: None,
+            generator: None,
+            validator: None,
+        }
+    }
+
+    /// Another dimension of the database that `config` streams from, with the rest of the same config.
+    pub fn dimension(config: &MapConfig, dimension: impl Into<String>) -> Self {
+        Self::new(MapConfig {
+            dimension: dimension.into(),
+            ..config.clone()
+        })
+    }
+
+    pub fn with_world_transform(mut self, world_transform: VoxelWorldTransform) -> Self {
+        self.world_transform = world_transform;
+        self
+    }
+
+    pub fn with_backend(mut self, backend: Arc<dyn MapBackend>) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    pub fn with_generator(mut self, generator: Arc<dyn ChunkGenerator>) -> Self {
+        self.generator = Some(generator);
+        self
+    }
+
+    pub fn with_validator(mut self, validator: Arc<dyn EditValidator>) -> Self {
+        self.validator = Some(validator);
+        self
+    }
+}
+
+/// The maps that are streamed next to the primary map, like a dungeon under the overworld or a preview map in an editor.
+///
+/// The primary map keeps its resources in the `World` (the [`ChunkClipMap`], [`MapEdits`], [`ChunkEvent`]s, and the rest),
+/// so existing systems only see the primary map. Every other map keeps its own copy of those resources here, and on every
+/// frame, each map's resources are swapped into the `World` while the map's systems run for it, right after they run for the
+/// primary map. Use [`Maps::with_map`] from an exclusive system to read or edit another map the same way.
+///
+/// Witnesses only stream the map in their [`Witness::map`](crate::Witness::map). The [`VoxelLayerSchema`] and the
+/// [`ChunkEntitySpawners`](crate::ChunkEntitySpawners) are shared by all maps. The other plugins, like the renderer, only
+/// see the primary map, except that the [`AutosavePlugin`](crate::AutosavePlugin) saves every open map when the app exits.
+///
+/// [`VoxelLayerSchema`]: crate::chunk::VoxelLayerSchema
+#[derive(Default)]
+pub struct Maps {
+    maps: BTreeMap<MapId, OpenMap>,
+    to_open: Vec<(MapId, MapDescriptor)>,
+    to_close: Vec<MapId>,
+}
+
+impl Maps {
+    /// Opens the map on the next frame. Nothing happens if a map with this `id` is already open or `id` is
+    /// [`MapId::PRIMARY`].
+    pub fn open(&mut self, id: MapId, descriptor: MapDescriptor) {
+        self.to_open.push((id, descriptor));
+    }
+
+    /// Closes the map on the next frame, blocking until its dirty chunks are written to its backend. The state of its
+    /// [`FluidSim`] isn't saved.
+    pub fn close(&mut self, id: MapId) {
+        self.to_close.push(id);
+    }
+
+    pub fn is_open(&self, id: MapId) -> bool {
+        self.maps.contains_key(&id)
+    }
+
+    /// The IDs of the open maps, other than [`MapId::PRIMARY`], in order.
+    pub fn ids(&self) -> impl Iterator<Item = MapId> + '_ {
+        self.maps.keys().copied()
+    }
+
+    /// The open map, other than [`MapId::PRIMARY`], that streams the [`MapConfig::dimension`] named `dimension` from a
+    /// database, e.g. to find the map that a [`Witness`](crate::Witness) should stream when it walks through a portal.
+    pub fn find_dimension(&self, dimension: &str) -> Option<MapId> {
+        self.maps
+            .iter()
+            .find(|(_, map)| map.dimension == dimension)
+            .map(|(&id, _)| id)
+    }
+
+    /// Calls `f` while the resources of map `id` are in the `World`. Returns `None` if the map isn't open.
+    ///
+    /// [`MapId::PRIMARY`] is always open, since its resources are already in the `World`.
+    pub fn with_map<R>(world: &mut World, id: MapId, f: impl FnOnce(&mut World) -> R) -> Option<R> {
+        if id == MapId::PRIMARY {
+            return Some(f(world));
+        }
+        world.resource_scope(|world, mut maps: Mut<Maps>| {
+            let map = maps.maps.get_mut(&id)?;
+            map.resources.swap(world);
+            let result = f(world);
+            map.resources.swap(world);
+            Some(result)
+        })
+    }
+
+    fn open_pending(&mut self, world: &mut World) {
+        for (id, descriptor) in std::mem::take(&mut self.to_open) {
+            if id == MapId::PRIMARY || self.maps.contains_key(&id) {
+                log::warn!("Map {:?} is already open", id);
+                continue;
+            }
+            let (update, last) = map_stages(descriptor.config.deterministic);
+            let dimension = descriptor.config.dimension.clone();
+            // Takes the primary map's resources out of the way while the new map's are created.
+            let mut resources = MapResources::default();
+            resources.swap(world);
+            init_map_resources(world, id, descriptor);
+            let mut startup = IntoSystem::into_system(plugin_startup);
+            startup.initialize(world);
+            startup.run((), world);
+            startup.apply_buffers(world);
+            resources.swap(world);
+            self.maps.insert(
+                id,
+                OpenMap {
+                    dimension,
+                    resources,
+                    update,
+                    last,
+                },
+            );
+        }
+    }
+
+    /// Writes everything that every open map, other than [`MapId::PRIMARY`], still has in memory.
+    pub(crate) fn save_all(world: &mut World) {
+        world.resource_scope(|world, mut maps: Mut<Maps>| {
+            for (&id, map) in maps.maps.iter_mut() {
+                map.resources.swap(world);
+                save_map(world, id);
+                map.resources.swap(world);
+            }
+        });
+    }
+
+    fn close_pending(&mut self, world: &mut World) {
+        for id in std::mem::take(&mut self.to_close) {
+            let mut map = if let Some(map) = self.maps.remove(&id) {
+                map
+            } else {
+                continue;
+            };
+            map.resources.swap(world);
+            save_map(world, id);
+            map.resources.swap(world);
+        }
+    }
+}
+
+/// Adds the resources of one map to `world`, replacing any that are already there.
+pub(crate) fn init_map_resources(world: &mut World, id: MapId, descriptor: MapDescriptor) {
+    let MapDescriptor {
+        config,
+        world_transform,
+        backend,
+        generator,
+        validator,
+    } = descriptor;
+    world.insert_resource(config);
+    world.insert_resource(ActiveMap(id));
+    world.insert_resource(world_transform);
+    world.insert_resource(MapCommands::default());
+    world.insert_resource(MapEdits::default());
+    world.insert_resource(MapHistory::default());
+    world.insert_resource(MapImports::default());
+    world.insert_resource(DirtyRegions::default());
+    world.insert_resource(CacheState::default());
+    world.insert_resource(PendingLightChunks::default());
+    world.insert_resource(FluidSim::default());
+    world.insert_resource(PendingFluidReads::default());
+    world.insert_resource(NavTiles::default());
+    world.insert_resource(ChunkEntities::default());
+    #[cfg(feature = "physics")]
+    world.insert_resource(ChunkColliders::default());
+    if let Some(backend) = backend {
+        world.insert_resource(backend);
+    }
+    if let Some(generator) = generator {
+        world.insert_resource(generator);
+    }
+    if let Some(validator) = validator {
+        world.insert_resource(validator);
+    }
+    MapResources::insert_events(world);
+}
+
+/// Lists the resources that each map has its own copy of.
+macro_rules! map_resources {
+    (
+        resources: [$($(#[$attr:meta])* $field:ident: $t:ty),* $(,)?],
+        events: [$($event_field:ident: $event:ty),* $(,)?] $(,)?
+    ) => {
+        /// The resources of a map that isn't in the `World` right now.
+        #[derive(Default)]
+        struct MapResources {
+            $($(#[$attr])* $field: Option<$t>,)*
+            $($event_field: Option<Events<$event>>,)*
+        }
+
+        impl MapResources {
+            /// Exchanges these resources with the ones in `world`.
+            fn swap(&mut self, world: &mut World) {
+                $(
+                    $(#[$attr])*
+                    {
+                        let taken = world.remove_resource::<$t>();
+                        if let Some(resource) = self.$field.take() {
+                            world.insert_resource(resource);
+                        }
+                        self.$field = taken;
+                    }
+                )*
+                $(
+                    let taken = world.remove_resource::<Events<$event>>();
+                    if let Some(events) = self.$event_field.take() {
+                        world.insert_resource(events);
+                    }
+                    self.$event_field = taken;
+                )*
+            }
+
+            fn insert_events(world: &mut World) {
+                $(world.insert_resource(Events::<$event>::default());)*
+            }
+
+            /// Does what the `App`'s event update systems do for the primary map.
+            fn update_events(world: &mut World) {
+                $(world.resource_mut::<Events<$event>>().update();)*
+            }
+        }
+    };
+}
+
+map_resources!(
+    resources: [
+        config: MapConfig,
+        active: ActiveMap,
+        world_transform: VoxelWorldTransform,
+        commands: MapCommands,
+        edits: MapEdits,
+        history: MapHistory,
+        imports: MapImports,
+        dirty_regions: DirtyRegions,
+        cache: CacheState,
+        light: PendingLightChunks,
+        fluid: FluidSim,
+        fluid_reads: PendingFluidReads,
+        nav_tiles: NavTiles,
+        chunk_entities: ChunkEntities,
+        #[cfg(feature = "physics")]
+        colliders: ChunkColliders,
+        backend: Arc<dyn MapBackend>,
+        db: Arc<RwLock<MapDb>>,
+        generator: Arc<dyn ChunkGenerator>,
+        validator: Arc<dyn EditValidator>,
+        warm_start: WarmStart,
+        clipmap: ChunkClipMap,
+        load_tasks: PendingLoadTasks,
+        save_tasks: PendingSaveTasks,
+        unsaved: UnsavedChunks,
+        downsample_tasks: PendingDownsampleTasks,
+        flush_task: PendingFlushTask,
+        compaction: CompactionState,
+    ],
+    events: [
+        chunk_events: ChunkEvent,
+        compaction_progress: CompactionProgress,
+        edits_applied: EditApplied,
+        edits_rejected: EditRejected,
+        map_errors: MapError,
+        nav_tile_events: NavTileEvent,
+        teleport_events: TeleportEvent,
+    ],
+);
+
+/// A map in [`Maps`]. Each map has its own copy of the map systems, so their local state, like the position of each
+/// `EventReader`, stays with the map's resources.
+struct OpenMap {
+    dimension: String,
+    resources: MapResources,
+    update: SystemStage,
+    last: SystemStage,
+}
+
+fn map_stages(deterministic: bool) -> (SystemStage, SystemStage) {
+    let new_stage = || {
+        if deterministic {
+            SystemStage::single_threaded()
+        } else {
+            SystemStage::parallel()
+        }
+    };
+    let (mut update, mut last) = (new_stage(), new_stage());
+    let (update_systems, last_systems) = map_systems();
+    for system in update_systems {
+        update.add_system(system);
+    }
+    for system in last_systems {
+        last.add_system(system);
+    }
+    (update, last)
+}
+
+/// Opens and closes [`Maps`], and runs the map systems of the `stage` for each of them.
+fn run_maps(world: &mut World, stage: MapStage) {
+    world.resource_scope(|world, mut maps: Mut<Maps>| {
+        if stage == MapStage::Update {
+            maps.close_pending(world);
+            maps.open_pending(world);
+        }
+        for map in maps.maps.values_mut() {
+            map.resources.swap(world);
+            match stage {
+                MapStage::Update => {
+                    MapResources::update_events(world);
+                    map.update.run(world);
+                }
+                MapStage::Last => map.last.run(world),
+            }
+            map.resources.swap(world);
+        }
+    });
+}
+
+/// Runs the map systems of the `Update` stage for every map in [`Maps`], after they ran for the primary map.
+pub fn maps_update_system(world: &mut World) {
+    run_maps(world, MapStage::Update);
+}
+
+/// Same as [`maps_update_system`], for the `Last` stage.
+pub fn maps_last_system(world: &mut World) {
+    run_maps(world, MapStage::Last);
+}
+
+/// Writes everything that the map still has in memory, like the [`AutosavePlugin`](crate::AutosavePlugin) does on exit.
+fn save_map(world: &mut World, id: MapId) {
+    world.resource_mut::<PendingFlushTask>().wait();
+    world.resource_mut::<PendingSaveTasks>().wait();
+    let dirty = world.resource::<ChunkClipMap>().take_all_dirty_chunks();
+    let unsaved = world.resource::<UnsavedChunks>().clone();
+    let batch = unsaved.insert_changes(dirty);
+    let encoding = world.resource::<MapConfig>().encoding;
+    let backend = Arc::clone(world.resource::<Arc<dyn MapBackend>>());
+    let db = world.get_resource::<Arc<RwLock<MapDb>>>().map(Arc::clone);
+    let report = save_chunks(
+        AutosaveTrigger::Exit,
+        &unsaved,
+        batch,
+        encoding,
+        backend,
+        db,
+    );
+    match report.error {
+        Some(error) => log::error!("Failed to save map {:?}: {}", id, error),
+        None => log::info!("Saved {} chunks of map {:?}", report.num_chunks, id),
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chunk::VoxelLayerSchema;
+    use crate::core::glam::Vec3A;
+    use crate::plugin::{MapStorage, SledDbs};
+    use crate::units::WorldUnits;
+
+    #[test]
+    fn maps_keep_their_own_resources() {
+        let config = MapConfig {
+            storage: MapStorage::Memory,
+            ..Default::default()
+        };
+        let mut world = World::new();
+        world.insert_resource(VoxelLayerSchema::default());
+        world.insert_resource(SledDbs::default());
+        init_map_resources(
+            &mut world,
+            MapId::PRIMARY,
+            MapDescriptor::new(config.clone()),
+        );
+
+        let dungeon = MapId(1);
+        let transform = VoxelWorldTransform::new(WorldUnits(Vec3A::new(0.0, -100.0, 0.0)), 2.0);
+        let mut maps = Maps::default();
+        maps.open(
+            dungeon,
+            MapDescriptor::dimension(&config, "dungeon").with_world_transform(transform),
+        );
+        maps.open_pending(&mut world);
+        assert!(maps.is_open(dungeon));
+        assert_eq!(maps.find_dimension("dungeon"), Some(dungeon));
+        assert_eq!(maps.find_dimension("main"), None);
+        world.insert_resource(maps);
+
+        // Only the dungeon ran the startup system, so only it has a clipmap.
+        assert_eq!(world.resource::<ActiveMap>().0, MapId::PRIMARY);
+        assert_eq!(
+            *world.resource::<VoxelWorldTransform>(),
+            VoxelWorldTransform::IDENTITY
+        );
+        assert!(world.get_resource::<ChunkClipMap>().is_none());
+        let (active, dungeon_transform, has_clipmap) =
+            Maps::with_map(&mut world, dungeon, |world| {
+                (
+                    world.resource::<ActiveMap>().0,
+                    *world.resource::<VoxelWorldTransform>(),
+                    world.get_resource::<ChunkClipMap>().is_some(),
+                )
+            })
+            .unwrap();
+        assert_eq!(active, dungeon);
+        assert_eq!(dungeon_transform, transform);
+        assert!(has_clipmap);
+        assert_eq!(world.resource::<ActiveMap>().0, MapId::PRIMARY);
+
+        assert!(Maps::with_map(&mut world, MapId(2), |_| ()).is_none());
+    }
+}
@@ -0,0 +1,293 @@
+use crate::database::MapDb;
+use crate::material::{MaterialClass, MaterialClasses, MaterialError, MaterialIds};
+use crate::palette::PaletteId8;
+
+use bevy::asset::{AssetLoader, LoadContext, LoadedAsset};
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::utils::BoxedFuture;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Loads the [`MaterialRegistry`] resource from a RON or TOML asset, and loads it again whenever the asset changes.
+///
+/// Files must end in `.materials.ron` or `.materials.toml` and list the materials in a `materials` sequence. Each material
+/// gets its ID by name from the [`MaterialIds`] stored in the [`MapDb`], and new materials are assigned the next free IDs,
+/// which are written back to the database. Without a database, IDs are only stable while the app is running.
+///
+/// Requires the [`MapPlugin`](crate::MapPlugin) and Bevy's `AssetPlugin`.
+pub struct MaterialRegistryPlugin {
+    pub path: String,
+}
+
+impl MaterialRegistryPlugin {
+    pub fn new(path: impl Into<String>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl Plugin for MaterialRegistryPlugin {
+    fn build(&self, app: &mut App) {
+        let path = self.path.clone();
+        app.insert_resource(MaterialRegistry::default())
+            .add_asset::<MaterialsAsset>()
+            .add_asset_loader(MaterialsLoader)
+            .add_startup_system(move |mut commands: Commands, assets: Res<AssetServer>| {
+                let handle: Handle<MaterialsAsset> = assets.load(path.as_str());
+                commands.insert_resource(MaterialsHandle(handle));
+            })
+            .add_system_to_stage(CoreStage::PreUpdate, material_asset_system);
+    }
+}
+
+/// The properties of one voxel material.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct MaterialDef {
+    /// Identifies the material across sessions, so renaming a material gives it a new ID. Mods should prefix their names to
+    /// avoid collisions.
+    pub name: String,
+    /// Linear RGBA.
+    pub color: [f32; 4],
+    pub hardness: f32,
+    pub is_solid: bool,
+    /// How the renderer culls and draws this material's faces.
+    pub class: MaterialClass,
+    /// The asset path of the sound played when walking on this material. It's loaded untyped, so the crate that plays it
+    /// decides the asset type.
+    pub footstep_sound: Option<String>,
+}
+
+impl Default for MaterialDef {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            color: [1.0; 4],
+            hardness: 1.0,
+            is_solid: true,
+            class: MaterialClass::Opaque,
+            footstep_sound: None,
+        }
+    }
+}
+
+/// The definitions and [`MaterialIds`] of all voxel materials, as loaded by the [`MaterialRegistryPlugin`]. Empty until the
+/// asset loads.
+#[derive(Default)]
+pub struct MaterialRegistry {
+    ids: MaterialIds,
+    /// Indexed by ID. IDs that are reserved for materials which are no longer defined have `None`.
+    materials: Vec<Option<MaterialDef>>,
+    footstep_sounds: Vec<Option<HandleUntyped>>,
+}
+
+impl MaterialRegistry {
+    /// Assigns IDs to `defs`, keeping the ones in `ids`. Footstep sounds are loaded with `load_sound`.
+    pub fn new(
+        mut ids: MaterialIds,
+        defs: Vec<MaterialDef>,
+        mut load_sound: impl FnMut(&str) -> HandleUntyped,
+    ) -> Result<Self, MaterialError> {
+        let mut materials = Vec::new();
+        let mut footstep_sounds = Vec::new();
+        for def in defs.into_iter() {
+            let index = usize::from(ids.assign(&def.name)?);
+            if index >= materials.len() {
+                materials.resize(index + 1, None);
+                footstep_sounds.resize(index + 1, None);
+            }
+            if materials[index].is_some() {
+                return Err(MaterialError::DuplicateName(def.name));
+            }
+            footstep_sounds[index] = def.footstep_sound.as_deref().map(&mut load_sound);
+            materials[index] = Some(def);
+        }
+        Ok(Self {
+            ids,
+            materials,
+            footstep_sounds,
+        })
+    }
+
+    pub fn ids(&self) -> &MaterialIds {
+        &self.ids
+    }
+
+    /// The ID of the material called `name`.
+    pub fn id(&self, name: &str) -> Option<PaletteId8> {
+        self.ids.get(name)
+    }
+
+    pub fn get(&self, id: PaletteId8) -> Option<&MaterialDef> {
+        self.materials.get(usize::from(id))?.as_ref()
+    }
+
+    pub fn footstep_sound(&self, id: PaletteId8) -> Option<&HandleUntyped> {
+        self.footstep_sounds.get(usize::from(id))?.as_ref()
+    }
+
+    /// The [`MaterialClass`] of every material. Undefined materials are opaque.
+    pub fn classes(&self) -> MaterialClasses {
+        let mut classes = MaterialClasses::default();
+        for (id, def) in self.iter() {
+            classes.set(id, def.class);
+        }
+        classes
+    }
+
+    /// The defined materials, by ID.
+    pub fn iter(&self) -> impl Iterator<Item = (PaletteId8, &MaterialDef)> {
+        self.materials
+            .iter()
+            .enumerate()
+            .filter_map(|(id, def)| Some((id as PaletteId8, def.as_ref()?)))
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, TypeUuid)]
+#[uuid = "5d1c2b0e-8f3a-4c67-9e4b-2a7f6c1d9b38"]
+pub struct MaterialsAsset {
+    pub materials: Vec<MaterialDef>,
+}
+
+/// The asset that the [`MaterialRegistryPlugin`] keeps loading.
+pub struct MaterialsHandle(pub Handle<MaterialsAsset>);
+
+#[derive(Default)]
+pub struct MaterialsLoader;
+
+impl AssetLoader for MaterialsLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let is_toml = load_context
+                .path()
+                .extension()
+                .map_or(false, |ext| ext == "toml");
+            let materials: MaterialsAsset = if is_toml {
+                toml::from_slice(bytes)?
+            } else {
+                ron::de::from_bytes(bytes)?
+            };
+            load_context.set_default_asset(LoadedAsset::new(materials));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["materials.ron", "materials.toml"]
+    }
+}
+
+fn material_asset_system(
+    handle: Option<Res<MaterialsHandle>>,
+    assets: Res<Assets<MaterialsAsset>>,
+    asset_server: Res<AssetServer>,
+    db: Option<Res<Arc<RwLock<MapDb>>>>,
+    mut asset_events: EventReader<AssetEvent<MaterialsAsset>>,
+    mut registry: ResMut<MaterialRegistry>,
+) {
+    let handle = if let Some(handle) = handle {
+        handle
+    } else {
+        return;
+    };
+    let changed = asset_events.iter().any(|event| match event {
+        AssetEvent::Created { handle: h } | AssetEvent::Modified { handle: h } => *h == handle.0,
+        AssetEvent::Removed { .. } => false,
+    });
+    if !changed {
+        return;
+    }
+    let asset = if let Some(asset) = assets.get(&handle.0) {
+        asset
+    } else {
+        return;
+    };
+
+    let ids = match &db {
+        Some(db) => db.read().material_ids().clone(),
+        None => registry.ids().clone(),
+    };
+    let new_registry = match MaterialRegistry::new(ids, asset.materials.clone(), |path| {
+        asset_server.load_untyped(path)
+    }) {
+        Ok(new_registry) => new_registry,
+        Err(e) => {
+            log::error!("Failed to register materials from {:?}: {:?}", handle.0, e);
+            return;
+        }
+    };
+    if let Some(db) = &db {
+        if let Err(e) = db.write().register_material_ids(new_registry.ids().clone()) {
+            log::error!("Failed to write material IDs: {:?}", e);
+            return;
+        }
+    }
+    log::info!(
+        "Registered {} materials from {:?}",
+        new_registry.iter().count(),
+        handle.0
+    );
+    *registry = new_registry;
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry_keeps_stored_ids() {
+        let mut stored = MaterialIds::default();
+        stored.assign("stone").unwrap();
+        stored.assign("sand").unwrap();
+
+        let asset: MaterialsAsset = ron::from_str(
+            "(materials: [
+                (name: \"mod:glass\", is_solid: false, class: Transparent, color: (0.8, 0.9, 1.0, 0.3)),
+                (name: \"stone\", hardness: 3.0),
+            ])",
+        )
+        .unwrap();
+        let registry =
+            MaterialRegistry::new(stored.clone(), asset.materials, |_| unreachable!()).unwrap();
+        assert!(stored.is_prefix_of(registry.ids()));
+        assert_eq!(registry.id("stone"), Some(0));
+        assert_eq!(registry.id("mod:glass"), Some(2));
+        assert_eq!(registry.get(0).unwrap().hardness, 3.0);
+        assert!(!registry.get(2).unwrap().is_solid);
+        assert_eq!(registry.get(2).unwrap().class, MaterialClass::Transparent);
+        assert_eq!(registry.classes().get(2), MaterialClass::Transparent);
+        assert_eq!(registry.classes().get(0), MaterialClass::Opaque);
+        // Sand is no longer defined, but its ID stays reserved.
+        assert_eq!(registry.id("sand"), Some(1));
+        assert_eq!(registry.get(1), None);
+        assert_eq!(
+            registry.iter().map(|(id, _)| id).collect::<Vec<_>>(),
+            vec![0, 2]
+        );
+
+        let duplicates = vec![
+            MaterialDef {
+                name: "stone".into(),
+                ..Default::default()
+            };
+            2
+        ];
+        assert_eq!(
+            MaterialRegistry::new(stored, duplicates, |_| unreachable!()).err(),
+            Some(MaterialError::DuplicateName("stone".into()))
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use super::config::MapConfig;
+use super::events::ChunkEvent;
+use super::tasks::MapTask;
+use crate::core::glam::IVec3;
+use crate::database::{ChunkDbKey, ChunkMetadata, MapDb, MetadataError};
+
+use bevy::prelude::*;
+use futures_lite::future;
+use grid_tree::NodeKey;
+use parking_lot::RwLock;
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+/// Persists `M` [`ChunkMetadata`] with the LOD0 chunks that own it, and sends [`ChunkMetadataEvent`]s as those chunks come and
+/// go.
+///
+/// Requires the [`MapPlugin`](crate::MapPlugin) with [`MapStorage::Sled`](crate::MapStorage::Sled), since only the [`MapDb`]
+/// stores metadata. Add one plugin for each metadata type.
+pub struct ChunkMetadataPlugin<M>(PhantomData<M>);
+
+impl<M> Default for ChunkMetadataPlugin<M> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<M: ChunkMetadata> Plugin for ChunkMetadataPlugin<M> {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ChunkMetadataWrites::<M>::default())
+            .insert_resource(PendingMetadataReads::<M>::default())
+            .add_event::<ChunkMetadataEvent<M>>()
+            .add_system_to_stage(CoreStage::PostUpdate, chunk_metadata_system::<M>);
+    }
+}
+
+/// The hooks for `M` metadata.
+pub enum ChunkMetadataEvent<M> {
+    /// A LOD0 chunk with `M` metadata was loaded. Chunks without metadata don't get an event.
+    Loaded { key: NodeKey<IVec3>, metadata: M },
+    /// A LOD0 chunk was evicted, so any entities spawned from its metadata should be saved with [`ChunkMetadataWrites`] and
+    /// despawned.
+    Evicted(NodeKey<IVec3>),
+}
+
+/// Changes to `M` metadata that are written to the [`MapDb`] at the end of the frame.
+///
+/// Metadata is only read when its LOD0 chunk is loaded. Chunks that have no voxels under an empty ancestor are never loaded,
+/// so metadata should be attached to chunks with voxels, e.g. the ground under a chest.
+pub struct ChunkMetadataWrites<M> {
+    queued: Vec<(ChunkDbKey, Option<M>)>,
+}
+
+impl<M> Default for ChunkMetadataWrites<M> {
+    fn default() -> Self {
+        Self { queued: Vec::new() }
+    }
+}
+
+impl<M> ChunkMetadataWrites<M> {
+    pub fn write(&mut self, key: NodeKey<IVec3>, metadata: M) {
+        self.queued.push((key.into(), Some(metadata)));
+    }
+
+    pub fn remove(&mut self, key: NodeKey<IVec3>) {
+        self.queued.push((key.into(), None));
+    }
+}
+
+type MetadataReads<M> = Vec<(NodeKey<IVec3>, Result<Option<M>, MetadataError>)>;
+
+/// Evictions wait behind the reads that were spawned before them, so a chunk's `Loaded` event is never sent after its
+/// `Evicted` event.
+enum PendingMetadata<M> {
+    Reads(MapTask<MetadataReads<M>>),
+    Evicted(NodeKey<IVec3>),
+}
+
+struct PendingMetadataReads<M> {
+    queue: VecDeque<PendingMetadata<M>>,
+}
+
+impl<M> Default for PendingMetadataReads<M> {
+    fn default() -> Self {
+        Self {
+            queue: VecDeque::new(),
+        }
+    }
+}
+
+fn chunk_metadata_system<M: ChunkMetadata>(
+    config: Res<MapConfig>,
+    db: Option<Res<Arc<RwLock<MapDb>>>>,
+    mut writes: ResMut<ChunkMetadataWrites<M>>,
+    mut pending: ResMut<PendingMetadataReads<M>>,
+    mut chunk_events: EventReader<ChunkEvent>,
+    mut metadata_events: EventWriter<ChunkMetadataEvent<M>>,
+) {
+    let db = if let Some(db) = db {
+        db
+    } else {
+        writes.queued.clear();
+        chunk_events.clear();
+        return;
+    };
+
+    // Writes are small and go straight to sled's page cache. Doing them here, before any reads are spawned, guarantees that a
+    // chunk reloaded right after eviction sees the metadata that was saved for it.
+    for (key, metadata) in writes.queued.drain(..) {
+        let result = match metadata {
+            Some(metadata) => db.read().write_chunk_metadata(key, &metadata),
+            None => db
+                .read()
+                .remove_chunk_metadata::<M>(key)
+                .map_err(MetadataError::from),
+        };
+        if let Err(e) = result {
+            log::error!(
+                "Failed to write {} metadata for {:?}: {:?}",
+                M::NAME,
+                key,
+                e
+            );
+        }
+    }
+
+    let mut loaded = Vec::new();
+    for event in chunk_events.iter() {
+        match *event {
+            ChunkEvent::Loaded(key) if key.level == 0 => loaded.push(key),
+            ChunkEvent::Evicted(key) if key.level == 0 => {
+                spawn_reads(&db, &mut loaded, &mut pending, config.deterministic);
+                pending.queue.push_back(PendingMetadata::Evicted(key));
+            }
+            _ => {}
+        }
+    }
+    spawn_reads(&db, &mut loaded, &mut pending, config.deterministic);
+
+    // Complete reads and evictions in the order the chunks were loaded and evicted.
+    while let Some(front) = pending.queue.pop_front() {
+        let mut task = match front {
+            PendingMetadata::Reads(task) => task,
+            PendingMetadata::Evicted(key) => {
+                metadata_events.send(ChunkMetadataEvent::Evicted(key));
+                continue;
+            }
+        };
+        let reads = if let Some(reads) = future::block_on(future::poll_once(&mut task)) {
+            reads
+        } else {
+            pending.queue.push_front(PendingMetadata::Reads(task));
+            break;
+        };
+        for (key, result) in reads {
+            match result {
+                Ok(Some(metadata)) => {
+                    metadata_events.send(ChunkMetadataEvent::Loaded { key, metadata })
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    log::error!("Failed to read {} metadata for {:?}: {:?}", M::NAME, key, e)
+                }
+            }
+        }
+    }
+}
+
+fn spawn_reads<M: ChunkMetadata>(
+    db: &Arc<RwLock<MapDb>>,
+    loaded: &mut Vec<NodeKey<IVec3>>,
+    pending: &mut PendingMetadataReads<M>,
+    deterministic: bool,
+) {
+    if loaded.is_empty() {
+        return;
+    }
+    let keys = std::mem::take(loaded);
+    let db = Arc::clone(db);
+    let task = MapTask::io(deterministic, async move {
+        let db = db.read();
+        keys.into_iter()
+            .map(|key| (key, db.read_chunk_metadata(key.into())))
+            .collect()
+    });
+    pending.queue.push_back(PendingMetadata::Reads(task));
+}
@@ -0,0 +1,155 @@
+use super::config::MapConfig;
+use super::events::ChunkEvent;
+use super::maps::ActiveMap;
+use super::witness::Witness;
+use crate::clipmap::{AgentParams, ChunkClipMap, NavTile};
+use crate::core::glam::IVec3;
+use crate::core::ilattice::prelude::Extent;
+use crate::core::{SmallKeyHashMap, SmallKeyHashSet};
+use crate::units::ChunkUnits;
+use crate::world_transform::VoxelWorldTransform;
+
+use bevy::prelude::*;
+use grid_tree::NodeKey;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub struct NavMeshConfig {
+    /// Keeps a [`NavTile`] for every loaded LOD0 chunk near a [`Witness`], so pathfinding crates can walk on the terrain.
+    pub enabled: bool,
+    /// Tiles are kept for the LOD0 chunks within this many chunks of any [`Witness`], measured along each axis.
+    pub radius_chunks: u32,
+    /// The maximum number of tiles that are extracted on each frame.
+    pub max_tiles_per_frame: usize,
+    pub agent: AgentParams,
+}
+
+impl Default for NavMeshConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            radius_chunks: 4,
+            max_tiles_per_frame: 8,
+            agent: AgentParams::default(),
+        }
+    }
+}
+
+/// Sent by the `navmesh_system` whenever the walkable surface of a chunk changes.
+#[derive(Clone, Debug)]
+pub enum NavTileEvent {
+    /// The tile was extracted for the first time, or it changed since it was last sent. Tiles without any cells are only sent
+    /// if they replace a tile that had some.
+    Changed(Arc<NavTile>),
+    /// The chunk went out of range or was evicted, so its tile should be dropped.
+    Removed(ChunkUnits<IVec3>),
+}
+
+/// The [`NavTile`]s of the chunks near each [`Witness`], keyed by LOD0 chunk coordinates.
+#[derive(Default)]
+pub struct NavTiles {
+    tiles: SmallKeyHashMap<IVec3, Arc<NavTile>>,
+    /// Chunks whose tiles need to be extracted again.
+    stale: SmallKeyHashSet<IVec3>,
+}
+
+impl NavTiles {
+    pub fn get(&self, coordinates: ChunkUnits<IVec3>) -> Option<&Arc<NavTile>> {
+        self.tiles.get(&coordinates.into_inner())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<NavTile>> {
+        self.tiles.values()
+    }
+
+    /// Marks the tiles of the chunks within one chunk of `chunks`, since their cells and links depend on the voxels around
+    /// them.
+    fn mark_around(&mut self, chunks: ChunkUnits<Extent<IVec3>>) {
+        let ChunkUnits(around) = chunks.padded(1);
+        for coords in around.iter3() {
+            if self.tiles.contains_key(&coords) {
+                self.stale.insert(coords);
+            }
+        }
+    }
+}
+
+/// Extracts a [`NavTile`] for each loaded LOD0 chunk near a [`Witness`], and sends a [`NavTileEvent`] whenever one changes.
+///
+/// Tiles are extracted again when their chunks or neighbors are loaded or edited, which stitches the border links of
+/// neighboring tiles as the map streams in.
+pub fn navmesh_system(
+    config: Res<MapConfig>,
+    clipmap: Res<ChunkClipMap>,
+    world_transform: Res<VoxelWorldTransform>,
+    active_map: Res<ActiveMap>,
+    witnesses: Query<(&Witness, &Transform)>,
+    mut tiles: ResMut<NavTiles>,
+    mut chunk_events: EventReader<ChunkEvent>,
+    mut nav_events: EventWriter<NavTileEvent>,
+) {
+    if !config.navmesh.enabled {
+        chunk_events.clear();
+        return;
+    }
+
+    let radius = config.navmesh.radius_chunks as i32;
+    let mut in_range = SmallKeyHashSet::default();
+    for (_, tfm) in witnesses.iter().filter(|(w, _)| w.is_in(&active_map)) {
+        let position = world_transform.transform_to_voxel(tfm);
+        let ChunkUnits(extent) = position.floor_chunk().extent_around(radius);
+        in_range.extend(
+            extent
+                .iter3()
+                .filter(|&c| clipmap.is_loaded(NodeKey::new(0, c))),
+        );
+    }
+
+    let out_of_range: Vec<_> = tiles
+        .tiles
+        .keys()
+        .filter(|coords| !in_range.contains(coords))
+        .copied()
+        .collect();
+    for coords in out_of_range.into_iter() {
+        let tile = tiles.tiles.remove(&coords).unwrap();
+        tiles.stale.remove(&coords);
+        if !tile.is_empty() {
+            nav_events.send(NavTileEvent::Removed(ChunkUnits(coords)));
+        }
+    }
+
+    for event in chunk_events.iter() {
+        match *event {
+            ChunkEvent::Loaded(key) if key.level == 0 => {
+                tiles.mark_around(ChunkUnits(key.coordinates).extent_around(0))
+            }
+            ChunkEvent::Edited(region) => {
+                tiles.mark_around(region.touched_chunks());
+            }
+            _ => {}
+        }
+    }
+
+    let mut stale: Vec<_> = in_range
+        .iter()
+        .filter(|coords| tiles.stale.contains(coords) || !tiles.tiles.contains_key(coords))
+        .copied()
+        .collect();
+    // Sorted so every run extracts the tiles in the same order.
+    stale.sort_by_key(|c| c.to_array());
+    for coords in stale.into_iter().take(config.navmesh.max_tiles_per_frame) {
+        tiles.stale.remove(&coords);
+        let tile = clipmap.extract_nav_tile(ChunkUnits(coords), &config.navmesh.agent);
+        let changed = match tiles.tiles.get(&coords) {
+            Some(old) => **old != tile,
+            None => !tile.is_empty(),
+        };
+        let tile = Arc::new(tile);
+        tiles.tiles.insert(coords, tile.clone());
+        if changed {
+            nav_events.send(NavTileEvent::Changed(tile));
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use super::chunk_entities::ChunkEntities;
+use super::config::MapConfig;
+use super::events::ChunkEvent;
+use super::maps::ActiveMap;
+use super::witness::Witness;
+use crate::chunk::{
+    ChunkShape, PaddedChunkShape, UniformChunk, AMBIENT_SD8, CHUNK_SHAPE_IVEC3, PADDED_CHUNK_EDGE,
+    PADDED_CHUNK_SIZE,
+};
+use crate::clipmap::{ChunkClipMap, ChunkNode};
+use crate::coordinates::{chunk_min, CUBE_CORNERS};
+use crate::core::glam::IVec3;
+use crate::core::ilattice::prelude::Extent;
+use crate::core::{SmallKeyHashMap, SmallKeyHashSet};
+use crate::sdf::Sd8;
+use crate::units::ChunkUnits;
+use crate::world_transform::VoxelWorldTransform;
+
+use bevy::prelude::*;
+use fast_surface_nets::ndshape::ConstShape3u32;
+use fast_surface_nets::{surface_nets, SurfaceNetsBuffer};
+use grid_tree::NodeKey;
+use ndshape::ConstShape;
+use parry3d::math::Point;
+use parry3d::shape::SharedShape;
+use serde::{Deserialize, Serialize};
+
+/// Same as [`PaddedChunkShape`], but with the coordinate type expected by `fast-surface-nets`.
+type SurfaceNetsShape = ConstShape3u32<
+    { PADDED_CHUNK_EDGE as u32 },
+    { PADDED_CHUNK_EDGE as u32 },
+    { PADDED_CHUNK_EDGE as u32 },
+>;
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub struct PhysicsConfig {
+    /// Colliders are kept for the LOD0 chunks within this many chunks of any [`Witness`], measured along each axis. This
+    /// bounds the number of colliders that physics integrations have to keep track of.
+    pub collider_radius_chunks: u32,
+}
+
+impl Default for PhysicsConfig {
+    fn default() -> Self {
+        Self {
+            collider_radius_chunks: 2,
+        }
+    }
+}
+
+/// A `parry3d` triangle mesh of the surface in one LOD0 chunk, spawned by the `collider_system`.
+///
+/// The entity's `Transform` is translated to the chunk's minimum, and the mesh vertices are relative to it. Physics
+/// integrations can copy the shape into their own collider components.
+#[derive(Clone, Component)]
+pub struct ChunkCollider {
+    pub coordinates: ChunkUnits<IVec3>,
+    pub shape: SharedShape,
+}
+
+/// The entities of all [`ChunkCollider`]s, keyed by LOD0 chunk coordinates. They're also indexed in the [`ChunkEntities`].
+#[derive(Default)]
+pub struct ChunkColliders {
+    /// Every chunk in range that was checked for a surface. `None` if it had no surface, or it wasn't loaded.
+    tracked: SmallKeyHashMap<IVec3, Option<Entity>>,
+    /// Tracked chunks whose voxels, or the voxels of their positive neighbors, changed since they were checked.
+    stale: SmallKeyHashSet<IVec3>,
+}
+
+impl ChunkColliders {
+    pub fn get(&self, coordinates: ChunkUnits<IVec3>) -> Option<Entity> {
+        let ChunkUnits(coordinates) = coordinates;
+        self.tracked.get(&coordinates).copied().flatten()
+    }
+
+    /// Marks the tracked chunks whose colliders depend on any of the LOD0 `chunks`, i.e. the chunks themselves and their
+    /// negative neighbors, which read them as padding.
+    fn mark_dependents(&mut self, chunks: Extent<IVec3>) {
+        let dependents =
+            Extent::from_min_and_lub(chunks.minimum - IVec3::ONE, chunks.least_upper_bound());
+        for coords in dependents.iter3() {
+            if self.tracked.contains_key(&coords) {
+                self.stale.insert(coords);
+            }
+        }
+    }
+
+    /// Despawns the collider of the chunk at `coords`, if it has one. If `keep_tracking`, the chunk isn't checked again
+    /// until it's marked as stale.
+    fn remove(
+        &mut self,
+        commands: &mut Commands,
+        chunk_entities: &mut ChunkEntities,
+        coords: IVec3,
+        keep_tracking: bool,
+    ) {
+        if let Some(Some(entity)) = self.tracked.remove(&coords) {
+            chunk_entities.remove(NodeKey::new(0, coords), entity);
+            commands.entity(entity).despawn();
+        }
+        if keep_tracking {
+            self.tracked.insert(coords, None);
+        } else {
+            self.stale.remove(&coords);
+        }
+    }
+}
+
+/// Keeps a [`ChunkCollider`] for every loaded LOD0 chunk with a surface near a [`Witness`].
+///
+/// Colliders are regenerated when a [`ChunkEvent`] changes the voxels they depend on (from edits, loads, undo, imports, or
+/// evictions), and they are despawned when their chunks are evicted, emptied, or fall out of range. Chunks that are written
+/// to the clipmap directly, without an event, keep their old colliders.
+///
+/// NOTE: Positive neighbors that aren't loaded are treated as ambient, so a collider can be missing a sliver of surface along
+/// its boundary until the neighbor loads.
+pub fn collider_system(
+    mut commands: Commands,
+    config: Res<MapConfig>,
+    clipmap: Res<ChunkClipMap>,
+    world_transform: Res<VoxelWorldTransform>,
+    mut colliders: ResMut<ChunkColliders>,
+    mut chunk_entities: ResMut<ChunkEntities>,
+    active_map: Res<ActiveMap>,
+    witnesses: Query<(&Witness, &Transform)>,
+    mut chunk_events: EventReader<ChunkEvent>,
+) {
+    for event in chunk_events.iter() {
+        match *event {
+            ChunkEvent::Loaded(key) | ChunkEvent::Evicted(key) if key.level == 0 => {
+                colliders.mark_dependents(Extent::from_min_and_shape(key.coordinates, IVec3::ONE))
+            }
+            ChunkEvent::Edited(region) => {
+                let ChunkUnits(chunks) = region.touched_chunks();
+                colliders.mark_dependents(chunks);
+            }
+            _ => {}
+        }
+    }
+
+    let radius = config.physics.collider_radius_chunks as i32;
+    let mut in_range = SmallKeyHashSet::default();
+    for (_, tfm) in witnesses.iter().filter(|(w, _)| w.is_in(&active_map)) {
+        let position = world_transform.transform_to_voxel(tfm);
+        let ChunkUnits(extent) = position.floor_chunk().extent_around(radius);
+        in_range.extend(extent.iter3());
+    }
+
+    let out_of_range: Vec<_> = colliders
+        .tracked
+        .keys()
+        .filter(|coords| !in_range.contains(coords))
+        .copied()
+        .collect();
+    for coords in out_of_range.into_iter() {
+        colliders.remove(&mut commands, &mut chunk_entities, coords, false);
+    }
+
+    for coords in in_range.into_iter() {
+        if colliders.tracked.contains_key(&coords) && !colliders.stale.remove(&coords) {
+            continue;
+        }
+
+        if is_uniform_neighborhood(&clipmap, coords) {
+            // No surface. This avoids decompressing solid chunks deep underground.
+            colliders.remove(&mut commands, &mut chunk_entities, coords, true);
+            continue;
+        }
+
+        let shape = copy_padded_sdf(&clipmap, ChunkUnits(coords))
+            .and_then(|padded| generate_trimesh(&padded));
+        let shape = if let Some(shape) = shape {
+            shape
+        } else {
+            // Evicted, empty, or without a surface.
+            colliders.remove(&mut commands, &mut chunk_entities, coords, true);
+            continue;
+        };
+
+        let collider = ChunkCollider {
+            coordinates: ChunkUnits(coords),
+            shape,
+        };
+        let entity = match colliders.get(ChunkUnits(coords)) {
+            Some(entity) => {
+                commands.entity(entity).insert(collider);
+                entity
+            }
+            None => {
+                let transform =
+                    world_transform.voxel_local_transform(chunk_min(ChunkUnits(coords)));
+                let entity = commands
+                    .spawn_bundle(TransformBundle::from_transform(transform))
+                    .insert(collider)
+                    .id();
+                chunk_entities.insert(NodeKey::new(0, coords), entity);
+                entity
+            }
+        };
+        colliders.tracked.insert(coords, Some(entity));
+    }
+}
+
+fn find_lod0_node(clipmap: &ChunkClipMap, coords: IVec3) -> Option<&ChunkNode> {
+    clipmap
+        .octree
+        .find_node(NodeKey::new(0, coords))
+        .and_then(|ptr| clipmap.octree.get_value(ptr))
+}
+
+/// Returns `true` if the chunk at `coords` and its positive neighbors are all the same [`UniformChunk`]. Missing neighbors are
+/// [`UniformChunk::Air`].
+fn is_uniform_neighborhood(clipmap: &ChunkClipMap, coords: IVec3) -> bool {
+    let mut uniforms = CUBE_CORNERS.into_iter().map(|offset| {
+        find_lod0_node(clipmap, coords + offset).map_or(Some(UniformChunk::Air), |n| n.uniform())
+    });
+    let first = uniforms.next().unwrap();
+    first.is_some() && uniforms.all(|u| u == first)
+}
+
+/// Copies the SDF of the chunk at `coords` and the 2 layers of voxels from its positive neighbors, laid out in
+/// [`PaddedChunkShape`]. Returns `None` if the chunk itself is empty or not loaded.
+fn copy_padded_sdf(
+    clipmap: &ChunkClipMap,
+    coords: ChunkUnits<IVec3>,
+) -> Option<Box<[Sd8; PADDED_CHUNK_SIZE]>> {
+    let ChunkUnits(coords) = coords;
+    let neighbors = CUBE_CORNERS.map(|offset| {
+        find_lod0_node(clipmap, coords + offset).and_then(|node| node.get_decompressed())
+    });
+    neighbors[0].as_ref()?;
+
+    let mut padded = Box::new([AMBIENT_SD8; PADDED_CHUNK_SIZE]);
+    for i in 0..PaddedChunkShape::SIZE {
+        let p = IVec3::from(PaddedChunkShape::delinearize(i));
+        // Same order as CUBE_CORNERS.
+        let neighbor_i = (p.x >= CHUNK_SHAPE_IVEC3.x) as usize
+            | ((p.y >= CHUNK_SHAPE_IVEC3.y) as usize) << 1
+            | ((p.z >= CHUNK_SHAPE_IVEC3.z) as usize) << 2;
+        if let Some(neighbor) = &neighbors[neighbor_i] {
+            let offset = p - CUBE_CORNERS[neighbor_i] * CHUNK_SHAPE_IVEC3;
+            let j = ChunkShape::linearize(offset.to_array()) as usize;
+            padded[i as usize] = neighbor.as_ref().sdf[j];
+        }
+    }
+    Some(padded)
+}
+
+/// Returns `None` if there is no surface.
+fn generate_trimesh(padded: &[Sd8; PADDED_CHUNK_SIZE]) -> Option<SharedShape> {
+    let sdf: Vec<f32> = padded.iter().map(|&d| f32::from(d)).collect();
+    let mut buffer = SurfaceNetsBuffer::default();
+    let max = [PADDED_CHUNK_EDGE as u32 - 1; 3];
+    surface_nets(&sdf, &SurfaceNetsShape {}, [0; 3], max, &mut buffer);
+    if buffer.indices.is_empty() {
+        return None;
+    }
+
+    let vertices = buffer.positions.into_iter().map(Point::from).collect();
+    let indices = buffer
+        .indices
+        .chunks_exact(3)
+        .map(|tri| [tri[0], tri[1], tri[2]])
+        .collect();
+    Some(SharedShape::trimesh(vertices, indices))
+}
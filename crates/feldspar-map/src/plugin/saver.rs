@@ -0,0 +1,149 @@
+use super::config::MapConfig;
+use super::events::ChunkEvent;
+use super::maps::ActiveMap;
+use super::tasks::MapTask;
+use super::unsaved::UnsavedChunks;
+use super::witness::{Witness, WitnessObservers};
+use crate::clipmap::ChunkClipMap;
+use crate::database::{BackendError, MapBackend};
+use crate::world_transform::VoxelWorldTransform;
+
+use bevy::prelude::*;
+use futures_lite::future;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub struct SaverConfig {
+    /// The number of dirty chunks to start saving in a single frame (batch).
+    pub save_batch_size: usize,
+    /// The maximum number of pending save tasks.
+    pub max_pending_save_tasks: usize,
+}
+
+impl Default for SaverConfig {
+    fn default() -> Self {
+        Self {
+            save_batch_size: 256,
+            max_pending_save_tasks: 16,
+        }
+    }
+}
+
+pub struct SavedBatch {
+    num_chunks: usize,
+    result: Result<(), BackendError>,
+}
+
+pub struct PendingSaveTasks {
+    tasks: VecDeque<MapTask<SavedBatch>>,
+}
+
+impl PendingSaveTasks {
+    pub fn new() -> Self {
+        PendingSaveTasks {
+            tasks: VecDeque::new(),
+        }
+    }
+
+    /// Blocks until every batch in flight is saved.
+    pub(crate) fn wait(&mut self) {
+        for task in self.tasks.drain(..) {
+            let saved_batch = future::block_on(task);
+            if let Err(e) = saved_batch.result {
+                log::error!(
+                    "Failed to save batch of {} chunks: {:?}",
+                    saved_batch.num_chunks,
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Evicts trees that have left the clip shapes of all witnesses, writing any dirty chunks back to the [`MapBackend`].
+pub fn saver_system(
+    config: Res<MapConfig>,
+    world_transform: Res<VoxelWorldTransform>,
+    witness_transforms: Query<(&Witness, &Transform)>,
+    active_map: Res<ActiveMap>,
+    backend: Res<Arc<dyn MapBackend>>,
+    mut clipmap: ResMut<ChunkClipMap>,
+    mut save_tasks: ResMut<PendingSaveTasks>,
+    unsaved: Res<UnsavedChunks>,
+    mut chunk_events: EventWriter<ChunkEvent>,
+) {
+    let PendingSaveTasks { tasks } = &mut *save_tasks;
+
+    // Complete pending save tasks in queue order.
+    while let Some(mut task) = tasks.pop_front() {
+        if let Some(saved_batch) = future::block_on(future::poll_once(&mut task)) {
+            if let Err(e) = saved_batch.result {
+                log::error!(
+                    "Failed to save batch of {} chunks: {:?}",
+                    saved_batch.num_chunks,
+                    e
+                );
+            }
+        } else {
+            tasks.push_front(task);
+            break;
+        }
+    }
+
+    // Trees in the prefetch cones are kept so that moving witnesses don't evict what they just prefetched.
+    let witnesses = WitnessObservers::new(
+        witness_transforms.iter(),
+        active_map.0,
+        &world_transform,
+        &config.loader,
+        clipmap.stream_config.clip_sphere_radius,
+    );
+    // After a teleport, nothing around the old positions is needed anymore, so it's all evicted in one batch.
+    if !witnesses.teleported && tasks.len() >= config.saver.max_pending_save_tasks {
+        return;
+    }
+    let save_batch_size = if witnesses.teleported {
+        usize::MAX
+    } else {
+        config.saver.save_batch_size
+    };
+
+    // Evict whole trees until we have a full batch of dirty chunks. Clean chunks are simply dropped. The nodes are freed
+    // before the batch is written, so the dirty chunks are kept in the unsaved set in case a witness comes back first.
+    let mut dirty_chunks = Vec::new();
+    let clip_region = witnesses.clip_region();
+    for root_key in clipmap.eviction_search(&clip_region) {
+        if dirty_chunks.len() >= save_batch_size {
+            break;
+        }
+        clipmap.evict_root(root_key, |evicted| {
+            chunk_events.send(ChunkEvent::Evicted(evicted.key));
+            if evicted.is_dirty {
+                dirty_chunks.push(evicted);
+            }
+        });
+    }
+
+    if dirty_chunks.is_empty() {
+        return;
+    }
+
+    // Spawn a new task to compress and save those chunks.
+    let batch = unsaved.insert(
+        dirty_chunks
+            .into_iter()
+            .map(|evicted| (evicted.key, evicted.chunk)),
+    );
+    let unsaved = UnsavedChunks::clone(&unsaved);
+    let backend_clone = Arc::clone(&backend);
+    let encoding = config.encoding;
+    let save_task = MapTask::io(config.deterministic, async move {
+        SavedBatch {
+            num_chunks: batch.len(),
+            result: unsaved.write(batch, encoding, &*backend_clone).map(|_| ()),
+        }
+    });
+    tasks.push_back(save_task);
+}
@@ -0,0 +1,99 @@
+#[cfg(not(target_arch = "wasm32"))]
+use bevy::tasks::{AsyncComputeTaskPool, IoTaskPool, Task};
+use futures_lite::future;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A task of one of the map's systems. It's spawned on a task pool, unless [`MapConfig::deterministic`] is set, in which
+/// case it runs to completion on the system's thread as soon as it's spawned.
+///
+/// Bevy's task pools in the browser can't be polled for their output, so on `wasm32`, every task runs to completion when
+/// it's spawned, as if the map was deterministic. There is only one thread there anyway, so this only moves the work from
+/// between frames into the system that spawned it.
+///
+/// [`MapConfig::deterministic`]: super::MapConfig::deterministic
+pub enum MapTask<T> {
+    #[cfg(not(target_arch = "wasm32"))]
+    Spawned(Task<T>),
+    Finished(Option<T>),
+}
+
+// The output is only ever moved out of the `Option`, never pinned.
+impl<T> Unpin for MapTask<T> {}
+
+impl<T: Send + 'static> MapTask<T> {
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn io(deterministic: bool, future: impl Future<Output = T> + Send + 'static) -> Self {
+        if deterministic {
+            Self::Finished(Some(future::block_on(future)))
+        } else {
+            Self::Spawned(IoTaskPool::get().spawn(future))
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn compute(deterministic: bool, future: impl Future<Output = T> + Send + 'static) -> Self {
+        if deterministic {
+            Self::Finished(Some(future::block_on(future)))
+        } else {
+            Self::Spawned(AsyncComputeTaskPool::get().spawn(future))
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn io(_deterministic: bool, future: impl Future<Output = T> + Send + 'static) -> Self {
+        Self::Finished(Some(future::block_on(future)))
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn compute(_deterministic: bool, future: impl Future<Output = T> + Send + 'static) -> Self {
+        Self::Finished(Some(future::block_on(future)))
+    }
+
+    /// Lets a spawned task keep running without anything waiting for its output.
+    pub fn detach(self) {
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Self::Spawned(task) = self {
+            task.detach();
+        }
+    }
+}
+
+impl<T> Future for MapTask<T> {
+    type Output = T;
+
+    #[cfg_attr(target_arch = "wasm32", allow(unused_variables))]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        match self.get_mut() {
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Spawned(task) => Pin::new(task).poll(cx),
+            Self::Finished(output) => Poll::Ready(
+                output
+                    .take()
+                    .expect("MapTask polled after it returned its output"),
+            ),
+        }
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deterministic_tasks_finish_without_a_task_pool() {
+        let mut task = MapTask::io(true, async {
+            MapTask::compute(true, async { 7 }).await + 1
+        });
+        assert!(matches!(task, MapTask::Finished(Some(8))));
+        assert_eq!(future::block_on(future::poll_once(&mut task)), Some(8));
+    }
+}
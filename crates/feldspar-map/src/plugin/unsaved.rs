@@ -0,0 +1,262 @@
+use super::loader::ChunkSlot;
+use crate::chunk::{Chunk, ChunkEncoding, CompressedChunk, UniformChunk};
+use crate::core::glam::IVec3;
+use crate::core::SmallKeyHashMap;
+use crate::database::{BackendError, Change, ChunkDbKey, MapBackend};
+
+use either::Either;
+use grid_tree::NodeKey;
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+/// Dirty chunks that were taken out of the [`ChunkClipMap`](crate::clipmap::ChunkClipMap), but aren't written to the
+/// [`MapBackend`] yet.
+///
+/// The `saver_system` frees evicted nodes right away, and their batch is written later on an IO task. Until it is, the
+/// chunks stay here, and the `loader_system` takes copies of them before it reads the backend, so a witness that comes back
+/// before the save finishes doesn't load a stale chunk.
+///
+/// Every writer of dirty chunks, i.e. the saver, the edit flushes, and the autosave, goes through here. Batches are written
+/// one at a time, and a chunk that was added again by a newer batch is skipped by the older one, so writes that finish out
+/// of order can't replace a chunk with an older version.
+#[derive(Clone, Default)]
+pub struct UnsavedChunks {
+    shared: Arc<SharedUnsavedChunks>,
+}
+
+#[derive(Default)]
+struct SharedUnsavedChunks {
+    chunks: Mutex<UnsavedChunkMap>,
+    /// Held while a batch is written.
+    write_lock: Mutex<()>,
+}
+
+#[derive(Default)]
+struct UnsavedChunkMap {
+    /// The latest unsaved version of each chunk, and the generation of the batch that it belongs to.
+    chunks: SmallKeyHashMap<NodeKey<IVec3>, (u64, UnsavedChunk)>,
+    next_generation: u64,
+    /// The number of [`UnsavedBatch`]es that haven't been written or dropped yet.
+    num_pending_batches: usize,
+}
+
+/// `None` if the chunk is empty.
+type UnsavedChunk = Option<Arc<Either<Box<Chunk>, CompressedChunk>>>;
+
+/// Chunks that were added to the [`UnsavedChunks`] together, to be written together.
+pub struct UnsavedBatch {
+    shared: Arc<SharedUnsavedChunks>,
+    generation: u64,
+    chunks: Vec<(NodeKey<IVec3>, UnsavedChunk)>,
+}
+
+impl Drop for UnsavedBatch {
+    fn drop(&mut self) {
+        self.shared.chunks.lock().num_pending_batches -= 1;
+    }
+}
+
+impl UnsavedBatch {
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+}
+
+impl UnsavedChunks {
+    /// Adds `chunks`, replacing any older versions of them that aren't written yet.
+    pub fn insert(
+        &self,
+        chunks: impl IntoIterator<Item = (NodeKey<IVec3>, ChunkSlot)>,
+    ) -> UnsavedBatch {
+        let mut shared = self.shared.chunks.lock();
+        let generation = shared.next_generation;
+        shared.next_generation += 1;
+        shared.num_pending_batches += 1;
+        let chunks: Vec<_> = chunks
+            .into_iter()
+            .map(|(key, slot)| (key, slot.map(Arc::new)))
+            .collect();
+        for (key, chunk) in chunks.iter() {
+            shared.chunks.insert(*key, (generation, chunk.clone()));
+        }
+        UnsavedBatch {
+            shared: Arc::clone(&self.shared),
+            generation,
+            chunks,
+        }
+    }
+
+    /// Like [`Self::insert`], for chunks that were taken out of the clipmap as changes.
+    pub fn insert_changes(
+        &self,
+        changes: impl IntoIterator<Item = (NodeKey<IVec3>, Change<Box<Chunk>>)>,
+    ) -> UnsavedBatch {
+        self.insert(changes.into_iter().map(|(key, change)| {
+            let slot = match change {
+                Change::Insert(chunk) => Some(Either::Left(chunk)),
+                Change::Remove => None,
+            };
+            (key, slot)
+        }))
+    }
+
+    /// A copy of the latest unsaved version of the chunk at `key`. Returns `None` if it must be read from the backend.
+    pub fn get(&self, key: NodeKey<IVec3>) -> Option<ChunkSlot> {
+        let shared = self.shared.chunks.lock();
+        let (_, chunk) = shared.chunks.get(&key)?;
+        Some(chunk.as_ref().map(|chunk| match &**chunk {
+            Either::Left(decompressed) => Either::Left(decompressed.clone()),
+            Either::Right(compressed) => Either::Right(compressed.clone()),
+        }))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shared.chunks.lock().chunks.is_empty()
+    }
+
+    /// True once every batch was either written or failed to be. Chunks whose write failed are still in the set, but they
+    /// aren't going to be written unless they're added again.
+    pub fn is_idle(&self) -> bool {
+        self.shared.chunks.lock().num_pending_batches == 0
+    }
+
+    /// Compresses the chunks of `batch` with `encoding` and writes them to `backend`, except for those that were replaced by
+    /// a newer batch. Once they're written, they're removed from the set. If the write fails, they stay readable.
+    ///
+    /// Empty chunks are written as [`UniformChunk::Air`] instead of being removed, since a chunk without an entry is
+    /// generated again the next time it's loaded.
+    ///
+    /// Returns the number of compressed bytes that were written.
+    pub fn write(
+        &self,
+        mut batch: UnsavedBatch,
+        encoding: ChunkEncoding,
+        backend: &dyn MapBackend,
+    ) -> Result<usize, BackendError> {
+        // The batch is pending until it's dropped at the end.
+        let generation = batch.generation;
+        let chunks = std::mem::take(&mut batch.chunks);
+        let _write_guard = self.shared.write_lock.lock();
+
+        let is_latest = |shared: &UnsavedChunkMap, key: NodeKey<IVec3>| {
+            shared
+                .chunks
+                .get(&key)
+                .map_or(false, |(g, _)| *g == generation)
+        };
+        let chunks: Vec<_> = {
+            let shared = self.shared.chunks.lock();
+            chunks
+                .into_iter()
+                .filter(|&(key, _)| is_latest(&shared, key))
+                .collect()
+        };
+        if chunks.is_empty() {
+            return Ok(0);
+        }
+
+        let codec = backend.codec();
+        let keys: Vec<_> = chunks.iter().map(|&(key, _)| key).collect();
+        let mut num_bytes = 0;
+        let changes = chunks
+            .into_iter()
+            .map(|(key, chunk)| {
+                let compressed = match chunk.as_deref() {
+                    Some(Either::Left(decompressed)) => decompressed.compress_as(encoding, codec),
+                    Some(Either::Right(compressed)) => {
+                        compressed.clone().recompress(encoding, codec)
+                    }
+                    None => UniformChunk::Air.compress(),
+                };
+                num_bytes += compressed.bytes.len();
+                (ChunkDbKey::from(key), Change::Insert(compressed))
+            })
+            .collect();
+        backend.write_chunks(changes)?;
+
+        // The main thread could have added newer versions while this batch was written, and those still have to be saved.
+        let mut shared = self.shared.chunks.lock();
+        for key in keys.into_iter() {
+            if is_latest(&shared, key) {
+                shared.chunks.remove(&key);
+            }
+        }
+        Ok(num_bytes)
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::MemoryBackend;
+    use crate::sdf::Sd8;
+
+    #[test]
+    fn older_batches_dont_overwrite_newer_chunks() {
+        let backend = MemoryBackend::new(Default::default());
+        let unsaved = UnsavedChunks::default();
+        let key = NodeKey::new(0, IVec3::ZERO);
+        let edited = |palette_id| {
+            let mut chunk = Chunk::default();
+            chunk.set_voxel(IVec3::ONE, palette_id, Sd8::MIN);
+            chunk
+        };
+        let stored = || match backend.read_chunk(key.into()).unwrap() {
+            Some(Change::Insert(compressed)) => compressed.decompress(),
+            other => panic!("Expected an inserted chunk, got {:?}", other),
+        };
+
+        // E.g. a flush that's still in flight when the chunk is edited again and evicted.
+        let flushed = unsaved.insert([(key, Some(Either::Left(Box::new(edited(1)))))]);
+        let evicted = unsaved.insert([(key, Some(Either::Left(Box::new(edited(2)))))]);
+        assert!(!unsaved.is_idle());
+        assert_eq!(
+            *unsaved.get(key).unwrap().unwrap().left().unwrap(),
+            edited(2)
+        );
+
+        assert!(
+            unsaved
+                .write(evicted, ChunkEncoding::default(), &backend)
+                .unwrap()
+                > 0
+        );
+        assert!(unsaved.is_empty());
+        assert!(!unsaved.is_idle());
+        assert_eq!(
+            unsaved
+                .write(flushed, ChunkEncoding::default(), &backend)
+                .unwrap(),
+            0
+        );
+        assert!(unsaved.is_idle());
+        assert_eq!(stored(), edited(2));
+
+        // In order, both are written, and the newer one stays.
+        let first = unsaved.insert([(key, Some(Either::Left(Box::new(edited(3)))))]);
+        let second = unsaved.insert([(key, Some(Either::Left(Box::new(edited(4)))))]);
+        assert_eq!(
+            unsaved
+                .write(first, ChunkEncoding::default(), &backend)
+                .unwrap(),
+            0
+        );
+        assert!(!unsaved.is_empty());
+        unsaved
+            .write(second, ChunkEncoding::default(), &backend)
+            .unwrap();
+        assert!(unsaved.is_empty());
+        assert_eq!(stored(), edited(4));
+    }
+}
@@ -0,0 +1,114 @@
+use crate::core::glam::IVec3;
+use crate::core::ilattice::prelude::Extent;
+use crate::units::{ChunkUnits, VoxelUnits};
+
+/// Identifies who queued an edit, so an [`EditValidator`] can check their permissions, and so a rejection can be matched to
+/// the edit that caused it.
+///
+/// Edits queued without a tag are [`EditTag::LOCAL`]. Other authors are up to the app, e.g. the ID of a network client, with
+/// a sequence number that the client chose.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct EditTag {
+    pub author: u64,
+    pub sequence: u64,
+}
+
+impl EditTag {
+    pub const LOCAL: Self = Self {
+        author: 0,
+        sequence: 0,
+    };
+
+    pub fn is_local(&self) -> bool {
+        self.author == Self::LOCAL.author
+    }
+}
+
+/// What an [`EditValidator`] knows about an edit.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct EditInfo {
+    pub tag: EditTag,
+    /// All voxels that could be changed by the edit.
+    pub extent: VoxelUnits<Extent<IVec3>>,
+}
+
+/// Decides whether queued [`MapEdits`](crate::MapEdits) are allowed, e.g. to enforce protected regions or per-player
+/// permissions on an authoritative server.
+///
+/// If an `Arc<dyn EditValidator>` resource exists, the `edit_system` calls it once for each edit, before the edit touches the
+/// clipmap. Rejected edits are dropped and reported with an [`EditRejected`] event, so the author can roll back any changes it
+/// predicted.
+pub trait EditValidator: Send + Sync {
+    /// Returns the reason for rejecting `edit`, if it's not allowed.
+    fn validate(&self, edit: &EditInfo) -> Result<(), String>;
+}
+
+/// Sent when an [`EditValidator`] rejects an edit, or when an edit by any author except [`EditTag::LOCAL`] is dropped because
+/// it's outside of the clipmap.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EditRejected {
+    pub edit: EditInfo,
+    pub reason: String,
+}
+
+/// Sent when an edit by any author except [`EditTag::LOCAL`] is written into a LOD0 chunk. Edits that touch multiple chunks
+/// send one event per chunk, in the same frame that the chunk's changes are added to the [`DirtyRegions`](crate::DirtyRegions).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct EditApplied {
+    pub tag: EditTag,
+    pub chunk: ChunkUnits<IVec3>,
+}
+
+/// Rejects edits by any author except [`EditTag::LOCAL`] that touch any of `extents`.
+#[derive(Clone, Debug, Default)]
+pub struct ProtectedRegions {
+    pub extents: Vec<VoxelUnits<Extent<IVec3>>>,
+}
+
+impl EditValidator for ProtectedRegions {
+    fn validate(&self, edit: &EditInfo) -> Result<(), String> {
+        if edit.tag.is_local() {
+            return Ok(());
+        }
+        let VoxelUnits(edit_extent) = edit.extent;
+        for &VoxelUnits(protected) in self.extents.iter() {
+            if !edit_extent.intersection(&protected).is_empty() {
+                return Err(format!("{:?} is protected", protected));
+            }
+        }
+        Ok(())
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protected_regions_reject_remote_edits() {
+        let validator = ProtectedRegions {
+            extents: vec![VoxelUnits(Extent::from_min_and_shape(
+                IVec3::ZERO,
+                IVec3::splat(10),
+            ))],
+        };
+        let edit = |author, min| EditInfo {
+            tag: EditTag {
+                author,
+                sequence: 7,
+            },
+            extent: VoxelUnits(Extent::from_min_and_shape(IVec3::splat(min), IVec3::ONE)),
+        };
+
+        assert!(validator.validate(&edit(0, 5)).is_ok());
+        assert!(validator.validate(&edit(3, 5)).is_err());
+        assert!(validator.validate(&edit(3, 10)).is_ok());
+    }
+}
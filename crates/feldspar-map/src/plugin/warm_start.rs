@@ -0,0 +1,170 @@
+use super::config::MapConfig;
+use super::history::MapHistory;
+use super::import::MapImports;
+use super::loader::{read_chunk_slot, ChunkSlot};
+use super::tasks::MapTask;
+use crate::clipmap::{ChunkClipMap, NodePtr, SlotState, VisitCommand};
+use crate::core::glam::IVec3;
+use crate::core::{SmallKeyHashMap, SmallKeyHashSet};
+use crate::database::{ChunkDbKey, MapBackend, MapDb};
+
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use grid_tree::NodeKey;
+use parking_lot::{Mutex, RwLock};
+use std::sync::Arc;
+
+/// Chunks from the load journal that were read ahead of time on startup, so the first loads around each witness don't have to
+/// wait on the database.
+///
+/// The load journal is the set of occupied nodes that were resident when the app last exited. The `loader_system` takes
+/// chunks out of here before it reads the database. Each chunk can only be taken once, since later loads could see newer
+/// versions in the database, and the whole set is dropped once the witnesses are fully loaded, or when the database moves to
+/// another version.
+#[derive(Clone)]
+pub struct WarmStart {
+    shared: Arc<Mutex<WarmStartChunks>>,
+}
+
+#[derive(Default)]
+struct WarmStartChunks {
+    chunks: SmallKeyHashMap<ChunkDbKey, ChunkSlot>,
+    /// Keys that were loaded from the database while the journal was still being read, so they must not be read ahead.
+    missed: SmallKeyHashSet<ChunkDbKey>,
+    is_reading: bool,
+    is_closed: bool,
+}
+
+impl WarmStart {
+    /// Nothing gets read ahead.
+    pub fn disabled() -> Self {
+        Self::new(WarmStartChunks {
+            is_closed: true,
+            ..Default::default()
+        })
+    }
+
+    /// Spawns a task that reads every chunk in the load journal of `db` from `backend`. If `deterministic`, the chunks are read
+    /// before this returns instead, so the first loads never race with the task.
+    pub fn read_journal(
+        db: Arc<RwLock<MapDb>>,
+        backend: Arc<dyn MapBackend>,
+        deterministic: bool,
+    ) -> Self {
+        let warm_start = Self::new(WarmStartChunks {
+            is_reading: true,
+            ..Default::default()
+        });
+
+        let task_warm_start = warm_start.clone();
+        MapTask::io(deterministic, async move {
+            let keys = match db.read().read_load_journal() {
+                Ok(keys) => keys,
+                Err(e) => {
+                    log::error!("Failed to read load journal: {:?}", e);
+                    task_warm_start.close();
+                    return;
+                }
+            };
+            log::info!("Warm starting with {} chunks", keys.len());
+
+            for key in keys.into_iter() {
+                // Failed reads are left to the loader.
+                let slot = read_chunk_slot(&*backend, key.into()).ok().flatten();
+                let mut shared = task_warm_start.shared.lock();
+                if shared.is_closed {
+                    return;
+                }
+                if let Some(slot) = slot {
+                    if !shared.missed.contains(&key) {
+                        shared.chunks.insert(key, slot);
+                    }
+                }
+            }
+
+            let mut shared = task_warm_start.shared.lock();
+            shared.is_reading = false;
+            shared.missed.clear();
+        })
+        .detach();
+
+        warm_start
+    }
+
+    fn new(chunks: WarmStartChunks) -> Self {
+        Self {
+            shared: Arc::new(Mutex::new(chunks)),
+        }
+    }
+
+    /// Takes the chunk at `key` if it was read ahead. Returns `None` if it must be read from the database instead.
+    pub(crate) fn take(&self, key: NodeKey<IVec3>) -> Option<ChunkSlot> {
+        let mut shared = self.shared.lock();
+        if shared.is_closed {
+            return None;
+        }
+        let key = ChunkDbKey::from(key);
+        let slot = shared.chunks.remove(&key);
+        if slot.is_none() && shared.is_reading {
+            shared.missed.insert(key);
+        }
+        slot
+    }
+
+    /// Drops all chunks that were read ahead, and stops reading the journal.
+    pub(crate) fn close(&self) {
+        let mut shared = self.shared.lock();
+        if !shared.is_closed {
+            *shared = WarmStartChunks {
+                is_closed: true,
+                ..Default::default()
+            };
+        }
+    }
+}
+
+/// Closes the [`WarmStart`] when the database moves to another version, and writes the load journal when the app exits.
+pub fn warm_start_system(
+    config: Res<MapConfig>,
+    db: Option<Res<Arc<RwLock<MapDb>>>>,
+    clipmap: Res<ChunkClipMap>,
+    history: Res<MapHistory>,
+    imports: Res<MapImports>,
+    warm_start: Res<WarmStart>,
+    mut exit_events: EventReader<AppExit>,
+) {
+    // Chunks that were read ahead could be older than those versions.
+    if history.is_busy() || imports.is_busy() {
+        warm_start.close();
+    }
+
+    let db = match db {
+        Some(db) if config.warm_start => db,
+        _ => return,
+    };
+    if exit_events.iter().next().is_none() {
+        return;
+    }
+
+    let mut keys = Vec::new();
+    for (root_key, root_node) in clipmap.octree.iter_roots() {
+        clipmap.octree.visit_tree_depth_first(
+            NodePtr::new(root_key.level, root_node.self_ptr),
+            root_key.coordinates,
+            0,
+            |ptr, coords| {
+                let state = clipmap.octree.get_value(ptr).unwrap().state();
+                if !state.is_loading() && state.slot_state() != SlotState::Empty {
+                    keys.push(ChunkDbKey::from(NodeKey::new(ptr.level(), coords)));
+                }
+                VisitCommand::Continue
+            },
+        );
+    }
+
+    if let Err(e) = db.read().write_load_journal(&keys) {
+        log::error!("Failed to write load journal: {:?}", e);
+    } else {
+        log::info!("Wrote load journal with {} chunks", keys.len());
+    }
+}
@@ -1,13 +1,301 @@
+use super::maps::{ActiveMap, MapId};
+use super::LoaderConfig;
+use crate::clipmap::{ChunkClipMap, ClipRegion, ClipShape, LoadObserver};
+use crate::core::geometry::Cone;
+use crate::units::VoxelUnits;
+use crate::world_transform::VoxelWorldTransform;
+
+use feldspar_core::glam::Vec3A;
+
 use bevy::prelude::*;
 
-/// An entity (usually a camera) that gets a clip sphere in the clipmap.
-#[derive(Component)]
+/// An entity (usually a camera) that gets a clip region in the clipmap.
+///
+/// Each witness can have its own [`ClipShape`], e.g. a map editor camera may need to see the whole map while AI observers only
+/// need the chunks right around them. Their [`WitnessPriority`] decides whose chunks come first when streaming can't keep up.
+#[derive(Component, Default)]
 pub struct Witness {
+    /// The shape of this witness's clip region. `None` is a sphere with the [`StreamingConfig::clip_sphere_radius`].
+    ///
+    /// [`StreamingConfig::clip_sphere_radius`]: crate::clipmap::StreamingConfig::clip_sphere_radius
+    pub clip_shape: Option<ClipShape>,
+    pub priority: WitnessPriority,
+    /// The map that this witness streams. Other maps don't load any chunks around it.
+    pub map: MapId,
     pub(crate) previous_transform: Option<Transform>,
+    pub(crate) previous_clip_shape: Option<ClipShape>,
 }
 
-pub fn witness_system(mut witness_transforms: Query<(&mut Witness, &Transform)>) {
+impl Witness {
+    pub fn with_clip_radius(self, clip_radius: VoxelUnits<f32>) -> Self {
+        let VoxelUnits(radius) = clip_radius;
+        self.with_clip_shape(ClipShape::Sphere { radius })
+    }
+
+    pub fn with_clip_shape(mut self, clip_shape: ClipShape) -> Self {
+        self.clip_shape = Some(clip_shape);
+        self
+    }
+
+    pub fn with_priority(mut self, priority: WitnessPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_map(mut self, map: MapId) -> Self {
+        self.map = map;
+        self
+    }
+
+    /// Whether this witness streams the map whose resources are in the `World`.
+    pub(crate) fn is_in(&self, active_map: &ActiveMap) -> bool {
+        self.map == active_map.0
+    }
+
+    /// The shape of this witness's clip region, falling back to a sphere of `default_radius` if it doesn't have its own.
+    pub fn clip_shape_or(&self, default_radius: VoxelUnits<f32>) -> ClipShape {
+        let VoxelUnits(radius) = default_radius;
+        self.clip_shape.unwrap_or(ClipShape::Sphere { radius })
+    }
+
+    /// The center and clip shape of this witness as of the previous frame.
+    pub(crate) fn previous_placed_clip_shape(
+        &self,
+        world_transform: &VoxelWorldTransform,
+    ) -> Option<(VoxelUnits<Vec3A>, ClipShape)> {
+        let prev_tfm = self.previous_transform.as_ref()?;
+        let center = world_transform.transform_to_voxel(prev_tfm);
+        Some((center, self.previous_clip_shape?))
+    }
+
+    /// The voxel positions of this witness in the previous and current frames, if it moved farther than
+    /// [`LoaderConfig::teleport_distance`] in between.
+    pub(crate) fn teleport(
+        &self,
+        tfm: &Transform,
+        world_transform: &VoxelWorldTransform,
+        config: &LoaderConfig,
+    ) -> Option<(VoxelUnits<Vec3A>, VoxelUnits<Vec3A>)> {
+        let VoxelUnits(teleport_distance) = config.teleport_distance;
+        if teleport_distance <= 0.0 {
+            return None;
+        }
+        let from = world_transform.transform_to_voxel(self.previous_transform.as_ref()?);
+        let to = world_transform.transform_to_voxel(tfm);
+        (from.0.distance(to.0) > teleport_distance).then(|| (from, to))
+    }
+
+    /// The direction that the witness moved since the previous frame, if it moved at all.
+    pub(crate) fn motion_direction(&self, tfm: &Transform) -> Option<Vec3A> {
+        let prev_tfm = self.previous_transform.as_ref()?;
+        // TODO: use .as_vec3a()
+        let delta = Vec3A::from((tfm.translation - prev_tfm.translation).to_array());
+        let length = delta.length();
+        (length > f32::EPSILON).then(|| delta / length)
+    }
+}
+
+/// Decides which [`Witness`] gets its chunks first when IO bandwidth or memory is constrained.
+///
+/// Loads are prioritized as if lower priority witnesses were farther away, by a factor of [`Self::distance_scale`]. When the
+/// decompressed chunks exceed the [`CacheConfig::max_resident_bytes`](super::CacheConfig::max_resident_bytes), chunks in the
+/// clip regions of higher priority witnesses are the last to be compressed.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum WitnessPriority {
+    /// An observer that only keeps the map loaded, like a server's observer of a distant client or an AI agent.
+    Background,
+    /// A camera that isn't controlled by a player, like a cutscene or minimap camera.
+    Camera,
+    Player,
+}
+
+impl Default for WitnessPriority {
+    fn default() -> Self {
+        Self::Player
+    }
+}
+
+impl WitnessPriority {
+    /// All priorities, from lowest to highest.
+    pub const ALL: [Self; 3] = [Self::Background, Self::Camera, Self::Player];
+
+    /// The factor applied to distances from a witness with this priority when ordering loads.
+    pub fn distance_scale(&self) -> f32 {
+        match self {
+            Self::Background => 16.0,
+            Self::Camera => 4.0,
+            Self::Player => 1.0,
+        }
+    }
+}
+
+/// The positions of all witnesses, plus the motion extrapolated from their previous transforms.
+#[derive(Default)]
+pub(crate) struct WitnessObservers {
+    pub observers: Vec<LoadObserver>,
+    /// The clip shape and priority of each witness, in the same order as `observers`.
+    pub clip_shapes: Vec<(ClipShape, WitnessPriority)>,
+    /// Where each moving witness will be after traveling `prefetch_distance` in its current direction.
+    pub predicted_observers: Vec<LoadObserver>,
+    /// Cones that extend past the clip shape of each moving witness, in its direction of motion.
+    pub prefetch_cones: Vec<VoxelUnits<Cone>>,
+    /// Whether any witness teleported since the previous frame. Teleported witnesses don't prefetch, since their motion
+    /// doesn't predict where they'll go next.
+    pub teleported: bool,
+}
+
+impl WitnessObservers {
+    /// Only the witnesses of `map` are observers.
+    pub fn new<'a>(
+        witness_transforms: impl Iterator<Item = (&'a Witness, &'a Transform)>,
+        map: MapId,
+        world_transform: &VoxelWorldTransform,
+        config: &LoaderConfig,
+        default_clip_radius: VoxelUnits<f32>,
+    ) -> Self {
+        let VoxelUnits(prefetch_distance) = config.prefetch_distance;
+
+        let mut observers = Self::default();
+        for (witness, tfm) in witness_transforms.filter(|(witness, _)| witness.map == map) {
+            let VoxelUnits(position) = world_transform.transform_to_voxel(tfm);
+            let clip_shape = witness.clip_shape_or(default_clip_radius);
+            let distance_scale = witness.priority.distance_scale();
+            observers
+                .observers
+                .push(LoadObserver::new(VoxelUnits(position), distance_scale));
+            observers.clip_shapes.push((clip_shape, witness.priority));
+
+            if witness.teleport(tfm, world_transform, config).is_some() {
+                observers.teleported = true;
+                continue;
+            }
+            if prefetch_distance <= 0.0 {
+                continue;
+            }
+            if let Some(direction) = witness.motion_direction(tfm) {
+                observers.predicted_observers.push(LoadObserver::new(
+                    VoxelUnits(position + prefetch_distance * direction),
+                    distance_scale,
+                ));
+                observers.prefetch_cones.push(VoxelUnits(Cone::new(
+                    position,
+                    direction,
+                    clip_shape.bounding_radius() + prefetch_distance,
+                    config.prefetch_cone_angle,
+                )));
+            }
+        }
+        observers
+    }
+
+    /// The clip shapes of all witnesses, extended by their prefetch cones.
+    pub fn clip_region(&self) -> ClipRegion {
+        ClipRegion::from_shapes(self.placed_clip_shapes(|_| true))
+            .with_prefetch_cones(self.prefetch_cones.iter().copied())
+    }
+
+    /// The clip shapes of the witnesses with each [`WitnessPriority`], from lowest to highest.
+    pub fn clip_regions_by_priority(&self) -> Vec<ClipRegion> {
+        WitnessPriority::ALL
+            .iter()
+            .map(|&priority| ClipRegion::from_shapes(self.placed_clip_shapes(|p| p == priority)))
+            .collect()
+    }
+
+    fn placed_clip_shapes(
+        &self,
+        filter: impl Fn(WitnessPriority) -> bool,
+    ) -> impl Iterator<Item = (VoxelUnits<Vec3A>, ClipShape)> + '_ {
+        self.observers
+            .iter()
+            .zip(self.clip_shapes.iter())
+            .filter(move |(_, (_, priority))| filter(*priority))
+            .map(|(observer, &(shape, _))| (observer.position, shape))
+    }
+
+    /// The current and predicted witness positions, which together determine the detail and ordering of the near phase load
+    /// search.
+    pub fn search_observers(&self) -> Vec<LoadObserver> {
+        self.observers
+            .iter()
+            .chain(self.predicted_observers.iter())
+            .copied()
+            .collect()
+    }
+}
+
+pub fn witness_system(
+    clipmap: Res<ChunkClipMap>,
+    active_map: Res<ActiveMap>,
+    mut witness_transforms: Query<(&mut Witness, &Transform)>,
+) {
+    let default_clip_radius = clipmap.stream_config.clip_sphere_radius;
     for (mut witness, transform) in witness_transforms.iter_mut() {
+        if !witness.is_in(&active_map) {
+            continue;
+        }
         witness.previous_transform = Some(transform.clone());
+        witness.previous_clip_shape = Some(witness.clip_shape_or(default_clip_radius));
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn teleported_witnesses_dont_prefetch() {
+        let config = LoaderConfig::default();
+        let world_transform = VoxelWorldTransform::default();
+        let witness = Witness {
+            previous_transform: Some(Transform::default()),
+            ..Default::default()
+        };
+        let observe = |tfm: &Transform| {
+            WitnessObservers::new(
+                [(&witness, tfm)].into_iter(),
+                MapId::PRIMARY,
+                &world_transform,
+                &config,
+                VoxelUnits(100.0),
+            )
+        };
+
+        let walked = Transform::from_xyz(10.0, 0.0, 0.0);
+        assert_eq!(witness.teleport(&walked, &world_transform, &config), None);
+        let observers = observe(&walked);
+        assert!(!observers.teleported);
+        assert_eq!(observers.predicted_observers.len(), 1);
+        assert_eq!(observers.prefetch_cones.len(), 1);
+
+        let teleported = Transform::from_xyz(10_000.0, 0.0, 0.0);
+        assert_eq!(
+            witness.teleport(&teleported, &world_transform, &config),
+            Some((
+                VoxelUnits(Vec3A::ZERO),
+                VoxelUnits(Vec3A::new(10_000.0, 0.0, 0.0))
+            ))
+        );
+        let observers = observe(&teleported);
+        assert!(observers.teleported);
+        assert_eq!(observers.observers.len(), 1);
+        assert!(observers.predicted_observers.is_empty());
+        assert!(observers.prefetch_cones.is_empty());
+
+        let no_teleports = LoaderConfig {
+            teleport_distance: VoxelUnits(0.0),
+            ..Default::default()
+        };
+        assert_eq!(
+            witness.teleport(&teleported, &world_transform, &no_teleports),
+            None
+        );
     }
 }
@@ -0,0 +1,231 @@
+//! Prefabricated structures, like trees, buildings, and dungeon pieces, that can be placed anywhere in the map.
+//!
+//! A [`VoxelStamp`] is a dense buffer of LOD0 voxels with an anchor. It can be placed directly with
+//! [`ChunkClipMap::stamp`](crate::clipmap::ChunkClipMap::stamp), or queued with [`MapEdits::stamp`](crate::MapEdits::stamp) so
+//! the edit goes through validation, history, and the database like any other brush.
+
+use crate::brush::Brush;
+use crate::clipmap::Voxel;
+use crate::core::glam::IVec3;
+use crate::core::ilattice::prelude::Extent;
+use crate::palette::PaletteId8;
+use crate::sdf::Sd8;
+use crate::units::VoxelUnits;
+
+use std::sync::Arc;
+
+/// How the voxels of a [`VoxelStamp`] are combined with the existing terrain.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StampBlendMode {
+    /// Every voxel of the stamp overwrites the terrain, including empty ones. Useful for building interiors that must be clear.
+    Replace,
+    /// The union of the stamp with the terrain. Empty space in the stamp keeps the existing terrain.
+    Add,
+    /// The solid voxels of the stamp are carved out of the terrain.
+    Subtract,
+}
+
+/// A box of LOD0 voxels that can be placed in the map.
+///
+/// The `anchor` is the voxel of the stamp (relative to its minimum) that is placed at the stamp position, e.g. the base of a
+/// tree's trunk.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VoxelStamp {
+    shape: IVec3,
+    pub anchor: IVec3,
+    voxels: Vec<Voxel>,
+}
+
+impl VoxelStamp {
+    /// A stamp of ambient voxels.
+    pub fn new(shape: IVec3, anchor: IVec3) -> Self {
+        Self::from_fn(shape, anchor, |_| Voxel::AMBIENT)
+    }
+
+    /// A stamp where the voxel at each offset from the minimum is `f(offset)`.
+    pub fn from_fn(shape: IVec3, anchor: IVec3, mut f: impl FnMut(IVec3) -> Voxel) -> Self {
+        assert!(
+            shape.cmpgt(IVec3::ZERO).all(),
+            "Stamp shape must be positive"
+        );
+        let mut voxels = Vec::with_capacity((shape.x * shape.y * shape.z) as usize);
+        for z in 0..shape.z {
+            for y in 0..shape.y {
+                for x in 0..shape.x {
+                    voxels.push(f(IVec3::new(x, y, z)));
+                }
+            }
+        }
+        Self {
+            shape,
+            anchor,
+            voxels,
+        }
+    }
+
+    pub fn shape(&self) -> IVec3 {
+        self.shape
+    }
+
+    /// `offset` is relative to the minimum of the stamp.
+    pub fn get(&self, offset: IVec3) -> Option<Voxel> {
+        self.index(offset).map(|i| self.voxels[i])
+    }
+
+    /// `offset` is relative to the minimum of the stamp.
+    pub fn get_mut(&mut self, offset: IVec3) -> Option<&mut Voxel> {
+        self.index(offset).map(|i| &mut self.voxels[i])
+    }
+
+    /// The LOD0 voxels covered by the stamp when its anchor is placed at `position`.
+    pub fn extent_at(&self, position: VoxelUnits<IVec3>) -> VoxelUnits<Extent<IVec3>> {
+        let VoxelUnits(position) = position;
+        VoxelUnits(Extent::from_min_and_shape(
+            position - self.anchor,
+            self.shape,
+        ))
+    }
+
+    /// Blends the stamp voxel at `offset` into `voxel` with `mode`. Offsets outside of the stamp are ignored.
+    pub fn blend(&self, offset: IVec3, mode: StampBlendMode, voxel: &mut Voxel) {
+        let stamp_voxel = match self.get(offset) {
+            Some(v) => v,
+            None => return,
+        };
+        match mode {
+            StampBlendMode::Replace => *voxel = stamp_voxel,
+            StampBlendMode::Add => {
+                if stamp_voxel.sdf.0 < voxel.sdf.0 {
+                    voxel.sdf = stamp_voxel.sdf;
+                    if stamp_voxel.sdf.0 < 0 {
+                        voxel.palette_id = stamp_voxel.palette_id;
+                    }
+                }
+            }
+            StampBlendMode::Subtract => {
+                voxel.sdf = Sd8(voxel.sdf.0.max(stamp_voxel.sdf.0.saturating_neg()));
+            }
+        }
+    }
+
+    fn index(&self, offset: IVec3) -> Option<usize> {
+        if offset.cmplt(IVec3::ZERO).any() || offset.cmpge(self.shape).any() {
+            return None;
+        }
+        Some((offset.x + self.shape.x * (offset.y + self.shape.y * offset.z)) as usize)
+    }
+}
+
+/// A [`VoxelStamp`] placed at a position, so it can be applied like any other [`Brush`].
+///
+/// The stamp is shared, since the same structure is usually placed many times.
+#[derive(Clone, Debug)]
+pub struct PlacedStamp {
+    pub stamp: Arc<VoxelStamp>,
+    /// Where the stamp's anchor is placed.
+    pub position: VoxelUnits<IVec3>,
+    pub mode: StampBlendMode,
+}
+
+impl Brush for PlacedStamp {
+    fn extent(&self) -> VoxelUnits<Extent<IVec3>> {
+        self.stamp.extent_at(self.position)
+    }
+
+    fn paint(&self, p: VoxelUnits<IVec3>, sdf: &mut Sd8, palette_id: &mut PaletteId8) {
+        let VoxelUnits(extent) = self.extent();
+        let mut voxel = Voxel {
+            sdf: *sdf,
+            palette_id: *palette_id,
+        };
+        self.stamp
+            .blend(p.0 - extent.minimum, self.mode, &mut voxel);
+        *sdf = voxel.sdf;
+        *palette_id = voxel.palette_id;
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOLID: Voxel = Voxel {
+        sdf: Sd8::MIN,
+        palette_id: 7,
+    };
+
+    #[test]
+    fn blend_modes() {
+        // A single solid voxel on top of a column of air.
+        let stamp = VoxelStamp::from_fn(IVec3::new(1, 2, 1), IVec3::ZERO, |offset| {
+            if offset.y == 1 {
+                SOLID
+            } else {
+                Voxel::AMBIENT
+            }
+        });
+        let ground = Voxel {
+            sdf: Sd8::MIN,
+            palette_id: 1,
+        };
+
+        let mut voxel = ground;
+        stamp.blend(IVec3::ZERO, StampBlendMode::Replace, &mut voxel);
+        assert_eq!(voxel, Voxel::AMBIENT);
+
+        let mut voxel = ground;
+        stamp.blend(IVec3::ZERO, StampBlendMode::Add, &mut voxel);
+        assert_eq!(voxel, ground);
+        let mut voxel = Voxel::AMBIENT;
+        stamp.blend(IVec3::Y, StampBlendMode::Add, &mut voxel);
+        assert_eq!(voxel, SOLID);
+
+        let mut voxel = ground;
+        stamp.blend(IVec3::Y, StampBlendMode::Subtract, &mut voxel);
+        assert_eq!(voxel.sdf, Sd8::MAX);
+        let mut voxel = ground;
+        stamp.blend(IVec3::ZERO, StampBlendMode::Subtract, &mut voxel);
+        assert_eq!(voxel, ground);
+
+        // Even a distance below `Sd8::MIN` can't overflow when it's negated.
+        let stamp = VoxelStamp::from_fn(IVec3::ONE, IVec3::ZERO, |_| Voxel {
+            sdf: Sd8(i8::MIN),
+            palette_id: 7,
+        });
+        let mut voxel = ground;
+        stamp.blend(IVec3::ZERO, StampBlendMode::Subtract, &mut voxel);
+        assert_eq!(voxel.sdf, Sd8::MAX);
+    }
+
+    #[test]
+    fn placed_stamp_is_anchored() {
+        let stamp = Arc::new(VoxelStamp::from_fn(
+            IVec3::splat(3),
+            IVec3::new(1, 0, 1),
+            |_| SOLID,
+        ));
+        let placed = PlacedStamp {
+            stamp,
+            position: VoxelUnits(IVec3::new(10, 20, 30)),
+            mode: StampBlendMode::Add,
+        };
+        let VoxelUnits(extent) = placed.extent();
+        assert_eq!(extent.minimum, IVec3::new(9, 20, 29));
+        assert_eq!(extent.shape, IVec3::splat(3));
+
+        let (mut sdf, mut palette_id) = (Sd8::MAX, 0);
+        placed.paint(
+            VoxelUnits(IVec3::new(11, 22, 31)),
+            &mut sdf,
+            &mut palette_id,
+        );
+        assert_eq!((sdf, palette_id), (Sd8::MIN, 7));
+    }
+}
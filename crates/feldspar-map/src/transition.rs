@@ -0,0 +1,100 @@
+//! Helpers for meshing chunks that border a coarser level of detail.
+//!
+//! When a chunk is rendered next to a chunk at half its resolution, the finer chunk has samples on the shared face that the
+//! coarser chunk can't see. Any isosurface that the fine samples introduce between the coarse samples opens a crack in the
+//! seam. Like the transition cells of Transvoxel, we constrain the fine side of the seam to the coarse side: every fine sample
+//! on the shared face is replaced by interpolating the samples that coincide with the coarse grid. Surface crossings along the
+//! face then happen at the same positions on both sides.
+
+use crate::chunk::{PaddedChunkShape, PADDED_CHUNK_SIZE};
+use crate::clipmap::Face;
+
+use ndshape::ConstShape;
+
+/// Resamples the face plane of a fine chunk's padded SDF so that it matches the coarser neighbor across `face`.
+///
+/// `padded_sdf` is laid out in [`PaddedChunkShape`], where the chunk's voxels start at `[0, 0, 0]` and the positive neighbors
+/// fill the last 2 layers. The face plane is the layer of samples shared with the neighbor, i.e. index `16` on the positive side
+/// or `0` on the negative side.
+pub fn resample_transition_face(padded_sdf: &mut [f32; PADDED_CHUNK_SIZE], face: Face) {
+    let axis = face.axis();
+    let plane = if face.is_positive() {
+        PaddedChunkShape::ARRAY[axis] - 2
+    } else {
+        0
+    };
+    let [u_axis, v_axis] = match axis {
+        0 => [1, 2],
+        1 => [0, 2],
+        _ => [0, 1],
+    };
+    let index = |u: i32, v: i32| {
+        let mut p = [0; 3];
+        p[axis] = plane;
+        p[u_axis] = u;
+        p[v_axis] = v;
+        PaddedChunkShape::linearize(p) as usize
+    };
+
+    // Chunks are aligned to the coarse grid, so coarse samples are on even coordinates. The last layer of padding has no coarse
+    // sample beyond it, so it's left alone. First fill in the fine samples that lie on a coarse edge.
+    let last = PaddedChunkShape::ARRAY[u_axis].min(PaddedChunkShape::ARRAY[v_axis]) - 2;
+    for v in (0..=last).step_by(2) {
+        for u in (1..last).step_by(2) {
+            padded_sdf[index(u, v)] =
+                0.5 * (padded_sdf[index(u - 1, v)] + padded_sdf[index(u + 1, v)]);
+        }
+    }
+    for u in (0..=last).step_by(2) {
+        for v in (1..last).step_by(2) {
+            padded_sdf[index(u, v)] =
+                0.5 * (padded_sdf[index(u, v - 1)] + padded_sdf[index(u, v + 1)]);
+        }
+    }
+    // Then the samples in the middle of each coarse face cell.
+    for v in (1..last).step_by(2) {
+        for u in (1..last).step_by(2) {
+            padded_sdf[index(u, v)] = 0.25
+                * (padded_sdf[index(u - 1, v - 1)]
+                    + padded_sdf[index(u + 1, v - 1)]
+                    + padded_sdf[index(u - 1, v + 1)]
+                    + padded_sdf[index(u + 1, v + 1)]);
+        }
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transition_face_is_bilinear_in_coarse_samples() {
+        // Fill the face plane with noise on fine samples, and a linear function on coarse samples.
+        let mut sdf = [0.0; PADDED_CHUNK_SIZE];
+        for i in 0..PaddedChunkShape::SIZE {
+            let [x, y, z] = PaddedChunkShape::delinearize(i);
+            sdf[i as usize] = if y % 2 == 0 && z % 2 == 0 {
+                (y + 2 * z) as f32
+            } else {
+                ((x * 31 + y * 17 + z * 7) % 5) as f32
+            };
+        }
+
+        resample_transition_face(&mut sdf, Face::PosX);
+
+        // The face plane is now linear everywhere between coarse samples.
+        for z in 0..=16 {
+            for y in 0..=16 {
+                let i = PaddedChunkShape::linearize([16, y, z]) as usize;
+                assert_eq!(sdf[i], (y + 2 * z) as f32, "y = {} z = {}", y, z);
+            }
+        }
+    }
+}
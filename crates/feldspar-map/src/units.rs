@@ -1,3 +1,8 @@
+use crate::chunk::{CHUNK_SHAPE_IVEC3, CHUNK_SHAPE_LOG2_IVEC3};
+use crate::core::geometry::Sphere;
+use crate::core::glam::{IVec3, Vec3A};
+use crate::core::ilattice::prelude::Extent;
+
 macro_rules! def_units {
     ($t:ident, $docstr:expr) => {
         #[doc = $docstr]
@@ -28,3 +33,244 @@ def_units!(
     ChunkUnits,
     "Denotes that the inner `T` is given in units of chunks. LOD is left unspecified."
 );
+def_units!(
+    WorldUnits,
+    "Denotes that the inner `T` is given in the units of the world that entities move through. See \
+     [`VoxelWorldTransform`](crate::world_transform::VoxelWorldTransform)."
+);
+
+/// Methods shared by [`VoxelUnits`] and [`ChunkUnits`] extents, so both can be compared without unwrapping them.
+macro_rules! impl_extent_units {
+    ($t:ident) => {
+        impl $t<Extent<IVec3>> {
+            pub fn intersection(&self, other: &Self) -> Self {
+                $t(self.0.intersection(&other.0))
+            }
+
+            /// Returns `true` if the extents share at least one point.
+            pub fn intersects(&self, other: &Self) -> bool {
+                !self.0.intersection(&other.0).is_empty()
+            }
+
+            pub fn contains_point(&self, p: $t<IVec3>) -> bool {
+                self.0.contains(p.0)
+            }
+
+            /// Returns `true` if every point of `other` is in this extent. Empty extents are contained by every extent.
+            pub fn contains_extent(&self, other: &Self) -> bool {
+                other.0.is_empty()
+                    || (self.0.minimum.cmple(other.0.minimum).all()
+                        && other
+                            .0
+                            .least_upper_bound()
+                            .cmple(self.0.least_upper_bound())
+                            .all())
+            }
+
+            /// Grows the extent by `pad` on every side.
+            pub fn padded(&self, pad: i32) -> Self {
+                $t(Extent::from_min_and_lub(
+                    self.0.minimum - pad,
+                    self.0.least_upper_bound() + pad,
+                ))
+            }
+        }
+    };
+}
+
+impl_extent_units!(VoxelUnits);
+impl_extent_units!(ChunkUnits);
+
+impl VoxelUnits<IVec3> {
+    /// The coordinates of the chunk that contains this voxel.
+    pub fn floor_chunk(self) -> ChunkUnits<IVec3> {
+        ChunkUnits(self.0 >> CHUNK_SHAPE_LOG2_IVEC3)
+    }
+
+    /// The coordinates of the first chunk whose minimum is at or above this voxel on every axis.
+    pub fn ceil_chunk(self) -> ChunkUnits<IVec3> {
+        ChunkUnits((self.0 + CHUNK_SHAPE_IVEC3 - 1) >> CHUNK_SHAPE_LOG2_IVEC3)
+    }
+}
+
+impl VoxelUnits<Vec3A> {
+    /// The coordinates of the voxel that contains this point.
+    pub fn floor_voxel(self) -> VoxelUnits<IVec3> {
+        VoxelUnits(self.0.floor().as_ivec3())
+    }
+
+    /// The coordinates of the chunk that contains this point.
+    pub fn floor_chunk(self) -> ChunkUnits<IVec3> {
+        self.floor_voxel().floor_chunk()
+    }
+}
+
+impl ChunkUnits<IVec3> {
+    /// The first voxel of this chunk.
+    pub fn min_voxel(self) -> VoxelUnits<IVec3> {
+        VoxelUnits(self.0 << CHUNK_SHAPE_LOG2_IVEC3)
+    }
+
+    pub fn voxel_extent(self) -> VoxelUnits<Extent<IVec3>> {
+        VoxelUnits(Extent::from_min_and_shape(
+            self.min_voxel().0,
+            CHUNK_SHAPE_IVEC3,
+        ))
+    }
+
+    /// The chunks within `radius` chunks of this one, measured along each axis.
+    pub fn extent_around(self, radius: i32) -> ChunkUnits<Extent<IVec3>> {
+        ChunkUnits(Extent::from_min_and_shape(
+            self.0 - radius,
+            IVec3::splat(2 * radius + 1),
+        ))
+    }
+}
+
+impl VoxelUnits<Extent<IVec3>> {
+    /// The chunks that share at least one voxel with this extent.
+    pub fn touched_chunks(&self) -> ChunkUnits<Extent<IVec3>> {
+        if self.0.is_empty() {
+            return ChunkUnits(Extent::from_min_and_shape(
+                self.floor_min_chunk(),
+                IVec3::ZERO,
+            ));
+        }
+        ChunkUnits(Extent::from_min_and_max(
+            self.floor_min_chunk(),
+            VoxelUnits(self.0.max()).floor_chunk().0,
+        ))
+    }
+
+    /// The chunks whose voxels are all in this extent.
+    pub fn covered_chunks(&self) -> ChunkUnits<Extent<IVec3>> {
+        let ChunkUnits(min) = VoxelUnits(self.0.minimum).ceil_chunk();
+        let ChunkUnits(lub) = VoxelUnits(self.0.least_upper_bound()).floor_chunk();
+        ChunkUnits(Extent::from_min_and_lub(min, lub.max(min)))
+    }
+
+    fn floor_min_chunk(&self) -> IVec3 {
+        VoxelUnits(self.0.minimum).floor_chunk().0
+    }
+}
+
+impl ChunkUnits<Extent<IVec3>> {
+    /// The voxels of all chunks in this extent.
+    pub fn voxel_extent(&self) -> VoxelUnits<Extent<IVec3>> {
+        VoxelUnits(Extent::from_min_and_shape(
+            self.0.minimum << CHUNK_SHAPE_LOG2_IVEC3,
+            self.0.shape << CHUNK_SHAPE_LOG2_IVEC3,
+        ))
+    }
+}
+
+impl VoxelUnits<Sphere> {
+    pub fn aabb(&self) -> VoxelUnits<Extent<Vec3A>> {
+        VoxelUnits(self.0.aabb())
+    }
+
+    /// The voxels that the sphere's [`aabb`](Self::aabb) overlaps.
+    pub fn voxel_extent(&self) -> VoxelUnits<Extent<IVec3>> {
+        VoxelUnits(self.0.aabb().containing_integer_extent())
+    }
+
+    pub fn contains_point(&self, p: VoxelUnits<Vec3A>) -> bool {
+        self.0.center.distance_squared(p.0) <= self.0.radius * self.0.radius
+    }
+
+    /// Returns `true` if the closest point of `extent` to the center is in the sphere.
+    pub fn intersects_extent(&self, extent: &VoxelUnits<Extent<Vec3A>>) -> bool {
+        let VoxelUnits(extent) = extent;
+        let closest = self
+            .0
+            .center
+            .clamp(extent.minimum, extent.least_upper_bound());
+        self.contains_point(VoxelUnits(closest))
+    }
+
+    /// Returns `true` if the farthest point of `extent` from the center is in the sphere.
+    pub fn contains_extent(&self, extent: &VoxelUnits<Extent<Vec3A>>) -> bool {
+        let VoxelUnits(extent) = extent;
+        let to_min = (extent.minimum - self.0.center).abs();
+        let to_lub = (extent.least_upper_bound() - self.0.center).abs();
+        let farthest = self.0.center + to_min.max(to_lub);
+        self.contains_point(VoxelUnits(farthest))
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chunk_conversions_round_down_and_up() {
+        assert_eq!(
+            VoxelUnits(IVec3::new(-1, 0, 17)).floor_chunk(),
+            ChunkUnits(IVec3::new(-1, 0, 1))
+        );
+        assert_eq!(
+            VoxelUnits(IVec3::new(-1, 0, 17)).ceil_chunk(),
+            ChunkUnits(IVec3::new(0, 0, 2))
+        );
+        assert_eq!(
+            VoxelUnits(Vec3A::new(-0.5, 15.9, 16.0)).floor_chunk(),
+            ChunkUnits(IVec3::new(-1, 0, 1))
+        );
+
+        let extent = VoxelUnits(Extent::from_min_and_lub(
+            IVec3::new(-1, 0, 0),
+            IVec3::new(33, 16, 15),
+        ));
+        assert_eq!(
+            extent.touched_chunks(),
+            ChunkUnits(Extent::from_min_and_lub(
+                IVec3::new(-1, 0, 0),
+                IVec3::new(3, 1, 1)
+            ))
+        );
+        // Only the X axis has whole chunks in it.
+        let covered = extent.covered_chunks();
+        assert_eq!(covered.0.minimum, IVec3::ZERO);
+        assert_eq!(covered.0.shape, IVec3::new(2, 1, 0));
+        assert!(extent.contains_extent(
+            &ChunkUnits(IVec3::new(1, 0, 0))
+                .voxel_extent()
+                .intersection(&extent)
+        ));
+        assert_eq!(
+            ChunkUnits(Extent::from_min_and_shape(IVec3::ONE, IVec3::ONE)).voxel_extent(),
+            ChunkUnits(IVec3::ONE).voxel_extent()
+        );
+    }
+
+    #[test]
+    fn extent_and_sphere_tests() {
+        let a = ChunkUnits(Extent::from_min_and_shape(IVec3::ZERO, IVec3::splat(2)));
+        let b = ChunkUnits(IVec3::splat(2)).extent_around(0);
+        assert!(!a.intersects(&b));
+        assert!(a.padded(1).intersects(&b));
+        assert!(a.padded(1).contains_extent(&a));
+        assert!(!a.contains_extent(&a.padded(1)));
+        assert!(a.contains_point(ChunkUnits(IVec3::ONE)));
+
+        let sphere = VoxelUnits(Sphere::new(Vec3A::ZERO, 2.0));
+        let near = VoxelUnits(Extent::from_min_and_shape(
+            Vec3A::new(1.0, 1.0, 0.0),
+            Vec3A::ONE,
+        ));
+        let far = VoxelUnits(Extent::from_min_and_shape(Vec3A::splat(1.5), Vec3A::ONE));
+        assert!(sphere.intersects_extent(&near));
+        assert!(!sphere.intersects_extent(&far));
+        assert!(!sphere.contains_extent(&near));
+        let inside = VoxelUnits(Extent::from_min_and_shape(Vec3A::splat(-1.0), Vec3A::ONE));
+        assert!(sphere.contains_extent(&inside));
+        assert!(sphere.voxel_extent().0.contains(IVec3::splat(-2)));
+    }
+}
@@ -2,19 +2,121 @@ use crate::core::SmallKeyHashMap;
 use crate::core::glam::IVec3;
 use crate::chunk::Chunk;
 use crate::coordinates::*;
+use crate::palette::PaletteId8;
 use crate::sdf::Sd8;
 use crate::units::*;
 
+use std::collections::hash_map::Entry;
 use vox_format::types::{ColorIndex, Model, Voxel};
 
+/// Maps MagicaVoxel color indices to [`PaletteId8`]s. The default palette maps each color index to the same palette ID.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct VoxPalette {
+    pub palette_ids: [PaletteId8; 256],
+}
+
+impl Default for VoxPalette {
+    fn default() -> Self {
+        let mut palette_ids = [0; 256];
+        for (i, id) in palette_ids.iter_mut().enumerate() {
+            *id = i as PaletteId8;
+        }
+        Self { palette_ids }
+    }
+}
+
+impl VoxPalette {
+    pub fn get(&self, color_index: u8) -> PaletteId8 {
+        self.palette_ids[color_index as usize]
+    }
+
+    pub fn set(&mut self, color_index: u8, palette_id: PaletteId8) {
+        self.palette_ids[color_index as usize] = palette_id;
+    }
+}
+
 pub fn convert_vox_model_to_chunks(model: &Model) -> SmallKeyHashMap<ChunkUnits<IVec3>, Chunk> {
+    place_vox_model_in_chunks(
+        model,
+        VoxelUnits(IVec3::ZERO),
+        &VoxPalette::default(),
+        |_| Ok::<_, ()>(Chunk::default()),
+    )
+    .unwrap()
+}
+
+/// Writes every voxel of `model` as a solid voxel, i.e. with a negative [`Sd8::MIN`] distance, translated by `offset`, into
+/// the chunks that contain it.
+///
+/// Each chunk starts out as the value returned by `background`, so the voxels around the model can be kept.
+pub fn place_vox_model_in_chunks<E>(
+    model: &Model,
+    offset: VoxelUnits<IVec3>,
+    palette: &VoxPalette,
+    mut background: impl FnMut(ChunkUnits<IVec3>) -> Result<Chunk, E>,
+) -> Result<SmallKeyHashMap<ChunkUnits<IVec3>, Chunk>, E> {
+    let VoxelUnits(offset) = offset;
     let mut chunks = SmallKeyHashMap::default();
-    for Voxel { point: p, color_index: ColorIndex(palette_id) } in model.voxels.iter() {
-        let p = IVec3::new(p.x.into(), p.y.into(), p.z.into());
+    for Voxel {
+        point: p,
+        color_index: ColorIndex(color_index),
+    } in model.voxels.iter()
+    {
+        let p = offset + IVec3::new(p.x.into(), p.y.into(), p.z.into());
         let chunk_coords = in_chunk(VoxelUnits(p));
-        let chunk = chunks.entry(chunk_coords).or_insert_with(Chunk::default);
+        let chunk = match chunks.entry(chunk_coords) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(background(chunk_coords)?),
+        };
         let VoxelUnits(chunk_min) = chunk_min(chunk_coords);
-        chunk.set_voxel(p - chunk_min, *palette_id, Sd8::MAX);
+        chunk.set_voxel(p - chunk_min, palette.get(*color_index), Sd8::MIN);
+    }
+    Ok(chunks)
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::ChunkShape;
+
+    use ndshape::ConstShape;
+    use vox_format::types::Vector;
+
+    #[test]
+    fn imported_voxels_are_solid() {
+        let model = Model {
+            size: Vector { x: 2, y: 1, z: 1 },
+            voxels: vec![Voxel {
+                point: Vector { x: 1, y: 0, z: 0 },
+                color_index: ColorIndex(5),
+            }],
+        };
+        let mut palette = VoxPalette::default();
+        palette.set(5, 9);
+
+        // The voxel lands on the far side of a chunk boundary.
+        let offset = VoxelUnits(IVec3::new(15, 0, 0));
+        let chunks =
+            place_vox_model_in_chunks(&model, offset, &palette, |_| Ok::<_, ()>(Chunk::default()))
+                .unwrap();
+        assert_eq!(chunks.len(), 1);
+        let chunk = &chunks[&ChunkUnits(IVec3::new(1, 0, 0))];
+        let index = ChunkShape::linearize([0, 0, 0]) as usize;
+        assert!(chunk.sdf[index].0 < 0);
+        assert_eq!(chunk.palette_ids[index], 9);
+
+        // Everything else is left as the background.
+        let ambient = Chunk::default();
+        let num_changed = (0..ChunkShape::SIZE as usize)
+            .filter(|&i| chunk.sdf[i] != ambient.sdf[i])
+            .count();
+        assert_eq!(num_changed, 1);
     }
-    chunks
 }
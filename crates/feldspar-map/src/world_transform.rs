@@ -0,0 +1,262 @@
+//! Placing the voxel grid in the world.
+//!
+//! The [`ChunkClipMap`] and everything stored in it use [`VoxelUnits`]. A [`VoxelWorldTransform`] says where those voxels are
+//! in the world that entities move through, so a map doesn't have to be one voxel per world unit at the world origin.
+//! Positions and lengths given in either [`WorldUnits`] or [`VoxelUnits`] can be converted with [`ToVoxelUnits`], e.g. to
+//! build a [`Brush`](crate::brush::Brush) around a point that the player clicked on.
+
+use crate::clipmap::{ChunkClipMap, RayHit, SweepHit};
+use crate::core::geometry::{Capsule, Sphere};
+use crate::core::glam::{IVec3, Vec3A};
+use crate::units::{VoxelUnits, WorldUnits};
+
+/// Where the voxel grid is placed in the world: the world position of a voxel point `p` is `origin + scale * p`.
+///
+/// The plugin reads the `VoxelWorldTransform` resource to find the voxel position of each
+/// [`Witness`](crate::Witness), and places the entities that it spawns for chunks with it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VoxelWorldTransform {
+    /// The world position of the voxel point at zero.
+    pub origin: WorldUnits<Vec3A>,
+    /// The length of one voxel edge, in world units. Must be positive.
+    pub scale: f32,
+}
+
+impl Default for VoxelWorldTransform {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+impl VoxelWorldTransform {
+    /// One voxel per world unit, with the grids aligned at the origin.
+    pub const IDENTITY: Self = Self {
+        origin: WorldUnits(Vec3A::ZERO),
+        scale: 1.0,
+    };
+
+    pub fn new(origin: WorldUnits<Vec3A>, scale: f32) -> Self {
+        assert!(scale > 0.0, "Voxel scale must be positive");
+        Self { origin, scale }
+    }
+
+    pub fn world_to_voxel(&self, p: WorldUnits<Vec3A>) -> VoxelUnits<Vec3A> {
+        VoxelUnits((p.0 - self.origin.0) / self.scale)
+    }
+
+    pub fn voxel_to_world(&self, p: VoxelUnits<Vec3A>) -> WorldUnits<Vec3A> {
+        WorldUnits(self.origin.0 + self.scale * p.0)
+    }
+
+    pub fn world_to_voxel_length(&self, length: WorldUnits<f32>) -> VoxelUnits<f32> {
+        VoxelUnits(length.0 / self.scale)
+    }
+
+    pub fn voxel_to_world_length(&self, length: VoxelUnits<f32>) -> WorldUnits<f32> {
+        WorldUnits(self.scale * length.0)
+    }
+
+    /// The world position of the minimum corner of the LOD0 voxel `p`.
+    pub fn voxel_min_to_world(&self, p: VoxelUnits<IVec3>) -> WorldUnits<Vec3A> {
+        self.voxel_to_world(p.map(|p| p.as_vec3a()))
+    }
+
+    /// The voxel position of an entity's [`Transform`](bevy::prelude::Transform).
+    #[cfg(feature = "bevy_plugin")]
+    pub fn transform_to_voxel(&self, tfm: &bevy::prelude::Transform) -> VoxelUnits<Vec3A> {
+        // TODO: use .as_vec3a()
+        self.world_to_voxel(WorldUnits(Vec3A::from(tfm.translation.to_array())))
+    }
+
+    /// A [`Transform`](bevy::prelude::Transform) that places an entity's local voxel space at the LOD0 voxel `min`, e.g. for
+    /// the mesh or collider of the chunk that starts there.
+    #[cfg(feature = "bevy_plugin")]
+    pub fn voxel_local_transform(&self, min: VoxelUnits<IVec3>) -> bevy::prelude::Transform {
+        let WorldUnits(translation) = self.voxel_min_to_world(min);
+        // TODO: use .into() once bevy and feldspar-core share a glam version
+        bevy::prelude::Transform::from_translation(bevy::prelude::Vec3::from(
+            translation.to_array(),
+        ))
+        .with_scale(bevy::prelude::Vec3::splat(self.scale))
+    }
+}
+
+/// A value in either [`WorldUnits`] or [`VoxelUnits`] that can be converted to [`VoxelUnits`] with a
+/// [`VoxelWorldTransform`]. Directions and other unitless values don't change.
+pub trait ToVoxelUnits<T> {
+    fn to_voxel_units(self, transform: &VoxelWorldTransform) -> VoxelUnits<T>;
+
+    /// Converts a `length` that is given in the same units as `Self`.
+    fn length_to_voxels(transform: &VoxelWorldTransform, length: f32) -> f32;
+}
+
+impl<T> ToVoxelUnits<T> for VoxelUnits<T> {
+    fn to_voxel_units(self, _transform: &VoxelWorldTransform) -> VoxelUnits<T> {
+        self
+    }
+
+    fn length_to_voxels(_transform: &VoxelWorldTransform, length: f32) -> f32 {
+        length
+    }
+}
+
+impl ToVoxelUnits<Vec3A> for WorldUnits<Vec3A> {
+    fn to_voxel_units(self, transform: &VoxelWorldTransform) -> VoxelUnits<Vec3A> {
+        transform.world_to_voxel(self)
+    }
+
+    fn length_to_voxels(transform: &VoxelWorldTransform, length: f32) -> f32 {
+        length / transform.scale
+    }
+}
+
+impl ToVoxelUnits<f32> for WorldUnits<f32> {
+    fn to_voxel_units(self, transform: &VoxelWorldTransform) -> VoxelUnits<f32> {
+        transform.world_to_voxel_length(self)
+    }
+
+    fn length_to_voxels(transform: &VoxelWorldTransform, length: f32) -> f32 {
+        length / transform.scale
+    }
+}
+
+impl ToVoxelUnits<Sphere> for WorldUnits<Sphere> {
+    fn to_voxel_units(self, transform: &VoxelWorldTransform) -> VoxelUnits<Sphere> {
+        let WorldUnits(sphere) = self;
+        let VoxelUnits(center) = transform.world_to_voxel(WorldUnits(sphere.center));
+        VoxelUnits(Sphere::new(center, sphere.radius / transform.scale))
+    }
+
+    fn length_to_voxels(transform: &VoxelWorldTransform, length: f32) -> f32 {
+        length / transform.scale
+    }
+}
+
+impl ToVoxelUnits<Capsule> for WorldUnits<Capsule> {
+    fn to_voxel_units(self, transform: &VoxelWorldTransform) -> VoxelUnits<Capsule> {
+        let WorldUnits(capsule) = self;
+        let VoxelUnits(a) = transform.world_to_voxel(WorldUnits(capsule.a));
+        let VoxelUnits(b) = transform.world_to_voxel(WorldUnits(capsule.b));
+        VoxelUnits(Capsule::new(a, b, capsule.radius / transform.scale))
+    }
+
+    fn length_to_voxels(transform: &VoxelWorldTransform, length: f32) -> f32 {
+        length / transform.scale
+    }
+}
+
+impl ChunkClipMap {
+    /// Same as [`Self::cast_ray`], but `origin` can be given in [`WorldUnits`], which are placed on the voxel grid by
+    /// `transform`. `max_t` is in the same units as `origin`, and so is the `t` of the hit. The rest of the hit is in
+    /// [`VoxelUnits`].
+    pub fn cast_ray_in<P: ToVoxelUnits<Vec3A>>(
+        &self,
+        transform: &VoxelWorldTransform,
+        origin: P,
+        dir: Vec3A,
+        max_t: f32,
+    ) -> Option<RayHit> {
+        let max_t = P::length_to_voxels(transform, max_t);
+        let voxels_per_unit = P::length_to_voxels(transform, 1.0);
+        self.cast_ray(origin.to_voxel_units(transform), dir, max_t)
+            .map(|hit| RayHit {
+                t: hit.t / voxels_per_unit,
+                ..hit
+            })
+    }
+
+    /// Same as [`Self::cast_sphere`], but `sphere` can be given in [`WorldUnits`]. Like [`Self::cast_ray_in`], `max_t` and
+    /// the time of impact are in the same units as `sphere`.
+    pub fn cast_sphere_in<S: ToVoxelUnits<Sphere>>(
+        &self,
+        transform: &VoxelWorldTransform,
+        sphere: S,
+        dir: Vec3A,
+        max_t: f32,
+    ) -> Option<SweepHit> {
+        let max_t = S::length_to_voxels(transform, max_t);
+        let voxels_per_unit = S::length_to_voxels(transform, 1.0);
+        self.cast_sphere(sphere.to_voxel_units(transform), dir, max_t)
+            .map(|hit| SweepHit {
+                toi: hit.toi / voxels_per_unit,
+                ..hit
+            })
+    }
+
+    /// Same as [`Self::cast_capsule`], but `capsule` can be given in [`WorldUnits`].
+    pub fn cast_capsule_in<C: ToVoxelUnits<Capsule>>(
+        &self,
+        transform: &VoxelWorldTransform,
+        capsule: C,
+        dir: Vec3A,
+        max_t: f32,
+    ) -> Option<SweepHit> {
+        let max_t = C::length_to_voxels(transform, max_t);
+        let voxels_per_unit = C::length_to_voxels(transform, 1.0);
+        self.cast_capsule(capsule.to_voxel_units(transform), dir, max_t)
+            .map(|hit| SweepHit {
+                toi: hit.toi / voxels_per_unit,
+                ..hit
+            })
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clipmap::StreamingConfig;
+    use crate::sdf::Sd8;
+    use crate::units::ChunkUnits;
+
+    #[test]
+    fn world_and_voxel_units_round_trip() {
+        let transform = VoxelWorldTransform::new(WorldUnits(Vec3A::new(10.0, 0.0, -4.0)), 0.5);
+        let p = WorldUnits(Vec3A::new(11.0, 2.0, -4.0));
+        let v = p.to_voxel_units(&transform);
+        assert_eq!(v, VoxelUnits(Vec3A::new(2.0, 4.0, 0.0)));
+        assert_eq!(transform.voxel_to_world(v), p);
+        assert_eq!(v.to_voxel_units(&transform), v);
+        assert_eq!(WorldUnits(3.0).to_voxel_units(&transform), VoxelUnits(6.0));
+
+        let VoxelUnits(sphere) = WorldUnits(Sphere::new(p.0, 1.0)).to_voxel_units(&transform);
+        assert_eq!(sphere.center, v.0);
+        assert_eq!(sphere.radius, 2.0);
+    }
+
+    #[test]
+    fn world_ray_hits_scaled_terrain() {
+        let mut clipmap = ChunkClipMap::new(3, StreamingConfig::default());
+        clipmap.edit_chunk(ChunkUnits(IVec3::ZERO), |chunk| {
+            for x in 0..16 {
+                for z in 0..16 {
+                    for y in 0..4 {
+                        chunk.set_voxel(IVec3::new(x, y, z), 1, Sd8::MIN);
+                    }
+                }
+            }
+        });
+        // Voxels are half a world unit, so the floor's top is at world Y = 102.
+        let transform = VoxelWorldTransform::new(WorldUnits(Vec3A::new(0.0, 100.0, 0.0)), 0.5);
+        let origin = WorldUnits(Vec3A::new(4.0, 106.0, 4.0));
+
+        let hit = clipmap
+            .cast_ray_in(&transform, origin, -Vec3A::Y, 10.0)
+            .unwrap();
+        let voxel_hit = clipmap
+            .cast_ray(origin.to_voxel_units(&transform), -Vec3A::Y, 20.0)
+            .unwrap();
+        assert_eq!(hit.position, voxel_hit.position);
+        assert!((hit.t - voxel_hit.t / 2.0).abs() < 1e-4);
+        assert!((transform.voxel_to_world(hit.position).0.y - 102.0).abs() < 0.5);
+        assert!(clipmap
+            .cast_ray_in(&transform, origin, -Vec3A::Y, 3.0)
+            .is_none());
+    }
+}
@@ -0,0 +1,429 @@
+use crate::{ClientMessage, PeerId, PredictedEdits, ServerMessage, Transport, TransportEvent};
+
+use feldspar_map::chunk::{ChunkDelta, CompressedChunk, CompressionCodec};
+use feldspar_map::core::glam::IVec3;
+use feldspar_map::core::SmallKeyHashMap;
+use feldspar_map::database::{BackendError, Change, ChunkDbKey, MapBackend};
+use feldspar_map::units::ChunkUnits;
+use feldspar_map::{EditInfo, EditRejected, EditTag, MapEdits};
+
+use bevy::prelude::*;
+use parking_lot::{Condvar, Mutex};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub struct NetClientConfig {
+    /// How long (in milliseconds) a load waits for the server to send its chunks. Loads that time out are retried.
+    pub request_timeout_ms: u32,
+}
+
+impl Default for NetClientConfig {
+    fn default() -> Self {
+        Self {
+            request_timeout_ms: 5000,
+        }
+    }
+}
+
+/// Loads the map from a server over `transport`, and applies the server's patches.
+///
+/// Edits are sent to the server with [`PredictedEdits::predict`], or with [`NetBackend::send_edit`] to wait for the server's
+/// change instead. If the server rejects one, an [`EditRejected`] event is sent with the [`EditTag::LOCAL`] author and the
+/// edit's sequence number.
+///
+/// Inserts a [`NetBackend`] as the `Arc<dyn MapBackend>`, so it must be added before the [`MapPlugin`](feldspar_map::MapPlugin)
+/// starts up.
+pub struct NetClientPlugin {
+    transport: Arc<dyn Transport>,
+    config: NetClientConfig,
+}
+
+impl NetClientPlugin {
+    pub fn new(transport: Arc<dyn Transport>, config: NetClientConfig) -> Self {
+        Self { transport, config }
+    }
+}
+
+impl Plugin for NetClientPlugin {
+    fn build(&self, app: &mut App) {
+        let backend = Arc::new(NetBackend::new(self.transport.clone(), self.config));
+        let map_backend: Arc<dyn MapBackend> = backend.clone();
+        app.insert_resource(PredictedEdits::new(backend.clone()))
+            .insert_resource(backend)
+            .insert_resource(map_backend)
+            // Patches are queued before the edit system runs.
+            .add_system_to_stage(CoreStage::PreUpdate, client_system);
+    }
+}
+
+/// A [`MapBackend`] that reads chunks from a server.
+///
+/// Reads block until the server answers, so they should only happen on the IO task pool, as they do in the loader. The server
+/// is authoritative, so writes are dropped. Messages are only received by [`NetBackend::receive_messages`], which the
+/// `client_system` calls every frame.
+pub struct NetBackend {
+    transport: Arc<dyn Transport>,
+    /// The requests that are being waited on, by ID, and their responses once they arrive.
+    requests: Mutex<SmallKeyHashMap<u64, Option<ChunksResponse>>>,
+    received: Condvar,
+    timeout: Duration,
+    next_request: AtomicU64,
+    next_sequence: AtomicU64,
+}
+
+type ChunksResponse = Vec<(ChunkDbKey, Option<CompressedChunk>)>;
+
+impl NetBackend {
+    pub fn new(transport: Arc<dyn Transport>, config: NetClientConfig) -> Self {
+        Self {
+            transport,
+            requests: Default::default(),
+            received: Condvar::new(),
+            timeout: Duration::from_millis(config.request_timeout_ms.into()),
+            next_request: AtomicU64::new(0),
+            next_sequence: AtomicU64::new(1),
+        }
+    }
+
+    /// Asks the server to apply `delta` to the LOD0 chunk at `coords`. Returns the sequence number that identifies the edit if
+    /// it's rejected.
+    ///
+    /// The edit isn't applied locally; the change comes back from the server like any other.
+    pub fn send_edit(&self, coords: ChunkUnits<IVec3>, delta: ChunkDelta) -> u64 {
+        let sequence = self.next_sequence.fetch_add(1, Ordering::Relaxed);
+        let message = ClientMessage::Edit {
+            sequence,
+            coords,
+            delta,
+        };
+        self.transport.send(PeerId::SERVER, message.encode());
+        sequence
+    }
+
+    /// Receives every message from the server. Chunks are handed to the reads waiting on them, and late chunks from reads that
+    /// timed out are dropped. Returns the other messages in the order they were received.
+    pub fn receive_messages(&self) -> Vec<ServerMessage> {
+        let mut messages = Vec::new();
+        while let Some(event) = self.transport.receive() {
+            let bytes = match event {
+                TransportEvent::Message(_, bytes) => bytes,
+                TransportEvent::Connected(_) | TransportEvent::Disconnected(_) => continue,
+            };
+            match ServerMessage::decode(&bytes) {
+                Ok(ServerMessage::Chunks { request, chunks }) => {
+                    if let Some(response) = self.requests.lock().get_mut(&request) {
+                        *response = Some(chunks);
+                    }
+                    self.received.notify_all();
+                }
+                Ok(message) => messages.push(message),
+                Err(e) => log::warn!("Dropped bad message from server: {:?}", e),
+            }
+        }
+        messages
+    }
+}
+
+impl MapBackend for NetBackend {
+    fn codec(&self) -> CompressionCodec {
+        CompressionCodec::default()
+    }
+
+    fn read_chunk(&self, key: ChunkDbKey) -> Result<Option<Change<CompressedChunk>>, BackendError> {
+        Ok(self.read_chunks(&[key])?.pop().unwrap())
+    }
+
+    fn read_chunks(
+        &self,
+        keys: &[ChunkDbKey],
+    ) -> Result<Vec<Option<Change<CompressedChunk>>>, BackendError> {
+        let deadline = Instant::now() + self.timeout;
+        let request = self.next_request.fetch_add(1, Ordering::Relaxed);
+        let mut requests = self.requests.lock();
+        requests.insert(request, None);
+        let message = ClientMessage::RequestChunks {
+            request,
+            keys: keys.to_vec(),
+        };
+        self.transport.send(PeerId::SERVER, message.encode());
+
+        while matches!(requests.get(&request), Some(None)) {
+            if self
+                .received
+                .wait_until(&mut requests, deadline)
+                .timed_out()
+            {
+                requests.remove(&request);
+                return Err(BackendError::Io(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "server didn't send the requested chunks",
+                )));
+            }
+        }
+        let chunks = requests.remove(&request).flatten().unwrap();
+        drop(requests);
+
+        if chunks.len() != keys.len() || chunks.iter().zip(keys).any(|((a, _), b)| a != b) {
+            return Err(BackendError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "server sent different chunks than were requested",
+            )));
+        }
+        Ok(chunks
+            .into_iter()
+            .map(|(_, chunk)| chunk.map(Change::Insert))
+            .collect())
+    }
+
+    fn write_chunks(
+        &self,
+        _changes: Vec<(ChunkDbKey, Change<CompressedChunk>)>,
+    ) -> Result<(), BackendError> {
+        Ok(())
+    }
+}
+
+/// Applies the edits from the server with [`MapEdits`], so they wait for any chunks that are still loading, reconciles them
+/// with the [`PredictedEdits`], and reports the rejected edits.
+pub fn client_system(
+    backend: Res<Arc<NetBackend>>,
+    mut predictions: ResMut<PredictedEdits>,
+    mut edits: ResMut<MapEdits>,
+    mut rejections: EventWriter<EditRejected>,
+) {
+    for message in backend.receive_messages().into_iter() {
+        match message {
+            ServerMessage::Patch(patch) => edits.apply_brush(patch),
+            ServerMessage::Delta { coords, delta } => {
+                let rest = predictions.reconcile(coords, delta);
+                queue_delta(&mut edits, coords, rest);
+            }
+            ServerMessage::Applied { sequence } => {
+                if let Some((coords, restore)) = predictions.resolve(sequence) {
+                    queue_delta(&mut edits, coords, restore);
+                }
+            }
+            ServerMessage::Rejected {
+                sequence,
+                extent,
+                reason,
+            } => {
+                log::debug!("Server rejected edit {}: {}", sequence, reason);
+                if let Some((coords, restore)) = predictions.resolve(sequence) {
+                    queue_delta(&mut edits, coords, restore);
+                }
+                rejections.send(EditRejected {
+                    edit: EditInfo {
+                        tag: EditTag {
+                            sequence,
+                            ..EditTag::LOCAL
+                        },
+                        extent,
+                    },
+                    reason,
+                });
+            }
+            ServerMessage::Chunks { .. } => unreachable!(),
+        }
+    }
+}
+
+fn queue_delta(edits: &mut MapEdits, coords: ChunkUnits<IVec3>, delta: ChunkDelta) {
+    if !delta.is_empty() {
+        edits.apply_delta(coords, delta);
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LocalServerTransport, VoxelPatch};
+    use feldspar_map::chunk::Chunk;
+    use feldspar_map::clipmap::NodeKey;
+    use feldspar_map::core::ilattice::prelude::Extent;
+    use feldspar_map::sdf::Sd8;
+    use feldspar_map::units::VoxelUnits;
+
+    #[test]
+    fn read_chunks_from_server() {
+        let server = LocalServerTransport::new();
+        let client = server.connect();
+        let peer = client.peer();
+        let backend = Arc::new(NetBackend::new(
+            Arc::new(client),
+            NetClientConfig::default(),
+        ));
+        assert_eq!(server.receive(), Some(TransportEvent::Connected(peer)));
+
+        let keys = [
+            ChunkDbKey::from(NodeKey::new(0, IVec3::new(1, 2, 3))),
+            ChunkDbKey::from(NodeKey::new(1, IVec3::ZERO)),
+        ];
+        let reader_backend = backend.clone();
+        let reader = std::thread::spawn(move || reader_backend.read_chunks(&keys).unwrap());
+
+        let request = loop {
+            if let Some(TransportEvent::Message(from, bytes)) = server.receive() {
+                assert_eq!(from, peer);
+                break ClientMessage::decode(&bytes).unwrap();
+            }
+            std::thread::yield_now();
+        };
+        let request = match request {
+            ClientMessage::RequestChunks {
+                request,
+                keys: requested,
+            } => {
+                assert_eq!(requested, keys.to_vec());
+                request
+            }
+            other => panic!("Expected a chunk request, got {:?}", other),
+        };
+
+        let mut chunk = Chunk::default();
+        chunk.set_voxel(IVec3::ONE, 3, Sd8::MIN);
+        let patch = VoxelPatch {
+            extent: VoxelUnits(Extent::from_min_and_shape(IVec3::ZERO, IVec3::ONE)),
+            sdf: vec![Sd8::MIN],
+            palette_ids: vec![2],
+        };
+        server.send(peer, ServerMessage::Patch(patch.clone()).encode());
+        let chunks = vec![(keys[0], Some(chunk.compress())), (keys[1], None)];
+        server.send(peer, ServerMessage::Chunks { request, chunks }.encode());
+        assert_eq!(
+            backend.receive_messages(),
+            vec![ServerMessage::Patch(patch)]
+        );
+
+        let mut read = reader.join().unwrap().into_iter();
+        assert_eq!(
+            read.next()
+                .unwrap()
+                .unwrap()
+                .into_insert()
+                .unwrap()
+                .decompress(),
+            chunk
+        );
+        assert!(read.next().unwrap().is_none());
+        assert!(read.next().is_none());
+    }
+
+    #[test]
+    fn overlapping_reads_get_their_own_responses() {
+        let server = LocalServerTransport::new();
+        let client = server.connect();
+        let peer = client.peer();
+        let backend = Arc::new(NetBackend::new(
+            Arc::new(client),
+            NetClientConfig::default(),
+        ));
+        let key = ChunkDbKey::from(NodeKey::new(0, IVec3::ZERO));
+
+        // E.g. a load that timed out is retried while the first request is still in flight.
+        let readers: Vec<_> = (0..2)
+            .map(|_| {
+                let backend = backend.clone();
+                std::thread::spawn(move || backend.read_chunk(key).unwrap())
+            })
+            .collect();
+        let mut requests = Vec::new();
+        while requests.len() < 2 {
+            match server.receive() {
+                Some(TransportEvent::Message(_, bytes)) => {
+                    requests.push(ClientMessage::decode(&bytes).unwrap())
+                }
+                Some(_) => (),
+                None => std::thread::yield_now(),
+            }
+        }
+
+        let mut chunk = Chunk::default();
+        chunk.set_voxel(IVec3::ONE, 3, Sd8::MIN);
+        for request in requests.into_iter().rev() {
+            let request = match request {
+                ClientMessage::RequestChunks { request, .. } => request,
+                other => panic!("Expected a chunk request, got {:?}", other),
+            };
+            let chunks = vec![(key, Some(chunk.compress()))];
+            server.send(peer, ServerMessage::Chunks { request, chunks }.encode());
+        }
+        backend.receive_messages();
+
+        for reader in readers.into_iter() {
+            let read = reader.join().unwrap().unwrap().into_insert().unwrap();
+            assert_eq!(read.decompress(), chunk);
+        }
+        assert!(backend.requests.lock().is_empty());
+    }
+
+    #[test]
+    fn send_edits_in_sequence() {
+        let server = LocalServerTransport::new();
+        let backend = NetBackend::new(Arc::new(server.connect()), NetClientConfig::default());
+        let mut chunk = Chunk::default();
+        let old = chunk;
+        chunk.set_voxel(IVec3::ONE, 1, Sd8::MIN);
+        let delta = ChunkDelta::between(&old, &chunk);
+        let coords = ChunkUnits(IVec3::new(0, -1, 0));
+
+        let first = backend.send_edit(coords, delta.clone());
+        let second = backend.send_edit(coords, delta.invert());
+        assert!(second > first);
+
+        let mut received = Vec::new();
+        while let Some(event) = server.receive() {
+            if let TransportEvent::Message(_, bytes) = event {
+                received.push(ClientMessage::decode(&bytes).unwrap());
+            }
+        }
+        assert_eq!(
+            received,
+            vec![
+                ClientMessage::Edit {
+                    sequence: first,
+                    coords,
+                    delta: delta.clone()
+                },
+                ClientMessage::Edit {
+                    sequence: second,
+                    coords,
+                    delta: delta.invert()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn read_times_out_without_server() {
+        let server = LocalServerTransport::new();
+        let backend = NetBackend::new(
+            Arc::new(server.connect()),
+            NetClientConfig {
+                request_timeout_ms: 10,
+            },
+        );
+        let key = ChunkDbKey::from(NodeKey::new(0, IVec3::ZERO));
+        assert!(matches!(backend.read_chunk(key), Err(BackendError::Io(_))));
+
+        // Late responses are dropped.
+        let message = ServerMessage::Chunks {
+            request: 0,
+            chunks: vec![(key, None)],
+        };
+        server.send(PeerId(1), message.encode());
+        backend.receive_messages();
+        assert!(backend.requests.lock().is_empty());
+    }
+}
@@ -0,0 +1,52 @@
+//! Replicates a voxel map from an authoritative server to any number of clients, for multiplayer worlds.
+//!
+//! # Server
+//!
+//! The [`NetServerPlugin`] answers chunk requests from clients with batches of [`CompressedChunk`]s, preferring the chunks
+//! resident in the server's [`ChunkClipMap`] over the [`MapBackend`], since they could have edits that aren't flushed yet.
+//! Every frame, the changes in the [`DirtyRegions`] are broadcast to all clients, so small edits don't resend whole chunks.
+//! Chunks with a recorded [`ChunkDelta`] only send the voxels that changed, and the rest of the regions are sent as
+//! [`VoxelPatch`]es.
+//!
+//! Edits are only applied to chunks that are loaded on the server, so the server should have a [`Witness`] for each client.
+//!
+//! Clients can send their own edits as [`ChunkDelta`]s. The server queues them with [`MapEdits`], tagged with the client's
+//! [`PeerId`], so an [`EditValidator`] can reject them like any other edit, and tells the author about each rejection.
+//!
+//! # Client
+//!
+//! The [`NetClientPlugin`] installs a [`NetBackend`], so the [`MapPlugin`]'s loader fills the clipmap from the server instead
+//! of a local database. Deltas and patches are applied with [`MapEdits`]. Clients are replicas, so their own writes to the
+//! backend are dropped, and [`MapStorage::Memory`] avoids opening a database that would never be used.
+//!
+//! Edits made with [`PredictedEdits`] show up right away, instead of after a round trip to the server. Changes from the server
+//! are reconciled with the pending predictions, and each prediction is rolled back to the server's voxels once the server
+//! applies or rejects it.
+//!
+//! # Transport
+//!
+//! Messages are plain byte buffers, so any reliable, ordered [`Transport`] works, e.g. TCP, WebSockets, or a reliable channel
+//! of a game networking library. [`LocalServerTransport`] connects clients in the same process.
+//!
+//! [`CompressedChunk`]: feldspar_map::chunk::CompressedChunk
+//! [`ChunkDelta`]: feldspar_map::chunk::ChunkDelta
+//! [`ChunkClipMap`]: feldspar_map::clipmap::ChunkClipMap
+//! [`MapBackend`]: feldspar_map::database::MapBackend
+//! [`DirtyRegions`]: feldspar_map::DirtyRegions
+//! [`Witness`]: feldspar_map::Witness
+//! [`MapPlugin`]: feldspar_map::MapPlugin
+//! [`MapEdits`]: feldspar_map::MapEdits
+//! [`MapStorage::Memory`]: feldspar_map::MapStorage::Memory
+//! [`EditValidator`]: feldspar_map::EditValidator
+
+mod client;
+mod message;
+mod prediction;
+mod server;
+mod transport;
+
+pub use client::*;
+pub use message::*;
+pub use prediction::*;
+pub use server::*;
+pub use transport::*;
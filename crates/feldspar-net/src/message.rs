@@ -0,0 +1,511 @@
+//! The messages sent between the server and its clients. All integers are little-endian.
+//!
+//! ```text
+//! ClientMessage::RequestChunks:
+//!     tag:        u8 = 0
+//!     request:    u64
+//!     num_keys:   u32
+//!     keys:       [[u8; 13]]  // ChunkDbKey::into_sled_key
+//!
+//! ClientMessage::Edit:
+//!     tag:        u8 = 1
+//!     sequence:   u64
+//!     coords:     [i32; 3]    // of the LOD0 chunk
+//!     delta:      [u8]        // ChunkDelta::to_bytes
+//!
+//! ServerMessage::Chunks:
+//!     tag:        u8 = 0
+//!     request:    u64
+//!     num_chunks: u32
+//!     chunks: [
+//!         key:        [u8; 13]
+//!         num_bytes:  u32       // u32::MAX if there is no chunk
+//!         bytes:      [u8]      // as in CompressedChunk
+//!     ]
+//!
+//! ServerMessage::Patch:
+//!     tag:         u8 = 1
+//!     minimum:     [i32; 3]
+//!     shape:       [i32; 3]
+//!     sdf:         [i8]    // x varies fastest, then y, then z
+//!     palette_ids: [u8]
+//!
+//! ServerMessage::Delta:
+//!     tag:         u8 = 2
+//!     coords:      [i32; 3]  // of the LOD0 chunk
+//!     delta:       [u8]      // ChunkDelta::to_bytes
+//!
+//! ServerMessage::Rejected:
+//!     tag:         u8 = 3
+//!     sequence:    u64
+//!     minimum:     [i32; 3]
+//!     shape:       [i32; 3]
+//!     reason:      [u8]      // UTF-8
+//!
+//! ServerMessage::Applied:
+//!     tag:         u8 = 4
+//!     sequence:    u64
+//! ```
+
+use feldspar_map::brush::Brush;
+use feldspar_map::chunk::{ChunkDelta, CompressedChunk};
+use feldspar_map::clipmap::SdfSampler;
+use feldspar_map::core::glam::IVec3;
+use feldspar_map::core::ilattice::prelude::Extent;
+use feldspar_map::database::ChunkDbKey;
+use feldspar_map::palette::PaletteId8;
+use feldspar_map::sdf::Sd8;
+use feldspar_map::units::{ChunkUnits, VoxelUnits};
+
+const NO_CHUNK: u32 = u32::MAX;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum DecodeError {
+    /// The message ended before all of its fields.
+    Truncated,
+    UnknownTag(u8),
+    /// A patch has a negative shape, or it doesn't have one value per voxel.
+    BadPatch,
+    /// A delta wasn't written by [`ChunkDelta::to_bytes`].
+    BadDelta,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ClientMessage {
+    /// Asks for the working version of each chunk. The server answers with a [`ServerMessage::Chunks`] with the same
+    /// `request`, so a client can have more than one request for a chunk in flight.
+    RequestChunks { request: u64, keys: Vec<ChunkDbKey> },
+    /// Asks the server to apply `delta` to the LOD0 chunk at `coords`. The server answers with [`ServerMessage::Applied`] or
+    /// [`ServerMessage::Rejected`].
+    Edit {
+        sequence: u64,
+        coords: ChunkUnits<IVec3>,
+        delta: ChunkDelta,
+    },
+}
+
+impl ClientMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Self::RequestChunks { request, keys } => {
+                let mut bytes = Vec::with_capacity(1 + 8 + 4 + 13 * keys.len());
+                bytes.push(0);
+                bytes.extend_from_slice(&request.to_le_bytes());
+                bytes.extend_from_slice(&(keys.len() as u32).to_le_bytes());
+                for key in keys.iter() {
+                    bytes.extend_from_slice(&key.into_sled_key());
+                }
+                bytes
+            }
+            Self::Edit {
+                sequence,
+                coords,
+                delta,
+            } => {
+                let mut bytes = Vec::with_capacity(1 + 8 + 12 + delta.num_bytes());
+                bytes.push(1);
+                bytes.extend_from_slice(&sequence.to_le_bytes());
+                push_ivec3(&mut bytes, coords.into_inner());
+                bytes.extend_from_slice(&delta.to_bytes());
+                bytes
+            }
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader { bytes };
+        match reader.u8()? {
+            0 => {
+                let request = reader.u64()?;
+                let num_keys = reader.u32()?;
+                let keys = (0..num_keys)
+                    .map(|_| reader.key())
+                    .collect::<Result<_, _>>()?;
+                Ok(Self::RequestChunks { request, keys })
+            }
+            1 => {
+                let sequence = reader.u64()?;
+                let coords = ChunkUnits(reader.ivec3()?);
+                let delta = reader.delta()?;
+                Ok(Self::Edit {
+                    sequence,
+                    coords,
+                    delta,
+                })
+            }
+            tag => Err(DecodeError::UnknownTag(tag)),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ServerMessage {
+    /// Answers the [`ClientMessage::RequestChunks`] with `request`, in the order of its keys. Chunks that don't exist are
+    /// `None`.
+    Chunks {
+        request: u64,
+        chunks: Vec<(ChunkDbKey, Option<CompressedChunk>)>,
+    },
+    /// Some LOD0 voxels changed, but their old values weren't known.
+    Patch(VoxelPatch),
+    /// Some voxels of the LOD0 chunk at `coords` changed.
+    Delta {
+        coords: ChunkUnits<IVec3>,
+        delta: ChunkDelta,
+    },
+    /// The [`ClientMessage::Edit`] with `sequence` was rejected by the server's
+    /// [`EditValidator`](feldspar_map::EditValidator).
+    Rejected {
+        sequence: u64,
+        extent: VoxelUnits<Extent<IVec3>>,
+        reason: String,
+    },
+    /// The [`ClientMessage::Edit`] with `sequence` was applied. This is sent after the [`ServerMessage::Delta`] that includes
+    /// the edit.
+    Applied { sequence: u64 },
+}
+
+impl ServerMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self {
+            Self::Chunks { request, chunks } => {
+                bytes.push(0);
+                bytes.extend_from_slice(&request.to_le_bytes());
+                bytes.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+                for (key, chunk) in chunks.iter() {
+                    bytes.extend_from_slice(&key.into_sled_key());
+                    if let Some(chunk) = chunk {
+                        bytes.extend_from_slice(&(chunk.bytes.len() as u32).to_le_bytes());
+                        bytes.extend_from_slice(&chunk.bytes);
+                    } else {
+                        bytes.extend_from_slice(&NO_CHUNK.to_le_bytes());
+                    }
+                }
+            }
+            Self::Patch(patch) => {
+                let VoxelUnits(extent) = patch.extent;
+                bytes.reserve(1 + 24 + 2 * patch.sdf.len());
+                bytes.push(1);
+                push_ivec3(&mut bytes, extent.minimum);
+                push_ivec3(&mut bytes, extent.shape);
+                bytes.extend(patch.sdf.iter().map(|sdf| sdf.0 as u8));
+                bytes.extend_from_slice(&patch.palette_ids);
+            }
+            Self::Delta { coords, delta } => {
+                bytes.reserve(1 + 12 + delta.num_bytes());
+                bytes.push(2);
+                push_ivec3(&mut bytes, coords.into_inner());
+                bytes.extend_from_slice(&delta.to_bytes());
+            }
+            Self::Rejected {
+                sequence,
+                extent,
+                reason,
+            } => {
+                let VoxelUnits(extent) = extent;
+                bytes.reserve(1 + 8 + 24 + reason.len());
+                bytes.push(3);
+                bytes.extend_from_slice(&sequence.to_le_bytes());
+                push_ivec3(&mut bytes, extent.minimum);
+                push_ivec3(&mut bytes, extent.shape);
+                bytes.extend_from_slice(reason.as_bytes());
+            }
+            Self::Applied { sequence } => {
+                bytes.push(4);
+                bytes.extend_from_slice(&sequence.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader { bytes };
+        match reader.u8()? {
+            0 => {
+                let request = reader.u64()?;
+                let num_chunks = reader.u32()?;
+                let chunks = (0..num_chunks)
+                    .map(|_| {
+                        let key = reader.key()?;
+                        let num_bytes = reader.u32()?;
+                        let chunk = if num_bytes == NO_CHUNK {
+                            None
+                        } else {
+                            Some(CompressedChunk {
+                                bytes: reader.take(num_bytes as usize)?.into(),
+                            })
+                        };
+                        Ok((key, chunk))
+                    })
+                    .collect::<Result<_, _>>()?;
+                Ok(Self::Chunks { request, chunks })
+            }
+            1 => {
+                let minimum = reader.ivec3()?;
+                let shape = reader.ivec3()?;
+                if shape.cmplt(IVec3::ZERO).any() {
+                    return Err(DecodeError::BadPatch);
+                }
+                let num_voxels = (shape.x as usize)
+                    .checked_mul(shape.y as usize)
+                    .and_then(|n| n.checked_mul(shape.z as usize))
+                    .ok_or(DecodeError::BadPatch)?;
+                if reader.bytes.len() % 2 != 0 || reader.bytes.len() / 2 != num_voxels {
+                    return Err(DecodeError::BadPatch);
+                }
+                let sdf = reader
+                    .take(num_voxels)?
+                    .iter()
+                    .map(|&b| Sd8(b as i8))
+                    .collect();
+                let palette_ids = reader.take(num_voxels)?.to_vec();
+                Ok(Self::Patch(VoxelPatch {
+                    extent: VoxelUnits(Extent::from_min_and_shape(minimum, shape)),
+                    sdf,
+                    palette_ids,
+                }))
+            }
+            2 => {
+                let coords = ChunkUnits(reader.ivec3()?);
+                let delta = reader.delta()?;
+                Ok(Self::Delta { coords, delta })
+            }
+            3 => {
+                let sequence = reader.u64()?;
+                let minimum = reader.ivec3()?;
+                let shape = reader.ivec3()?;
+                let reason = String::from_utf8_lossy(reader.bytes).into_owned();
+                Ok(Self::Rejected {
+                    sequence,
+                    extent: VoxelUnits(Extent::from_min_and_shape(minimum, shape)),
+                    reason,
+                })
+            }
+            4 => Ok(Self::Applied {
+                sequence: reader.u64()?,
+            }),
+            tag => Err(DecodeError::UnknownTag(tag)),
+        }
+    }
+}
+
+/// A copy of all LOD0 voxels in an extent.
+///
+/// Applying a patch as a [`Brush`] overwrites those voxels, so patches can be applied more than once, and in any order with
+/// respect to edits of other voxels.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VoxelPatch {
+    pub extent: VoxelUnits<Extent<IVec3>>,
+    pub sdf: Vec<Sd8>,
+    pub palette_ids: Vec<PaletteId8>,
+}
+
+impl VoxelPatch {
+    /// Copies the voxels in `extent` from the clipmap behind `sampler`.
+    pub fn sample(extent: VoxelUnits<Extent<IVec3>>, sampler: &mut SdfSampler) -> Self {
+        let VoxelUnits(e) = extent;
+        let num_voxels = e.volume() as usize;
+        let mut patch = Self {
+            extent,
+            sdf: vec![Sd8::MAX; num_voxels],
+            palette_ids: vec![0; num_voxels],
+        };
+        for p in e.iter3() {
+            let i = patch.index(p);
+            let (sdf, palette_id) = sampler.voxel(VoxelUnits(p));
+            patch.sdf[i] = sdf;
+            patch.palette_ids[i] = palette_id;
+        }
+        patch
+    }
+
+    fn index(&self, p: IVec3) -> usize {
+        let VoxelUnits(extent) = self.extent;
+        let d = p - extent.minimum;
+        ((d.z * extent.shape.y + d.y) * extent.shape.x + d.x) as usize
+    }
+}
+
+impl Brush for VoxelPatch {
+    fn extent(&self) -> VoxelUnits<Extent<IVec3>> {
+        self.extent
+    }
+
+    fn paint(&self, p: VoxelUnits<IVec3>, sdf: &mut Sd8, palette_id: &mut PaletteId8) {
+        let i = self.index(p.into_inner());
+        *sdf = self.sdf[i];
+        *palette_id = self.palette_ids[i];
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() < n {
+            return Err(DecodeError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, DecodeError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn ivec3(&mut self) -> Result<IVec3, DecodeError> {
+        let mut c = [0; 3];
+        for c in c.iter_mut() {
+            *c = i32::from_le_bytes(self.take(4)?.try_into().unwrap());
+        }
+        Ok(IVec3::from(c))
+    }
+
+    fn key(&mut self) -> Result<ChunkDbKey, DecodeError> {
+        Ok(ChunkDbKey::from_sled_key(self.take(13)?))
+    }
+
+    /// Takes the rest of the bytes.
+    fn delta(&mut self) -> Result<ChunkDelta, DecodeError> {
+        let delta = ChunkDelta::from_bytes(self.bytes).ok_or(DecodeError::BadDelta)?;
+        self.bytes = &[];
+        Ok(delta)
+    }
+}
+
+fn push_ivec3(bytes: &mut Vec<u8>, v: IVec3) {
+    for c in v.to_array() {
+        bytes.extend_from_slice(&c.to_le_bytes());
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use feldspar_map::chunk::Chunk;
+    use feldspar_map::clipmap::NodeKey;
+
+    #[test]
+    fn messages_round_trip() {
+        let keys = vec![
+            ChunkDbKey::from(NodeKey::new(0, IVec3::new(1, -2, 3))),
+            ChunkDbKey::from(NodeKey::new(3, IVec3::new(-7, 0, 4))),
+        ];
+        let request = ClientMessage::RequestChunks {
+            request: 7,
+            keys: keys.clone(),
+        };
+        assert_eq!(ClientMessage::decode(&request.encode()), Ok(request));
+
+        let mut chunk = Chunk::default();
+        chunk.set_voxel(IVec3::new(1, 2, 3), 5, Sd8::MIN);
+        let chunks = ServerMessage::Chunks {
+            request: 7,
+            chunks: vec![(keys[0], Some(chunk.compress())), (keys[1], None)],
+        };
+        assert_eq!(ServerMessage::decode(&chunks.encode()), Ok(chunks));
+
+        let patch = ServerMessage::Patch(VoxelPatch {
+            extent: VoxelUnits(Extent::from_min_and_shape(
+                IVec3::new(-1, 0, 2),
+                IVec3::new(2, 1, 3),
+            )),
+            sdf: (0..6).map(|i| Sd8(i - 3)).collect(),
+            palette_ids: (0..6).collect(),
+        });
+        assert_eq!(ServerMessage::decode(&patch.encode()), Ok(patch));
+
+        let mut edited = chunk;
+        edited.set_voxel(IVec3::new(4, 0, 9), 6, Sd8::MIN);
+        let delta = ServerMessage::Delta {
+            coords: ChunkUnits(IVec3::new(2, -1, 0)),
+            delta: ChunkDelta::between(&chunk, &edited),
+        };
+        assert_eq!(ServerMessage::decode(&delta.encode()), Ok(delta));
+
+        let edit = ClientMessage::Edit {
+            sequence: u64::MAX - 1,
+            coords: ChunkUnits(IVec3::new(-3, 0, 1)),
+            delta: ChunkDelta::between(&chunk, &edited),
+        };
+        assert_eq!(ClientMessage::decode(&edit.encode()), Ok(edit));
+
+        let rejected = ServerMessage::Rejected {
+            sequence: 12,
+            extent: VoxelUnits(Extent::from_min_and_shape(IVec3::splat(-4), IVec3::ONE)),
+            reason: "spawn is protected".into(),
+        };
+        assert_eq!(ServerMessage::decode(&rejected.encode()), Ok(rejected));
+
+        let applied = ServerMessage::Applied { sequence: 3 };
+        assert_eq!(ServerMessage::decode(&applied.encode()), Ok(applied));
+    }
+
+    #[test]
+    fn reject_malformed_messages() {
+        assert_eq!(ClientMessage::decode(&[]), Err(DecodeError::Truncated));
+        assert_eq!(ClientMessage::decode(&[9]), Err(DecodeError::UnknownTag(9)));
+
+        let request = ClientMessage::RequestChunks {
+            request: 1,
+            keys: vec![ChunkDbKey::from(NodeKey::new(0, IVec3::ZERO))],
+        };
+        let bytes = request.encode();
+        assert_eq!(
+            ClientMessage::decode(&bytes[..bytes.len() - 1]),
+            Err(DecodeError::Truncated)
+        );
+
+        let patch = ServerMessage::Patch(VoxelPatch {
+            extent: VoxelUnits(Extent::from_min_and_shape(IVec3::ZERO, IVec3::ONE)),
+            sdf: vec![Sd8::MIN],
+            palette_ids: vec![1],
+        });
+        let mut bytes = patch.encode();
+        bytes.push(0);
+        assert_eq!(ServerMessage::decode(&bytes), Err(DecodeError::BadPatch));
+    }
+
+    #[test]
+    fn patch_paints_its_voxels() {
+        let extent = Extent::from_min_and_shape(IVec3::new(4, 5, 6), IVec3::new(3, 2, 2));
+        let patch = VoxelPatch {
+            extent: VoxelUnits(extent),
+            sdf: (0..12).map(Sd8).collect(),
+            palette_ids: (0..12).collect(),
+        };
+        for (i, p) in [
+            IVec3::new(4, 5, 6),
+            IVec3::new(6, 5, 6),
+            IVec3::new(4, 6, 6),
+            IVec3::new(6, 6, 7),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let (mut sdf, mut palette_id) = (Sd8::MAX, 0);
+            patch.paint(VoxelUnits(p), &mut sdf, &mut palette_id);
+            let expected = [0, 2, 3, 11][i];
+            assert_eq!((sdf, palette_id), (Sd8(expected), expected as u8));
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use crate::NetBackend;
+
+use feldspar_map::chunk::ChunkDelta;
+use feldspar_map::core::glam::IVec3;
+use feldspar_map::units::ChunkUnits;
+use feldspar_map::MapEdits;
+
+use std::sync::Arc;
+
+/// Edits that a client applied to its own clipmap before the server accepted them, so the player doesn't wait a round trip to
+/// see them.
+///
+/// Each prediction keeps the authoritative value of every voxel it overwrote. Changes from the server that touch those voxels
+/// only update the kept values, so they don't undo the prediction. When the server answers, the kept values are written back:
+/// an applied edit is already part of them, and a rejected edit disappears.
+///
+/// Only [`ServerMessage::Delta`](crate::ServerMessage::Delta)s are reconciled. Patches overwrite any predictions of their
+/// voxels.
+pub struct PredictedEdits {
+    backend: Arc<NetBackend>,
+    /// Oldest first.
+    pending: Vec<Prediction>,
+}
+
+struct Prediction {
+    sequence: u64,
+    coords: IVec3,
+    /// The old values are authoritative, unless an older prediction changed the same voxels.
+    delta: ChunkDelta,
+}
+
+impl PredictedEdits {
+    pub fn new(backend: Arc<NetBackend>) -> Self {
+        Self {
+            backend,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Applies `delta` to the LOD0 chunk at `coords` and sends it to the server. The old values of `delta` should be the
+    /// current values in the clipmap, e.g. from [`ChunkDelta::between`] a copy of the chunk and its edited version.
+    ///
+    /// Returns the sequence number of the edit.
+    pub fn predict(
+        &mut self,
+        edits: &mut MapEdits,
+        coords: ChunkUnits<IVec3>,
+        delta: ChunkDelta,
+    ) -> u64 {
+        let sequence = self.backend.send_edit(coords, delta.clone());
+        edits.apply_delta(coords, delta.clone());
+        self.pending.push(Prediction {
+            sequence,
+            coords: coords.into_inner(),
+            delta,
+        });
+        sequence
+    }
+
+    /// The number of edits that the server hasn't answered yet.
+    pub fn num_pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Takes an authoritative change of the chunk at `coords`. Returns the part of it that should be applied to the clipmap.
+    pub fn reconcile(&mut self, coords: ChunkUnits<IVec3>, delta: ChunkDelta) -> ChunkDelta {
+        // Voxels are kept by the oldest prediction that changed them.
+        let mut rest = delta;
+        for prediction in self.predictions_of(coords.into_inner(), 0) {
+            rest = prediction.delta.rebase(&rest);
+        }
+        rest
+    }
+
+    /// Forgets the edit with `sequence`, because the server applied or rejected it. Returns the change that restores the
+    /// voxels it predicted, if it was predicted here.
+    pub fn resolve(&mut self, sequence: u64) -> Option<(ChunkUnits<IVec3>, ChunkDelta)> {
+        let index = self
+            .pending
+            .iter()
+            .position(|prediction| prediction.sequence == sequence)?;
+        let resolved = self.pending.remove(index);
+
+        // Newer predictions of the same voxels take over the values kept by this one.
+        let mut restore = resolved.delta.invert();
+        for prediction in self.predictions_of(resolved.coords, index) {
+            restore = prediction.delta.rebase(&restore);
+        }
+        Some((ChunkUnits(resolved.coords), restore))
+    }
+
+    fn predictions_of(
+        &mut self,
+        coords: IVec3,
+        first: usize,
+    ) -> impl Iterator<Item = &mut Prediction> {
+        self.pending[first..]
+            .iter_mut()
+            .filter(move |prediction| prediction.coords == coords)
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LocalServerTransport, NetClientConfig};
+    use feldspar_map::chunk::Chunk;
+    use feldspar_map::sdf::Sd8;
+
+    fn predictions() -> (LocalServerTransport, PredictedEdits) {
+        let server = LocalServerTransport::new();
+        let backend = NetBackend::new(Arc::new(server.connect()), NetClientConfig::default());
+        (server, PredictedEdits::new(Arc::new(backend)))
+    }
+
+    fn edited(chunk: &Chunk, p: IVec3, palette_id: u8) -> Chunk {
+        let mut chunk = *chunk;
+        chunk.set_voxel(p, palette_id, Sd8::MIN);
+        chunk
+    }
+
+    #[test]
+    fn rejected_prediction_restores_authoritative_voxels() {
+        let (_server, mut predictions) = predictions();
+        let mut edits = MapEdits::default();
+        let coords = ChunkUnits(IVec3::ZERO);
+        let (a, b) = (IVec3::new(1, 0, 0), IVec3::new(2, 0, 0));
+
+        let authoritative = Chunk::default();
+        let mut local = edited(&authoritative, a, 1);
+        let first = predictions.predict(
+            &mut edits,
+            coords,
+            ChunkDelta::between(&authoritative, &local),
+        );
+        let predicted = edited(&edited(&local, a, 2), b, 2);
+        let second =
+            predictions.predict(&mut edits, coords, ChunkDelta::between(&local, &predicted));
+        local = predicted;
+
+        // Someone else changes both voxels, but the predictions stay on top.
+        let other = edited(&edited(&authoritative, a, 3), b, 3);
+        let rest = predictions.reconcile(coords, ChunkDelta::between(&authoritative, &other));
+        assert!(rest.is_empty());
+
+        // The first edit was applied, but it's hidden by the second.
+        predictions.resolve(first).unwrap().1.apply(&mut local);
+        assert_eq!(local, predicted);
+
+        // The second edit was rejected.
+        let (restore_coords, restore) = predictions.resolve(second).unwrap();
+        assert_eq!(restore_coords, coords);
+        restore.apply(&mut local);
+        assert_eq!(local, other);
+        assert_eq!(predictions.num_pending(), 0);
+        assert!(predictions.resolve(second).is_none());
+    }
+}
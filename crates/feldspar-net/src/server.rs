@@ -0,0 +1,243 @@
+use crate::{ClientMessage, PeerId, ServerMessage, Transport, TransportEvent, VoxelPatch};
+
+use feldspar_map::chunk::{Chunk, CompressedChunk, UniformChunk};
+use feldspar_map::clipmap::{ChunkClipMap, NodeKey, SdfSampler};
+use feldspar_map::coordinates::{chunk_extent_ivec3, in_chunk_extent};
+use feldspar_map::core::glam::IVec3;
+use feldspar_map::core::SmallKeyHashSet;
+use feldspar_map::database::{Change, ChunkDbKey, MapBackend};
+use feldspar_map::units::{ChunkUnits, VoxelUnits};
+use feldspar_map::{DirtyRegions, EditApplied, EditRejected, EditTag, MapEdits};
+
+use bevy::prelude::*;
+use bevy::tasks::IoTaskPool;
+use std::sync::Arc;
+
+/// Serves the map to clients over `transport`.
+///
+/// Requires the [`MapPlugin`](feldspar_map::MapPlugin).
+pub struct NetServerPlugin {
+    transport: Arc<dyn Transport>,
+}
+
+impl NetServerPlugin {
+    pub fn new(transport: Arc<dyn Transport>) -> Self {
+        Self { transport }
+    }
+}
+
+impl Plugin for NetServerPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(NetServer::new(self.transport.clone()))
+            // The dirty regions are complete by the end of the update stage.
+            .add_system_to_stage(CoreStage::PostUpdate, server_system);
+    }
+}
+
+/// The connected clients.
+pub struct NetServer {
+    transport: Arc<dyn Transport>,
+    clients: SmallKeyHashSet<PeerId>,
+}
+
+impl NetServer {
+    pub fn new(transport: Arc<dyn Transport>) -> Self {
+        Self {
+            transport,
+            clients: Default::default(),
+        }
+    }
+
+    pub fn clients(&self) -> impl Iterator<Item = PeerId> + '_ {
+        self.clients.iter().copied()
+    }
+}
+
+/// A chunk that was copied out of the clipmap, or that must be read from the backend.
+enum ServedChunk {
+    Air,
+    Compressed(CompressedChunk),
+    Decompressed(Box<Chunk>),
+    Stored,
+}
+
+/// Answers chunk requests, queues the edits sent by clients, and broadcasts the changes in the [`DirtyRegions`] to every client.
+///
+/// Client edits are tagged with the client's [`PeerId`], so an [`EditValidator`](feldspar_map::EditValidator) can reject them.
+/// Only the author is told whether its edit was applied or rejected, after the broadcast that includes the edit's changes.
+pub fn server_system(
+    mut server: ResMut<NetServer>,
+    backend: Res<Arc<dyn MapBackend>>,
+    clipmap: Res<ChunkClipMap>,
+    regions: Res<DirtyRegions>,
+    mut edits: ResMut<MapEdits>,
+    mut applied: EventReader<EditApplied>,
+    mut rejections: EventReader<EditRejected>,
+) {
+    while let Some(event) = server.transport.receive() {
+        match event {
+            TransportEvent::Connected(peer) => {
+                log::info!("Client {:?} connected", peer);
+                server.clients.insert(peer);
+            }
+            TransportEvent::Disconnected(peer) => {
+                log::info!("Client {:?} disconnected", peer);
+                server.clients.remove(&peer);
+            }
+            TransportEvent::Message(peer, bytes) => match ClientMessage::decode(&bytes) {
+                Ok(ClientMessage::RequestChunks { request, keys }) => {
+                    serve_chunks(&server.transport, &backend, &clipmap, peer, request, keys)
+                }
+                Ok(ClientMessage::Edit {
+                    sequence,
+                    coords,
+                    delta,
+                }) => {
+                    let tag = EditTag {
+                        author: peer.0,
+                        sequence,
+                    };
+                    edits.apply_delta_as(tag, coords, delta);
+                }
+                Err(e) => log::warn!("Dropped bad message from {:?}: {:?}", peer, e),
+            },
+        }
+    }
+
+    if server.clients.is_empty() {
+        return;
+    }
+
+    let broadcast = |message: ServerMessage| {
+        let bytes = message.encode();
+        for peer in server.clients() {
+            server.transport.send(peer, bytes.clone());
+        }
+    };
+
+    for (coords, delta) in regions.deltas() {
+        broadcast(ServerMessage::Delta {
+            coords,
+            delta: delta.clone(),
+        });
+    }
+
+    // The changes without deltas are sent as patches, split by chunk so none of them get much bigger than a chunk. Patches
+    // sample the current voxels, so they still apply after the deltas of the same chunks.
+    let mut sampler = SdfSampler::new(&clipmap);
+    for region in regions.iter_without_deltas() {
+        let ChunkUnits(chunks_extent) = in_chunk_extent(region);
+        for coords in chunks_extent.iter3() {
+            // Regions of chunks that aren't loaded here could only have changed in the database, e.g. from an import.
+            if is_resident(&clipmap, NodeKey::new(0, coords)) {
+                let VoxelUnits(chunk_extent) = chunk_extent_ivec3(ChunkUnits(coords));
+                let piece = VoxelUnits(chunk_extent.intersection(&region.into_inner()));
+                broadcast(ServerMessage::Patch(VoxelPatch::sample(
+                    piece,
+                    &mut sampler,
+                )));
+            }
+        }
+    }
+
+    let replies = applied
+        .iter()
+        .map(|applied| {
+            let message = ServerMessage::Applied {
+                sequence: applied.tag.sequence,
+            };
+            (applied.tag, message)
+        })
+        .chain(rejections.iter().map(|rejection| {
+            let message = ServerMessage::Rejected {
+                sequence: rejection.edit.tag.sequence,
+                extent: rejection.edit.extent,
+                reason: rejection.reason.clone(),
+            };
+            (rejection.edit.tag, message)
+        }));
+    for (tag, message) in replies {
+        let author = PeerId(tag.author);
+        if server.clients.contains(&author) {
+            server.transport.send(author, message.encode());
+        }
+    }
+}
+
+fn serve_chunks(
+    transport: &Arc<dyn Transport>,
+    backend: &Arc<dyn MapBackend>,
+    clipmap: &ChunkClipMap,
+    peer: PeerId,
+    request: u64,
+    keys: Vec<ChunkDbKey>,
+) {
+    // Resident chunks are copied now, since they could have edits that aren't flushed to the backend yet.
+    let served: Vec<_> = keys
+        .iter()
+        .map(|&key| resident_chunk(clipmap, key.into()))
+        .collect();
+
+    let transport = transport.clone();
+    let backend = backend.clone();
+    IoTaskPool::get()
+        .spawn(async move {
+            let stored_keys: Vec<_> = keys
+                .iter()
+                .zip(served.iter())
+                .filter(|(_, chunk)| matches!(chunk, ServedChunk::Stored))
+                .map(|(&key, _)| key)
+                .collect();
+            let mut stored = match backend.read_chunks(&stored_keys) {
+                Ok(stored) => stored.into_iter(),
+                Err(e) => {
+                    // The client's load will time out and try again.
+                    log::error!("Failed to read chunks requested by {:?}: {:?}", peer, e);
+                    return;
+                }
+            };
+
+            let codec = backend.codec();
+            let chunks = keys
+                .into_iter()
+                .zip(served)
+                .map(|(key, chunk)| {
+                    let chunk = match chunk {
+                        ServedChunk::Air => None,
+                        ServedChunk::Compressed(compressed) => Some(compressed),
+                        ServedChunk::Decompressed(chunk) => Some(chunk.compress_with(codec)),
+                        ServedChunk::Stored => match stored.next().unwrap() {
+                            Some(Change::Insert(compressed)) => Some(compressed),
+                            Some(Change::Remove) | None => None,
+                        },
+                    };
+                    (key, chunk)
+                })
+                .collect();
+            transport.send(peer, ServerMessage::Chunks { request, chunks }.encode());
+        })
+        .detach();
+}
+
+fn is_resident(clipmap: &ChunkClipMap, key: NodeKey<IVec3>) -> bool {
+    clipmap
+        .octree
+        .find_node(key)
+        .and_then(|ptr| clipmap.octree.get_value(ptr))
+        .map_or(false, |node| !node.state().is_loading())
+}
+
+fn resident_chunk(clipmap: &ChunkClipMap, key: NodeKey<IVec3>) -> ServedChunk {
+    if !is_resident(clipmap, key) {
+        return ServedChunk::Stored;
+    }
+    let ptr = clipmap.octree.find_node(key).unwrap();
+    let node = clipmap.octree.get_value(ptr).unwrap();
+    match node.uniform() {
+        Some(UniformChunk::Air) => ServedChunk::Air,
+        Some(uniform) => ServedChunk::Compressed(uniform.compress()),
+        None => node.get_decompressed().map_or(ServedChunk::Air, |chunk| {
+            ServedChunk::Decompressed(Box::new(*chunk.as_ref()))
+        }),
+    }
+}
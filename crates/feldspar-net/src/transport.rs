@@ -0,0 +1,114 @@
+use feldspar_map::core::SmallKeyHashMap;
+
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Identifies one end of a connection.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct PeerId(pub u64);
+
+impl PeerId {
+    /// Clients send all of their messages to this peer.
+    pub const SERVER: Self = Self(0);
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum TransportEvent {
+    Connected(PeerId),
+    Disconnected(PeerId),
+    Message(PeerId, Vec<u8>),
+}
+
+/// Delivers messages between the server and its clients.
+///
+/// Messages must arrive reliably and in the order they were sent. Clients only have one peer, [`PeerId::SERVER`].
+pub trait Transport: Send + Sync {
+    /// Queues `message` to be sent to `peer`. Messages to disconnected peers are dropped.
+    fn send(&self, peer: PeerId, message: Vec<u8>);
+
+    /// Returns the next event, or `None` if nothing has been received since the last call.
+    fn receive(&self) -> Option<TransportEvent>;
+}
+
+type EventQueue = Arc<Mutex<VecDeque<TransportEvent>>>;
+
+/// The server end of an in-process [`Transport`]. Clients connect with [`LocalServerTransport::connect`].
+#[derive(Default)]
+pub struct LocalServerTransport {
+    inbox: EventQueue,
+    clients: Mutex<SmallKeyHashMap<PeerId, EventQueue>>,
+    last_peer: AtomicU64,
+}
+
+impl LocalServerTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connects a new client, which is disconnected when the returned transport is dropped.
+    pub fn connect(&self) -> LocalClientTransport {
+        let peer = PeerId(self.last_peer.fetch_add(1, Ordering::Relaxed) + 1);
+        let client_inbox = EventQueue::default();
+        self.clients.lock().insert(peer, client_inbox.clone());
+        self.inbox.lock().push_back(TransportEvent::Connected(peer));
+        LocalClientTransport {
+            peer,
+            inbox: client_inbox,
+            server_inbox: self.inbox.clone(),
+        }
+    }
+}
+
+impl Transport for LocalServerTransport {
+    fn send(&self, peer: PeerId, message: Vec<u8>) {
+        if let Some(client_inbox) = self.clients.lock().get(&peer) {
+            client_inbox
+                .lock()
+                .push_back(TransportEvent::Message(PeerId::SERVER, message));
+        }
+    }
+
+    fn receive(&self) -> Option<TransportEvent> {
+        let event = self.inbox.lock().pop_front();
+        if let Some(TransportEvent::Disconnected(peer)) = &event {
+            self.clients.lock().remove(peer);
+        }
+        event
+    }
+}
+
+/// The client end of an in-process [`Transport`].
+pub struct LocalClientTransport {
+    peer: PeerId,
+    inbox: EventQueue,
+    server_inbox: EventQueue,
+}
+
+impl LocalClientTransport {
+    /// The ID that the server knows this client by.
+    pub fn peer(&self) -> PeerId {
+        self.peer
+    }
+}
+
+impl Transport for LocalClientTransport {
+    fn send(&self, _peer: PeerId, message: Vec<u8>) {
+        self.server_inbox
+            .lock()
+            .push_back(TransportEvent::Message(self.peer, message));
+    }
+
+    fn receive(&self) -> Option<TransportEvent> {
+        self.inbox.lock().pop_front()
+    }
+}
+
+impl Drop for LocalClientTransport {
+    fn drop(&mut self) {
+        self.server_inbox
+            .lock()
+            .push_back(TransportEvent::Disconnected(self.peer));
+    }
+}
@@ -0,0 +1,156 @@
+use feldspar_map::chunk::PADDED_CHUNK_EDGE;
+use feldspar_map::clipmap::PaddedChunk;
+use feldspar_map::core::glam::Vec3A;
+use feldspar_map::AoQuality;
+
+/// The brightness of a fully occluded vertex. Completely black corners look like holes.
+pub const MIN_AO_BRIGHTNESS: f32 = 0.3;
+
+/// Distances along the normal (in voxels) where [`surface_nets_ao`] samples the SDF. Each sample has half the weight of the
+/// previous one.
+const LOW_SAMPLE_DISTANCES: &[f32] = &[0.5, 1.0];
+const HIGH_SAMPLE_DISTANCES: &[f32] = &[0.5, 1.0, 2.0, 3.0];
+
+/// Estimates the ambient occlusion of each surface nets vertex from how much closer the surface is along the vertex normal than
+/// it would be in front of a flat wall, in the style of raymarched SDF occlusion.
+///
+/// The SDF saturates at one voxel, so samples further than that only detect solid voxels that are close to the sample. This is
+/// still enough to darken the ground under overhangs.
+///
+/// Returns a brightness from [`MIN_AO_BRIGHTNESS`] to 1 for each vertex, or all ones if `quality` is [`AoQuality::Off`].
+pub fn surface_nets_ao(
+    padded: &PaddedChunk,
+    positions: &[[f32; 3]],
+    normals: &[[f32; 3]],
+    quality: AoQuality,
+) -> Vec<f32> {
+    let distances = match quality {
+        AoQuality::Off => return vec![1.0; positions.len()],
+        AoQuality::Low => LOW_SAMPLE_DISTANCES,
+        AoQuality::High => HIGH_SAMPLE_DISTANCES,
+    };
+    positions
+        .iter()
+        .zip(normals)
+        .map(|(&p, &n)| {
+            let p = Vec3A::from(p);
+            let n = Vec3A::from(n).normalize_or_zero();
+            let mut occlusion = 0.0;
+            let mut max_occlusion = 0.0;
+            let mut weight = 1.0;
+            for &d in distances {
+                let expected = d.min(1.0);
+                let sdf = sample_sdf(padded, p + d * n);
+                occlusion += weight * (expected - sdf).clamp(0.0, expected);
+                max_occlusion += weight * expected;
+                weight *= 0.5;
+            }
+            occlusion_brightness(occlusion / max_occlusion)
+        })
+        .collect()
+}
+
+/// Trilinearly interpolates the SDF at `p`, in the padded chunk's voxel coordinates. Points outside of the padded chunk are
+/// clamped to its boundary.
+fn sample_sdf(padded: &PaddedChunk, p: Vec3A) -> f32 {
+    let max = (PADDED_CHUNK_EDGE - 1) as f32;
+    let p = p.clamp(Vec3A::ZERO, Vec3A::splat(max));
+    let min = p.floor().min(Vec3A::splat(max - 1.0));
+    let t = p - min;
+    let [x, y, z] = min.to_array().map(|c| c as usize);
+
+    let edge = PADDED_CHUNK_EDGE as usize;
+    let value = |dx: usize, dy: usize, dz: usize| {
+        let i = (x + dx) + edge * ((y + dy) + edge * (z + dz));
+        f32::from(padded.sdf[i])
+    };
+    let lerp = |a: f32, b: f32, t: f32| a + t * (b - a);
+    let x00 = lerp(value(0, 0, 0), value(1, 0, 0), t.x);
+    let x10 = lerp(value(0, 1, 0), value(1, 1, 0), t.x);
+    let x01 = lerp(value(0, 0, 1), value(1, 0, 1), t.x);
+    let x11 = lerp(value(0, 1, 1), value(1, 1, 1), t.x);
+    lerp(lerp(x00, x10, t.y), lerp(x01, x11, t.y), t.z)
+}
+
+/// Converts a fraction of occlusion into a brightness from [`MIN_AO_BRIGHTNESS`] to 1.
+fn occlusion_brightness(occlusion: f32) -> f32 {
+    1.0 - (1.0 - MIN_AO_BRIGHTNESS) * occlusion.clamp(0.0, 1.0)
+}
+
+/// The classic occlusion of a cube face corner, from the solidity of the 2 voxels beside the corner and the voxel diagonal to
+/// it, all on the open side of the face. Ranges from 0 (fully occluded) to 3 (open).
+///
+/// When both sides are solid, the corner is fully occluded regardless of the diagonal, since light can't get in.
+pub fn voxel_corner_ao(side1: bool, side2: bool, corner: bool) -> u8 {
+    if side1 && side2 {
+        0
+    } else {
+        3 - side1 as u8 - side2 as u8 - corner as u8
+    }
+}
+
+/// Converts a [`voxel_corner_ao`] into a brightness from [`MIN_AO_BRIGHTNESS`] to 1.
+pub fn voxel_ao_brightness(ao: u8) -> f32 {
+    occlusion_brightness(1.0 - f32::from(ao) / 3.0)
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use feldspar_map::chunk::{AMBIENT_SD8, PADDED_CHUNK_SIZE};
+    use feldspar_map::sdf::Sd8;
+
+    /// Ground at `y < 8`, and a ceiling at `y > 10` over `x < 9`.
+    fn ground_with_overhang() -> Box<PaddedChunk> {
+        let mut padded = Box::new(PaddedChunk {
+            sdf: [AMBIENT_SD8; PADDED_CHUNK_SIZE],
+            palette_ids: [0; PADDED_CHUNK_SIZE],
+            material_weights: None,
+            light: None,
+        });
+        let edge = PADDED_CHUNK_EDGE as usize;
+        for z in 0..edge {
+            for y in 0..edge {
+                for x in 0..edge {
+                    let ground = y as f32 - 7.5;
+                    let ceiling = if x < 9 { 10.0 - y as f32 } else { 1.0 };
+                    let i = x + edge * (y + edge * z);
+                    padded.sdf[i] = Sd8::from(ground.min(ceiling));
+                }
+            }
+        }
+        padded
+    }
+
+    #[test]
+    fn overhangs_occlude_the_ground() {
+        let padded = ground_with_overhang();
+        let positions = [[4.0, 7.5, 8.0], [14.0, 7.5, 8.0]];
+        let normals = [[0.0, 1.0, 0.0]; 2];
+
+        let off = surface_nets_ao(&padded, &positions, &normals, AoQuality::Off);
+        assert_eq!(off, [1.0, 1.0]);
+
+        let high = surface_nets_ao(&padded, &positions, &normals, AoQuality::High);
+        // Open ground isn't occluded, but ground under the ceiling is.
+        assert!((high[1] - 1.0).abs() < 0.02, "{:?}", high);
+        assert!(high[0] < 0.9, "{:?}", high);
+    }
+
+    #[test]
+    fn corner_ao_levels() {
+        assert_eq!(voxel_corner_ao(false, false, false), 3);
+        assert_eq!(voxel_corner_ao(false, false, true), 2);
+        assert_eq!(voxel_corner_ao(true, false, true), 1);
+        assert_eq!(voxel_corner_ao(true, true, false), 0);
+        assert_eq!(voxel_ao_brightness(3), 1.0);
+        assert_eq!(voxel_ao_brightness(0), MIN_AO_BRIGHTNESS);
+    }
+}
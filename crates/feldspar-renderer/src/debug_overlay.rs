@@ -0,0 +1,295 @@
+use feldspar_map::clipmap::{ChunkClipMap, ClipShape, Level, NodePtr, NodeState, VisitCommand};
+use feldspar_map::coordinates::chunk_extent_at_level_vec3a;
+use feldspar_map::core::glam::{IVec3, Vec3A};
+use feldspar_map::units::{ChunkUnits, VoxelUnits};
+use feldspar_map::world_transform::VoxelWorldTransform;
+use feldspar_map::{MapId, Witness};
+
+use bevy::prelude::*;
+use bevy::render::mesh::PrimitiveTopology;
+use bevy::render::view::NoFrustumCulling;
+use std::f32::consts::TAU;
+use std::ops::Range;
+
+/// Draws the bounds of the [`ChunkClipMap`] nodes, colored by their state, and the clip shape of every [`Witness`], to make
+/// the loading state machine visible while tuning a [`MapConfig`](feldspar_map::MapConfig).
+///
+/// Bevy doesn't have an immediate mode line renderer yet, so all of the lines are rebuilt into a single mesh every frame while
+/// the [`DebugOverlay`] is enabled.
+pub struct DebugOverlayPlugin;
+
+impl Plugin for DebugOverlayPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<DebugOverlay>()
+            .add_startup_system(setup_debug_overlay)
+            // The clipmap is done changing for the frame.
+            .add_system_to_stage(CoreStage::PostUpdate, debug_overlay_system);
+    }
+}
+
+/// Controls the [`DebugOverlayPlugin`] at runtime.
+#[derive(Clone, Debug)]
+pub struct DebugOverlay {
+    pub enabled: bool,
+    /// Pressing this key toggles `enabled`.
+    pub toggle_key: Option<KeyCode>,
+    pub show_nodes: bool,
+    /// Only nodes at these levels are drawn. Drawing every LOD0 node of a large clip region is slow.
+    pub levels: Range<Level>,
+    pub show_clip_shapes: bool,
+}
+
+impl Default for DebugOverlay {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            toggle_key: Some(KeyCode::F3),
+            show_nodes: true,
+            levels: 0..Level::MAX,
+            show_clip_shapes: true,
+        }
+    }
+}
+
+/// The color of a node that's claimed by a load batch, but not read yet.
+pub const LOAD_PENDING_COLOR: Color = Color::YELLOW;
+pub const LOADING_COLOR: Color = Color::ORANGE;
+pub const LOADED_COLOR: Color = Color::GREEN;
+/// The color of a node with changes that must be saved before it's evicted.
+pub const DIRTY_COLOR: Color = Color::RED;
+pub const CLIP_SHAPE_COLOR: Color = Color::CYAN;
+
+const CIRCLE_SEGMENTS: usize = 64;
+
+/// The color that the overlay draws for a node in `state`. Dirty nodes are highlighted above all other states.
+pub fn node_state_color(state: &NodeState) -> Color {
+    if state.is_dirty() {
+        DIRTY_COLOR
+    } else if state.is_loading() {
+        LOADING_COLOR
+    } else if state.has_load_pending() {
+        LOAD_PENDING_COLOR
+    } else {
+        LOADED_COLOR
+    }
+}
+
+struct DebugOverlayMesh {
+    entity: Entity,
+    mesh: Handle<Mesh>,
+}
+
+fn setup_debug_overlay(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let mesh = meshes.add(Mesh::new(PrimitiveTopology::LineList));
+    let material = materials.add(StandardMaterial {
+        base_color: Color::WHITE,
+        unlit: true,
+        ..Default::default()
+    });
+    let entity = commands
+        .spawn_bundle(PbrBundle {
+            mesh: mesh.clone(),
+            material,
+            visibility: Visibility { is_visible: false },
+            ..Default::default()
+        })
+        // The mesh changes every frame, so its bounding box would be stale.
+        .insert(NoFrustumCulling)
+        .id();
+    commands.insert_resource(DebugOverlayMesh { entity, mesh });
+}
+
+fn debug_overlay_system(
+    keys: Option<Res<Input<KeyCode>>>,
+    clipmap: Res<ChunkClipMap>,
+    world_transform: Res<VoxelWorldTransform>,
+    witness_transforms: Query<(&Witness, &Transform)>,
+    mut overlay_transforms: Query<&mut Transform, Without<Witness>>,
+    overlay_mesh: Res<DebugOverlayMesh>,
+    mut overlay: ResMut<DebugOverlay>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut visibility: Query<&mut Visibility>,
+) {
+    if let (Some(keys), Some(toggle_key)) = (keys, overlay.toggle_key) {
+        if keys.just_pressed(toggle_key) {
+            overlay.enabled = !overlay.enabled;
+        }
+    }
+
+    let mut lines = LineBuffer::default();
+    if overlay.enabled {
+        if overlay.show_nodes {
+            lines.push_nodes(&clipmap, overlay.levels.clone());
+        }
+        if overlay.show_clip_shapes {
+            let default_radius = clipmap.stream_config.clip_sphere_radius;
+            for (witness, tfm) in witness_transforms
+                .iter()
+                .filter(|(witness, _)| witness.map == MapId::PRIMARY)
+            {
+                let VoxelUnits(center) = world_transform.transform_to_voxel(tfm);
+                lines.push_clip_shape(
+                    center,
+                    witness.clip_shape_or(default_radius),
+                    CLIP_SHAPE_COLOR,
+                );
+            }
+        }
+    }
+
+    // Empty vertex buffers can't be drawn, so the mesh is left alone and hidden.
+    let is_visible = !lines.positions.is_empty();
+    if let Ok(mut visibility) = visibility.get_mut(overlay_mesh.entity) {
+        if visibility.is_visible != is_visible {
+            visibility.is_visible = is_visible;
+        }
+    }
+    if !is_visible {
+        return;
+    }
+    // Lines are drawn in voxel units.
+    if let Ok(mut tfm) = overlay_transforms.get_mut(overlay_mesh.entity) {
+        let placed = world_transform.voxel_local_transform(VoxelUnits(IVec3::ZERO));
+        if *tfm != placed {
+            *tfm = placed;
+        }
+    }
+    if let Some(mesh) = meshes.get_mut(&overlay_mesh.mesh) {
+        let num_vertices = lines.positions.len();
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, lines.positions);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0.0, 1.0, 0.0]; num_vertices]);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, lines.colors);
+    }
+}
+
+/// Vertices of a [`PrimitiveTopology::LineList`], two per line.
+#[derive(Default)]
+struct LineBuffer {
+    positions: Vec<[f32; 3]>,
+    colors: Vec<[f32; 4]>,
+}
+
+impl LineBuffer {
+    fn push_line(&mut self, a: Vec3A, b: Vec3A, color: Color) {
+        self.positions.extend([a.to_array(), b.to_array()]);
+        let color = color.as_linear_rgba_f32();
+        self.colors.extend([color, color]);
+    }
+
+    fn push_box(&mut self, min: Vec3A, max: Vec3A, color: Color) {
+        let corner = |i: usize| {
+            Vec3A::new(
+                if i & 1 == 0 { min.x } else { max.x },
+                if i & 2 == 0 { min.y } else { max.y },
+                if i & 4 == 0 { min.z } else { max.z },
+            )
+        };
+        // Each edge connects two corners that differ in one axis.
+        for i in 0..8 {
+            for axis_bit in [1, 2, 4] {
+                if i & axis_bit == 0 {
+                    self.push_line(corner(i), corner(i | axis_bit), color);
+                }
+            }
+        }
+    }
+
+    /// A circle of `radius` around `center`, perpendicular to `axis`.
+    fn push_circle(&mut self, center: Vec3A, axis: usize, radius: f32, color: Color) {
+        let point = |angle: f32| {
+            let (sin, cos) = angle.sin_cos();
+            let mut offset = [0.0; 3];
+            offset[(axis + 1) % 3] = radius * cos;
+            offset[(axis + 2) % 3] = radius * sin;
+            center + Vec3A::from(offset)
+        };
+        for i in 0..CIRCLE_SEGMENTS {
+            let angle = |i: usize| TAU * i as f32 / CIRCLE_SEGMENTS as f32;
+            self.push_line(point(angle(i)), point(angle(i + 1)), color);
+        }
+    }
+
+    /// Spheres are approximated with a circle around each axis, and cylinders with their caps and four lines between them.
+    fn push_clip_shape(&mut self, center: Vec3A, shape: ClipShape, color: Color) {
+        match shape {
+            ClipShape::Sphere { radius } => {
+                for axis in 0..3 {
+                    self.push_circle(center, axis, radius, color);
+                }
+            }
+            ClipShape::Box { half_extent } => {
+                self.push_box(center - half_extent, center + half_extent, color);
+            }
+            ClipShape::VerticalCylinder {
+                radius,
+                half_height,
+            } => {
+                let up = Vec3A::new(0.0, half_height, 0.0);
+                self.push_circle(center - up, 1, radius, color);
+                self.push_circle(center + up, 1, radius, color);
+                for side in [Vec3A::X, Vec3A::Z, -Vec3A::X, -Vec3A::Z] {
+                    let side = center + radius * side;
+                    self.push_line(side - up, side + up, color);
+                }
+            }
+        }
+    }
+
+    fn push_nodes(&mut self, clipmap: &ChunkClipMap, levels: Range<Level>) {
+        for (root_key, root_node) in clipmap.octree.iter_roots() {
+            clipmap.octree.visit_tree_depth_first(
+                NodePtr::new(root_key.level, root_node.self_ptr),
+                root_key.coordinates,
+                0,
+                |ptr, coords| {
+                    let level = ptr.level();
+                    if levels.contains(&level) {
+                        let state = clipmap.octree.get_value(ptr).unwrap().state();
+                        let VoxelUnits(extent) =
+                            chunk_extent_at_level_vec3a(level, ChunkUnits(coords));
+                        self.push_box(
+                            extent.minimum,
+                            extent.least_upper_bound(),
+                            node_state_color(state),
+                        );
+                    }
+                    if level <= levels.start {
+                        VisitCommand::SkipDescendants
+                    } else {
+                        VisitCommand::Continue
+                    }
+                },
+            );
+        }
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn box_has_twelve_edges() {
+        let mut lines = LineBuffer::default();
+        lines.push_box(Vec3A::ZERO, Vec3A::ONE, Color::WHITE);
+        assert_eq!(lines.positions.len(), 24);
+        assert_eq!(lines.colors.len(), 24);
+
+        // Every edge has length 1.
+        for edge in lines.positions.chunks_exact(2) {
+            let [a, b] = [edge[0], edge[1]].map(Vec3A::from);
+            assert_eq!(a.distance(b), 1.0);
+        }
+    }
+}
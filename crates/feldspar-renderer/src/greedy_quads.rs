@@ -0,0 +1,597 @@
+use crate::{voxel_ao_brightness, voxel_corner_ao};
+
+use feldspar_map::chunk::{MaterialWeights, CHUNK_EDGE, MAX_LIGHT, PADDED_CHUNK_EDGE};
+use feldspar_map::clipmap::PaddedChunk;
+use feldspar_map::material::{MaterialClass, MaterialClasses};
+use feldspar_map::palette::PaletteId8;
+use feldspar_map::AoQuality;
+
+/// The output of [`greedy_quads`].
+///
+/// Positions are in voxel units relative to the chunk's minimum, where voxel `p` is the unit cube with minimum corner `p`.
+#[derive(Clone, Debug, Default)]
+pub struct GreedyQuadsBuffer {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    /// The [`PaletteId8`] of the solid voxel behind each vertex's face.
+    pub material_ids: Vec<u32>,
+    /// The [`MaterialWeights`] of the solid voxel behind each vertex's face.
+    pub material_weights: Vec<[u8; 4]>,
+    /// The brightness of each vertex after ambient occlusion, or 1 if ambient occlusion is off.
+    pub ambient_occlusion: Vec<f32>,
+    /// The light level of each vertex from 0 to 1, or 1 if the chunk wasn't lit.
+    pub light: Vec<f32>,
+    pub indices: Vec<u32>,
+}
+
+impl GreedyQuadsBuffer {
+    pub fn reset(&mut self) {
+        self.positions.clear();
+        self.normals.clear();
+        self.material_ids.clear();
+        self.material_weights.clear();
+        self.ambient_occlusion.clear();
+        self.light.clear();
+        self.indices.clear();
+    }
+
+    pub fn num_quads(&self) -> usize {
+        self.positions.len() / 4
+    }
+}
+
+/// A visible face in a slice of the chunk.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct FaceSample {
+    pub material: PaletteId8,
+    pub weights: MaterialWeights,
+    /// The [`voxel_corner_ao`] of each corner, in the same order as the quad's vertices.
+    pub ao: [u8; 4],
+    /// The light level of each corner, averaged over the empty voxels around it in front of the face.
+    pub light: [u8; 4],
+    /// Whether the face points in the positive direction of the slice's axis.
+    pub positive: bool,
+}
+
+/// The location of the unit face between the voxel at `slice` along `axis` and the next voxel.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct FaceCell {
+    pub axis: usize,
+    pub slice: usize,
+    pub u: usize,
+    pub v: usize,
+}
+
+/// One [`GreedyQuadsBuffer`] for each [`MaterialClass`], so that each class can be drawn with its own material.
+#[derive(Clone, Debug, Default)]
+pub struct ClassifiedQuadsBuffer {
+    buffers: [GreedyQuadsBuffer; MaterialClass::ALL.len()],
+}
+
+impl ClassifiedQuadsBuffer {
+    pub fn reset(&mut self) {
+        for buffer in self.buffers.iter_mut() {
+            buffer.reset();
+        }
+    }
+
+    pub fn get(&self, class: MaterialClass) -> &GreedyQuadsBuffer {
+        &self.buffers[class.index()]
+    }
+
+    /// Moves the quads of `class` out of this buffer.
+    pub fn take(&mut self, class: MaterialClass) -> GreedyQuadsBuffer {
+        std::mem::take(&mut self.buffers[class.index()])
+    }
+
+    pub fn num_quads(&self) -> usize {
+        self.buffers.iter().map(GreedyQuadsBuffer::num_quads).sum()
+    }
+}
+
+/// Generates axis-aligned cube faces for all solid voxels in `padded` that touch a non-solid voxel, merging coplanar faces of
+/// the same material and [`MaterialWeights`] into larger quads. A voxel is solid when its signed distance is negative.
+///
+/// Each chunk only generates the faces between its own voxels and their positive neighbors, i.e. the face between voxels `x`
+/// and `x + 1` for `x` in `[0, CHUNK_EDGE)`. The faces on the negative boundary are generated by the negative neighbors, so
+/// every face in the map is generated exactly once.
+///
+/// Unless `ambient_occlusion` is [`AoQuality::Off`], each vertex is darkened by the solid voxels around it in front of its face,
+/// and only faces with the same occlusion at every corner are merged. Both qualities are the same for cubes. The padded chunk
+/// doesn't include the negative neighbors, so voxels there are treated as empty, which can leave faces on the negative boundary
+/// of the chunk slightly too bright.
+///
+/// If the padded chunk has light levels, each corner gets the mean light of the empty voxels that touch it in front of the
+/// face, the same voxels that occlude it, so light fades smoothly across blocky terrain.
+///
+/// Every material is treated as [`MaterialClass::Opaque`]. See [`classified_greedy_quads`] for the other classes.
+pub fn greedy_quads(
+    padded: &PaddedChunk,
+    ambient_occlusion: AoQuality,
+    buffer: &mut GreedyQuadsBuffer,
+) {
+    buffer.reset();
+    merge_faces(
+        padded,
+        ambient_occlusion,
+        &MaterialClasses::default(),
+        std::slice::from_mut(buffer),
+        |_| 0,
+    );
+}
+
+/// Like [`greedy_quads`], but the faces of each [`MaterialClass`] go into their own buffer, and faces are culled by the
+/// [`MaterialClasses::hides`] rules. A face between two solid voxels that don't hide each other, like glass against water, is
+/// generated on both sides.
+pub fn classified_greedy_quads(
+    padded: &PaddedChunk,
+    ambient_occlusion: AoQuality,
+    classes: &MaterialClasses,
+    buffers: &mut ClassifiedQuadsBuffer,
+) {
+    buffers.reset();
+    merge_faces(
+        padded,
+        ambient_occlusion,
+        classes,
+        &mut buffers.buffers,
+        |class| class.index(),
+    );
+}
+
+/// Greedily merges the visible faces into quads in the buffer picked by `buffer_index`.
+fn merge_faces(
+    padded: &PaddedChunk,
+    ambient_occlusion: AoQuality,
+    classes: &MaterialClasses,
+    buffers: &mut [GreedyQuadsBuffer],
+    buffer_index: impl Fn(MaterialClass) -> usize,
+) {
+    let edge = CHUNK_EDGE as usize;
+    let mut mask = [None; (CHUNK_EDGE * CHUNK_EDGE) as usize];
+    for axis in 0..3 {
+        // Cyclic order makes `u x v` point along `axis`.
+        let u_axis = (axis + 1) % 3;
+        let v_axis = (axis + 2) % 3;
+
+        for slice in 0..edge {
+            // Faces pointing either way can share a cell when neither voxel hides the other, so each direction gets its own
+            // pass.
+            for positive in [true, false] {
+                // Find the visible faces between this slice and the next.
+                for v in 0..edge {
+                    for u in 0..edge {
+                        let cell = FaceCell { axis, slice, u, v };
+                        mask[u + edge * v] =
+                            face_sample(padded, ambient_occlusion, classes, cell, positive);
+                    }
+                }
+
+                // Greedily merge the faces into quads, first along u, then along v.
+                for v in 0..edge {
+                    let mut u = 0;
+                    while u < edge {
+                        let face = if let Some(face) = mask[u + edge * v] {
+                            face
+                        } else {
+                            u += 1;
+                            continue;
+                        };
+
+                        let mut width = 1;
+                        while u + width < edge && mask[u + width + edge * v] == Some(face) {
+                            width += 1;
+                        }
+                        let mut height = 1;
+                        'grow: while v + height < edge {
+                            for du in 0..width {
+                                if mask[u + du + edge * (v + height)] != Some(face) {
+                                    break 'grow;
+                                }
+                            }
+                            height += 1;
+                        }
+
+                        for dv in 0..height {
+                            for du in 0..width {
+                                mask[u + du + edge * (v + dv)] = None;
+                            }
+                        }
+
+                        let mut origin = [0.0; 3];
+                        origin[axis] = (slice + 1) as f32;
+                        origin[u_axis] = u as f32;
+                        origin[v_axis] = v as f32;
+                        let mut du = [0.0; 3];
+                        du[u_axis] = width as f32;
+                        let mut dv = [0.0; 3];
+                        dv[v_axis] = height as f32;
+                        let mut normal = [0.0; 3];
+                        normal[axis] = if face.positive { 1.0 } else { -1.0 };
+                        let buffer = &mut buffers[buffer_index(classes.get(face.material))];
+                        push_quad(buffer, origin, du, dv, normal, face);
+
+                        u += width;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns the face at `cell` that points in the `positive` direction, or the opposite one, if the voxel behind it is solid
+/// and the voxel in front of it doesn't hide it.
+pub(crate) fn face_sample(
+    padded: &PaddedChunk,
+    ambient_occlusion: AoQuality,
+    classes: &MaterialClasses,
+    cell: FaceCell,
+    positive: bool,
+) -> Option<FaceSample> {
+    let FaceCell { axis, slice, u, v } = cell;
+    // Cyclic order makes `u x v` point along `axis`.
+    let u_axis = (axis + 1) % 3;
+    let v_axis = (axis + 2) % 3;
+
+    let padded_edge = PADDED_CHUNK_EDGE as usize;
+    let sample = |p: [usize; 3]| {
+        let i = p[0] + padded_edge * (p[1] + padded_edge * p[2]);
+        let weights = padded
+            .material_weights
+            .as_ref()
+            .map_or(MaterialWeights::default(), |w| w[i]);
+        (padded.sdf[i].0 < 0).then(|| (padded.palette_ids[i], weights))
+    };
+    let is_solid = |p: [i32; 3]| padded_index(p).map_or(false, |i| padded.sdf[i].0 < 0);
+    // `None` for solid voxels and voxels outside of the padded chunk.
+    let light_level = |p: [i32; 3]| {
+        let light = padded.light.as_ref()?;
+        let i = padded_index(p)?;
+        (padded.sdf[i].0 >= 0).then(|| light[i])
+    };
+
+    let mut p = [0; 3];
+    p[axis] = slice;
+    p[u_axis] = u;
+    p[v_axis] = v;
+    let lower = sample(p);
+    p[axis] += 1;
+    let upper = sample(p);
+    let (behind, in_front) = if positive {
+        (lower, upper)
+    } else {
+        (upper, lower)
+    };
+    let (material, weights) = behind?;
+    if let Some((neighbor, _)) = in_front {
+        if classes.hides(neighbor, material) {
+            return None;
+        }
+    }
+
+    // Sample the layer in front of the face, which is empty unless it's a voxel that doesn't hide the face.
+    let mut air = [0; 3];
+    air[axis] = (if positive { slice + 1 } else { slice }) as i32;
+    air[u_axis] = u as i32;
+    air[v_axis] = v as i32;
+    let offset = |du: i32, dv: i32| {
+        let mut p = air;
+        p[u_axis] += du;
+        p[v_axis] += dv;
+        p
+    };
+    let ao = if ambient_occlusion == AoQuality::Off {
+        [3; 4]
+    } else {
+        CORNER_DIRECTIONS.map(|(du, dv)| {
+            voxel_corner_ao(
+                is_solid(offset(du, 0)),
+                is_solid(offset(0, dv)),
+                is_solid(offset(du, dv)),
+            )
+        })
+    };
+    let light = if padded.light.is_some() {
+        CORNER_DIRECTIONS.map(|(du, dv)| {
+            // Light can't reach the diagonal voxel through two solid sides.
+            let blocked = is_solid(offset(du, 0)) && is_solid(offset(0, dv));
+            let diagonal = (!blocked).then(|| offset(du, dv));
+            let samples = [
+                Some(offset(0, 0)),
+                Some(offset(du, 0)),
+                Some(offset(0, dv)),
+                diagonal,
+            ];
+            mean_light(samples.into_iter().flatten().filter_map(light_level))
+        })
+    } else {
+        [MAX_LIGHT; 4]
+    };
+    Some(FaceSample {
+        material,
+        weights,
+        ao,
+        light,
+        positive,
+    })
+}
+
+/// The corners of a unit face, in the same order as the quad's vertices, as directions along `u` and `v`.
+const CORNER_DIRECTIONS: [(i32, i32); 4] = [(-1, -1), (1, -1), (1, 1), (-1, 1)];
+
+fn padded_index(p: [i32; 3]) -> Option<usize> {
+    if p.iter().any(|c| !(0..PADDED_CHUNK_EDGE).contains(c)) {
+        return None;
+    }
+    let [x, y, z] = p;
+    Some((x + PADDED_CHUNK_EDGE * (y + PADDED_CHUNK_EDGE * z)) as usize)
+}
+
+fn mean_light(levels: impl Iterator<Item = u8>) -> u8 {
+    let (sum, count) = levels.fold((0, 0), |(sum, count), level| {
+        (sum + u32::from(level), count + 1)
+    });
+    if count == 0 {
+        MAX_LIGHT
+    } else {
+        ((sum + count / 2) / count) as u8
+    }
+}
+
+fn push_quad(
+    buffer: &mut GreedyQuadsBuffer,
+    origin: [f32; 3],
+    du: [f32; 3],
+    dv: [f32; 3],
+    normal: [f32; 3],
+    face: FaceSample,
+) {
+    let add = |a: [f32; 3], b: [f32; 3]| [a[0] + b[0], a[1] + b[1], a[2] + b[2]];
+
+    let start = buffer.positions.len() as u32;
+    buffer.positions.extend_from_slice(&[
+        origin,
+        add(origin, du),
+        add(add(origin, du), dv),
+        add(origin, dv),
+    ]);
+    buffer.normals.extend_from_slice(&[normal; 4]);
+    buffer
+        .material_ids
+        .extend_from_slice(&[face.material as u32; 4]);
+    buffer
+        .material_weights
+        .extend_from_slice(&[face.weights.0; 4]);
+    let ao = face.ao.map(voxel_ao_brightness);
+    let light = face.light.map(|l| f32::from(l) / f32::from(MAX_LIGHT));
+    buffer.ambient_occlusion.extend_from_slice(&ao);
+    buffer.light.extend_from_slice(&light);
+    // Counter-clockwise when viewed from the side the normal points to. Split the quad along the diagonal between the brighter
+    // pair of corners, otherwise the shadow of one corner bleeds across both triangles.
+    let brightness = [0, 1, 2, 3].map(|i| ao[i] * light[i]);
+    let flip = brightness[0] + brightness[2] < brightness[1] + brightness[3];
+    let corners = match (face.positive, flip) {
+        (true, false) => [0, 1, 2, 0, 2, 3],
+        (true, true) => [0, 1, 3, 1, 2, 3],
+        (false, false) => [0, 2, 1, 0, 3, 2],
+        (false, true) => [0, 3, 1, 1, 3, 2],
+    };
+    buffer.indices.extend(corners.iter().map(|c| start + c));
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MIN_AO_BRIGHTNESS;
+    use feldspar_map::chunk::{AMBIENT_SD8, PADDED_CHUNK_SIZE};
+    use feldspar_map::sdf::Sd8;
+
+    fn empty_padded_chunk() -> Box<PaddedChunk> {
+        Box::new(PaddedChunk {
+            sdf: [AMBIENT_SD8; PADDED_CHUNK_SIZE],
+            palette_ids: [0; PADDED_CHUNK_SIZE],
+            material_weights: None,
+            light: None,
+        })
+    }
+
+    fn set_solid(padded: &mut PaddedChunk, p: [usize; 3], material: PaletteId8) {
+        let padded_edge = PADDED_CHUNK_EDGE as usize;
+        let i = p[0] + padded_edge * (p[1] + padded_edge * p[2]);
+        padded.sdf[i] = Sd8::MIN;
+        padded.palette_ids[i] = material;
+    }
+
+    #[test]
+    fn single_voxel_has_six_faces() {
+        let mut padded = empty_padded_chunk();
+        set_solid(&mut padded, [3, 4, 5], 7);
+
+        let mut buffer = GreedyQuadsBuffer::default();
+        greedy_quads(&padded, AoQuality::Off, &mut buffer);
+
+        assert_eq!(buffer.num_quads(), 6);
+        assert_eq!(buffer.indices.len(), 36);
+        assert!(buffer.material_ids.iter().all(|&id| id == 7));
+        for p in buffer.positions.iter() {
+            assert!((3.0..=4.0).contains(&p[0]));
+            assert!((4.0..=5.0).contains(&p[1]));
+            assert!((5.0..=6.0).contains(&p[2]));
+        }
+    }
+
+    #[test]
+    fn coplanar_faces_merge_by_material() {
+        // A horizontal slab filling the chunk at y = 0, with two materials split at x = 8.
+        let mut padded = empty_padded_chunk();
+        for z in 0..PADDED_CHUNK_EDGE as usize {
+            for x in 0..PADDED_CHUNK_EDGE as usize {
+                set_solid(&mut padded, [x, 0, z], if x < 8 { 1 } else { 2 });
+            }
+        }
+
+        let mut buffer = GreedyQuadsBuffer::default();
+        greedy_quads(&padded, AoQuality::Off, &mut buffer);
+
+        // The top face is one quad per material. The slab continues into the padding, so there are no side faces, and the
+        // bottom face belongs to the chunk below.
+        assert_eq!(buffer.num_quads(), 2);
+        assert!(buffer.normals.iter().all(|n| *n == [0.0, 1.0, 0.0]));
+        assert!(buffer.material_ids[..4].iter().all(|&id| id == 1));
+        assert!(buffer.material_ids[4..].iter().all(|&id| id == 2));
+    }
+
+    #[test]
+    fn faces_with_different_weights_are_not_merged() {
+        let mut padded = empty_padded_chunk();
+        for z in 0..PADDED_CHUNK_EDGE as usize {
+            for x in 0..PADDED_CHUNK_EDGE as usize {
+                set_solid(&mut padded, [x, 0, z], 1);
+            }
+        }
+        let mut weights = Box::new([MaterialWeights::default(); PADDED_CHUNK_SIZE]);
+        let rock = MaterialWeights([0, 255, 0, 0]);
+        // Only the voxel at the minimum corner is rock.
+        weights[0] = rock;
+        padded.material_weights = Some(weights);
+
+        let mut buffer = GreedyQuadsBuffer::default();
+        greedy_quads(&padded, AoQuality::Off, &mut buffer);
+
+        assert!(buffer.num_quads() > 1);
+        assert_eq!(buffer.material_weights.len(), buffer.positions.len());
+        assert!(buffer.material_weights[..4].iter().all(|&w| w == rock.0));
+        assert!(buffer.material_weights[4..]
+            .iter()
+            .all(|&w| w == MaterialWeights::default().0));
+    }
+
+    #[test]
+    fn inner_corners_are_occluded() {
+        // A floor at y = 0 with walls at x = 0 and z = 0, so the floor's corner at the origin is in an inner corner.
+        let mut padded = empty_padded_chunk();
+        for a in 0..PADDED_CHUNK_EDGE as usize {
+            for b in 0..PADDED_CHUNK_EDGE as usize {
+                set_solid(&mut padded, [a, 0, b], 1);
+                set_solid(&mut padded, [0, a, b], 1);
+                set_solid(&mut padded, [a, b, 0], 1);
+            }
+        }
+
+        let mut buffer = GreedyQuadsBuffer::default();
+        greedy_quads(&padded, AoQuality::Low, &mut buffer);
+        assert_eq!(buffer.ambient_occlusion.len(), buffer.positions.len());
+
+        let floor_brightness = |x: f32, z: f32| {
+            buffer
+                .positions
+                .iter()
+                .zip(buffer.normals.iter())
+                .zip(buffer.ambient_occlusion.iter())
+                .filter(|((p, n), _)| **n == [0.0, 1.0, 0.0] && p[0] == x && p[2] == z)
+                .map(|(_, &ao)| ao)
+                .fold(f32::INFINITY, f32::min)
+        };
+        // The inner corner is fully occluded, but faces far from the walls aren't occluded at all.
+        assert_eq!(floor_brightness(1.0, 1.0), MIN_AO_BRIGHTNESS);
+        assert_eq!(floor_brightness(16.0, 16.0), 1.0);
+
+        // Occlusion stops the floor from merging into one quad.
+        let mut unoccluded = GreedyQuadsBuffer::default();
+        greedy_quads(&padded, AoQuality::Off, &mut unoccluded);
+        assert!(buffer.num_quads() > unoccluded.num_quads());
+        assert!(unoccluded.ambient_occlusion.iter().all(|&ao| ao == 1.0));
+    }
+
+    #[test]
+    fn vertices_get_the_light_in_front_of_them() {
+        let mut padded = empty_padded_chunk();
+        for z in 0..PADDED_CHUNK_EDGE as usize {
+            for x in 0..PADDED_CHUNK_EDGE as usize {
+                set_solid(&mut padded, [x, 0, z], 1);
+            }
+        }
+        let mut light = Box::new([MAX_LIGHT; PADDED_CHUNK_SIZE]);
+        let padded_edge = PADDED_CHUNK_EDGE as usize;
+        light[5 + padded_edge * (1 + padded_edge * 5)] = 3;
+        padded.light = Some(light);
+
+        let mut buffer = GreedyQuadsBuffer::default();
+        greedy_quads(&padded, AoQuality::Off, &mut buffer);
+        assert_eq!(buffer.light.len(), buffer.positions.len());
+
+        // Each corner around the dark voxel averages it with 3 fully lit voxels.
+        for (p, &light) in buffer.positions.iter().zip(buffer.light.iter()) {
+            if *p == [5.0, 1.0, 5.0] {
+                assert_eq!(light, 12.0 / 15.0);
+            }
+            if *p == [16.0, 1.0, 16.0] {
+                assert_eq!(light, 1.0);
+            }
+        }
+        assert!(buffer.light.iter().any(|&l| l < 1.0));
+    }
+
+    #[test]
+    fn transparent_faces_are_culled_and_split_by_class() {
+        let (stone, glass, water) = (1, 2, 3);
+        let mut classes = MaterialClasses::default();
+        classes.set(glass, MaterialClass::Transparent);
+        classes.set(water, MaterialClass::Liquid);
+
+        // A stone voxel, then two glass voxels, then a water voxel, along x.
+        let mut padded = empty_padded_chunk();
+        set_solid(&mut padded, [2, 5, 5], stone);
+        set_solid(&mut padded, [3, 5, 5], glass);
+        set_solid(&mut padded, [4, 5, 5], glass);
+        set_solid(&mut padded, [5, 5, 5], water);
+
+        let mut buffers = ClassifiedQuadsBuffer::default();
+        classified_greedy_quads(&padded, AoQuality::Off, &classes, &mut buffers);
+
+        // The stone shows its face to the glass, but the glass is hidden behind the stone.
+        let opaque = buffers.get(MaterialClass::Opaque);
+        assert_eq!(opaque.num_quads(), 6);
+        assert!(opaque.material_ids.iter().all(|&id| id == u32::from(stone)));
+        // The glass is merged into one box without a face between its voxels, and it shows a face to the water.
+        let transparent = buffers.get(MaterialClass::Transparent);
+        assert_eq!(transparent.num_quads(), 5);
+        assert!(transparent
+            .positions
+            .iter()
+            .all(|p| (3.0..=5.0).contains(&p[0])));
+        assert!(transparent.normals.contains(&[1.0, 0.0, 0.0]));
+        assert!(!transparent.normals.contains(&[-1.0, 0.0, 0.0]));
+        // The water shows its face to the glass as well.
+        let liquid = buffers.get(MaterialClass::Liquid);
+        assert_eq!(liquid.num_quads(), 6);
+        assert_eq!(buffers.num_quads(), 17);
+        assert_eq!(buffers.get(MaterialClass::Cutout).num_quads(), 0);
+
+        // Without classes, only the outside of the row is meshed, with the glass sides still merged.
+        let mut buffer = GreedyQuadsBuffer::default();
+        greedy_quads(&padded, AoQuality::Off, &mut buffer);
+        assert_eq!(buffer.num_quads(), 2 + 4 * 3);
+    }
+
+    #[test]
+    fn face_against_positive_neighbor_is_generated() {
+        let mut padded = empty_padded_chunk();
+        set_solid(&mut padded, [16, 0, 0], 3);
+
+        let mut buffer = GreedyQuadsBuffer::default();
+        greedy_quads(&padded, AoQuality::Off, &mut buffer);
+
+        // Only the face between voxel 15 and the neighbor's voxel belongs to this chunk.
+        assert_eq!(buffer.num_quads(), 1);
+        assert_eq!(buffer.normals[0], [-1.0, 0.0, 0.0]);
+        assert!(buffer.positions.iter().all(|p| p[0] == 16.0));
+    }
+}
@@ -0,0 +1,574 @@
+use crate::greedy_quads::{face_sample, FaceCell};
+
+use bevy::asset::load_internal_asset;
+use bevy::core_pipeline::core_3d::Opaque3d;
+use bevy::ecs::query::QueryItem;
+use bevy::ecs::system::lifetimeless::{Read, SQuery, SRes};
+use bevy::ecs::system::SystemParamItem;
+use bevy::pbr::{
+    MeshPipeline, MeshPipelineKey, MeshUniform, SetMeshBindGroup, SetMeshViewBindGroup,
+};
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::render::extract_component::{ExtractComponent, ExtractComponentPlugin};
+use bevy::render::extract_resource::{ExtractResource, ExtractResourcePlugin};
+use bevy::render::mesh::{GpuBufferInfo, Indices, MeshVertexBufferLayout, PrimitiveTopology};
+use bevy::render::render_asset::RenderAssets;
+use bevy::render::render_phase::{
+    AddRenderCommand, DrawFunctions, EntityRenderCommand, RenderCommandResult, RenderPhase,
+    SetItemPipeline, TrackedRenderPass,
+};
+use bevy::render::render_resource::*;
+use bevy::render::renderer::{RenderDevice, RenderQueue};
+use bevy::render::view::{ExtractedView, VisibleEntities};
+use bevy::render::{RenderApp, RenderStage};
+use bevy::utils::{HashMap, HashSet};
+use bytemuck::{Pod, Zeroable};
+use feldspar_map::chunk::CHUNK_EDGE;
+use feldspar_map::clipmap::PaddedChunk;
+use feldspar_map::material::MaterialClasses;
+use feldspar_map::palette::PaletteId8;
+use feldspar_map::AoQuality;
+use std::sync::Arc;
+
+pub const INSTANCED_FACES_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 0x6665_6c64_7370_6172);
+
+/// Chunks bigger than 16³ need more than 4 bits for each voxel coordinate, so their faces don't fit into 32 bits.
+const WIDE_FACES: bool = CHUNK_EDGE > 16;
+/// The number of 32-bit words in a [`FaceInstance`].
+const FACE_WORDS: usize = if WIDE_FACES { 2 } else { 1 };
+
+/// One visible cube face, packed into 32 bits for [`MeshMode::InstancedFaces`](feldspar_map::MeshMode::InstancedFaces).
+///
+/// From the lowest bit:
+///
+/// - 4 bits for each coordinate of the voxel behind the face along its axis, relative to the chunk's minimum
+/// - 2 bits for the axis
+/// - 1 bit that's set if the face points in the positive direction
+/// - 8 bits for the [`PaletteId8`] of the solid voxel
+/// - 2 bits for the [`voxel_corner_ao`](crate::voxel_corner_ao) of each corner, in the same order as [`greedy_quads`](crate::greedy_quads)
+///   vertices
+///
+/// The face is always on the positive side of that voxel, so a face pointing in the negative direction belongs to the solid
+/// voxel in front of it.
+///
+/// When the crate is built for chunks bigger than 16³, faces take two words instead. The first word has the same fields
+/// without the coordinates, starting with the axis at the lowest bit, and the second has 8 bits for each coordinate.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[repr(transparent)]
+pub struct FaceInstance(pub [u32; FACE_WORDS]);
+
+unsafe impl Zeroable for FaceInstance {}
+unsafe impl Pod for FaceInstance {}
+
+impl FaceInstance {
+    pub fn new(
+        voxel: [u8; 3],
+        axis: u8,
+        positive: bool,
+        material: PaletteId8,
+        ao: [u8; 4],
+    ) -> Self {
+        debug_assert!(voxel.iter().all(|&c| i32::from(c) < CHUNK_EDGE) && axis < 3);
+        let [x, y, z] = voxel.map(u32::from);
+        let ao = ao
+            .iter()
+            .enumerate()
+            .fold(0, |bits, (i, &ao)| bits | u32::from(ao.min(3)) << (2 * i));
+        let fields =
+            u32::from(axis) | u32::from(positive) << 2 | u32::from(material) << 3 | ao << 11;
+        let mut words = [0; FACE_WORDS];
+        if WIDE_FACES {
+            words[0] = fields;
+            words[FACE_WORDS - 1] = x | y << 8 | z << 16;
+        } else {
+            words[0] = x | y << 4 | z << 8 | fields << 12;
+        }
+        Self(words)
+    }
+
+    pub fn voxel(self) -> [u8; 3] {
+        if WIDE_FACES {
+            [0, 8, 16].map(|shift| ((self.0[FACE_WORDS - 1] >> shift) & 0xFF) as u8)
+        } else {
+            [0, 4, 8].map(|shift| ((self.0[0] >> shift) & 0xF) as u8)
+        }
+    }
+
+    pub fn axis(self) -> u8 {
+        (self.fields() & 0b11) as u8
+    }
+
+    pub fn is_positive(self) -> bool {
+        (self.fields() >> 2) & 1 == 1
+    }
+
+    pub fn material(self) -> PaletteId8 {
+        ((self.fields() >> 3) & 0xFF) as PaletteId8
+    }
+
+    pub fn ao(self) -> [u8; 4] {
+        [0, 1, 2, 3].map(|i| ((self.fields() >> (11 + 2 * i)) & 0b11) as u8)
+    }
+
+    /// Every field but the voxel coordinates, starting with the axis at the lowest bit.
+    fn fields(self) -> u32 {
+        if WIDE_FACES {
+            self.0[0]
+        } else {
+            self.0[0] >> 12
+        }
+    }
+}
+
+/// Finds the same faces as [`greedy_quads`](crate::greedy_quads), without merging them, and packs each into a
+/// [`FaceInstance`]. This is much cheaper than building a mesh, and each face takes 4 bytes instead of the 48 bytes of a quad's
+/// vertex positions. Every material is treated as [`MaterialClass::Opaque`](feldspar_map::material::MaterialClass::Opaque).
+pub fn face_instances(
+    padded: &PaddedChunk,
+    ambient_occlusion: AoQuality,
+    instances: &mut Vec<FaceInstance>,
+) {
+    instances.clear();
+    let classes = MaterialClasses::default();
+    for axis in 0..3 {
+        let u_axis = (axis + 1) % 3;
+        let v_axis = (axis + 2) % 3;
+        let edge = CHUNK_EDGE as usize;
+        for slice in 0..edge {
+            for v in 0..edge {
+                for u in 0..edge {
+                    let cell = FaceCell { axis, slice, u, v };
+                    // Opaque voxels hide each other, so at most one of these is visible.
+                    let face = face_sample(padded, ambient_occlusion, &classes, cell, true)
+                        .or_else(|| face_sample(padded, ambient_occlusion, &classes, cell, false));
+                    if let Some(face) = face {
+                        let mut voxel = [0; 3];
+                        voxel[axis] = slice as u8;
+                        voxel[u_axis] = u as u8;
+                        voxel[v_axis] = v as u8;
+                        instances.push(FaceInstance::new(
+                            voxel,
+                            axis as u8,
+                            face.positive,
+                            face.material,
+                            face.ao,
+                        ));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The faces of one chunk, drawn by the [`InstancedFacesPlugin`] with the [`FaceQuadMesh`].
+#[derive(Clone, Component)]
+pub struct ChunkFaceInstances {
+    pub faces: Arc<Vec<FaceInstance>>,
+    /// Unique to each set of faces, so the render world only uploads a new buffer when the faces change.
+    pub generation: u64,
+}
+
+impl ExtractComponent for ChunkFaceInstances {
+    type Query = &'static Self;
+    type Filter = ();
+
+    fn extract_component(item: QueryItem<Self::Query>) -> Self {
+        item.clone()
+    }
+}
+
+/// The color of each [`PaletteId8`] for instanced faces. Faces are shaded by their direction and ambient occlusion, but not by
+/// the scene's lights.
+#[derive(Clone)]
+pub struct FacePalette(pub [[f32; 4]; 256]);
+
+impl Default for FacePalette {
+    fn default() -> Self {
+        Self([[0.6, 0.6, 0.6, 1.0]; 256])
+    }
+}
+
+impl ExtractResource for FacePalette {
+    type Source = Self;
+
+    fn extract_resource(source: &Self::Source) -> Self {
+        source.clone()
+    }
+}
+
+/// The unit quad that is instanced for every face. Vertex positions hold the corner of the face in `x` and `y`.
+pub struct FaceQuadMesh(pub Handle<Mesh>);
+
+/// Draws the [`ChunkFaceInstances`] of chunks meshed with [`MeshMode::InstancedFaces`](feldspar_map::MeshMode::InstancedFaces)
+/// in the opaque pass. Each chunk takes one instanced draw call, and its instance buffer is only uploaded when its faces
+/// change.
+///
+/// Instanced faces don't cast shadows. Added by the [`RenderPlugin`](crate::RenderPlugin).
+pub struct InstancedFacesPlugin;
+
+impl Plugin for InstancedFacesPlugin {
+    fn build(&self, app: &mut App) {
+        load_internal_asset!(
+            app,
+            INSTANCED_FACES_SHADER_HANDLE,
+            "instanced_faces.wgsl",
+            Shader::from_wgsl
+        );
+        app.init_resource::<FacePalette>()
+            .add_plugin(ExtractComponentPlugin::<ChunkFaceInstances>::default())
+            .add_plugin(ExtractResourcePlugin::<FacePalette>::default())
+            .add_startup_system(setup_face_quad_mesh);
+
+        if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app
+                .add_render_command::<Opaque3d, DrawFaceInstances>()
+                .init_resource::<FacePipeline>()
+                .init_resource::<SpecializedMeshPipelines<FacePipeline>>()
+                .init_resource::<FaceBufferCache>()
+                .add_system_to_stage(RenderStage::Prepare, prepare_face_palette)
+                .add_system_to_stage(RenderStage::Prepare, prepare_face_buffers)
+                .add_system_to_stage(RenderStage::Queue, queue_face_instances);
+        }
+    }
+}
+
+fn setup_face_quad_mesh(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>) {
+    let mut quad = Mesh::new(PrimitiveTopology::TriangleList);
+    quad.insert_attribute(
+        Mesh::ATTRIBUTE_POSITION,
+        vec![
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [0.0, 1.0, 0.0],
+        ],
+    );
+    // Only here to satisfy the mesh pipeline's vertex layout. The shader derives the normal from the instance.
+    quad.insert_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0.0, 0.0, 1.0]; 4]);
+    quad.insert_attribute(
+        Mesh::ATTRIBUTE_UV_0,
+        vec![[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]],
+    );
+    quad.set_indices(Some(Indices::U32(vec![0, 1, 2, 0, 2, 3])));
+    commands.insert_resource(FaceQuadMesh(meshes.add(quad)));
+}
+
+pub struct FacePipeline {
+    mesh_pipeline: MeshPipeline,
+    palette_layout: BindGroupLayout,
+    palette_buffer: Buffer,
+    palette_bind_group: BindGroup,
+}
+
+impl FromWorld for FacePipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let palette_size = std::mem::size_of::<FacePalette>() as u64;
+        let palette_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("face_palette_layout"),
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::VERTEX,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(palette_size),
+                },
+                count: None,
+            }],
+        });
+        let palette_buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("face_palette_buffer"),
+            size: palette_size,
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let palette_bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+            label: Some("face_palette_bind_group"),
+            layout: &palette_layout,
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: palette_buffer.as_entire_binding(),
+            }],
+        });
+        Self {
+            mesh_pipeline: world.resource::<MeshPipeline>().clone(),
+            palette_layout,
+            palette_buffer,
+            palette_bind_group,
+        }
+    }
+}
+
+impl SpecializedMeshPipeline for FacePipeline {
+    type Key = MeshPipelineKey;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayout,
+    ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+        let mut descriptor = self.mesh_pipeline.specialize(key, layout)?;
+        descriptor.label = Some("instanced_faces_pipeline".into());
+        descriptor.vertex.shader = INSTANCED_FACES_SHADER_HANDLE.typed();
+        descriptor.vertex.buffers.push(VertexBufferLayout {
+            array_stride: std::mem::size_of::<FaceInstance>() as u64,
+            step_mode: VertexStepMode::Instance,
+            attributes: vec![VertexAttribute {
+                format: if WIDE_FACES {
+                    VertexFormat::Uint32x2
+                } else {
+                    VertexFormat::Uint32
+                },
+                offset: 0,
+                shader_location: 3,
+            }],
+        });
+        if WIDE_FACES {
+            descriptor
+                .vertex
+                .shader_defs
+                .push("WIDE_FACE_INSTANCES".into());
+        }
+        descriptor.fragment.as_mut().unwrap().shader = INSTANCED_FACES_SHADER_HANDLE.typed();
+        descriptor.layout = Some(vec![
+            self.mesh_pipeline.view_layout.clone(),
+            self.mesh_pipeline.mesh_layout.clone(),
+            self.palette_layout.clone(),
+        ]);
+        Ok(descriptor)
+    }
+}
+
+fn prepare_face_palette(
+    palette: Res<FacePalette>,
+    pipeline: Res<FacePipeline>,
+    render_queue: Res<RenderQueue>,
+) {
+    if palette.is_changed() {
+        render_queue.write_buffer(
+            &pipeline.palette_buffer,
+            0,
+            bytemuck::cast_slice(&palette.0),
+        );
+    }
+}
+
+#[derive(Component)]
+pub struct FaceInstanceBuffer {
+    buffer: Buffer,
+    length: u32,
+}
+
+/// Instance buffers of the chunks that were drawn last frame, keyed by entity, with the generation of faces they hold.
+#[derive(Default)]
+struct FaceBufferCache {
+    buffers: HashMap<Entity, (u64, Buffer)>,
+}
+
+fn prepare_face_buffers(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    mut cache: ResMut<FaceBufferCache>,
+    chunks: Query<(Entity, &ChunkFaceInstances)>,
+) {
+    let FaceBufferCache { buffers } = &mut *cache;
+    let mut extracted = HashSet::default();
+    for (entity, instances) in chunks.iter() {
+        extracted.insert(entity);
+        let buffer = match buffers.get(&entity) {
+            Some((generation, buffer)) if *generation == instances.generation => buffer.clone(),
+            _ => {
+                let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+                    label: Some("face_instance_buffer"),
+                    contents: bytemuck::cast_slice(instances.faces.as_slice()),
+                    usage: BufferUsages::VERTEX,
+                });
+                buffers.insert(entity, (instances.generation, buffer.clone()));
+                buffer
+            }
+        };
+        commands.entity(entity).insert(FaceInstanceBuffer {
+            buffer,
+            length: instances.faces.len() as u32,
+        });
+    }
+    // Despawned chunks release their buffers.
+    buffers.retain(|entity, _| extracted.contains(entity));
+}
+
+#[allow(clippy::too_many_arguments)]
+fn queue_face_instances(
+    opaque_draw_functions: Res<DrawFunctions<Opaque3d>>,
+    face_pipeline: Res<FacePipeline>,
+    msaa: Res<Msaa>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<FacePipeline>>,
+    mut pipeline_cache: ResMut<PipelineCache>,
+    meshes: Res<RenderAssets<Mesh>>,
+    chunks: Query<(&MeshUniform, &Handle<Mesh>), With<ChunkFaceInstances>>,
+    mut views: Query<(&ExtractedView, &VisibleEntities, &mut RenderPhase<Opaque3d>)>,
+) {
+    let draw_faces = opaque_draw_functions
+        .read()
+        .get_id::<DrawFaceInstances>()
+        .unwrap();
+    let msaa_key = MeshPipelineKey::from_msaa_samples(msaa.samples);
+
+    for (view, visible_entities, mut opaque_phase) in views.iter_mut() {
+        let view_row_2 = view.transform.compute_matrix().row(2);
+        for &entity in visible_entities.entities.iter() {
+            let (mesh_uniform, mesh_handle) = if let Ok(chunk) = chunks.get(entity) {
+                chunk
+            } else {
+                continue;
+            };
+            let mesh = if let Some(mesh) = meshes.get(mesh_handle) {
+                mesh
+            } else {
+                continue;
+            };
+            let key = msaa_key | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology);
+            let pipeline = match pipelines.specialize(
+                &mut pipeline_cache,
+                &face_pipeline,
+                key,
+                &mesh.layout,
+            ) {
+                Ok(pipeline) => pipeline,
+                Err(err) => {
+                    error!("{}", err);
+                    continue;
+                }
+            };
+            opaque_phase.add(Opaque3d {
+                distance: -view_row_2.dot(mesh_uniform.transform.col(3)),
+                pipeline,
+                entity,
+                draw_function: draw_faces,
+            });
+        }
+    }
+}
+
+type DrawFaceInstances = (
+    SetItemPipeline,
+    SetMeshViewBindGroup<0>,
+    SetMeshBindGroup<1>,
+    SetFacePaletteBindGroup<2>,
+    DrawFaceInstanced,
+);
+
+struct SetFacePaletteBindGroup<const I: usize>;
+
+impl<const I: usize> EntityRenderCommand for SetFacePaletteBindGroup<I> {
+    type Param = SRes<FacePipeline>;
+
+    fn render<'w>(
+        _view: Entity,
+        _item: Entity,
+        pipeline: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        pass.set_bind_group(I, &pipeline.into_inner().palette_bind_group, &[]);
+        RenderCommandResult::Success
+    }
+}
+
+struct DrawFaceInstanced;
+
+impl EntityRenderCommand for DrawFaceInstanced {
+    type Param = (
+        SRes<RenderAssets<Mesh>>,
+        SQuery<Read<Handle<Mesh>>>,
+        SQuery<Read<FaceInstanceBuffer>>,
+    );
+
+    fn render<'w>(
+        _view: Entity,
+        item: Entity,
+        (meshes, mesh_query, instance_buffer_query): SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let (mesh_handle, instance_buffer) =
+            match (mesh_query.get(item), instance_buffer_query.get_inner(item)) {
+                (Ok(mesh_handle), Ok(instance_buffer)) => (mesh_handle, instance_buffer),
+                _ => return RenderCommandResult::Failure,
+            };
+        let gpu_mesh = match meshes.into_inner().get(mesh_handle) {
+            Some(gpu_mesh) => gpu_mesh,
+            None => return RenderCommandResult::Failure,
+        };
+
+        pass.set_vertex_buffer(0, gpu_mesh.vertex_buffer.slice(..));
+        pass.set_vertex_buffer(1, instance_buffer.buffer.slice(..));
+        match &gpu_mesh.buffer_info {
+            GpuBufferInfo::Indexed {
+                buffer,
+                index_format,
+                count,
+            } => {
+                pass.set_index_buffer(buffer.slice(..), 0, *index_format);
+                pass.draw_indexed(0..*count, 0, 0..instance_buffer.length);
+            }
+            GpuBufferInfo::NonIndexed { vertex_count } => {
+                pass.draw(0..*vertex_count, 0..instance_buffer.length);
+            }
+        }
+        RenderCommandResult::Success
+    }
+}
+
+// ████████╗███████╗███████╗████████╗
+// ╚══██╔══╝██╔════╝██╔════╝╚══██╔══╝
+//    ██║   █████╗  ███████╗   ██║
+//    ██║   ██╔══╝  ╚════██║   ██║
+//    ██║   ███████╗███████║   ██║
+//    ╚═╝   ╚══════╝╚══════╝   ╚═╝
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{greedy_quads, GreedyQuadsBuffer};
+    use feldspar_map::chunk::{AMBIENT_SD8, PADDED_CHUNK_SIZE};
+    use feldspar_map::sdf::Sd8;
+
+    #[test]
+    fn face_instance_round_trips_fields() {
+        let face = FaceInstance::new([15, 0, 7], 2, true, 200, [3, 0, 1, 2]);
+        assert_eq!(face.voxel(), [15, 0, 7]);
+        assert_eq!(face.axis(), 2);
+        assert!(face.is_positive());
+        assert_eq!(face.material(), 200);
+        assert_eq!(face.ao(), [3, 0, 1, 2]);
+        assert!(!FaceInstance::new([0; 3], 0, false, 0, [0; 4]).is_positive());
+    }
+
+    #[test]
+    fn instances_cover_the_same_faces_as_greedy_quads() {
+        let mut padded = Box::new(PaddedChunk {
+            sdf: [AMBIENT_SD8; PADDED_CHUNK_SIZE],
+            palette_ids: [0; PADDED_CHUNK_SIZE],
+            material_weights: None,
+            light: None,
+        });
+        // A 2x1x1 bar, so the greedy faces merge but instances don't.
+        for x in [3, 4] {
+            let i = x + 18 * (5 + 18 * 6);
+            padded.sdf[i] = Sd8::MIN;
+            padded.palette_ids[i] = 9;
+        }
+
+        let mut instances = Vec::new();
+        face_instances(&padded, AoQuality::Off, &mut instances);
+        assert_eq!(instances.len(), 10);
+        assert!(instances.iter().all(|f| f.material() == 9));
+        // The face at the negative end is on the positive side of the empty voxel before the bar.
+        assert!(instances.contains(&FaceInstance::new([2, 5, 6], 0, false, 9, [3; 4])));
+
+        let mut buffer = GreedyQuadsBuffer::default();
+        greedy_quads(&padded, AoQuality::Off, &mut buffer);
+        assert_eq!(buffer.num_quads(), 6);
+    }
+}
@@ -0,0 +1,81 @@
+#import bevy_pbr::mesh_view_bindings
+#import bevy_pbr::mesh_bindings
+#import bevy_pbr::mesh_functions
+
+struct FacePalette {
+    colors: array<vec4<f32>, 256>,
+};
+
+@group(2) @binding(0)
+var<uniform> palette: FacePalette;
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
+    @location(2) uv: vec2<f32>,
+#ifdef WIDE_FACE_INSTANCES
+    // The fields, then the voxel coordinates with 8 bits each.
+    @location(3) face: vec2<u32>,
+#else
+    @location(3) face: u32,
+#endif
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) color: vec4<f32>,
+};
+
+// Matches the minimum brightness of the baked ambient occlusion in greedy quad meshes.
+let MIN_AO_BRIGHTNESS: f32 = 0.3;
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+#ifdef WIDE_FACE_INSTANCES
+    let coords = vertex.face.y;
+    let voxel = vec3<f32>(vec3<u32>(coords, coords >> 8u, coords >> 16u) & vec3<u32>(255u));
+    let fields = vertex.face.x;
+#else
+    let voxel = vec3<f32>(vec3<u32>(vertex.face, vertex.face >> 4u, vertex.face >> 8u) & vec3<u32>(15u));
+    let fields = vertex.face >> 12u;
+#endif
+    let axis = fields & 3u;
+    let positive = ((fields >> 2u) & 1u) == 1u;
+    let material = (fields >> 3u) & 255u;
+
+    // Negative faces swap the corner's coordinates to flip the winding.
+    var corner = vertex.position.xy;
+    if (!positive) {
+        corner = corner.yx;
+    }
+    // Corners are packed in the order (0, 0), (1, 0), (1, 1), (0, 1).
+    let cu = u32(corner.x);
+    let cv = u32(corner.y);
+    let corner_index = cu + 3u * cv - 2u * cu * cv;
+    let ao = f32((fields >> (11u + 2u * corner_index)) & 3u);
+
+    // Corner coordinates are along the axes after `axis` in cyclic order, like greedy quads.
+    var offset: vec3<f32>;
+    var shade: f32;
+    if (axis == 0u) {
+        offset = vec3<f32>(1.0, corner.x, corner.y);
+        shade = 0.8;
+    } else if (axis == 1u) {
+        offset = vec3<f32>(corner.y, 1.0, corner.x);
+        shade = select(0.5, 1.0, positive);
+    } else {
+        offset = vec3<f32>(corner.x, corner.y, 1.0);
+        shade = 0.7;
+    }
+
+    let brightness = shade * (MIN_AO_BRIGHTNESS + (1.0 - MIN_AO_BRIGHTNESS) * ao / 3.0);
+    var out: VertexOutput;
+    out.clip_position = mesh_position_local_to_clip(mesh.model, vec4<f32>(voxel + offset, 1.0));
+    out.color = vec4<f32>(palette.colors[material].rgb * brightness, 1.0);
+    return out;
+}
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    return in.color;
+}
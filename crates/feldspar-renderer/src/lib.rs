@@ -2,8 +2,51 @@
 //!
 //! # Surface Nets Mesh Extraction
 //!
-//! All geometry is generated by the [`fast-surface-nets`](https://github.com/bonsairobo/fast-surface-nets-rs) crate, which uses
-//! a dense, padded chunk of voxels to estimate mesh vertex positions and normals.
+//! By default, all geometry is generated by the [`fast-surface-nets`](https://github.com/bonsairobo/fast-surface-nets-rs) crate,
+//! which uses a dense, padded chunk of voxels to estimate mesh vertex positions and normals.
+//!
+//! # Greedy Quads
+//!
+//! For maps made of cubes, [`MeshMode::GreedyQuads`](feldspar_map::MeshMode::GreedyQuads) generates a face for each solid voxel
+//! that touches a non-solid voxel, merging coplanar faces of the same material. Each vertex gets the material of its face in the
+//! [`ATTRIBUTE_MATERIAL_ID`] attribute.
+//!
+//! ## Material Classes
+//!
+//! With a [`MaterialRegistry`](feldspar_map::MaterialRegistry), each material's
+//! [`MaterialClass`](feldspar_map::material::MaterialClass) decides which faces it hides. Glass and water only hide their own
+//! faces, cutouts like leaves hide nothing, and the faces of each class go into their own mesh, drawn with the
+//! [`ChunkClassMaterials`], so transparent geometry is sorted and blended apart from the opaque terrain.
+//!
+//! # Material Blending
+//!
+//! When [`MeshConfig::material_weights`](feldspar_map::MeshConfig::material_weights) is enabled, every vertex also gets the
+//! [`MaterialWeights`](feldspar_map::chunk::MaterialWeights) of the nearby voxels in the [`ATTRIBUTE_MATERIAL_WEIGHTS`]
+//! attribute, so a triplanar shader can blend up to 4 terrain textures.
+//!
+//! # Ambient Occlusion
+//!
+//! [`MeshConfig::ambient_occlusion`](feldspar_map::MeshConfig::ambient_occlusion) bakes occlusion into the vertex colors of
+//! each mesh. Greedy quads use the classic per-corner occlusion of the voxels touching each vertex, and surface nets sample the
+//! SDF along each vertex normal.
+//!
+//! # Voxel Lighting
+//!
+//! When [`LightConfig::enabled`](feldspar_map::LightConfig::enabled) is set, the map floods sunlight through LOD0 chunks and
+//! every vertex gets the light of the empty voxels around it in the [`ATTRIBUTE_LIGHT`] attribute. A custom shader can use it
+//! to darken caves and the undersides of overhangs.
+//!
+//! # Biomes
+//!
+//! If there is a [`BiomeMap`](feldspar_map::biome::BiomeMap) resource, every vertex gets the biome of the column it's in
+//! in the [`ATTRIBUTE_BIOME`] attribute, so a shader can tint grass or pick textures per biome.
+//!
+//! # Instanced Faces
+//!
+//! [`MeshMode::InstancedFaces`](feldspar_map::MeshMode::InstancedFaces) skips building meshes for cubic maps. Each visible face
+//! is packed into a 32-bit [`FaceInstance`], and the [`InstancedFacesPlugin`] draws a chunk's faces as instances of one quad.
+//! Faces are colored by the [`FacePalette`] and their ambient occlusion, but they aren't lit by the scene's lights and don't
+//! cast shadows.
 //!
 //! # Biplanar Texture Mapping (work in progress)
 //!
@@ -21,11 +64,24 @@
 //!
 //! To avoid the incredibly frustrating issue of multi-resolution mesh stitching, we sacrifice some GPU memory to blend between
 //! vertices of adjacent levels of detail together in the vertex shader, based on the distance from the camera to the vertex.
+//!
+//! # Debug Overlay
+//!
+//! The [`DebugOverlayPlugin`] draws the bounds of every clipmap node, colored by its loading state, and the clip shape of each
+//! witness. Toggle it with the [`DebugOverlay`] resource.
 
+mod ambient_occlusion;
 mod config;
+mod debug_overlay;
+mod greedy_quads;
+mod instanced;
 mod mesher;
 mod plugin;
 
+pub use ambient_occlusion::*;
 pub use config::*;
+pub use debug_overlay::*;
+pub use greedy_quads::*;
+pub use instanced::*;
 pub use mesher::*;
 pub use plugin::*;
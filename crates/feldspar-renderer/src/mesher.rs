@@ -1,7 +1,618 @@
-use feldspar_map::clipmap::ChunkClipMap;
+use feldspar_map::biome::BiomeMap;
+use feldspar_map::chunk::{
+    MaterialWeights, CHUNK_SHAPE_LOG2_IVEC3, CHUNK_SHAPE_VEC3A, MAX_LIGHT, PADDED_CHUNK_EDGE,
+};
+use feldspar_map::clipmap::{
+    ChunkClipMap, LodChange, NodeKey, NodeLocation, PaddedChunk, SplitChunk,
+};
+use feldspar_map::core::glam::IVec3;
+use feldspar_map::core::SmallKeyHashMap;
+use feldspar_map::material::{MaterialClass, MaterialClasses};
+use feldspar_map::units::VoxelUnits;
+use feldspar_map::world_transform::VoxelWorldTransform;
+use feldspar_map::{
+    ActiveMap, AoQuality, ChunkEntities, ChunkEvent, MapConfig, MaterialRegistry, MeshConfig,
+    MeshMode, Witness,
+};
 
+use crate::{
+    classified_greedy_quads, face_instances, greedy_quads, surface_nets_ao, ChunkFaceInstances,
+    ClassifiedQuadsBuffer, FaceInstance, FaceQuadMesh, GreedyQuadsBuffer,
+};
+
+use bevy::pbr::NotShadowCaster;
 use bevy::prelude::*;
+use bevy::render::mesh::{Indices, MeshVertexAttribute, PrimitiveTopology, VertexAttributeValues};
+use bevy::render::primitives::Aabb;
+use bevy::render::render_resource::VertexFormat;
+use bevy::tasks::{AsyncComputeTaskPool, Task};
+use fast_surface_nets::ndshape::{ConstShape, ConstShape3u32};
+use fast_surface_nets::{surface_nets, SurfaceNetsBuffer};
+use futures_lite::future;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// Same as [`PaddedChunkShape`](feldspar_map::chunk::PaddedChunkShape), but with the coordinate type expected by
+/// `fast-surface-nets`.
+type SurfaceNetsShape = ConstShape3u32<
+    { PADDED_CHUNK_EDGE as u32 },
+    { PADDED_CHUNK_EDGE as u32 },
+    { PADDED_CHUNK_EDGE as u32 },
+>;
+
+/// The [`PaletteId8`](feldspar_map::palette::PaletteId8) of each vertex's face, generated in [`MeshMode::GreedyQuads`].
+pub const ATTRIBUTE_MATERIAL_ID: MeshVertexAttribute =
+    MeshVertexAttribute::new("Vertex_MaterialId", 0x6665_6c64, VertexFormat::Uint32);
+
+/// The [`MaterialWeights`] around each vertex, only generated if [`MeshConfig::material_weights`] is enabled. Surface nets
+/// vertices average the 8 voxels of their cell, and greedy quads use the voxel behind their face.
+pub const ATTRIBUTE_MATERIAL_WEIGHTS: MeshVertexAttribute = MeshVertexAttribute::new(
+    "Vertex_MaterialWeights",
+    0x6665_6c65,
+    VertexFormat::Unorm8x4,
+);
+
+/// The light level around each vertex from 0 to 1, only generated if
+/// [`LightConfig::enabled`](feldspar_map::LightConfig::enabled). Vertices of chunks that haven't been lit yet, or that aren't at
+/// LOD0, are fully lit.
+pub const ATTRIBUTE_LIGHT: MeshVertexAttribute =
+    MeshVertexAttribute::new("Vertex_Light", 0x6665_6c66, VertexFormat::Float32);
+
+/// The [`BiomeId`](feldspar_map::biome::BiomeId) of the column under each vertex, only generated if there is a [`BiomeMap`]
+/// resource. Not generated in [`MeshMode::InstancedFaces`].
+pub const ATTRIBUTE_BIOME: MeshVertexAttribute =
+    MeshVertexAttribute::new("Vertex_Biome", 0x6665_6c67, VertexFormat::Uint32);
+
+/// A chunk mesh that finished generating on the compute pool.
+pub struct GeneratedMesh {
+    key: NodeKey<IVec3>,
+    generation: u64,
+    /// The [`ChunkClipMap::edit_generation`] of the chunk when its neighborhood was copied.
+    edit_generation: u64,
+    /// `None` if the neighborhood has no surface.
+    geometry: Option<ChunkGeometry>,
+}
+
+/// The geometry generated for one chunk, depending on the [`MeshMode`].
+pub enum ChunkGeometry {
+    Mesh(Mesh),
+    /// One mesh for each [`MaterialClass`] with any faces, generated in [`MeshMode::GreedyQuads`] when some materials aren't
+    /// opaque.
+    Classified(Vec<(MaterialClass, Mesh)>),
+    Faces(Vec<FaceInstance>),
+}
+
+impl GeneratedMesh {
+    /// The number of bytes uploaded to the GPU for this mesh.
+    fn upload_size(&self) -> usize {
+        match &self.geometry {
+            Some(ChunkGeometry::Mesh(mesh)) => mesh_size(mesh),
+            Some(ChunkGeometry::Classified(meshes)) => {
+                meshes.iter().map(|(_, m)| mesh_size(m)).sum()
+            }
+            Some(ChunkGeometry::Faces(faces)) => std::mem::size_of_val(faces.as_slice()),
+            None => 0,
+        }
+    }
+}
+
+fn mesh_size(mesh: &Mesh) -> usize {
+    let index_size = match mesh.indices() {
+        Some(Indices::U16(indices)) => 2 * indices.len(),
+        Some(Indices::U32(indices)) => 4 * indices.len(),
+        None => 0,
+    };
+    mesh.count_vertices() * mesh.get_vertex_size() as usize + index_size
+}
+
+pub struct PendingMeshTasks {
+    tasks: VecDeque<Task<Vec<GeneratedMesh>>>,
+    /// Finished meshes waiting for the per-frame upload budget, in the order they finished.
+    uploads: VecDeque<GeneratedMesh>,
+}
+
+impl PendingMeshTasks {
+    pub fn new() -> Self {
+        Self {
+            tasks: VecDeque::new(),
+            uploads: VecDeque::new(),
+        }
+    }
+}
+
+/// The entities of all chunk meshes, keyed by the chunk they were generated from. They're also indexed in the
+/// [`ChunkEntities`].
+#[derive(Default)]
+pub struct ChunkMeshes {
+    entities: SmallKeyHashMap<NodeKey<IVec3>, Entity>,
+    /// The latest generation of mesh requested for each chunk. A mesh that completes with an older generation is stale.
+    requested: SmallKeyHashMap<NodeKey<IVec3>, u64>,
+    next_generation: u64,
+}
+
+impl ChunkMeshes {
+    pub fn entity(&self, key: NodeKey<IVec3>) -> Option<Entity> {
+        self.entities.get(&key).copied()
+    }
+
+    fn request(&mut self, key: NodeKey<IVec3>) -> u64 {
+        let generation = self.next_generation;
+        self.next_generation += 1;
+        self.requested.insert(key, generation);
+        generation
+    }
+
+    /// Whether `generated` is the latest requested mesh of its chunk, and the chunk hasn't been edited since its voxels were
+    /// copied.
+    fn is_current(&self, clipmap: &ChunkClipMap, generated: &GeneratedMesh) -> bool {
+        self.requested.get(&generated.key) == Some(&generated.generation)
+            && clipmap.edit_generation(generated.key) == generated.edit_generation
+    }
+
+    fn remove(
+        &mut self,
+        commands: &mut Commands,
+        chunk_entities: &mut ChunkEntities,
+        key: NodeKey<IVec3>,
+    ) {
+        self.requested.remove(&key);
+        if let Some(entity) = self.entities.remove(&key) {
+            chunk_entities.remove(key, entity);
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+/// The material shared by all chunk meshes, except for the meshes of the other [`MaterialClass`]es.
+pub struct ChunkMaterial(pub Handle<StandardMaterial>);
+
+/// The materials of the chunk meshes that aren't [`MaterialClass::Opaque`]. Only used in [`MeshMode::GreedyQuads`] when there
+/// is a [`MaterialRegistry`].
+pub struct ChunkClassMaterials {
+    pub cutout: Handle<StandardMaterial>,
+    pub transparent: Handle<StandardMaterial>,
+    pub liquid: Handle<StandardMaterial>,
+}
+
+/// Generates meshes for chunks whose render detail or voxels changed, and despawns the meshes they replace.
+///
+/// Finished meshes are uploaded within the [`MeshConfig`] upload budget, so a burst of completed tasks doesn't stall a single
+/// frame. No new tasks are spawned while a full batch of meshes is still waiting to be uploaded. A mesh whose chunk was edited
+/// while it was generating is dropped, and the old mesh stays until the chunk is remeshed by the remesh search.
+///
+/// With a [`MaterialRegistry`], greedy quads are culled by the [`MaterialClass`] of each material, and each class gets its own
+/// mesh as a child of the chunk's entity.
+pub fn mesher_system(
+    config: Res<MapConfig>,
+    world_transform: Res<VoxelWorldTransform>,
+    witness_transforms: Query<(&Witness, &Transform)>,
+    active_map: Res<ActiveMap>,
+    clipmap: Res<ChunkClipMap>,
+    material: Res<ChunkMaterial>,
+    class_materials: Res<ChunkClassMaterials>,
+    face_quad: Res<FaceQuadMesh>,
+    biomes: Option<Res<BiomeMap>>,
+    material_registry: Option<Res<MaterialRegistry>>,
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut chunk_meshes: ResMut<ChunkMeshes>,
+    mut chunk_entities: ResMut<ChunkEntities>,
+    mut mesh_tasks: ResMut<PendingMeshTasks>,
+    mut chunk_events: EventWriter<ChunkEvent>,
+) {
+    let PendingMeshTasks { tasks, uploads } = &mut *mesh_tasks;
+
+    // Complete pending mesh tasks in queue order.
+    while let Some(mut task) = tasks.pop_front() {
+        if let Some(generated) = future::block_on(future::poll_once(&mut task)) {
+            uploads.extend(generated);
+        } else {
+            tasks.push_front(task);
+            break;
+        }
+    }
+
+    // Upload as many finished meshes as the budget allows. Stale meshes are dropped without counting against it.
+    let mut num_uploaded = 0;
+    let mut bytes_uploaded = 0;
+    while num_uploaded < config.mesh.max_mesh_uploads_per_frame {
+        let generated = if let Some(generated) = uploads.pop_front() {
+            generated
+        } else {
+            break;
+        };
+        if chunk_meshes.is_current(&clipmap, &generated) {
+            let size = generated.upload_size();
+            if num_uploaded > 0
+                && bytes_uploaded + size > config.mesh.max_mesh_upload_bytes_per_frame
+            {
+                uploads.push_front(generated);
+                break;
+            }
+            num_uploaded += 1;
+            bytes_uploaded += size;
+        }
+        spawn_chunk_mesh(
+            &mut commands,
+            &mut meshes,
+            &mut chunk_meshes,
+            &mut chunk_entities,
+            &mut chunk_events,
+            &material,
+            &class_materials,
+            &face_quad,
+            &world_transform,
+            &clipmap,
+            generated,
+        );
+    }
+
+    if tasks.len() >= config.mesh.max_pending_mesh_tasks
+        || uploads.len() >= config.mesh.mesh_batch_size
+    {
+        return;
+    }
+
+    // Every witness of the active map gets render detail, the same as the loader gives them voxels.
+    let observers: Vec<_> = witness_transforms
+        .iter()
+        .filter(|(witness, _)| witness.map == active_map.0)
+        .map(|(_, tfm)| world_transform.transform_to_voxel(tfm))
+        .collect();
+    if observers.is_empty() {
+        return;
+    }
+
+    // Despawn the meshes that are being replaced, and find the new neighborhoods that need meshes.
+    let mut new_nhoods = Vec::new();
+    for lod_change in clipmap.render_search(&observers, config.mesh.mesh_batch_size) {
+        match lod_change {
+            LodChange::Spawn(nhood) => new_nhoods.push(nhood),
+            LodChange::Split(split) => {
+                let SplitChunk {
+                    old_chunk,
+                    new_chunks,
+                } = *split;
+                chunk_meshes.remove(&mut commands, &mut chunk_entities, location_key(&old_chunk));
+                new_nhoods.extend(new_chunks.into_iter().flatten());
+            }
+            LodChange::Merge(merge) => {
+                for old_chunk in merge.old_chunks.iter() {
+                    chunk_meshes.remove(
+                        &mut commands,
+                        &mut chunk_entities,
+                        location_key(old_chunk),
+                    );
+                }
+                new_nhoods.push(merge.new_chunk);
+            }
+        }
+    }
+
+    // Chunks that are still rendering but whose voxels changed also need new meshes.
+    let remesh_budget = config.mesh.mesh_batch_size.saturating_sub(new_nhoods.len());
+    new_nhoods.extend(clipmap.remesh_search(remesh_budget));
+
+    // Copy the voxels out of the clipmap so the meshing task doesn't need to hold any locks. Uniform neighborhoods have no
+    // surface, so they aren't copied or meshed.
+    let copied: Vec<_> = new_nhoods
+        .iter()
+        .map(|nhood| {
+            let key = NodeKey::new(nhood.level, nhood.coordinates.into_inner());
+            let generation = chunk_meshes.request(key);
+            let edit_generation = clipmap.edit_generation(key);
+            let padded = clipmap
+                .uniform_neighborhood(nhood)
+                .is_none()
+                .then(|| clipmap.copy_padded_neighborhood(nhood));
+            (key, generation, edit_generation, padded)
+        })
+        .collect();
+
+    if copied.is_empty() {
+        return;
+    }
+
+    // Spawn a new task to mesh those neighborhoods.
+    let mesh_config = config.mesh;
+    let light = config.light.enabled;
+    let biomes = biomes.map(|b| b.clone());
+    let classes = material_registry.map(|r| r.classes());
+    let compute_pool = AsyncComputeTaskPool::get();
+    let task = compute_pool.spawn(async move {
+        copied
+            .into_iter()
+            .map(|(key, generation, edit_generation, padded)| GeneratedMesh {
+                key,
+                generation,
+                edit_generation,
+                geometry: padded.and_then(|padded| {
+                    generate_geometry(
+                        &mesh_config,
+                        light,
+                        biomes.as_ref(),
+                        classes.as_ref(),
+                        key,
+                        &padded,
+                    )
+                }),
+            })
+            .collect()
+    });
+    tasks.push_back(task);
+}
+
+/// Generates the geometry for `padded` in voxel units of the chunk's level. Returns `None` if there is no surface.
+fn generate_geometry(
+    config: &MeshConfig,
+    light: bool,
+    biomes: Option<&BiomeMap>,
+    classes: Option<&MaterialClasses>,
+    key: NodeKey<IVec3>,
+    padded: &PaddedChunk,
+) -> Option<ChunkGeometry> {
+    if config.mode == MeshMode::InstancedFaces {
+        let mut faces = Vec::new();
+        face_instances(padded, config.ambient_occlusion, &mut faces);
+        return (!faces.is_empty()).then(|| ChunkGeometry::Faces(faces));
+    }
+    if let (MeshMode::GreedyQuads, Some(classes)) = (config.mode, classes) {
+        let mut buffers = ClassifiedQuadsBuffer::default();
+        classified_greedy_quads(padded, config.ambient_occlusion, classes, &mut buffers);
+        let mut meshes: Vec<_> = MaterialClass::ALL
+            .into_iter()
+            .filter_map(|class| {
+                let mut mesh = greedy_quads_mesh(config, light, buffers.take(class))?;
+                if let Some(biomes) = biomes {
+                    insert_biomes(&mut mesh, biomes, key);
+                }
+                Some((class, mesh))
+            })
+            .collect();
+        return match meshes.len() {
+            0 => None,
+            1 if meshes[0].0 == MaterialClass::Opaque => {
+                Some(ChunkGeometry::Mesh(meshes.remove(0).1))
+            }
+            _ => Some(ChunkGeometry::Classified(meshes)),
+        };
+    }
+    let mut mesh = generate_mesh(config, light, padded)?;
+    if let Some(biomes) = biomes {
+        insert_biomes(&mut mesh, biomes, key);
+    }
+    Some(ChunkGeometry::Mesh(mesh))
+}
+
+fn generate_mesh(config: &MeshConfig, light: bool, padded: &PaddedChunk) -> Option<Mesh> {
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    match config.mode {
+        MeshMode::SurfaceNets => {
+            let sdf: Vec<f32> = padded.sdf.iter().map(|&d| f32::from(d)).collect();
+            debug_assert_eq!(sdf.len(), SurfaceNetsShape::SIZE as usize);
+            let mut buffer = SurfaceNetsBuffer::default();
+            let max = [PADDED_CHUNK_EDGE as u32 - 1; 3];
+            surface_nets(&sdf, &SurfaceNetsShape {}, [0; 3], max, &mut buffer);
+            if buffer.indices.is_empty() {
+                return None;
+            }
+            if config.ambient_occlusion != AoQuality::Off {
+                let ao = surface_nets_ao(
+                    padded,
+                    &buffer.positions,
+                    &buffer.normals,
+                    config.ambient_occlusion,
+                );
+                insert_ao_colors(&mut mesh, &ao);
+            }
+            if light {
+                let light: Vec<f32> = buffer
+                    .surface_points
+                    .iter()
+                    .map(|&p| cell_light(padded, p))
+                    .collect();
+                mesh.insert_attribute(ATTRIBUTE_LIGHT, light);
+            }
+            if config.material_weights {
+                let weights = buffer
+                    .surface_points
+                    .iter()
+                    .map(|&p| cell_material_weights(padded, p).0)
+                    .collect();
+                mesh.insert_attribute(
+                    ATTRIBUTE_MATERIAL_WEIGHTS,
+                    VertexAttributeValues::Unorm8x4(weights),
+                );
+            }
+            mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, buffer.positions);
+            mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, buffer.normals);
+            mesh.set_indices(Some(Indices::U32(buffer.indices)));
+        }
+        MeshMode::GreedyQuads => {
+            let mut buffer = GreedyQuadsBuffer::default();
+            greedy_quads(padded, config.ambient_occlusion, &mut buffer);
+            return greedy_quads_mesh(config, light, buffer);
+        }
+        MeshMode::InstancedFaces => unreachable!("instanced faces aren't meshed"),
+    }
+    Some(mesh)
+}
+
+/// Returns `None` if `buffer` has no quads.
+fn greedy_quads_mesh(config: &MeshConfig, light: bool, buffer: GreedyQuadsBuffer) -> Option<Mesh> {
+    if buffer.indices.is_empty() {
+        return None;
+    }
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, buffer.positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, buffer.normals);
+    mesh.insert_attribute(ATTRIBUTE_MATERIAL_ID, buffer.material_ids);
+    if config.ambient_occlusion != AoQuality::Off {
+        insert_ao_colors(&mut mesh, &buffer.ambient_occlusion);
+    }
+    if light {
+        mesh.insert_attribute(ATTRIBUTE_LIGHT, buffer.light);
+    }
+    if config.material_weights {
+        mesh.insert_attribute(
+            ATTRIBUTE_MATERIAL_WEIGHTS,
+            VertexAttributeValues::Unorm8x4(buffer.material_weights),
+        );
+    }
+    mesh.set_indices(Some(Indices::U32(buffer.indices)));
+    Some(mesh)
+}
+
+/// Bakes the ambient occlusion brightness into the vertex colors, which the [`StandardMaterial`] multiplies with its base color.
+fn insert_ao_colors(mesh: &mut Mesh, ao: &[f32]) {
+    let colors: Vec<[f32; 4]> = ao.iter().map(|&b| [b, b, b, 1.0]).collect();
+    mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+}
+
+/// Looks up the biome of each vertex from its LOD0 position.
+fn insert_biomes(mesh: &mut Mesh, biomes: &BiomeMap, key: NodeKey<IVec3>) {
+    let positions = match mesh.attribute(Mesh::ATTRIBUTE_POSITION) {
+        Some(VertexAttributeValues::Float32x3(positions)) => positions,
+        _ => return,
+    };
+    let voxel_size = (1 << key.level) as f32;
+    let chunk_min = (key.coordinates << CHUNK_SHAPE_LOG2_IVEC3) << key.level as i32;
+    let ids: Vec<u32> = positions
+        .iter()
+        .map(|p| {
+            let x = chunk_min.x + (p[0] * voxel_size).floor() as i32;
+            let z = chunk_min.z + (p[2] * voxel_size).floor() as i32;
+            biomes.biome_at_column(x, z) as u32
+        })
+        .collect();
+    mesh.insert_attribute(ATTRIBUTE_BIOME, ids);
+}
+
+/// The mean weights of the 8 voxels at the corners of the surface nets cell with minimum `cell`.
+fn cell_material_weights(padded: &PaddedChunk, cell: [u32; 3]) -> MaterialWeights {
+    let weights = if let Some(weights) = &padded.material_weights {
+        weights
+    } else {
+        return MaterialWeights::default();
+    };
+    MaterialWeights::mean((0..8).map(|corner| {
+        let p = [0, 1, 2].map(|axis| cell[axis] + ((corner >> axis) & 1));
+        weights[SurfaceNetsShape::linearize(p) as usize]
+    }))
+}
+
+/// The mean light of the empty voxels at the corners of the surface nets cell with minimum `cell`, from 0 to 1.
+fn cell_light(padded: &PaddedChunk, cell: [u32; 3]) -> f32 {
+    let light = if let Some(light) = &padded.light {
+        light
+    } else {
+        return 1.0;
+    };
+    let (sum, count) = (0..8)
+        .map(|corner| {
+            let p = [0, 1, 2].map(|axis| cell[axis] + ((corner >> axis) & 1));
+            SurfaceNetsShape::linearize(p) as usize
+        })
+        .filter(|&i| padded.sdf[i].0 >= 0)
+        .fold((0.0, 0), |(sum, count), i| {
+            (sum + f32::from(light[i]), count + 1)
+        });
+    if count == 0 {
+        1.0
+    } else {
+        sum / (count as f32 * f32::from(MAX_LIGHT))
+    }
+}
+
+fn location_key(loc: &NodeLocation) -> NodeKey<IVec3> {
+    NodeKey::new(loc.ptr.level(), loc.coordinates.into_inner())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn spawn_chunk_mesh(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    chunk_meshes: &mut ChunkMeshes,
+    chunk_entities: &mut ChunkEntities,
+    chunk_events: &mut EventWriter<ChunkEvent>,
+    material: &ChunkMaterial,
+    class_materials: &ChunkClassMaterials,
+    face_quad: &FaceQuadMesh,
+    world_transform: &VoxelWorldTransform,
+    clipmap: &ChunkClipMap,
+    generated: GeneratedMesh,
+) {
+    if !chunk_meshes.is_current(clipmap, &generated) {
+        // This chunk was despawned, remeshed, or edited while the task was running. An edited chunk is still marked as
+        // needing a mesh, so it gets a fresh one without being requested here.
+        return;
+    }
+    let GeneratedMesh {
+        key,
+        generation,
+        geometry,
+        ..
+    } = generated;
+
+    chunk_meshes.requested.remove(&key);
+    if let Some(old_entity) = chunk_meshes.entities.remove(&key) {
+        chunk_entities.remove(key, old_entity);
+        commands.entity(old_entity).despawn_recursive();
+    }
+    chunk_events.send(ChunkEvent::Meshed(key));
+
+    let geometry = if let Some(geometry) = geometry {
+        geometry
+    } else {
+        return;
+    };
 
-pub fn mesher_system(clipmap: Res<ChunkClipMap>) {
-    todo!()
+    // Mesh positions are in voxel units at the chunk's level, so scale them up to LOD0.
+    let voxel_size = (1 << key.level) as f32;
+    let chunk_min = (key.coordinates << CHUNK_SHAPE_LOG2_IVEC3) << key.level as i32;
+    let mut transform = world_transform.voxel_local_transform(VoxelUnits(chunk_min));
+    transform.scale *= voxel_size;
+    let entity = match geometry {
+        ChunkGeometry::Mesh(mesh) => commands
+            .spawn_bundle(PbrBundle {
+                mesh: meshes.add(mesh),
+                material: material.0.clone(),
+                transform,
+                ..Default::default()
+            })
+            .id(),
+        ChunkGeometry::Classified(class_meshes) => commands
+            .spawn_bundle(SpatialBundle::from_transform(transform))
+            .with_children(|parent| {
+                for (class, mesh) in class_meshes.into_iter() {
+                    let material = match class {
+                        MaterialClass::Opaque => &material.0,
+                        MaterialClass::Cutout => &class_materials.cutout,
+                        MaterialClass::Transparent => &class_materials.transparent,
+                        MaterialClass::Liquid => &class_materials.liquid,
+                    };
+                    parent.spawn_bundle(PbrBundle {
+                        mesh: meshes.add(mesh),
+                        material: material.clone(),
+                        ..Default::default()
+                    });
+                }
+            })
+            .id(),
+        ChunkGeometry::Faces(faces) => commands
+            .spawn_bundle(SpatialBundle::from_transform(transform))
+            .insert_bundle((
+                face_quad.0.clone(),
+                ChunkFaceInstances {
+                    faces: Arc::new(faces),
+                    generation,
+                },
+                // The quad's own bounds would cull the whole chunk.
+                Aabb::from_min_max(Vec3::ZERO, Vec3::from(CHUNK_SHAPE_VEC3A)),
+                NotShadowCaster,
+            ))
+            .id(),
+    };
+    chunk_meshes.entities.insert(key, entity);
+    chunk_entities.insert(key, entity);
 }
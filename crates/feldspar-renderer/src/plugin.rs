@@ -1,9 +1,56 @@
-use bevy::prelude::Plugin;
+use crate::{
+    mesher_system, ChunkClassMaterials, ChunkMaterial, ChunkMeshes, InstancedFacesPlugin,
+    PendingMeshTasks,
+};
 
+use bevy::prelude::*;
+
+/// Generates and maintains meshes for the chunks in the [`ChunkClipMap`](feldspar_map::clipmap::ChunkClipMap).
+///
+/// Requires the [`MapPlugin`](feldspar_map::MapPlugin) and its [`MapConfig`](feldspar_map::MapConfig), which determines how
+/// much meshing work happens per frame.
 pub struct RenderPlugin;
 
 impl Plugin for RenderPlugin {
-    fn build(&self, app: &mut bevy::prelude::App) {
-        todo!();
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ChunkMeshes::default())
+            .insert_resource(PendingMeshTasks::new())
+            .add_plugin(InstancedFacesPlugin)
+            .add_startup_system(setup_chunk_material)
+            .add_system_to_stage(CoreStage::Update, mesher_system);
     }
 }
+
+fn setup_chunk_material(mut commands: Commands, mut materials: ResMut<Assets<StandardMaterial>>) {
+    let material = materials.add(StandardMaterial {
+        base_color: Color::rgb(0.6, 0.6, 0.6),
+        perceptual_roughness: 0.9,
+        ..Default::default()
+    });
+    commands.insert_resource(ChunkMaterial(material));
+
+    // Cutout textures are expected to put their holes in the alpha channel.
+    let cutout = materials.add(StandardMaterial {
+        base_color: Color::rgb(0.6, 0.6, 0.6),
+        perceptual_roughness: 0.9,
+        alpha_mode: AlphaMode::Mask(0.5),
+        ..Default::default()
+    });
+    let transparent = materials.add(StandardMaterial {
+        base_color: Color::rgba(0.8, 0.9, 1.0, 0.3),
+        perceptual_roughness: 0.1,
+        alpha_mode: AlphaMode::Blend,
+        ..Default::default()
+    });
+    let liquid = materials.add(StandardMaterial {
+        base_color: Color::rgba(0.2, 0.4, 0.8, 0.6),
+        perceptual_roughness: 0.05,
+        alpha_mode: AlphaMode::Blend,
+        ..Default::default()
+    });
+    commands.insert_resource(ChunkClassMaterials {
+        cutout,
+        transparent,
+        liquid,
+    });
+}